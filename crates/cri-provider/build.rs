@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Only the client side of the CRI API is needed since the runtime is the server
+    tonic_build::configure()
+        .build_server(false)
+        .compile(&["proto/api.proto"], &["proto"])?;
+    Ok(())
+}
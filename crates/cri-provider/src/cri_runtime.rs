@@ -0,0 +1,161 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use tokio::sync::watch::{self, Sender};
+use tokio::task::JoinHandle;
+use tonic::transport::Channel;
+
+use kubelet::handle::{RuntimeHandle, Stop};
+use kubelet::status::ContainerStatus;
+
+use crate::api::runtime_service_client::RuntimeServiceClient;
+use crate::api::{ContainerState, ContainerStatusRequest, StopContainerRequest};
+
+/// How often the runtime is polled for the status of a running container
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stops a container running in a CRI runtime
+pub struct ContainerStopper {
+    client: RuntimeServiceClient<Channel>,
+    container_id: String,
+    grace_period: i64,
+    status_handle: JoinHandle<()>,
+}
+
+#[async_trait::async_trait]
+impl Stop for ContainerStopper {
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        // The runtime sends SIGTERM and then SIGKILL once the timeout has passed, so this
+        // returns once the container has actually stopped
+        self.client
+            .stop_container(StopContainerRequest {
+                container_id: self.container_id.clone(),
+                timeout: self.grace_period,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn wait(&mut self) -> anyhow::Result<()> {
+        (&mut self.status_handle).await?;
+        Ok(())
+    }
+}
+
+/// Opens the log file the runtime writes a container's output to
+pub struct LogHandleFactory {
+    path: PathBuf,
+}
+
+impl kubelet::handle::LogHandleFactory<tokio::fs::File> for LogHandleFactory {
+    /// Creates `tokio::fs::File` on demand for log reading.
+    ///
+    /// The runtime only creates the log file once the container starts, so a missing file
+    /// is created for the runtime to append to. If the file can't be opened at all, the
+    /// logs are read as empty
+    fn new_handle(&self) -> tokio::fs::File {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .or_else(|e| {
+                warn!("Unable to open log file {}: {}", self.path.display(), e);
+                std::fs::File::open("/dev/null")
+            })
+            .expect("the null device can always be opened");
+        tokio::fs::File::from_std(file)
+    }
+}
+
+/// Creates a handle for a container that has already been started in the runtime.
+///
+/// The status of the container is polled from the runtime and reported over the
+/// handle's status channel until the container exits.
+pub fn container_handle(
+    client: RuntimeServiceClient<Channel>,
+    container_id: String,
+    log_path: PathBuf,
+    grace_period: i64,
+) -> RuntimeHandle<ContainerStopper, LogHandleFactory> {
+    let (status_sender, status_recv) = watch::channel(ContainerStatus::waiting(
+        "No status has been received from the runtime",
+    ));
+    let status_handle = tokio::spawn(poll_status(
+        client.clone(),
+        container_id.clone(),
+        status_sender,
+    ));
+    RuntimeHandle::new(
+        ContainerStopper {
+            client,
+            container_id,
+            grace_period,
+            status_handle,
+        },
+        LogHandleFactory { path: log_path },
+        status_recv,
+    )
+}
+
+async fn poll_status(
+    mut client: RuntimeServiceClient<Channel>,
+    container_id: String,
+    status_sender: Sender<ContainerStatus>,
+) {
+    let mut running = false;
+    loop {
+        let response = client
+            .container_status(ContainerStatusRequest {
+                container_id: container_id.clone(),
+                verbose: false,
+            })
+            .await;
+        let status = match response.map(|r| r.into_inner().status) {
+            Ok(Some(s)) => s,
+            Ok(None) => {
+                error!("Runtime returned no status for container {}", container_id);
+                send_terminated(&status_sender, "runtime returned no status", 1);
+                return;
+            }
+            Err(e) => {
+                error!("Unable to get status of container {}: {}", container_id, e);
+                send_terminated(&status_sender, &e.to_string(), 1);
+                return;
+            }
+        };
+        match ContainerState::from_i32(status.state) {
+            Some(ContainerState::ContainerRunning) if !running => {
+                debug!("Container {} is running", container_id);
+                running = true;
+                let _ = status_sender.broadcast(ContainerStatus::Running {
+                    timestamp: chrono::Utc::now(),
+                });
+            }
+            Some(ContainerState::ContainerExited) => {
+                debug!(
+                    "Container {} exited with code {}",
+                    container_id, status.exit_code
+                );
+                let message = if status.reason.is_empty() {
+                    format!("Exited with code {}", status.exit_code)
+                } else {
+                    status.reason
+                };
+                send_terminated(&status_sender, &message, status.exit_code);
+                return;
+            }
+            _ => {}
+        }
+        tokio::time::delay_for(STATUS_POLL_INTERVAL).await;
+    }
+}
+
+fn send_terminated(sender: &Sender<ContainerStatus>, message: &str, exit_code: i32) {
+    let _ = sender.broadcast(ContainerStatus::Terminated {
+        timestamp: chrono::Utc::now(),
+        message: message.to_owned(),
+        exit_code,
+    });
+}
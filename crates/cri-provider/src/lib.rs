@@ -0,0 +1,447 @@
+//! A kubelet backend that runs containers with a [CRI](https://github.com/kubernetes/cri-api)
+//! compatible runtime such as containerd
+//!
+//! The crate provides the [`CriProvider`] type which can be used as a provider with
+//! [`kubelet`]. It talks to the runtime over its gRPC socket, so the runtime must already be
+//! running on the node.
+//!
+//! # Example
+//! ```rust,no_run
+//! use kubelet::{Kubelet, config::Config};
+//! use cri_provider::{CriProvider, DEFAULT_RUNTIME_ENDPOINT};
+//!
+//! async {
+//!     // Get a configuration for the Kubelet
+//!     let kubelet_config = Config::default();
+//!
+//!     // Load a kubernetes configuration
+//!     let kubeconfig = kube::Config::infer().await.unwrap();
+//!     let client = kube::Client::new(kubeconfig.clone());
+//!
+//!     // Instantiate the provider type
+//!     let provider = CriProvider::new(DEFAULT_RUNTIME_ENDPOINT, &kubelet_config, client)
+//!         .await
+//!         .unwrap();
+//!
+//!     // Instantiate the Kubelet
+//!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config);
+//!     // Start the Kubelet and block on it
+//!     kubelet.start().await.unwrap();
+//! };
+//! ```
+
+#![deny(missing_docs)]
+
+mod cri_runtime;
+
+#[allow(missing_docs, clippy::all)]
+mod api {
+    tonic::include_proto!("runtime.v1alpha2");
+}
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use kubelet::concurrency::Operation;
+use kubelet::container::Container;
+use kubelet::error::PodSyncError;
+use kubelet::handle::{key_from_pod, pod_key, PodHandle};
+use kubelet::pod_dirs::{remove_legacy_dirs, LEGACY_VOLUMES_DIR};
+use kubelet::pod_logs;
+use kubelet::provider::ProviderError;
+use kubelet::volumes::VolumeRef;
+use kubelet::{NodeBuilder, Pod, Provider};
+use log::{debug, error, info, trace, warn};
+use tokio::net::UnixStream;
+use tokio::sync::RwLock;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+use api::image_service_client::ImageServiceClient;
+use api::runtime_service_client::RuntimeServiceClient;
+use cri_runtime::{container_handle, ContainerStopper, LogHandleFactory};
+
+/// The default location of the containerd CRI socket
+pub const DEFAULT_RUNTIME_ENDPOINT: &str = "/run/containerd/containerd.sock";
+
+/// The directory in the data directory logs were kept in before they were kept with the
+/// rest of each pod's data
+const LEGACY_LOG_DIR: &str = "cri-logs";
+
+/// The architecture of the node, named the way Kubernetes names it
+#[cfg(target_arch = "x86_64")]
+const ARCH_NATIVE: &str = "amd64";
+#[cfg(target_arch = "aarch64")]
+const ARCH_NATIVE: &str = "arm64";
+#[cfg(target_arch = "arm")]
+const ARCH_NATIVE: &str = "arm";
+#[cfg(all(target_arch = "powerpc64", target_endian = "little"))]
+const ARCH_NATIVE: &str = "ppc64le";
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "arm",
+    all(target_arch = "powerpc64", target_endian = "little")
+)))]
+const ARCH_NATIVE: &str = std::env::consts::ARCH;
+
+/// A running pod and the sandbox its containers were started in
+struct SandboxedPod {
+    sandbox_id: String,
+    handle: PodHandle<ContainerStopper, LogHandleFactory>,
+}
+
+/// CriProvider provides a Kubelet runtime implementation that runs pods in a CRI
+/// compatible container runtime
+#[derive(Clone)]
+pub struct CriProvider {
+    handles: Arc<RwLock<HashMap<String, SandboxedPod>>>,
+    runtime: RuntimeServiceClient<Channel>,
+    images: ImageServiceClient<Channel>,
+    runtime_version: String,
+    /// Where the runtime writes the logs of each pod for node level log shippers. When
+    /// this is not set, they are written to the pod's logs directory
+    log_path: Option<PathBuf>,
+    client: kube::Client,
+    /// The Kubelet's data directory, which holds the volumes and logs of each pod
+    data_dir: PathBuf,
+}
+
+impl CriProvider {
+    /// Create a new CRI provider connected to the runtime listening on the given unix socket
+    pub async fn new<P: AsRef<Path>>(
+        endpoint: P,
+        config: &kubelet::config::Config,
+        client: kube::Client,
+    ) -> anyhow::Result<Self> {
+        // The runtime writes logs in the CRI format, so they go straight where log
+        // shippers look for them. Without permission to write there, such as when not
+        // running as root, they go to the pod's data directory instead
+        let log_path = match &config.pod_log_dir {
+            Some(dir) => match tokio::fs::create_dir_all(dir).await {
+                Ok(()) => Some(dir.clone()),
+                Err(e) => {
+                    warn!(
+                        "Unable to create pod log directory {}, writing container logs to the data directory of each pod: {}",
+                        dir.display(),
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        tokio::fs::create_dir_all(&config.data_dir).await?;
+        remove_legacy_dirs(&config.data_dir, &[LEGACY_VOLUMES_DIR, LEGACY_LOG_DIR]).await;
+
+        // The URI is required by tonic but is ignored because the connector always
+        // dials the socket
+        let socket = endpoint.as_ref().to_path_buf();
+        let channel = Endpoint::from_static("http://[::]:50051")
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                UnixStream::connect(socket.clone())
+            }))
+            .await?;
+        let mut runtime = RuntimeServiceClient::new(channel.clone());
+        let version = runtime
+            .version(api::VersionRequest {
+                version: "v1alpha2".to_owned(),
+            })
+            .await?
+            .into_inner();
+        info!(
+            "Connected to CRI runtime {} {}",
+            version.runtime_name, version.runtime_version
+        );
+
+        Ok(Self {
+            handles: Default::default(),
+            runtime,
+            images: ImageServiceClient::new(channel),
+            runtime_version: format!("{}://{}", version.runtime_name, version.runtime_version),
+            log_path,
+            client,
+            data_dir: config.data_dir.clone(),
+        })
+    }
+
+    /// Creates and starts a single container in the given sandbox, returning the runtime ID
+    /// of the container and the location of its logs
+    async fn start_container(
+        &self,
+        pod: &Pod,
+        container: &Container,
+        sandbox_id: &str,
+        sandbox_config: &api::PodSandboxConfig,
+    ) -> anyhow::Result<(String, PathBuf)> {
+        let image = container.spec().image.clone().ok_or_else(|| {
+            anyhow::anyhow!("container {} does not have an image", container.name())
+        })?;
+        let image_spec = api::ImageSpec {
+            image: image.clone(),
+            annotations: HashMap::new(),
+        };
+        debug!("Pulling image {} for container {}", image, container.name());
+        let mut images = self.images.clone();
+        let pull = images.pull_image(api::PullImageRequest {
+            image: Some(image_spec.clone()),
+            auth: None,
+            sandbox_config: Some(sandbox_config.clone()),
+        });
+        pod.concurrency()
+            .run(Operation::ImagePull, pull)
+            .await
+            .map_err(|e| PodSyncError::ImagePull {
+                image,
+                source: e.into(),
+            })?;
+
+        let security_context = container.security_context();
+        let log_file = Path::new(container.name()).join(pod_logs::LOG_FILE);
+        tokio::fs::create_dir_all(Path::new(&sandbox_config.log_directory).join(container.name()))
+            .await?;
+        let config = api::ContainerConfig {
+            metadata: Some(api::ContainerMetadata {
+                name: container.name().to_owned(),
+                attempt: 0,
+            }),
+            image: Some(image_spec),
+            command: container.spec().command.clone().unwrap_or_default(),
+            args: container.spec().args.clone().unwrap_or_default(),
+            working_dir: container.spec().working_dir.clone().unwrap_or_default(),
+            envs: container
+                .env()
+                .iter()
+                .map(|(key, value)| api::KeyValue {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            mounts: container
+                .volume_mounts()
+                .iter()
+                .map(|vm| api::Mount {
+                    container_path: vm.guest_path.to_string_lossy().into_owned(),
+                    host_path: vm.host_path.to_string_lossy().into_owned(),
+                    readonly: vm.read_only,
+                })
+                .collect(),
+            log_path: log_file.to_string_lossy().into_owned(),
+            stdin: container.spec().stdin.unwrap_or(false),
+            stdin_once: container.spec().stdin_once.unwrap_or(false),
+            tty: container.spec().tty.unwrap_or(false),
+            linux: Some(api::LinuxContainerConfig {
+                security_context: Some(api::LinuxContainerSecurityContext {
+                    privileged: security_context.privileged,
+                    run_as_user: security_context
+                        .run_as_user
+                        .map(|value| api::Int64Value { value }),
+                    readonly_rootfs: security_context.read_only_root_filesystem,
+                    run_as_group: security_context
+                        .run_as_group
+                        .map(|value| api::Int64Value { value }),
+                }),
+            }),
+            ..Default::default()
+        };
+
+        let mut runtime = self.runtime.clone();
+        let container_id = runtime
+            .create_container(api::CreateContainerRequest {
+                pod_sandbox_id: sandbox_id.to_owned(),
+                config: Some(config),
+                sandbox_config: Some(sandbox_config.clone()),
+            })
+            .await?
+            .into_inner()
+            .container_id;
+        debug!(
+            "Starting container {} with ID {}",
+            container.name(),
+            container_id
+        );
+        runtime
+            .start_container(api::StartContainerRequest {
+                container_id: container_id.clone(),
+            })
+            .await?;
+        Ok((
+            container_id,
+            PathBuf::from(&sandbox_config.log_directory).join(log_file),
+        ))
+    }
+
+    /// Stops and removes the given sandbox along with all of its containers
+    async fn remove_sandbox(&self, sandbox_id: &str) -> anyhow::Result<()> {
+        let mut runtime = self.runtime.clone();
+        runtime
+            .stop_pod_sandbox(api::StopPodSandboxRequest {
+                pod_sandbox_id: sandbox_id.to_owned(),
+            })
+            .await?;
+        runtime
+            .remove_pod_sandbox(api::RemovePodSandboxRequest {
+                pod_sandbox_id: sandbox_id.to_owned(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    fn sandbox_config(&self, pod: &Pod) -> api::PodSandboxConfig {
+        let uid = pod.uid().unwrap_or_default();
+        api::PodSandboxConfig {
+            metadata: Some(api::PodSandboxMetadata {
+                name: pod.name().to_owned(),
+                uid: uid.to_owned(),
+                namespace: pod.namespace().to_owned(),
+                attempt: 0,
+            }),
+            hostname: pod.hostname(),
+            log_directory: match &self.log_path {
+                Some(dir) => pod_logs::pod_log_dir(dir, pod),
+                None => pod.data_dirs(&self.data_dir).logs(),
+            }
+            .to_string_lossy()
+            .into_owned(),
+            labels: pod
+                .labels()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            annotations: pod
+                .annotations()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Provider for CriProvider {
+    const ARCH: &'static str = ARCH_NATIVE;
+
+    async fn node(&self, builder: &mut NodeBuilder) -> anyhow::Result<()> {
+        builder.set_container_runtime_version(&self.runtime_version);
+        Ok(())
+    }
+
+    async fn add(&self, pod: Pod) -> anyhow::Result<()> {
+        let pod_name = pod.name();
+        let client = self.client.clone();
+        let volumes = VolumeRef::volumes_from_pod(&self.data_dir, &pod, &client).await?;
+        let containers = Container::resolve_all::<Self>(&pod, &client, &volumes).await?;
+
+        let sandbox_config = self.sandbox_config(&pod);
+        tokio::fs::create_dir_all(&sandbox_config.log_directory).await?;
+        info!("Running sandbox for pod {:?}", pod_name);
+        let sandbox_id = self
+            .runtime
+            .clone()
+            .run_pod_sandbox(api::RunPodSandboxRequest {
+                config: Some(sandbox_config.clone()),
+                runtime_handler: String::new(),
+            })
+            .await?
+            .into_inner()
+            .pod_sandbox_id;
+
+        let grace_period = pod.termination_grace_period_seconds();
+        let mut container_handles = HashMap::new();
+        info!("Starting containers for pod {:?}", pod_name);
+        for container in containers {
+            let (container_id, log_path) = match self
+                .start_container(&pod, &container, &sandbox_id, &sandbox_config)
+                .await
+            {
+                Ok(c) => c,
+                Err(e) => {
+                    // Don't leave a half started pod behind in the runtime
+                    if let Err(e) = self.remove_sandbox(&sandbox_id).await {
+                        error!("Unable to remove sandbox {}: {}", sandbox_id, e);
+                    }
+                    return Err(e);
+                }
+            };
+            let handle =
+                container_handle(self.runtime.clone(), container_id, log_path, grace_period);
+            container_handles.insert(container.name().to_owned(), handle);
+        }
+        info!(
+            "All containers started for pod {:?}. Updating status",
+            pod_name
+        );
+
+        let mut handles = self.handles.write().await;
+        handles.insert(
+            key_from_pod(&pod),
+            SandboxedPod {
+                sandbox_id,
+                handle: PodHandle::new(container_handles, pod, client, Some(volumes))?,
+            },
+        );
+        Ok(())
+    }
+
+    async fn modify(&self, pod: Pod) -> anyhow::Result<()> {
+        debug!(
+            "Got pod modified event for {} in namespace {}",
+            pod.name(),
+            pod.namespace()
+        );
+        trace!("Modified pod spec: {:#?}", pod.as_kube_pod());
+        if pod.deletion_timestamp().is_none() {
+            return Ok(());
+        }
+        let mut handles = self.handles.write().await;
+        match handles.get_mut(&key_from_pod(&pod)) {
+            // The kubelet reports the terminated statuses and removes the pod once
+            // everything is stopped
+            Some(p) => p.handle.stop().await,
+            None => {
+                error!(
+                    "Unable to find pod {} in namespace {} when trying to stop all containers",
+                    pod.name(),
+                    pod.namespace()
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn delete(&self, pod: Pod) -> anyhow::Result<()> {
+        let removed = self.handles.write().await.remove(&key_from_pod(&pod));
+        match removed {
+            Some(p) => {
+                self.remove_sandbox(&p.sandbox_id).await?;
+                debug!(
+                    "Pod {} in namespace {} removed",
+                    pod.name(),
+                    pod.namespace()
+                );
+            }
+            None => info!(
+                "unable to find pod {} in namespace {}, it was likely already deleted",
+                pod.name(),
+                pod.namespace()
+            ),
+        }
+        Ok(())
+    }
+
+    async fn logs(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        sender: kubelet::LogSender,
+    ) -> anyhow::Result<()> {
+        let mut handles = self.handles.write().await;
+        let pod = handles
+            .get_mut(&pod_key(&namespace, &pod_name))
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        pod.handle.output(&container_name, sender).await
+    }
+}
@@ -0,0 +1,273 @@
+//! Macros that write the repetitive parts of Kubelet providers
+//!
+//! The macros are re-exported by the `kubelet` crate from `kubelet::provider::sdk`,
+//! which documents how to use them, so providers depend on `kubelet` rather than on
+//! this crate.
+
+#![deny(missing_docs)]
+
+extern crate proc_macro;
+
+use std::collections::HashSet;
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{parse_quote, AttributeArgs, ImplItem, ItemImpl, Lit, LitStr, Meta, NestedMeta};
+
+/// Fill in the parts of a `kubelet::Provider` implementation that most providers write
+/// the same way. See `kubelet::provider::sdk` for the arguments
+#[proc_macro_attribute]
+pub fn provider(
+    args: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let args = syn::parse_macro_input!(args as AttributeArgs);
+    let item = syn::parse_macro_input!(item as ItemImpl);
+    match expand(args, item) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// The arguments of `#[provider(...)]`
+#[derive(Debug, Default)]
+struct Options {
+    /// The architecture the provider sets as its `ARCH`
+    arch: Option<LitStr>,
+    /// Whether pods are added, modified and deleted through the provider's state machine
+    states: bool,
+    /// Whether logs are served from the provider's pod handles
+    handles: bool,
+    /// Whether images are prefetched into the provider's module store
+    modules: bool,
+}
+
+impl Options {
+    fn parse(args: AttributeArgs) -> syn::Result<Self> {
+        let mut options = Options::default();
+        for arg in args {
+            match arg {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("arch") => match nv.lit {
+                    Lit::Str(arch) => options.arch = Some(arch),
+                    lit => {
+                        return Err(syn::Error::new_spanned(
+                            lit,
+                            "the architecture must be a string, such as \"wasm32-wasi\"",
+                        ))
+                    }
+                },
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("states") => {
+                    options.states = true
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("handles") => {
+                    options.handles = true
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("modules") => {
+                    options.modules = true
+                }
+                arg => {
+                    return Err(syn::Error::new_spanned(
+                        arg,
+                        "expected `arch = \"...\"`, `states`, `handles` or `modules`",
+                    ))
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Add the items the options ask for to the implementation, leaving out those it
+/// already has
+fn expand(args: AttributeArgs, mut item: ItemImpl) -> syn::Result<TokenStream> {
+    let options = Options::parse(args)?;
+    if item.trait_.is_none() {
+        return Err(syn::Error::new_spanned(
+            &item.self_ty,
+            "#[provider] goes on an `impl Provider for ...` block",
+        ));
+    }
+    let defined: HashSet<String> = item
+        .items
+        .iter()
+        .filter_map(|i| match i {
+            ImplItem::Method(method) => Some(method.sig.ident.to_string()),
+            ImplItem::Const(constant) => Some(constant.ident.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    match (options.arch, defined.contains("ARCH")) {
+        (Some(arch), false) => item
+            .items
+            .push(parse_quote!(const ARCH: &'static str = #arch;)),
+        (Some(arch), true) => {
+            return Err(syn::Error::new_spanned(
+                arch,
+                "the provider sets ARCH already",
+            ))
+        }
+        (None, true) => (),
+        (None, false) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "set the provider's architecture with `arch = \"...\"`",
+            ))
+        }
+    }
+
+    let sdk = quote!(::kubelet::provider::sdk);
+    let result = quote!(#sdk::__private::Result);
+    let mut generated: Vec<(&str, ImplItem)> = Vec::new();
+    if options.states {
+        generated.push((
+            "add",
+            parse_quote! {
+                async fn add(&self, pod: ::kubelet::Pod) -> #result<()> {
+                    #sdk::StateMachineProvider::state_machine(self).add(self, pod).await
+                }
+            },
+        ));
+        generated.push((
+            "modify",
+            parse_quote! {
+                async fn modify(&self, pod: ::kubelet::Pod) -> #result<()> {
+                    #sdk::StateMachineProvider::state_machine(self).modify(self, pod).await
+                }
+            },
+        ));
+        generated.push((
+            "delete",
+            parse_quote! {
+                async fn delete(&self, pod: ::kubelet::Pod) -> #result<()> {
+                    #sdk::StateMachineProvider::state_machine(self).delete(self, pod).await
+                }
+            },
+        ));
+    }
+    if options.handles {
+        generated.push((
+            "logs",
+            parse_quote! {
+                async fn logs(
+                    &self,
+                    namespace: String,
+                    pod: String,
+                    container: String,
+                    sender: ::kubelet::LogSender,
+                ) -> #result<()> {
+                    #sdk::HandleProvider::pod_logs(self, namespace, pod, container, sender).await
+                }
+            },
+        ));
+    }
+    if options.modules {
+        generated.push((
+            "prefetch",
+            parse_quote! {
+                async fn prefetch(&self, image: &str) -> #result<()> {
+                    #sdk::ModuleProvider::prefetch_module(self, image).await
+                }
+            },
+        ));
+    }
+    item.items.extend(
+        generated
+            .into_iter()
+            .filter(|(name, _)| !defined.contains(*name))
+            .map(|(_, method)| method),
+    );
+
+    let has_async_trait = item.attrs.iter().any(
+        |attr| matches!(attr.path.segments.last(), Some(segment) if segment.ident == "async_trait"),
+    );
+    if !has_async_trait {
+        item.attrs
+            .push(parse_quote!(#[#sdk::__private::async_trait]));
+    }
+    Ok(quote!(#item))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expand_str(args: TokenStream, item: TokenStream) -> syn::Result<String> {
+        let args: syn::punctuated::Punctuated<NestedMeta, syn::Token![,]> =
+            syn::parse::Parser::parse2(syn::punctuated::Punctuated::parse_terminated, args)?;
+        let item: ItemImpl = syn::parse2(item)?;
+        expand(args.into_iter().collect(), item).map(|tokens| tokens.to_string())
+    }
+
+    fn methods(expanded: &str) -> Vec<String> {
+        let item: ItemImpl = syn::parse_str(expanded).unwrap();
+        item.items
+            .iter()
+            .filter_map(|i| match i {
+                ImplItem::Method(method) => Some(method.sig.ident.to_string()),
+                ImplItem::Const(constant) => Some(constant.ident.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_generated_items() {
+        let expanded = expand_str(
+            quote!(arch = "wasm32-wasi", states, handles),
+            quote! {
+                impl Provider for MyProvider {
+                    async fn modify(&self, pod: Pod) -> anyhow::Result<()> {
+                        todo!()
+                    }
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["modify", "ARCH", "add", "delete", "logs"],
+            methods(&expanded)
+        );
+        assert!(expanded.contains("\"wasm32-wasi\""));
+        assert!(expanded.contains("__private :: async_trait"));
+        assert!(!expanded.contains("prefetch"));
+
+        // Implementations that use #[async_trait] already don't get it twice
+        let expanded = expand_str(
+            quote!(modules),
+            quote! {
+                #[async_trait::async_trait]
+                impl Provider for MyProvider {
+                    const ARCH: &'static str = "my-arch";
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(vec!["ARCH", "prefetch"], methods(&expanded));
+        assert!(!expanded.contains("__private :: async_trait"));
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let item = quote!(impl Provider for MyProvider {});
+        let error = |args| expand_str(args, item.clone()).unwrap_err().to_string();
+        assert_eq!(
+            "set the provider's architecture with `arch = \"...\"`",
+            error(quote!(states))
+        );
+        assert_eq!(
+            "the architecture must be a string, such as \"wasm32-wasi\"",
+            error(quote!(arch = 32))
+        );
+        assert_eq!(
+            "expected `arch = \"...\"`, `states`, `handles` or `modules`",
+            error(quote!(arch = "my-arch", logs))
+        );
+        assert_eq!(
+            "#[provider] goes on an `impl Provider for ...` block",
+            expand_str(quote!(arch = "my-arch"), quote!(impl MyProvider {}))
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
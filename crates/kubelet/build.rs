@@ -0,0 +1,51 @@
+//! Records what the Kubelet is built from for `kubelet::version`.
+//!
+//! The git commit and build date can be set with the `KRUSTLET_GIT_SHA` and
+//! `SOURCE_DATE_EPOCH` environment variables, for builds outside of a git checkout or
+//! that should be reproducible.
+use std::path::Path;
+use std::process::Command;
+
+use chrono::{SecondsFormat, TimeZone, Utc};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=KRUSTLET_GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let git_sha = std::env::var("KRUSTLET_GIT_SHA")
+        .ok()
+        .or_else(|| git(&["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=KRUSTLET_GIT_SHA={}", git_sha);
+
+    // Build again when the checkout moves to another commit
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        let git_dir = Path::new(&git_dir);
+        println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+        if let Some(head) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}", git_dir.join(head).display());
+        }
+    }
+
+    let build_date = match std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+    {
+        Some(epoch) => Utc.timestamp(epoch, 0),
+        None => Utc::now(),
+    };
+    println!(
+        "cargo:rustc-env=KRUSTLET_BUILD_DATE={}",
+        build_date.to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+}
+
+/// Run git with the arguments, returning its output if it succeeded
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_owned()).filter(|o| !o.is_empty())
+}
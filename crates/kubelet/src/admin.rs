@@ -0,0 +1,666 @@
+//! A local admin API for node operators and debugging tools
+//!
+//! When [`Config::admin_socket`](crate::config::Config::admin_socket) is set, the
+//! Kubelet serves a small JSON API on a unix socket at that path, so the pods it is
+//! handling can be inspected and nudged without crafting requests to the Kubernetes
+//! API. The socket is only readable and writable by the user the Kubelet runs as,
+//! which is all the authentication the API has. Its requests to the API server are
+//! made under the Kubelet's [rate limits](crate::rate_limit):
+//!
+//! - `GET /pods` lists the pods the Kubelet knows, with their state and last error
+//! - `GET /queue` counts the pods in each state
+//! - `POST /pods/{namespace}/{name}/resync` fetches a pod from the API server and passes
+//!   it to the provider again. Pods that failed to start are started again
+//! - `POST /drain` cordons the node and evicts its pods like `kubectl drain`, leaving
+//!   out pods of daemon sets and mirror pods of static pods, then waits for the evicted
+//!   pods to be gone. It fails with `503 Service Unavailable` if any pods could not be
+//!   evicted, for example because of a disruption budget, or are still terminating
+//!   after five minutes, and can be retried
+//! - `POST /uncordon` makes the node schedulable again
+//!
+//! ```text
+//! curl --unix-socket /var/run/krustlet/admin.sock -X POST http://localhost/drain
+//! ```
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use k8s_openapi::api::core::v1::{Node as KubeNode, Pod as KubePod};
+use kube::api::{ListParams, PatchParams, WatchEvent};
+use kube::Api;
+use log::{info, warn};
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::pod::Pod;
+use crate::rate_limit::RateLimits;
+use crate::registry::PodRegistry;
+use crate::state::State;
+
+/// The annotation the API server sets on the mirror pods of static pods
+const MIRROR_ANNOTATION: &str = "kubernetes.io/config.mirror";
+
+/// How long a drain waits for the evicted pods to be gone
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often a drain checks whether the evicted pods are gone
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the admin API works with
+pub(crate) struct Admin {
+    client: kube::Client,
+    limits: RateLimits,
+    registry: PodRegistry,
+    node_name: String,
+    /// Where resynced pods are sent, to be queued like events from the pod watch
+    resyncs: mpsc::Sender<WatchEvent<KubePod>>,
+    drain_timeout: Duration,
+}
+
+impl Admin {
+    pub(crate) fn new(
+        client: kube::Client,
+        limits: RateLimits,
+        registry: PodRegistry,
+        node_name: String,
+        resyncs: mpsc::Sender<WatchEvent<KubePod>>,
+    ) -> Self {
+        Admin {
+            client,
+            limits,
+            registry,
+            node_name,
+            resyncs,
+            drain_timeout: DRAIN_TIMEOUT,
+        }
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let path: Vec<&str> = req.uri().path().split('/').collect();
+        match (req.method(), path.as_slice()) {
+            (&Method::GET, [_, "pods"]) => self.get_pods(),
+            (&Method::GET, [_, "queue"]) => self.get_queue(),
+            (&Method::POST, [_, "pods", namespace, name, "resync"]) => {
+                self.resync(namespace, name).await
+            }
+            (&Method::POST, [_, "drain"]) => self.drain().await,
+            (&Method::POST, [_, "uncordon"]) => match self.set_unschedulable(false).await {
+                Ok(()) => json_response(StatusCode::OK, json!({ "unschedulable": false })),
+                Err(e) => error_response(StatusCode::BAD_GATEWAY, e),
+            },
+            _ => error_response(StatusCode::NOT_FOUND, "Not Found"),
+        }
+    }
+
+    /// Implements `GET /pods`
+    fn get_pods(&self) -> Response<Body> {
+        let pods: Vec<_> = self
+            .registry
+            .pods()
+            .into_iter()
+            .map(|entry| {
+                json!({
+                    "namespace": entry.pod.namespace(),
+                    "name": entry.pod.name(),
+                    "uid": entry.pod.uid(),
+                    "state": format!("{:?}", entry.state),
+                    "since": entry.since,
+                    "error": entry.error,
+                })
+            })
+            .collect();
+        json_response(StatusCode::OK, json!({ "pods": pods }))
+    }
+
+    /// Implements `GET /queue`
+    fn get_queue(&self) -> Response<Body> {
+        let pods = self.registry.pods();
+        let mut states = BTreeMap::new();
+        for entry in &pods {
+            *states.entry(format!("{:?}", entry.state)).or_insert(0) += 1;
+        }
+        let failing = pods.iter().filter(|entry| entry.error.is_some()).count();
+        json_response(
+            StatusCode::OK,
+            json!({ "pods": pods.len(), "states": states, "failing": failing }),
+        )
+    }
+
+    /// Implements `POST /pods/{namespace}/{name}/resync`
+    async fn resync(&self, namespace: &str, name: &str) -> Response<Body> {
+        // Only pods the Kubelet already handles are resynced, so pods the pod watch
+        // filters out are never started
+        let entry = match self.registry.get(namespace, name) {
+            Some(entry) => entry,
+            None => {
+                return error_response(
+                    StatusCode::NOT_FOUND,
+                    format!("the Kubelet is not handling pod {}/{}", namespace, name),
+                )
+            }
+        };
+        let api: Api<KubePod> = Api::namespaced(self.client.clone(), namespace);
+        let pod = match self.limits.limited(api.get(name)).await {
+            Ok(pod) => pod,
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                return error_response(
+                    StatusCode::NOT_FOUND,
+                    format!("pod {}/{} no longer exists", namespace, name),
+                )
+            }
+            Err(e) => return error_response(StatusCode::BAD_GATEWAY, e),
+        };
+        // Pods that failed to start, or are waiting to be started again after failing,
+        // are added again. Adding pods that are starting or running would start them twice
+        let retry_start =
+            entry.state == State::Error || (entry.error.is_some() && entry.state != State::Running);
+        let (event, kind) = if retry_start {
+            (WatchEvent::Added(pod), "Added")
+        } else {
+            (WatchEvent::Modified(pod), "Modified")
+        };
+        if self.resyncs.clone().send(event).await.is_err() {
+            return error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the Kubelet is not watching pods",
+            );
+        }
+        info!(
+            "Resyncing pod {} in namespace {} on request of the admin API",
+            name, namespace
+        );
+        json_response(
+            StatusCode::ACCEPTED,
+            json!({ "namespace": namespace, "name": name, "event": kind }),
+        )
+    }
+
+    /// Implements `POST /drain`
+    async fn drain(&self) -> Response<Body> {
+        if let Err(e) = self.set_unschedulable(true).await {
+            return error_response(StatusCode::BAD_GATEWAY, e);
+        }
+        info!(
+            "Draining node {} on request of the admin API",
+            self.node_name
+        );
+        let api: Api<KubePod> = Api::all(self.client.clone());
+        let params = ListParams {
+            field_selector: Some(format!("spec.nodeName={}", self.node_name)),
+            ..Default::default()
+        };
+        let pods = match self.limits.limited(api.list(&params)).await {
+            Ok(pods) => pods,
+            Err(e) => return error_response(StatusCode::BAD_GATEWAY, e),
+        };
+
+        let mut evicted = vec![];
+        // Pods that are deleted already, maybe by an earlier drain, are waited for too
+        let mut deleting = vec![];
+        let mut skipped = vec![];
+        let mut failed = vec![];
+        for pod in pods.items.into_iter().map(Pod::new) {
+            let key = format!("{}/{}", pod.namespace(), pod.name());
+            if pod.deletion_timestamp().is_some() {
+                deleting.push(pod);
+                continue;
+            }
+            // Daemon sets would put their pods straight back on the node, and mirror
+            // pods can't be evicted, as they only reflect static pods
+            if pod
+                .owner_references()
+                .iter()
+                .any(|owner| owner.kind == "DaemonSet")
+                || pod.annotations().contains_key(MIRROR_ANNOTATION)
+            {
+                skipped.push(key);
+                continue;
+            }
+            match self.limits.limited(evict(&self.client, &pod)).await {
+                Ok(()) => evicted.push(pod),
+                Err(e) => {
+                    warn!("Unable to evict pod {}: {}", key, e);
+                    failed.push(json!({ "pod": key, "error": e.to_string() }));
+                }
+            }
+        }
+        deleting.extend(evicted.iter().cloned());
+        let terminating = match self.wait_until_gone(deleting).await {
+            Ok(terminating) => terminating,
+            Err(e) => return error_response(StatusCode::BAD_GATEWAY, e),
+        };
+        let status = if failed.is_empty() && terminating.is_empty() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let keys = |pods: &[Pod]| -> Vec<String> {
+            pods.iter()
+                .map(|pod| format!("{}/{}", pod.namespace(), pod.name()))
+                .collect()
+        };
+        json_response(
+            status,
+            json!({
+                "unschedulable": true,
+                "evicted": keys(&evicted),
+                "terminating": keys(&terminating),
+                "skipped": skipped,
+                "failed": failed,
+            }),
+        )
+    }
+
+    /// Wait until the given pods are deleted, or replaced by pods of the same name,
+    /// returning the pods that are still terminating when the drain times out
+    async fn wait_until_gone(&self, mut terminating: Vec<Pod>) -> anyhow::Result<Vec<Pod>> {
+        let deadline = Instant::now() + self.drain_timeout;
+        loop {
+            let mut remaining = vec![];
+            for pod in terminating {
+                let api: Api<KubePod> = Api::namespaced(self.client.clone(), pod.namespace());
+                match self.limits.limited(api.get(pod.name())).await {
+                    Ok(current) => {
+                        let uid = current.metadata.as_ref().and_then(|m| m.uid.as_deref());
+                        if uid == pod.uid() {
+                            remaining.push(pod);
+                        }
+                    }
+                    Err(kube::Error::Api(e)) if e.code == 404 => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if remaining.is_empty() || Instant::now() >= deadline {
+                return Ok(remaining);
+            }
+            terminating = remaining;
+            tokio::time::delay_for(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Cordon or uncordon the node
+    async fn set_unschedulable(&self, unschedulable: bool) -> anyhow::Result<()> {
+        let api: Api<KubeNode> = Api::all(self.client.clone());
+        let patch = json!({ "spec": { "unschedulable": unschedulable } });
+        let patch = serde_json::to_vec(&patch)?;
+        self.limits
+            .limited(api.patch(&self.node_name, &PatchParams::default(), patch))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Evict a pod through the Eviction API, which respects its disruption budgets
+async fn evict(client: &kube::Client, pod: &Pod) -> anyhow::Result<()> {
+    let eviction = json!({
+        "apiVersion": "policy/v1beta1",
+        "kind": "Eviction",
+        "metadata": { "name": pod.name(), "namespace": pod.namespace() },
+    });
+    let request = hyper::Request::post(format!(
+        "/api/v1/namespaces/{}/pods/{}/eviction",
+        pod.namespace(),
+        pod.name()
+    ))
+    .header(hyper::header::CONTENT_TYPE, "application/json")
+    .body(serde_json::to_vec(&eviction)?)?;
+    match client.request_text(request).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(e)) if e.code == 429 => Err(anyhow::anyhow!(
+            "the eviction would violate a disruption budget: {}",
+            e.message
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, error: impl std::fmt::Display) -> Response<Body> {
+    json_response(status, json!({ "error": error.to_string() }))
+}
+
+/// Serve the admin API on the unix socket at the given path, or do nothing if there is
+/// none
+#[cfg(unix)]
+pub(crate) async fn serve(path: Option<PathBuf>, admin: Admin) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use std::sync::Arc;
+
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let parent = match path.parent() {
+        Some(parent) if parent != std::path::Path::new("") => parent.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    tokio::fs::create_dir_all(&parent).await?;
+    let mut listener =
+        bind_private(&path, &parent).with_context(|| format!("Could not listen on {:?}", path))?;
+    info!("Serving the admin API at {:?}", path);
+
+    let admin = Arc::new(admin);
+    loop {
+        let (conn, _) = listener.accept().await?;
+        let admin = admin.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let admin = admin.clone();
+                async move { Ok::<_, std::convert::Infallible>(admin.handle(req).await) }
+            });
+            if let Err(e) = Http::new().serve_connection(conn, service).await {
+                warn!("Error handling admin API connection: {}", e);
+            }
+        });
+    }
+}
+
+/// Bind a unix socket at the given path that only the Kubelet's user can connect to.
+/// The socket is bound inside a directory only that user can enter and made private
+/// there, then moved into place, so there is no moment where others could connect
+#[cfg(unix)]
+fn bind_private(
+    path: &std::path::Path,
+    parent: &std::path::Path,
+) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "the path has no file name",
+        )
+    })?;
+    let private = parent.join(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    // A directory left behind by an earlier run with the same process ID is replaced
+    match std::fs::remove_dir_all(&private) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = (|| -> std::io::Result<_> {
+        let socket = private.join(file_name);
+        let listener = tokio::net::UnixListener::bind(&socket)?;
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600))?;
+        // Replaces a socket left behind by an earlier run
+        std::fs::rename(&socket, path)?;
+        Ok(listener)
+    })();
+    let removed = std::fs::remove_dir_all(&private);
+    let listener = bound?;
+    removed?;
+    Ok(listener)
+}
+
+/// The admin API needs unix sockets, so it is not served on other platforms
+#[cfg(not(unix))]
+pub(crate) async fn serve(path: Option<PathBuf>, _admin: Admin) -> anyhow::Result<()> {
+    if let Some(path) = path {
+        warn!(
+            "Not serving the admin API at {:?}, as unix sockets are not supported on this platform",
+            path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+
+    const NODE_NAME: &str = "krustlet";
+
+    fn admin(server: &MockApiServer) -> (Admin, mpsc::Receiver<WatchEvent<KubePod>>) {
+        let (resyncs, received) = mpsc::channel(4);
+        let admin = Admin::new(
+            server.client(),
+            RateLimits::default(),
+            PodRegistry::default(),
+            NODE_NAME.to_owned(),
+            resyncs,
+        );
+        (admin, received)
+    }
+
+    fn scheduled_pod(name: &str) -> KubePod {
+        let mut pod = fake_pod(name, "default");
+        pod.spec.as_mut().unwrap().node_name = Some(NODE_NAME.to_owned());
+        pod
+    }
+
+    async fn request(admin: &Admin, method: Method, path: &str) -> (StatusCode, serde_json::Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = admin.handle(req).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_pods_and_queue() {
+        let server = MockApiServer::start().await.unwrap();
+        let (admin, _) = admin(&server);
+        let running: Pod = scheduled_pod("running").into();
+        let failed: Pod = scheduled_pod("failed").into();
+        admin.registry.set_state(&running, State::Running);
+        admin.registry.set_state(&failed, State::Error);
+        admin
+            .registry
+            .set_error(&failed, Some("no runtime".to_owned()));
+
+        let (status, body) = request(&admin, Method::GET, "/pods").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("failed", body["pods"][0]["name"]);
+        assert_eq!("default-failed-uid", body["pods"][0]["uid"]);
+        assert_eq!("Error", body["pods"][0]["state"]);
+        assert_eq!("no runtime", body["pods"][0]["error"]);
+        assert_eq!("Running", body["pods"][1]["state"]);
+        assert!(body["pods"][1]["error"].is_null());
+
+        let (status, body) = request(&admin, Method::GET, "/queue").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            json!({ "pods": 2, "states": { "Error": 1, "Running": 1 }, "failing": 1 }),
+            body
+        );
+
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            request(&admin, Method::GET, "/nope").await.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resync() {
+        let server = MockApiServer::start().await.unwrap();
+        let (admin, mut received) = admin(&server);
+        for name in &["running", "failed"] {
+            server.insert("/api/v1/namespaces/default/pods", scheduled_pod(name));
+        }
+        admin
+            .registry
+            .set_state(&scheduled_pod("running").into(), State::Running);
+        admin
+            .registry
+            .set_state(&scheduled_pod("failed").into(), State::Error);
+
+        let (status, body) = request(&admin, Method::POST, "/pods/default/running/resync").await;
+        assert_eq!(StatusCode::ACCEPTED, status);
+        assert_eq!("Modified", body["event"]);
+        assert!(matches!(
+            received.recv().await,
+            Some(WatchEvent::Modified(_))
+        ));
+
+        let (status, body) = request(&admin, Method::POST, "/pods/default/failed/resync").await;
+        assert_eq!(StatusCode::ACCEPTED, status);
+        assert_eq!("Added", body["event"]);
+        match received.recv().await {
+            Some(WatchEvent::Added(pod)) => {
+                assert_eq!(Some("failed"), pod.metadata.unwrap().name.as_deref())
+            }
+            e => panic!("unexpected event: {:?}", e),
+        }
+
+        // Pods the Kubelet doesn't handle, or that are gone, are not resynced
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            request(&admin, Method::POST, "/pods/default/other/resync")
+                .await
+                .0
+        );
+        server.remove("/api/v1/namespaces/default/pods/running");
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            request(&admin, Method::POST, "/pods/default/running/resync")
+                .await
+                .0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drain_and_uncordon() {
+        let server = MockApiServer::start().await.unwrap();
+        let (admin, _) = admin(&server);
+        server.insert(
+            "/api/v1/nodes",
+            json!({ "metadata": { "name": NODE_NAME }, "spec": {} }),
+        );
+        server.insert("/api/v1/namespaces/default/pods", scheduled_pod("app"));
+        let mut daemon = scheduled_pod("daemon");
+        daemon.metadata.as_mut().unwrap().owner_references = Some(vec![OwnerReference {
+            api_version: "apps/v1".to_owned(),
+            kind: "DaemonSet".to_owned(),
+            name: "daemon".to_owned(),
+            uid: "daemon-uid".to_owned(),
+            ..Default::default()
+        }]);
+        server.insert("/api/v1/namespaces/default/pods", daemon);
+        let mut mirror = scheduled_pod("mirror");
+        mirror
+            .metadata
+            .as_mut()
+            .unwrap()
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(MIRROR_ANNOTATION.to_owned(), "hash".to_owned());
+        server.insert("/api/v1/namespaces/default/pods", mirror);
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("elsewhere", "default"),
+        );
+
+        // The drain waits for the evicted pod to be gone
+        let removing = server.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+            removing.remove("/api/v1/namespaces/default/pods/app");
+        });
+        let (status, body) = request(&admin, Method::POST, "/drain").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(json!(["default/app"]), body["evicted"]);
+        assert_eq!(json!([]), body["terminating"]);
+        assert_eq!(json!(["default/daemon", "default/mirror"]), body["skipped"]);
+        assert!(server.get("/api/v1/namespaces/default/pods/app").is_none());
+        let node = server.get(&format!("/api/v1/nodes/{}", NODE_NAME)).unwrap();
+        assert_eq!(true, node["spec"]["unschedulable"]);
+        let deleting = |name: &str| {
+            !server
+                .get(&format!("/api/v1/namespaces/default/pods/{}", name))
+                .unwrap()["metadata"]["deletionTimestamp"]
+                .is_null()
+        };
+        assert!(!deleting("daemon"));
+        assert!(!deleting("mirror"));
+        assert!(!deleting("elsewhere"));
+
+        let (status, _) = request(&admin, Method::POST, "/uncordon").await;
+        assert_eq!(StatusCode::OK, status);
+        let node = server.get(&format!("/api/v1/nodes/{}", NODE_NAME)).unwrap();
+        assert_eq!(false, node["spec"]["unschedulable"]);
+    }
+
+    #[tokio::test]
+    async fn test_drain_times_out_on_terminating_pods() {
+        let server = MockApiServer::start().await.unwrap();
+        let (mut admin, _) = admin(&server);
+        admin.drain_timeout = Duration::from_millis(0);
+        server.insert(
+            "/api/v1/nodes",
+            json!({ "metadata": { "name": NODE_NAME }, "spec": {} }),
+        );
+        server.insert("/api/v1/namespaces/default/pods", scheduled_pod("app"));
+
+        let (status, body) = request(&admin, Method::POST, "/drain").await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!(json!(["default/app"]), body["evicted"]);
+        assert_eq!(json!(["default/app"]), body["terminating"]);
+
+        // A retried drain waits for the pod that is still terminating
+        let (status, body) = request(&admin, Method::POST, "/drain").await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!(json!([]), body["evicted"]);
+        assert_eq!(json!(["default/app"]), body["terminating"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serve() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let server = MockApiServer::start().await.unwrap();
+        let (admin, _) = admin(&server);
+        let dir = std::env::temp_dir().join(format!("krustlet-admin-{}", std::process::id()));
+        let path = dir.join("admin.sock");
+        // A socket left behind by an earlier run is replaced
+        std::fs::create_dir_all(&dir).unwrap();
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        tokio::spawn(serve(Some(path.clone()), admin));
+
+        let stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::delay_for(Duration::from_millis(10)).await,
+            }
+        };
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        // Only the socket is left in the directory
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::get("http://localhost/queue")
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            json!({ "pods": 0, "states": {}, "failing": 0 }),
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
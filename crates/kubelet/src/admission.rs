@@ -0,0 +1,510 @@
+//! Deciding whether the Kubelet runs a pod scheduled to its node
+//!
+//! Pods are checked before the Kubelet does anything else with them. As with the
+//! Kubernetes kubelet, a pod that may not run is failed with the reason it was
+//! rejected, without being given the Kubelet's finalizer or reaching the provider.
+//! Pods without a name, namespace, UID or spec can't even be rejected, so their events
+//! are ignored before they are checked, and admitted pods are [`ValidatedPod`]s.
+//!
+//! Pods are rejected when:
+//!
+//! - their namespace is not allowed by the Kubelet's
+//!   [`namespaces`](crate::config::Config::namespaces) configuration
+//! - one of their containers binds a host port that a pod already on the node binds,
+//!   see [`PortBinding::conflicts_with`]. The ports of a pod are released once it has
+//!   finished or is terminated. When the Kubelet lists the pods on its node, they are
+//!   checked oldest first, so the pods already running keep their ports
+//! - their `nodeSelector` asks for an architecture or operating system, in the
+//!   `kubernetes.io/arch` or `kubernetes.io/os` labels or their deprecated `beta`
+//!   versions, other than the provider's. The scheduler doesn't place such pods on the
+//!   node, but pods that set their `nodeName` themselves still reach it
+//! - their namespace would run more pods, or request more memory, on the node than the
+//!   Kubelet's [`namespace_quotas`](crate::config::Config::namespace_quotas) allow, so
+//!   one tenant of a shared node can't take all of it. Like host ports, what a pod
+//!   counts against its quota is released once it is terminated
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::PodCondition;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use log::{info, warn};
+
+use crate::config::{Config, NamespaceFilter};
+use crate::container::{port_bindings, PortBinding};
+use crate::handle::key_from_pod;
+use crate::node::{ARCH_LABELS, OS_LABELS};
+use crate::pod::{Pod, ValidatedPod};
+use crate::stats::{parse_quantity, requirements};
+use crate::status::{Phase, StatusPatch};
+
+/// The reason reported on pods rejected because of their namespace
+pub(crate) const REASON_NAMESPACE: &str = "NamespaceNotAllowed";
+/// The reason reported on pods rejected because a host port they bind is already
+/// bound, as the Kubernetes kubelet reports it
+pub(crate) const REASON_HOST_PORTS: &str = "NodePorts";
+/// The reason reported on pods rejected because their node selector doesn't match the
+/// provider, as the Kubernetes kubelet reports it
+pub(crate) const REASON_NODE_AFFINITY: &str = "NodeAffinity";
+/// The reason reported on pods rejected because their namespace would go over its
+/// quota on the node
+pub(crate) const REASON_NAMESPACE_QUOTA: &str = "NamespaceQuotaExceeded";
+
+/// The namespace whose quota applies to namespaces without a quota of their own
+const ANY_NAMESPACE: &str = "*";
+
+/// The most pods and memory the pods of a namespace may use on the node
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Quota {
+    pods: Option<usize>,
+    memory_bytes: Option<u64>,
+}
+
+impl Quota {
+    /// Parse the caps of a namespace, by resource name. Invalid caps are left out, as
+    /// the configuration reports them when it is validated
+    fn parse(caps: &HashMap<String, String>) -> Self {
+        Quota {
+            pods: caps.get("pods").and_then(|pods| pods.parse().ok()),
+            memory_bytes: caps
+                .get("memory")
+                .and_then(|memory| parse_quantity(&Quantity(memory.clone())))
+                .filter(|bytes| *bytes >= 0.0)
+                .map(|bytes| bytes.round() as u64),
+        }
+    }
+}
+
+/// The key and host ports bound by each admitted pod, by pod UID
+type HostPorts = HashMap<String, (String, Vec<PortBinding>)>;
+
+/// The checks a pod must pass to be run by the Kubelet
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Admission {
+    namespaces: NamespaceFilter,
+    /// The architecture and operating system of the provider, if pods are checked
+    /// against them
+    platform: Option<(&'static str, &'static str)>,
+    /// The host ports bound by each admitted pod. A pod created again with the same
+    /// name is admitted while the deleted one may still be released, so the pods are
+    /// told apart by their UIDs
+    host_ports: Arc<Mutex<HostPorts>>,
+    /// The quota of each namespace that has one
+    quotas: HashMap<String, Quota>,
+    /// The namespace and requested memory of each admitted pod whose namespace has a
+    /// quota, by pod UID
+    quota_usage: Arc<Mutex<HashMap<String, (String, u64)>>>,
+}
+
+/// Why a pod may not run on the node
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Rejection {
+    pub(crate) reason: &'static str,
+    pub(crate) message: String,
+}
+
+impl Admission {
+    pub(crate) fn new(config: &Config) -> Self {
+        Admission::default()
+            .with_namespaces(config.namespaces.clone())
+            .with_namespace_quotas(&config.namespace_quotas)
+    }
+
+    /// Only admit pods from the namespaces the filter allows
+    pub(crate) fn with_namespaces(mut self, namespaces: NamespaceFilter) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    /// Only admit pods that keep their namespace within its quota, given as the caps
+    /// of each namespace by resource name
+    pub(crate) fn with_namespace_quotas(
+        mut self,
+        quotas: &HashMap<String, HashMap<String, String>>,
+    ) -> Self {
+        self.quotas = quotas
+            .iter()
+            .map(|(namespace, caps)| (namespace.clone(), Quota::parse(caps)))
+            .collect();
+        self
+    }
+
+    /// Only admit pods whose node selector allows the given architecture and operating
+    /// system, which are those of the provider
+    pub(crate) fn with_platform(mut self, arch: &'static str, os: &'static str) -> Self {
+        self.platform = Some((arch, os));
+        self
+    }
+
+    /// Check whether the pod may run, returning why it may not. The host ports of a pod
+    /// that may run, and what it counts against the quota of its namespace, are reserved
+    /// for it until it is [released](Admission::release)
+    pub(crate) fn check(&self, pod: &ValidatedPod) -> Result<(), Rejection> {
+        if !self.namespaces.allows(pod.namespace()) {
+            return Err(Rejection {
+                reason: REASON_NAMESPACE,
+                message: format!(
+                    "Pod was rejected: pods from namespace {:?} are not allowed on this node",
+                    pod.namespace()
+                ),
+            });
+        }
+        if let Some(rejection) = self.check_platform(pod) {
+            return Err(rejection);
+        }
+        // Pods that have already finished no longer bind their ports or use the node
+        if finished(pod) {
+            return Ok(());
+        }
+        let mut quota_usage = self.quota_usage.lock().unwrap();
+        let memory_bytes = self.check_quota(pod, &quota_usage)?;
+        self.reserve_host_ports(pod)?;
+        if let Some(memory_bytes) = memory_bytes {
+            quota_usage.insert(
+                pod.uid().to_owned(),
+                (pod.namespace().to_owned(), memory_bytes),
+            );
+        }
+        Ok(())
+    }
+
+    /// Reserve the host ports the pod binds, unless a pod already on the node binds one
+    /// of them
+    fn reserve_host_ports(&self, pod: &ValidatedPod) -> Result<(), Rejection> {
+        let bindings: Vec<PortBinding> = pod.containers().iter().flat_map(port_bindings).collect();
+        if bindings.is_empty() {
+            return Ok(());
+        }
+        let mut host_ports = self.host_ports.lock().unwrap();
+        let conflict = host_ports
+            .iter()
+            .filter(|(uid, _)| *uid != pod.uid())
+            .flat_map(|(_, (other, bound))| bound.iter().map(move |b| (other, b)))
+            .find(|(_, bound)| bindings.iter().any(|b| b.conflicts_with(bound)));
+        if let Some((other, bound)) = conflict {
+            return Err(Rejection {
+                reason: REASON_HOST_PORTS,
+                message: format!(
+                    "Pod was rejected: host port {}/{} is already bound by pod {}",
+                    bound.host_port, bound.protocol, other
+                ),
+            });
+        }
+        host_ports.insert(pod.uid().to_owned(), (key_from_pod(pod), bindings));
+        Ok(())
+    }
+
+    /// Check that the pod keeps its namespace within its quota, counting the pods of
+    /// the namespace admitted already. Returns the memory the pod requests if its
+    /// namespace has a quota
+    fn check_quota(
+        &self,
+        pod: &ValidatedPod,
+        quota_usage: &HashMap<String, (String, u64)>,
+    ) -> Result<Option<u64>, Rejection> {
+        let namespace = pod.namespace();
+        let quota = match self
+            .quotas
+            .get(namespace)
+            .or_else(|| self.quotas.get(ANY_NAMESPACE))
+        {
+            Some(quota) => quota,
+            None => return Ok(None),
+        };
+        let memory_bytes: u64 = pod
+            .containers()
+            .iter()
+            .filter_map(|c| requirements(c.resources.as_ref()).0.memory_bytes)
+            .sum();
+        let (pods, requested) = quota_usage
+            .iter()
+            .filter(|(uid, (other_namespace, _))| *uid != pod.uid() && other_namespace == namespace)
+            .fold((1, memory_bytes), |(pods, requested), (_, (_, bytes))| {
+                (pods + 1, requested + bytes)
+            });
+        let rejection = |message| {
+            Err(Rejection {
+                reason: REASON_NAMESPACE_QUOTA,
+                message,
+            })
+        };
+        match quota.pods {
+            Some(max) if pods > max => {
+                return rejection(format!(
+                    "Pod was rejected: namespace {:?} would run {} pods on this node, over its quota of {}",
+                    namespace, pods, max
+                ))
+            }
+            _ => (),
+        }
+        match quota.memory_bytes {
+            Some(max) if requested > max => {
+                return rejection(format!(
+                    "Pod was rejected: namespace {:?} would request {} bytes of memory on this node, over its quota of {} bytes",
+                    namespace, requested, max
+                ))
+            }
+            _ => (),
+        }
+        Ok(Some(memory_bytes))
+    }
+
+    /// Check the architecture and operating system the pod's node selector asks for
+    fn check_platform(&self, pod: &ValidatedPod) -> Option<Rejection> {
+        let (arch, os) = self.platform?;
+        let selector = pod.spec().node_selector.as_ref()?;
+        let checks = ARCH_LABELS
+            .iter()
+            .map(|label| (label, arch, "architecture"))
+            .chain(
+                OS_LABELS
+                    .iter()
+                    .map(|label| (label, os, "operating system")),
+            );
+        for (label, supported, kind) in checks {
+            match selector.get(*label) {
+                Some(wanted) if wanted != supported => {
+                    return Some(Rejection {
+                        reason: REASON_NODE_AFFINITY,
+                        message: format!(
+                            "Pod was rejected: node selector {}={} doesn't match the {} of this node, {:?}",
+                            label, wanted, kind, supported
+                        ),
+                    })
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// Release the host ports, and what counts against the quota of its namespace, of
+    /// a pod that has been terminated or deleted
+    pub(crate) fn release(&self, pod: &Pod) {
+        if let Some(uid) = pod.uid() {
+            self.host_ports.lock().unwrap().remove(uid);
+            self.quota_usage.lock().unwrap().remove(uid);
+        }
+    }
+}
+
+/// Fail the pod with the reason it was rejected. Pods that have already finished are
+/// left alone, and the Kubelet's finalizer is removed in case the pod was admitted
+/// by an earlier Kubelet with a different configuration, so its deletion isn't blocked
+pub(crate) async fn reject(client: &kube::Client, pod: &ValidatedPod, rejection: Rejection) {
+    if let Err(e) = pod.remove_finalizer(client.clone()).await {
+        warn!(
+            "Unable to remove finalizer from rejected pod {} in namespace {}: {}",
+            pod.name(),
+            pod.namespace(),
+            e
+        );
+    }
+    if pod.deletion_timestamp().is_some() || finished(pod) {
+        return;
+    }
+    info!(
+        "Rejecting pod {} in namespace {}: {}",
+        pod.name(),
+        pod.namespace(),
+        rejection.message
+    );
+    let patch = StatusPatch::new()
+        .phase(Phase::Failed)
+        .reason(rejection.reason)
+        .message(&rejection.message)
+        .condition(PodCondition {
+            type_: "Ready".to_owned(),
+            status: "False".to_owned(),
+            reason: Some(rejection.reason.to_owned()),
+            last_transition_time: Some(Time(Utc::now())),
+            ..Default::default()
+        });
+    if let Err(e) = patch.apply_for(client.clone(), pod).await {
+        warn!(
+            "Unable to report rejection of pod {} in namespace {}: {}",
+            pod.name(),
+            pod.namespace(),
+            e
+        );
+    }
+}
+
+/// Whether the pod has succeeded or failed, so it no longer runs on the node
+pub(crate) fn finished(pod: &Pod) -> bool {
+    let phase = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.phase.as_deref());
+    phase == Some("Succeeded") || phase == Some("Failed")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+    use k8s_openapi::api::core::v1::{ContainerPort, Pod as KubePod};
+
+    fn validated(kube_pod: KubePod) -> ValidatedPod {
+        ValidatedPod::new(Pod::new(kube_pod)).unwrap()
+    }
+
+    #[test]
+    fn test_namespace_rejection() {
+        let admission = Admission::default().with_namespaces(NamespaceFilter {
+            allowed: vec!["apps".to_owned()],
+            blocked: Vec::new(),
+        });
+        assert!(admission.check(&validated(fake_pod("foo", "apps"))).is_ok());
+        let rejection = admission
+            .check(&validated(fake_pod("foo", "default")))
+            .unwrap_err();
+        assert_eq!(REASON_NAMESPACE, rejection.reason);
+        assert!(rejection.message.contains("\"default\""));
+    }
+
+    fn with_host_port(name: &str, host_port: i32) -> ValidatedPod {
+        let mut kube_pod = fake_pod(name, "default");
+        kube_pod.spec.as_mut().unwrap().containers[0].ports = Some(vec![ContainerPort {
+            container_port: 8080,
+            host_port: Some(host_port),
+            ..Default::default()
+        }]);
+        validated(kube_pod)
+    }
+
+    #[test]
+    fn test_host_port_conflicts() {
+        let admission = Admission::default();
+        let first = with_host_port("first", 80);
+        assert!(admission.check(&first).is_ok());
+        // Checking a pod again, such as after its events are replayed, is not a conflict
+        assert!(admission.check(&first).is_ok());
+        assert!(admission.check(&with_host_port("other", 8080)).is_ok());
+
+        let second = with_host_port("second", 80);
+        let rejection = admission.check(&second).unwrap_err();
+        assert_eq!(REASON_HOST_PORTS, rejection.reason);
+        assert!(rejection.message.contains("80/TCP"));
+
+        admission.release(&first);
+        assert!(admission.check(&second).is_ok());
+
+        // A pod created again with the same name conflicts with the deleted one until
+        // the deleted one is released
+        let mut recreated = second.as_kube_pod().clone();
+        recreated.metadata.as_mut().unwrap().uid = Some("recreated".to_owned());
+        let recreated = validated(recreated);
+        assert!(admission.check(&recreated).is_err());
+        admission.release(&second);
+        assert!(admission.check(&recreated).is_ok());
+    }
+
+    #[test]
+    fn test_namespace_quota_rejection() {
+        let with_memory = |name: &str, namespace: &str, memory: &str| {
+            let mut kube_pod = fake_pod(name, namespace);
+            kube_pod.spec.as_mut().unwrap().containers[0].resources = Some(
+                serde_json::from_value(serde_json::json!({ "limits": { "memory": memory } }))
+                    .unwrap(),
+            );
+            validated(kube_pod)
+        };
+        let caps = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect::<HashMap<_, _>>()
+        };
+        let mut quotas = HashMap::new();
+        quotas.insert("tenant".to_owned(), caps(&[("memory", "1Gi")]));
+        quotas.insert("*".to_owned(), caps(&[("pods", "1")]));
+        let admission = Admission::default().with_namespace_quotas(&quotas);
+
+        let first = with_memory("first", "tenant", "512Mi");
+        assert!(admission.check(&first).is_ok());
+        assert!(admission.check(&first).is_ok());
+        assert!(admission
+            .check(&with_memory("second", "tenant", "512Mi"))
+            .is_ok());
+        let rejection = admission
+            .check(&with_memory("third", "tenant", "1Mi"))
+            .unwrap_err();
+        assert_eq!(REASON_NAMESPACE_QUOTA, rejection.reason);
+        assert!(rejection.message.contains("1074790400 bytes"));
+
+        // Namespaces without a quota of their own each get the `*` one
+        let apps = validated(fake_pod("a", "apps"));
+        assert!(admission.check(&apps).is_ok());
+        assert!(admission.check(&validated(fake_pod("a", "tools"))).is_ok());
+        let rejection = admission
+            .check(&validated(fake_pod("b", "apps")))
+            .unwrap_err();
+        assert_eq!(
+            "Pod was rejected: namespace \"apps\" would run 2 pods on this node, over its quota of 1",
+            rejection.message
+        );
+
+        // A pod created again with the same name counts as another pod until the
+        // deleted one is released, and releasing the deleted one late leaves the
+        // recreated one counted
+        let mut recreated = fake_pod("a", "apps");
+        recreated.metadata.as_mut().unwrap().uid = Some("recreated".to_owned());
+        let recreated = validated(recreated);
+        assert!(admission.check(&recreated).is_err());
+        admission.release(&apps);
+        assert!(admission.check(&recreated).is_ok());
+        admission.release(&apps);
+        assert!(admission.check(&validated(fake_pod("b", "apps"))).is_err());
+
+        admission.release(&first);
+        assert!(admission
+            .check(&with_memory("third", "tenant", "1Mi"))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_node_selector_rejection() {
+        let with_selector = |labels: &[(&str, &str)]| {
+            let mut kube_pod = fake_pod("foo", "default");
+            kube_pod.spec.as_mut().unwrap().node_selector = Some(
+                labels
+                    .iter()
+                    .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                    .collect(),
+            );
+            validated(kube_pod)
+        };
+        let admission = Admission::default().with_platform("wasm32-wasi", "linux");
+        assert!(admission
+            .check(&validated(fake_pod("foo", "default")))
+            .is_ok());
+        assert!(admission
+            .check(&with_selector(&[
+                ("kubernetes.io/arch", "wasm32-wasi"),
+                ("kubernetes.io/os", "linux"),
+                ("disktype", "ssd"),
+            ]))
+            .is_ok());
+
+        let rejection = admission
+            .check(&with_selector(&[("beta.kubernetes.io/arch", "amd64")]))
+            .unwrap_err();
+        assert_eq!(REASON_NODE_AFFINITY, rejection.reason);
+        assert_eq!(
+            "Pod was rejected: node selector beta.kubernetes.io/arch=amd64 doesn't match the architecture of this node, \"wasm32-wasi\"",
+            rejection.message
+        );
+        let rejection = admission
+            .check(&with_selector(&[("kubernetes.io/os", "windows")]))
+            .unwrap_err();
+        assert!(rejection.message.contains("operating system"));
+
+        // Without a platform, node selectors are left to the scheduler
+        assert!(Admission::default()
+            .check(&with_selector(&[("kubernetes.io/arch", "amd64")]))
+            .is_ok());
+    }
+}
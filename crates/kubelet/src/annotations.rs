@@ -0,0 +1,266 @@
+//! Per-pod configuration of providers through annotations
+//!
+//! Providers read settings for a pod, such as runtime flags, from annotations whose
+//! keys start with [`PREFIX`]. The version of the schema is part of the prefix, so a
+//! key can change once it graduates from alpha without breaking existing pods.
+//!
+//! A provider describes its settings as a type implementing [`AnnotationConfig`] and
+//! loads it with [`load`], which reports invalid values as warning events on the pod.
+//! Settings the node doesn't allow a pod to have are reported with [`report_denied`].
+//!
+//! # Example
+//! ```rust
+//! use kubelet::annotations::{self, AnnotationConfig, Annotations};
+//!
+//! #[derive(Default)]
+//! struct RuntimeConfig {
+//!     debug: bool,
+//!     stack_size: Option<usize>,
+//! }
+//!
+//! impl AnnotationConfig for RuntimeConfig {
+//!     fn from_annotations(annotations: &mut Annotations) -> Self {
+//!         RuntimeConfig {
+//!             // Read from `alpha.krustlet.dev/debug`
+//!             debug: annotations.get("debug").unwrap_or_default(),
+//!             stack_size: annotations.get("stack-size"),
+//!         }
+//!     }
+//! }
+//!
+//! async fn start(client: kube::Client, pod: kubelet::Pod) {
+//!     let _config: RuntimeConfig = annotations::load(&client, &pod).await;
+//! }
+//! ```
+use std::fmt::Display;
+use std::str::FromStr;
+
+use log::warn;
+use thiserror::Error;
+
+use crate::events::record_pod_warning;
+use crate::pod::Pod;
+
+/// The prefix of all annotations that configure how providers run a pod
+pub const PREFIX: &str = "alpha.krustlet.dev/";
+
+/// An annotation that has a value a provider cannot use
+#[derive(Clone, Debug, Error, PartialEq)]
+#[error("invalid value {value:?} for annotation {key}: {reason}")]
+pub struct AnnotationError {
+    /// The full key of the annotation
+    pub key: String,
+    /// The value of the annotation
+    pub value: String,
+    /// Why the value is invalid
+    pub reason: String,
+}
+
+/// The krustlet annotations of a pod, which collects the errors found while
+/// reading them
+pub struct Annotations<'a> {
+    pod: &'a Pod,
+    errors: Vec<AnnotationError>,
+}
+
+impl<'a> Annotations<'a> {
+    /// Get the value of the annotation with the given key, without the [`PREFIX`]
+    pub fn raw(&self, key: &str) -> Option<&'a str> {
+        self.pod.get_annotation(&format!("{}{}", PREFIX, key))
+    }
+
+    /// Parse the value of the annotation with the given key, without the [`PREFIX`].
+    ///
+    /// Returns `None` if the annotation is not set or its value cannot be parsed, in
+    /// which case the error is recorded.
+    pub fn get<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse_with(key, |value| value.parse::<T>().map_err(|e| e.to_string()))
+    }
+
+    /// Parse the value of the annotation with the given key, without the [`PREFIX`],
+    /// using a custom parser that returns why a value is invalid.
+    ///
+    /// Returns `None` if the annotation is not set or the parser fails, in which case
+    /// the error is recorded.
+    pub fn parse_with<T, F>(&mut self, key: &str, parse: F) -> Option<T>
+    where
+        F: FnOnce(&str) -> Result<T, String>,
+    {
+        let value = self.raw(key)?;
+        match parse(value.trim()) {
+            Ok(parsed) => Some(parsed),
+            Err(reason) => {
+                self.errors.push(AnnotationError {
+                    key: format!("{}{}", PREFIX, key),
+                    value: value.to_owned(),
+                    reason,
+                });
+                None
+            }
+        }
+    }
+}
+
+/// Settings that are read from a pod's annotations
+pub trait AnnotationConfig: Sized {
+    /// Read the settings from the annotations. Settings whose annotations are missing
+    /// or invalid should fall back to their defaults
+    fn from_annotations(annotations: &mut Annotations) -> Self;
+}
+
+/// Read the settings from a pod's annotations, returning any invalid annotations
+/// alongside them
+pub fn parse<T: AnnotationConfig>(pod: &Pod) -> (T, Vec<AnnotationError>) {
+    let mut annotations = Annotations {
+        pod,
+        errors: Vec::new(),
+    };
+    let config = T::from_annotations(&mut annotations);
+    (config, annotations.errors)
+}
+
+/// Read the settings from a pod's annotations.
+///
+/// Invalid annotations are ignored and recorded as `InvalidAnnotation` warning events
+/// on the pod, so they show up in `kubectl describe pod`.
+pub async fn load<T: AnnotationConfig>(client: &kube::Client, pod: &Pod) -> T {
+    let (config, errors) = parse(pod);
+    report(client, pod, errors).await;
+    config
+}
+
+/// Log invalid annotations and record them as `InvalidAnnotation` warning events on
+/// the pod
+pub(crate) async fn report(client: &kube::Client, pod: &Pod, errors: Vec<AnnotationError>) {
+    for error in errors {
+        warn!(
+            "Pod {} in namespace {} has an {}",
+            pod.name(),
+            pod.namespace(),
+            error
+        );
+        record_pod_warning(client, pod, "InvalidAnnotation", &error.to_string()).await;
+    }
+}
+
+/// Log that a pod's annotations ask for more than the node allows, and record it as a
+/// `PolicyDenied` warning event on the pod.
+///
+/// Providers use this when they run a pod with only the part of its request that the
+/// node allows, so the pod's owner can see why it got less.
+pub async fn report_denied(client: &kube::Client, pod: &Pod, message: &str) {
+    warn!(
+        "Pod {} in namespace {} asks for more than the node allows: {}",
+        pod.name(),
+        pod.namespace(),
+        message
+    );
+    record_pod_warning(client, pod, "PolicyDenied", message).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Settings {
+        debug: bool,
+        stack_size: Option<usize>,
+        mode: Option<String>,
+    }
+
+    impl AnnotationConfig for Settings {
+        fn from_annotations(annotations: &mut Annotations) -> Self {
+            Settings {
+                debug: annotations.get("debug").unwrap_or_default(),
+                stack_size: annotations.get("stack-size"),
+                mode: annotations.parse_with("mode", |mode| match mode {
+                    "fast" | "safe" => Ok(mode.to_owned()),
+                    _ => Err("must be fast or safe".to_owned()),
+                }),
+            }
+        }
+    }
+
+    fn annotated(annotations: &[(&str, &str)]) -> Pod {
+        let mut pod = fake_pod("foo", "default");
+        pod.metadata.as_mut().unwrap().annotations = Some(
+            annotations
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect(),
+        );
+        Pod::new(pod)
+    }
+
+    #[test]
+    fn test_parse() {
+        let pod = annotated(&[
+            ("alpha.krustlet.dev/debug", "true"),
+            ("alpha.krustlet.dev/stack-size", " 1024 "),
+            ("alpha.krustlet.dev/mode", "safe"),
+            ("debug", "false"),
+        ]);
+        let (settings, errors) = parse::<Settings>(&pod);
+        assert!(errors.is_empty());
+        assert_eq!(
+            Settings {
+                debug: true,
+                stack_size: Some(1024),
+                mode: Some("safe".to_owned()),
+            },
+            settings
+        );
+
+        let pod = annotated(&[
+            ("alpha.krustlet.dev/stack-size", "big"),
+            ("alpha.krustlet.dev/mode", "reckless"),
+        ]);
+        let (settings, errors) = parse::<Settings>(&pod);
+        assert_eq!(Settings::default(), settings);
+        assert_eq!(2, errors.len());
+        assert_eq!("alpha.krustlet.dev/stack-size", errors[0].key);
+        assert_eq!("must be fast or safe", errors[1].reason);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_annotations_are_recorded_as_events() {
+        let server = MockApiServer::start().await.unwrap();
+        let pod = annotated(&[("alpha.krustlet.dev/debug", "maybe")]);
+        let settings: Settings = load(&server.client(), &pod).await;
+        assert!(!settings.debug);
+
+        let request = server
+            .requests_to(hyper::Method::POST, "/api/v1/namespaces/default/events")
+            .pop()
+            .expect("an event should be recorded");
+        let event = request.body.unwrap();
+        assert_eq!("InvalidAnnotation", event["reason"]);
+        assert_eq!("Warning", event["type"]);
+        assert_eq!("foo", event["involvedObject"]["name"]);
+        assert!(event["message"]
+            .as_str()
+            .unwrap()
+            .contains("alpha.krustlet.dev/debug"));
+    }
+
+    #[tokio::test]
+    async fn test_denied_requests_are_recorded_as_events() {
+        let server = MockApiServer::start().await.unwrap();
+        let pod = annotated(&[("alpha.krustlet.dev/wasi-random", "true")]);
+        report_denied(&server.client(), &pod, "random numbers are not allowed").await;
+
+        let request = server
+            .requests_to(hyper::Method::POST, "/api/v1/namespaces/default/events")
+            .pop()
+            .expect("an event should be recorded");
+        let event = request.body.unwrap();
+        assert_eq!("PolicyDenied", event["reason"]);
+        assert_eq!("random numbers are not allowed", event["message"]);
+    }
+}
@@ -0,0 +1,351 @@
+//! An append-only audit log of the modules and pods the Kubelet ran
+//!
+//! When [`Config::audit_log`](crate::config::Config::audit_log) is set, the Kubelet
+//! records every module fetched for a container, with its image, digest and signature
+//! status, and every pod it started and stopped to `audit.log` in its data directory.
+//! The log is kept across restarts, with one JSON object per line:
+//!
+//! ```json
+//! {"time":"2020-06-01T12:00:00Z","event":"PodStarted","namespace":"default","pod":"hello","uid":"...","previous":"sha256:..."}
+//! ```
+//!
+//! Each entry names the SHA-256 digest of the line before it in `previous` (the first
+//! entry has none), so lines that were changed, removed or reordered later are found
+//! by [`verify`]. The Kubelet also records a digest of its configuration, without
+//! secrets, each time it starts, so changes to how a node was run can be traced. The
+//! configuration is serialized to JSON with its keys sorted, so the same configuration
+//! always has the same digest.
+//!
+//! Modules are not signed yet, so their signature status is always `unverified`.
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::pod::Pod;
+
+/// The name of the audit log in the data directory
+pub const FILE_NAME: &str = "audit.log";
+
+/// The signature status recorded for modules, which are not signed yet
+const UNVERIFIED: &str = "unverified";
+
+/// Something recorded in the audit log
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    /// The Kubelet started with the configuration of the given digest
+    KubeletStarted {
+        /// The name of the node
+        node_name: String,
+        /// The digest of the configuration, without secrets
+        config: String,
+    },
+    /// The module of a container was fetched
+    ModulePulled {
+        /// The namespace of the pod
+        namespace: String,
+        /// The name of the pod
+        pod: String,
+        /// The name of the container
+        container: String,
+        /// The image the module was fetched for
+        image: String,
+        /// The digest of the module, in the form `sha256:<hex>`
+        digest: String,
+        /// Whether the module's signature was verified
+        signature: String,
+    },
+    /// A pod was started by the provider
+    PodStarted {
+        /// The namespace of the pod
+        namespace: String,
+        /// The name of the pod
+        pod: String,
+        /// The UID of the pod
+        uid: Option<String>,
+    },
+    /// A pod was stopped, because it was deleted or evicted
+    PodStopped {
+        /// The namespace of the pod
+        namespace: String,
+        /// The name of the pod
+        pod: String,
+        /// The UID of the pod
+        uid: Option<String>,
+    },
+}
+
+/// An entry of the audit log
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the event happened
+    pub time: DateTime<Utc>,
+    /// What happened
+    #[serde(flatten)]
+    pub event: Event,
+    /// The digest of the line before this one
+    pub previous: Option<String>,
+}
+
+/// The audit log of a Kubelet. Clones record to the same log, and the default records
+/// nothing
+#[derive(Clone, Default)]
+pub(crate) struct AuditLog(Option<Arc<Mutex<Chain>>>);
+
+/// The file of the audit log and the digest of its last line
+struct Chain {
+    file: File,
+    last: Option<String>,
+}
+
+impl AuditLog {
+    /// Record to the audit log in the given data directory, continuing the log if it
+    /// already exists
+    pub(crate) fn open(data_dir: &Path) -> anyhow::Result<Self> {
+        Ok(AuditLog(Some(Arc::new(Mutex::new(Chain::open(data_dir)?)))))
+    }
+
+    /// Record the configuration the Kubelet started with
+    pub(crate) fn kubelet_started(&self, config: &Config) {
+        let digest = match config_digest(config) {
+            Ok(digest) => digest,
+            Err(e) => {
+                warn!(
+                    "Unable to serialize the configuration for the audit log: {}",
+                    e
+                );
+                return;
+            }
+        };
+        self.record(Event::KubeletStarted {
+            node_name: config.node_name.clone(),
+            config: digest,
+        });
+    }
+
+    /// Record that the module of a container was fetched
+    pub(crate) fn module_pulled(&self, pod: &Pod, container: &str, image: &str, module: &[u8]) {
+        self.record(Event::ModulePulled {
+            namespace: pod.namespace().to_owned(),
+            pod: pod.name().to_owned(),
+            container: container.to_owned(),
+            image: image.to_owned(),
+            digest: format!("sha256:{:x}", Sha256::digest(module)),
+            signature: UNVERIFIED.to_owned(),
+        });
+    }
+
+    /// Record that a pod was started
+    pub(crate) fn pod_started(&self, pod: &Pod) {
+        self.record(Event::PodStarted {
+            namespace: pod.namespace().to_owned(),
+            pod: pod.name().to_owned(),
+            uid: pod.uid().map(str::to_owned),
+        });
+    }
+
+    /// Record that a pod was stopped
+    pub(crate) fn pod_stopped(&self, pod: &Pod) {
+        self.record(Event::PodStopped {
+            namespace: pod.namespace().to_owned(),
+            pod: pod.name().to_owned(),
+            uid: pod.uid().map(str::to_owned),
+        });
+    }
+
+    fn record(&self, event: Event) {
+        if let Some(chain) = &self.0 {
+            chain.lock().unwrap().record(event);
+        }
+    }
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("recording", &self.0.is_some())
+            .finish()
+    }
+}
+
+/// The digest of the configuration, serialized without its secrets. Serializing to a
+/// JSON value sorts the keys of its maps, so the digest doesn't depend on their order
+fn config_digest(config: &Config) -> serde_json::Result<String> {
+    Ok(digest(&serde_json::to_value(config)?.to_string()))
+}
+
+impl Chain {
+    fn open(data_dir: &Path) -> anyhow::Result<Self> {
+        let path = data_dir.join(FILE_NAME);
+        // The chain continues from the last entry written before
+        let mut last = None;
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if !line.is_empty() {
+                        last = Some(digest(&line));
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Chain { file, last })
+    }
+
+    fn record(&mut self, event: Event) {
+        let entry = Entry {
+            time: Utc::now(),
+            event,
+            previous: self.last.clone(),
+        };
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Unable to serialize audit log entry: {}", e);
+                return;
+            }
+        };
+        match writeln!(self.file, "{}", line) {
+            Ok(()) => self.last = Some(digest(&line)),
+            Err(e) => warn!("Unable to write to the audit log: {}", e),
+        }
+    }
+}
+
+/// A line of the audit log that was changed or is out of place
+#[derive(Debug, thiserror::Error)]
+#[error("line {line} of the audit log {}: {problem}", .path.display())]
+pub struct Tampered {
+    /// The audit log
+    pub path: PathBuf,
+    /// The number of the line, starting from 1
+    pub line: usize,
+    /// What is wrong with the line
+    pub problem: String,
+}
+
+/// Check that no entries of the audit log at the given path were changed, removed or
+/// reordered, returning its entries
+pub fn verify(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut last = None;
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let tampered = |problem: String| Tampered {
+            path: path.to_owned(),
+            line: i + 1,
+            problem,
+        };
+        let entry: Entry = serde_json::from_str(&line)
+            .map_err(|e| tampered(format!("is not a valid entry: {}", e)))?;
+        if entry.previous != last {
+            return Err(tampered("does not follow the line before it".to_owned()).into());
+        }
+        last = Some(digest(&line));
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn digest(line: &str) -> String {
+    format!("sha256:{:x}", Sha256::digest(line.as_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_is_chained() {
+        let dir = std::env::temp_dir().join(format!("krustlet-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(FILE_NAME);
+        let _ = std::fs::remove_file(&path);
+
+        let started = Event::PodStarted {
+            namespace: "default".to_owned(),
+            pod: "foo".to_owned(),
+            uid: None,
+        };
+        let log = AuditLog::open(&dir).unwrap();
+        log.record(started.clone());
+        log.record(Event::ModulePulled {
+            namespace: "default".to_owned(),
+            pod: "foo".to_owned(),
+            container: "foo".to_owned(),
+            image: "example.com/foo:v1".to_owned(),
+            digest: digest("module"),
+            signature: UNVERIFIED.to_owned(),
+        });
+        // The chain continues across restarts
+        let log = AuditLog::open(&dir).unwrap();
+        log.record(Event::PodStopped {
+            namespace: "default".to_owned(),
+            pod: "foo".to_owned(),
+            uid: None,
+        });
+        drop(log);
+
+        let entries = verify(&path).unwrap();
+        assert_eq!(3, entries.len());
+        assert_eq!(None, entries[0].previous);
+        assert_eq!(started, entries[0].event);
+        match &entries[1].event {
+            Event::ModulePulled {
+                digest, signature, ..
+            } => {
+                assert!(digest.starts_with("sha256:"));
+                assert_eq!(UNVERIFIED, signature);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(matches!(entries[2].event, Event::PodStopped { .. }));
+
+        // Removing an entry breaks the chain
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        let err = verify(&path).unwrap_err();
+        let tampered = err.downcast_ref::<Tampered>().unwrap();
+        assert_eq!(2, tampered.line);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_digest() {
+        let labels = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect()
+        };
+        let mut config = crate::config::test::test_config();
+        config.server_config.pfx_password = "hunter2".to_owned();
+        config.node_labels = labels(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")]);
+        let mut same = config.clone();
+        same.server_config.pfx_password = "other".to_owned();
+        same.node_labels = labels(&[("d", "4"), ("c", "3"), ("b", "2"), ("a", "1")]);
+
+        // The digest doesn't depend on the order of maps or on secrets
+        let digest = config_digest(&config).unwrap();
+        assert_eq!(digest, config_digest(&same).unwrap());
+        let serialized = serde_json::to_string(&config).unwrap();
+        assert!(!serialized.contains("hunter2"));
+
+        same.node_labels.insert("e".to_owned(), "5".to_owned());
+        assert_ne!(digest, config_digest(&same).unwrap());
+    }
+}
@@ -0,0 +1,265 @@
+//! Authenticating and authorizing requests to the Kubelet server
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+use hyper::{header, Body, Request};
+
+use crate::config::AuthConfig;
+
+/// The user a request was authenticated as
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct UserInfo {
+    pub(crate) name: String,
+    pub(crate) uid: String,
+    pub(crate) groups: Vec<String>,
+}
+
+/// Why a request was refused
+#[derive(Debug, PartialEq)]
+pub(crate) enum Denied {
+    /// The request did not carry valid credentials
+    Unauthorized,
+    /// The user is not allowed to use the server
+    Forbidden(String),
+}
+
+/// Checks the credentials of requests against the configured tokens and rules
+pub(crate) struct Authenticator {
+    /// The known tokens, or `None` when there is no token file
+    tokens: Option<HashMap<String, UserInfo>>,
+    /// Whether clients can authenticate with a certificate
+    client_certificates: bool,
+    users: HashSet<String>,
+    groups: HashSet<String>,
+}
+
+impl Authenticator {
+    /// Create an authenticator, reading the token file if there is one
+    pub(crate) async fn load(config: &AuthConfig) -> anyhow::Result<Self> {
+        let tokens = match &config.token_file {
+            Some(path) => {
+                let contents = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("Could not read token file {:?}", path))?;
+                Some(
+                    parse_tokens(&contents)
+                        .with_context(|| format!("Invalid token file {:?}", path))?,
+                )
+            }
+            None => None,
+        };
+        Ok(Authenticator {
+            tokens,
+            client_certificates: config.client_ca_file.is_some(),
+            users: config.authorized_users.iter().cloned().collect(),
+            groups: config.authorized_groups.iter().cloned().collect(),
+        })
+    }
+
+    /// Check that the request may be served, returning the user it was made by, if
+    /// authentication is enabled. The user of the client certificate the connection was
+    /// made with, if any, is preferred over a token
+    pub(crate) fn check<'a>(
+        &'a self,
+        req: &Request<Body>,
+        client: Option<&'a UserInfo>,
+    ) -> Result<Option<&'a UserInfo>, Denied> {
+        if self.tokens.is_none() && !self.client_certificates {
+            return Ok(None);
+        }
+        let token_user = || {
+            let tokens = self.tokens.as_ref()?;
+            req.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|h| h.to_str().ok())
+                .filter(|h| h.starts_with("Bearer "))
+                .map(|h| &h["Bearer ".len()..])
+                .and_then(|token| tokens.get(token.trim()))
+        };
+        let user = client.or_else(token_user).ok_or(Denied::Unauthorized)?;
+        if self.authorized(user) {
+            Ok(Some(user))
+        } else {
+            Err(Denied::Forbidden(user.name.clone()))
+        }
+    }
+
+    fn authorized(&self, user: &UserInfo) -> bool {
+        (self.users.is_empty() && self.groups.is_empty())
+            || self.users.contains(&user.name)
+            || user.groups.iter().any(|g| self.groups.contains(g))
+    }
+}
+
+/// Parses a token file in the same format as the API server's `--token-auth-file`.
+///
+/// Each line is `token,user,uid` optionally followed by a quoted, comma separated
+/// list of groups. Empty lines and lines starting with `#` are ignored.
+fn parse_tokens(contents: &str) -> anyhow::Result<HashMap<String, UserInfo>> {
+    let mut tokens = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = split_fields(line);
+        if fields.len() < 3 || fields[0].is_empty() || fields[1].is_empty() {
+            anyhow::bail!(
+                "line {} must have at least a token, user and uid",
+                number + 1
+            );
+        }
+        let groups = fields
+            .get(3)
+            .map(|g| {
+                g.split(',')
+                    .map(|g| g.trim().to_owned())
+                    .filter(|g| !g.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let user = UserInfo {
+            name: fields[1].clone(),
+            uid: fields[2].clone(),
+            groups,
+        };
+        if tokens.insert(fields[0].clone(), user).is_some() {
+            anyhow::bail!("line {} repeats a token", number + 1);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Splits a CSV line on commas that are not within double quotes
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.iter().map(|f| f.trim().to_owned()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TOKENS: &str = r#"
+# Tokens for the API server and monitoring
+abc,kube-apiserver,1,"system:masters,system:nodes"
+def,prometheus,2
+"#;
+
+    fn request(token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri("/containerLogs/default/pod/container");
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn authenticator(users: &[&str], groups: &[&str]) -> Authenticator {
+        Authenticator {
+            tokens: Some(parse_tokens(TOKENS).unwrap()),
+            client_certificates: true,
+            users: users.iter().map(|u| u.to_string()).collect(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_tokens() {
+        let tokens = parse_tokens(TOKENS).unwrap();
+        assert_eq!(2, tokens.len());
+        assert_eq!(
+            UserInfo {
+                name: "kube-apiserver".to_owned(),
+                uid: "1".to_owned(),
+                groups: vec!["system:masters".to_owned(), "system:nodes".to_owned()],
+            },
+            tokens["abc"]
+        );
+        assert!(tokens["def"].groups.is_empty());
+
+        assert!(parse_tokens("abc,user").is_err());
+        assert!(parse_tokens("abc,user,1\nabc,other,2").is_err());
+    }
+
+    #[test]
+    fn test_authentication() {
+        let auth = authenticator(&[], &[]);
+        assert_eq!(
+            "prometheus",
+            auth.check(&request(Some("def")), None)
+                .unwrap()
+                .unwrap()
+                .name
+        );
+        assert_eq!(Err(Denied::Unauthorized), auth.check(&request(None), None));
+        assert_eq!(
+            Err(Denied::Unauthorized),
+            auth.check(&request(Some("wrong")), None)
+        );
+
+        // Without a token file everything is allowed
+        let auth = Authenticator {
+            tokens: None,
+            client_certificates: false,
+            users: HashSet::new(),
+            groups: HashSet::new(),
+        };
+        assert_eq!(Ok(None), auth.check(&request(None), None));
+    }
+
+    #[test]
+    fn test_authorization() {
+        let auth = authenticator(&["prometheus"], &[]);
+        assert!(auth.check(&request(Some("def")), None).is_ok());
+        assert_eq!(
+            Err(Denied::Forbidden("kube-apiserver".to_owned())),
+            auth.check(&request(Some("abc")), None)
+        );
+
+        let auth = authenticator(&[], &["system:nodes"]);
+        assert!(auth.check(&request(Some("abc")), None).is_ok());
+        assert!(auth.check(&request(Some("def")), None).is_err());
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let node = UserInfo {
+            name: "system:node:edge".to_owned(),
+            uid: String::new(),
+            groups: vec!["system:nodes".to_owned()],
+        };
+        let auth = authenticator(&[], &["system:nodes"]);
+        assert_eq!(Ok(Some(&node)), auth.check(&request(None), Some(&node)));
+        // The certificate is preferred over the token
+        assert_eq!(
+            Ok(Some(&node)),
+            auth.check(&request(Some("def")), Some(&node))
+        );
+        let other = UserInfo {
+            groups: vec!["fleet".to_owned()],
+            ..node.clone()
+        };
+        assert_eq!(
+            Err(Denied::Forbidden("system:node:edge".to_owned())),
+            auth.check(&request(None), Some(&other))
+        );
+
+        // Without a token file, clients have to present a certificate
+        let auth = Authenticator {
+            tokens: None,
+            client_certificates: true,
+            users: HashSet::new(),
+            groups: HashSet::new(),
+        };
+        assert_eq!(Err(Denied::Unauthorized), auth.check(&request(None), None));
+        assert!(auth.check(&request(None), Some(&node)).is_ok());
+    }
+}
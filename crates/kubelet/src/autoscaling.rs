@@ -0,0 +1,166 @@
+//! Hints for cluster autoscalers about the capacity behind a node
+//!
+//! A provider backed by an elastic backend, such as a pool of machines that grows on
+//! demand, isn't full when its node's allocatable resources are used up, and may be
+//! full long before they are. Providers that know better report [`ScalingHints`] from
+//! [`Provider::scaling_hints`], which the Kubelet publishes on the node with every
+//! status update for autoscaling tooling to read:
+//!
+//! - the `alpha.krustlet.dev/available-pods` annotation holds how many more pods the
+//!   backend can run, if the provider knows
+//! - the `alpha.krustlet.dev/pending-pods` annotation holds the number of pods waiting
+//!   on the backend for each reason, such as `BackendCapacity=3,QuotaExceeded=1`
+//! - the `PodsPending` condition is `True` while any pods are waiting, with the most
+//!   common reason as its reason
+//!
+//! Providers that report no hints (the default) get none of these.
+//!
+//! [`Provider::scaling_hints`]: crate::Provider::scaling_hints
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::NodeCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+/// The annotation holding how many more pods the provider can run
+pub const AVAILABLE_PODS_ANNOTATION: &str = "alpha.krustlet.dev/available-pods";
+
+/// The annotation holding the number of pending pods for each reason
+pub const PENDING_PODS_ANNOTATION: &str = "alpha.krustlet.dev/pending-pods";
+
+/// The type of the node condition reporting whether pods are pending
+pub const PODS_PENDING_CONDITION: &str = "PodsPending";
+
+/// What a provider knows about how many more pods it can run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScalingHints {
+    /// How many more pods the provider can run, if it knows
+    pub available_pods: Option<u32>,
+    /// The number of pods waiting to run for each reason, such as `BackendCapacity`.
+    /// Reasons are in CamelCase like those of pod conditions
+    pub pending_pods: BTreeMap<String, u32>,
+}
+
+impl ScalingHints {
+    /// Add the hints of another provider to these, as the hints for a node backed by
+    /// both of them
+    pub fn merge(&mut self, other: ScalingHints) {
+        self.available_pods = match (self.available_pods, other.available_pods) {
+            (Some(a), Some(b)) => Some(a.saturating_add(b)),
+            (a, b) => a.or(b),
+        };
+        for (reason, count) in other.pending_pods {
+            *self.pending_pods.entry(reason).or_insert(0) += count;
+        }
+    }
+
+    /// The annotations that publish the hints on the node
+    pub fn annotations(&self) -> BTreeMap<String, String> {
+        let mut annotations = BTreeMap::new();
+        if let Some(available) = self.available_pods {
+            annotations.insert(AVAILABLE_PODS_ANNOTATION.to_owned(), available.to_string());
+        }
+        let pending: Vec<String> = self
+            .pending_pods
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .map(|(reason, count)| format!("{}={}", reason, count))
+            .collect();
+        annotations.insert(PENDING_PODS_ANNOTATION.to_owned(), pending.join(","));
+        annotations
+    }
+
+    /// The condition reporting whether pods are pending. It keeps the transition time
+    /// of the `previous` condition if its status is the same
+    pub fn condition(&self, previous: Option<&NodeCondition>) -> NodeCondition {
+        let now = Time(Utc::now());
+        let total: u32 = self.pending_pods.values().sum();
+        // Reasons with the same count are ordered by name, so the reason doesn't flap
+        // between them
+        let reason = self
+            .pending_pods
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(reason, _)| reason.clone());
+        let status = if total > 0 { "True" } else { "False" };
+        let last_transition_time = previous
+            .filter(|p| p.status == status)
+            .and_then(|p| p.last_transition_time.clone())
+            .unwrap_or_else(|| now.clone());
+        NodeCondition {
+            type_: PODS_PENDING_CONDITION.to_owned(),
+            status: status.to_owned(),
+            reason: Some(reason.unwrap_or_else(|| "NoPodsPending".to_owned())),
+            message: Some(match total {
+                0 => "no pods are waiting on the provider".to_owned(),
+                1 => "1 pod is waiting on the provider".to_owned(),
+                n => format!("{} pods are waiting on the provider", n),
+            }),
+            last_heartbeat_time: Some(now),
+            last_transition_time: Some(last_transition_time),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hints(available_pods: Option<u32>, pending: &[(&str, u32)]) -> ScalingHints {
+        ScalingHints {
+            available_pods,
+            pending_pods: pending
+                .iter()
+                .map(|(reason, count)| ((*reason).to_owned(), *count))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_annotations_and_condition() {
+        let hints = hints(Some(5), &[("QuotaExceeded", 1), ("BackendCapacity", 3)]);
+        let annotations = hints.annotations();
+        assert_eq!("5", annotations[AVAILABLE_PODS_ANNOTATION]);
+        assert_eq!(
+            "BackendCapacity=3,QuotaExceeded=1",
+            annotations[PENDING_PODS_ANNOTATION]
+        );
+        let condition = hints.condition(None);
+        assert_eq!("True", condition.status);
+        assert_eq!(Some("BackendCapacity"), condition.reason.as_deref());
+        assert_eq!(
+            Some("4 pods are waiting on the provider"),
+            condition.message.as_deref()
+        );
+
+        let idle = ScalingHints::default();
+        assert!(!idle.annotations().contains_key(AVAILABLE_PODS_ANNOTATION));
+        assert_eq!("", idle.annotations()[PENDING_PODS_ANNOTATION]);
+        assert_eq!("False", idle.condition(None).status);
+    }
+
+    #[test]
+    fn test_condition_keeps_transition_time() {
+        let mut previous = hints(None, &[("BackendCapacity", 1)]).condition(None);
+        let transitioned = Time(Utc::now() - chrono::Duration::hours(1));
+        previous.last_transition_time = Some(transitioned.clone());
+        let condition = hints(None, &[("BackendCapacity", 2)]).condition(Some(&previous));
+        assert_eq!(Some(transitioned.clone()), condition.last_transition_time);
+        // Once no pods are pending, the condition transitions
+        let condition = hints(None, &[]).condition(Some(&previous));
+        assert_ne!(Some(transitioned), condition.last_transition_time);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut merged = hints(Some(2), &[("BackendCapacity", 1)]);
+        merged.merge(hints(None, &[("BackendCapacity", 2), ("QuotaExceeded", 1)]));
+        assert_eq!(
+            hints(Some(2), &[("BackendCapacity", 3), ("QuotaExceeded", 1)]),
+            merged
+        );
+        merged.merge(hints(Some(3), &[]));
+        assert_eq!(Some(5), merged.available_pods);
+    }
+}
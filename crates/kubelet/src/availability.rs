@@ -0,0 +1,326 @@
+//! Reporting pods as not ready while their provider is unavailable
+//!
+//! A provider that is restarting, or otherwise can't serve its pods for a while, says so
+//! by returning [`ProviderError::Unavailable`] from [`Provider::health`]. The Kubelet
+//! checks the health of the provider regularly and, while it is unavailable, reports the
+//! `Ready` condition of the running pods as `False` with the reason `ProviderUnavailable`
+//! so Services stop sending traffic to them. When the provider is healthy again, the
+//! pods whose readiness wasn't reported since get back the `Ready` condition they had
+//! before.
+//!
+//! These conditions are merged into the pods' statuses like the Kubelet's other status
+//! updates, which keep them until they report the pod's readiness themselves.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::{Pod as KubePod, PodCondition};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::Api;
+use log::{info, warn};
+
+use crate::handle::key_from_pod;
+use crate::pod::Pod;
+use crate::provider::{Provider, ProviderError};
+use crate::registry::PodRegistry;
+use crate::state::State;
+use crate::status::StatusPatch;
+
+/// How often the health of the provider is checked
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The reason reported on the `Ready` condition of pods whose provider is unavailable
+pub(crate) const REASON_PROVIDER_UNAVAILABLE: &str = "ProviderUnavailable";
+
+/// Regularly check the health of the provider, and report its running pods as not ready
+/// while it is unavailable
+pub(crate) async fn run<P: Provider + Sync + Send>(
+    client: kube::Client,
+    provider: Arc<P>,
+    registry: PodRegistry,
+) {
+    let mut availability = Availability::default();
+    loop {
+        tokio::time::delay_for(HEALTH_CHECK_INTERVAL).await;
+        let health = provider.health().await;
+        availability.check(&client, &health, &registry).await;
+    }
+}
+
+/// The pods reported as not ready because the provider is unavailable
+#[derive(Default)]
+struct Availability {
+    /// The pods by key, with the `Ready` condition each had before
+    unavailable: HashMap<String, (Pod, Option<PodCondition>)>,
+}
+
+impl Availability {
+    /// Report the running pods as not ready if the provider is unavailable, or restore
+    /// the pods reported earlier if it is not
+    async fn check(
+        &mut self,
+        client: &kube::Client,
+        health: &anyhow::Result<()>,
+        registry: &PodRegistry,
+    ) {
+        match unavailable_message(health) {
+            Some(message) => self.mark(client, &message, registry).await,
+            None => self.restore(client, registry).await,
+        }
+    }
+
+    /// Report the running pods that were not reported already as not ready
+    async fn mark(&mut self, client: &kube::Client, message: &str, registry: &PodRegistry) {
+        for entry in registry.pods() {
+            let key = key_from_pod(&entry.pod);
+            if entry.state != State::Running || self.unavailable.contains_key(&key) {
+                continue;
+            }
+            let previous = ready_condition(&entry.pod);
+            let condition = PodCondition {
+                type_: "Ready".to_owned(),
+                status: "False".to_owned(),
+                reason: Some(REASON_PROVIDER_UNAVAILABLE.to_owned()),
+                message: Some(message.to_owned()),
+                last_transition_time: Some(Time(Utc::now())),
+                ..Default::default()
+            };
+            if apply(client, &entry.pod, StatusPatch::new().condition(condition)).await {
+                self.unavailable.insert(key, (entry.pod, previous));
+            }
+        }
+    }
+
+    /// Give the pods that are still running and still reported as unavailable back their
+    /// `Ready` condition, or remove it if they had none. Pods that could not be restored
+    /// are tried again on the next check
+    async fn restore(&mut self, client: &kube::Client, registry: &PodRegistry) {
+        if self.unavailable.is_empty() {
+            return;
+        }
+        info!("Provider is available again, restoring the readiness of its pods");
+        let mut failed = HashMap::new();
+        for (key, (pod, previous)) in self.unavailable.drain() {
+            let running = matches!(
+                registry.get(pod.namespace(), pod.name()),
+                Some(entry) if entry.state == State::Running
+            );
+            if !running {
+                // The pod stopped in the meantime, which reports its `Ready` condition
+                continue;
+            }
+            let patch = match previous.clone() {
+                Some(condition) => StatusPatch::new().condition(condition),
+                None => StatusPatch::new().without_condition("Ready"),
+            };
+            let restored = match still_unavailable(client, &pod).await {
+                Ok(true) => apply(client, &pod, patch).await,
+                // The readiness of the pod was reported since it was marked
+                Ok(false) => true,
+                Err(e) => {
+                    warn!(
+                        "Unable to read the status of pod {} in namespace {}: {}",
+                        pod.name(),
+                        pod.namespace(),
+                        e
+                    );
+                    false
+                }
+            };
+            if !restored {
+                failed.insert(key, (pod, previous));
+            }
+        }
+        self.unavailable = failed;
+    }
+}
+
+/// Returns the message of the health error if it says the provider is unavailable
+fn unavailable_message(health: &anyhow::Result<()>) -> Option<String> {
+    match health.as_ref().err()?.downcast_ref::<ProviderError>() {
+        Some(ProviderError::Unavailable { message }) => Some(message.clone()),
+        _ => None,
+    }
+}
+
+/// Whether the `Ready` condition of the pod in the API still reports the provider as
+/// unavailable
+async fn still_unavailable(client: &kube::Client, pod: &Pod) -> kube::Result<bool> {
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let current = pod.rate_limits().limited(api.get(pod.name())).await?;
+    let ready = ready_condition(&Pod::new(current));
+    Ok(ready.and_then(|c| c.reason).as_deref() == Some(REASON_PROVIDER_UNAVAILABLE))
+}
+
+/// Returns the `Ready` condition last reported for the pod
+fn ready_condition(pod: &Pod) -> Option<PodCondition> {
+    pod.as_kube_pod()
+        .status
+        .as_ref()?
+        .conditions
+        .as_ref()?
+        .iter()
+        .find(|c| c.type_ == "Ready")
+        .cloned()
+}
+
+/// Merge the patch into the pod's status, returning whether it was applied
+async fn apply(client: &kube::Client, pod: &Pod, patch: StatusPatch) -> bool {
+    match patch.apply_for(client.clone(), pod).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "Unable to report availability of pod {} in namespace {}: {}",
+                pod.name(),
+                pod.namespace(),
+                e
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    const STATUS_PATH: &str = "/api/v1/namespaces/default/pods/foo/status";
+
+    fn patches(server: &MockApiServer) -> Vec<serde_json::Value> {
+        server
+            .requests_to(hyper::Method::PATCH, STATUS_PATH)
+            .into_iter()
+            .map(|r| {
+                let query = r.query.unwrap();
+                assert!(query.split('&').any(|p| p == "fieldManager=krustlet"));
+                r.body.unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unavailable_message() {
+        let unavailable: anyhow::Result<()> = Err(ProviderError::Unavailable {
+            message: "restarting".to_owned(),
+        }
+        .into());
+        assert_eq!(
+            Some("restarting".to_owned()),
+            unavailable_message(&unavailable)
+        );
+        assert_eq!(None, unavailable_message(&Ok(())));
+        assert_eq!(None, unavailable_message(&Err(anyhow::anyhow!("broken"))));
+    }
+
+    #[tokio::test]
+    async fn test_pods_are_not_ready_while_the_provider_is_unavailable() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.status = Some(
+            serde_json::from_value(serde_json::json!({
+                "conditions": [{ "type": "Ready", "status": "True" }],
+            }))
+            .unwrap(),
+        );
+        server.insert("/api/v1/namespaces/default/pods", &kube_pod);
+        let pod = Pod::new(kube_pod);
+        let registry = PodRegistry::default();
+        registry.set_state(&pod, State::Running);
+        let unavailable: anyhow::Result<()> = Err(ProviderError::Unavailable {
+            message: "restarting".to_owned(),
+        }
+        .into());
+
+        let mut availability = Availability::default();
+        availability
+            .check(&server.client(), &Ok(()), &registry)
+            .await;
+        assert!(patches(&server).is_empty());
+
+        availability
+            .check(&server.client(), &unavailable, &registry)
+            .await;
+        availability
+            .check(&server.client(), &unavailable, &registry)
+            .await;
+        let sent = patches(&server);
+        assert_eq!(1, sent.len(), "pods are only marked once per outage");
+        let condition = &sent[0]["status"]["conditions"][0];
+        assert_eq!("Ready", condition["type"]);
+        assert_eq!("False", condition["status"]);
+        assert_eq!(REASON_PROVIDER_UNAVAILABLE, condition["reason"]);
+        assert_eq!("restarting", condition["message"]);
+
+        availability
+            .check(&server.client(), &Ok(()), &registry)
+            .await;
+        let sent = patches(&server);
+        assert_eq!(2, sent.len());
+        assert_eq!("True", sent[1]["status"]["conditions"][0]["status"]);
+        assert!(availability.unavailable.is_empty());
+
+        // Pods that stopped during the outage are left alone
+        availability
+            .check(&server.client(), &unavailable, &registry)
+            .await;
+        registry.set_state(&pod, State::Terminated);
+        availability
+            .check(&server.client(), &Ok(()), &registry)
+            .await;
+        assert_eq!(3, patches(&server).len());
+    }
+
+    #[tokio::test]
+    async fn test_restore_keeps_newer_readiness() {
+        let server = MockApiServer::start().await.unwrap();
+        let registry = PodRegistry::default();
+        let mut pods = Vec::new();
+        for name in &["foo", "bar"] {
+            let kube_pod = fake_pod(name, "default");
+            server.insert("/api/v1/namespaces/default/pods", &kube_pod);
+            let pod = Pod::new(kube_pod);
+            registry.set_state(&pod, State::Running);
+            pods.push(pod);
+        }
+        let unavailable: anyhow::Result<()> = Err(ProviderError::Unavailable {
+            message: "restarting".to_owned(),
+        }
+        .into());
+        let mut availability = Availability::default();
+        availability
+            .check(&server.client(), &unavailable, &registry)
+            .await;
+
+        // The Kubelet reports bar as ready during the outage
+        StatusPatch::new()
+            .condition(PodCondition {
+                type_: "Ready".to_owned(),
+                status: "True".to_owned(),
+                ..Default::default()
+            })
+            .apply(server.client(), "default", "bar")
+            .await
+            .unwrap();
+        availability
+            .check(&server.client(), &Ok(()), &registry)
+            .await;
+        assert!(availability.unavailable.is_empty());
+
+        let ready = |name: &str| {
+            let pod = server
+                .get(&format!("/api/v1/namespaces/default/pods/{}", name))
+                .unwrap();
+            pod["status"]["conditions"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find(|c| c["type"] == "Ready")
+                .cloned()
+        };
+        // foo had no Ready condition before the outage, so it has none again
+        assert_eq!(None, ready("foo"));
+        assert_eq!("True", ready("bar").unwrap()["status"]);
+    }
+}
@@ -0,0 +1,171 @@
+//! Stopping the Kubelet's subsystems in order
+//!
+//! Each subsystem of the Kubelet runs in its own task and is told to stop with a
+//! [`CancellationToken`], so the Kubelet can stop them one after the other and wait
+//! for each to finish before stopping the next. The tasks are started with
+//! [`Supervisor::spawn`], which turns a failed or panicked task into a request to stop
+//! the whole Kubelet, rather than leaving the others running without it.
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use log::error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Tells a task to stop. Clones share the same signal
+#[derive(Clone)]
+pub(crate) struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl CancellationToken {
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        CancellationToken {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Tell the tasks holding the token to stop
+    pub(crate) fn cancel(&self) {
+        // The token holds a receiver itself, so this can't fail
+        let _ = self.sender.broadcast(true);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Wait until the token is cancelled
+    pub(crate) async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+        while let Some(cancelled) = receiver.recv().await {
+            if cancelled {
+                return;
+            }
+        }
+    }
+
+    /// Run the future until it completes or the token is cancelled, whichever comes
+    /// first. Returns `None` if the token was cancelled
+    pub(crate) async fn run_until_cancelled<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.cancelled() => None,
+        }
+    }
+}
+
+/// Starts the Kubelet's tasks, stopping the Kubelet if any of them fails
+#[derive(Clone)]
+pub(crate) struct Supervisor {
+    stop: CancellationToken,
+    failure: Arc<Mutex<Option<anyhow::Error>>>,
+}
+
+impl Supervisor {
+    /// Create a supervisor that cancels the given token when a task fails
+    pub(crate) fn new(stop: CancellationToken) -> Self {
+        Supervisor {
+            stop,
+            failure: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Run the task in the background. If it returns an error or panics, the first
+    /// such error is kept and the Kubelet is told to stop. Tasks that finish
+    /// successfully, such as those with nothing to do, leave the Kubelet running
+    pub(crate) fn spawn<F>(&self, name: &'static str, task: F) -> JoinHandle<()>
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let task = tokio::spawn(task);
+        tokio::spawn(async move {
+            let result = match task.await {
+                Ok(result) => result,
+                Err(e) => Err(anyhow::anyhow!("task panicked: {}", e)),
+            };
+            if let Err(e) = result {
+                let e = e.context(format!("{} failed", name));
+                error!("{:#}, stopping the Kubelet", e);
+                supervisor.failure.lock().unwrap().get_or_insert(e);
+                supervisor.stop.cancel();
+            }
+        })
+    }
+
+    /// The first error of a failed task, if any
+    pub(crate) fn take_failure(&self) -> Option<anyhow::Error> {
+        self.failure.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn test_cancellation() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        let waiting = tokio::spawn(async move {
+            clone
+                .run_until_cancelled(futures::future::pending::<()>())
+                .await
+        });
+        token.cancel();
+        assert_eq!(
+            None,
+            tokio::time::timeout(TIMEOUT, waiting)
+                .await
+                .unwrap()
+                .unwrap()
+        );
+        assert!(token.is_cancelled());
+        // Waiting on a cancelled token returns straight away
+        tokio::time::timeout(TIMEOUT, token.cancelled())
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(1),
+            CancellationToken::new()
+                .run_until_cancelled(async { 1 })
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_tasks_stop_the_kubelet() {
+        let stop = CancellationToken::new();
+        let supervisor = Supervisor::new(stop.clone());
+        supervisor
+            .spawn("finished", async { Ok(()) })
+            .await
+            .unwrap();
+        assert!(!stop.is_cancelled());
+        assert!(supervisor.take_failure().is_none());
+
+        supervisor
+            .spawn("webserver", async {
+                Err(anyhow::anyhow!("address in use"))
+            })
+            .await
+            .unwrap();
+        supervisor
+            .spawn("pod watch", async { panic!("boom") })
+            .await
+            .unwrap();
+        assert!(stop.is_cancelled());
+        assert_eq!(
+            "webserver failed: address in use",
+            format!("{:#}", supervisor.take_failure().unwrap())
+        );
+    }
+}
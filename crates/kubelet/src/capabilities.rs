@@ -0,0 +1,184 @@
+//! Extra capabilities that embedders give to the workloads of a provider
+//!
+//! Applications that embed the Kubelet can offer platform APIs to workloads, such as
+//! key-value storage, logging or HTTP egress, without forking their provider: they
+//! register each capability under a name with a provider that supports them, and
+//! pods opt in to the capabilities they need by listing their names in the
+//! `alpha.krustlet.dev/capabilities` annotation. What a capability is depends on the
+//! provider. The WASI provider, for example, links the host functions of the pod's
+//! capabilities into its modules.
+//!
+//! # Example
+//! ```rust
+//! use std::sync::Arc;
+//! use kubelet::capabilities::Capabilities;
+//!
+//! trait Greeter: Send + Sync {
+//!     fn greet(&self) -> String;
+//! }
+//!
+//! struct English;
+//!
+//! impl Greeter for English {
+//!     fn greet(&self) -> String {
+//!         "hello".to_owned()
+//!     }
+//! }
+//!
+//! let mut capabilities: Capabilities<dyn Greeter> = Capabilities::new();
+//! capabilities.register("greet", Arc::new(English));
+//!
+//! async fn start(capabilities: &Capabilities<dyn Greeter>, client: kube::Client, pod: kubelet::Pod) {
+//!     // The capabilities listed in the pod's annotation, such as
+//!     // `alpha.krustlet.dev/capabilities: greet`
+//!     for greeter in capabilities.load(&client, &pod).await {
+//!         println!("{}", greeter.greet());
+//!     }
+//! }
+//! ```
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::annotations::{self, AnnotationConfig, AnnotationError, Annotations, PREFIX};
+use crate::pod::Pod;
+
+/// The annotation, without the [`PREFIX`], that lists the capabilities of a pod
+pub const ANNOTATION: &str = "capabilities";
+
+/// The capabilities registered with a provider, by name.
+///
+/// The registry can be cloned cheaply, as clones share the capabilities themselves.
+pub struct Capabilities<C: ?Sized> {
+    registered: BTreeMap<String, Arc<C>>,
+}
+
+impl<C: ?Sized> Default for Capabilities<C> {
+    fn default() -> Self {
+        Capabilities {
+            registered: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C: ?Sized> Clone for Capabilities<C> {
+    fn clone(&self) -> Self {
+        Capabilities {
+            registered: self.registered.clone(),
+        }
+    }
+}
+
+/// The names a pod lists in its capabilities annotation
+struct Requested(Vec<String>);
+
+impl AnnotationConfig for Requested {
+    fn from_annotations(annotations: &mut Annotations) -> Self {
+        let names = annotations
+            .raw(ANNOTATION)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_owned)
+            .collect();
+        Requested(names)
+    }
+}
+
+impl<C: ?Sized> Capabilities<C> {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a capability that pods can opt in to with the given name, replacing
+    /// any capability already registered with it
+    pub fn register(&mut self, name: &str, capability: Arc<C>) {
+        self.registered.insert(name.to_owned(), capability);
+    }
+
+    /// Returns the names of the registered capabilities, in order
+    pub fn names(&self) -> Vec<&str> {
+        self.registered.keys().map(String::as_str).collect()
+    }
+
+    /// Returns the capabilities a pod opted in to, in the order they are listed in its
+    /// annotation, alongside errors for the names that are not registered
+    pub fn requested(&self, pod: &Pod) -> (Vec<Arc<C>>, Vec<AnnotationError>) {
+        let (Requested(names), _) = annotations::parse::<Requested>(pod);
+        let mut capabilities = Vec::new();
+        let mut errors = Vec::new();
+        for name in names {
+            match self.registered.get(&name) {
+                Some(capability) => capabilities.push(capability.clone()),
+                None => errors.push(AnnotationError {
+                    key: format!("{}{}", PREFIX, ANNOTATION),
+                    value: pod
+                        .get_annotation(&format!("{}{}", PREFIX, ANNOTATION))
+                        .unwrap_or_default()
+                        .to_owned(),
+                    reason: format!(
+                        "unknown capability {:?}, the available capabilities are {:?}",
+                        name,
+                        self.names()
+                    ),
+                }),
+            }
+        }
+        (capabilities, errors)
+    }
+
+    /// Returns the capabilities a pod opted in to.
+    ///
+    /// Names that are not registered are ignored and recorded as `InvalidAnnotation`
+    /// warning events on the pod, like other invalid annotations.
+    pub async fn load(&self, client: &kube::Client, pod: &Pod) -> Vec<Arc<C>> {
+        let (capabilities, errors) = self.requested(pod);
+        annotations::report(client, pod, errors).await;
+        capabilities
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+
+    fn pod_with_capabilities(capabilities: &str) -> Pod {
+        let mut pod = fake_pod("foo", "default");
+        pod.metadata.as_mut().unwrap().annotations = Some(
+            vec![(
+                "alpha.krustlet.dev/capabilities".to_owned(),
+                capabilities.to_owned(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+        Pod::new(pod)
+    }
+
+    #[test]
+    fn test_requested_capabilities() {
+        let mut capabilities: Capabilities<str> = Capabilities::new();
+        capabilities.register("kv", Arc::from("key-value"));
+        capabilities.register("http", Arc::from("http egress"));
+        assert_eq!(vec!["http", "kv"], capabilities.names());
+
+        let (requested, errors) = capabilities.requested(&pod_with_capabilities("kv, ,http"));
+        assert!(errors.is_empty());
+        assert_eq!(
+            vec!["key-value", "http egress"],
+            requested.iter().map(|c| &**c).collect::<Vec<_>>()
+        );
+
+        let (requested, errors) = capabilities.requested(&pod_with_capabilities("kv,logging"));
+        assert_eq!(1, requested.len());
+        assert_eq!(1, errors.len());
+        assert_eq!("alpha.krustlet.dev/capabilities", errors[0].key);
+        assert!(errors[0].reason.contains("\"logging\""));
+
+        let (requested, errors) = capabilities.requested(&fake_pod("bar", "default").into());
+        assert!(requested.is_empty());
+        assert!(errors.is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+//! Running a provider as a Kubelet binary
+//!
+//! Every Kubelet binary does the same things before its provider gets to run: it
+//! installs a logger, parses its flags, loads and configures the kubeconfig, shuts the
+//! Kubelet down in order on SIGTERM or SIGINT and, on Windows, runs as a service when
+//! started by the service control manager. [`run_provider`] does all of this, leaving
+//! only the construction of the provider to the binary:
+//!
+//! ```rust,no_run
+//! # struct MyProvider;
+//! # #[async_trait::async_trait]
+//! # impl kubelet::Provider for MyProvider {
+//! #     const ARCH: &'static str = "my-arch";
+//! #     async fn add(&self, pod: kubelet::Pod) -> anyhow::Result<()> { todo!() }
+//! #     async fn modify(&self, pod: kubelet::Pod) -> anyhow::Result<()> { todo!() }
+//! #     async fn delete(&self, pod: kubelet::Pod) -> anyhow::Result<()> { todo!() }
+//! #     async fn logs(&self, namespace: String, pod: String, container: String, sender: kubelet::LogSender) -> anyhow::Result<()> { todo!() }
+//! # }
+//! # impl MyProvider {
+//! #     async fn new(config: &kubelet::config::Config, client: kube::Client) -> anyhow::Result<Self> { Ok(MyProvider) }
+//! # }
+//! #[tokio::main]
+//! async fn main() -> anyhow::Result<()> {
+//!     kubelet::cli::run_provider(
+//!         "krustlet-mine",
+//!         env!("CARGO_PKG_VERSION"),
+//!         |config, client| async move { MyProvider::new(&config, client).await },
+//!     )
+//!     .await
+//! }
+//! ```
+//!
+//! [`run_provider_with_client_factory`] does the same with the Kubernetes clients of
+//! both the Kubelet and the provider made by a
+//! [`ClientFactory`](crate::kubeconfig::ClientFactory).
+use std::future::Future;
+#[cfg(windows)]
+use std::sync::{Arc, Mutex};
+
+use log::{error, info};
+
+use crate::config::Config;
+use crate::kubeconfig::{ClientFactory, ClientPurpose};
+use crate::log_filter::LogFilter;
+use crate::{Kubelet, Provider};
+
+/// Run a Kubelet for the provider made by `new_provider` until the process is stopped.
+///
+/// The provider is given the Kubelet's configuration, parsed from the command line
+/// flags with the given version, and a client that is held to the same timeout and
+/// sends the same headers as the Kubelet's own requests. When started by the Windows
+/// service control manager, the Kubelet runs as the service with the given name and
+/// stops with it.
+pub async fn run_provider<P, F, Fut>(
+    name: &'static str,
+    version: &'static str,
+    new_provider: F,
+) -> anyhow::Result<()>
+where
+    P: 'static + Provider + Sync + Send,
+    F: FnOnce(Config, kube::Client) -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<P>> + Send + 'static,
+{
+    run_provider_with_client_factory(
+        name,
+        version,
+        crate::kubeconfig::default_client,
+        new_provider,
+    )
+    .await
+}
+
+/// Run a Kubelet like [`run_provider`], making the clients of the Kubelet and the
+/// provider with the given factory
+pub async fn run_provider_with_client_factory<C, P, F, Fut>(
+    name: &'static str,
+    version: &'static str,
+    factory: C,
+    new_provider: F,
+) -> anyhow::Result<()>
+where
+    C: 'static + ClientFactory,
+    P: 'static + Provider + Sync + Send,
+    F: FnOnce(Config, kube::Client) -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<P>> + Send + 'static,
+{
+    // When started as a Windows service, the service control manager decides when to stop
+    #[cfg(windows)]
+    let (factory, new_provider) = {
+        let shared = Arc::new(Mutex::new(Some((factory, new_provider))));
+        let service_provider = shared.clone();
+        let service = move |shutdown| async move {
+            let taken = service_provider.lock().unwrap().take();
+            let (factory, new_provider) = taken.expect("service started twice");
+            // The event log logger is not filtered by directives
+            run(
+                version,
+                factory,
+                new_provider,
+                shutdown,
+                LogFilter::default(),
+            )
+            .await
+        };
+        if crate::windows::run_as_service(name, service).await? {
+            return Ok(());
+        }
+        // The service function is dropped unused when not started as a service
+        let taken = shared.lock().unwrap().take();
+        taken.expect("service function was run")
+    };
+    #[cfg(not(windows))]
+    let _ = name;
+
+    let log_filter = init_logger()?;
+    run(version, factory, new_provider, stop_signal(), log_filter).await
+}
+
+/// Resolves once the process is asked to stop, with SIGTERM or SIGINT on Unix and
+/// Ctrl-C elsewhere, so the Kubelet stops its pods in order before it exits
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) {
+            (Ok(mut terminate), Ok(mut interrupt)) => {
+                futures::future::select(Box::pin(terminate.recv()), Box::pin(interrupt.recv()))
+                    .await;
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Unable to listen for stop signals: {}", e);
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Unable to listen for Ctrl-C: {}", e);
+            futures::future::pending::<()>().await;
+        }
+    }
+    info!("Received a stop signal, shutting down");
+}
+
+async fn run<C, P, F, Fut, S>(
+    version: &str,
+    factory: C,
+    new_provider: F,
+    shutdown: S,
+    log_filter: LogFilter,
+) -> anyhow::Result<()>
+where
+    C: 'static + ClientFactory,
+    P: 'static + Provider + Sync + Send,
+    F: FnOnce(Config, kube::Client) -> Fut,
+    Fut: Future<Output = anyhow::Result<P>>,
+    S: Future<Output = ()>,
+{
+    let config = Config::new_from_flags(version);
+
+    let mut kubeconfig = crate::kubeconfig::load(config.kubeconfig.as_deref()).await?;
+    // Requests made by the provider are held to the same timeout as the Kubelet's, and
+    // are identified the same way
+    crate::kubeconfig::configure(&mut kubeconfig, &config.api_client)?;
+
+    let client = factory.client(ClientPurpose::Requests, kubeconfig.clone())?;
+    let provider = new_provider(config.clone(), client).await?;
+    let kubelet = Kubelet::new(provider, kubeconfig, config)
+        .with_client_factory(factory)
+        .with_log_filter(log_filter);
+    kubelet.start_with_shutdown(shutdown).await
+}
+
+fn init_logger() -> anyhow::Result<LogFilter> {
+    // Send entries straight to journald if requested
+    #[cfg(unix)]
+    {
+        if crate::systemd::journald_requested() {
+            return crate::systemd::JournalLogger::init();
+        }
+    }
+    // Which entries are written is decided by the Kubelet, so it can be changed at runtime
+    let logger = env_logger::Builder::new().parse_filters("trace").build();
+    crate::log_filter::init(Box::new(logger), "")
+}
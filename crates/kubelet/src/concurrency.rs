@@ -0,0 +1,190 @@
+//! Limiting how many heavy operations run at once
+//!
+//! Pulling images, compiling modules and setting up volumes are heavy on the network,
+//! CPU or disk. When many pods start at once, running all of them together slows
+//! everything down, including the Kubelet's own status updates and node heartbeats.
+//! Each class of [`Operation`] has its own limit, so a burst of pulls doesn't hold
+//! up compilations and the other way around, and operations over the limit wait
+//! their turn in the order they arrived.
+//!
+//! The limits are set with [`Config::concurrency`](crate::config::Config::concurrency).
+//! The Kubelet shares its [`Limits`] with the pods it hands to providers, and providers
+//! wrap the heavy operations for a pod in [`Limits::run`], or hold a permit from
+//! [`Limits::acquire`] for as long as the operation runs:
+//!
+//! ```rust
+//! use kubelet::concurrency::Operation;
+//!
+//! # async fn compile(module: &[u8]) {}
+//! # async {
+//! # let pod = kubelet::Pod::default();
+//! # let module = Vec::new();
+//! pod.concurrency()
+//!     .run(Operation::ModuleCompilation, compile(&module))
+//!     .await;
+//! # };
+//! ```
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use crate::config::ConcurrencyConfig;
+
+/// A class of heavy operation with its own limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Pulling an image or module from a registry or other store
+    ImagePull,
+    /// Compiling a module before it runs
+    ModuleCompilation,
+    /// Filling a volume, such as with the keys of a secret
+    VolumeSetup,
+}
+
+/// The limits on each class of operation. Cloning them shares the limits, and the
+/// default doesn't limit operations at all
+#[derive(Clone, Debug, Default)]
+pub struct Limits(Option<Arc<Semaphores>>);
+
+#[derive(Debug)]
+struct Semaphores {
+    image_pulls: Arc<Semaphore>,
+    module_compilations: Arc<Semaphore>,
+    volume_setups: Arc<Semaphore>,
+}
+
+/// Allows an operation to run. The slot is given to the next waiting operation when
+/// this is dropped
+pub struct Permit {
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(semaphore) = &self.semaphore {
+            semaphore.add_permits(1);
+        }
+    }
+}
+
+impl Limits {
+    /// Create the limits of each class of operation
+    pub fn new(config: &ConcurrencyConfig) -> Self {
+        let semaphore = |limit: usize| Arc::new(Semaphore::new(limit.max(1)));
+        Limits(Some(Arc::new(Semaphores {
+            image_pulls: semaphore(config.image_pulls),
+            module_compilations: semaphore(config.module_compilations),
+            volume_setups: semaphore(config.volume_setups),
+        })))
+    }
+
+    /// Wait until an operation of the given class may run. Operations wait their turn
+    /// in the order they arrived, and one that stops waiting gives its turn to the next
+    pub async fn acquire(&self, operation: Operation) -> Permit {
+        let semaphore = match &self.0 {
+            Some(semaphores) => match operation {
+                Operation::ImagePull => semaphores.image_pulls.clone(),
+                Operation::ModuleCompilation => semaphores.module_compilations.clone(),
+                Operation::VolumeSetup => semaphores.volume_setups.clone(),
+            },
+            None => return Permit { semaphore: None },
+        };
+        // The permit borrows the semaphore, so the slot is handed back by the permit
+        // that owns it instead
+        semaphore.acquire().await.forget();
+        Permit {
+            semaphore: Some(semaphore),
+        }
+    }
+
+    /// Run an operation of the given class once it may
+    pub async fn run<F: Future>(&self, operation: Operation, future: F) -> F::Output {
+        let _permit = self.acquire(operation).await;
+        future.await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+    use std::task::{Context, Poll};
+
+    fn limits(limit: usize) -> Limits {
+        Limits::new(&ConcurrencyConfig {
+            image_pulls: limit,
+            module_compilations: limit,
+            volume_setups: limit,
+        })
+    }
+
+    /// Poll a future once, so that it waits in line if it can't finish yet
+    fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        let waker = futures::task::noop_waker();
+        future.poll_unpin(&mut Context::from_waker(&waker))
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let limits = limits(2);
+        let first = limits.acquire(Operation::ImagePull).await;
+        let _second = limits.acquire(Operation::ImagePull).await;
+
+        let mut third = Box::pin(limits.acquire(Operation::ImagePull));
+        let mut fourth = Box::pin(limits.acquire(Operation::ImagePull));
+        assert!(poll_once(&mut third).is_pending());
+        assert!(poll_once(&mut fourth).is_pending());
+
+        // The slots go to the waiters in the order they arrived
+        drop(first);
+        assert!(poll_once(&mut fourth).is_pending());
+        let third = match poll_once(&mut third) {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!("the first waiter should get the slot"),
+        };
+        drop(third);
+        assert!(poll_once(&mut fourth).is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiters_give_up_their_slot() {
+        let limits = limits(1);
+        let first = limits.acquire(Operation::ImagePull).await;
+        let mut cancelled = Box::pin(limits.acquire(Operation::ImagePull));
+        assert!(poll_once(&mut cancelled).is_pending());
+
+        // The slot is handed to the waiter, which goes away before it is polled again
+        drop(first);
+        drop(cancelled);
+        assert!(limits
+            .acquire(Operation::ImagePull)
+            .now_or_never()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_classes_are_limited_separately() {
+        let limits = limits(1);
+        let _pull = limits.acquire(Operation::ImagePull).await;
+        assert!(limits
+            .acquire(Operation::ModuleCompilation)
+            .now_or_never()
+            .is_some());
+        assert!(limits
+            .acquire(Operation::ImagePull)
+            .now_or_never()
+            .is_none());
+
+        // Without limits nothing waits
+        let unlimited = Limits::default();
+        let _pulls: Vec<_> = (0..10)
+            .map(|_| {
+                unlimited
+                    .acquire(Operation::ImagePull)
+                    .now_or_never()
+                    .unwrap()
+            })
+            .collect();
+    }
+}
@@ -3,18 +3,32 @@
 //! The best way to configure the kubelet is by using [`Config::default_config`]
 //! or by turning on the "cli" feature and using [`Config::new_from_flags`].
 
-use std::net::IpAddr;
-use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use anyhow::Context;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube::api::ListParams;
+use oci_distribution::client::RegistryTlsConfig;
 use rpassword;
+use serde::Serialize;
 #[cfg(feature = "cli")]
 use structopt::StructOpt;
+use thiserror::Error;
 
 use std::collections::HashMap;
 
 const DEFAULT_PORT: u16 = 3000;
 
+/// The largest stack pods' modules may ask for by default, which is wasmtime's default
+/// stack size
+const DEFAULT_MAX_WASM_STACK: usize = 1024 * 1024;
+
+/// The longest DNS subdomain, such as a node name, that Kubernetes accepts
+const MAX_SUBDOMAIN_LENGTH: usize = 253;
+
 /// The configuration needed for a kubelet to run properly.
 ///
 /// This can be configured manually in your code or if you are exposing a CLI, use the
@@ -23,7 +37,7 @@ const DEFAULT_PORT: u16 = 3000;
 ///
 /// Use [`Config::default_config`] to generate a config with all
 /// of the default values set.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Config {
     /// The ip address the node is exposed on
     pub node_ip: IpAddr,
@@ -37,9 +51,318 @@ pub struct Config {
     pub data_dir: PathBuf,
     /// Labels to add when registering the node in the cluster
     pub node_labels: HashMap<String, String>,
+    /// What the Kubelet does when a node with its name is already registered
+    pub node_adoption: AdoptionPolicy,
+    /// The kubeconfig file to connect to the Kubernetes API with. When this is
+    /// not set, the configuration is inferred from the environment, using the pod's
+    /// service account when running in a cluster. See
+    /// [`kubeconfig::load`](crate::kubeconfig::load)
+    pub kubeconfig: Option<PathBuf>,
+    /// When set, several Kubelets can back this node and only the one elected as
+    /// leader runs it. See [`leader`](crate::leader)
+    pub leader_election: Option<LeaderElectionConfig>,
+    /// Limits on the requests made to the Kubernetes API
+    pub api_client: ApiClientConfig,
+    /// Timeouts and connection pooling of the client that images are pulled from
+    /// registries with
+    pub registry_client: RegistryClientConfig,
+    /// Limits on how many heavy operations run at once. See
+    /// [`concurrency`](crate::concurrency)
+    pub concurrency: ConcurrencyConfig,
+    /// How long the node's shutdown is delayed so its pods can be stopped gracefully.
+    /// Graceful node shutdown is disabled when this is zero
+    pub shutdown_grace_period: Duration,
+    /// How much of the shutdown grace period is kept for critical pods, such as node
+    /// agents, which are stopped after the node's other pods. When none of it is kept,
+    /// critical pods share what is left once the other pods have stopped. See
+    /// [`Pod::is_critical`](crate::Pod::is_critical)
+    pub shutdown_grace_period_critical_pods: Duration,
+    /// When set, only the node's pods with labels matching this selector are run by
+    /// this Kubelet, so the node's pods can be split between several Kubelets
+    pub pod_label_selector: Option<String>,
+    /// A field selector that the node's pods must also match to be run by this
+    /// Kubelet, in addition to `spec.nodeName`
+    pub pod_field_selector: Option<String>,
+    /// The namespaces whose pods this Kubelet runs. Pods from other namespaces are
+    /// failed without reaching the provider
+    pub namespaces: NamespaceFilter,
+    /// Caps on what the pods of each namespace may use on this node, by namespace and
+    /// then resource, `pods` or `memory`, such as `pods: 10`. They are enforced when
+    /// pods are admitted, independently of any `ResourceQuota` in the cluster. The
+    /// caps of the `*` namespace apply to each namespace without caps of its own
+    pub namespace_quotas: HashMap<String, HashMap<String, String>>,
+    /// The Bindle server that the modules of bindle images are fetched from. See
+    /// [`module_store::bindle`](crate::module_store::bindle)
+    pub bindle_server: Option<reqwest::Url>,
+    /// Resources reserved for the operating system and its daemons, by resource name,
+    /// such as `cpu: 500m`. They are subtracted from the node's allocatable resources
+    pub system_reserved: HashMap<String, String>,
+    /// Resources reserved for the Kubelet itself, by resource name. They are
+    /// subtracted from the node's allocatable resources
+    pub kube_reserved: HashMap<String, String>,
+    /// The directory container logs are also written to for node level log shippers
+    /// to collect, or `None` to only serve them over HTTP. See
+    /// [`pod_logs`](crate::pod_logs)
+    pub pod_log_dir: Option<PathBuf>,
+    /// The DNS domain of the cluster, which the fully qualified domain names of pods
+    /// with a subdomain end with
+    pub cluster_domain: String,
+    /// The largest stack, in bytes, that the WebAssembly modules of pods may ask for.
+    /// Larger stacks that pods ask for are cut down to this
+    pub max_wasm_stack: usize,
+    /// What the WASI sandbox lets the modules of pods access
+    pub wasi_sandbox: WasiSandboxConfig,
+    /// Whether the modules and pods the Kubelet runs are recorded in an audit log in
+    /// the data directory. See [`audit`](crate::audit)
+    pub audit_log: bool,
+    /// When set, the custom resource definitions the provider depends on are only
+    /// checked to be installed when the Kubelet starts, instead of being applied, for
+    /// clusters where the Kubelet is not allowed to manage them
+    pub skip_crd_registration: bool,
+    /// The unix socket node operators reach the admin API on, or `None` to not serve
+    /// it. See [`admin`](crate::admin)
+    pub admin_socket: Option<PathBuf>,
+    /// The images whose modules are fetched before any pod uses them. See
+    /// [`prefetch`](crate::prefetch)
+    pub prefetch: PrefetchConfig,
+    /// The failures injected into the Kubelet, to test how it recovers from them. See
+    /// [`faults`](crate::faults)
+    #[cfg(feature = "fault-injection")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "fault-injection")))]
+    pub faults: crate::faults::Faults,
+}
+
+/// Limits on the requests made to the Kubernetes API, so that many Kubelets don't
+/// overwhelm a small API server and a hung request doesn't stall pod syncing
+#[derive(Clone, Debug, Serialize)]
+pub struct ApiClientConfig {
+    /// The sustained number of requests per second. Rate limiting is disabled when
+    /// this is not positive
+    pub qps: f64,
+    /// The number of requests that can be made at once before `qps` applies
+    pub burst: u32,
+    /// How long a request can take before it fails. Watches are not affected
+    pub request_timeout: Duration,
+    /// The User-Agent sent with every request, so the Kubelet's traffic can be told
+    /// apart from that of other clients in audit logs and metrics
+    pub user_agent: String,
+    /// Extra headers sent with every request, so proxies and audit policies in front of
+    /// the API server can tell the fleet's traffic apart. API Priority and Fairness
+    /// classifies requests by the user making them, so a fleet of Kubelets gets its own
+    /// flow schema through the credentials it is given instead
+    pub headers: HashMap<String, String>,
+}
+
+impl Default for ApiClientConfig {
+    fn default() -> Self {
+        ApiClientConfig {
+            qps: 5.0,
+            burst: 10,
+            request_timeout: Duration::from_secs(30),
+            user_agent: default_user_agent(env!("CARGO_PKG_VERSION")),
+            headers: HashMap::new(),
+        }
+    }
+}
+
+/// Timeouts and connection pooling of the client images are pulled with. Modules are
+/// pulled over long-lived connections that are shared by all pulls from a registry
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RegistryClientConfig {
+    /// How long a request to a registry, including reading its response, can take
+    /// before it fails, or `None` for no limit
+    pub timeout: Option<Duration>,
+    /// How long connecting to a registry can take, or `None` for no limit
+    pub connect_timeout: Option<Duration>,
+    /// How many idle connections to each registry are kept, or `None` for no limit
+    pub max_idle_per_host: Option<usize>,
+    /// Talk HTTP/2 to registries without negotiating it first, so that all requests to
+    /// a registry share one connection. Only for registries known to support HTTP/2
+    pub http2_prior_knowledge: bool,
+    /// The directory with the CA certificates and client certificates of registries
+    /// signed by private CAs, laid out as [`RegistryTlsConfig::load_dir`] expects. When
+    /// this is not set, the `certs.d` directory in the data directory is used if it
+    /// exists
+    pub certs_dir: Option<PathBuf>,
+}
+
+impl RegistryClientConfig {
+    /// The configuration of an OCI client with these settings, reading the TLS
+    /// settings of registries from the certs directory, or from `certs.d` in the given
+    /// data directory
+    pub fn client_config(
+        &self,
+        data_dir: &Path,
+    ) -> anyhow::Result<oci_distribution::client::ClientConfig> {
+        let registry_tls = match &self.certs_dir {
+            Some(dir) => RegistryTlsConfig::load_dir(dir)
+                .with_context(|| format!("unable to read registry certificates in {:?}", dir))?,
+            None => {
+                let dir = data_dir.join("certs.d");
+                if dir.exists() {
+                    RegistryTlsConfig::load_dir(&dir).with_context(|| {
+                        format!("unable to read registry certificates in {:?}", dir)
+                    })?
+                } else {
+                    HashMap::new()
+                }
+            }
+        };
+        Ok(oci_distribution::client::ClientConfig {
+            timeout: self.timeout,
+            connect_timeout: self.connect_timeout,
+            max_idle_per_host: self.max_idle_per_host,
+            http2_prior_knowledge: self.http2_prior_knowledge,
+            registry_tls,
+            ..Default::default()
+        })
+    }
+}
+
+/// Returns the User-Agent of a Kubelet of the given version, such as
+/// `krustlet/0.3.0 (linux/x86_64)`
+pub fn default_user_agent(version: &str) -> String {
+    format!(
+        "krustlet/{} ({}/{})",
+        version,
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )
+}
+
+/// How many operations of each heavy class can run at once, so that starting many
+/// pods doesn't starve status updates and heartbeats
+#[derive(Clone, Debug, Serialize)]
+pub struct ConcurrencyConfig {
+    /// How many images or modules can be pulled at once
+    pub image_pulls: usize,
+    /// How many modules can be compiled at once
+    pub module_compilations: usize,
+    /// How many volumes can be set up at once
+    pub volume_setups: usize,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig {
+            image_pulls: 5,
+            module_compilations: 2,
+            volume_setups: 10,
+        }
+    }
+}
+
+/// The namespaces whose pods a Kubelet runs, so that nodes in a shared cluster only
+/// run workloads from designated namespaces
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct NamespaceFilter {
+    /// The only namespaces whose pods are run. Pods from any namespace are run when
+    /// this is empty
+    pub allowed: Vec<String>,
+    /// The namespaces whose pods are never run, even if they are also allowed
+    pub blocked: Vec<String>,
+}
+
+impl NamespaceFilter {
+    /// Returns true if pods from the given namespace may run
+    pub fn allows(&self, namespace: &str) -> bool {
+        (self.allowed.is_empty() || self.allowed.iter().any(|n| n == namespace))
+            && !self.blocked.iter().any(|n| n == namespace)
+    }
+}
+
+/// The images a Kubelet fetches when it starts, so that pods using them start quickly
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PrefetchConfig {
+    /// The references of the images
+    pub images: Vec<String>,
+    /// Whether more images are listed in an annotation of the Kubelet's node, see
+    /// [`PREFETCH_IMAGES_ANNOTATION`](crate::prefetch::PREFETCH_IMAGES_ANNOTATION)
+    pub from_node: bool,
+}
+
+/// What the node lets the modules of pods access in the WASI sandbox. Pods ask for
+/// access with annotations, and are given what this allows
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WasiSandboxConfig {
+    /// The node's environment variables that pods may pass through to their modules
+    pub env_passthrough: Vec<String>,
+    /// The host directories, and the directories below them, that `hostPath` volumes
+    /// may be preopened from. `None` allows any directory
+    pub preopened_dirs: Option<Vec<PathBuf>>,
+    /// Whether modules may read the wall clock
+    pub wall_clock: bool,
+    /// Whether modules may get random numbers
+    pub random: bool,
+}
+
+impl Default for WasiSandboxConfig {
+    fn default() -> Self {
+        WasiSandboxConfig {
+            env_passthrough: Vec::new(),
+            preopened_dirs: None,
+            wall_clock: true,
+            random: true,
+        }
+    }
+}
+
+/// What the Kubelet does when a node with its name is already registered, such as by
+/// an earlier run of the Kubelet or by another Kubelet given the same name by mistake
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub enum AdoptionPolicy {
+    /// Adopt the node, applying the Kubelet's definition on top of it
+    #[default]
+    Adopt,
+    /// Adopt the node only if it has every label the Kubelet registers it with
+    MatchingLabels,
+    /// Adopt the node unless it is owned by another Kubelet instance, as recorded in
+    /// its [`NODE_OWNER_ANNOTATION`](crate::NODE_OWNER_ANNOTATION)
+    Unowned,
+    /// Take the node over, replacing labels, annotations and taints set by anyone else
+    Overwrite,
+}
+
+impl std::str::FromStr for AdoptionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "adopt" => Ok(AdoptionPolicy::Adopt),
+            "matching-labels" => Ok(AdoptionPolicy::MatchingLabels),
+            "unowned" => Ok(AdoptionPolicy::Unowned),
+            "overwrite" => Ok(AdoptionPolicy::Overwrite),
+            other => Err(anyhow::anyhow!(
+                "unknown node adoption policy {:?}, expected adopt, matching-labels, unowned or overwrite",
+                other
+            )),
+        }
+    }
+}
+
+/// The configuration for electing a leader among Kubelets sharing a node
+#[derive(Clone, Debug, Serialize)]
+pub struct LeaderElectionConfig {
+    /// The namespace of the lease used to elect the leader
+    pub lease_namespace: String,
+    /// How long the leader holds the lease without renewing it. Other Kubelets take
+    /// over once it expires
+    pub lease_duration: Duration,
+    /// How often the leader renews the lease and the other Kubelets try to acquire it
+    pub retry_period: Duration,
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        LeaderElectionConfig {
+            lease_namespace: "kube-system".to_owned(),
+            lease_duration: Duration::from_secs(15),
+            retry_period: Duration::from_secs(2),
+        }
+    }
 }
 /// The configuration for the Kubelet server.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct ServerConfig {
     /// The ip address the Kubelet server is running on
     pub addr: IpAddr,
@@ -47,8 +370,48 @@ pub struct ServerConfig {
     pub port: u16,
     /// The path to a pfx file needed for TLS
     pub pfx_path: PathBuf,
-    /// The password for decrypting the pfx file
+    /// The password for decrypting the pfx file, which is never serialized
+    #[serde(skip)]
     pub pfx_password: String,
+    /// The path to a PEM file of the certificate needed for TLS, followed by its chain,
+    /// such as the `tls.crt` that cert-manager writes. When it is set with
+    /// `private_key_file`, it is used instead of the pfx file. Only supported on Linux
+    pub cert_file: Option<PathBuf>,
+    /// The path to a PEM file of the private key of `cert_file`, such as `tls.key`
+    pub private_key_file: Option<PathBuf>,
+    /// How requests to the Kubelet server are authenticated and authorized
+    pub auth: AuthConfig,
+    /// When set, the server only serves endpoints that read from the node, such as
+    /// logs (without following them), pods and stats. Exec, attach and port
+    /// forwarding, as well as anything that is not a `GET`, are refused
+    pub read_only: bool,
+    /// When set, the server runs on a Tokio runtime of its own with this many worker
+    /// threads, so heavy log streaming or exec traffic can't starve the pod watch and
+    /// the node heartbeats. Otherwise it shares the runtime the Kubelet runs on
+    pub worker_threads: Option<usize>,
+}
+
+/// The authentication and authorization settings for the Kubelet server.
+///
+/// Without a token file or client CA every request is allowed. Authorization rules
+/// follow the same conventions as Kubernetes, where the user of a client certificate
+/// is its common name (CN) and its groups are its organizations (O).
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AuthConfig {
+    /// A file of static bearer tokens, in the same format as the API server's
+    /// `--token-auth-file`: one `token,user,uid,"group1,group2"` line per token.
+    /// When set, requests without one of these tokens are rejected
+    pub token_file: Option<PathBuf>,
+    /// A PEM file of the certificate authorities that sign client certificates. When
+    /// set, clients can authenticate with a certificate signed by one of them, and
+    /// requests with neither a certificate nor a token are rejected. Only supported on
+    /// Linux
+    pub client_ca_file: Option<PathBuf>,
+    /// The users allowed to use the server. When this and `authorized_groups` are
+    /// both empty, any authenticated user is allowed
+    pub authorized_users: Vec<String>,
+    /// The groups whose members are allowed to use the server
+    pub authorized_groups: Vec<String>,
 }
 
 impl Config {
@@ -63,22 +426,272 @@ impl Config {
             node_ip: default_node_ip(&mut hostname.clone(), preferred_ip_family)?,
             node_name: sanitize_hostname(&hostname),
             node_labels: HashMap::new(),
+            node_adoption: AdoptionPolicy::default(),
+            kubeconfig: None,
+            leader_election: None,
+            api_client: ApiClientConfig::default(),
+            registry_client: RegistryClientConfig::default(),
+            concurrency: ConcurrencyConfig::default(),
+            shutdown_grace_period: Duration::from_secs(0),
+            shutdown_grace_period_critical_pods: Duration::from_secs(0),
+            pod_label_selector: None,
+            pod_field_selector: None,
+            namespaces: NamespaceFilter::default(),
+            namespace_quotas: HashMap::new(),
+            bindle_server: None,
+            system_reserved: HashMap::new(),
+            kube_reserved: HashMap::new(),
+            pod_log_dir: Some(PathBuf::from(crate::pod_logs::DEFAULT_DIR)),
+            cluster_domain: crate::pod::DEFAULT_CLUSTER_DOMAIN.to_owned(),
+            max_wasm_stack: DEFAULT_MAX_WASM_STACK,
+            wasi_sandbox: WasiSandboxConfig::default(),
+            audit_log: false,
+            skip_crd_registration: false,
+            admin_socket: None,
+            prefetch: PrefetchConfig::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
             hostname,
             data_dir: default_data_dir()?,
             server_config: ServerConfig {
-                addr: match preferred_ip_family {
-                    // Just unwrap these because they are programmer error if they
-                    // don't parse
-                    IpAddr::V4(_) => "0.0.0.0".parse().unwrap(),
-                    IpAddr::V6(_) => "::".parse().unwrap(),
-                },
+                addr: unspecified_addr(preferred_ip_family),
                 port: DEFAULT_PORT,
                 pfx_password: String::new(),
                 pfx_path: default_pfx_path(),
+                cert_file: None,
+                private_key_file: None,
+                auth: AuthConfig::default(),
+                read_only: false,
+                worker_threads: None,
             },
         })
     }
 
+    /// Checks that the configuration can be used to run a Kubelet, returning every
+    /// problem with it at once.
+    ///
+    /// Validation leaves nothing on disk: a data directory that does not exist yet is
+    /// not created, only checked to be creatable. [`Kubelet::start`] validates its
+    /// configuration before doing anything else.
+    ///
+    /// [`Kubelet::start`]: crate::Kubelet::start
+    pub fn validate(&self) -> Result<(), InvalidConfig> {
+        let mut problems = Vec::new();
+        if self.server_config.port == 0 {
+            problems.push("the server port must be between 1 and 65535".to_owned());
+        }
+        if self.max_wasm_stack == 0 {
+            problems.push("the largest wasm stack must be at least one byte".to_owned());
+        }
+        if self.server_config.worker_threads == Some(0) {
+            problems.push("the server needs at least one worker thread".to_owned());
+        }
+        if !is_reachable_on(&self.server_config.addr, &self.node_ip) {
+            problems.push(format!(
+                "the server listens on {}, so it can't be reached on the node IP {}",
+                self.server_config.addr, self.node_ip
+            ));
+        }
+        match (
+            &self.server_config.cert_file,
+            &self.server_config.private_key_file,
+        ) {
+            (None, None) => {
+                if !self.server_config.pfx_path.is_file() {
+                    problems.push(format!(
+                        "the server certificate {} does not exist",
+                        self.server_config.pfx_path.display()
+                    ));
+                }
+            }
+            (Some(cert_file), Some(private_key_file)) => {
+                for file in &[cert_file, private_key_file] {
+                    if !file.is_file() {
+                        problems.push(format!(
+                            "the server certificate {} does not exist",
+                            file.display()
+                        ));
+                    }
+                }
+            }
+            _ => problems.push(
+                "the server certificate and private key files must be given together".to_owned(),
+            ),
+        }
+        if let Some(token_file) = &self.server_config.auth.token_file {
+            if !token_file.is_file() {
+                problems.push(format!(
+                    "the token auth file {} does not exist",
+                    token_file.display()
+                ));
+            }
+        }
+        if let Some(client_ca_file) = &self.server_config.auth.client_ca_file {
+            if !cfg!(target_os = "linux") {
+                problems.push("client certificates are only supported on Linux".to_owned());
+            } else if !client_ca_file.is_file() {
+                problems.push(format!(
+                    "the client CA file {} does not exist",
+                    client_ca_file.display()
+                ));
+            }
+        }
+        if let Some(kubeconfig) = &self.kubeconfig {
+            // The kubeconfig can be a list of files, as in KUBECONFIG
+            for path in std::env::split_paths(kubeconfig).filter(|p| !p.as_os_str().is_empty()) {
+                if !path.is_file() {
+                    problems.push(format!("the kubeconfig {} does not exist", path.display()));
+                }
+            }
+        }
+        if let Err(problem) = validate_subdomain(&self.node_name) {
+            problems.push(format!("the node name {:?} {}", self.node_name, problem));
+        }
+        if let Err(problem) = validate_subdomain(&self.cluster_domain) {
+            problems.push(format!(
+                "the cluster domain {:?} {}",
+                self.cluster_domain, problem
+            ));
+        }
+        if let Err(e) = check_writable(&self.data_dir) {
+            problems.push(format!(
+                "the data directory {} is not writable: {}",
+                self.data_dir.display(),
+                e
+            ));
+        }
+        for (kind, reserved) in &[
+            ("system", &self.system_reserved),
+            ("kube", &self.kube_reserved),
+        ] {
+            for (resource, quantity) in reserved.iter() {
+                if resource.is_empty() {
+                    problems.push(format!(
+                        "the {} reserved quantity {:?} is missing its resource",
+                        kind, quantity
+                    ));
+                } else if crate::stats::parse_quantity(&Quantity(quantity.clone())).is_none() {
+                    problems.push(format!(
+                        "the {} reserved {} {:?} is not a valid quantity",
+                        kind, resource, quantity
+                    ));
+                }
+            }
+        }
+        for (namespace, quota) in &self.namespace_quotas {
+            if namespace.is_empty() {
+                problems.push("a namespace quota is missing its namespace".to_owned());
+            } else if quota.is_empty() {
+                problems.push(format!(
+                    "the quota of namespace {:?} caps nothing, such as pods=10",
+                    namespace
+                ));
+            }
+            for (resource, amount) in quota {
+                let valid = match resource.as_str() {
+                    "pods" => amount.parse::<usize>().is_ok(),
+                    "memory" => matches!(
+                        crate::stats::parse_quantity(&Quantity(amount.clone())),
+                        Some(bytes) if bytes >= 0.0
+                    ),
+                    _ => {
+                        problems.push(format!(
+                            "the quota of namespace {:?} caps {:?}, but only pods and memory can be capped",
+                            namespace, resource
+                        ));
+                        continue;
+                    }
+                };
+                if !valid {
+                    problems.push(format!(
+                        "the {} quota of namespace {:?}, {:?}, is not a valid amount",
+                        resource, namespace, amount
+                    ));
+                }
+            }
+        }
+        if self.shutdown_grace_period_critical_pods > self.shutdown_grace_period {
+            problems.push(format!(
+                "the shutdown grace period for critical pods ({}s) is longer than the shutdown grace period ({}s)",
+                self.shutdown_grace_period_critical_pods.as_secs(),
+                self.shutdown_grace_period.as_secs()
+            ));
+        }
+        if self.api_client.qps <= 0.0 || self.api_client.burst == 0 {
+            problems.push("the Kubernetes API QPS and burst must be positive".to_owned());
+        }
+        if reqwest::header::HeaderValue::from_str(&self.api_client.user_agent).is_err() {
+            problems.push(format!(
+                "the Kubernetes API user agent {:?} is not a valid header value",
+                self.api_client.user_agent
+            ));
+        }
+        for (name, value) in &self.api_client.headers {
+            if value.is_empty() {
+                problems.push(format!(
+                    "the Kubernetes API header {:?} is missing its value",
+                    name
+                ));
+            } else if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+                || reqwest::header::HeaderValue::from_str(value).is_err()
+            {
+                problems.push(format!(
+                    "the Kubernetes API header {}={:?} is not a valid header",
+                    name, value
+                ));
+            }
+        }
+        for namespace in &self.namespaces.allowed {
+            if self.namespaces.blocked.contains(namespace) {
+                problems.push(format!(
+                    "the namespace {:?} is both allowed and blocked",
+                    namespace
+                ));
+            }
+        }
+        for image in &self.prefetch.images {
+            if oci_distribution::Reference::try_from(image.as_str()).is_err() {
+                problems.push(format!(
+                    "the image {:?} to prefetch is not a valid reference",
+                    image
+                ));
+            }
+        }
+        let concurrency = &self.concurrency;
+        if concurrency.image_pulls == 0
+            || concurrency.module_compilations == 0
+            || concurrency.volume_setups == 0
+        {
+            problems.push("the limits on concurrent operations must be positive".to_owned());
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            problems.sort();
+            Err(InvalidConfig { problems })
+        }
+    }
+
+    /// Returns the parameters for listing and watching the pods this Kubelet runs: the
+    /// pods scheduled to its node that match its pod selectors
+    pub(crate) fn pod_list_params(&self) -> ListParams {
+        let mut field_selector = format!("spec.nodeName={}", self.node_name);
+        if let Some(selector) = self.pod_field_selector.as_deref() {
+            if !selector.is_empty() {
+                field_selector.push(',');
+                field_selector.push_str(selector);
+            }
+        }
+        ListParams {
+            field_selector: Some(field_selector),
+            label_selector: self
+                .pod_label_selector
+                .clone()
+                .filter(|selector| !selector.is_empty()),
+            ..Default::default()
+        }
+    }
+
     /// Parses all command line flags and sets the proper defaults. The version
     /// of your application should be passed to set the proper version for the CLI
     #[cfg(any(feature = "cli", feature = "docs"))]
@@ -90,13 +703,22 @@ impl Config {
         // merging down the road
         let app = Opts::clap().version(version);
         let opts = Opts::from_clap(&app.get_matches());
-        // Copy the addr to avoid a partial move when computing node_ip
-        let addr = opts.addr;
+        // Without an address to listen on, listen on every address of the preferred
+        // family. The node IP is of the preferred family, or of the address given
+        let prefer_ipv6 = flag(opts.prefer_ipv6, "KRUSTLET_PREFER_IPV6");
+        let preferred_ip_family = match (prefer_ipv6, opts.addr) {
+            (true, _) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            (false, Some(addr)) => addr,
+            (false, None) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        let addr = opts
+            .addr
+            .unwrap_or_else(|| unspecified_addr(&preferred_ip_family));
         let hostname = opts
             .hostname
             .unwrap_or_else(|| default_hostname().expect("unable to get default hostname"));
         let node_ip = opts.node_ip.unwrap_or_else(|| {
-            default_node_ip(&mut hostname.clone(), &addr)
+            default_node_ip(&mut hostname.clone(), &preferred_ip_family)
                 .expect("unable to get default node IP address")
         });
         let node_name = opts
@@ -112,7 +734,22 @@ impl Config {
         let port = opts.port;
         let pfx_path = opts.pfx_path.unwrap_or_else(default_pfx_path);
 
-        let pfx_password = opts.pfx_password.unwrap_or_else(read_password_from_tty);
+        // PEM certificates have no password to ask for
+        let pem = opts.tls_cert_file.is_some() || opts.tls_private_key_file.is_some();
+        let pfx_password = match opts.pfx_password {
+            Some(password) => password,
+            None if pem => String::new(),
+            None => read_password_from_tty(),
+        };
+
+        let leader_election = if flag(opts.leader_elect, "KRUSTLET_LEADER_ELECT") {
+            Some(LeaderElectionConfig {
+                lease_namespace: opts.leader_elect_namespace,
+                ..Default::default()
+            })
+        } else {
+            None
+        };
 
         let data_dir = opts
             .data_dir
@@ -121,6 +758,65 @@ impl Config {
             node_ip,
             node_name,
             node_labels,
+            node_adoption: opts.node_adoption,
+            kubeconfig: opts.kubeconfig,
+            leader_election,
+            api_client: ApiClientConfig {
+                qps: opts.kube_api_qps,
+                burst: opts.kube_api_burst,
+                request_timeout: Duration::from_secs(opts.kube_api_timeout),
+                user_agent: opts
+                    .kube_api_user_agent
+                    .unwrap_or_else(|| default_user_agent(version)),
+                headers: parse_headers(opts.kube_api_headers.as_deref()),
+            },
+            registry_client: RegistryClientConfig {
+                timeout: opts.registry_timeout.map(Duration::from_secs),
+                connect_timeout: opts.registry_connect_timeout.map(Duration::from_secs),
+                max_idle_per_host: opts.registry_max_idle_connections,
+                http2_prior_knowledge: flag(opts.registry_http2, "KRUSTLET_REGISTRY_HTTP2"),
+                certs_dir: opts.registry_certs_dir,
+            },
+            concurrency: ConcurrencyConfig {
+                image_pulls: opts.max_concurrent_image_pulls,
+                module_compilations: opts.max_concurrent_compilations,
+                volume_setups: opts.max_concurrent_volume_setups,
+            },
+            shutdown_grace_period: Duration::from_secs(opts.shutdown_grace_period),
+            shutdown_grace_period_critical_pods: Duration::from_secs(
+                opts.shutdown_grace_period_critical_pods,
+            ),
+            pod_label_selector: opts.pod_label_selector,
+            pod_field_selector: opts.pod_field_selector,
+            namespaces: NamespaceFilter {
+                allowed: opts.allowed_namespaces,
+                blocked: opts.blocked_namespaces,
+            },
+            namespace_quotas: parse_namespace_quotas(opts.namespace_quotas.as_deref()),
+            bindle_server: opts.bindle_server,
+            system_reserved: parse_reserved(opts.system_reserved.as_deref()),
+            kube_reserved: parse_reserved(opts.kube_reserved.as_deref()),
+            pod_log_dir: Some(opts.pod_log_dir).filter(|dir| !dir.as_os_str().is_empty()),
+            cluster_domain: opts.cluster_domain,
+            max_wasm_stack: opts.max_wasm_stack,
+            wasi_sandbox: WasiSandboxConfig {
+                env_passthrough: opts.wasi_env_passthrough,
+                preopened_dirs: opts.wasi_preopened_dirs,
+                wall_clock: !flag(opts.wasi_deny_wall_clock, "KRUSTLET_WASI_DENY_WALL_CLOCK"),
+                random: !flag(opts.wasi_deny_random, "KRUSTLET_WASI_DENY_RANDOM"),
+            },
+            audit_log: flag(opts.audit_log, "KRUSTLET_AUDIT_LOG"),
+            skip_crd_registration: flag(
+                opts.skip_crd_registration,
+                "KRUSTLET_SKIP_CRD_REGISTRATION",
+            ),
+            admin_socket: opts.admin_socket,
+            prefetch: PrefetchConfig {
+                images: opts.prefetch_images,
+                from_node: flag(opts.prefetch_from_node, "KRUSTLET_PREFETCH_FROM_NODE"),
+            },
+            #[cfg(feature = "fault-injection")]
+            faults: opts.fault_injection.unwrap_or_default(),
             hostname,
             data_dir,
             server_config: ServerConfig {
@@ -128,6 +824,16 @@ impl Config {
                 port,
                 pfx_path,
                 pfx_password,
+                cert_file: opts.tls_cert_file,
+                private_key_file: opts.tls_private_key_file,
+                auth: AuthConfig {
+                    token_file: opts.token_auth_file,
+                    client_ca_file: opts.client_ca_file,
+                    authorized_users: opts.authorized_users,
+                    authorized_groups: opts.authorized_groups,
+                },
+                read_only: flag(opts.read_only_server, "KRUSTLET_READ_ONLY_SERVER"),
+                worker_threads: opts.server_threads,
             },
         }
     }
@@ -158,11 +864,16 @@ pub struct Opts {
     #[structopt(
         short = "a",
         long = "addr",
-        default_value = "0.0.0.0",
         env = "KRUSTLET_ADDRESS",
-        help = "The address krustlet should listen on"
+        help = "The address krustlet should listen on. Defaults to 0.0.0.0, or :: with --prefer-ipv6"
     )]
-    addr: IpAddr,
+    addr: Option<IpAddr>,
+
+    #[structopt(
+        long = "prefer-ipv6",
+        help = "Prefer IPv6 addresses, as in IPv6-only clusters: listen on :: and register an IPv6 address of the node unless --addr or --node-ip say otherwise. Can also be turned on with KRUSTLET_PREFER_IPV6=true"
+    )]
+    prefer_ipv6: bool,
 
     #[structopt(
         short = "p",
@@ -187,6 +898,50 @@ pub struct Opts {
     )]
     pfx_password: Option<String>,
 
+    #[structopt(
+        long = "tls-cert-file",
+        env = "KRUSTLET_TLS_CERT_FILE",
+        help = "A PEM file of the certificate for ssl, followed by its chain, to use instead of the pfx bundle, such as the tls.crt that cert-manager writes. It is reloaded when it changes. Requires --tls-private-key-file and is only supported on Linux"
+    )]
+    tls_cert_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-private-key-file",
+        env = "KRUSTLET_TLS_PRIVATE_KEY_FILE",
+        help = "A PEM file of the private key of --tls-cert-file, such as the tls.key that cert-manager writes"
+    )]
+    tls_private_key_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "token-auth-file",
+        env = "KRUSTLET_TOKEN_AUTH_FILE",
+        help = "A file of static bearer tokens that clients of the krustlet server must authenticate with. Each line is token,user,uid,\"group1,group2\""
+    )]
+    token_auth_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "client-ca-file",
+        env = "KRUSTLET_CLIENT_CA_FILE",
+        help = "A PEM file of the certificate authorities that sign client certificates. Clients of the krustlet server can then authenticate with a certificate, as its common name (CN) with its organizations (O) as groups. Only supported on Linux"
+    )]
+    client_ca_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "authorized-users",
+        env = "KRUSTLET_AUTHORIZED_USERS",
+        use_delimiter = true,
+        help = "The users allowed to use the krustlet server, separated by ','. Any authenticated user is allowed when no users or groups are given"
+    )]
+    authorized_users: Vec<String>,
+
+    #[structopt(
+        long = "authorized-groups",
+        env = "KRUSTLET_AUTHORIZED_GROUPS",
+        use_delimiter = true,
+        help = "The groups allowed to use the krustlet server, separated by ','"
+    )]
+    authorized_groups: Vec<String>,
+
     #[structopt(
         short = "n",
         long = "node-ip",
@@ -231,6 +986,361 @@ pub struct Opts {
         help = "The data path (logs, container images, etc) for krustlet storage. Defaults to $HOME/.krustlet"
     )]
     data_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "kubeconfig",
+        env = "KUBECONFIG",
+        help = "The kubeconfig file, or list of files separated like PATH, used to connect to the Kubernetes API. Client certificates and credential plugins (exec) are only supported from the single file in KUBECONFIG or $HOME/.kube/config. Defaults to the service account configuration when running in a pod, or $HOME/.kube/config otherwise"
+    )]
+    kubeconfig: Option<PathBuf>,
+
+    #[structopt(
+        long = "node-adoption",
+        default_value = "adopt",
+        env = "KRUSTLET_NODE_ADOPTION",
+        help = "What krustlet does when a node with its name is already registered: adopt it, adopt it only if it has matching-labels, adopt it only if it is unowned by another krustlet, or overwrite it"
+    )]
+    node_adoption: AdoptionPolicy,
+
+    #[structopt(
+        long = "leader-elect",
+        help = "Elect a leader among several krustlets running with the same node name, so that only one of them runs the node at a time. Can also be turned on with KRUSTLET_LEADER_ELECT=true"
+    )]
+    leader_elect: bool,
+
+    #[structopt(
+        long = "read-only-server",
+        help = "Only serve read-only endpoints (logs without follow, pods and stats) from the krustlet server, refusing exec, attach, port forwarding and provider routes. Can also be turned on with KRUSTLET_READ_ONLY_SERVER=true"
+    )]
+    read_only_server: bool,
+
+    #[structopt(
+        long = "server-threads",
+        env = "KRUSTLET_SERVER_THREADS",
+        help = "Run the krustlet server on a runtime of its own with this many worker threads, so streaming traffic can't slow down the rest of krustlet"
+    )]
+    server_threads: Option<usize>,
+
+    #[structopt(
+        long = "leader-elect-namespace",
+        default_value = "kube-system",
+        env = "KRUSTLET_LEADER_ELECT_NAMESPACE",
+        help = "The namespace of the lease used for leader election"
+    )]
+    leader_elect_namespace: String,
+
+    #[structopt(
+        long = "kube-api-qps",
+        default_value = "5",
+        env = "KRUSTLET_KUBE_API_QPS",
+        help = "The number of requests per second krustlet can make to the Kubernetes API. Set to 0 to disable rate limiting"
+    )]
+    kube_api_qps: f64,
+
+    #[structopt(
+        long = "kube-api-burst",
+        default_value = "10",
+        env = "KRUSTLET_KUBE_API_BURST",
+        help = "The number of requests krustlet can make to the Kubernetes API at once before it is rate limited"
+    )]
+    kube_api_burst: u32,
+
+    #[structopt(
+        long = "kube-api-timeout",
+        default_value = "30",
+        env = "KRUSTLET_KUBE_API_TIMEOUT",
+        help = "The number of seconds a request to the Kubernetes API can take before it fails"
+    )]
+    kube_api_timeout: u64,
+
+    #[structopt(
+        long = "kube-api-user-agent",
+        env = "KRUSTLET_KUBE_API_USER_AGENT",
+        help = "The User-Agent krustlet sends to the Kubernetes API. Defaults to krustlet/<version> (<os>/<arch>)"
+    )]
+    kube_api_user_agent: Option<String>,
+
+    #[structopt(
+        long = "kube-api-headers",
+        env = "KRUSTLET_KUBE_API_HEADERS",
+        help = "Extra headers krustlet sends with every request to the Kubernetes API, as comma separated name=value pairs, such as \"X-Krustlet-Fleet=edge\""
+    )]
+    kube_api_headers: Option<String>,
+
+    #[structopt(
+        long = "registry-timeout",
+        env = "KRUSTLET_REGISTRY_TIMEOUT",
+        help = "The number of seconds a request to an image registry, including downloading a layer, can take before it fails. Requests have no time limit by default"
+    )]
+    registry_timeout: Option<u64>,
+
+    #[structopt(
+        long = "registry-connect-timeout",
+        env = "KRUSTLET_REGISTRY_CONNECT_TIMEOUT",
+        help = "The number of seconds connecting to an image registry can take before it fails"
+    )]
+    registry_connect_timeout: Option<u64>,
+
+    #[structopt(
+        long = "registry-max-idle-connections",
+        env = "KRUSTLET_REGISTRY_MAX_IDLE_CONNECTIONS",
+        help = "The number of idle connections to each image registry krustlet keeps open for later pulls. There is no limit by default"
+    )]
+    registry_max_idle_connections: Option<usize>,
+
+    #[structopt(
+        long = "registry-http2",
+        help = "Talk HTTP/2 to image registries without negotiating it first, so pulls from a registry share one connection. Only for registries known to support HTTP/2. Can also be turned on with KRUSTLET_REGISTRY_HTTP2=true"
+    )]
+    registry_http2: bool,
+
+    #[structopt(
+        long = "registry-certs-dir",
+        env = "KRUSTLET_REGISTRY_CERTS_DIR",
+        help = "The directory with a subdirectory per image registry holding its CA bundle (ca.crt) and client certificate (client.pfx and client.pfx.password), such as registry.internal:5000/ca.crt. Defaults to certs.d in the data directory"
+    )]
+    registry_certs_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "max-concurrent-image-pulls",
+        default_value = "5",
+        env = "KRUSTLET_MAX_CONCURRENT_IMAGE_PULLS",
+        help = "The number of images or modules krustlet pulls at once"
+    )]
+    max_concurrent_image_pulls: usize,
+
+    #[structopt(
+        long = "max-concurrent-compilations",
+        default_value = "2",
+        env = "KRUSTLET_MAX_CONCURRENT_COMPILATIONS",
+        help = "The number of modules krustlet compiles at once"
+    )]
+    max_concurrent_compilations: usize,
+
+    #[structopt(
+        long = "max-concurrent-volume-setups",
+        default_value = "10",
+        env = "KRUSTLET_MAX_CONCURRENT_VOLUME_SETUPS",
+        help = "The number of volumes krustlet sets up at once"
+    )]
+    max_concurrent_volume_setups: usize,
+
+    #[structopt(
+        long = "shutdown-grace-period",
+        default_value = "0",
+        env = "KRUSTLET_SHUTDOWN_GRACE_PERIOD",
+        help = "The number of seconds the node's shutdown is delayed to stop its pods gracefully. Requires systemd-logind. Set to 0 to disable graceful node shutdown"
+    )]
+    shutdown_grace_period: u64,
+
+    #[structopt(
+        long = "shutdown-grace-period-critical-pods",
+        default_value = "0",
+        env = "KRUSTLET_SHUTDOWN_GRACE_PERIOD_CRITICAL_PODS",
+        help = "The number of seconds of the shutdown grace period kept for stopping critical pods, which are stopped after the other pods. When 0, critical pods share what is left of the grace period once the other pods have stopped. Must not be longer than the shutdown grace period"
+    )]
+    shutdown_grace_period_critical_pods: u64,
+
+    #[structopt(
+        long = "pod-label-selector",
+        env = "KRUSTLET_POD_LABEL_SELECTOR",
+        help = "Only run the node's pods whose labels match this selector, such as \"runtime=wasm\""
+    )]
+    pod_label_selector: Option<String>,
+
+    #[structopt(
+        long = "pod-field-selector",
+        env = "KRUSTLET_POD_FIELD_SELECTOR",
+        help = "Only run the node's pods whose fields also match this selector, such as \"metadata.namespace=apps\""
+    )]
+    pod_field_selector: Option<String>,
+
+    #[structopt(
+        long = "allowed-namespaces",
+        env = "KRUSTLET_ALLOWED_NAMESPACES",
+        use_delimiter = true,
+        help = "The only namespaces whose pods krustlet runs, separated by ','. Pods from other namespaces are failed. Pods from any namespace are run when none are given"
+    )]
+    allowed_namespaces: Vec<String>,
+
+    #[structopt(
+        long = "blocked-namespaces",
+        env = "KRUSTLET_BLOCKED_NAMESPACES",
+        use_delimiter = true,
+        help = "Namespaces whose pods krustlet never runs, separated by ','"
+    )]
+    blocked_namespaces: Vec<String>,
+
+    #[structopt(
+        long = "namespace-quotas",
+        env = "KRUSTLET_NAMESPACE_QUOTAS",
+        help = "The most pods and memory the pods of each namespace may use on this node, separated by ';', such as \"tenant-a:pods=10,memory=2Gi;*:pods=5\". The quota of \"*\" applies to each namespace without one of its own"
+    )]
+    namespace_quotas: Option<String>,
+
+    #[structopt(
+        long = "bindle-server",
+        env = "KRUSTLET_BINDLE_SERVER",
+        help = "The URL of the Bindle server to fetch bindle images from, such as \"https://bindle.example.com/v1\""
+    )]
+    bindle_server: Option<reqwest::Url>,
+
+    #[structopt(
+        long = "system-reserved",
+        env = "KRUSTLET_SYSTEM_RESERVED",
+        help = "Resources to reserve for the operating system, which are not allocated to pods, such as \"cpu=500m,memory=1Gi\""
+    )]
+    system_reserved: Option<String>,
+
+    #[structopt(
+        long = "kube-reserved",
+        env = "KRUSTLET_KUBE_RESERVED",
+        help = "Resources to reserve for krustlet itself, which are not allocated to pods, such as \"cpu=100m,memory=256Mi\""
+    )]
+    kube_reserved: Option<String>,
+
+    #[structopt(
+        long = "pod-log-dir",
+        env = "KRUSTLET_POD_LOG_DIR",
+        default_value = "/var/log/pods",
+        help = "The directory container logs are written to for node level log shippers to collect. Set it to \"\" to only serve logs over HTTP"
+    )]
+    pod_log_dir: PathBuf,
+
+    #[structopt(
+        long = "cluster-domain",
+        env = "KRUSTLET_CLUSTER_DOMAIN",
+        default_value = "cluster.local",
+        help = "The DNS domain of the cluster, used for the fully qualified domain names of pods"
+    )]
+    cluster_domain: String,
+
+    #[structopt(
+        long = "max-wasm-stack",
+        env = "KRUSTLET_MAX_WASM_STACK",
+        default_value = "1048576",
+        help = "The largest stack, in bytes, that the WebAssembly modules of pods may ask for with the alpha.krustlet.dev/wasmtime-max-wasm-stack annotation"
+    )]
+    max_wasm_stack: usize,
+
+    #[structopt(
+        long = "wasi-env-passthrough",
+        env = "KRUSTLET_WASI_ENV_PASSTHROUGH",
+        use_delimiter = true,
+        help = "The environment variables of krustlet, separated by ',', that pods may pass through to their WASI modules with the alpha.krustlet.dev/wasi-env-passthrough annotation"
+    )]
+    wasi_env_passthrough: Vec<String>,
+
+    #[structopt(
+        long = "wasi-preopened-dirs",
+        env = "KRUSTLET_WASI_PREOPENED_DIRS",
+        use_delimiter = true,
+        help = "The host directories, separated by ',', that the hostPath volumes of WASI pods may be preopened from. Any directory may be preopened when this is not set"
+    )]
+    wasi_preopened_dirs: Option<Vec<PathBuf>>,
+
+    #[structopt(
+        long = "wasi-deny-wall-clock",
+        help = "Hide the time of day from the WASI modules of pods. Can also be turned on with KRUSTLET_WASI_DENY_WALL_CLOCK=true"
+    )]
+    wasi_deny_wall_clock: bool,
+
+    #[structopt(
+        long = "wasi-deny-random",
+        help = "Deny the WASI modules of pods random numbers. Can also be turned on with KRUSTLET_WASI_DENY_RANDOM=true"
+    )]
+    wasi_deny_random: bool,
+
+    #[structopt(
+        long = "audit-log",
+        help = "Record every module pulled and every pod started and stopped in audit.log in the data directory, with each entry chained to the one before it by its digest. Can also be turned on with KRUSTLET_AUDIT_LOG=true"
+    )]
+    audit_log: bool,
+
+    #[structopt(
+        long = "skip-crd-registration",
+        help = "Only check that the custom resource definitions the provider depends on are installed, instead of applying them when krustlet starts. Can also be turned on with KRUSTLET_SKIP_CRD_REGISTRATION=true"
+    )]
+    skip_crd_registration: bool,
+
+    #[structopt(
+        long = "admin-socket",
+        env = "KRUSTLET_ADMIN_SOCKET",
+        help = "Serve the admin API, for listing pods, resyncing them and draining the node, on a unix socket at this path"
+    )]
+    admin_socket: Option<PathBuf>,
+
+    #[structopt(
+        long = "prefetch-images",
+        env = "KRUSTLET_PREFETCH_IMAGES",
+        use_delimiter = true,
+        help = "Images to pull, and compile if the provider can, as soon as krustlet starts, separated by ','"
+    )]
+    prefetch_images: Vec<String>,
+
+    #[structopt(
+        long = "prefetch-from-node",
+        help = "Also prefetch the images listed in the alpha.krustlet.dev/prefetch-images annotation of the node, separated by ',' or new lines. It is checked for new images every minute. Can also be turned on with KRUSTLET_PREFETCH_FROM_NODE=true"
+    )]
+    prefetch_from_node: bool,
+
+    #[cfg(feature = "fault-injection")]
+    #[structopt(
+        long = "fault-injection",
+        env = "KRUSTLET_FAULT_INJECTION",
+        help = "Failures to inject for testing, such as 'drop-status-patches=10,delay-image-pulls=2s,fail-provider-calls=3'. Never use this in production"
+    )]
+    fault_injection: Option<crate::faults::Faults>,
+}
+
+/// A configuration that cannot be used to run a Kubelet
+#[derive(Debug, Error)]
+#[error("invalid configuration:\n  - {}", .problems.join("\n  - "))]
+pub struct InvalidConfig {
+    /// Each of the problems with the configuration
+    pub problems: Vec<String>,
+}
+
+// Node names must be DNS subdomains as defined in RFC 1123, like all Kubernetes
+// object names, and so must the cluster domain
+fn validate_subdomain(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() {
+        return Err("must not be empty");
+    }
+    if name.len() > MAX_SUBDOMAIN_LENGTH {
+        return Err("must be no more than 253 characters");
+    }
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    if !name.split('.').all(valid_label) {
+        return Err("must consist of lower case alphanumeric characters, '-' or '.', and must start and end with an alphanumeric character");
+    }
+    Ok(())
+}
+
+/// Checks that the directory can be written to, or that it can be created if it doesn't
+/// exist yet. Validation leaves nothing behind, so a missing directory is not created
+/// and its closest existing ancestor is checked instead
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    let existing = dir
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or_else(|| Path::new("."));
+    if !existing.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{} is not a directory", existing.display()),
+        ));
+    }
+    let probe = existing.join(format!(".krustlet-write-check-{}", std::process::id()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
 }
 
 fn default_hostname() -> anyhow::Result<String> {
@@ -271,6 +1381,7 @@ fn default_node_ip(hostname: &mut String, preferred_ip_family: &IpAddr) -> anyho
             !i.ip().is_loopback()
                 && !i.ip().is_multicast()
                 && !i.ip().is_unspecified()
+                && !is_link_local(&i.ip())
                 && is_same_ip_family(&i.ip(), preferred_ip_family)
         })
         .ok_or_else(|| {
@@ -287,6 +1398,31 @@ fn default_pfx_path() -> PathBuf {
         .join(".krustlet/config/certificate.pfx")
 }
 
+/// The address that listens on every address of the same family
+fn unspecified_addr(ip_family: &IpAddr) -> IpAddr {
+    match ip_family {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+// Link local addresses (169.254.0.0/16 and fe80::/10) are only reachable from the
+// same link, so the API server can't reach the node on them
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+// Whether a server listening on the address accepts connections to the node IP. An
+// IPv4 server can't be reached on an IPv6 node IP, but listening on :: also accepts
+// IPv4 connections, except on Windows where IPv6 sockets only accept IPv6 by default
+fn is_reachable_on(addr: &IpAddr, node_ip: &IpAddr) -> bool {
+    is_same_ip_family(addr, node_ip)
+        || (*addr == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && cfg!(not(windows)))
+}
+
 fn is_same_ip_family(first: &IpAddr, second: &IpAddr) -> bool {
     match first {
         IpAddr::V4(_) => second.is_ipv4(),
@@ -298,6 +1434,70 @@ fn read_password_from_tty() -> String {
     rpassword::read_password_from_tty(Some("PFX file password: ")).unwrap()
 }
 
+// Reserved resources are given like `cpu=500m,memory=1Gi`, as with the Kubernetes
+// kubelet
+fn parse_reserved(reserved: Option<&str>) -> HashMap<String, String> {
+    parse_pairs(reserved)
+}
+
+// Namespace quotas are given like `tenant-a:pods=10,memory=2Gi;tenant-b:pods=5`.
+// Malformed quotas are kept, with no namespace or no caps, for validation to report
+fn parse_namespace_quotas(quotas: Option<&str>) -> HashMap<String, HashMap<String, String>> {
+    quotas
+        .unwrap_or_default()
+        .split(';')
+        .map(str::trim)
+        .filter(|quota| !quota.is_empty())
+        .map(|quota| {
+            let mut parts = quota.splitn(2, ':');
+            let namespace = parts.next().unwrap_or_default().trim();
+            (namespace.to_owned(), parse_reserved(parts.next()))
+        })
+        .collect()
+}
+
+/// Whether a flag is set on the command line or its environment variable is `true`.
+/// clap makes flags with an `env` take a value, so the variable is read here instead
+#[cfg(any(feature = "cli", feature = "docs"))]
+fn flag(set: bool, env: &str) -> bool {
+    set || match std::env::var(env) {
+        Ok(value) => parse_flag(&value)
+            .unwrap_or_else(|| panic!("{} must be true or false, not {:?}", env, value)),
+        Err(_) => false,
+    }
+}
+
+#[cfg(any(feature = "cli", feature = "docs"))]
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses comma separated `name=value` headers
+fn parse_headers(headers: Option<&str>) -> HashMap<String, String> {
+    parse_pairs(headers)
+}
+
+/// Parses comma separated `name=value` pairs. Malformed pairs are kept, with an empty
+/// name or value, so [`Config::validate`] can report them
+fn parse_pairs(pairs: Option<&str>) -> HashMap<String, String> {
+    pairs
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim();
+            let value = parts.next().unwrap_or_default().trim();
+            (name.to_owned(), value.to_owned())
+        })
+        .collect()
+}
+
 fn split_one_label(in_string: &str) -> Option<(String, String)> {
     let mut splitter = in_string.splitn(2, '=');
 
@@ -309,3 +1509,363 @@ fn split_one_label(in_string: &str) -> Option<(String, String)> {
         },
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// A configuration that doesn't depend on the host, for tests
+    pub(crate) fn test_config() -> Config {
+        Config {
+            node_ip: IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
+            hostname: String::from("krustlet"),
+            node_name: String::from("krustlet"),
+            server_config: ServerConfig {
+                addr: IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
+                port: DEFAULT_PORT,
+                pfx_password: String::new(),
+                pfx_path: PathBuf::new(),
+                cert_file: None,
+                private_key_file: None,
+                auth: Default::default(),
+                read_only: false,
+                worker_threads: None,
+            },
+            data_dir: PathBuf::new(),
+            node_labels: HashMap::new(),
+            node_adoption: Default::default(),
+            kubeconfig: None,
+            leader_election: None,
+            api_client: Default::default(),
+            registry_client: Default::default(),
+            concurrency: Default::default(),
+            namespaces: Default::default(),
+            namespace_quotas: HashMap::new(),
+            shutdown_grace_period: Default::default(),
+            shutdown_grace_period_critical_pods: Default::default(),
+            pod_label_selector: None,
+            pod_field_selector: None,
+            bindle_server: None,
+            system_reserved: HashMap::new(),
+            kube_reserved: HashMap::new(),
+            pod_log_dir: None,
+            cluster_domain: "cluster.local".to_owned(),
+            max_wasm_stack: DEFAULT_MAX_WASM_STACK,
+            wasi_sandbox: Default::default(),
+            audit_log: false,
+            skip_crd_registration: false,
+            admin_socket: None,
+            prefetch: Default::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_pod_list_params() {
+        let params = test_config().pod_list_params();
+        assert_eq!(
+            Some("spec.nodeName=krustlet"),
+            params.field_selector.as_deref()
+        );
+        assert_eq!(None, params.label_selector);
+
+        let config = Config {
+            pod_label_selector: Some("runtime=wasm".to_owned()),
+            pod_field_selector: Some("metadata.namespace=apps".to_owned()),
+            ..test_config()
+        };
+        let params = config.pod_list_params();
+        assert_eq!(
+            Some("spec.nodeName=krustlet,metadata.namespace=apps"),
+            params.field_selector.as_deref()
+        );
+        assert_eq!(Some("runtime=wasm"), params.label_selector.as_deref());
+
+        // Selectors on addresses are unchanged in IPv6-only clusters
+        let config = Config {
+            node_ip: "fd00::10".parse().unwrap(),
+            pod_field_selector: Some("status.hostIP=fd00::10".to_owned()),
+            ..test_config()
+        };
+        assert_eq!(
+            Some("spec.nodeName=krustlet,status.hostIP=fd00::10"),
+            config.pod_list_params().field_selector.as_deref()
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = std::env::temp_dir().join(format!("krustlet-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pfx_path = dir.join("certificate.pfx");
+        std::fs::write(&pfx_path, b"").unwrap();
+        let mut config = test_config();
+        config.data_dir = dir.join("data");
+        config.server_config.pfx_path = pfx_path;
+        config.validate().unwrap();
+        // Validating checks the data directory could be created without creating it
+        assert!(!config.data_dir.exists());
+
+        // Every file of a kubeconfig list has to exist
+        let kubeconfig = dir.join("kubeconfig");
+        std::fs::write(&kubeconfig, b"").unwrap();
+        config.kubeconfig = Some(
+            std::env::join_paths([&kubeconfig, &kubeconfig].iter())
+                .unwrap()
+                .into(),
+        );
+        config.validate().unwrap();
+        config.kubeconfig = Some(
+            std::env::join_paths([kubeconfig, dir.join("missing-kubeconfig")].iter())
+                .unwrap()
+                .into(),
+        );
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(1, problems.len(), "{:?}", problems);
+        assert!(problems[0].contains("missing-kubeconfig"));
+        config.kubeconfig = None;
+
+        // PEM certificates are used instead of the pfx file, and need their key
+        let cert_file = dir.join("tls.crt");
+        std::fs::write(&cert_file, b"").unwrap();
+        config.server_config.pfx_path = dir.join("unused.pfx");
+        config.server_config.cert_file = Some(cert_file);
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(
+            vec!["the server certificate and private key files must be given together"],
+            problems
+        );
+        config.server_config.private_key_file = Some(dir.join("tls.key"));
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(1, problems.len(), "{:?}", problems);
+        assert!(problems[0].contains("tls.key"));
+        std::fs::write(dir.join("tls.key"), b"").unwrap();
+        config.validate().unwrap();
+        config.server_config.cert_file = None;
+        config.server_config.private_key_file = None;
+
+        config.node_name = "Krustlet_1".to_owned();
+        config.server_config.port = 0;
+        config.server_config.worker_threads = Some(0);
+        config.max_wasm_stack = 0;
+        config.cluster_domain = "cluster.local.".to_owned();
+        config.server_config.pfx_path = dir.join("missing.pfx");
+        config
+            .system_reserved
+            .insert("cpu".to_owned(), "lots".to_owned());
+        config
+            .api_client
+            .headers
+            .insert("Bad Header".to_owned(), "x".to_owned());
+        config.namespaces.allowed = vec!["apps".to_owned(), "tools".to_owned()];
+        config.namespaces.blocked = vec!["tools".to_owned()];
+        config.prefetch.images = vec![
+            "webassembly.azurecr.io/hello:v1".to_owned(),
+            "Not An Image".to_owned(),
+        ];
+        config.node_ip = "fd00::10".parse().unwrap();
+        config.shutdown_grace_period_critical_pods = Duration::from_secs(30);
+        config.namespace_quotas =
+            parse_namespace_quotas(Some("apps:pods=ten,cpu=1;tools:memory=-1Gi;bad;:pods=1"));
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(17, problems.len(), "{:?}", problems);
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("pods quota of namespace \"apps\"")));
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("memory quota of namespace \"tools\", \"-1Gi\"")));
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("namespace \"bad\" caps nothing")));
+        assert!(problems.iter().any(|p| p.contains("missing its namespace")));
+        assert!(problems.iter().any(|p| p.contains("caps \"cpu\"")));
+        assert!(problems.iter().any(|p| p.contains("worker thread")));
+        assert!(problems.iter().any(|p| p.contains("wasm stack")));
+        assert!(problems.iter().any(|p| p.contains("critical pods (30s)")));
+        assert!(problems.iter().any(|p| p.contains("node IP fd00::10")));
+        assert!(problems.iter().any(|p| p.contains("\"Not An Image\"")));
+        assert!(problems.iter().any(|p| p.contains("\"tools\" is both")));
+        assert!(problems.iter().any(|p| p.contains("Bad Header")));
+        assert!(problems.iter().any(|p| p.contains("Krustlet_1")));
+        assert!(problems.iter().any(|p| p.contains("missing.pfx")));
+        assert!(problems.iter().any(|p| p.contains("port")));
+        assert!(problems.iter().any(|p| p.contains("cluster domain")));
+        assert!(problems.iter().any(|p| p.contains("\"lots\"")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_subdomain() {
+        assert!(validate_subdomain("krustlet").is_ok());
+        assert!(validate_subdomain("krustlet-1.example.com").is_ok());
+        assert!(validate_subdomain("").is_err());
+        assert!(validate_subdomain("-krustlet").is_err());
+        assert!(validate_subdomain("krustlet..example").is_err());
+        assert!(validate_subdomain("krustlet.").is_err());
+        assert!(validate_subdomain(&"a".repeat(254)).is_err());
+    }
+
+    #[test]
+    fn test_ip_addresses() {
+        let ipv4: IpAddr = "10.0.0.5".parse().unwrap();
+        let ipv6: IpAddr = "fd00::10".parse().unwrap();
+        assert_eq!("0.0.0.0", unspecified_addr(&ipv4).to_string());
+        assert_eq!("::", unspecified_addr(&ipv6).to_string());
+
+        assert!(is_reachable_on(&"0.0.0.0".parse().unwrap(), &ipv4));
+        assert!(!is_reachable_on(&"0.0.0.0".parse().unwrap(), &ipv6));
+        assert_eq!(
+            !cfg!(windows),
+            is_reachable_on(&"::".parse().unwrap(), &ipv4)
+        );
+        assert!(is_reachable_on(&"::".parse().unwrap(), &ipv6));
+        assert!(!is_reachable_on(&"fd00::10".parse().unwrap(), &ipv4));
+
+        assert!(is_link_local(&"fe80::1".parse().unwrap()));
+        assert!(is_link_local(&"febf::1".parse().unwrap()));
+        assert!(is_link_local(&"169.254.1.1".parse().unwrap()));
+        assert!(!is_link_local(&ipv6));
+        assert!(!is_link_local(&ipv4));
+    }
+
+    #[test]
+    fn test_parse_reserved() {
+        let reserved = parse_reserved(Some("cpu=500m, memory=1Gi,"));
+        assert_eq!(2, reserved.len());
+        assert_eq!("500m", reserved["cpu"]);
+        assert_eq!("1Gi", reserved["memory"]);
+        assert!(parse_reserved(None).is_empty());
+
+        // Malformed pairs are kept so they are reported
+        let config = Config {
+            system_reserved: parse_reserved(Some("cpu, =1Gi")),
+            ..test_config()
+        };
+        let problems = config.validate().unwrap_err().to_string();
+        assert!(problems.contains("the system reserved cpu \"\" is not a valid quantity"));
+        assert!(problems.contains("the system reserved quantity \"1Gi\" is missing its resource"));
+    }
+
+    #[test]
+    fn test_parse_namespace_quotas() {
+        let quotas = parse_namespace_quotas(Some("tenant-a:pods=10,memory=2Gi; *:pods=5;bad;"));
+        assert_eq!(3, quotas.len());
+        assert_eq!("10", quotas["tenant-a"]["pods"]);
+        assert_eq!("2Gi", quotas["tenant-a"]["memory"]);
+        assert_eq!("5", quotas["*"]["pods"]);
+        // Malformed quotas are kept for validation to report
+        assert!(quotas["bad"].is_empty());
+        assert!(parse_namespace_quotas(None).is_empty());
+    }
+
+    #[test]
+    fn test_namespace_filter() {
+        let mut filter = NamespaceFilter::default();
+        assert!(filter.allows("default"));
+        filter.blocked = vec!["kube-system".to_owned()];
+        assert!(filter.allows("default"));
+        assert!(!filter.allows("kube-system"));
+        filter.allowed = vec!["apps".to_owned(), "kube-system".to_owned()];
+        assert!(filter.allows("apps"));
+        assert!(!filter.allows("default"));
+        assert!(!filter.allows("kube-system"));
+    }
+
+    #[test]
+    fn test_parse_adoption_policy() {
+        assert_eq!(
+            AdoptionPolicy::MatchingLabels,
+            "matching-labels".parse().unwrap()
+        );
+        assert_eq!(AdoptionPolicy::Overwrite, "overwrite".parse().unwrap());
+        assert!("steal".parse::<AdoptionPolicy>().is_err());
+    }
+
+    #[cfg(any(feature = "cli", feature = "docs"))]
+    #[test]
+    fn test_flags() {
+        let opts = Opts::from_iter_safe(vec!["krustlet", "--leader-elect"]).unwrap();
+        assert!(opts.leader_elect);
+        let opts = Opts::from_iter_safe(vec!["krustlet"]).unwrap();
+        assert!(!opts.leader_elect);
+        assert!(Opts::from_iter_safe(vec!["krustlet", "--leader-elect", "false"]).is_err());
+        let opts = Opts::from_iter_safe(vec!["krustlet", "--read-only-server"]).unwrap();
+        assert!(opts.read_only_server);
+        let opts = Opts::from_iter_safe(vec!["krustlet", "--registry-http2"]).unwrap();
+        assert!(opts.registry_http2);
+        let opts = Opts::from_iter_safe(vec!["krustlet", "--audit-log"]).unwrap();
+        assert!(opts.audit_log);
+        let opts = Opts::from_iter_safe(vec!["krustlet", "--prefetch-from-node"]).unwrap();
+        assert!(opts.prefetch_from_node);
+        let opts = Opts::from_iter_safe(vec!["krustlet", "--skip-crd-registration"]).unwrap();
+        assert!(opts.skip_crd_registration);
+        let opts = Opts::from_iter_safe(vec!["krustlet", "--prefer-ipv6"]).unwrap();
+        assert!(opts.prefer_ipv6);
+        let opts = Opts::from_iter_safe(vec!["krustlet", "--wasi-deny-wall-clock"]).unwrap();
+        assert!(opts.wasi_deny_wall_clock);
+        assert!(!opts.wasi_deny_random);
+        assert_eq!(None, opts.wasi_preopened_dirs);
+        let opts =
+            Opts::from_iter_safe(vec!["krustlet", "--wasi-preopened-dirs", "/srv,/data"]).unwrap();
+        assert_eq!(
+            Some(vec![PathBuf::from("/srv"), PathBuf::from("/data")]),
+            opts.wasi_preopened_dirs
+        );
+
+        assert_eq!(Some(true), parse_flag("True"));
+        assert_eq!(Some(false), parse_flag("false"));
+        assert_eq!(Some(false), parse_flag("0"));
+        assert_eq!(None, parse_flag("yes please"));
+    }
+
+    #[test]
+    fn test_registry_certs_dir() {
+        let dir = std::env::temp_dir().join(format!("krustlet-certs-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("certs.d/registry.internal:5000")).unwrap();
+        let config = RegistryClientConfig::default();
+        let client_config = config.client_config(&dir).unwrap();
+        assert!(client_config
+            .registry_tls
+            .contains_key("registry.internal:5000"));
+
+        // Without certs.d in the data directory no registry has TLS settings
+        let client_config = config.client_config(&dir.join("missing")).unwrap();
+        assert!(client_config.registry_tls.is_empty());
+
+        // A certs directory that was asked for has to exist
+        let config = RegistryClientConfig {
+            certs_dir: Some(dir.join("missing")),
+            ..Default::default()
+        };
+        assert!(config.client_config(&dir).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        let headers = parse_headers(Some("X-Krustlet-Fleet=edge, X-Krustlet-Zone=a,"));
+        assert_eq!(2, headers.len());
+        assert_eq!("edge", headers["X-Krustlet-Fleet"]);
+        assert_eq!("a", headers["X-Krustlet-Zone"]);
+        assert!(parse_headers(None).is_empty());
+
+        // Malformed pairs are kept so they are reported
+        let headers = parse_headers(Some("X-Krustlet-Fleet,=edge"));
+        assert_eq!("", headers["X-Krustlet-Fleet"]);
+        assert_eq!("edge", headers[""]);
+        let config = Config {
+            api_client: ApiClientConfig {
+                headers,
+                ..Default::default()
+            },
+            ..test_config()
+        };
+        let problems = config.validate().unwrap_err().to_string();
+        assert!(problems.contains("\"X-Krustlet-Fleet\" is missing its value"));
+        assert!(problems.contains("header =\"edge\" is not a valid header"));
+        assert!(default_user_agent("1.2.3").starts_with("krustlet/1.2.3 ("));
+    }
+}
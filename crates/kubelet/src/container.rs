@@ -0,0 +1,396 @@
+//! A resolved view of a pod's containers for use by providers
+//!
+//! Resolving the environment, volume mounts, and image reference of a container
+//! is the same for every provider. [`Container::resolve_all`] does this once for
+//! all of a pod's containers so providers only need to act on the result.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+use k8s_openapi::api::core::v1::{Container as KubeContainer, ContainerPort, EphemeralContainer};
+use oci_distribution::Reference;
+
+use crate::module_store::bindle::bindle_id;
+use crate::pod::Pod;
+use crate::provider::{Provider, ResolutionContext};
+use crate::volumes::VolumeRef;
+
+/// The environment variable that holds the fully qualified domain name of a pod with a
+/// subdomain, see [`Pod::fqdn`]
+pub const FQDN_ENV_VAR: &str = "POD_FQDN";
+
+/// A volume mount for a container, resolved to its location on the host
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VolumeMount {
+    /// The name of the pod volume being mounted
+    pub name: String,
+    /// The location of the volume on the host
+    pub host_path: PathBuf,
+    /// The location the volume should be mounted at inside the container, including
+    /// the sub path if one was given
+    pub guest_path: PathBuf,
+    /// Whether the volume should be mounted read only
+    pub read_only: bool,
+}
+
+/// A container port that is bound to a port on the host, so that providers without
+/// network isolation know exactly where a container should listen
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortBinding {
+    /// The name of the port, if it has one
+    pub name: Option<String>,
+    /// The port the container listens on
+    pub container_port: u16,
+    /// The port on the host that is bound
+    pub host_port: u16,
+    /// The host address to bind to. The unspecified address binds all of them
+    pub host_ip: IpAddr,
+    /// The protocol of the port, `TCP`, `UDP` or `SCTP`
+    pub protocol: String,
+}
+
+impl PortBinding {
+    /// Returns the binding of the given port, or `None` if it is not bound to a port
+    /// on the host
+    pub fn from_port(port: &ContainerPort) -> Option<Self> {
+        let host_port = port.host_port.filter(|p| *p > 0)?;
+        Some(PortBinding {
+            name: port.name.clone(),
+            container_port: u16::try_from(port.container_port).ok()?,
+            host_port: u16::try_from(host_port).ok()?,
+            // Like the Kubernetes kubelet, an address that doesn't parse binds all of them
+            host_ip: port
+                .host_ip
+                .as_deref()
+                .and_then(|ip| ip.parse().ok())
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            protocol: port.protocol.clone().unwrap_or_else(|| "TCP".to_owned()),
+        })
+    }
+
+    /// Returns true if the two bindings cannot both be bound at once, because they
+    /// bind the same port with the same protocol on overlapping addresses
+    pub fn conflicts_with(&self, other: &PortBinding) -> bool {
+        self.host_port == other.host_port
+            && self.protocol == other.protocol
+            && (self.host_ip == other.host_ip
+                || self.host_ip.is_unspecified()
+                || other.host_ip.is_unspecified())
+    }
+}
+
+/// The security settings that apply to a container after combining the pod and
+/// container security contexts. Container settings take precedence
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SecurityContext {
+    /// The UID to run the container as
+    pub run_as_user: Option<i64>,
+    /// The GID to run the container as
+    pub run_as_group: Option<i64>,
+    /// Whether the container must run as a non-root user
+    pub run_as_non_root: bool,
+    /// Whether the container should run in privileged mode
+    pub privileged: bool,
+    /// Whether the container's root filesystem should be read only
+    pub read_only_root_filesystem: bool,
+}
+
+/// A container from a pod spec along with everything resolved for running it
+#[derive(Clone, Debug)]
+pub struct Container {
+    spec: KubeContainer,
+    env: HashMap<String, String>,
+    volume_mounts: Vec<VolumeMount>,
+    image: Option<Reference>,
+    security_context: SecurityContext,
+}
+
+impl Container {
+    /// Resolve a single container from the given pod.
+    ///
+    /// The given volumes should be the ones returned by [`VolumeRef::volumes_from_pod`].
+    /// Environment variables are resolved using [`Provider::env_vars`] so providers
+    /// that override it get their custom resolution.
+    pub async fn resolve<P: Provider>(
+        spec: &KubeContainer,
+        pod: &Pod,
+        client: &kube::Client,
+        volumes: &HashMap<String, VolumeRef>,
+    ) -> anyhow::Result<Self> {
+        let context = ResolutionContext::new(client.clone(), pod);
+        Self::resolve_with_context::<P>(spec, pod, &context, volumes).await
+    }
+
+    /// Resolve all of the given pod's containers, in the order they appear in the spec.
+    ///
+    /// Secrets and config maps referenced by more than one container are only fetched once.
+    pub async fn resolve_all<P: Provider>(
+        pod: &Pod,
+        client: &kube::Client,
+        volumes: &HashMap<String, VolumeRef>,
+    ) -> anyhow::Result<Vec<Self>> {
+        let context = ResolutionContext::new(client.clone(), pod);
+        let mut containers = Vec::with_capacity(pod.containers().len());
+        for spec in pod.containers() {
+            containers.push(Self::resolve_with_context::<P>(spec, pod, &context, volumes).await?);
+        }
+        Ok(containers)
+    }
+
+    async fn resolve_with_context<P: Provider>(
+        spec: &KubeContainer,
+        pod: &Pod,
+        context: &ResolutionContext,
+        volumes: &HashMap<String, VolumeRef>,
+    ) -> anyhow::Result<Self> {
+        let mut env = P::env_vars(spec, pod, context).await?;
+        // Like container runtimes do, containers get the pod's hostname unless they set
+        // their own, and pods with a subdomain also get their domain name
+        env.entry("HOSTNAME".to_owned())
+            .or_insert_with(|| pod.hostname());
+        if let Some(fqdn) = pod.fqdn() {
+            env.entry(FQDN_ENV_VAR.to_owned()).or_insert(fqdn);
+        }
+        let volume_mounts = spec
+            .volume_mounts
+            .iter()
+            .flatten()
+            .map(|vm| {
+                // Check the volume exists first
+                let vol = volumes.get(&vm.name).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "no volume with the name of {} found for container {}",
+                        vm.name,
+                        spec.name
+                    )
+                })?;
+                let mut guest_path = PathBuf::from(&vm.mount_path);
+                if let Some(sub_path) = &vm.sub_path {
+                    guest_path.push(sub_path);
+                }
+                Ok(VolumeMount {
+                    name: vm.name.clone(),
+                    host_path: vol.to_path_buf(),
+                    guest_path,
+                    read_only: vm.read_only.unwrap_or(false),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        // Bindles are not OCI images, so their containers have no image reference
+        let image = match spec.image.as_deref() {
+            Some(image) if bindle_id(pod, image)?.is_none() => {
+                Some(Reference::try_from(image).map_err(|e| {
+                    anyhow::anyhow!("invalid image for container {}: {}", spec.name, e)
+                })?)
+            }
+            _ => None,
+        };
+
+        Ok(Container {
+            spec: spec.clone(),
+            env,
+            volume_mounts,
+            image,
+            security_context: effective_security_context(spec, pod),
+        })
+    }
+
+    /// Get the name of the container
+    pub fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    /// Get the raw container spec
+    pub fn spec(&self) -> &KubeContainer {
+        &self.spec
+    }
+
+    /// Get the resolved environment variables
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    /// Get the resolved volume mounts
+    pub fn volume_mounts(&self) -> &[VolumeMount] {
+        &self.volume_mounts
+    }
+
+    /// Get the parsed image reference, if the container has an OCI image
+    pub fn image(&self) -> Option<&Reference> {
+        self.image.as_ref()
+    }
+
+    /// Get the effective security context
+    pub fn security_context(&self) -> &SecurityContext {
+        &self.security_context
+    }
+
+    /// Get the container's ports that are bound to ports on the host. The Kubelet only
+    /// runs a pod once none of its bindings conflict with those of the node's other pods
+    pub fn port_bindings(&self) -> Vec<PortBinding> {
+        port_bindings(&self.spec)
+    }
+}
+
+/// The ports of the container spec that are bound to ports on the host
+pub(crate) fn port_bindings(spec: &KubeContainer) -> Vec<PortBinding> {
+    spec.ports
+        .iter()
+        .flatten()
+        .filter_map(PortBinding::from_port)
+        .collect()
+}
+
+/// Convert an ephemeral container into a regular container spec.
+///
+/// Ephemeral containers share most of their fields with regular containers, so this
+/// lets them be resolved and run the same way. Fields that ephemeral containers do not
+/// support, such as ports and probes, are dropped.
+pub fn from_ephemeral(ephemeral: &EphemeralContainer) -> KubeContainer {
+    KubeContainer {
+        name: ephemeral.name.clone(),
+        image: ephemeral.image.clone(),
+        image_pull_policy: ephemeral.image_pull_policy.clone(),
+        command: ephemeral.command.clone(),
+        args: ephemeral.args.clone(),
+        env: ephemeral.env.clone(),
+        env_from: ephemeral.env_from.clone(),
+        volume_mounts: ephemeral.volume_mounts.clone(),
+        volume_devices: ephemeral.volume_devices.clone(),
+        working_dir: ephemeral.working_dir.clone(),
+        security_context: ephemeral.security_context.clone(),
+        stdin: ephemeral.stdin,
+        stdin_once: ephemeral.stdin_once,
+        tty: ephemeral.tty,
+        termination_message_path: ephemeral.termination_message_path.clone(),
+        termination_message_policy: ephemeral.termination_message_policy.clone(),
+        ..Default::default()
+    }
+}
+
+fn effective_security_context(spec: &KubeContainer, pod: &Pod) -> SecurityContext {
+    let pod_context = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.security_context.clone())
+        .unwrap_or_default();
+    let container_context = spec.security_context.clone().unwrap_or_default();
+    SecurityContext {
+        run_as_user: container_context.run_as_user.or(pod_context.run_as_user),
+        run_as_group: container_context.run_as_group.or(pod_context.run_as_group),
+        run_as_non_root: container_context
+            .run_as_non_root
+            .or(pod_context.run_as_non_root)
+            .unwrap_or(false),
+        privileged: container_context.privileged.unwrap_or(false),
+        read_only_root_filesystem: container_context.read_only_root_filesystem.unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, FakeProvider};
+    use k8s_openapi::api::core::v1::{
+        EnvVar, PodSecurityContext, SecurityContext as KubeSecurityContext,
+        VolumeMount as KubeVolumeMount,
+    };
+
+    fn mock_client() -> kube::Client {
+        kube::Client::new(kube::Config::new(
+            reqwest::Url::parse("http://127.0.0.1:8080").unwrap(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let mut kube_pod = fake_pod("foo", "default");
+        let spec = kube_pod.spec.as_mut().unwrap();
+        spec.subdomain = Some("greeters".to_owned());
+        spec.security_context = Some(PodSecurityContext {
+            run_as_user: Some(1000),
+            run_as_group: Some(1000),
+            ..Default::default()
+        });
+        let container = &mut spec.containers[0];
+        container.env = Some(vec![EnvVar {
+            name: "GREETING".to_owned(),
+            value: Some("hello".to_owned()),
+            ..Default::default()
+        }]);
+        container.security_context = Some(KubeSecurityContext {
+            run_as_user: Some(0),
+            read_only_root_filesystem: Some(true),
+            ..Default::default()
+        });
+        let pod = Pod::new(kube_pod);
+
+        let containers =
+            Container::resolve_all::<FakeProvider>(&pod, &mock_client(), &HashMap::new())
+                .await
+                .expect("container should resolve");
+        assert_eq!(1, containers.len());
+        let container = &containers[0];
+        assert_eq!("foo", container.name());
+        assert_eq!("hello", container.env()["GREETING"]);
+        assert_eq!("foo", container.env()["HOSTNAME"]);
+        assert_eq!(
+            "foo.greeters.default.svc.cluster.local",
+            container.env()[FQDN_ENV_VAR]
+        );
+        assert!(container.volume_mounts().is_empty());
+        assert_eq!("foo", container.image().unwrap().repository());
+        assert_eq!(
+            &SecurityContext {
+                run_as_user: Some(0),
+                run_as_group: Some(1000),
+                read_only_root_filesystem: true,
+                ..Default::default()
+            },
+            container.security_context()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_missing_volume() {
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.spec.as_mut().unwrap().containers[0].volume_mounts = Some(vec![KubeVolumeMount {
+            name: "missing".to_owned(),
+            mount_path: "/data".to_owned(),
+            ..Default::default()
+        }]);
+        let pod = Pod::new(kube_pod);
+
+        Container::resolve_all::<FakeProvider>(&pod, &mock_client(), &HashMap::new())
+            .await
+            .expect_err("a mount without a volume should fail");
+    }
+
+    #[test]
+    fn test_port_bindings() {
+        let port = |container_port, host_port, host_ip: Option<&str>| ContainerPort {
+            container_port,
+            host_port,
+            host_ip: host_ip.map(str::to_owned),
+            ..Default::default()
+        };
+        assert!(PortBinding::from_port(&port(8080, None, None)).is_none());
+        let all = PortBinding::from_port(&port(8080, Some(80), None)).unwrap();
+        assert_eq!(80, all.host_port);
+        assert_eq!("TCP", all.protocol);
+        assert!(all.host_ip.is_unspecified());
+
+        let local = PortBinding::from_port(&port(8080, Some(80), Some("127.0.0.1"))).unwrap();
+        let other = PortBinding::from_port(&port(8080, Some(80), Some("10.0.0.1"))).unwrap();
+        assert!(all.conflicts_with(&local));
+        assert!(local.conflicts_with(&all));
+        assert!(!local.conflicts_with(&other));
+        let udp = PortBinding {
+            protocol: "UDP".to_owned(),
+            ..all.clone()
+        };
+        assert!(!all.conflicts_with(&udp));
+    }
+}
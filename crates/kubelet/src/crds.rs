@@ -0,0 +1,301 @@
+//! Registering the custom resource definitions providers depend on
+//!
+//! Providers declare the definitions they need with
+//! [`Provider::custom_resource_definitions`](crate::Provider::custom_resource_definitions).
+//! When the Kubelet starts, it creates each definition, or applies it if it exists
+//! already so it matches what the provider expects, and waits for the API server to
+//! establish it. With [`Config::skip_crd_registration`] the definitions are only checked
+//! to be installed, for clusters where an administrator installs them.
+//!
+//! Missing definitions and missing permissions stop the Kubelet from starting, with an
+//! error that says which RBAC permissions the Kubelet's user needs.
+//!
+//! [`Config::skip_crd_registration`]: crate::config::Config::skip_crd_registration
+use std::time::{Duration, Instant};
+
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::api::{Api, PostParams};
+use kube::error::ErrorResponse;
+use log::info;
+
+use crate::error::KubeletError;
+use crate::rate_limit::RateLimits;
+use crate::status::apply_params;
+
+/// How long the API server has to establish a registered definition
+pub(crate) const ESTABLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a definition is checked while waiting for it to be established
+const ESTABLISH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Returns the name of the definition, such as `bindings.wascc.dev`
+pub(crate) fn name(crd: &CustomResourceDefinition) -> &str {
+    crd.metadata
+        .as_ref()
+        .and_then(|m| m.name.as_deref())
+        .unwrap_or_default()
+}
+
+/// Register the definitions, or only check that they are installed when `apply` is
+/// false, and wait up to `timeout` for each of them to be established
+pub(crate) async fn register(
+    client: &kube::Client,
+    limits: &RateLimits,
+    crds: &[CustomResourceDefinition],
+    apply: bool,
+    timeout: Duration,
+) -> Result<(), KubeletError> {
+    let api: Api<CustomResourceDefinition> = Api::all(client.clone());
+    for crd in crds {
+        if apply {
+            apply_definition(&api, limits, crd).await?;
+        }
+        wait_established(&api, limits, name(crd), apply, timeout).await?;
+    }
+    Ok(())
+}
+
+/// Create the definition, or apply it if it exists already
+async fn apply_definition(
+    api: &Api<CustomResourceDefinition>,
+    limits: &RateLimits,
+    crd: &CustomResourceDefinition,
+) -> Result<(), KubeletError> {
+    let name = name(crd);
+    match limits
+        .limited(api.create(&PostParams::default(), crd))
+        .await
+    {
+        Ok(_) => {
+            info!("Registered custom resource definition {}", name);
+            Ok(())
+        }
+        Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => {
+            let mut crd = crd.clone();
+            if let Some(metadata) = crd.metadata.as_mut() {
+                metadata.resource_version = None;
+            }
+            let data = serde_json::to_vec(&crd).map_err(|e| unavailable(name, e.to_string()))?;
+            limits
+                .limited(api.patch(name, &apply_params(), data))
+                .await
+                .map_err(|e| failed(name, "patch", true, e))?;
+            info!("Updated custom resource definition {}", name);
+            Ok(())
+        }
+        Err(e) => Err(failed(name, "create", true, e)),
+    }
+}
+
+/// Wait for the API server to establish the definition. A definition that was not
+/// registered by the Kubelet must exist already
+async fn wait_established(
+    api: &Api<CustomResourceDefinition>,
+    limits: &RateLimits,
+    name: &str,
+    registered: bool,
+    timeout: Duration,
+) -> Result<(), KubeletError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match limits.limited(api.get(name)).await {
+            Ok(crd) if is_established(&crd) => return Ok(()),
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) if !registered => {
+                return Err(unavailable(
+                    name,
+                    "it is not installed. Install it, or start krustlet without \
+                     --skip-crd-registration to register it"
+                        .to_owned(),
+                ))
+            }
+            Ok(_) | Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => (),
+            Err(e) => return Err(failed(name, "get", registered, e)),
+        }
+        if Instant::now() >= deadline {
+            return Err(unavailable(
+                name,
+                format!("the API server did not establish it within {:?}", timeout),
+            ));
+        }
+        tokio::time::delay_for(ESTABLISH_POLL_INTERVAL).await;
+    }
+}
+
+/// Whether the API server serves the resources of the definition
+fn is_established(crd: &CustomResourceDefinition) -> bool {
+    crd.status
+        .as_ref()
+        .and_then(|s| s.conditions.as_ref())
+        .into_iter()
+        .flatten()
+        .any(|c| c.type_ == "Established" && c.status == "True")
+}
+
+fn unavailable(name: &str, reason: String) -> KubeletError {
+    KubeletError::CustomResourceDefinition {
+        name: name.to_owned(),
+        reason,
+    }
+}
+
+/// The error for a request about the definition that failed, saying which permissions
+/// are missing if it was forbidden
+fn failed(name: &str, verb: &str, registering: bool, error: kube::Error) -> KubeletError {
+    let reason = match error {
+        kube::Error::Api(ErrorResponse { code: 403, .. }) if registering => format!(
+            "krustlet is not allowed to {} customresourcedefinitions. Grant its user `get`, \
+             `create` and `patch` on `customresourcedefinitions` in the `apiextensions.k8s.io` \
+             API group, or install the definition and start krustlet with \
+             --skip-crd-registration",
+            verb
+        ),
+        kube::Error::Api(ErrorResponse { code: 403, .. }) => format!(
+            "krustlet is not allowed to {} customresourcedefinitions. Grant its user `get` on \
+             `customresourcedefinitions` in the `apiextensions.k8s.io` API group",
+            verb
+        ),
+        e => e.to_string(),
+    };
+    unavailable(name, reason)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::MockApiServer;
+
+    const CRDS: &str = "/apis/apiextensions.k8s.io/v1/customresourcedefinitions";
+
+    fn crd(name: &str) -> CustomResourceDefinition {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "apiextensions.k8s.io/v1",
+            "kind": "CustomResourceDefinition",
+            "metadata": { "name": name },
+            "spec": {
+                "group": "wascc.dev",
+                "names": { "kind": "Binding", "plural": "bindings" },
+                "scope": "Namespaced",
+                "versions": [{ "name": "v1", "served": true, "storage": true }],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn established(name: &str) -> serde_json::Value {
+        let mut crd = serde_json::to_value(crd(name)).unwrap();
+        crd["status"] = serde_json::json!({
+            "acceptedNames": crd["spec"]["names"].clone(),
+            "conditions": [{ "type": "Established", "status": "True" }],
+            "storedVersions": ["v1"],
+        });
+        crd
+    }
+
+    fn reason(error: KubeletError) -> String {
+        match error {
+            KubeletError::CustomResourceDefinition { reason, .. } => reason,
+            e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(CRDS, established("bindings.wascc.dev"));
+        let crds = vec![crd("bindings.wascc.dev"), crd("actors.wascc.dev")];
+
+        // Existing definitions are applied, new ones created and waited for
+        let error = register(
+            &server.client(),
+            &RateLimits::default(),
+            &crds,
+            true,
+            Duration::from_millis(0),
+        )
+        .await
+        .unwrap_err();
+        let patches = server.requests_to(
+            hyper::Method::PATCH,
+            &format!("{}/bindings.wascc.dev", CRDS),
+        );
+        assert_eq!(1, patches.len());
+        assert!(server.get(&format!("{}/actors.wascc.dev", CRDS)).is_some());
+        assert!(reason(error).contains("did not establish it"));
+
+        server.modify(
+            &format!("{}/actors.wascc.dev", CRDS),
+            &established("actors.wascc.dev"),
+        );
+        register(
+            &server.client(),
+            &RateLimits::default(),
+            &crds,
+            true,
+            ESTABLISH_TIMEOUT,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_skip_registration() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(CRDS, established("bindings.wascc.dev"));
+        register(
+            &server.client(),
+            &RateLimits::default(),
+            &[crd("bindings.wascc.dev")],
+            false,
+            ESTABLISH_TIMEOUT,
+        )
+        .await
+        .unwrap();
+        assert!(server.requests_to(hyper::Method::POST, CRDS).is_empty());
+
+        let error = register(
+            &server.client(),
+            &RateLimits::default(),
+            &[crd("actors.wascc.dev")],
+            false,
+            ESTABLISH_TIMEOUT,
+        )
+        .await
+        .unwrap_err();
+        assert!(reason(error).starts_with("it is not installed"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_permissions() {
+        let server = MockApiServer::start().await.unwrap();
+        server.deny(CRDS);
+        let crds = [crd("bindings.wascc.dev")];
+
+        let error = register(
+            &server.client(),
+            &RateLimits::default(),
+            &crds,
+            true,
+            ESTABLISH_TIMEOUT,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            "custom resource definition bindings.wascc.dev is unavailable: krustlet is not \
+             allowed to create customresourcedefinitions. Grant its user `get`, `create` and \
+             `patch` on `customresourcedefinitions` in the `apiextensions.k8s.io` API group, \
+             or install the definition and start krustlet with --skip-crd-registration",
+            error.to_string()
+        );
+
+        let error = register(
+            &server.client(),
+            &RateLimits::default(),
+            &crds,
+            false,
+            ESTABLISH_TIMEOUT,
+        )
+        .await
+        .unwrap_err();
+        assert!(reason(error).contains("Grant its user `get` on"));
+    }
+}
@@ -0,0 +1,378 @@
+//! Resolving cluster DNS names for workloads that cannot query the cluster's DNS
+//!
+//! WebAssembly workloads have no raw sockets, so they cannot ask the cluster's DNS
+//! server for the address of a service. A [`Resolver`] answers those queries on the
+//! node instead, the way a pod's resolver configured by the Kubernetes kubelet would:
+//!
+//! - names that are not fully qualified are tried with the pod's search domains, which
+//!   are `<namespace>.svc.<cluster domain>`, `svc.<cluster domain>` and
+//!   `<cluster domain>` followed by any `dnsConfig.searches` of the pod, unless they
+//!   have at least `ndots` dots (5 by default) in which case they are tried as they are
+//!   first
+//! - `<service>.<namespace>.svc.<cluster domain>` resolves to the cluster IP of the
+//!   service, fetched from the API and cached for a short while. When the service
+//!   can't be fetched, the next name is tried, and the error is only returned if no
+//!   name resolves in the cluster
+//! - anything else is resolved by the node's resolver
+//!
+//! Pods with a `dnsPolicy` other than `ClusterFirst` (the default) or
+//! `ClusterFirstWithHostNet` only use the node's resolver. Headless services have no
+//! cluster IP, so their names are not resolved.
+//!
+//! ```rust,no_run
+//! use kubelet::dns::Resolver;
+//!
+//! async fn connect(client: kube::Client, pod: kubelet::Pod) -> anyhow::Result<()> {
+//!     let resolver = Resolver::new(client);
+//!     // The `db` service in the pod's namespace
+//!     let addresses = resolver.resolve(&pod, "db").await?;
+//!     println!("db is at {:?}", addresses);
+//!     Ok(())
+//! }
+//! ```
+use std::collections::HashMap;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Service;
+use kube::api::Api;
+use kube::error::ErrorResponse;
+use tokio::time::Instant;
+
+use crate::pod::Pod;
+use crate::rate_limit::RateLimits;
+
+/// How long the cluster IP of a service, or the lack of a service, is cached for
+const CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// The most services whose cluster IPs are cached. When the cache is full, expired
+/// entries are dropped first, and then the one fetched the longest ago
+const MAX_CACHED_SERVICES: usize = 1024;
+
+/// The number of dots a name needs to be tried as it is before the search domains
+const DEFAULT_NDOTS: usize = 5;
+
+/// The cluster IP of each service by namespace and name, or `None` if the service does
+/// not exist or is headless, along with when it was fetched
+type ServiceCache = HashMap<(String, String), (Instant, Option<IpAddr>)>;
+
+/// Resolves names for the pods on the node. Clones share the same cache
+#[derive(Clone)]
+pub struct Resolver {
+    client: kube::Client,
+    services: Arc<Mutex<ServiceCache>>,
+    max_services: usize,
+}
+
+impl Resolver {
+    /// Create a resolver that looks up services with the given client
+    pub fn new(client: kube::Client) -> Self {
+        Resolver {
+            client,
+            services: Arc::new(Mutex::new(HashMap::new())),
+            max_services: MAX_CACHED_SERVICES,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_max_services(mut self, max_services: usize) -> Self {
+        self.max_services = max_services;
+        self
+    }
+
+    /// Resolve a name for the given pod, returning no addresses if it does not exist
+    pub async fn resolve(&self, pod: &Pod, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let cluster_domain = pod.cluster_domain();
+        if cluster_first(pod) {
+            let mut failed = None;
+            for candidate in candidates(pod, cluster_domain, name) {
+                if let Some((service, namespace)) = service_name(&candidate, cluster_domain) {
+                    match self.cluster_ip(pod.rate_limits(), namespace, service).await {
+                        Ok(Some(ip)) => return Ok(vec![ip]),
+                        Ok(None) => (),
+                        Err(e) => {
+                            failed.get_or_insert(e);
+                        }
+                    }
+                }
+            }
+            // The name may be a service that couldn't be fetched, so it isn't left to
+            // the node
+            if let Some(e) = failed {
+                return Err(e);
+            }
+        }
+        // Names outside the cluster are left to the node, which has its own search
+        // domains
+        let name = name.trim_end_matches('.').to_owned();
+        let addresses = tokio::task::spawn_blocking(move || {
+            (name.as_str(), 0)
+                .to_socket_addrs()
+                .map(|addresses| addresses.map(|a| a.ip()).collect::<Vec<_>>())
+        })
+        .await?;
+        match addresses {
+            Ok(mut addresses) => {
+                addresses.dedup();
+                Ok(addresses)
+            }
+            // The name does not exist, or isn't a valid name
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
+    async fn cluster_ip(
+        &self,
+        limits: &RateLimits,
+        namespace: &str,
+        name: &str,
+    ) -> anyhow::Result<Option<IpAddr>> {
+        let key = (namespace.to_owned(), name.to_owned());
+        if let Some((fetched, ip)) = self.services.lock().unwrap().get(&key) {
+            if fetched.elapsed() < CACHE_TTL {
+                return Ok(*ip);
+            }
+        }
+        let api: Api<Service> = Api::namespaced(self.client.clone(), namespace);
+        let ip = match limits.limited(api.get(name)).await {
+            Ok(service) => service
+                .spec
+                .and_then(|s| s.cluster_ip)
+                .and_then(|ip| ip.parse().ok()),
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let mut services = self.services.lock().unwrap();
+        if services.len() >= self.max_services && !services.contains_key(&key) {
+            services.retain(|_, (fetched, _)| fetched.elapsed() < CACHE_TTL);
+            let oldest = services
+                .iter()
+                .min_by_key(|(_, (fetched, _))| *fetched)
+                .map(|(key, _)| key.clone());
+            if let (true, Some(oldest)) = (services.len() >= self.max_services, oldest) {
+                services.remove(&oldest);
+            }
+        }
+        services.insert(key, (Instant::now(), ip));
+        Ok(ip)
+    }
+}
+
+/// Returns true if the pod resolves names in the cluster before asking the node
+fn cluster_first(pod: &Pod) -> bool {
+    let policy = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.dns_policy.as_deref());
+    matches!(
+        policy,
+        None | Some("ClusterFirst") | Some("ClusterFirstWithHostNet")
+    )
+}
+
+/// The fully qualified names to try for a name, in order
+fn candidates(pod: &Pod, cluster_domain: &str, name: &str) -> Vec<String> {
+    if name.ends_with('.') {
+        return vec![name.trim_end_matches('.').to_owned()];
+    }
+    let dns_config = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.dns_config.as_ref());
+    let ndots = dns_config
+        .and_then(|c| c.options.as_ref())
+        .and_then(|options| {
+            options
+                .iter()
+                .filter(|o| o.name.as_deref() == Some("ndots"))
+                .find_map(|o| o.value.as_deref()?.parse().ok())
+        })
+        .unwrap_or(DEFAULT_NDOTS);
+    let mut searches = vec![
+        format!("{}.svc.{}", pod.namespace(), cluster_domain),
+        format!("svc.{}", cluster_domain),
+        cluster_domain.to_owned(),
+    ];
+    searches.extend(
+        dns_config
+            .and_then(|c| c.searches.clone())
+            .unwrap_or_default(),
+    );
+    let mut candidates: Vec<String> = searches
+        .iter()
+        .map(|search| format!("{}.{}", name, search.trim_end_matches('.')))
+        .collect();
+    if name.matches('.').count() >= ndots {
+        candidates.insert(0, name.to_owned());
+    } else {
+        candidates.push(name.to_owned());
+    }
+    candidates
+}
+
+/// Returns the service and namespace of a `<service>.<namespace>.svc.<cluster domain>`
+/// name
+fn service_name<'a>(name: &'a str, cluster_domain: &str) -> Option<(&'a str, &'a str)> {
+    let suffix = format!(".svc.{}", cluster_domain);
+    if !name.ends_with(&suffix) {
+        return None;
+    }
+    let mut labels = name[..name.len() - suffix.len()].split('.');
+    match (labels.next(), labels.next(), labels.next()) {
+        (Some(service), Some(namespace), None) if !service.is_empty() && !namespace.is_empty() => {
+            Some((service, namespace))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+    use k8s_openapi::api::core::v1::{PodDNSConfig, PodDNSConfigOption, ServiceSpec};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    #[test]
+    fn test_candidates() {
+        let pod = Pod::new(fake_pod("foo", "apps"));
+        let candidates = candidates(&pod, "cluster.local", "db");
+        assert_eq!(
+            vec![
+                "db.apps.svc.cluster.local",
+                "db.svc.cluster.local",
+                "db.cluster.local",
+                "db"
+            ],
+            candidates
+        );
+        assert_eq!(
+            vec!["db.apps.svc.cluster.local"],
+            super::candidates(&pod, "cluster.local", "db.apps.svc.cluster.local.")
+        );
+
+        let mut kube_pod = fake_pod("foo", "apps");
+        kube_pod.spec.as_mut().unwrap().dns_config = Some(PodDNSConfig {
+            searches: Some(vec!["example.com".to_owned()]),
+            options: Some(vec![PodDNSConfigOption {
+                name: Some("ndots".to_owned()),
+                value: Some("1".to_owned()),
+            }]),
+            ..Default::default()
+        });
+        let pod = Pod::new(kube_pod);
+        let candidates = super::candidates(&pod, "cluster.local", "db.other");
+        assert_eq!("db.other", candidates[0]);
+        assert_eq!("db.other.example.com", candidates[4]);
+    }
+
+    #[test]
+    fn test_service_name() {
+        assert_eq!(
+            Some(("db", "apps")),
+            service_name("db.apps.svc.cluster.local", "cluster.local")
+        );
+        assert_eq!(None, service_name("db.svc.cluster.local", "cluster.local"));
+        assert_eq!(
+            None,
+            service_name("web-0.web.apps.svc.cluster.local", "cluster.local")
+        );
+        assert_eq!(None, service_name("example.com", "cluster.local"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_service() {
+        let server = MockApiServer::start().await.unwrap();
+        let service = |name: &str, cluster_ip: &str| Service {
+            metadata: Some(ObjectMeta {
+                name: Some(name.to_owned()),
+                namespace: Some("apps".to_owned()),
+                ..Default::default()
+            }),
+            spec: Some(ServiceSpec {
+                cluster_ip: Some(cluster_ip.to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        server.insert(
+            "/api/v1/namespaces/apps/services",
+            service("db", "10.0.0.10"),
+        );
+        server.insert("/api/v1/namespaces/apps/services", service("peers", "None"));
+        let resolver = Resolver::new(server.client());
+        let pod = Pod::new(fake_pod("foo", "apps"));
+
+        let expected: IpAddr = "10.0.0.10".parse().unwrap();
+        assert_eq!(vec![expected], resolver.resolve(&pod, "db").await.unwrap());
+        assert_eq!(
+            vec![expected],
+            resolver.resolve(&pod, "db.apps.svc").await.unwrap()
+        );
+        // The cluster IP is cached
+        resolver.resolve(&pod, "db").await.unwrap();
+        let gets = server.requests_to(hyper::Method::GET, "/api/v1/namespaces/apps/services/db");
+        assert_eq!(1, gets.len());
+
+        // Headless services have no address of their own, and neither do missing ones
+        assert_eq!(
+            None,
+            resolver
+                .cluster_ip(pod.rate_limits(), "apps", "peers")
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            resolver
+                .cluster_ip(pod.rate_limits(), "apps", "missing")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_tries_next_name_on_error() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/other/services",
+            serde_json::json!({
+                "metadata": { "name": "db", "namespace": "other" },
+                "spec": { "clusterIP": "10.0.0.20" },
+            }),
+        );
+        server.deny("/api/v1/namespaces/apps/services/db");
+        let resolver = Resolver::new(server.client());
+        let mut kube_pod = fake_pod("foo", "apps");
+        kube_pod.spec.as_mut().unwrap().dns_config = Some(PodDNSConfig {
+            searches: Some(vec!["other.svc.cluster.local".to_owned()]),
+            ..Default::default()
+        });
+        let pod = Pod::new(kube_pod);
+
+        let expected: IpAddr = "10.0.0.20".parse().unwrap();
+        assert_eq!(vec![expected], resolver.resolve(&pod, "db").await.unwrap());
+        // Without a name that resolves, the error is returned
+        server.deny("/api/v1/namespaces/other/services/web");
+        server.deny("/api/v1/namespaces/apps/services/web");
+        assert!(resolver.resolve(&pod, "web").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_service_cache_is_bounded() {
+        let server = MockApiServer::start().await.unwrap();
+        let resolver = Resolver::new(server.client()).with_max_services(2);
+        let limits = RateLimits::default();
+        for name in &["a", "b", "c"] {
+            resolver.cluster_ip(&limits, "apps", name).await.unwrap();
+        }
+        let services = resolver.services.lock().unwrap();
+        assert_eq!(2, services.len());
+        // The service fetched first made room for the last one
+        assert!(!services.contains_key(&("apps".to_owned(), "a".to_owned())));
+        assert!(services.contains_key(&("apps".to_owned(), "c".to_owned())));
+    }
+}
@@ -0,0 +1,142 @@
+//! Typed errors for Kubelet operations
+//!
+//! Providers return `anyhow::Error` from their operations, but the Kubelet
+//! needs to know what went wrong in order to report the correct reason on the
+//! pod or node. Providers can return a [`PodSyncError`] (wrapped in an
+//! `anyhow::Error`) to give the Kubelet that information, and any other error is
+//! treated as a generic provider failure.
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// The reason containers are reported waiting with when their image could not be pulled
+pub const REASON_IMAGE_PULL: &str = "ErrImagePull";
+/// The reason containers are reported waiting with when their configuration, such as the
+/// secrets and config maps they refer to, could not be resolved
+pub const REASON_CONTAINER_CONFIG: &str = "CreateContainerConfigError";
+/// The reason containers are reported waiting with while they are being created
+pub const REASON_CONTAINER_CREATING: &str = "ContainerCreating";
+/// A pod status reason used when a pod could not be set up for its containers to start
+pub const REASON_POD_SETUP: &str = "CreatePodSandboxError";
+/// A pod status reason used for generic provider failures
+pub const REASON_PROVIDER_FAILED: &str = "ProviderFailed";
+
+/// An error that occured while syncing a pod with its provider
+#[derive(Debug, Error)]
+pub enum PodSyncError {
+    /// The image for a container could not be pulled. The pod is added again after a
+    /// backoff rather than failed
+    #[error("failed to pull image {image}: {source}")]
+    ImagePull {
+        /// The image reference that failed to pull
+        image: String,
+        /// The underlying error
+        source: anyhow::Error,
+    },
+    /// What the pod as a whole needs, such as its network, volumes or service account
+    /// token, could not be set up
+    #[error("failed to set up pod: {0}")]
+    PodSetup(anyhow::Error),
+    /// The configuration of the pod's containers could not be resolved, for example
+    /// because a secret or config map they require does not exist. As the object may
+    /// appear later, the pod is added again after a backoff rather than failed
+    #[error("failed to create container configuration: {0}")]
+    ContainerConfig(anyhow::Error),
+    /// The pod is not ready to start yet, for example because it waits for an external
+    /// resource, and should be added again after the given time. Nothing is reported as
+    /// failed, and nothing waits for the pod in the meantime
+    #[error("pod is not ready to start, trying again in {0:?}")]
+    Requeue(Duration),
+    /// The provider failed to handle the pod for any other reason
+    #[error(transparent)]
+    Provider(anyhow::Error),
+}
+
+impl PodSyncError {
+    /// Returns the Kubernetes reason to set on the pod status for this error
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PodSyncError::ImagePull { .. } => REASON_IMAGE_PULL,
+            PodSyncError::ContainerConfig(_) => REASON_CONTAINER_CONFIG,
+            PodSyncError::PodSetup(_) => REASON_POD_SETUP,
+            PodSyncError::Requeue(_) => REASON_CONTAINER_CREATING,
+            PodSyncError::Provider(_) => REASON_PROVIDER_FAILED,
+        }
+    }
+}
+
+impl From<anyhow::Error> for PodSyncError {
+    /// Recovers a `PodSyncError` returned by a provider, treating anything else
+    /// as a generic provider failure
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<PodSyncError>() {
+            Ok(e) => e,
+            Err(e) => PodSyncError::Provider(e),
+        }
+    }
+}
+
+/// An error that occured while running the Kubelet
+#[derive(Debug, Error)]
+pub enum KubeletError {
+    /// The node or its lease could not be registered with the API server
+    #[error("failed to register node {node_name}: {source}")]
+    Registration {
+        /// The name of the node being registered
+        node_name: String,
+        /// The underlying error
+        source: kube::Error,
+    },
+    /// A node with the Kubelet's name is already registered, and its
+    /// [`AdoptionPolicy`](crate::config::AdoptionPolicy) does not allow adopting it
+    #[error("node {node_name} is already registered and cannot be adopted: {reason}")]
+    NodeConflict {
+        /// The name of the node being registered
+        node_name: String,
+        /// Why the node cannot be adopted
+        reason: String,
+    },
+    /// A custom resource definition the provider depends on could not be registered,
+    /// or is not installed
+    #[error("custom resource definition {name} is unavailable: {reason}")]
+    CustomResourceDefinition {
+        /// The name of the definition
+        name: String,
+        /// Why the definition is unavailable
+        reason: String,
+    },
+    /// The provider failed to fill in the node definition
+    #[error("failed to build node definition: {0}")]
+    NodeDefinition(anyhow::Error),
+    /// A pod status could not be patched
+    #[error("failed to patch status for pod {pod_name}: {source}")]
+    StatusPatch {
+        /// The name of the pod being patched
+        pod_name: String,
+        /// The underlying error
+        source: kube::Error,
+    },
+    /// A pod could not be synced with the provider
+    #[error(transparent)]
+    PodSync(#[from] PodSyncError),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pod_sync_error_from_anyhow() {
+        let err: PodSyncError = anyhow::Error::from(PodSyncError::ImagePull {
+            image: "foo:v1".to_owned(),
+            source: anyhow::anyhow!("not found"),
+        })
+        .into();
+        assert_eq!(REASON_IMAGE_PULL, err.reason());
+        assert_eq!("failed to pull image foo:v1: not found", err.to_string());
+
+        let err: PodSyncError = anyhow::anyhow!("boom").into();
+        assert_eq!(REASON_PROVIDER_FAILED, err.reason());
+        assert_eq!("boom", err.to_string());
+    }
+}
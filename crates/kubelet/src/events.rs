@@ -0,0 +1,521 @@
+//! Recording Kubernetes events about pods and the node
+//!
+//! Like the upstream Kubelet's event recorder, events are correlated before they are
+//! sent, so a pod that keeps failing doesn't flood the API server with events:
+//!
+//! * An event that was already recorded for the same pod, with the same type, reason
+//!   and message, increases the `count` of the existing event instead of creating
+//!   a new one.
+//! * Once a pod has had too many events with the same reason but different messages
+//!   in a short time, they are combined into a single event.
+//! * Each pod has a budget of events that refills slowly, and events over budget are
+//!   dropped.
+//!
+//! Each Kubelet correlates its events with an [`EventRecorder`] of its own, which the
+//! pods it hands out carry.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::Event;
+use kube::api::{Api, PatchParams, PostParams};
+use log::{debug, warn};
+
+use crate::pod::{Pod, ValidatedPod};
+use crate::rate_limit::RateLimits;
+
+/// The component events are reported as coming from
+const COMPONENT: &str = "krustlet";
+
+/// How long events with the same reason count towards being combined
+const AGGREGATE_INTERVAL: Duration = Duration::from_secs(600);
+/// How many events with the same reason but different messages an object can have
+/// within the aggregate interval before they are combined
+const AGGREGATE_MAX_EVENTS: usize = 10;
+/// The prefix of the message of a combined event
+const AGGREGATE_PREFIX: &str = "(combined from similar events): ";
+/// How many events can be recorded for an object at once
+const SPAM_BURST: f64 = 25.0;
+/// How many events per second are added to an object's budget, one every 5 minutes
+const SPAM_QPS: f64 = 1.0 / 300.0;
+/// How many objects and events the correlator remembers. Once this is exceeded, the
+/// ones not seen within the aggregate interval are forgotten
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// Correlates the events a Kubelet records. Clones share what was recorded
+#[derive(Clone, Default)]
+pub(crate) struct EventRecorder(Arc<Mutex<Correlator>>);
+
+impl std::fmt::Debug for EventRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventRecorder").finish()
+    }
+}
+
+/// Record a warning event about a pod, such as those shown by `kubectl describe pod`.
+///
+/// Events are informational, so failing to record one is only logged. So is an event
+/// about a pod without the metadata to address it.
+pub(crate) async fn record_pod_warning(
+    client: &kube::Client,
+    pod: &Pod,
+    reason: &str,
+    message: &str,
+) {
+    let pod = match ValidatedPod::new(pod.clone()) {
+        Ok(pod) => pod,
+        Err(e) => {
+            warn!("Unable to record {} event: {}", reason, e);
+            return;
+        }
+    };
+    let object = Involved {
+        kind: "Pod",
+        name: pod.name().to_owned(),
+        namespace: Some(pod.namespace().to_owned()),
+        uid: pod.uid().to_owned(),
+        host: pod.spec().node_name.clone(),
+    };
+    record(
+        pod.events(),
+        client,
+        pod.rate_limits(),
+        &object,
+        "Warning",
+        reason,
+        message,
+    )
+    .await
+}
+
+/// Record an event about a node, such as those shown by `kubectl describe node`.
+///
+/// As with the events of the Kubernetes kubelet, they are recorded in the `default`
+/// namespace with the node's name as its UID.
+pub(crate) async fn record_node_event(
+    events: &EventRecorder,
+    client: &kube::Client,
+    limits: &RateLimits,
+    node_name: &str,
+    type_: &str,
+    reason: &str,
+    message: &str,
+) {
+    let object = Involved {
+        kind: "Node",
+        name: node_name.to_owned(),
+        namespace: None,
+        uid: node_name.to_owned(),
+        host: Some(node_name.to_owned()),
+    };
+    record(events, client, limits, &object, type_, reason, message).await
+}
+
+/// The object an event is about
+struct Involved {
+    kind: &'static str,
+    name: String,
+    /// The namespace of the object, or `None` for objects such as nodes that are not
+    /// namespaced
+    namespace: Option<String>,
+    uid: String,
+    /// The node the event comes from
+    host: Option<String>,
+}
+
+impl Involved {
+    /// The namespace events about the object are recorded in
+    fn event_namespace(&self) -> &str {
+        self.namespace.as_deref().unwrap_or("default")
+    }
+}
+
+impl std::fmt::Display for Involved {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = self.kind.to_lowercase();
+        match &self.namespace {
+            Some(namespace) => write!(f, "{} {} in namespace {}", kind, self.name, namespace),
+            None => write!(f, "{} {}", kind, self.name),
+        }
+    }
+}
+
+async fn record(
+    events: &EventRecorder,
+    client: &kube::Client,
+    limits: &RateLimits,
+    object: &Involved,
+    type_: &str,
+    reason: &str,
+    message: &str,
+) {
+    let key = format!(
+        "{}/{}/{}/{}",
+        object.kind,
+        object.event_namespace(),
+        object.name,
+        object.uid
+    );
+    let action = events.0.lock().unwrap().correlate(
+        &key,
+        &object.name,
+        type_,
+        reason,
+        message,
+        Instant::now(),
+    );
+    let api: Api<Event> = Api::namespaced(client.clone(), object.event_namespace());
+    let result = match &action {
+        Action::Drop => {
+            debug!(
+                "Dropped {} event for {} because too many events were recorded for it",
+                reason, object
+            );
+            return;
+        }
+        Action::Create(recorded) => {
+            let event = new_event(object, type_, reason, recorded);
+            limits
+                .limited(api.create(&PostParams::default(), &event))
+                .await
+                .map(|_| ())
+        }
+        Action::Update(recorded) => {
+            let patch = serde_json::json!({
+                "message": recorded.message,
+                "count": recorded.count,
+                "lastTimestamp": Utc::now(),
+            });
+            let data = serde_json::to_vec(&patch).expect("Should always serialize");
+            limits
+                .limited(api.patch(&recorded.name, &PatchParams::default(), data))
+                .await
+                .map(|_| ())
+        }
+    };
+    match result {
+        Ok(()) => debug!("Recorded {} event for {}", reason, object),
+        Err(e) => {
+            // The event may have expired, so the next one starts over with a new event
+            events.0.lock().unwrap().forget(&action);
+            warn!("Unable to record {} event for {}: {}", reason, object, e)
+        }
+    }
+}
+
+fn new_event(object: &Involved, type_: &str, reason: &str, recorded: &Recorded) -> Event {
+    let now = Utc::now();
+    let event = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Event",
+        "metadata": {
+            "name": recorded.name,
+            "namespace": object.event_namespace(),
+        },
+        "involvedObject": {
+            "apiVersion": "v1",
+            "kind": object.kind,
+            "name": object.name,
+            "namespace": object.namespace,
+            "uid": object.uid,
+        },
+        "reason": reason,
+        "message": recorded.message,
+        "type": type_,
+        "source": { "component": COMPONENT, "host": object.host },
+        "firstTimestamp": recorded.first_timestamp,
+        "lastTimestamp": now,
+        "count": recorded.count,
+    });
+    serde_json::from_value(event).expect("failed to deserialize event from event JSON")
+}
+
+/// What to do with an event once it has been correlated
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Create a new event
+    Create(Recorded),
+    /// Update an event that was already recorded
+    Update(Recorded),
+    /// Drop the event, as too many were recorded for the object
+    Drop,
+}
+
+/// An event recorded in the API
+#[derive(Clone, Debug, PartialEq)]
+struct Recorded {
+    /// The key the event is remembered by
+    key: String,
+    name: String,
+    message: String,
+    count: i32,
+    first_timestamp: DateTime<Utc>,
+    seen: Instant,
+}
+
+/// The events recorded for an object with the same type and reason
+struct Similar {
+    messages: HashSet<String>,
+    since: Instant,
+}
+
+/// An object's budget of events
+struct Budget {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Default)]
+struct Correlator {
+    /// Keyed by object, type and reason
+    similar: HashMap<String, Similar>,
+    /// Keyed by object, type, reason and message
+    recorded: HashMap<String, Recorded>,
+    /// Keyed by object
+    budgets: HashMap<String, Budget>,
+}
+
+impl Correlator {
+    /// Correlate an event about an object, which is identified by a unique key and
+    /// the name its events are named after
+    fn correlate(
+        &mut self,
+        object: &str,
+        name: &str,
+        type_: &str,
+        reason: &str,
+        message: &str,
+        now: Instant,
+    ) -> Action {
+        self.trim(now);
+
+        let budget = self.budgets.entry(object.to_owned()).or_insert(Budget {
+            tokens: SPAM_BURST,
+            last: now,
+        });
+        let refilled = now.duration_since(budget.last).as_secs_f64() * SPAM_QPS;
+        budget.tokens = (budget.tokens + refilled).min(SPAM_BURST);
+        budget.last = now;
+        if budget.tokens < 1.0 {
+            return Action::Drop;
+        }
+        budget.tokens -= 1.0;
+
+        let aggregate_key = format!("{}/{}/{}", object, type_, reason);
+        let similar = self
+            .similar
+            .entry(aggregate_key.clone())
+            .or_insert_with(|| Similar {
+                messages: HashSet::new(),
+                since: now,
+            });
+        if now.duration_since(similar.since) > AGGREGATE_INTERVAL {
+            similar.messages.clear();
+            similar.since = now;
+        }
+        similar.messages.insert(message.to_owned());
+        let (key, message) = if similar.messages.len() > AGGREGATE_MAX_EVENTS {
+            // All combined events share one key, so they update the same event
+            (aggregate_key, format!("{}{}", AGGREGATE_PREFIX, message))
+        } else {
+            (format!("{}/{}", aggregate_key, message), message.to_owned())
+        };
+
+        match self.recorded.get_mut(&key) {
+            Some(recorded) => {
+                recorded.message = message;
+                recorded.count += 1;
+                recorded.seen = now;
+                Action::Update(recorded.clone())
+            }
+            None => {
+                let first_timestamp = Utc::now();
+                // Event names only need to be unique, so they are named after the object
+                // and the time like the ones recorded by the kubelet
+                let name = format!("{}.{:x}", name, first_timestamp.timestamp_nanos());
+                let recorded = Recorded {
+                    key: key.clone(),
+                    name,
+                    message,
+                    count: 1,
+                    first_timestamp,
+                    seen: now,
+                };
+                self.recorded.insert(key, recorded.clone());
+                Action::Create(recorded)
+            }
+        }
+    }
+
+    /// Forget an event that could not be recorded
+    fn forget(&mut self, action: &Action) {
+        if let Action::Create(recorded) | Action::Update(recorded) = action {
+            self.recorded.remove(&recorded.key);
+        }
+    }
+
+    /// Forget about objects and events that have not been seen in a while once there
+    /// are too many of them
+    fn trim(&mut self, now: Instant) {
+        let fresh = |seen: Instant| now.duration_since(seen) <= AGGREGATE_INTERVAL;
+        if self.recorded.len() > MAX_CACHE_ENTRIES {
+            self.recorded.retain(|_, r| fresh(r.seen));
+        }
+        if self.similar.len() > MAX_CACHE_ENTRIES {
+            self.similar.retain(|_, s| fresh(s.since));
+        }
+        if self.budgets.len() > MAX_CACHE_ENTRIES {
+            // A budget that has been refilled completely is the same as a new one
+            self.budgets.retain(|_, b| {
+                b.tokens + now.duration_since(b.last).as_secs_f64() * SPAM_QPS < SPAM_BURST
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    #[test]
+    fn test_repeated_events_are_counted() {
+        let mut correlator = Correlator::default();
+        let now = Instant::now();
+        let first =
+            match correlator.correlate("Pod/default/foo/", "foo", "Warning", "Failed", "boom", now)
+            {
+                Action::Create(recorded) => recorded,
+                action => panic!("unexpected action: {:?}", action),
+            };
+        match correlator.correlate("Pod/default/foo/", "foo", "Warning", "Failed", "boom", now) {
+            Action::Update(recorded) => {
+                assert_eq!(first.name, recorded.name);
+                assert_eq!(2, recorded.count);
+            }
+            action => panic!("unexpected action: {:?}", action),
+        }
+        assert!(matches!(
+            correlator.correlate("Pod/default/foo/", "foo", "Warning", "Failed", "bang", now),
+            Action::Create(_)
+        ));
+        assert!(matches!(
+            correlator.correlate("Pod/default/bar/", "bar", "Warning", "Failed", "boom", now),
+            Action::Create(_)
+        ));
+    }
+
+    #[test]
+    fn test_similar_events_are_combined() {
+        let mut correlator = Correlator::default();
+        let now = Instant::now();
+        for i in 0..AGGREGATE_MAX_EVENTS {
+            let message = format!("attempt {}", i);
+            assert!(matches!(
+                correlator.correlate(
+                    "Pod/default/foo/",
+                    "foo",
+                    "Warning",
+                    "Failed",
+                    &message,
+                    now
+                ),
+                Action::Create(_)
+            ));
+        }
+        match correlator.correlate(
+            "Pod/default/foo/",
+            "foo",
+            "Warning",
+            "Failed",
+            "attempt 10",
+            now,
+        ) {
+            Action::Create(recorded) => {
+                assert_eq!(
+                    "(combined from similar events): attempt 10",
+                    recorded.message
+                )
+            }
+            action => panic!("unexpected action: {:?}", action),
+        }
+        match correlator.correlate(
+            "Pod/default/foo/",
+            "foo",
+            "Warning",
+            "Failed",
+            "attempt 11",
+            now,
+        ) {
+            Action::Update(recorded) => {
+                assert_eq!(
+                    "(combined from similar events): attempt 11",
+                    recorded.message
+                );
+                assert_eq!(2, recorded.count);
+            }
+            action => panic!("unexpected action: {:?}", action),
+        }
+
+        // The messages are only combined within the aggregate interval
+        let later = now + AGGREGATE_INTERVAL + Duration::from_secs(1);
+        assert!(matches!(
+            correlator.correlate("Pod/default/foo/", "foo", "Warning", "Failed", "attempt 12", later),
+            Action::Create(recorded) if recorded.message == "attempt 12"
+        ));
+    }
+
+    #[test]
+    fn test_event_floods_are_dropped() {
+        let mut correlator = Correlator::default();
+        let now = Instant::now();
+        for _ in 0..SPAM_BURST as usize {
+            assert_ne!(
+                Action::Drop,
+                correlator.correlate("Pod/default/foo/", "foo", "Warning", "Failed", "boom", now)
+            );
+        }
+        assert_eq!(
+            Action::Drop,
+            correlator.correlate("Pod/default/foo/", "foo", "Warning", "Failed", "boom", now)
+        );
+        assert_ne!(
+            Action::Drop,
+            correlator.correlate("Pod/default/bar/", "bar", "Warning", "Failed", "boom", now)
+        );
+        // The budget refills over time
+        let later = now + Duration::from_secs(301);
+        assert_ne!(
+            Action::Drop,
+            correlator.correlate(
+                "Pod/default/foo/",
+                "foo",
+                "Warning",
+                "Failed",
+                "boom",
+                later
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_pod_warning() {
+        let server = MockApiServer::start().await.unwrap();
+        let pod: Pod = fake_pod("recorded", "default").into();
+        for _ in 0..3 {
+            record_pod_warning(&server.client(), &pod, "Failed", "boom").await;
+        }
+
+        let api: Api<Event> = Api::namespaced(server.client(), "default");
+        let events = api.list(&Default::default()).await.unwrap().items;
+        assert_eq!(1, events.len());
+        assert_eq!(Some(3), events[0].count);
+        assert_eq!(Some("boom"), events[0].message.as_deref());
+
+        // Another Kubelet's pod doesn't count towards the events recorded by this one
+        let other: Pod = fake_pod("recorded", "default").into();
+        record_pod_warning(&server.client(), &other, "Failed", "boom").await;
+        assert_eq!(2, api.list(&Default::default()).await.unwrap().items.len());
+    }
+}
@@ -0,0 +1,270 @@
+//! Evicting pods that use more local storage than they are allowed
+//!
+//! Wasm workloads that write a lot of logs or fill their `emptyDir` volumes could fill
+//! the node's disk, so the Kubelet regularly measures the local storage of its pods.
+//! Like the Kubernetes kubelet, it evicts a pod when:
+//!
+//! * An `emptyDir` volume holds more than its `sizeLimit`.
+//! * The pod uses more in total than the `ephemeral-storage` limits of its containers.
+//!
+//! Evicted pods are marked as `Failed` with the reason `Evicted`, so controllers
+//! replace them, and are then deleted, which stops their containers.
+use std::collections::HashSet;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::{Pod as KubePod, PodCondition};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::api::{Api, DeleteParams};
+use kube::error::ErrorResponse;
+use log::{info, warn};
+
+use crate::events::record_pod_warning;
+use crate::handle::key_from_pod;
+use crate::pod::Pod;
+use crate::stats::{parse_quantity, requirements, PodStorage, ResourceAccounting, Resources};
+use crate::status::{Phase, StatusPatch};
+
+/// How often the local storage of the pods is checked
+const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The reason reported on evicted pods
+const EVICTED: &str = "Evicted";
+
+/// Regularly evict the pods that use more local storage than they are allowed
+pub(crate) async fn run(client: kube::Client, accounting: ResourceAccounting) {
+    let mut evicted = HashSet::new();
+    loop {
+        tokio::time::delay_for(STORAGE_CHECK_INTERVAL).await;
+        check(&client, &accounting, &mut evicted).await;
+    }
+}
+
+/// Evict the pods over their limits that were not evicted already. The keys of evicted
+/// pods are kept until the pods are no longer accounted for
+async fn check(
+    client: &kube::Client,
+    accounting: &ResourceAccounting,
+    evicted: &mut HashSet<String>,
+) {
+    let pods = accounting.pods();
+    evicted.retain(|key| pods.iter().any(|pod| &key_from_pod(pod) == key));
+    for pod in pods {
+        let key = key_from_pod(&pod);
+        if evicted.contains(&key) || pod.deletion_timestamp().is_some() {
+            continue;
+        }
+        let storage = match accounting.storage(&pod).await {
+            Some(storage) => storage,
+            None => return,
+        };
+        if let Some(message) = violation(&pod, &storage) {
+            evict(client, &pod, &message).await;
+            evicted.insert(key);
+        }
+    }
+}
+
+/// Returns why the pod should be evicted for the storage it uses, if it should be
+fn violation(pod: &Pod, storage: &PodStorage) -> Option<String> {
+    for volume in pod.volumes().into_iter().flatten() {
+        let limit = volume
+            .empty_dir
+            .as_ref()
+            .and_then(|e| e.size_limit.as_ref());
+        let used = storage.volumes.get(&volume.name);
+        if let (Some(limit), Some(used)) = (limit, used) {
+            if matches!(parse_quantity(limit), Some(limit) if *used as f64 > limit) {
+                return Some(format!(
+                    "Usage of EmptyDir volume {:?} exceeds the limit {:?}.",
+                    volume.name, limit.0
+                ));
+            }
+        }
+    }
+    let mut limits = Resources::default();
+    for container in pod.containers() {
+        let (_, limit) = requirements(container.resources.as_ref());
+        limits.add(limit);
+    }
+    match limits.ephemeral_storage_bytes {
+        Some(limit) if storage.total_bytes() > limit => Some(format!(
+            "Pod ephemeral local storage usage exceeds the total limit of containers {}.",
+            limit
+        )),
+        _ => None,
+    }
+}
+
+/// Mark the pod as evicted, keeping the rest of its status, and delete it. Failures
+/// are only logged, as the pod is checked again if it is still there
+async fn evict(client: &kube::Client, pod: &Pod, message: &str) {
+    info!(
+        "Evicting pod {} in namespace {}: {}",
+        pod.name(),
+        pod.namespace(),
+        message
+    );
+    record_pod_warning(client, pod, EVICTED, message).await;
+    let patch = StatusPatch::new()
+        .phase(Phase::Failed)
+        .reason(EVICTED)
+        .message(message)
+        .condition(PodCondition {
+            type_: "DisruptionTarget".to_owned(),
+            status: "True".to_owned(),
+            reason: Some("TerminationByKubelet".to_owned()),
+            message: Some(message.to_owned()),
+            last_transition_time: Some(Time(chrono::Utc::now())),
+            ..Default::default()
+        });
+    if let Err(e) = patch.apply_for(client.clone(), pod).await {
+        warn!(
+            "Unable to report eviction of pod {} in namespace {}: {}",
+            pod.name(),
+            pod.namespace(),
+            e
+        );
+    }
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    match pod
+        .rate_limits()
+        .limited(api.delete(pod.name(), &DeleteParams::default()))
+        .await
+    {
+        Ok(_) | Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => (),
+        Err(e) => warn!(
+            "Unable to delete evicted pod {} in namespace {}: {}",
+            pod.name(),
+            pod.namespace(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+    use k8s_openapi::api::core::v1::ResourceRequirements;
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    const POD_PATH: &str = "/api/v1/namespaces/default/pods/foo";
+
+    fn pod_with_limits(storage_limit: &str, size_limit: &str) -> Pod {
+        let mut kube_pod = fake_pod("foo", "default");
+        let spec = kube_pod.spec.as_mut().unwrap();
+        spec.containers[0].resources = Some(ResourceRequirements {
+            limits: Some(
+                vec![(
+                    "ephemeral-storage".to_owned(),
+                    Quantity(storage_limit.to_owned()),
+                )]
+                .into_iter()
+                .collect(),
+            ),
+            requests: None,
+        });
+        spec.volumes = Some(vec![serde_json::from_value(serde_json::json!({
+            "name": "scratch",
+            "emptyDir": { "sizeLimit": size_limit },
+        }))
+        .unwrap()]);
+        Pod::new(kube_pod)
+    }
+
+    fn storage(scratch: u64, logs_bytes: u64) -> PodStorage {
+        PodStorage {
+            volumes: vec![("scratch".to_owned(), scratch)].into_iter().collect(),
+            logs_bytes,
+        }
+    }
+
+    #[test]
+    fn test_violation() {
+        let pod = pod_with_limits("4Ki", "2Ki");
+        assert_eq!(None, violation(&pod, &storage(1024, 1024)));
+        assert_eq!(
+            Some("Usage of EmptyDir volume \"scratch\" exceeds the limit \"2Ki\".".to_owned()),
+            violation(&pod, &storage(3 * 1024, 0))
+        );
+        assert_eq!(
+            Some(
+                "Pod ephemeral local storage usage exceeds the total limit of containers 4096."
+                    .to_owned()
+            ),
+            violation(&pod, &storage(1024, 4 * 1024))
+        );
+        // Pods without limits are never evicted
+        let pod = Pod::new(fake_pod("foo", "default"));
+        assert_eq!(None, violation(&pod, &storage(1 << 30, 1 << 30)));
+    }
+
+    #[tokio::test]
+    async fn test_pods_over_their_limits_are_evicted() {
+        let server = MockApiServer::start().await.unwrap();
+        let pod = pod_with_limits("1Ki", "1Mi");
+        server.insert("/api/v1/namespaces/default/pods", pod.as_kube_pod());
+        let dir = std::env::temp_dir().join(format!("krustlet-eviction-{}", std::process::id()));
+        let dirs = pod.data_dirs(&dir);
+        dirs.create().await.unwrap();
+        let accounting = ResourceAccounting::new(dir.clone());
+        accounting.register_pod(&pod);
+
+        let mut evicted = HashSet::new();
+        check(&server.client(), &accounting, &mut evicted).await;
+        assert!(evicted.is_empty());
+
+        std::fs::create_dir_all(dirs.volumes().join("scratch/nested")).unwrap();
+        std::fs::write(dirs.volumes().join("scratch/nested/data"), vec![0; 1024]).unwrap();
+        std::fs::write(dirs.logs().join("foo.log"), vec![0; 1024]).unwrap();
+        check(&server.client(), &accounting, &mut evicted).await;
+        let patch = server
+            .requests_to(hyper::Method::PATCH, &format!("{}/status", POD_PATH))
+            .pop()
+            .and_then(|r| r.body)
+            .expect("eviction should be reported");
+        assert_eq!("Failed", patch["status"]["phase"]);
+        assert_eq!(EVICTED, patch["status"]["reason"]);
+        assert_eq!(1, server.requests_to(hyper::Method::DELETE, POD_PATH).len());
+
+        // Pods are only evicted once
+        check(&server.client(), &accounting, &mut evicted).await;
+        assert_eq!(1, server.requests_to(hyper::Method::DELETE, POD_PATH).len());
+        accounting.unregister_pod(&pod);
+        check(&server.client(), &accounting, &mut evicted).await;
+        assert!(evicted.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_eviction_keeps_existing_status() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut pod = serde_json::to_value(fake_pod("foo", "default")).unwrap();
+        pod["status"] = serde_json::json!({
+            "phase": "Running",
+            "conditions": [{ "type": "Ready", "status": "True" }],
+            "containerStatuses": [{ "name": "foo", "ready": true, "restartCount": 0, "image": "", "imageID": "" }],
+        });
+        server.insert("/api/v1/namespaces/default/pods", &pod);
+        let pod = Pod::new(serde_json::from_value(pod).unwrap());
+
+        evict(&server.client(), &pod, "too much storage").await;
+        // The status is applied as a whole, so what the eviction doesn't change is
+        // carried forward rather than removed
+        let patch = server
+            .requests_to(hyper::Method::PATCH, &format!("{}/status", POD_PATH))
+            .pop()
+            .and_then(|r| r.body)
+            .expect("eviction should be reported");
+        assert_eq!("Failed", patch["status"]["phase"]);
+        assert_eq!(EVICTED, patch["status"]["reason"]);
+        assert_eq!("foo", patch["status"]["containerStatuses"][0]["name"]);
+        let conditions: Vec<&str> = patch["status"]["conditions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["Ready", "DisruptionTarget"], conditions);
+    }
+}
@@ -0,0 +1,512 @@
+//! Reporting pod sync failures onto pod statuses
+//!
+//! Failures are kept per pod instead of in a bounded channel, so a pod worker never
+//! blocks on a slow API server and no failure is dropped because the channel is
+//! full. Only the newest unreported failure for a pod is kept, and it is discarded
+//! if the pod syncs successfully before it is reported, so the pod status never
+//! goes back to an older failure. Patches that the API server will keep refusing, such
+//! as a 403 Forbidden, are given up on instead of being retried.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kube::error::ErrorResponse;
+use log::{debug, error, warn};
+use tokio::sync::mpsc;
+
+use crate::cancellation::CancellationToken;
+use crate::error::{KubeletError, PodSyncError};
+use crate::handle::key_from_pod;
+use crate::pod::{Pod, ValidatedPod};
+use crate::redact::redact;
+use crate::sandbox::ready_condition;
+use crate::status::{Phase, StatusPatch};
+
+/// The longest we wait before retrying a failed status patch
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+struct Pending {
+    /// Tells this failure apart from the other failures reported for the pod
+    id: u64,
+    pod: ValidatedPod,
+    error: PodSyncError,
+    /// The error message, with the pod's sensitive values redacted while they are
+    /// still known
+    message: String,
+    attempts: u32,
+}
+
+#[derive(Default)]
+struct State {
+    /// The failures waiting to be patched, by pod key
+    pending: HashMap<String, Pending>,
+    /// The id of the failure of each pod that is being patched or waits for a retry
+    in_flight: HashMap<String, u64>,
+    next_id: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    wake: mpsc::UnboundedSender<String>,
+}
+
+/// The sending half of the failure pipeline, which is cloned into each pod worker
+#[derive(Clone)]
+pub(crate) struct FailureReporter {
+    shared: Arc<Shared>,
+}
+
+/// The receiving half of the failure pipeline
+pub(crate) struct FailureQueue {
+    reporter: FailureReporter,
+    wake: mpsc::UnboundedReceiver<String>,
+}
+
+impl FailureReporter {
+    /// Create a new failure pipeline
+    pub(crate) fn new() -> (Self, FailureQueue) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let reporter = FailureReporter {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                wake: sender,
+            }),
+        };
+        let queue = FailureQueue {
+            reporter: reporter.clone(),
+            wake: receiver,
+        };
+        (reporter, queue)
+    }
+
+    /// Report a failure for the given pod, replacing any failure that has not been
+    /// reported yet
+    pub(crate) fn report(&self, pod: ValidatedPod, error: PodSyncError) {
+        self.insert(
+            key_from_pod(&pod),
+            Pending {
+                message: redact(&pod, &error.to_string()),
+                pod,
+                error,
+                id: 0,
+                attempts: 0,
+            },
+        );
+    }
+
+    /// Discard any unreported failure for the pod because it has since synced successfully
+    pub(crate) fn resolve(&self, pod: &Pod) {
+        let key = key_from_pod(pod);
+        let mut state = self.shared.state.lock().unwrap();
+        state.pending.remove(&key);
+        // A failure waiting for a retry is out of date too
+        state.in_flight.remove(&key);
+    }
+
+    fn insert(&self, key: String, mut pending: Pending) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.next_id += 1;
+        pending.id = state.next_id;
+        state.in_flight.remove(&key);
+        // The queue is only woken once per pod, however many failures are reported
+        // before it gets to it
+        let newly_pending = state.pending.insert(key.clone(), pending).is_none();
+        drop(state);
+        if newly_pending {
+            // The queue only goes away when the Kubelet is shutting down
+            let _ = self.shared.wake.send(key);
+        }
+    }
+
+    /// Put back a failure whose patch failed, unless a newer failure or a successful
+    /// sync for the pod came in since it was taken
+    fn retry(&self, key: String, pending: Pending) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.in_flight.get(&key) == Some(&pending.id) {
+            state.in_flight.remove(&key);
+            state.pending.insert(key.clone(), pending);
+            drop(state);
+            let _ = self.shared.wake.send(key);
+        }
+    }
+
+    /// Forget a failure that was patched or given up on
+    fn done(&self, key: &str, pending: &Pending) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.in_flight.get(key) == Some(&pending.id) {
+            state.in_flight.remove(key);
+        }
+    }
+}
+
+impl FailureQueue {
+    /// Wait for the next pod with an unreported failure
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) async fn next(&mut self) -> Option<(ValidatedPod, PodSyncError)> {
+        self.next_pending()
+            .await
+            .map(|(_, pending)| (pending.pod, pending.error))
+    }
+
+    async fn next_pending(&mut self) -> Option<(String, Pending)> {
+        loop {
+            let key = self.wake.recv().await?;
+            // The failure may have been resolved since the queue was woken
+            let mut state = self.reporter.shared.state.lock().unwrap();
+            if let Some(pending) = state.pending.remove(&key) {
+                state.in_flight.insert(key.clone(), pending.id);
+                return Some((key, pending));
+            }
+        }
+    }
+
+    /// Patch each reported failure onto its pod's status.
+    ///
+    /// Patches that fail are retried with an increasing delay until they succeed, the
+    /// pod no longer exists or the API server refuses them for good. Once `stop` is cancelled, the failures still pending
+    /// are patched a last time, without retrying, and the queue stops.
+    pub(crate) async fn run(mut self, client: kube::Client, stop: CancellationToken) {
+        loop {
+            let (key, mut pending) = match stop.run_until_cancelled(self.next_pending()).await {
+                Some(Some(next)) => next,
+                Some(None) => break,
+                None => return self.flush(&client).await,
+            };
+            match patch_failure(&client, &pending).await {
+                Ok(()) => self.reporter.done(&key, &pending),
+                Err(e) if !retryable(&e) => {
+                    error!(
+                        "Unable to patch status during pod failure for {}, giving up: {}",
+                        pending.pod.name(),
+                        e
+                    );
+                    self.reporter.done(&key, &pending);
+                }
+                Err(e) => {
+                    pending.attempts += 1;
+                    let delay = retry_delay(pending.attempts);
+                    warn!(
+                        "Unable to patch status during pod failure for {}, retrying in {:?}: {}",
+                        pending.pod.name(),
+                        delay,
+                        e
+                    );
+                    let reporter = self.reporter.clone();
+                    tokio::spawn(async move {
+                        tokio::time::delay_for(delay).await;
+                        reporter.retry(key, pending);
+                    });
+                }
+            }
+        }
+        error!("Failure pipeline closed, pod failures will no longer be reported");
+    }
+
+    /// Patch the failures that are still pending once, as the Kubelet stops
+    async fn flush(self, client: &kube::Client) {
+        let pending: Vec<Pending> = self
+            .reporter
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .pending
+            .drain()
+            .map(|(_, pending)| pending)
+            .collect();
+        for pending in pending {
+            if let Err(e) = patch_failure(client, &pending).await {
+                warn!(
+                    "Unable to patch status during pod failure for {} before stopping: {}",
+                    pending.pod.name(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Patch a failure onto its pod's status, keeping the containers and conditions already
+/// reported for it. A pod that no longer exists needs no patch
+async fn patch_failure(client: &kube::Client, pending: &Pending) -> Result<(), KubeletError> {
+    let pod = &pending.pod;
+    let mut patch = StatusPatch::new()
+        .phase(Phase::Failed)
+        .reason(pending.error.reason())
+        .message(pending.message.clone());
+    if let PodSyncError::PodSetup(_) = pending.error {
+        patch = patch.condition(ready_condition(false, Some(pending.message.clone())));
+    }
+
+    debug!("Setting pod status for {} using {:?}", pod.name(), patch);
+    match patch.apply_for(client.clone(), pod).await {
+        Ok(_) => Ok(()),
+        Err(KubeletError::StatusPatch {
+            source: kube::Error::Api(ErrorResponse { code: 404, .. }),
+            ..
+        }) => {
+            debug!(
+                "Pod {} in namespace {} was removed before its failure was reported",
+                pod.name(),
+                pod.namespace()
+            );
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether a failed patch may succeed when it is retried. The API server refuses other
+/// client errors, such as a forbidden or invalid patch, every time
+fn retryable(error: &KubeletError) -> bool {
+    match error {
+        KubeletError::StatusPatch {
+            source: kube::Error::Api(ErrorResponse { code, .. }),
+            ..
+        } => !(400..500).contains(code) || *code == 409 || *code == 429,
+        _ => true,
+    }
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    Duration::from_millis(100)
+        .checked_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .unwrap_or(MAX_RETRY_DELAY)
+        .min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn validated(name: &str) -> ValidatedPod {
+        ValidatedPod::new(fake_pod(name, "default").into()).unwrap()
+    }
+
+    fn failure(message: &str) -> PodSyncError {
+        PodSyncError::Provider(anyhow::anyhow!(message.to_owned()))
+    }
+
+    #[tokio::test]
+    async fn test_newest_failure_wins() {
+        let (reporter, mut queue) = FailureReporter::new();
+        let pod = validated("foo");
+        reporter.report(pod.clone(), failure("first"));
+        reporter.report(pod.clone(), failure("second"));
+        reporter.report(validated("bar"), failure("other"));
+
+        let (pod, err) = queue.next().await.unwrap();
+        assert_eq!("foo", pod.name());
+        assert_eq!("second", err.to_string());
+        let (pod, _) = queue.next().await.unwrap();
+        assert_eq!("bar", pod.name());
+    }
+
+    #[tokio::test]
+    async fn test_resolved_failures_are_dropped() {
+        let (reporter, mut queue) = FailureReporter::new();
+        let pod = validated("foo");
+        reporter.report(pod.clone(), failure("stale"));
+        reporter.resolve(&pod);
+        reporter.report(validated("bar"), failure("other"));
+
+        let (pod, _) = queue.next().await.unwrap();
+        assert_eq!("bar", pod.name());
+    }
+
+    #[tokio::test]
+    async fn test_resolved_failures_are_not_retried() {
+        let (reporter, mut queue) = FailureReporter::new();
+        let pod = validated("foo");
+        reporter.report(pod.clone(), failure("stale"));
+        let (key, pending) = queue.next_pending().await.unwrap();
+        // The pod syncs while the failed patch waits to be retried
+        reporter.resolve(&pod);
+        reporter.retry(key.clone(), pending);
+        assert!(!reporter
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .pending
+            .contains_key(&key));
+
+        // A newer failure is not replaced by the retry of an older one either
+        reporter.report(pod.clone(), failure("first"));
+        let (key, first) = queue.next_pending().await.unwrap();
+        reporter.report(pod.clone(), failure("second"));
+        reporter.retry(key, first);
+        let (_, err) = queue.next().await.unwrap();
+        assert_eq!("second", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_patches_are_given_up() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("foo", "default"),
+        );
+        server.deny("/api/v1/namespaces/default/pods/foo/status");
+        let (reporter, queue) = FailureReporter::new();
+        tokio::spawn(queue.run(server.client(), CancellationToken::new()));
+        reporter.report(validated("foo"), failure("boom"));
+
+        // A failure waiting for a retry stays in flight
+        let patches = || {
+            server.requests_to(
+                hyper::Method::PATCH,
+                "/api/v1/namespaces/default/pods/foo/status",
+            )
+        };
+        let given_up = async {
+            while patches().is_empty()
+                || !reporter.shared.state.lock().unwrap().in_flight.is_empty()
+            {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, given_up).await.unwrap();
+        assert_eq!(1, patches().len());
+    }
+
+    #[test]
+    fn test_retryable() {
+        let status_error = |code| KubeletError::StatusPatch {
+            pod_name: "foo".to_owned(),
+            source: kube::Error::Api(ErrorResponse {
+                status: "Failure".to_owned(),
+                message: String::new(),
+                reason: String::new(),
+                code,
+            }),
+        };
+        assert!(retryable(&status_error(409)));
+        assert!(retryable(&status_error(429)));
+        assert!(retryable(&status_error(500)));
+        assert!(!retryable(&status_error(403)));
+        assert!(!retryable(&status_error(422)));
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_patched_onto_status() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("foo", "default"),
+        );
+        let (reporter, queue) = FailureReporter::new();
+        tokio::spawn(queue.run(server.client(), CancellationToken::new()));
+        // A pod that is already gone should not hold up the others
+        reporter.report(validated("gone"), failure("lost"));
+        reporter.report(validated("foo"), failure("boom"));
+
+        let patched = async {
+            loop {
+                let pod = server.get("/api/v1/namespaces/default/pods/foo").unwrap();
+                if pod["status"]["phase"] == "Failed" {
+                    return pod;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        let pod = tokio::time::timeout(TIMEOUT, patched).await.unwrap();
+        assert_eq!("boom", pod["status"]["message"]);
+    }
+
+    #[tokio::test]
+    async fn test_failure_keeps_existing_status() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut pod = serde_json::to_value(fake_pod("foo", "default")).unwrap();
+        pod["status"] = serde_json::json!({
+            "phase": "Running",
+            "conditions": [
+                { "type": "Initialized", "status": "True" },
+                { "type": "Ready", "status": "True" },
+            ],
+            "containerStatuses": [{ "name": "foo", "ready": true, "restartCount": 0, "image": "", "imageID": "" }],
+        });
+        server.insert("/api/v1/namespaces/default/pods", &pod);
+        let (reporter, queue) = FailureReporter::new();
+        tokio::spawn(queue.run(server.client(), CancellationToken::new()));
+        reporter.report(
+            validated("foo"),
+            PodSyncError::PodSetup(anyhow::anyhow!("no volume")),
+        );
+
+        let patched = async {
+            loop {
+                let pod = server.get("/api/v1/namespaces/default/pods/foo").unwrap();
+                if pod["status"]["phase"] == "Failed" {
+                    return pod;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        let status = tokio::time::timeout(TIMEOUT, patched).await.unwrap()["status"].clone();
+        assert_eq!("foo", status["containerStatuses"][0]["name"]);
+        let conditions = status["conditions"].as_array().unwrap();
+        assert_eq!(3, conditions.len());
+        assert_eq!("Initialized", conditions[0]["type"]);
+        assert_eq!("Ready", conditions[1]["type"]);
+        assert_eq!("PodReadyToStartContainers", conditions[2]["type"]);
+        assert_eq!("False", conditions[2]["status"]);
+    }
+
+    #[tokio::test]
+    async fn test_pending_failures_are_flushed_on_stop() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("foo", "default"),
+        );
+        let (reporter, queue) = FailureReporter::new();
+        reporter.report(validated("foo"), failure("boom"));
+        let stop = CancellationToken::new();
+        stop.cancel();
+
+        tokio::time::timeout(TIMEOUT, queue.run(server.client(), stop))
+            .await
+            .expect("the queue should stop once flushed");
+        let pod = server.get("/api/v1/namespaces/default/pods/foo").unwrap();
+        assert_eq!("Failed", pod["status"]["phase"]);
+        assert_eq!("boom", pod["status"]["message"]);
+    }
+
+    #[tokio::test]
+    async fn test_sensitive_values_are_redacted() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("leaky", "default"),
+        );
+        let pod = validated("leaky");
+        crate::redact::register(&pod, "hunter22");
+        let (reporter, queue) = FailureReporter::new();
+        tokio::spawn(queue.run(server.client(), CancellationToken::new()));
+        reporter.report(pod.clone(), failure("bad password hunter22"));
+        crate::redact::forget(&pod);
+
+        let patched = async {
+            loop {
+                let pod = server.get("/api/v1/namespaces/default/pods/leaky").unwrap();
+                if pod["status"]["phase"] == "Failed" {
+                    return pod;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        let pod = tokio::time::timeout(TIMEOUT, patched).await.unwrap();
+        assert_eq!("bad password [REDACTED]", pod["status"]["message"]);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(Duration::from_millis(100), retry_delay(1));
+        assert_eq!(Duration::from_millis(400), retry_delay(3));
+        assert_eq!(MAX_RETRY_DELAY, retry_delay(40));
+    }
+}
@@ -0,0 +1,266 @@
+//! Injecting failures into the Kubelet to test how it recovers from them
+//!
+//! With the `fault-injection` feature, the Kubelet can be made to fail the way it does
+//! on a busy cluster, so the retries and backoffs around those failures can be tested
+//! end to end. Faults are chosen by a comma separated list of:
+//!
+//! - `drop-status-patches=<percent>`, which fails that share of pod status patches as
+//!   if the API server were unavailable
+//! - `delay-image-pulls=<duration>`, which holds the modules of every pod for that long
+//!   before they are fetched, such as `500ms` or `2s`
+//! - `fail-provider-calls=<k>`, which fails every kth pod event passed to the provider
+//!
+//! Each Kubelet has a [`FaultInjector`] of its own. The faults start out as
+//! [`Config::faults`](crate::config::Config::faults) and can be replaced at any time
+//! through the Kubelet server:
+//!
+//! ```text
+//! curl -X PUT --data 'drop-status-patches=20,fail-provider-calls=3' https://<node>:3000/debug/faults
+//! ```
+//!
+//! Failures are spread evenly rather than at random, so a run can be reproduced.
+//! Setting the faults starts counting again. This is not meant for production nodes.
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use kube::error::ErrorResponse;
+
+/// The failures injected into the Kubelet
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct Faults {
+    /// The percentage of pod status patches that fail, from 0 to 100
+    pub drop_status_patches: u8,
+    /// How long every image pull is held before it starts
+    pub delay_image_pulls: Duration,
+    /// Every kth pod event passed to the provider fails, or none if this is 0
+    pub fail_provider_calls: u64,
+}
+
+impl FromStr for Faults {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut faults = Faults::default();
+        for fault in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (name, value) = match fault.find('=') {
+                Some(i) => (&fault[..i], fault[i + 1..].trim()),
+                None => anyhow::bail!("fault {:?} has no value", fault),
+            };
+            let invalid = || anyhow::anyhow!("invalid value {:?} for fault {}", value, name);
+            match name.trim() {
+                "drop-status-patches" => {
+                    faults.drop_status_patches = value.parse().map_err(|_| invalid())?;
+                    if faults.drop_status_patches > 100 {
+                        return Err(invalid());
+                    }
+                }
+                "delay-image-pulls" => {
+                    faults.delay_image_pulls = parse_duration(value).ok_or_else(invalid)?
+                }
+                "fail-provider-calls" => {
+                    faults.fail_provider_calls = value.parse().map_err(|_| invalid())?
+                }
+                other => anyhow::bail!("unknown fault {:?}", other),
+            }
+        }
+        Ok(faults)
+    }
+}
+
+impl fmt::Display for Faults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut faults = Vec::new();
+        if self.drop_status_patches > 0 {
+            faults.push(format!("drop-status-patches={}", self.drop_status_patches));
+        }
+        if self.delay_image_pulls > Duration::from_secs(0) {
+            let delay = self.delay_image_pulls;
+            if delay.subsec_millis() == 0 {
+                faults.push(format!("delay-image-pulls={}s", delay.as_secs()));
+            } else {
+                faults.push(format!("delay-image-pulls={}ms", delay.as_millis()));
+            }
+        }
+        if self.fail_provider_calls > 0 {
+            faults.push(format!("fail-provider-calls={}", self.fail_provider_calls));
+        }
+        write!(f, "{}", faults.join(","))
+    }
+}
+
+/// Parses a whole number of seconds or milliseconds, such as `2s` or `500ms`
+fn parse_duration(value: &str) -> Option<Duration> {
+    let unit = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit);
+    let number = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        _ => None,
+    }
+}
+
+/// Injects the faults, counting the calls they apply to
+#[derive(Debug, Default)]
+struct Injector {
+    faults: Faults,
+    status_patches: AtomicU64,
+    provider_calls: AtomicU64,
+}
+
+impl Injector {
+    fn new(faults: Faults) -> Self {
+        Injector {
+            faults,
+            ..Default::default()
+        }
+    }
+
+    fn drop_status_patch(&self) -> bool {
+        let percent = u64::from(self.faults.drop_status_patches);
+        let n = self.status_patches.fetch_add(1, Ordering::SeqCst);
+        // A patch is dropped each time the share of patches dropped so far falls behind
+        (n + 1) * percent / 100 > n * percent / 100
+    }
+
+    fn fail_provider_call(&self) -> bool {
+        let k = self.faults.fail_provider_calls;
+        let n = self.provider_calls.fetch_add(1, Ordering::SeqCst);
+        k > 0 && n % k == k - 1
+    }
+}
+
+/// The faults injected into a Kubelet. Clones share the same faults, and the default
+/// injects nothing
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjector(Arc<RwLock<Arc<Injector>>>);
+
+impl FaultInjector {
+    /// Inject the given faults
+    pub(crate) fn new(faults: &Faults) -> Self {
+        FaultInjector(Arc::new(RwLock::new(Arc::new(Injector::new(
+            faults.clone(),
+        )))))
+    }
+
+    /// Replace the injected faults with the given list, such as `fail-provider-calls=3`.
+    /// An empty list injects nothing
+    pub fn set(&self, faults: &str) -> anyhow::Result<()> {
+        *self.0.write().unwrap() = Arc::new(Injector::new(faults.parse()?));
+        Ok(())
+    }
+
+    /// The faults injected now, in the format taken by [`set`](FaultInjector::set)
+    pub fn get(&self) -> String {
+        self.injector().faults.to_string()
+    }
+
+    fn injector(&self) -> Arc<Injector> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Returns an error in place of a status patch if the patch is to be dropped
+    pub(crate) fn status_patch(&self) -> Result<(), kube::Error> {
+        if self.injector().drop_status_patch() {
+            return Err(kube::Error::Api(ErrorResponse {
+                status: "Failure".to_owned(),
+                message: "status patch dropped by fault injection".to_owned(),
+                reason: "ServiceUnavailable".to_owned(),
+                code: 503,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Wait before an image pull starts, for as long as pulls are delayed
+    pub(crate) async fn image_pull(&self) {
+        let delay = self.injector().faults.delay_image_pulls;
+        if delay > Duration::from_secs(0) {
+            tokio::time::delay_for(delay).await;
+        }
+    }
+
+    /// Returns an error in place of a call to the provider if the call is to fail
+    pub(crate) fn provider_call(&self) -> anyhow::Result<()> {
+        if self.injector().fail_provider_call() {
+            anyhow::bail!("provider call failed by fault injection");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_faults() {
+        let faults: Faults =
+            "drop-status-patches=10, delay-image-pulls=500ms,fail-provider-calls=3"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            Faults {
+                drop_status_patches: 10,
+                delay_image_pulls: Duration::from_millis(500),
+                fail_provider_calls: 3,
+            },
+            faults
+        );
+        assert_eq!(
+            "drop-status-patches=10,delay-image-pulls=500ms,fail-provider-calls=3",
+            faults.to_string()
+        );
+        assert_eq!(
+            "delay-image-pulls=2s",
+            "delay-image-pulls=2s"
+                .parse::<Faults>()
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!(Faults::default(), "".parse().unwrap());
+
+        assert!("drop-status-patches=101".parse::<Faults>().is_err());
+        assert!("delay-image-pulls=2".parse::<Faults>().is_err());
+        assert!("fail-provider-calls".parse::<Faults>().is_err());
+        assert!("fail-everything=1".parse::<Faults>().is_err());
+    }
+
+    #[test]
+    fn test_injector() {
+        let injector = Injector::new(
+            "drop-status-patches=25,fail-provider-calls=3"
+                .parse()
+                .unwrap(),
+        );
+        let dropped = (0..100).filter(|_| injector.drop_status_patch()).count();
+        assert_eq!(25, dropped);
+        let failed: Vec<bool> = (0..6).map(|_| injector.fail_provider_call()).collect();
+        assert_eq!(vec![false, false, true, false, false, true], failed);
+
+        let injector = Injector::default();
+        assert!(!(0..100).any(|_| injector.drop_status_patch() || injector.fail_provider_call()));
+    }
+
+    #[test]
+    fn test_kubelets_have_their_own_faults() {
+        let first = FaultInjector::new(&"fail-provider-calls=1".parse().unwrap());
+        let second = FaultInjector::default();
+        assert!(first.provider_call().is_err());
+        assert!(second.provider_call().is_ok());
+
+        // Clones share the faults, which start counting again when they are set
+        let shared = first.clone();
+        shared.set("drop-status-patches=100").unwrap();
+        assert_eq!("drop-status-patches=100", first.get());
+        assert!(first.status_patch().is_err());
+        assert!(first.provider_call().is_ok());
+        assert!(first.set("fail-everything=1").is_err());
+        assert_eq!("", second.get());
+    }
+}
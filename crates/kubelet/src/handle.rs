@@ -6,6 +6,7 @@
 
 use std::collections::HashMap;
 use std::io::SeekFrom;
+use std::path::Path;
 
 use log::{debug, error, info};
 use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
@@ -15,6 +16,7 @@ use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 
 use crate::logs::{stream_logs, LogSender};
+use crate::pod_logs::PodLogFiles;
 use crate::provider::ProviderError;
 use crate::status::{ContainerStatus, Status};
 use crate::volumes::VolumeRef;
@@ -87,6 +89,15 @@ impl<S: Stop, H> RuntimeHandle<S, H> {
         Ok(())
     }
 
+    /// Copies output from the running process, from the start, to its log file
+    fn write_log_file<R>(&self, files: &PodLogFiles, container_name: &str)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        H: LogHandleFactory<R>,
+    {
+        files.write(container_name, self.handle_factory.new_handle());
+    }
+
     /// Returns a clone of the status_channel for use in reporting the status to
     /// another process
     pub(crate) fn status(&self) -> Receiver<ContainerStatus> {
@@ -110,7 +121,8 @@ pub struct PodHandle<S, H> {
     pod: Pod,
     // Storage for the volume references so they don't get dropped until the runtime handle is
     // dropped
-    _volumes: HashMap<String, VolumeRef>,
+    volumes: HashMap<String, VolumeRef>,
+    log_files: Option<PodLogFiles>,
 }
 
 impl<S: Stop, H> PodHandle<S, H> {
@@ -155,10 +167,48 @@ impl<S: Stop, H> PodHandle<S, H> {
             container_handles: RwLock::new(container_handles),
             status_handle,
             pod,
-            _volumes: volumes.unwrap_or_default(),
+            volumes: volumes.unwrap_or_default(),
+            log_files: None,
         })
     }
 
+    /// Also write the output of the pod's containers to log files in the given
+    /// directory for node level log shippers, see [`pod_logs`](crate::pod_logs). The
+    /// files are removed a minute after the handle is dropped
+    pub fn with_log_files<R>(mut self, dir: &Path) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        H: LogHandleFactory<R>,
+    {
+        let files = PodLogFiles::new(dir, &self.pod);
+        let handles = self.container_handles.into_inner();
+        for (name, handle) in handles.iter() {
+            handle.write_log_file(&files, name);
+        }
+        self.container_handles = RwLock::new(handles);
+        self.log_files = Some(files);
+        self
+    }
+
+    /// Get the volumes that were given to the pod's containers
+    pub fn volumes(&self) -> &HashMap<String, VolumeRef> {
+        &self.volumes
+    }
+
+    /// Add a handle for a container that was started after the pod, such as an ephemeral
+    /// container. The container can be stopped and have its logs fetched like any other, but
+    /// its status updates are not reported
+    pub async fn add_container<R>(&self, name: String, handle: RuntimeHandle<S, H>)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        H: LogHandleFactory<R>,
+    {
+        if let Some(files) = &self.log_files {
+            handle.write_log_file(files, &name);
+        }
+        self.container_handles.write().await.insert(name, handle);
+    }
+
     /// Streams output from the specified container into the given sender.
     /// Optionally tails the output and/or continues to watch the file and stream changes.
     pub async fn output<R>(&mut self, container_name: &str, sender: LogSender) -> anyhow::Result<()>
@@ -179,22 +229,32 @@ impl<S: Stop, H> PodHandle<S, H> {
 
     /// Signal the pod and all its running containers to stop and wait for them
     /// to complete. As of right now, there is not a way to do this in wasmtime,
-    /// so this does nothing
+    /// so this does nothing. Containers that fail to stop don't keep the others from
+    /// being stopped, and fail the stop once the others are done, so it can be retried
     pub async fn stop(&mut self) -> anyhow::Result<()> {
+        let mut failed = Vec::new();
         {
             let mut handles = self.container_handles.write().await;
             for (name, handle) in handles.iter_mut() {
                 info!("Stopping container: {}", name);
                 match handle.stop().await {
                     Ok(_) => debug!("Successfully stopped container {}", name),
-                    // NOTE: I am not sure what recovery or retry steps should be
-                    // done here, but we should definitely continue and try to stop
-                    // the other containers
-                    Err(e) => error!("Error while trying to stop pod {}: {:?}", name, e),
+                    Err(e) => {
+                        error!("Error while trying to stop pod {}: {:?}", name, e);
+                        failed.push(name.clone());
+                    }
                 }
             }
         }
-        Ok(())
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            failed.sort();
+            Err(anyhow::anyhow!(
+                "unable to stop containers {}",
+                failed.join(", ")
+            ))
+        }
     }
 
     /// Wait for all containers in the pod to complete
@@ -8,6 +8,9 @@ use async_trait::async_trait;
 use oci_distribution::Reference;
 
 /// An image client capable of fetching images from a storage location
+///
+/// Clients are shared by all pulls, so they must be able to pull several images
+/// at once.
 #[async_trait]
 pub trait ImageClient {
     /// Given a certain image reference pull the image data from a storage location
@@ -22,7 +25,7 @@ pub trait ImageClient {
     ///
     /// #[async_trait]
     /// impl ImageClient for InMemoryClient {
-    ///     async fn pull(&mut self, image: &Reference) -> anyhow::Result<Vec<u8>> {
+    ///     async fn pull(&self, image: &Reference) -> anyhow::Result<Vec<u8>> {
     ///         let image = self
     ///             .0
     ///             .get(image)
@@ -31,12 +34,60 @@ pub trait ImageClient {
     ///     }
     /// }
     /// ```
-    async fn pull(&mut self, image: &Reference) -> anyhow::Result<Vec<u8>>;
+    async fn pull(&self, image: &Reference) -> anyhow::Result<Vec<u8>>;
+
+    /// Pull the image given `base`, the module of an earlier version of it that is
+    /// stored already. Clients that fetch deltas between versions download less this
+    /// way. By default the whole image is pulled
+    async fn pull_from_base(&self, image: &Reference, _base: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.pull(image).await
+    }
+
+    /// Pull the image as [`ImageClient::pull`] does, or as [`ImageClient::pull_from_base`]
+    /// does when a base is given, along with the digest of the image's manifest if the
+    /// client knows it. The digest identifies the image in container statuses. By
+    /// default it isn't known
+    async fn pull_with_digest(
+        &self,
+        image: &Reference,
+        base: Option<&[u8]>,
+    ) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+        let module = match base {
+            Some(base) => self.pull_from_base(image, base).await?,
+            None => self.pull(image).await?,
+        };
+        Ok((module, None))
+    }
+
+    /// The digest of the manifest the image's reference points to now, if the client
+    /// can look it up. Stores shared by several nodes key modules by digest, so an
+    /// image whose tag has moved is not served from an old copy. By default it isn't
+    /// known
+    async fn digest(&self, _image: &Reference) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
 }
 
 #[async_trait]
 impl ImageClient for oci_distribution::Client {
-    async fn pull(&mut self, image: &Reference) -> anyhow::Result<Vec<u8>> {
+    async fn pull(&self, image: &Reference) -> anyhow::Result<Vec<u8>> {
         self.pull_image(image).await
     }
+
+    async fn pull_from_base(&self, image: &Reference, base: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.pull_image_from_base(image, base).await
+    }
+
+    async fn pull_with_digest(
+        &self,
+        image: &Reference,
+        base: Option<&[u8]>,
+    ) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+        let (module, digest) = self.pull_image_with_digest(image, base).await?;
+        Ok((module, Some(digest)))
+    }
+
+    async fn digest(&self, image: &Reference) -> anyhow::Result<Option<String>> {
+        Ok(Some(self.fetch_manifest_digest(image).await?))
+    }
 }
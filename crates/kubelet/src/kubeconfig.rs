@@ -0,0 +1,641 @@
+//! Loading the configuration used to connect to the Kubernetes API
+//!
+//! Besides static tokens and client certificates, kubeconfig files can use
+//! [credential plugins](https://kubernetes.io/docs/reference/access-authn-authz/authentication/#client-go-credential-plugins)
+//! such as `aws-iam-authenticator` or `gke-gcloud-auth-plugin`, which most managed
+//! clusters require. The plugin is run when the configuration is loaded, and again
+//! before any request made after the token it returned is about to expire, so
+//! long running Kubelets keep working as tokens are rotated.
+//!
+//! Plugins must return a token. Plugins that only return a client certificate are
+//! not supported, and tokens returned without an `expirationTimestamp` are never
+//! refreshed.
+//!
+//! The kubeconfig can be a list of files, separated like `PATH` is, which are merged
+//! the way `kubectl` merges them: the first file to set the current context or to
+//! define a cluster, user or context by a name wins. Client certificates, credential
+//! plugins and auth providers are loaded by the kube client itself, which only reads
+//! them from the single file in `KUBECONFIG` or from `$HOME/.kube/config`, so they
+//! can't be used from other files or from a list.
+//!
+//! The Kubelet makes its clients from the configuration with a [`ClientFactory`], which
+//! applications that embed the Kubelet can replace to instrument its requests.
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use kube::config::{AuthInfo, Cluster, ExecConfig, KubeConfigOptions, Kubeconfig};
+use log::info;
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION, USER_AGENT};
+
+use crate::config::ApiClientConfig;
+
+/// Where the token, CA certificate and namespace of pods that run with a service account
+/// are mounted
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Load the Kubernetes client configuration.
+///
+/// If a kubeconfig path, or list of paths, is given, it is always used. Otherwise the
+/// configuration is inferred from the environment with [`infer`].
+pub async fn load(path: Option<&Path>) -> anyhow::Result<kube::Config> {
+    match path {
+        Some(path) => load_from(path).await,
+        None => infer().await,
+    }
+}
+
+async fn load_from(path: &Path) -> anyhow::Result<kube::Config> {
+    let kubeconfig = read(path.as_os_str())?;
+    let user = current_user(&kubeconfig)?;
+    if !loaded_by_kube(user) {
+        return from_kubeconfig(&kubeconfig);
+    }
+    // The kube client finds the file itself, and has to be the one to load it
+    if kube_kubeconfig().as_deref() != Some(path) {
+        return Err(anyhow::anyhow!(
+            "client certificates, credential plugins and auth providers can only be loaded from the kubeconfig in KUBECONFIG or $HOME/.kube/config, not {}",
+            path.display()
+        ));
+    }
+    if let Some(exec) = exec_plugin(&kubeconfig) {
+        info!(
+            "Using credential plugin {} from kubeconfig {}",
+            exec.command,
+            path.display()
+        );
+    }
+    Ok(kube::Config::new_from_kubeconfig(&KubeConfigOptions::default()).await?)
+}
+
+/// Infer the Kubernetes client configuration from the environment.
+///
+/// When running in a pod, the pod's service account is used (its token and CA
+/// certificate, along with the API server address from `KUBERNETES_SERVICE_HOST` and
+/// `KUBERNETES_SERVICE_PORT`), so the Kubelet can be run as a pod without any extra
+/// flags. Otherwise the kubeconfig file in `KUBECONFIG` or `$HOME/.kube/config` is used.
+pub async fn infer() -> anyhow::Result<kube::Config> {
+    let service = (
+        std::env::var_os("KUBERNETES_SERVICE_HOST"),
+        std::env::var_os("KUBERNETES_SERVICE_PORT"),
+    );
+    let service_account = Path::new(SERVICE_ACCOUNT_DIR);
+    if running_in_cluster(service.clone(), &service_account.join("token")) {
+        info!("Using the in-cluster service account configuration");
+        let (host, port) = (service.0.unwrap_or_default(), service.1.unwrap_or_default());
+        return from_service_account(
+            &host.to_string_lossy(),
+            &port.to_string_lossy(),
+            service_account,
+        )
+        .map_err(|e| anyhow::anyhow!("unable to load in-cluster configuration: {}", e));
+    }
+    match kube_kubeconfig() {
+        Some(path) => load_from(&path).await,
+        None => Err(anyhow::anyhow!(
+            "no kubeconfig was given and there is no home directory to find one in"
+        )),
+    }
+}
+
+/// Apply the request timeout, User-Agent and extra headers of the Kubelet's API client
+/// configuration to a Kubernetes client configuration.
+///
+/// Providers that make their own requests should configure their client with this too,
+/// so their traffic is identified and classified the same way as the Kubelet's.
+pub fn configure(kubeconfig: &mut kube::Config, config: &ApiClientConfig) -> anyhow::Result<()> {
+    kubeconfig.timeout = config.request_timeout;
+    let user_agent = HeaderValue::from_str(&config.user_agent)
+        .map_err(|_| anyhow::anyhow!("invalid user agent {:?}", config.user_agent))?;
+    kubeconfig.headers.insert(USER_AGENT, user_agent);
+    for (name, value) in &config.headers {
+        let invalid = || anyhow::anyhow!("invalid header {}={:?}", name, value);
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+        let value = HeaderValue::from_str(value).map_err(|_| invalid())?;
+        kubeconfig.headers.insert(name, value);
+    }
+    Ok(())
+}
+
+/// What a client made by a [`ClientFactory`] is used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClientPurpose {
+    /// Requests that finish within the configured request timeout
+    Requests,
+    /// Watches of pods, secrets and config maps, which stay open for minutes and so
+    /// have a longer timeout
+    Watches,
+}
+
+/// Makes the Kubernetes clients the Kubelet uses, see [`Kubelet::with_client_factory`].
+///
+/// The kube client sends its requests itself, so it can't be wrapped directly.
+/// Instead, a factory that logs, caches or measures the Kubelet's requests changes the
+/// configuration before making the client, for example pointing its `cluster_url` at
+/// an in-process proxy that does so before forwarding the requests to the API server.
+/// Closures that take the purpose and configuration are factories too.
+///
+/// [`Kubelet::with_client_factory`]: crate::Kubelet::with_client_factory
+pub trait ClientFactory: Send + Sync {
+    /// Make a client for the given purpose from the Kubelet's configuration, which
+    /// already has its timeout set for the purpose
+    fn client(&self, purpose: ClientPurpose, config: kube::Config) -> anyhow::Result<kube::Client>;
+}
+
+impl<F> ClientFactory for F
+where
+    F: Fn(ClientPurpose, kube::Config) -> anyhow::Result<kube::Client> + Send + Sync,
+{
+    fn client(&self, purpose: ClientPurpose, config: kube::Config) -> anyhow::Result<kube::Client> {
+        self(purpose, config)
+    }
+}
+
+/// The factory used unless the Kubelet is given another one, which makes clients just
+/// as they are configured
+pub(crate) fn default_client(
+    _purpose: ClientPurpose,
+    config: kube::Config,
+) -> anyhow::Result<kube::Client> {
+    Ok(kube::Client::try_from(config)?)
+}
+
+/// Whether the Kubelet runs in a pod, given the host and port of the `kubernetes`
+/// service from the environment
+fn running_in_cluster(service: (Option<OsString>, Option<OsString>), token: &Path) -> bool {
+    service.0.is_some() && service.1.is_some() && token.exists()
+}
+
+/// Make the client configuration of the service account whose files are in `dir`, for
+/// the API server's service at the given host and port
+fn from_service_account(host: &str, port: &str, dir: &Path) -> anyhow::Result<kube::Config> {
+    let read = |name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .map_err(|e| anyhow::anyhow!("unable to read the service account {}: {}", name, e))
+    };
+    let mut config = kube::Config::new(service_url(host, port)?);
+    config.default_ns = read("namespace")?.trim().to_owned();
+    config.root_cert = Some(reqwest::Certificate::from_pem(read("ca.crt")?.as_bytes())?);
+    let token = read("token")?;
+    let authorization = HeaderValue::from_str(&format!("Bearer {}", token.trim()))
+        .map_err(|_| anyhow::anyhow!("the service account token is not a valid header"))?;
+    config.headers.insert(AUTHORIZATION, authorization);
+    Ok(config)
+}
+
+/// The URL of the API server's service. In IPv6-only clusters its host is an IPv6
+/// address, which has to be put in brackets (the kube client's in-cluster configuration
+/// doesn't, so it can't be used there)
+fn service_url(host: &str, port: &str) -> anyhow::Result<reqwest::Url> {
+    let host = match host.parse() {
+        Ok(IpAddr::V6(_)) => format!("[{}]", host),
+        _ => host.to_owned(),
+    };
+    reqwest::Url::parse(&format!("https://{}:{}", host, port))
+        .map_err(|e| anyhow::anyhow!("invalid API server address {}:{}: {}", host, port, e))
+}
+
+/// Returns the kubeconfig the kube client reads, from `KUBECONFIG` or the home directory
+fn kube_kubeconfig() -> Option<PathBuf> {
+    std::env::var_os("KUBECONFIG")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".kube").join("config")))
+}
+
+/// Read and merge the kubeconfig files in a list of paths
+fn read(paths: &OsStr) -> anyhow::Result<Kubeconfig> {
+    let mut merged: Option<Kubeconfig> = None;
+    for path in std::env::split_paths(paths).filter(|p| !p.as_os_str().is_empty()) {
+        let mut kubeconfig = Kubeconfig::read_from(&path)
+            .map_err(|e| anyhow::anyhow!("unable to read kubeconfig {}: {}", path.display(), e))?;
+        resolve_paths(
+            &mut kubeconfig,
+            path.parent().unwrap_or_else(|| Path::new("")),
+        );
+        merged = Some(match merged {
+            None => kubeconfig,
+            Some(mut merged) => {
+                if merged.current_context.is_empty() {
+                    merged.current_context = kubeconfig.current_context;
+                }
+                for cluster in kubeconfig.clusters {
+                    if !merged.clusters.iter().any(|c| c.name == cluster.name) {
+                        merged.clusters.push(cluster);
+                    }
+                }
+                for user in kubeconfig.auth_infos {
+                    if !merged.auth_infos.iter().any(|u| u.name == user.name) {
+                        merged.auth_infos.push(user);
+                    }
+                }
+                for context in kubeconfig.contexts {
+                    if !merged.contexts.iter().any(|c| c.name == context.name) {
+                        merged.contexts.push(context);
+                    }
+                }
+                merged
+            }
+        });
+    }
+    merged.ok_or_else(|| anyhow::anyhow!("no kubeconfig was given"))
+}
+
+/// Make the relative file paths in a kubeconfig relative to the directory it is in
+fn resolve_paths(kubeconfig: &mut Kubeconfig, dir: &Path) {
+    let resolve = |path: &mut Option<String>| {
+        if let Some(p) = path {
+            if Path::new(p).is_relative() {
+                *p = dir.join(&p).to_string_lossy().into_owned();
+            }
+        }
+    };
+    for cluster in &mut kubeconfig.clusters {
+        resolve(&mut cluster.cluster.certificate_authority);
+    }
+    for user in &mut kubeconfig.auth_infos {
+        resolve(&mut user.auth_info.token_file);
+        resolve(&mut user.auth_info.client_certificate);
+        resolve(&mut user.auth_info.client_key);
+    }
+}
+
+fn current_context(kubeconfig: &Kubeconfig) -> anyhow::Result<&kube::config::Context> {
+    kubeconfig
+        .contexts
+        .iter()
+        .find(|c| c.name == kubeconfig.current_context)
+        .map(|c| &c.context)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "the current context {:?} is not in the kubeconfig",
+                kubeconfig.current_context
+            )
+        })
+}
+
+/// Returns the user of the current context, if it has credentials
+fn current_user(kubeconfig: &Kubeconfig) -> anyhow::Result<Option<&AuthInfo>> {
+    let context = current_context(kubeconfig)?;
+    Ok(kubeconfig
+        .auth_infos
+        .iter()
+        .find(|u| u.name == context.user)
+        .map(|u| &u.auth_info))
+}
+
+/// Whether the user's credentials can only be loaded by the kube client
+fn loaded_by_kube(user: Option<&AuthInfo>) -> bool {
+    user.map(|u| {
+        u.client_certificate.is_some()
+            || u.client_certificate_data.is_some()
+            || u.exec.is_some()
+            || u.auth_provider.is_some()
+    })
+    .unwrap_or(false)
+}
+
+/// Make the client configuration for the current context of a kubeconfig whose
+/// credentials, if any, are a token or a username and password
+fn from_kubeconfig(kubeconfig: &Kubeconfig) -> anyhow::Result<kube::Config> {
+    let context = current_context(kubeconfig)?;
+    let cluster = kubeconfig
+        .clusters
+        .iter()
+        .find(|c| c.name == context.cluster)
+        .map(|c| &c.cluster)
+        .ok_or_else(|| {
+            anyhow::anyhow!("the cluster {:?} is not in the kubeconfig", context.cluster)
+        })?;
+    let url = reqwest::Url::parse(&cluster.server)
+        .map_err(|e| anyhow::anyhow!("invalid server URL {:?}: {}", cluster.server, e))?;
+    let mut config = kube::Config::new(url);
+    if let Some(namespace) = &context.namespace {
+        config.default_ns = namespace.clone();
+    }
+    if let Some(ca) = certificate_authority(cluster)? {
+        config.root_cert = Some(reqwest::Certificate::from_pem(&ca)?);
+    }
+    config.accept_invalid_certs = cluster.insecure_skip_tls_verify.unwrap_or(false);
+    if let Some(user) = current_user(kubeconfig)? {
+        if let Some(authorization) = authorization(user)? {
+            config.headers.insert(AUTHORIZATION, authorization);
+        }
+    }
+    Ok(config)
+}
+
+fn certificate_authority(cluster: &Cluster) -> anyhow::Result<Option<Vec<u8>>> {
+    if let Some(data) = &cluster.certificate_authority_data {
+        return Ok(Some(base64::decode(data).map_err(|e| {
+            anyhow::anyhow!("invalid certificate-authority-data: {}", e)
+        })?));
+    }
+    match &cluster.certificate_authority {
+        Some(path) => Ok(Some(std::fs::read(path).map_err(|e| {
+            anyhow::anyhow!("unable to read certificate authority {}: {}", path, e)
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the `Authorization` header for a user's token or username and password
+fn authorization(user: &AuthInfo) -> anyhow::Result<Option<HeaderValue>> {
+    let value = match (&user.token, &user.token_file, &user.username) {
+        (Some(token), _, _) => format!("Bearer {}", token),
+        (None, Some(path), _) => {
+            let token = std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("unable to read token file {}: {}", path, e))?;
+            format!("Bearer {}", token.trim())
+        }
+        (None, None, Some(username)) => {
+            let password = user.password.as_deref().unwrap_or_default();
+            format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            )
+        }
+        (None, None, None) => return Ok(None),
+    };
+    let mut value = HeaderValue::from_str(&value)
+        .map_err(|_| anyhow::anyhow!("the kubeconfig credentials are not a valid header"))?;
+    value.set_sensitive(true);
+    Ok(Some(value))
+}
+
+/// Returns the credential plugin used by the current context, if any
+fn exec_plugin(kubeconfig: &Kubeconfig) -> Option<&ExecConfig> {
+    let context = kubeconfig
+        .contexts
+        .iter()
+        .find(|c| c.name == kubeconfig.current_context)?;
+    kubeconfig
+        .auth_infos
+        .iter()
+        .find(|a| a.name == context.context.user)?
+        .auth_info
+        .exec
+        .as_ref()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kubeconfig(server: &str, user: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Config",
+            "clusters": [{"name": "test", "cluster": {"server": server}}],
+            "users": [
+                {"name": "static", "user": {"token": "abc"}},
+                {"name": "test", "user": user},
+            ],
+            "contexts": [{"name": "test", "context": {"cluster": "test", "user": "test"}}],
+            "current-context": "test",
+        })
+    }
+
+    #[tokio::test]
+    async fn test_configure() {
+        use crate::testing::MockApiServer;
+        use k8s_openapi::api::core::v1::Pod;
+
+        let server = MockApiServer::start().await.unwrap();
+        let config = ApiClientConfig {
+            user_agent: "krustlet/1.2.3 (test)".to_owned(),
+            headers: vec![("Impersonate-Group".to_owned(), "fleet".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let mut kubeconfig = kube::Config::new(server.url());
+        configure(&mut kubeconfig, &config).unwrap();
+
+        let pods: kube::Api<Pod> = kube::Api::namespaced(kube::Client::new(kubeconfig), "default");
+        pods.get("missing").await.unwrap_err();
+        let request = server
+            .requests_to(
+                hyper::Method::GET,
+                "/api/v1/namespaces/default/pods/missing",
+            )
+            .pop()
+            .unwrap();
+        assert_eq!("krustlet/1.2.3 (test)", request.headers["user-agent"]);
+        assert_eq!("fleet", request.headers["impersonate-group"]);
+
+        let invalid = ApiClientConfig {
+            headers: vec![("Bad Header".to_owned(), "x".to_owned())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert!(configure(&mut kube::Config::new(server.url()), &invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_factory() {
+        use crate::testing::MockApiServer;
+        use k8s_openapi::api::core::v1::Pod;
+        use std::sync::{Arc, Mutex};
+
+        // A factory that sends the requests to a proxy, recording what it made clients for
+        let proxy = MockApiServer::start().await.unwrap();
+        let proxy_url = proxy.url();
+        let purposes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = purposes.clone();
+        let factory = move |purpose, mut config: kube::Config| {
+            recorded.lock().unwrap().push(purpose);
+            config.cluster_url = proxy_url.clone();
+            default_client(purpose, config)
+        };
+        let config = kube::Config::new("https://api.example.com".parse().unwrap());
+        let factory: &dyn ClientFactory = &factory;
+        let client = factory.client(ClientPurpose::Watches, config).unwrap();
+        let pods: kube::Api<Pod> = kube::Api::namespaced(client, "default");
+        pods.list(&Default::default()).await.unwrap();
+        assert_eq!(
+            1,
+            proxy
+                .requests_to(hyper::Method::GET, "/api/v1/namespaces/default/pods")
+                .len()
+        );
+        assert_eq!(vec![ClientPurpose::Watches], *purposes.lock().unwrap());
+    }
+
+    #[test]
+    fn test_exec_plugin() {
+        let config = kubeconfig(
+            "https://example.com",
+            serde_json::json!({"exec": {"apiVersion": "client.authentication.k8s.io/v1beta1", "command": "aws-iam-authenticator"}}),
+        );
+        let config: Kubeconfig = serde_json::from_value(config).unwrap();
+        assert_eq!(
+            "aws-iam-authenticator",
+            exec_plugin(&config).unwrap().command
+        );
+
+        let config = kubeconfig("https://example.com", serde_json::json!({"token": "abc"}));
+        let config: Kubeconfig = serde_json::from_value(config).unwrap();
+        assert!(exec_plugin(&config).is_none());
+    }
+
+    #[test]
+    fn test_running_in_cluster() {
+        let token = std::env::temp_dir().join(format!("krustlet-token-{}", std::process::id()));
+        std::fs::write(&token, "abc").unwrap();
+        assert!(!running_in_cluster((None, Some("443".into())), &token));
+
+        let service = || (Some("10.0.0.1".into()), Some("443".into()));
+        assert!(running_in_cluster(service(), &token));
+        // A pod without a service account token can't be configured from the cluster
+        std::fs::remove_file(&token).unwrap();
+        assert!(!running_in_cluster(service(), &token));
+    }
+
+    #[test]
+    fn test_service_url() {
+        let url = |host, port| service_url(host, port).unwrap().to_string();
+        assert_eq!("https://10.0.0.1:6443/", url("10.0.0.1", "6443"));
+        assert_eq!("https://[fd00::1]:6443/", url("fd00::1", "6443"));
+        assert_eq!("https://kubernetes/", url("kubernetes", "443"));
+        assert!(service_url("10.0.0.1", "https").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kubeconfig_lists() {
+        use crate::testing::MockApiServer;
+        use k8s_openapi::api::core::v1::Pod;
+
+        let server = MockApiServer::start().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("krustlet-kubeconfigs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // The first file picks the context and defines the cluster, the second defines
+        // the user and a cluster of the same name that is ignored
+        let first = serde_json::json!({
+            "clusters": [{"name": "test", "cluster": {"server": server.url().as_str()}}],
+            "users": [],
+            "contexts": [{"name": "test", "context": {"cluster": "test", "user": "test", "namespace": "apps"}}],
+            "current-context": "test",
+        });
+        let second = kubeconfig(
+            "https://ignored.example.com",
+            serde_json::json!({"tokenFile": "token"}),
+        );
+        std::fs::write(dir.join("first"), serde_json::to_vec(&first).unwrap()).unwrap();
+        std::fs::write(dir.join("second"), serde_json::to_vec(&second).unwrap()).unwrap();
+        // Relative paths are relative to the file that has them
+        std::fs::write(dir.join("token"), "abc\n").unwrap();
+        let paths = std::env::join_paths(vec![dir.join("first"), dir.join("second")]).unwrap();
+
+        let config = load(Some(Path::new(&paths))).await.unwrap();
+        assert_eq!("apps", config.default_ns);
+        let pods: kube::Api<Pod> = kube::Api::namespaced(kube::Client::new(config), "apps");
+        pods.get("missing").await.unwrap_err();
+        let request = server
+            .requests_to(hyper::Method::GET, "/api/v1/namespaces/apps/pods/missing")
+            .pop()
+            .unwrap();
+        assert_eq!("Bearer abc", request.headers["authorization"]);
+
+        // Credentials only the kube client can load are refused outside KUBECONFIG
+        let exec = kubeconfig(
+            server.url().as_str(),
+            serde_json::json!({"exec": {"apiVersion": "client.authentication.k8s.io/v1beta1", "command": "plugin"}}),
+        );
+        std::fs::write(dir.join("exec"), serde_json::to_vec(&exec).unwrap()).unwrap();
+        if kube_kubeconfig() != Some(dir.join("exec")) {
+            assert!(load(Some(&dir.join("exec"))).await.is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_basic_authorization() {
+        let user: AuthInfo =
+            serde_json::from_value(serde_json::json!({"username": "admin", "password": "secret"}))
+                .unwrap();
+        assert_eq!(
+            "Basic YWRtaW46c2VjcmV0",
+            authorization(&user).unwrap().unwrap()
+        );
+        let user: AuthInfo = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert!(authorization(&user).unwrap().is_none());
+    }
+
+    /// Set in the child process that runs a test with `KUBECONFIG` set, so the variable
+    /// never changes for the other tests
+    const CHILD: &str = "KRUSTLET_KUBECONFIG_TEST";
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_exec_plugin_tokens_are_refreshed() {
+        use crate::testing::MockApiServer;
+        use k8s_openapi::api::core::v1::Node;
+        use std::os::unix::fs::PermissionsExt;
+
+        // The kube client only runs the plugins of the kubeconfig in KUBECONFIG
+        if std::env::var_os(CHILD).is_none() {
+            let dir =
+                std::env::temp_dir().join(format!("krustlet-kubeconfig-{}", std::process::id()));
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .arg("kubeconfig::test::test_exec_plugin_tokens_are_refreshed")
+                .arg("--exact")
+                .env(CHILD, "1")
+                .env("KUBECONFIG", dir.join("config"))
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{}", stdout);
+            assert!(stdout.contains(" 1 passed"), "{}", stdout);
+            return;
+        }
+
+        let server = MockApiServer::start().await.unwrap();
+        let path = PathBuf::from(std::env::var_os("KUBECONFIG").unwrap());
+        let dir = path.parent().unwrap().to_owned();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // A plugin that hands out a new token on every call, each of which expires
+        // immediately
+        let plugin = dir.join("plugin.sh");
+        let count = dir.join("count");
+        std::fs::write(
+            &plugin,
+            format!(
+                r#"#!/bin/sh
+echo x >> {count}
+n=$(wc -l < {count} | tr -d ' ')
+cat <<EOF
+{{"apiVersion": "client.authentication.k8s.io/v1beta1", "kind": "ExecCredential", "status": {{"token": "token-$n", "expirationTimestamp": "$(date -u +%Y-%m-%dT%H:%M:%SZ)"}}}}
+EOF
+"#,
+                count = count.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = kubeconfig(
+            server.url().as_str(),
+            serde_json::json!({"exec": {"apiVersion": "client.authentication.k8s.io/v1beta1", "command": plugin}}),
+        );
+        std::fs::write(&path, serde_json::to_vec(&config).unwrap()).unwrap();
+
+        let client = kube::Client::new(load(Some(&path)).await.unwrap());
+        let nodes: kube::Api<Node> = kube::Api::all(client);
+        for _ in 0..2 {
+            // The node does not exist, but the request still has to be authenticated
+            let _ = nodes.get("missing").await;
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let tokens: Vec<_> = server
+            .requests()
+            .into_iter()
+            .map(|r| r.headers["authorization"].to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(2, tokens.len());
+        assert!(tokens.iter().all(|t| t.starts_with("Bearer token-")));
+        assert_ne!(tokens[0], tokens[1]);
+    }
+}
@@ -1,23 +1,50 @@
 ///! This library contains code for running a kubelet. Use this to create a new
 ///! Kubelet with a specific handler (called a `Provider`)
+use crate::admin::{self, Admin};
+use crate::admission::Admission;
+use crate::audit::AuditLog;
+use crate::cancellation::{CancellationToken, Supervisor};
+use crate::concurrency::Limits;
 use crate::config::Config;
-use crate::node::{create_node, update_node};
+use crate::events::EventRecorder;
+use crate::failures::FailureReporter;
+use crate::kubeconfig::{ClientFactory, ClientPurpose};
+use crate::leader::LeaderElector;
+use crate::lifecycle::Lifecycle;
+use crate::log_filter::LogFilter;
+use crate::node::{create_node, record_registration, update_node, Readiness, Refusals};
+use crate::object_manager::ObjectManager;
 use crate::queue::PodQueue;
-use crate::server::start_webserver;
-use crate::status::{update_pod_status, Phase};
+use crate::rate_limit::RateLimits;
+use crate::registry::PodRegistry;
+use crate::server::{run_isolated, start_webserver, DebugFlags};
+use crate::shutdown;
+use crate::stats::ResourceAccounting;
+use crate::status::annotations::AnnotationWriter;
 use crate::Provider;
 
 use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::core::v1::Pod as KubePod;
-use kube::{
-    api::{ListParams, Meta},
-    runtime::Informer,
-    Api,
-};
-use log::{debug, error, warn};
-use tokio::sync::mpsc;
+use kube::api::WatchEvent;
+use kube::{runtime::Informer, Api};
+use log::{debug, error, info, warn};
 
 use std::sync::Arc;
+use std::time::Duration;
+
+/// The timeout for watch requests, which the kube client asks the API server to end
+/// after 290 seconds
+const WATCH_TIMEOUT: Duration = Duration::from_secs(295);
+
+/// How long to wait before trying to watch pods again after the watch could not be
+/// started
+const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// The status code of watch errors for versions too old to watch from
+const GONE: u16 = 410;
+
+/// How long the pod workers are given to finish the events they are handling when the
+/// Kubelet stops
+const WORKER_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// A Kubelet server backed by a given `Provider`.
 ///
@@ -35,6 +62,10 @@ pub struct Kubelet<P> {
     provider: Arc<P>,
     kube_config: kube::Config,
     config: Config,
+    registry: PodRegistry,
+    client_factory: Arc<dyn ClientFactory>,
+    log_filter: LogFilter,
+    lifecycle: Lifecycle,
 }
 
 impl<T: 'static + Provider + Sync + Send> Kubelet<T> {
@@ -45,108 +76,585 @@ impl<T: 'static + Provider + Sync + Send> Kubelet<T> {
             provider: Arc::new(provider),
             kube_config,
             config,
+            registry: PodRegistry::default(),
+            client_factory: Arc::new(crate::kubeconfig::default_client),
+            log_filter: LogFilter::default(),
+            lifecycle: Lifecycle::default(),
         }
     }
 
+    /// Let the Kubelet's server change the directives of the given filter, which
+    /// should be the one returned when the logger was installed. See
+    /// [`log_filter`](crate::log_filter)
+    pub fn with_log_filter(mut self, filter: LogFilter) -> Self {
+        self.log_filter = filter;
+        self
+    }
+
+    /// Make the Kubelet's Kubernetes clients with the given factory, so that their
+    /// requests can be logged, cached or measured. See
+    /// [`ClientFactory`](crate::kubeconfig::ClientFactory)
+    pub fn with_client_factory<F: ClientFactory + 'static>(mut self, factory: F) -> Self {
+        self.client_factory = Arc::new(factory);
+        self
+    }
+
+    /// Make a client for the given purpose with the Kubelet's client factory, configured
+    /// as the [`ApiClientConfig`](crate::config::ApiClientConfig) says
+    fn client(&self, purpose: ClientPurpose) -> anyhow::Result<kube::Client> {
+        let mut kube_config = self.kube_config.clone();
+        crate::kubeconfig::configure(&mut kube_config, &self.config.api_client)?;
+        // Watches stay open for minutes, so they can't use the usual request timeout
+        if purpose == ClientPurpose::Watches {
+            kube_config.timeout = WATCH_TIMEOUT;
+        }
+        self.client_factory.client(purpose, kube_config)
+    }
+
+    /// Returns the registry of the pods this Kubelet is handling, which can be used to
+    /// inspect their state while the Kubelet runs
+    pub fn pods(&self) -> PodRegistry {
+        self.registry.clone()
+    }
+
+    /// Subscribe to what happens to the node and its pods from now on. See
+    /// [`lifecycle`](crate::lifecycle)
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<crate::lifecycle::Event> {
+        self.lifecycle.subscribe()
+    }
+
     /// Begin answering requests for the Kubelet.
     ///
     /// This will listen on the given address, and will also begin watching for Pod
-    /// events, which it will handle.
+    /// events, which it will handle. An invalid configuration is reported before
+    /// anything starts, see [`Config::validate`](crate::config::Config::validate).
     pub async fn start(&self) -> anyhow::Result<()> {
-        let client = kube::Client::new(self.kube_config.clone());
+        self.start_with_shutdown(futures::future::pending()).await
+    }
+
+    /// Begin answering requests for the Kubelet until the given shutdown signal completes.
+    ///
+    /// This behaves like [`Kubelet::start`], but returns `Ok(())` once `shutdown` completes
+    /// and the Kubelet's subsystems have stopped in order, which allows service managers to
+    /// stop the Kubelet gracefully. If a subsystem fails, the others are stopped the same
+    /// way and its error is returned.
+    ///
+    /// If leader election is configured, the Kubelet waits until it is elected before
+    /// doing anything and returns an error if it stops being the leader, so it can be
+    /// restarted as a follower.
+    ///
+    /// Requests to the Kubernetes API are rate limited, time out and carry the User-Agent
+    /// and headers set in the configuration's
+    /// [`ApiClientConfig`](crate::config::ApiClientConfig). Node heartbeats and leader
+    /// lease renewals are limited apart from other requests, so they are never held up
+    /// behind a burst of pod updates.
+    pub async fn start_with_shutdown<F>(&self, shutdown: F) -> anyhow::Result<()>
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        self.config.validate()?;
+        let limits = RateLimits::new(&self.config.api_client);
+        #[cfg(feature = "fault-injection")]
+        let limits = limits.with_faults(crate::faults::FaultInjector::new(&self.config.faults));
+        let audit = if self.config.audit_log {
+            let audit = AuditLog::open(&self.config.data_dir)?;
+            audit.kubelet_started(&self.config);
+            audit
+        } else {
+            AuditLog::default()
+        };
+        let client = self.client(ClientPurpose::Requests)?;
+        let elector = self.config.leader_election.as_ref().map(|election| {
+            // Kubelets started from the same image share a hostname, so the identity
+            // has a random suffix, like the leases of other Kubernetes components
+            let identity = format!("{}_{:08x}", self.config.hostname, rand::random::<u32>());
+            LeaderElector::new(client.clone(), &self.config.node_name, &identity, election)
+                .with_rate_limits(limits.for_heartbeats())
+        });
+        // The Kubelet is stopped through the token, so its subsystems stop in order
+        // rather than all being dropped at once
+        let stop = CancellationToken::new();
+        let result = {
+            let lead = self.lead(client, limits, audit, elector.as_ref(), stop.clone());
+            tokio::pin!(lead);
+            tokio::select! {
+                result = &mut lead => result,
+                _ = shutdown => {
+                    info!("Received shutdown signal, stopping Kubelet");
+                    stop.cancel();
+                    lead.await
+                }
+            }
+        };
+        // Let another replica take over straight away rather than waiting for our lease
+        // to expire
+        if let Some(elector) = elector {
+            elector.release().await;
+        }
+        result
+    }
+
+    async fn lead(
+        &self,
+        client: kube::Client,
+        limits: RateLimits,
+        audit: AuditLog,
+        elector: Option<&LeaderElector>,
+        stop: CancellationToken,
+    ) -> anyhow::Result<()> {
+        let elector = match elector {
+            Some(e) => e,
+            None => return self.run(client, limits, audit, stop).await,
+        };
+        if stop
+            .run_until_cancelled(elector.acquire())
+            .await
+            .transpose()?
+            .is_none()
+        {
+            return Ok(());
+        }
+        let run = self.run(client, limits, audit, stop.clone());
+        tokio::pin!(run);
+        tokio::select! {
+            result = &mut run => result,
+            e = elector.hold() => {
+                // Another replica may already be taking over, so stop as we would for
+                // a shutdown before handing the node over
+                stop.cancel();
+                if let Err(e) = run.await {
+                    warn!("Error while stopping after losing leadership: {:#}", e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Run the Kubelet until `stop` is cancelled or one of its subsystems fails.
+    ///
+    /// The subsystems are then stopped in order, each finishing before the next is
+    /// stopped:
+    ///
+    /// 1. The pod watch, so no more events are queued
+    /// 2. The pod queue, whose workers finish the events they are handling
+    /// 3. The failure queue and the annotation writer, which write what is still pending
+    /// 4. The webserver and the other background tasks
+    /// 5. The node updates, after a final update of the node
+    async fn run(
+        &self,
+        client: kube::Client,
+        limits: RateLimits,
+        audit: AuditLog,
+        stop: CancellationToken,
+    ) -> anyhow::Result<()> {
+        // The provider can't run its pods without the resources it depends on
+        crate::crds::register(
+            &client,
+            &limits,
+            &self.provider.custom_resource_definitions()?,
+            !self.config.skip_crd_registration,
+            crate::crds::ESTABLISH_TIMEOUT,
+        )
+        .await?;
+
         // Create the node. If it already exists, "adopt" the node definition
-        create_node(&client, &self.config, T::ARCH).await;
+        let mut last_applied =
+            create_node(&client, &self.config, &limits, self.provider.as_ref()).await?;
+        // Events are correlated across everything the Kubelet records
+        let events = EventRecorder::default();
+        record_registration::<T>(&events, &client, &self.config, &limits, &last_applied).await;
+        #[cfg(unix)]
+        notify_systemd(crate::systemd::notify_ready());
+
+        let supervisor = Supervisor::new(stop.clone());
+        let informer_stop = CancellationToken::new();
+        let failures_stop = CancellationToken::new();
+        let tasks_stop = CancellationToken::new();
+        let node_stop = CancellationToken::new();
+        // Shared by the node updates, the pod watch and the shutdown watch, which report
+        // the node as not ready while the watch is disconnected or the node shuts down
+        let readiness = Readiness::default().with_lifecycle(self.lifecycle.clone());
 
-        // Get the node name for use in the update loop
-        let node_name = self.config.node_name.clone();
         // Start updating the node lease periodically
         let update_client = client.clone();
-        let node_updater = tokio::task::spawn(async move {
-            let sleep_interval = std::time::Duration::from_secs(10);
+        let update_config = self.config.clone();
+        let update_limits = limits.clone();
+        let update_provider = self.provider.clone();
+        let update_readiness = readiness.clone();
+        let update_stop = node_stop.clone();
+        let node_updater = supervisor.spawn("node updates", async move {
+            let sleep_interval = heartbeat_interval();
+            let mut refusals = Refusals::default();
             loop {
-                update_node(&update_client, &node_name).await;
-                tokio::time::delay_for(sleep_interval).await;
+                update_node(
+                    &update_client,
+                    &update_config,
+                    &update_limits,
+                    update_provider.as_ref(),
+                    &update_readiness,
+                    &mut last_applied,
+                    &mut refusals,
+                )
+                .await;
+                #[cfg(unix)]
+                notify_systemd(crate::systemd::notify_watchdog());
+                let delay = tokio::time::delay_for(sleep_interval);
+                if update_stop.run_until_cancelled(delay).await.is_none() {
+                    break;
+                }
             }
+            // Leave the node with the state the pods were left in
+            update_node(
+                &update_client,
+                &update_config,
+                &update_limits,
+                update_provider.as_ref(),
+                &update_readiness,
+                &mut last_applied,
+                &mut refusals,
+            )
+            .await;
+            Ok(())
         });
 
-        // TODO: How should we configure this value? We should eventually have a max pods setting
-        // just like a normal kubelet, so maybe that?
-        let (error_sender, mut error_receiver) = mpsc::channel::<(KubePod, anyhow::Error)>(200);
-        let client_clone = client.clone();
-        let error_handler = tokio::task::spawn(async move {
-            let client = client_clone;
-            while let Some((pod, err)) = error_receiver.recv().await {
-                let json_status = serde_json::json!(
-                    {
-                        "metadata": {
-                            "resourceVersion": "",
-                        },
-                        "status": {
-                            "phase": Phase::Failed,
-                            "message": format!("{}", err),
-                        }
-                    }
-                );
+        // Failures are patched onto pod statuses in the background so pod workers never
+        // wait on the API server
+        let (failures, failure_queue) = FailureReporter::new();
+        let failure_client = client.clone();
+        let failure_stop = failures_stop.clone();
+        let error_handler = supervisor.spawn("failure reporting", async move {
+            failure_queue.run(failure_client, failure_stop).await;
+            Ok(())
+        });
 
-                debug!(
-                    "Setting pod status for {} using {:?}",
-                    pod.name(),
-                    json_status
-                );
-                let pod_name = pod.name();
-                match update_pod_status(
-                    client.clone(),
-                    &pod.namespace().unwrap_or_default(),
-                    &pod_name,
-                    &json_status,
-                )
-                .await
-                {
-                    Ok(_) => (),
-                    Err(e) => error!(
-                        "Unable to patch status during pod failure for {}: {}",
-                        pod_name, e
-                    ),
-                }
-            }
+        // Annotations published by the provider are written in the background too
+        let (annotations, annotation_queue) = AnnotationWriter::new();
+        let annotation_client = client.clone();
+        let annotation_limits = limits.clone();
+        let annotation_stop = failures_stop.clone();
+        let annotation_writer = supervisor.spawn("annotation writes", async move {
+            annotation_queue
+                .run(annotation_client, annotation_limits, annotation_stop)
+                .await;
+            Ok(())
         });
 
+        let watch_client = self.client(ClientPurpose::Watches)?;
+
+        // Secrets and config maps used by pods are watched and served from a cache
+        let objects = ObjectManager::new(watch_client.clone(), limits.clone());
+
+        // The usage of the pods in the queue is collected for the Summary API and for
+        // evicting pods over their storage limits
+        let accounting = ResourceAccounting::new(self.config.data_dir.clone());
+
         // Create a queue that locks on events per pod
-        let mut queue = PodQueue::new(self.provider.clone(), error_sender);
+        let mut queue = PodQueue::new(
+            self.provider.clone(),
+            client.clone(),
+            failures,
+            objects,
+            accounting.clone(),
+            self.registry.clone(),
+            Admission::new(&self.config).with_platform(T::ARCH, T::OS),
+        )
+        .with_rate_limits(limits.clone())
+        .with_concurrency(Limits::new(&self.config.concurrency))
+        .with_events(events)
+        .with_audit(audit)
+        .with_lifecycle(self.lifecycle.clone())
+        .with_annotation_writer(annotations)
+        .with_cluster_domain(&self.config.cluster_domain);
 
-        let node_selector = format!("spec.nodeName={}", self.config.node_name);
-        let pod_informer = tokio::task::spawn(async move {
-            // Create our informer and start listening.
-            let params = ListParams {
-                field_selector: Some(node_selector),
-                ..Default::default()
-            };
-            let api = Api::<KubePod>::all(client);
-            let informer = Informer::new(api).params(params);
-            loop {
-                let mut stream = informer.poll().await.expect("informer poll failed").boxed();
-                while let Some(event) = stream.try_next().await.unwrap() {
+        // Pods resynced through the admin API are queued along with the watched events
+        let (resyncs, mut resynced) = tokio::sync::mpsc::channel(16);
+        // So are the stops of pods when the node shuts down, which the pod workers make
+        let (stops, mut stop_requests) = tokio::sync::mpsc::channel(1);
+        let admin = Admin::new(
+            client.clone(),
+            limits.clone(),
+            self.registry.clone(),
+            self.config.node_name.clone(),
+            resyncs,
+        );
+        let admin_socket = self.config.admin_socket.clone();
+        let admin_stop = tasks_stop.clone();
+        let admin_server = supervisor.spawn("admin API", async move {
+            // The pods keep running without the admin API, so this doesn't stop the Kubelet
+            if let Some(Err(e)) = admin_stop
+                .run_until_cancelled(admin::serve(admin_socket, admin))
+                .await
+            {
+                error!("Unable to serve the admin API: {:#}", e);
+            }
+            Ok(())
+        });
+
+        let params = self.config.pod_list_params();
+        let watch_limits = limits.clone();
+        let watch_readiness = readiness.clone();
+        let watch_stop = informer_stop.clone();
+        let pod_informer = supervisor.spawn("pod watch", async move {
+            let api = Api::<KubePod>::all(watch_client);
+            // The informer watches from where the last list left off
+            let mut informer: Option<Informer<KubePod>> = None;
+            'watch: while !watch_stop.is_cancelled() {
+                // The pods are listed when the Kubelet starts and whenever the watch
+                // falls too far behind to carry on. Pods are admitted in the order they
+                // are queued, so they are queued oldest first, and the pods already
+                // running keep their host ports and quotas rather than losing them to
+                // newer pods
+                let listed = match informer {
+                    Some(_) => Ok(()),
+                    None => match watch_limits.limited(api.list(&params)).await {
+                        Ok(list) => {
+                            let version = list.metadata.resource_version.unwrap_or_default();
+                            for pod in oldest_first(list.items) {
+                                if let Err(e) = queue.enqueue(WatchEvent::Added(pod)).await {
+                                    warn!("Error enqueuing listed pod: {}", e);
+                                }
+                            }
+                            informer = Some(
+                                Informer::new(api.clone())
+                                    .params(params.clone())
+                                    .set_version(version),
+                            );
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    },
+                };
+                // Each poll of the informer starts a watch, which is a request too
+                let polled = match (listed, &informer) {
+                    (Ok(()), Some(informer)) => watch_limits.limited(informer.poll()).await,
+                    (Err(e), _) => Err(e),
+                    (Ok(()), None) => unreachable!("the pods are listed before they are watched"),
+                };
+                let mut stream = match polled {
+                    Ok(stream) => stream.boxed(),
+                    Err(e) => {
+                        // The node is reported as not ready if this goes on for too long
+                        warn!("Unable to watch pods: {}", e);
+                        watch_readiness.set_informer_connected(false);
+                        let mut delay = tokio::time::delay_for(WATCH_RETRY_INTERVAL);
+                        loop {
+                            tokio::select! {
+                                _ = &mut delay => break,
+                                Some(request) = stop_requests.recv() => {
+                                    queue.stop_for_shutdown(request)
+                                }
+                                _ = watch_stop.cancelled() => break,
+                            }
+                        }
+                        continue;
+                    }
+                };
+                watch_readiness.set_informer_connected(true);
+                let mut relist = false;
+                loop {
+                    let event = tokio::select! {
+                        event = stream.try_next() => match event {
+                            Ok(Some(event)) => event,
+                            Ok(None) => break,
+                            Err(e) => {
+                                warn!("Pod watch failed: {}", e);
+                                watch_readiness.set_informer_connected(false);
+                                break;
+                            }
+                        },
+                        Some(event) = resynced.recv() => event,
+                        Some(request) = stop_requests.recv() => {
+                            queue.stop_for_shutdown(request);
+                            continue;
+                        }
+                        _ = watch_stop.cancelled() => break 'watch,
+                    };
+                    // The version watched from is too old, so the pods are listed again
+                    if let WatchEvent::Error(e) = &event {
+                        if e.code == GONE {
+                            relist = true;
+                            break;
+                        }
+                    }
                     debug!("Handling Kubernetes pod event: {:?}", event);
                     match queue.enqueue(event).await {
                         Ok(()) => debug!("Enqueued event for processing"),
                         Err(e) => warn!("Error enqueuing pod event: {}", e),
                     };
                 }
+                drop(stream);
+                if relist {
+                    informer = None;
+                }
             }
+            // No more events are queued, so the workers can finish what they are doing
+            queue.drain(WORKER_DRAIN_TIMEOUT).await;
+            Ok(())
         });
 
+        // Stop the pods gracefully if the host shuts down
+        let shutdown_watcher = supervisor.spawn(
+            "shutdown watch",
+            until_cancelled(
+                tasks_stop.clone(),
+                shutdown::watch(
+                    client.clone(),
+                    self.config.clone(),
+                    limits.clone(),
+                    self.provider.clone(),
+                    readiness,
+                    stops,
+                ),
+            ),
+        );
+
+        // The data of pods that no longer exist is removed in the background
+        let pod_data_collector = supervisor.spawn(
+            "pod data collection",
+            until_cancelled(
+                tasks_stop.clone(),
+                crate::pod_dirs::collect_garbage(
+                    client.clone(),
+                    limits.clone(),
+                    self.config.data_dir.clone(),
+                    self.config.node_name.clone(),
+                    self.registry.clone(),
+                ),
+            ),
+        );
+
+        // Pods that fill the node's disk are evicted
+        let storage_eviction = supervisor.spawn(
+            "storage eviction",
+            until_cancelled(
+                tasks_stop.clone(),
+                crate::eviction::run(client.clone(), accounting.clone()),
+            ),
+        );
+
+        // Running pods are reported as not ready while the provider is unavailable
+        let availability = supervisor.spawn(
+            "provider availability",
+            until_cancelled(
+                tasks_stop.clone(),
+                crate::availability::run(
+                    client.clone(),
+                    self.provider.clone(),
+                    self.registry.clone(),
+                ),
+            ),
+        );
+
+        // Modules of known workloads are fetched before their pods arrive
+        let prefetcher = supervisor.spawn(
+            "prefetching",
+            until_cancelled(
+                tasks_stop.clone(),
+                crate::prefetch::run(
+                    client.clone(),
+                    limits.clone(),
+                    self.config.node_name.clone(),
+                    self.config.prefetch.clone(),
+                    self.provider.clone(),
+                ),
+            ),
+        );
+
         // Start the webserver
-        let webserver = start_webserver(self.provider.clone(), &self.config.server_config);
+        let server_provider = self.provider.clone();
+        let server_config = self.config.server_config.clone();
+        let server_registry = self.registry.clone();
+        let server_node_name = self.config.node_name.clone();
+        let server_debug = DebugFlags::default().with_log_filter(self.log_filter.clone());
+        #[cfg(feature = "fault-injection")]
+        let server_debug = server_debug.with_faults(limits.faults().clone());
+        let server_stop = tasks_stop.clone();
+        let webserver = supervisor.spawn("webserver", async move {
+            let worker_threads = server_config.worker_threads;
+            let webserver = async move {
+                let webserver = start_webserver(
+                    server_provider,
+                    &server_config,
+                    accounting,
+                    server_registry,
+                    server_node_name,
+                    server_debug,
+                );
+                server_stop
+                    .run_until_cancelled(webserver)
+                    .await
+                    .unwrap_or(Ok(()))
+            };
+            match worker_threads {
+                Some(threads) => run_isolated(threads, webserver).await,
+                None => webserver.await,
+            }
+        });
 
-        let threads = async {
-            futures::try_join!(node_updater, pod_informer, error_handler)?;
-            Ok(())
-        };
+        stop.cancelled().await;
+        info!("Stopping the Kubelet's subsystems");
+        informer_stop.cancel();
+        let _ = pod_informer.await;
+        failures_stop.cancel();
+        let _ = futures::join!(error_handler, annotation_writer);
+        tasks_stop.cancel();
+        let _ = futures::join!(
+            webserver,
+            admin_server,
+            shutdown_watcher,
+            pod_data_collector,
+            storage_eviction,
+            availability,
+            prefetcher
+        );
+        node_stop.cancel();
+        let _ = node_updater.await;
+
+        match supervisor.take_failure() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Run a background task until it finishes or the token is cancelled
+async fn until_cancelled<F: std::future::Future<Output = ()>>(
+    stop: CancellationToken,
+    task: F,
+) -> anyhow::Result<()> {
+    stop.run_until_cancelled(task).await;
+    Ok(())
+}
+
+/// Sort listed pods by when they were created, oldest first. Pods created at the same
+/// time keep the order they were listed in
+fn oldest_first(mut pods: Vec<KubePod>) -> Vec<KubePod> {
+    pods.sort_by_key(|pod| {
+        pod.metadata
+            .as_ref()
+            .and_then(|m| m.creation_timestamp.as_ref())
+            .map(|created| created.0)
+    });
+    pods
+}
 
-        // Return an error as soon as either the webserver or the threads error
-        futures::try_join!(webserver, threads)?;
+/// Returns how often to update the node lease. This is shortened if needed so the systemd
+/// watchdog, which is pinged after each update, is pinged at least twice per interval
+fn heartbeat_interval() -> Duration {
+    let interval = Duration::from_secs(10);
+    #[cfg(unix)]
+    {
+        if let Some(watchdog) = crate::systemd::watchdog_interval() {
+            return interval.min(watchdog / 2);
+        }
+    }
+    interval
+}
 
-        Ok(())
+#[cfg(unix)]
+fn notify_systemd(result: std::io::Result<bool>) {
+    if let Err(e) = result {
+        warn!("Unable to notify systemd: {}", e);
     }
 }
 
@@ -158,6 +666,10 @@ impl<P> Clone for Kubelet<P> {
             provider: self.provider.clone(),
             kube_config: self.kube_config.clone(),
             config: self.config.clone(),
+            registry: self.registry.clone(),
+            client_factory: self.client_factory.clone(),
+            log_filter: self.log_filter.clone(),
+            lifecycle: self.lifecycle.clone(),
         }
     }
 }
@@ -165,11 +677,15 @@ impl<P> Clone for Kubelet<P> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::Pod;
+    use crate::pod::Pod;
+    use crate::provider::ResolutionContext;
+    use crate::testing::MockApiServer;
+    use chrono::TimeZone;
     use k8s_openapi::api::core::v1::{
         Container, EnvVar, EnvVarSource, ObjectFieldSelector, PodSpec, PodStatus,
     };
-    use kube::api::ObjectMeta;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use kube::api::{Meta, ObjectMeta};
     use std::collections::BTreeMap;
 
     fn mock_client() -> kube::Client {
@@ -305,7 +821,10 @@ mod test {
                 ..Default::default()
             }),
         });
-        let env = MockProvider::env_vars(&container, &pod, &mock_client()).await;
+        let context = ResolutionContext::new(mock_client(), &pod);
+        let env = MockProvider::env_vars(&container, &pod, &context)
+            .await
+            .unwrap();
 
         assert_eq!(
             "value",
@@ -330,4 +849,219 @@ mod test {
         assert_eq!("10.21.77.2", env.get("POD_IP").expect("pod_ip").as_str());
         assert_eq!("10.21.77.1", env.get("HOST_IP").expect("host_ip").as_str());
     }
+
+    #[tokio::test]
+    async fn test_env_vars_from_secret_and_config_map() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/secrets",
+            serde_json::json!({
+                "metadata": {"name": "creds"},
+                // "hunter2" base64 encoded
+                "data": {"password": "aHVudGVyMg=="}
+            }),
+        );
+        server.insert(
+            "/api/v1/namespaces/default/configmaps",
+            serde_json::json!({"metadata": {"name": "settings"}, "data": {"mode": "fast"}}),
+        );
+        let container: Container = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "env": [
+                {
+                    "name": "PASSWORD",
+                    "valueFrom": {"secretKeyRef": {"name": "creds", "key": "password"}}
+                },
+                {
+                    "name": "MODE",
+                    "valueFrom": {"configMapKeyRef": {"name": "settings", "key": "mode"}}
+                },
+                {
+                    "name": "MISSING",
+                    "valueFrom": {"secretKeyRef": {"name": "nope", "key": "password", "optional": true}}
+                },
+                {
+                    "name": "MISSING_KEY",
+                    "valueFrom": {"configMapKeyRef": {"name": "settings", "key": "nope", "optional": true}}
+                },
+            ]
+        }))
+        .unwrap();
+        let pod = Pod::new(crate::testing::fake_pod("test", "default"));
+
+        let context = ResolutionContext::new(server.client(), &pod);
+        let env = MockProvider::env_vars(&container, &pod, &context)
+            .await
+            .unwrap();
+
+        assert_eq!("hunter2", env.get("PASSWORD").unwrap());
+        assert_eq!("fast", env.get("MODE").unwrap());
+        // Optional variables are left out when what they refer to is missing
+        assert!(!env.contains_key("MISSING"));
+        assert!(!env.contains_key("MISSING_KEY"));
+    }
+
+    #[tokio::test]
+    async fn test_env_vars_require_secret_and_config_map() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/configmaps",
+            serde_json::json!({"metadata": {"name": "settings"}, "data": {"mode": "fast"}}),
+        );
+        let pod = Pod::new(crate::testing::fake_pod("test", "default"));
+        let cases = vec![
+            (
+                serde_json::json!({"secretKeyRef": {"name": "nope", "key": "password"}}),
+                "secret \"nope\" not found",
+            ),
+            (
+                serde_json::json!({"configMapKeyRef": {"name": "settings", "key": "nope", "optional": false}}),
+                "couldn't find key nope in ConfigMap default/settings",
+            ),
+        ];
+        for (source, expected) in cases {
+            let container: Container = serde_json::from_value(serde_json::json!({
+                "name": "test",
+                "env": [{"name": "VALUE", "valueFrom": source}]
+            }))
+            .unwrap();
+            let context = ResolutionContext::new(server.client(), &pod);
+            let error = MockProvider::env_vars(&container, &pod, &context)
+                .await
+                .unwrap_err();
+            match crate::error::PodSyncError::from(error) {
+                crate::error::PodSyncError::ContainerConfig(e) => {
+                    assert_eq!(expected, e.to_string())
+                }
+                other => panic!("expected a container config error, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_env_vars_share_lookups_across_containers() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/secrets",
+            serde_json::json!({"metadata": {"name": "creds"}, "data": {"password": "aHVudGVyMg=="}}),
+        );
+        let container: Container = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "env": [
+                {
+                    "name": "PASSWORD",
+                    "valueFrom": {"secretKeyRef": {"name": "creds", "key": "password"}}
+                },
+                {
+                    "name": "OTHER",
+                    "valueFrom": {"secretKeyRef": {"name": "creds", "key": "other", "optional": true}}
+                },
+            ]
+        }))
+        .unwrap();
+        let pod = Pod::new(crate::testing::fake_pod("test", "default"));
+
+        let context = ResolutionContext::new(server.client(), &pod);
+        for _ in 0..2 {
+            let env = MockProvider::env_vars(&container, &pod, &context)
+                .await
+                .unwrap();
+            assert_eq!("hunter2", env.get("PASSWORD").unwrap());
+            assert!(!env.contains_key("OTHER"));
+        }
+        assert_eq!(
+            1,
+            server
+                .requests_to(
+                    hyper::Method::GET,
+                    "/api/v1/namespaces/default/secrets/creds"
+                )
+                .len()
+        );
+    }
+
+    #[test]
+    fn test_oldest_first() {
+        let created = |name: &str, secs: i64| {
+            let mut pod = crate::testing::fake_pod(name, "default");
+            pod.metadata.as_mut().unwrap().creation_timestamp =
+                Some(Time(chrono::Utc.timestamp(secs, 0)));
+            pod
+        };
+        let pods = oldest_first(vec![created("c", 30), created("a", 10), created("b", 20)]);
+        let names: Vec<String> = pods.iter().map(|p| p.name()).collect();
+        assert_eq!(vec!["a", "b", "c"], names);
+    }
+
+    #[tokio::test]
+    async fn test_pod_informer_against_mock_server() {
+        let server = MockApiServer::start().await.unwrap();
+        let params = kube::api::ListParams {
+            field_selector: Some("spec.nodeName=krustlet".to_owned()),
+            label_selector: Some("runtime=wasm".to_owned()),
+            ..Default::default()
+        };
+        let informer = Informer::new(Api::<KubePod>::all(server.client())).params(params);
+        let mut stream = informer.poll().await.unwrap().boxed();
+
+        let mut other = crate::testing::fake_pod("elsewhere", "default");
+        other.spec.as_mut().unwrap().node_name = Some("other".to_owned());
+        server.insert("/api/v1/namespaces/default/pods", other);
+        let mut unlabeled = crate::testing::fake_pod("unlabeled", "default");
+        unlabeled.spec.as_mut().unwrap().node_name = Some("krustlet".to_owned());
+        server.insert("/api/v1/namespaces/default/pods", unlabeled);
+        let mut mine = crate::testing::fake_pod("mine", "default");
+        mine.spec.as_mut().unwrap().node_name = Some("krustlet".to_owned());
+        mine.metadata
+            .as_mut()
+            .unwrap()
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert("runtime".to_owned(), "wasm".to_owned());
+        server.insert("/api/v1/namespaces/default/pods", mine);
+
+        match stream.try_next().await.unwrap() {
+            Some(WatchEvent::Added(pod)) => assert_eq!("mine", pod.name()),
+            e => panic!("unexpected event: {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clients_come_from_the_factory() {
+        use std::sync::Mutex;
+
+        // A factory that sends the requests to a proxy, recording what it made clients for
+        let proxy = MockApiServer::start().await.unwrap();
+        let proxy_url = proxy.url();
+        let made = Arc::new(Mutex::new(Vec::new()));
+        let recorded = made.clone();
+        let factory = move |purpose, mut config: kube::Config| {
+            recorded.lock().unwrap().push((purpose, config.timeout));
+            config.cluster_url = proxy_url.clone();
+            crate::kubeconfig::default_client(purpose, config)
+        };
+        let kube_config = kube::Config::new("https://api.example.com".parse().unwrap());
+        let config = crate::config::test::test_config();
+        let request_timeout = config.api_client.request_timeout;
+        let kubelet = Kubelet::new(MockProvider, kube_config, config).with_client_factory(factory);
+
+        for purpose in &[ClientPurpose::Requests, ClientPurpose::Watches] {
+            let pods: kube::Api<KubePod> =
+                kube::Api::namespaced(kubelet.client(*purpose).unwrap(), "default");
+            pods.list(&Default::default()).await.unwrap();
+        }
+        assert_eq!(
+            2,
+            proxy
+                .requests_to(hyper::Method::GET, "/api/v1/namespaces/default/pods")
+                .len()
+        );
+        assert_eq!(
+            vec![
+                (ClientPurpose::Requests, request_timeout),
+                (ClientPurpose::Watches, WATCH_TIMEOUT)
+            ],
+            *made.lock().unwrap()
+        );
+    }
 }
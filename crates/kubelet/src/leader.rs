@@ -0,0 +1,316 @@
+//! Leader election for running several Kubelets behind a single node
+//!
+//! Replicas race for a [`Lease`] named after the node. Only the replica holding the
+//! lease registers the node, handles pod events, and serves the Kubelet API; the
+//! others wait and take over as soon as the lease expires. This follows the same
+//! protocol as client-go's leader election, using the lease's resource version so
+//! that only one replica can win each round.
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::coordination::v1::Lease;
+use kube::api::{Api, PatchParams, PostParams};
+use kube::error::ErrorResponse;
+use kube::Error;
+use log::{debug, info, warn};
+
+use crate::config::LeaderElectionConfig;
+use crate::rate_limit::RateLimits;
+
+/// Elects a single leader among the Kubelets sharing a node
+pub struct LeaderElector {
+    leases: Api<Lease>,
+    name: String,
+    identity: String,
+    lease_duration: Duration,
+    retry_period: Duration,
+    limits: RateLimits,
+}
+
+impl LeaderElector {
+    /// Create an elector for the given node, identifying this replica by `identity`
+    pub fn new(
+        client: kube::Client,
+        node_name: &str,
+        identity: &str,
+        config: &LeaderElectionConfig,
+    ) -> Self {
+        LeaderElector {
+            leases: Api::namespaced(client, &config.lease_namespace),
+            name: format!("{}-leader", node_name),
+            identity: identity.to_owned(),
+            lease_duration: config.lease_duration,
+            retry_period: config.retry_period,
+            limits: RateLimits::default(),
+        }
+    }
+
+    /// Make the lease requests under the given rate limits
+    pub(crate) fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Wait until this replica holds the lease
+    pub async fn acquire(&self) -> anyhow::Result<()> {
+        info!(
+            "Waiting to acquire leader lease {} as {}",
+            self.name, self.identity
+        );
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => {
+                    info!("Acquired leader lease {}", self.name);
+                    return Ok(());
+                }
+                Ok(false) => debug!("Leader lease {} is held by another replica", self.name),
+                Err(e) => warn!("Unable to acquire leader lease {}: {}", self.name, e),
+            }
+            tokio::time::delay_for(self.retry_period).await;
+        }
+    }
+
+    /// Keep renewing the lease, returning an error once leadership has been lost.
+    ///
+    /// Leadership is lost when the lease could not be renewed before it expired, as
+    /// another replica may have taken over by then.
+    pub async fn hold(&self) -> anyhow::Error {
+        let mut last_renewed = Utc::now();
+        loop {
+            tokio::time::delay_for(self.retry_period).await;
+            match self.try_acquire_or_renew().await {
+                Ok(true) => last_renewed = Utc::now(),
+                Ok(false) => {
+                    return anyhow::anyhow!("leader lease {} was taken over", self.name);
+                }
+                Err(e) => {
+                    warn!("Unable to renew leader lease {}: {}", self.name, e);
+                    if expired(last_renewed, self.lease_duration, Utc::now()) {
+                        return anyhow::anyhow!(
+                            "unable to renew leader lease {} before it expired: {}",
+                            self.name,
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Give up the lease if this replica holds it, so another replica can take over
+    /// straight away instead of waiting for it to expire
+    pub async fn release(&self) {
+        let lease = match self.limits.limited(self.leases.get(&self.name)).await {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("Unable to release leader lease {}: {}", self.name, e);
+                return;
+            }
+        };
+        if holder(&lease) != Some(self.identity.as_str()) {
+            return;
+        }
+        let patch = serde_json::json!({
+            "metadata": { "resourceVersion": resource_version(&lease) },
+            "spec": {
+                "holderIdentity": null,
+                "leaseDurationSeconds": 1,
+                "renewTime": micro_time(Utc::now()),
+            }
+        });
+        match self.patch(patch).await {
+            Ok(_) => info!("Released leader lease {}", self.name),
+            Err(e) => warn!("Unable to release leader lease {}: {}", self.name, e),
+        }
+    }
+
+    /// Returns true if this replica holds the lease after the attempt
+    async fn try_acquire_or_renew(&self) -> Result<bool, Error> {
+        let now = Utc::now();
+        let lease = match self.limits.limited(self.leases.get(&self.name)).await {
+            Ok(l) => l,
+            Err(Error::Api(ErrorResponse { code: 404, .. })) => return self.create(now).await,
+            Err(e) => return Err(e),
+        };
+        let spec = lease.spec.clone().unwrap_or_default();
+        let held_by_us = holder(&lease) == Some(self.identity.as_str());
+        if !held_by_us && holder(&lease).is_some() {
+            let renewed = spec.renew_time.map(|t| t.0).unwrap_or(now);
+            let duration =
+                Duration::from_secs(spec.lease_duration_seconds.unwrap_or(0).max(0) as u64);
+            if !expired(renewed, duration, now) {
+                return Ok(false);
+            }
+            info!(
+                "Leader lease {} held by {} has expired, taking over",
+                self.name,
+                holder(&lease).unwrap_or_default()
+            );
+        }
+
+        let mut spec_patch = serde_json::json!({
+            "holderIdentity": self.identity,
+            "leaseDurationSeconds": self.lease_duration.as_secs(),
+            "renewTime": micro_time(now),
+        });
+        if !held_by_us {
+            spec_patch["acquireTime"] = micro_time(now).into();
+            spec_patch["leaseTransitions"] = (spec.lease_transitions.unwrap_or(0) + 1).into();
+        }
+        // The resource version makes the update fail if another replica got there first
+        let patch = serde_json::json!({
+            "metadata": { "resourceVersion": resource_version(&lease) },
+            "spec": spec_patch,
+        });
+        match self.patch(patch).await {
+            Ok(_) => Ok(true),
+            Err(Error::Api(ErrorResponse { code: 409, .. })) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn create(&self, now: DateTime<Utc>) -> Result<bool, Error> {
+        let lease = serde_json::json!({
+            "apiVersion": "coordination.k8s.io/v1",
+            "kind": "Lease",
+            "metadata": { "name": self.name },
+            "spec": {
+                "holderIdentity": self.identity,
+                "leaseDurationSeconds": self.lease_duration.as_secs(),
+                "acquireTime": micro_time(now),
+                "renewTime": micro_time(now),
+                "leaseTransitions": 0,
+            }
+        });
+        let lease: Lease = serde_json::from_value(lease)
+            .expect("failed to deserialize lease from lease definition JSON");
+        match self
+            .limits
+            .limited(self.leases.create(&PostParams::default(), &lease))
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(Error::Api(ErrorResponse { code: 409, .. })) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn patch(&self, patch: serde_json::Value) -> Result<Lease, Error> {
+        let data = serde_json::to_vec(&patch).expect("Lease should always be serializable to JSON");
+        self.limits
+            .limited(self.leases.patch(&self.name, &PatchParams::default(), data))
+            .await
+    }
+}
+
+fn holder(lease: &Lease) -> Option<&str> {
+    lease
+        .spec
+        .as_ref()
+        .and_then(|s| s.holder_identity.as_deref())
+        .filter(|h| !h.is_empty())
+}
+
+fn resource_version(lease: &Lease) -> Option<&str> {
+    lease
+        .metadata
+        .as_ref()
+        .and_then(|m| m.resource_version.as_deref())
+}
+
+fn expired(renewed: DateTime<Utc>, duration: Duration, now: DateTime<Utc>) -> bool {
+    let duration =
+        chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero());
+    renewed + duration <= now
+}
+
+/// Formats a time the way the API server expects for lease times
+fn micro_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::MockApiServer;
+
+    const LEASE_PATH: &str =
+        "/apis/coordination.k8s.io/v1/namespaces/kube-system/leases/krustlet-leader";
+
+    fn elector(server: &MockApiServer, identity: &str) -> LeaderElector {
+        let config = LeaderElectionConfig {
+            lease_duration: Duration::from_secs(1),
+            retry_period: Duration::from_millis(50),
+            ..Default::default()
+        };
+        LeaderElector::new(server.client(), "krustlet", identity, &config)
+    }
+
+    #[tokio::test]
+    async fn test_only_one_leader() {
+        let server = MockApiServer::start().await.unwrap();
+        let first = elector(&server, "first");
+        let second = elector(&server, "second");
+
+        assert!(first.try_acquire_or_renew().await.unwrap());
+        assert!(!second.try_acquire_or_renew().await.unwrap());
+        // The leader keeps the lease by renewing it
+        assert!(first.try_acquire_or_renew().await.unwrap());
+        let lease = server.get(LEASE_PATH).unwrap();
+        assert_eq!("first", lease["spec"]["holderIdentity"]);
+        assert_eq!(0, lease["spec"]["leaseTransitions"]);
+    }
+
+    #[tokio::test]
+    async fn test_expired_lease_is_taken_over() {
+        let server = MockApiServer::start().await.unwrap();
+        let first = elector(&server, "first");
+        let second = elector(&server, "second");
+        assert!(first.try_acquire_or_renew().await.unwrap());
+
+        tokio::time::timeout(Duration::from_secs(5), second.acquire())
+            .await
+            .expect("the lease should be taken over once it expires")
+            .unwrap();
+        let lease = server.get(LEASE_PATH).unwrap();
+        assert_eq!("second", lease["spec"]["holderIdentity"]);
+        assert_eq!(1, lease["spec"]["leaseTransitions"]);
+
+        // The old leader finds out it lost the lease on its next renewal
+        let err = tokio::time::timeout(Duration::from_secs(5), first.hold())
+            .await
+            .unwrap();
+        assert!(err.to_string().contains("taken over"));
+    }
+
+    #[tokio::test]
+    async fn test_released_lease_is_taken_over_immediately() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut first = elector(&server, "first");
+        let mut second = elector(&server, "second");
+        // Make sure the lease is not simply expiring
+        first.lease_duration = Duration::from_secs(60);
+        second.lease_duration = Duration::from_secs(60);
+        assert!(first.try_acquire_or_renew().await.unwrap());
+        assert!(!second.try_acquire_or_renew().await.unwrap());
+
+        first.release().await;
+        assert!(second.try_acquire_or_renew().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_stale_update_loses() {
+        let server = MockApiServer::start().await.unwrap();
+        let first = elector(&server, "first");
+        assert!(first.try_acquire_or_renew().await.unwrap());
+
+        let patch = serde_json::json!({
+            "metadata": { "resourceVersion": "0" },
+            "spec": { "holderIdentity": "second" },
+        });
+        match first.patch(patch).await {
+            Err(Error::Api(ErrorResponse { code: 409, .. })) => (),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+}
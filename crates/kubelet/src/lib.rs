@@ -45,24 +45,77 @@
 #![deny(missing_docs)]
 #![cfg_attr(feature = "docs", feature(doc_cfg))]
 
+mod admission;
+mod auth;
+mod availability;
+mod cancellation;
+mod crds;
+mod events;
+mod eviction;
+mod failures;
 mod kubelet;
+#[cfg(target_os = "linux")]
+mod logind;
 mod logs;
 mod node;
+mod object_manager;
 mod pod;
+mod pull_backoff;
 mod queue;
+mod rate_limit;
+mod sandbox;
 mod server;
+mod shutdown;
+mod start_queue;
+mod tls;
 
+pub mod admin;
+pub mod annotations;
+pub mod audit;
+pub mod autoscaling;
+pub mod capabilities;
+#[cfg(feature = "cli")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "cli")))]
+pub mod cli;
+pub mod concurrency;
 pub mod config;
+pub mod container;
+pub mod dns;
+pub mod error;
+#[cfg(feature = "fault-injection")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "fault-injection")))]
+pub mod faults;
 pub mod handle;
 pub mod image_client;
+pub mod kubeconfig;
+pub mod leader;
+pub mod lifecycle;
+pub mod log_filter;
 pub mod module_store;
+pub mod pod_changes;
+pub mod pod_dirs;
+pub mod pod_logs;
+pub mod prefetch;
 pub mod provider;
+pub mod redact;
+pub mod registry;
+pub mod state;
+pub mod stats;
 pub mod status;
+#[cfg(unix)]
+pub mod systemd;
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "testing")))]
+pub mod testing;
+pub mod version;
 pub mod volumes;
+#[cfg(windows)]
+pub mod windows;
 
 pub use self::kubelet::Kubelet;
 pub use handle::{LogHandleFactory, PodHandle, RuntimeHandle};
 pub use logs::{LogSendError, LogSender};
-pub use pod::Pod;
+pub use node::{NodeBuilder, NODE_OWNER_ANNOTATION};
+pub use pod::{Pod, RestartPolicy, POD_FINALIZER};
 #[doc(inline)]
 pub use provider::Provider;
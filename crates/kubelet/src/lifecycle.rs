@@ -0,0 +1,346 @@
+//! A stream of what happens to the node and its pods, for applications that embed the
+//! Kubelet
+//!
+//! Embedders and tests react to the Kubelet's work by subscribing to its events with
+//! [`Kubelet::subscribe`](crate::Kubelet::subscribe), instead of watching the API
+//! server or patching the Kubelet:
+//!
+//! ```rust,no_run
+//! use kubelet::lifecycle::Event;
+//! use kubelet::{Kubelet, Provider};
+//!
+//! async fn log_failures<P: Provider + Send + Sync + 'static>(kubelet: &Kubelet<P>) {
+//!     let mut events = kubelet.subscribe();
+//!     while let Ok(event) = events.recv().await {
+//!         if let Event::PodFailed { namespace, pod, .. } = event {
+//!             println!("pod {} in namespace {} failed", pod, namespace);
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Events are broadcast to every subscriber of the Kubelet they happen in, and only
+//! those that happen after a subscription are received. A subscriber that falls more
+//! than [`CAPACITY`] events behind misses the oldest ones, which `recv` reports as
+//! [`RecvError::Lagged`](tokio::sync::broadcast::RecvError::Lagged).
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::handle::pod_key;
+use crate::pod::Pod;
+
+/// How many events a subscriber can fall behind before it misses some
+pub const CAPACITY: usize = 256;
+
+/// Something that happened to the node or one of its pods
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A pod passed the Kubelet's admission checks and is handed to the provider
+    PodAdmitted {
+        /// The namespace of the pod
+        namespace: String,
+        /// The name of the pod
+        pod: String,
+        /// The UID of the pod
+        uid: Option<String>,
+    },
+    /// The module of a container was fetched
+    ImagePulled {
+        /// The namespace of the pod
+        namespace: String,
+        /// The name of the pod
+        pod: String,
+        /// The name of the container
+        container: String,
+        /// The image the module was fetched for
+        image: String,
+    },
+    /// A container was reported as running
+    ContainerStarted {
+        /// The namespace of the pod
+        namespace: String,
+        /// The name of the pod
+        pod: String,
+        /// The name of the container
+        container: String,
+    },
+    /// A pod was reported as failed
+    PodFailed {
+        /// The namespace of the pod
+        namespace: String,
+        /// The name of the pod
+        pod: String,
+        /// Why the pod failed, such as `Evicted`
+        reason: Option<String>,
+        /// A human readable message about the failure
+        message: Option<String>,
+    },
+    /// The node was reported as ready or not ready
+    NodeReadyChanged {
+        /// Whether the node can run pods
+        ready: bool,
+        /// Why the node is not ready
+        message: Option<String>,
+    },
+}
+
+/// The events of a Kubelet, which its queue, status patches and node updates send.
+/// Cloning it shares the subscribers, and each Kubelet has its own
+#[derive(Clone)]
+pub(crate) struct Lifecycle {
+    sender: broadcast::Sender<Event>,
+    reported: Arc<Mutex<Reported>>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Lifecycle {
+            sender: broadcast::channel(CAPACITY).0,
+            reported: Default::default(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Lifecycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lifecycle")
+            .field("subscribers", &self.sender.receiver_count())
+            .finish()
+    }
+}
+
+impl Lifecycle {
+    /// Subscribe to the events that happen from now on
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    fn send(&self, event: Event) {
+        // Nobody may be subscribed, which is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Record that a pod was admitted
+    pub(crate) fn pod_admitted(&self, pod: &Pod) {
+        self.send(Event::PodAdmitted {
+            namespace: pod.namespace().to_owned(),
+            pod: pod.name().to_owned(),
+            uid: pod.uid().map(str::to_owned),
+        });
+    }
+
+    /// Record that the module of a container was fetched
+    pub(crate) fn image_pulled(&self, pod: &Pod, container: &str, image: &str) {
+        self.send(Event::ImagePulled {
+            namespace: pod.namespace().to_owned(),
+            pod: pod.name().to_owned(),
+            container: container.to_owned(),
+            image: image.to_owned(),
+        });
+    }
+
+    /// Record the pod status that was applied, reporting the containers that started
+    /// and whether the pod failed since the status applied before
+    pub(crate) fn status_applied(&self, namespace: &str, pod: &str, status: &serde_json::Value) {
+        let events = self.reported.lock().unwrap().status(namespace, pod, status);
+        events.into_iter().for_each(|event| self.send(event));
+    }
+
+    /// Record whether the node is ready, reporting it if it changed
+    pub(crate) fn node_ready(&self, not_ready: Option<&str>) {
+        let event = self.reported.lock().unwrap().node(not_ready);
+        event.into_iter().for_each(|event| self.send(event));
+    }
+
+    /// Forget what was reported about a pod that was deleted
+    pub(crate) fn pod_removed(&self, pod: &Pod) {
+        self.reported
+            .lock()
+            .unwrap()
+            .pods
+            .remove(&pod_key(pod.namespace(), pod.name()));
+    }
+}
+
+/// What was last reported about the node and its pods, so only changes are sent
+#[derive(Default)]
+struct Reported {
+    /// Whether each pod failed, and its running containers, by pod key
+    pods: HashMap<String, (bool, HashSet<String>)>,
+    node_ready: Option<bool>,
+}
+
+impl Reported {
+    fn status(&mut self, namespace: &str, pod: &str, status: &serde_json::Value) -> Vec<Event> {
+        let (failed, running) = self.pods.entry(pod_key(namespace, pod)).or_default();
+        let mut events = Vec::new();
+        let containers = status["containerStatuses"].as_array().into_iter().flatten();
+        for container in containers {
+            let name = match container["name"].as_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if !container["state"]["running"].is_object() {
+                running.remove(name);
+            } else if running.insert(name.to_owned()) {
+                events.push(Event::ContainerStarted {
+                    namespace: namespace.to_owned(),
+                    pod: pod.to_owned(),
+                    container: name.to_owned(),
+                });
+            }
+        }
+        match status["phase"].as_str() {
+            Some("Failed") if !*failed => {
+                *failed = true;
+                events.push(Event::PodFailed {
+                    namespace: namespace.to_owned(),
+                    pod: pod.to_owned(),
+                    reason: status["reason"].as_str().map(str::to_owned),
+                    message: status["message"].as_str().map(str::to_owned),
+                });
+            }
+            Some("Failed") | None => (),
+            Some(_) => *failed = false,
+        }
+        events
+    }
+
+    fn node(&mut self, not_ready: Option<&str>) -> Option<Event> {
+        let ready = not_ready.is_none();
+        if self.node_ready == Some(ready) {
+            return None;
+        }
+        self.node_ready = Some(ready);
+        Some(Event::NodeReadyChanged {
+            ready,
+            message: not_ready.map(str::to_owned),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+
+    fn running(container: &str) -> serde_json::Value {
+        serde_json::json!({ "name": container, "state": { "running": {} } })
+    }
+
+    #[test]
+    fn test_status_changes() {
+        let mut reported = Reported::default();
+        let waiting = serde_json::json!({ "name": "b", "state": { "waiting": {} } });
+        let started = |container: &str| Event::ContainerStarted {
+            namespace: "default".to_owned(),
+            pod: "foo".to_owned(),
+            container: container.to_owned(),
+        };
+
+        let status = serde_json::json!({
+            "phase": "Running",
+            "containerStatuses": [running("a"), waiting],
+        });
+        assert_eq!(
+            vec![started("a")],
+            reported.status("default", "foo", &status)
+        );
+        assert!(reported.status("default", "foo", &status).is_empty());
+
+        let status = serde_json::json!({
+            "phase": "Running",
+            "containerStatuses": [running("a"), running("b")],
+        });
+        assert_eq!(
+            vec![started("b")],
+            reported.status("default", "foo", &status)
+        );
+
+        let failed = serde_json::json!({ "phase": "Failed", "reason": "Evicted" });
+        assert_eq!(
+            vec![Event::PodFailed {
+                namespace: "default".to_owned(),
+                pod: "foo".to_owned(),
+                reason: Some("Evicted".to_owned()),
+                message: None,
+            }],
+            reported.status("default", "foo", &failed)
+        );
+        assert!(reported.status("default", "foo", &failed).is_empty());
+        // Other pods are reported on their own
+        assert_eq!(1, reported.status("default", "bar", &failed).len());
+    }
+
+    #[test]
+    fn test_node_changes() {
+        let mut reported = Reported::default();
+        assert_eq!(
+            Some(Event::NodeReadyChanged {
+                ready: true,
+                message: None
+            }),
+            reported.node(None)
+        );
+        assert_eq!(None, reported.node(None));
+        assert_eq!(
+            Some(Event::NodeReadyChanged {
+                ready: false,
+                message: Some("provider is unavailable".to_owned())
+            }),
+            reported.node(Some("provider is unavailable"))
+        );
+        assert_eq!(None, reported.node(Some("still unavailable")));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let lifecycle = Lifecycle::default();
+        let mut events = lifecycle.subscribe();
+        let pod = Pod::new(fake_pod("lifecycle-subscriber", "default"));
+        lifecycle.pod_admitted(&pod);
+        lifecycle.status_applied(
+            "default",
+            "lifecycle-subscriber",
+            &serde_json::json!({ "containerStatuses": [running("app")] }),
+        );
+        lifecycle.pod_removed(&pod);
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            match events.recv().await {
+                Ok(event) => received.push(event),
+                // Falling behind is reported, and the events after are still received
+                Err(broadcast::RecvError::Lagged(_)) => (),
+                Err(broadcast::RecvError::Closed) => panic!("the lifecycle is still open"),
+            }
+        }
+        assert_eq!(
+            vec![
+                Event::PodAdmitted {
+                    namespace: "default".to_owned(),
+                    pod: "lifecycle-subscriber".to_owned(),
+                    uid: pod.uid().map(str::to_owned),
+                },
+                Event::ContainerStarted {
+                    namespace: "default".to_owned(),
+                    pod: "lifecycle-subscriber".to_owned(),
+                    container: "app".to_owned(),
+                },
+            ],
+            received
+        );
+        assert!(!lifecycle
+            .reported
+            .lock()
+            .unwrap()
+            .pods
+            .contains_key("default:lifecycle-subscriber"));
+
+        // Other Kubelets' events are not received
+        Lifecycle::default().pod_admitted(&pod);
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,241 @@
+//! Changing which log entries are written while the Kubelet runs
+//!
+//! The entries written are chosen by directives in the same format as `RUST_LOG`: a
+//! comma separated list of levels for modules such as `info,kubelet::queue=trace`,
+//! where a level without a module applies to everything else. The directives start
+//! out as the value of `RUST_LOG` and can be replaced at any time with
+//! [`LogFilter::set`], or through the server of a Kubelet given the filter with
+//! [`Kubelet::with_log_filter`](crate::Kubelet::with_log_filter), so a production
+//! node can be debugged without a restart:
+//!
+//! ```text
+//! curl -X PUT --data 'info,kubelet::queue=trace' https://<node>:3000/debug/flags/log
+//! ```
+//!
+//! This only applies to loggers installed with [`init`].
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use log::{warn, LevelFilter, Log, Metadata, Record};
+
+/// Which log entries are written, by module
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    /// The directives, with the most specific modules first
+    directives: Vec<Directive>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Directive {
+    module: Option<String>,
+    level: LevelFilter,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut directives = Vec::new();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (module, level) = match directive.find('=') {
+                Some(i) => (Some(&directive[..i]), &directive[i + 1..]),
+                // A lone level applies to everything, a lone module enables it fully
+                None => match directive.parse::<LevelFilter>() {
+                    Ok(_) => (None, directive),
+                    Err(_) => (Some(directive), "trace"),
+                },
+            };
+            let level = level
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid log level {:?}", level))?;
+            directives.push(Directive {
+                module: module.map(str::to_owned),
+                level,
+            });
+        }
+        // Later directives for the same module win, as with `RUST_LOG`
+        let mut seen = HashSet::new();
+        directives.reverse();
+        directives.retain(|d| seen.insert(d.module.clone()));
+        directives.sort_by_key(|d| std::cmp::Reverse(d.module.as_ref().map(String::len)));
+        Ok(Filter { directives })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let directives: Vec<String> = self
+            .directives
+            .iter()
+            .rev()
+            .map(|d| match &d.module {
+                Some(module) => format!("{}={}", module, d.level.to_string().to_lowercase()),
+                None => d.level.to_string().to_lowercase(),
+            })
+            .collect();
+        write!(f, "{}", directives.join(","))
+    }
+}
+
+impl Filter {
+    /// Whether entries with the given metadata are written. Without any directives
+    /// only errors are, as with `RUST_LOG`
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        if self.directives.is_empty() {
+            return metadata.level() <= LevelFilter::Error;
+        }
+        let target = metadata.target();
+        self.directives
+            .iter()
+            .find(|d| match &d.module {
+                Some(module) => {
+                    target == module
+                        || (target.starts_with(module.as_str())
+                            && target[module.len()..].starts_with("::"))
+                }
+                None => true,
+            })
+            .map(|d| metadata.level() <= d.level)
+            .unwrap_or(false)
+    }
+
+    /// The most verbose level that any entry is written at
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|d| d.level)
+            .max()
+            .unwrap_or(LevelFilter::Error)
+    }
+}
+
+/// The directives that choose which entries a logger installed with [`init`] writes.
+/// Clones share the directives, and the default filter is not used by any logger
+#[derive(Clone, Debug, Default)]
+pub struct LogFilter {
+    filter: Arc<RwLock<Filter>>,
+    /// Whether the filter is the installed logger's, whose levels decide the most
+    /// verbose level any entry is made at
+    installed: bool,
+}
+
+impl LogFilter {
+    /// Replace the directives that choose which log entries are written
+    pub fn set(&self, directives: &str) -> anyhow::Result<()> {
+        let filter: Filter = directives.parse()?;
+        if self.installed {
+            log::set_max_level(filter.max_level());
+        }
+        *self.filter.write().unwrap() = filter;
+        Ok(())
+    }
+
+    /// Returns the directives that currently choose which log entries are written
+    pub fn get(&self) -> String {
+        self.filter.read().unwrap().to_string()
+    }
+}
+
+/// Install a logger as the global logger, filtered by the directives in `RUST_LOG`, or
+/// by the given default directives when it is not set or invalid. The returned filter
+/// changes the directives.
+///
+/// The logger itself should write every entry it is given, as the filtering is done
+/// before entries reach it.
+pub fn init(logger: Box<dyn Log>, default: &str) -> anyhow::Result<LogFilter> {
+    let (filter, invalid) = match std::env::var("RUST_LOG") {
+        Ok(directives) => match directives.parse::<Filter>() {
+            Ok(filter) => (filter, None),
+            Err(e) => (default.parse()?, Some(e)),
+        },
+        Err(_) => (default.parse()?, None),
+    };
+    log::set_max_level(filter.max_level());
+    let filter = LogFilter {
+        filter: Arc::new(RwLock::new(filter)),
+        installed: true,
+    };
+    log::set_boxed_logger(Box::new(Filtered {
+        filter: filter.filter.clone(),
+        logger,
+    }))?;
+    if let Some(e) = invalid {
+        warn!("Ignoring invalid RUST_LOG, using {:?}: {}", default, e);
+    }
+    Ok(filter)
+}
+
+/// A logger that only passes on the entries the current filter allows
+struct Filtered {
+    filter: Arc<RwLock<Filter>>,
+    logger: Box<dyn Log>,
+}
+
+impl Log for Filtered {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.read().unwrap().enabled(metadata) && self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+
+    fn enabled(filter: &Filter, target: &str, level: Level) -> bool {
+        filter.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter: Filter = "info, kubelet::queue=trace,kubelet=warn,hyper"
+            .parse()
+            .unwrap();
+        assert!(enabled(&filter, "kubelet::queue", Level::Trace));
+        assert!(enabled(&filter, "kubelet::queue::worker", Level::Debug));
+        assert!(!enabled(&filter, "kubelet::queuing", Level::Info));
+        assert!(enabled(&filter, "kubelet::queuing", Level::Warn));
+        assert!(!enabled(&filter, "kubelet::node", Level::Info));
+        assert!(enabled(&filter, "hyper::client", Level::Trace));
+        assert!(enabled(&filter, "wasi_provider", Level::Info));
+        assert!(!enabled(&filter, "wasi_provider", Level::Debug));
+        assert_eq!(LevelFilter::Trace, filter.max_level());
+        assert_eq!(
+            "info,hyper=trace,kubelet=warn,kubelet::queue=trace",
+            filter.to_string()
+        );
+
+        let filter: Filter = "kubelet=debug,kubelet=error".parse().unwrap();
+        assert!(!enabled(&filter, "kubelet", Level::Warn));
+        assert!(!enabled(&filter, "wasi_provider", Level::Error));
+
+        let filter = Filter::default();
+        assert!(enabled(&filter, "kubelet", Level::Error));
+        assert!(!enabled(&filter, "kubelet", Level::Warn));
+
+        assert!("kubelet=loud".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn test_log_filters_are_separate() {
+        let filter = LogFilter::default();
+        let other = LogFilter::default();
+        filter.set("info,kubelet::queue=trace").unwrap();
+        assert_eq!("info,kubelet::queue=trace", filter.clone().get());
+        assert_eq!("", other.get());
+        assert!(filter.set("kubelet=loud").is_err());
+        assert_eq!("info,kubelet::queue=trace", filter.get());
+    }
+}
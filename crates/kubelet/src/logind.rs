@@ -0,0 +1,707 @@
+//! A minimal client for the [logind](https://www.freedesktop.org/wiki/Software/systemd/logind/)
+//! D-Bus API
+//!
+//! Only what is needed to delay a shutdown is implemented: taking a delay inhibitor
+//! lock and waiting for the `PrepareForShutdown` signal. Messages are exchanged with
+//! the system bus over its socket directly, so the Kubelet does not depend on libdbus.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+const SYSTEM_BUS_ADDRESS_ENV: &str = "DBUS_SYSTEM_BUS_ADDRESS";
+const SYSTEM_BUS_SOCKET: &str = "/run/dbus/system_bus_socket";
+
+const DBUS_SERVICE: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const LOGIND_SERVICE: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+const FIELD_UNIX_FDS: u8 = 9;
+
+/// A connection to logind that can hold a delay inhibitor lock for shutdowns
+pub(crate) struct ShutdownInhibitor {
+    bus: Bus,
+    lock: Option<File>,
+}
+
+impl ShutdownInhibitor {
+    /// Connect to logind and subscribe to its shutdown announcements
+    pub(crate) fn connect() -> io::Result<Self> {
+        let mut bus = Bus::system()?;
+        let mut body = Encoder::default();
+        body.string(&format!(
+            "type='signal',interface='{}',member='PrepareForShutdown'",
+            LOGIND_MANAGER
+        ));
+        bus.call(
+            Message::method_call(DBUS_SERVICE, DBUS_PATH, DBUS_SERVICE, "AddMatch")
+                .with_body("s", body),
+        )?;
+        Ok(ShutdownInhibitor { bus, lock: None })
+    }
+
+    /// The longest logind lets a delay inhibitor lock hold up a shutdown, as set by
+    /// `InhibitDelayMaxSec` in `logind.conf`
+    pub(crate) fn max_delay(&mut self) -> io::Result<Duration> {
+        let mut body = Encoder::default();
+        body.string(LOGIND_MANAGER);
+        body.string("InhibitDelayMaxUSec");
+        let reply = self.bus.call(
+            Message::method_call(LOGIND_SERVICE, LOGIND_PATH, PROPERTIES_INTERFACE, "Get")
+                .with_body("ss", body),
+        )?;
+        let mut body = reply.body();
+        if body.signature()? != "t" {
+            return Err(invalid("unexpected type of InhibitDelayMaxUSec"));
+        }
+        Ok(Duration::from_micros(body.u64()?))
+    }
+
+    /// Take the inhibitor lock, unless it is already held
+    pub(crate) fn acquire(&mut self, why: &str) -> io::Result<()> {
+        if self.lock.is_some() {
+            return Ok(());
+        }
+        let mut body = Encoder::default();
+        for arg in &["shutdown", "krustlet", why, "delay"] {
+            body.string(arg);
+        }
+        let mut reply = self.bus.call(
+            Message::method_call(LOGIND_SERVICE, LOGIND_PATH, LOGIND_MANAGER, "Inhibit")
+                .with_body("ssss", body),
+        )?;
+        let index = reply.body().u32()? as usize;
+        if index >= reply.fds.len() {
+            return Err(invalid("logind did not send an inhibitor lock"));
+        }
+        self.lock = Some(reply.fds.swap_remove(index));
+        Ok(())
+    }
+
+    /// Release the inhibitor lock so a shutdown can go ahead
+    pub(crate) fn release(&mut self) {
+        self.lock = None;
+    }
+
+    /// Wait for logind to announce that the host is about to shut down, which returns
+    /// `true`, or that a shutdown was cancelled, which returns `false`
+    pub(crate) fn wait(&mut self) -> io::Result<bool> {
+        loop {
+            let signal = self.bus.next_signal()?;
+            if signal.interface.as_deref() == Some(LOGIND_MANAGER)
+                && signal.member.as_deref() == Some("PrepareForShutdown")
+            {
+                return signal.body().bool();
+            }
+        }
+    }
+}
+
+/// A connection to the system bus
+struct Bus {
+    stream: UnixStream,
+    serial: u32,
+    incoming: Vec<u8>,
+    fds: VecDeque<File>,
+    signals: VecDeque<Message>,
+}
+
+impl Bus {
+    fn system() -> io::Result<Bus> {
+        let path = match std::env::var(SYSTEM_BUS_ADDRESS_ENV) {
+            Ok(address) => unix_path(&address)
+                .ok_or_else(|| invalid(&format!("unsupported system bus address {}", address)))?,
+            Err(_) => SYSTEM_BUS_SOCKET.to_owned(),
+        };
+        let mut stream = UnixStream::connect(path)?;
+        authenticate(&mut stream)?;
+        let mut bus = Bus {
+            stream,
+            serial: 0,
+            incoming: Vec::new(),
+            fds: VecDeque::new(),
+            signals: VecDeque::new(),
+        };
+        bus.call(Message::method_call(
+            DBUS_SERVICE,
+            DBUS_PATH,
+            DBUS_SERVICE,
+            "Hello",
+        ))?;
+        Ok(bus)
+    }
+
+    /// Call a method and wait for its reply. Signals received in the meantime are kept
+    /// for [`Bus::next_signal`]
+    fn call(&mut self, mut message: Message) -> io::Result<Message> {
+        self.serial += 1;
+        message.serial = self.serial;
+        self.stream.write_all(&message.encode())?;
+        loop {
+            let reply = self.receive()?;
+            match reply.kind {
+                SIGNAL => self.signals.push_back(reply),
+                METHOD_RETURN if reply.reply_serial == Some(message.serial) => return Ok(reply),
+                ERROR if reply.reply_serial == Some(message.serial) => {
+                    let text = reply.body().string().unwrap_or_default();
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "{} failed: {} {}",
+                            message.member.unwrap_or_default(),
+                            reply.error_name.unwrap_or_default(),
+                            text
+                        ),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn next_signal(&mut self) -> io::Result<Message> {
+        loop {
+            if let Some(signal) = self.signals.pop_front() {
+                return Ok(signal);
+            }
+            let message = self.receive()?;
+            if message.kind == SIGNAL {
+                self.signals.push_back(message);
+            }
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Message> {
+        loop {
+            if let Some(len) = message_len(&self.incoming)? {
+                if self.incoming.len() >= len {
+                    let bytes: Vec<u8> = self.incoming.drain(..len).collect();
+                    let mut message = Message::decode(&bytes)?;
+                    let fds = (message.unix_fds as usize).min(self.fds.len());
+                    message.fds = self.fds.drain(..fds).collect();
+                    return Ok(message);
+                }
+            }
+            let mut buf = [0u8; 4096];
+            let read = recv_with_fds(self.stream.as_raw_fd(), &mut buf, &mut self.fds)?;
+            if read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the system bus closed the connection",
+                ));
+            }
+            self.incoming.extend_from_slice(&buf[..read]);
+        }
+    }
+}
+
+/// Returns the socket path of a `unix:path=` bus address
+fn unix_path(address: &str) -> Option<String> {
+    address
+        .split(';')
+        .filter(|a| a.starts_with("unix:"))
+        .flat_map(|a| a["unix:".len()..].split(','))
+        .find(|kv| kv.starts_with("path="))
+        .map(|kv| kv["path=".len()..].to_owned())
+}
+
+/// Authenticate as the user running the Kubelet and enable file descriptor passing,
+/// which logind needs to hand out inhibitor locks
+fn authenticate(stream: &mut UnixStream) -> io::Result<()> {
+    let uid = unsafe { libc::getuid() }.to_string();
+    let uid: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+    stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", uid).as_bytes())?;
+    expect_line(stream, "OK")?;
+    stream.write_all(b"NEGOTIATE_UNIX_FD\r\n")?;
+    expect_line(stream, "AGREE_UNIX_FD")?;
+    stream.write_all(b"BEGIN\r\n")
+}
+
+fn expect_line(stream: &mut UnixStream, expected: &str) -> io::Result<()> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while !line.ends_with(b"\r\n") {
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8_lossy(&line);
+    if line.starts_with(expected) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("system bus authentication failed: {}", line.trim()),
+        ))
+    }
+}
+
+/// Read from a socket, keeping any file descriptors sent along with the data
+fn recv_with_fds(socket: RawFd, buf: &mut [u8], fds: &mut VecDeque<File>) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // Aligned for a cmsghdr, with room for a few descriptors
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    let read = loop {
+        let read = unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if read >= 0 {
+            break read as usize;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    fds.push_back(File::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    // The descriptors that didn't fit were closed by the kernel, so a lock could have
+    // been lost with them
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(invalid("file descriptors sent by the bus were truncated"));
+    }
+    Ok(read)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// A D-Bus message, with only the header fields logind uses
+#[derive(Debug, Default)]
+struct Message {
+    kind: u8,
+    serial: u32,
+    big_endian: bool,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    destination: Option<String>,
+    signature: String,
+    unix_fds: u32,
+    body: Vec<u8>,
+    fds: Vec<File>,
+}
+
+impl Message {
+    fn method_call(destination: &str, path: &str, interface: &str, member: &str) -> Self {
+        Message {
+            kind: METHOD_CALL,
+            destination: Some(destination.to_owned()),
+            path: Some(path.to_owned()),
+            interface: Some(interface.to_owned()),
+            member: Some(member.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn with_body(mut self, signature: &str, body: Encoder) -> Self {
+        self.signature = signature.to_owned();
+        self.body = body.buf;
+        self
+    }
+
+    fn body(&self) -> Decoder<'_> {
+        Decoder {
+            buf: &self.body,
+            pos: 0,
+            big_endian: self.big_endian,
+        }
+    }
+
+    /// Encode the message in little endian
+    fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        e.u8(b'l');
+        e.u8(self.kind);
+        e.u8(0);
+        e.u8(1);
+        e.u32(self.body.len() as u32);
+        e.u32(self.serial);
+        // The length of the header fields is filled in once they are written
+        e.u32(0);
+        let strings = [
+            (FIELD_PATH, "o", &self.path),
+            (FIELD_INTERFACE, "s", &self.interface),
+            (FIELD_MEMBER, "s", &self.member),
+            (FIELD_ERROR_NAME, "s", &self.error_name),
+            (FIELD_DESTINATION, "s", &self.destination),
+        ];
+        for (code, signature, value) in strings.iter() {
+            if let Some(value) = value {
+                e.field(*code, signature);
+                e.string(value);
+            }
+        }
+        if let Some(reply_serial) = self.reply_serial {
+            e.field(FIELD_REPLY_SERIAL, "u");
+            e.u32(reply_serial);
+        }
+        if !self.signature.is_empty() {
+            e.field(FIELD_SIGNATURE, "g");
+            e.signature(&self.signature);
+        }
+        if self.unix_fds > 0 {
+            e.field(FIELD_UNIX_FDS, "u");
+            e.u32(self.unix_fds);
+        }
+        let fields_len = (e.buf.len() - 16) as u32;
+        e.buf[12..16].copy_from_slice(&fields_len.to_le_bytes());
+        e.align(8);
+        e.buf.extend_from_slice(&self.body);
+        e.buf
+    }
+
+    /// Decode a complete message
+    fn decode(bytes: &[u8]) -> io::Result<Message> {
+        let mut d = Decoder::new(bytes)?;
+        let kind = d.u8()?;
+        let _flags = d.u8()?;
+        let _version = d.u8()?;
+        let body_len = d.u32()? as usize;
+        let mut message = Message {
+            kind,
+            serial: d.u32()?,
+            big_endian: d.big_endian,
+            ..Default::default()
+        };
+        let fields_end = d.u32()? as usize + d.pos;
+        while d.pos < fields_end {
+            d.align(8)?;
+            let code = d.u8()?;
+            match d.signature()? {
+                "s" | "o" => {
+                    let value = Some(d.string()?);
+                    match code {
+                        FIELD_PATH => message.path = value,
+                        FIELD_INTERFACE => message.interface = value,
+                        FIELD_MEMBER => message.member = value,
+                        FIELD_ERROR_NAME => message.error_name = value,
+                        FIELD_DESTINATION => message.destination = value,
+                        _ => {}
+                    }
+                }
+                "g" => {
+                    let value = d.signature()?;
+                    if code == FIELD_SIGNATURE {
+                        message.signature = value.to_owned();
+                    }
+                }
+                "u" => {
+                    let value = d.u32()?;
+                    match code {
+                        FIELD_REPLY_SERIAL => message.reply_serial = Some(value),
+                        FIELD_UNIX_FDS => message.unix_fds = value,
+                        _ => {}
+                    }
+                }
+                other => {
+                    return Err(invalid(&format!(
+                        "unsupported header field type {:?}",
+                        other
+                    )))
+                }
+            }
+        }
+        d.pos = fields_end;
+        d.align(8)?;
+        message.body = d.take(body_len)?.to_vec();
+        Ok(message)
+    }
+}
+
+/// Returns the length of the message at the start of `bytes`, once enough of it has
+/// been received to tell
+fn message_len(bytes: &[u8]) -> io::Result<Option<usize>> {
+    if bytes.len() < 16 {
+        return Ok(None);
+    }
+    let mut d = Decoder::new(bytes)?;
+    d.pos = 4;
+    let body_len = d.u32()? as usize;
+    d.pos = 12;
+    let fields_len = d.u32()? as usize;
+    let header_len = (16 + fields_len + 7) / 8 * 8;
+    Ok(Some(header_len + body_len))
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn align(&mut self, n: usize) {
+        while self.buf.len() % n != 0 {
+            self.buf.push(0);
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.u8(value.len() as u8);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// Start a header field, which is a struct of its code and a variant
+    fn field(&mut self, code: u8, signature: &str) {
+        self.align(8);
+        self.u8(code);
+        self.signature(signature);
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Decoder<'a> {
+    /// Start decoding a message, whose first byte gives its endianness
+    fn new(buf: &'a [u8]) -> io::Result<Self> {
+        let big_endian = match buf.first() {
+            Some(b'l') => false,
+            Some(b'B') => true,
+            _ => return Err(invalid("invalid message endianness")),
+        };
+        Ok(Decoder {
+            buf,
+            pos: 1,
+            big_endian,
+        })
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.pos + n > self.buf.len() {
+            return Err(invalid("message is truncated"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn align(&mut self, n: usize) -> io::Result<()> {
+        let padding = (n - self.pos % n) % n;
+        self.take(padding).map(|_| ())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.align(4)?;
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        self.align(8)?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u32()? != 0)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let value = self.take(len)?;
+        self.take(1)?;
+        String::from_utf8(value.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn signature(&mut self) -> io::Result<&'a str> {
+        let len = self.u8()? as usize;
+        let value = self.take(len)?;
+        self.take(1)?;
+        std::str::from_utf8(value).map_err(|_| invalid("signature is not UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut body = Encoder::default();
+        body.string("shutdown");
+        body.u32(7);
+        let mut message =
+            Message::method_call(LOGIND_SERVICE, LOGIND_PATH, LOGIND_MANAGER, "Inhibit")
+                .with_body("su", body);
+        message.serial = 3;
+        message.reply_serial = Some(2);
+        message.unix_fds = 1;
+        let bytes = message.encode();
+        assert_eq!(0, (bytes.len() - message.body.len()) % 8);
+        assert_eq!(Some(bytes.len()), message_len(&bytes).unwrap());
+        assert_eq!(None, message_len(&bytes[..15]).unwrap());
+
+        let decoded = Message::decode(&bytes).unwrap();
+        assert_eq!(METHOD_CALL, decoded.kind);
+        assert_eq!(3, decoded.serial);
+        assert_eq!(Some(2), decoded.reply_serial);
+        assert_eq!(1, decoded.unix_fds);
+        assert_eq!(Some(LOGIND_PATH), decoded.path.as_deref());
+        assert_eq!(Some("Inhibit"), decoded.member.as_deref());
+        assert_eq!(Some(LOGIND_SERVICE), decoded.destination.as_deref());
+        assert_eq!("su", decoded.signature);
+        let mut body = decoded.body();
+        assert_eq!("shutdown", body.string().unwrap());
+        assert_eq!(7, body.u32().unwrap());
+        assert!(body.u8().is_err());
+    }
+
+    #[test]
+    fn test_decode_big_endian() {
+        // A PrepareForShutdown(true) signal as sent by a big endian bus
+        let mut bytes = vec![b'B', SIGNAL, 0, 1, 0, 0, 0, 4, 0, 0, 0, 9];
+        let mut fields = Vec::new();
+        fields.extend_from_slice(&[FIELD_SIGNATURE, 1, b'g', 0, 1, b'b', 0, 0]);
+        fields.extend_from_slice(&[FIELD_MEMBER, 1, b's', 0]);
+        fields.extend_from_slice(&18u32.to_be_bytes());
+        fields.extend_from_slice(b"PrepareForShutdown\0");
+        bytes.extend_from_slice(&(fields.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&fields);
+        while bytes.len() % 8 != 0 {
+            bytes.push(0);
+        }
+        bytes.extend_from_slice(&1u32.to_be_bytes());
+        assert_eq!(Some(bytes.len()), message_len(&bytes).unwrap());
+
+        let message = Message::decode(&bytes).unwrap();
+        assert_eq!(SIGNAL, message.kind);
+        assert_eq!(9, message.serial);
+        assert_eq!("b", message.signature);
+        assert_eq!(Some("PrepareForShutdown"), message.member.as_deref());
+        assert!(message.body().bool().unwrap());
+    }
+
+    /// Send the bytes along with the given file descriptors
+    fn send_with_fds(socket: &UnixStream, bytes: &[u8], fds: &[RawFd]) {
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_ptr() as *mut libc::c_void,
+            iov_len: bytes.len(),
+        };
+        let data_len = std::mem::size_of_val(fds) as u32;
+        let mut control = vec![0u64; unsafe { libc::CMSG_SPACE(data_len) } as usize / 8 + 1];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(data_len) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(data_len) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+            assert_eq!(
+                bytes.len() as isize,
+                libc::sendmsg(socket.as_raw_fd(), &msg, 0)
+            );
+        }
+    }
+
+    #[test]
+    fn test_recv_with_fds() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let file = File::open("/dev/null").unwrap();
+        let mut buf = [0; 16];
+        let mut fds = VecDeque::new();
+
+        send_with_fds(&sender, b"lock", &[file.as_raw_fd()]);
+        assert_eq!(
+            4,
+            recv_with_fds(receiver.as_raw_fd(), &mut buf, &mut fds).unwrap()
+        );
+        assert_eq!(b"lock", &buf[..4]);
+        assert_eq!(1, fds.len());
+
+        // More descriptors than fit are an error rather than silently lost
+        send_with_fds(&sender, b"locks", &[file.as_raw_fd(); 32]);
+        let error = recv_with_fds(receiver.as_raw_fd(), &mut buf, &mut fds).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn test_unix_path() {
+        assert_eq!(
+            Some("/run/dbus/system_bus_socket".to_owned()),
+            unix_path("unix:path=/run/dbus/system_bus_socket")
+        );
+        assert_eq!(
+            Some("/tmp/bus".to_owned()),
+            unix_path("tcp:host=localhost;unix:guid=1234,path=/tmp/bus")
+        );
+        assert_eq!(None, unix_path("unix:abstract=/tmp/bus"));
+    }
+}
@@ -1,16 +1,89 @@
 //! Stores of container module images
+use crate::concurrency::{Limits, Operation};
+use crate::error::PodSyncError;
+use crate::handle::key_from_pod;
 use crate::image_client::ImageClient;
 use crate::pod::Pod;
 
 use async_trait::async_trait;
 use log::debug;
 use oci_distribution::Reference;
-use tokio::sync::Mutex;
+use tokio::sync::watch;
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+
+pub mod bindle;
+pub mod s3;
+
+/// The manifest digest and size of a module that was fetched for a container image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// The digest of the image's manifest, in the form `sha256:<hex>`, if the module
+    /// store knows it
+    pub digest: Option<String>,
+    /// The size of the module in bytes
+    pub size: u64,
+}
+
+impl ImageInfo {
+    /// The ID of the image as reported in container statuses, which names the image
+    /// it was resolved from along with the digest, such as `example.com/app@sha256:...`.
+    /// There is none if the digest isn't known
+    pub fn image_id(&self, image: &str) -> Option<String> {
+        let digest = self.digest.as_ref()?;
+        Some(match Reference::try_from(image) {
+            Ok(r) => format!("{}/{}@{}", r.registry(), r.repository(), digest),
+            Err(_) => digest.clone(),
+        })
+    }
+}
+
+/// The info of the modules fetched for the containers of the pods a Kubelet handles.
+/// Clones share the info, which is kept until the pod is forgotten
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FetchedImages(Arc<RwLock<HashMap<String, HashMap<String, Fetched>>>>);
+
+#[derive(Clone, Debug)]
+struct Fetched {
+    image: String,
+    info: ImageInfo,
+}
+
+impl FetchedImages {
+    /// Record the info of the module fetched for a container of the pod
+    pub(crate) fn record(&self, pod: &Pod, container: &str, image: &str, info: ImageInfo) {
+        let fetched = Fetched {
+            image: image.to_owned(),
+            info,
+        };
+        self.0
+            .write()
+            .unwrap()
+            .entry(key_from_pod(pod))
+            .or_default()
+            .insert(container.to_owned(), fetched);
+    }
+
+    /// Get the info of the module last fetched for a container of the pod, if it was
+    /// fetched for the given image
+    pub(crate) fn get(&self, pod: &Pod, container: &str, image: &str) -> Option<ImageInfo> {
+        let images = self.0.read().unwrap();
+        let fetched = images.get(&key_from_pod(pod))?.get(container)?;
+        if fetched.image == image {
+            Some(fetched.info.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Forget the modules fetched for the pod
+    pub(crate) fn forget(&self, pod: &Pod) {
+        self.0.write().unwrap().remove(&key_from_pod(pod));
+    }
+}
 
 /// A store of container modules.
 ///
@@ -44,10 +117,30 @@ pub trait ModuleStore {
     /// It is up to the implementation to establish caching and network fetching policies.
     async fn get(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>>;
 
+    /// Get the module of one of a pod's containers given the container's image.
+    ///
+    /// By default the image is parsed as a [`Reference`] and fetched with
+    /// [`ModuleStore::get`]. Stores that fetch modules from other sources, such as the
+    /// [`BindleModuleStore`](bindle::BindleModuleStore), decide which source to use for
+    /// each pod and image here.
+    async fn fetch_container_module(&self, _pod: &Pod, image: &str) -> anyhow::Result<Vec<u8>> {
+        self.get(&Reference::try_from(image)?).await
+    }
+
+    /// Get the digest of the manifest of the image whose module was last fetched for
+    /// one of the pod's containers, which is reported as the container's image ID. By
+    /// default it isn't known
+    async fn image_digest(&self, _pod: &Pod, _image: &str) -> Option<String> {
+        None
+    }
+
     /// Fetch all container modules for a given `Pod` storing the name of the
     /// container and the module's data as key/value pairs in a hashmap.
     ///
-    /// This will fetch all of the container modules in parallel.
+    /// This will fetch all of the container modules in parallel. Failures to fetch
+    /// a module are returned as a [`PodSyncError::ImagePull`]. The [`ImageInfo`] of
+    /// each module is recorded so it can be reported in the pod's status, see
+    /// [`Pod::image_info`].
     ///
     /// # Panics
     ///
@@ -57,14 +150,32 @@ pub trait ModuleStore {
             "Fetching all the container modules for pod '{}'",
             pod.name()
         );
+        #[cfg(feature = "fault-injection")]
+        pod.rate_limits().faults().image_pull().await;
         // Fetch all of the container modules in parallel
         let container_module_futures = pod.containers().iter().map(move |container| {
             let image = container
                 .image
                 .clone()
                 .expect("FATAL ERROR: container must have an image");
-            let reference = Reference::try_from(image).unwrap();
-            async move { Ok((container.name.clone(), self.get(&reference).await?)) }
+            async move {
+                let module = self
+                    .fetch_container_module(pod, &image)
+                    .await
+                    .map_err(|source| PodSyncError::ImagePull {
+                        image: image.clone(),
+                        source,
+                    })?;
+                pod.audit()
+                    .module_pulled(pod, &container.name, &image, &module);
+                pod.lifecycle().image_pulled(pod, &container.name, &image);
+                let info = ImageInfo {
+                    digest: self.image_digest(pod, &image).await,
+                    size: module.len() as u64,
+                };
+                pod.record_image(&container.name, &image, info);
+                Ok((container.name.clone(), module))
+            }
         });
 
         // Collect the container modules into a HashMap for quick lookup
@@ -80,9 +191,35 @@ pub trait ModuleStore {
 /// This type is generic over the type of Kubernetes client used
 /// to fetch modules from a remote store. This client is expected
 /// to be an [`ImageClient`]
+///
+/// Concurrent requests for a module that is not cached yet share a single pull,
+/// so pods that start together with the same image only download it once. Pulls of
+/// different images run concurrently on the same client, limited for each pod by its
+/// [`concurrency`](Pod::concurrency) limits. When the store has the module
+/// of another tag of the image's repository, the most recently pulled one is given to
+/// the client as a base to pull a delta from, see [`ImageClient::pull_from_base`].
 pub struct FileModuleStore<C> {
     root_dir: PathBuf,
-    client: Arc<Mutex<C>>,
+    client: Arc<C>,
+    pulls: Arc<std::sync::Mutex<HashMap<Reference, PullReceiver>>>,
+}
+
+/// The outcome of a pull as seen by the requests waiting on it. Errors are shared
+/// as their message as they can't be cloned
+type PullResult = Result<Arc<Vec<u8>>, String>;
+
+type PullReceiver = watch::Receiver<Option<PullResult>>;
+
+/// Removes a pull from the in progress pulls once it is done, even if it is cancelled
+struct PullGuard {
+    pulls: Arc<std::sync::Mutex<HashMap<Reference, PullReceiver>>>,
+    image_ref: Reference,
+}
+
+impl Drop for PullGuard {
+    fn drop(&mut self) {
+        self.pulls.lock().unwrap().remove(&self.image_ref);
+    }
 }
 
 impl<C> FileModuleStore<C> {
@@ -90,7 +227,8 @@ impl<C> FileModuleStore<C> {
     pub fn new<T: AsRef<Path>>(client: C, root_dir: T) -> Self {
         Self {
             root_dir: root_dir.as_ref().into(),
-            client: Arc::new(Mutex::new(client)),
+            client: Arc::new(client),
+            pulls: Arc::new(std::sync::Mutex::new(HashMap::new())),
         }
     }
 
@@ -105,30 +243,150 @@ impl<C> FileModuleStore<C> {
         self.pull_path(r).join("module.wasm")
     }
 
-    async fn store(&self, image_ref: &Reference, contents: &[u8]) -> anyhow::Result<()> {
+    fn digest_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join("digest")
+    }
+
+    /// Returns the path of the module most recently stored for another tag of the
+    /// image's repository
+    fn base_file_path(&self, r: &Reference) -> Option<PathBuf> {
+        let repository = self.pull_path(r).parent()?.to_owned();
+        std::fs::read_dir(repository)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name() != std::ffi::OsStr::new(r.tag()))
+            .map(|entry| entry.path().join("module.wasm"))
+            .filter_map(|path| Some((path.metadata().ok()?.modified().ok()?, path)))
+            .max()
+            .map(|(_, path)| path)
+    }
+
+    async fn store(
+        &self,
+        image_ref: &Reference,
+        contents: &[u8],
+        digest: Option<&str>,
+    ) -> anyhow::Result<()> {
         tokio::fs::create_dir_all(self.pull_path(image_ref)).await?;
+        // The digest is written first, so a stored module never has the digest of the
+        // module it replaced
+        let digest_path = self.digest_file_path(image_ref);
+        match digest {
+            Some(digest) => tokio::fs::write(&digest_path, digest).await?,
+            None if digest_path.exists() => tokio::fs::remove_file(&digest_path).await?,
+            None => (),
+        }
         let path = self.pull_file_path(image_ref);
         tokio::fs::write(&path, contents).await?;
         Ok(())
     }
 }
 
-#[async_trait]
-impl<C: ImageClient + Send> ModuleStore for FileModuleStore<C> {
-    async fn get(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
+impl<C: ImageClient + Send + Sync> FileModuleStore<C> {
+    /// Get a module, pulling it under the given limits if it is not cached
+    async fn fetch(&self, image_ref: &Reference, limits: &Limits) -> anyhow::Result<Vec<u8>> {
         let path = self.pull_file_path(image_ref);
-        if !path.exists() {
-            debug!(
-                "Image ref '{:?}' doesn't exist on disk. Fetching remotely...",
-                image_ref
-            );
-            let contents = self.client.lock().await.pull(image_ref).await?;
-            self.store(image_ref, &contents).await?;
-            return Ok(contents);
+        let next = {
+            let mut pulls = self.pulls.lock().unwrap();
+            // Modules are stored before their pull is removed, so checking the disk
+            // while holding the lock can't miss a pull that just finished
+            if let Some(receiver) = pulls.get(image_ref) {
+                Next::Wait(receiver.clone())
+            } else if path.exists() {
+                Next::Read
+            } else {
+                let (sender, receiver) = watch::channel(None);
+                pulls.insert(image_ref.clone(), receiver);
+                Next::Pull(sender)
+            }
+        };
+
+        match next {
+            Next::Wait(receiver) => {
+                debug!(
+                    "Waiting for in progress pull of image ref '{:?}'",
+                    image_ref
+                );
+                wait_for_pull(receiver).await
+            }
+            Next::Pull(sender) => {
+                let _guard = PullGuard {
+                    pulls: self.pulls.clone(),
+                    image_ref: image_ref.clone(),
+                };
+                let result = self.pull(image_ref, limits).await.map(Arc::new);
+                let shared = match &result {
+                    Ok(contents) => Ok(contents.clone()),
+                    Err(e) => Err(e.to_string()),
+                };
+                // Nobody may be waiting, which is fine
+                let _ = sender.broadcast(Some(shared));
+                result.map(|contents| {
+                    Arc::try_unwrap(contents).unwrap_or_else(|c| c.as_ref().clone())
+                })
+            }
+            Next::Read => {
+                debug!("Fetching image ref '{:?}' from disk", image_ref);
+                Ok(tokio::fs::read(path).await?)
+            }
         }
+    }
+
+    async fn pull(&self, image_ref: &Reference, limits: &Limits) -> anyhow::Result<Vec<u8>> {
+        debug!(
+            "Image ref '{:?}' doesn't exist on disk. Fetching remotely...",
+            image_ref
+        );
+        let base = match self.base_file_path(image_ref) {
+            Some(path) => tokio::fs::read(path).await.ok(),
+            None => None,
+        };
+        let pull = self.client.pull_with_digest(image_ref, base.as_deref());
+        let (contents, digest) = limits.run(Operation::ImagePull, pull).await?;
+        self.store(image_ref, &contents, digest.as_deref()).await?;
+        Ok(contents)
+    }
+}
+
+/// What a request for a module has to do to get it
+enum Next {
+    /// Wait for the pull another request started
+    Wait(PullReceiver),
+    /// Pull the module, sharing the result with anyone who waits for it
+    Pull(watch::Sender<Option<PullResult>>),
+    /// Read the module from disk
+    Read,
+}
+
+/// Wait for a pull started by another request to finish
+async fn wait_for_pull(mut receiver: PullReceiver) -> anyhow::Result<Vec<u8>> {
+    while let Some(result) = receiver.recv().await {
+        match result {
+            Some(Ok(contents)) => return Ok(contents.as_ref().clone()),
+            Some(Err(e)) => return Err(anyhow::anyhow!(e)),
+            None => (),
+        }
+    }
+    Err(anyhow::anyhow!("image pull was cancelled"))
+}
+
+#[async_trait]
+impl<C: ImageClient + Send + Sync> ModuleStore for FileModuleStore<C> {
+    async fn get(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
+        self.fetch(image_ref, &Limits::default()).await
+    }
+
+    /// Modules that are not cached are pulled under the pod's limits
+    async fn fetch_container_module(&self, pod: &Pod, image: &str) -> anyhow::Result<Vec<u8>> {
+        self.fetch(&Reference::try_from(image)?, pod.concurrency())
+            .await
+    }
 
-        debug!("Fetching image ref '{:?}' from disk", image_ref);
-        Ok(tokio::fs::read(path).await?)
+    async fn image_digest(&self, _pod: &Pod, image: &str) -> Option<String> {
+        let image_ref = Reference::try_from(image).ok()?;
+        tokio::fs::read_to_string(self.digest_file_path(&image_ref))
+            .await
+            .ok()
     }
 }
 
@@ -137,6 +395,248 @@ impl<C> Clone for FileModuleStore<C> {
         Self {
             root_dir: self.root_dir.clone(),
             client: self.client.clone(),
+            pulls: self.pulls.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct SlowClient {
+        pulls: Arc<AtomicUsize>,
+        in_flight: AtomicUsize,
+        max_in_flight: Arc<AtomicUsize>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl ImageClient for SlowClient {
+        async fn pull(&self, _image: &Reference) -> anyhow::Result<Vec<u8>> {
+            self.pulls.fetch_add(1, Ordering::SeqCst);
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::delay_for(std::time::Duration::from_millis(50)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            if self.fail {
+                anyhow::bail!("registry unavailable");
+            }
+            Ok(b"module".to_vec())
+        }
+    }
+
+    type Store = (
+        FileModuleStore<SlowClient>,
+        Arc<AtomicUsize>,
+        Arc<AtomicUsize>,
+        PathBuf,
+    );
+
+    fn store(name: &str, fail: bool) -> Store {
+        let pulls = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let dir = std::env::temp_dir().join(format!(
+            "krustlet-module-store-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let client = SlowClient {
+            pulls: pulls.clone(),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: max_in_flight.clone(),
+            fail,
+        };
+        (
+            FileModuleStore::new(client, &dir),
+            pulls,
+            max_in_flight,
+            dir,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_gets_share_one_pull() {
+        let (store, pulls, _, dir) = store("shared", false);
+        let image_ref = Reference::try_from("example.com/hello:v1").unwrap();
+
+        let gets = (0..5).map(|_| store.get(&image_ref));
+        let modules = futures::future::join_all(gets).await;
+        assert!(modules.iter().all(|m| m.as_ref().unwrap() == b"module"));
+        assert_eq!(1, pulls.load(Ordering::SeqCst));
+
+        // Later requests are served from disk
+        assert_eq!(b"module".to_vec(), store.get(&image_ref).await.unwrap());
+        assert_eq!(1, pulls.load(Ordering::SeqCst));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_pull_is_shared_and_retried() {
+        let (store, pulls, _, _) = store("failed", true);
+        let image_ref = Reference::try_from("example.com/hello:v1").unwrap();
+
+        let gets = (0..3).map(|_| store.get(&image_ref));
+        let modules = futures::future::join_all(gets).await;
+        for module in modules {
+            assert!(module
+                .unwrap_err()
+                .to_string()
+                .contains("registry unavailable"));
         }
+        assert_eq!(1, pulls.load(Ordering::SeqCst));
+
+        // A failed pull is not remembered, so the next request tries again
+        assert!(store.get(&image_ref).await.is_err());
+        assert_eq!(2, pulls.load(Ordering::SeqCst));
+    }
+
+    struct DeltaClient {
+        bases: std::sync::Mutex<Vec<Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl ImageClient for DeltaClient {
+        async fn pull(&self, image: &Reference) -> anyhow::Result<Vec<u8>> {
+            Ok(format!("module {}", image.tag()).into_bytes())
+        }
+
+        async fn pull_from_base(&self, image: &Reference, base: &[u8]) -> anyhow::Result<Vec<u8>> {
+            self.bases.lock().unwrap().push(base.to_vec());
+            self.pull(image).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_from_base() {
+        let dir = std::env::temp_dir().join(format!(
+            "krustlet-module-store-delta-{}",
+            std::process::id()
+        ));
+        let client = DeltaClient {
+            bases: std::sync::Mutex::new(Vec::new()),
+        };
+        let store = FileModuleStore::new(client, &dir);
+        let v1 = Reference::try_from("example.com/hello:v1").unwrap();
+        let v2 = Reference::try_from("example.com/hello:v2").unwrap();
+        let other = Reference::try_from("example.com/other:v1").unwrap();
+
+        // Nothing to pull a delta from yet, even for other repositories
+        store.get(&v1).await.unwrap();
+        store.get(&other).await.unwrap();
+        assert!(store.client.bases.lock().unwrap().is_empty());
+
+        assert_eq!(b"module v2".to_vec(), store.get(&v2).await.unwrap());
+        assert_eq!(
+            vec![b"module v1".to_vec()],
+            *store.client.bases.lock().unwrap()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    struct DigestClient;
+
+    #[async_trait]
+    impl ImageClient for DigestClient {
+        async fn pull(&self, _image: &Reference) -> anyhow::Result<Vec<u8>> {
+            Ok(b"module".to_vec())
+        }
+
+        async fn pull_with_digest(
+            &self,
+            image: &Reference,
+            _base: Option<&[u8]>,
+        ) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+            let digest = format!("sha256:{}", image.tag());
+            Ok((self.pull(image).await?, Some(digest)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_image_digest_is_stored() {
+        let dir = std::env::temp_dir().join(format!(
+            "krustlet-module-store-digest-{}",
+            std::process::id()
+        ));
+        let store = FileModuleStore::new(DigestClient, &dir);
+        let pod = Pod::new(fake_pod("foo", "default"));
+        const IMAGE: &str = "fake.registry.io/foo:v1";
+        assert_eq!(None, store.image_digest(&pod, IMAGE).await);
+
+        store.fetch_pod_modules(&pod).await.unwrap();
+        assert_eq!(
+            Some("sha256:v1"),
+            store.image_digest(&pod, IMAGE).await.as_deref()
+        );
+        let info = ImageInfo {
+            digest: Some("sha256:v1".to_owned()),
+            size: 6,
+        };
+        assert_eq!(Some(info), pod.image_info("foo"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetched_images() {
+        let images = FetchedImages::default();
+        let pod = |kube_pod| Pod::new(kube_pod).with_images(images.clone());
+        let info = ImageInfo {
+            digest: Some("sha256:manifest".to_owned()),
+            size: 6,
+        };
+        let first = pod(fake_pod("foo", "default"));
+        first.record_image("foo", "fake.registry.io/foo:v1", info.clone());
+
+        // Later definitions of the pod see the info while the image is the same
+        let later = pod(fake_pod("foo", "default"));
+        assert_eq!(Some(info), later.image_info("foo"));
+        let mut changed = fake_pod("foo", "default");
+        changed.spec.as_mut().unwrap().containers[0].image =
+            Some("fake.registry.io/foo:v2".to_owned());
+        assert_eq!(None, pod(changed).image_info("foo"));
+        assert_eq!(None, pod(fake_pod("foo", "other")).image_info("foo"));
+
+        images.forget(&first);
+        assert_eq!(None, later.image_info("foo"));
+    }
+
+    #[tokio::test]
+    async fn test_different_images_pull_concurrently() {
+        let (store, pulls, max_in_flight, dir) = store("concurrent", false);
+        let image_refs: Vec<Reference> = (0..3)
+            .map(|i| Reference::try_from(format!("example.com/hello:v{}", i)).unwrap())
+            .collect();
+
+        let gets = image_refs.iter().map(|image_ref| store.get(image_ref));
+        let modules = futures::future::join_all(gets).await;
+        assert!(modules.iter().all(|m| m.as_ref().unwrap() == b"module"));
+        assert_eq!(3, pulls.load(Ordering::SeqCst));
+        assert_eq!(3, max_in_flight.load(Ordering::SeqCst));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pulls_for_pods_are_limited() {
+        let (store, pulls, max_in_flight, dir) = store("limited", false);
+        let pod = Pod::new(fake_pod("hello", "default")).with_concurrency(Limits::new(
+            &crate::config::ConcurrencyConfig {
+                image_pulls: 1,
+                ..Default::default()
+            },
+        ));
+
+        let fetches = (0..3).map(|i| {
+            let image = format!("example.com/hello:v{}", i);
+            let store = &store;
+            let pod = &pod;
+            async move { store.fetch_container_module(pod, &image).await }
+        });
+        let modules = futures::future::join_all(fetches).await;
+        assert!(modules.iter().all(|m| m.as_ref().unwrap() == b"module"));
+        assert_eq!(3, pulls.load(Ordering::SeqCst));
+        assert_eq!(1, max_in_flight.load(Ordering::SeqCst));
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,545 @@
+//! Fetching modules from a Bindle server
+//!
+//! [Bindle](https://github.com/deislabs/bindle) distributes applications as an
+//! invoice, which lists the parcels that make up the application, and the parcels
+//! themselves. The module of a container is the application's WebAssembly parcel.
+//!
+//! A container's image names a bindle instead of an OCI image when it has the
+//! `bindle:` scheme, such as `bindle:example.com/hello/1.0.0`, or when its pod has the
+//! `alpha.krustlet.dev/module-source: bindle` annotation, in which case all of the
+//! pod's images are bindle IDs. Pods with any other value for the annotation fail to
+//! start.
+//!
+//! Parcels are addressed by their digest, so once fetched they can be kept in a cache
+//! directory and are not fetched again for other bindles or pods.
+//!
+//! ```rust,no_run
+//! use kubelet::module_store::bindle::BindleModuleStore;
+//! use kubelet::module_store::FileModuleStore;
+//!
+//! let oci = FileModuleStore::new(oci_distribution::Client::default(), "/var/lib/krustlet/modules");
+//! let store = BindleModuleStore::new(oci)
+//!     .with_server(reqwest::Url::parse("https://bindle.example.com/v1").unwrap())
+//!     .with_cache_dir("/var/lib/krustlet/parcels");
+//! ```
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use oci_distribution::Reference;
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::ModuleStore;
+use crate::annotations::{self, AnnotationConfig, Annotations};
+use crate::concurrency::Operation;
+use crate::pod::Pod;
+
+/// The scheme of images that name a bindle
+pub const SCHEME: &str = "bindle:";
+
+/// The annotation, without the annotation prefix, that selects where the modules of
+/// a pod's containers come from: `oci` (the default) or `bindle`
+pub const ANNOTATION: &str = "module-source";
+
+/// The media type of the parcel that holds an application's module
+const WASM_MEDIA_TYPE: &str = "application/wasm";
+
+/// Whether a pod's images are bindle IDs, according to its annotation
+struct Source {
+    bindle: bool,
+}
+
+impl AnnotationConfig for Source {
+    fn from_annotations(annotations: &mut Annotations) -> Self {
+        let bindle = annotations
+            .parse_with(ANNOTATION, |value| match value {
+                "oci" => Ok(false),
+                "bindle" => Ok(true),
+                other => Err(format!(
+                    "unknown module source {:?}, expected \"oci\" or \"bindle\"",
+                    other
+                )),
+            })
+            .unwrap_or_default();
+        Source { bindle }
+    }
+}
+
+/// Returns the ID of the bindle a container's image names, or `None` if the image is
+/// an OCI image. Fails if the pod's module source annotation is invalid, as its images
+/// can't be told apart then
+pub fn bindle_id<'a>(pod: &Pod, image: &'a str) -> anyhow::Result<Option<&'a str>> {
+    if image.starts_with(SCHEME) {
+        return Ok(image.splitn(2, ':').nth(1));
+    }
+    let (source, errors) = annotations::parse::<Source>(pod);
+    if let Some(error) = errors.into_iter().next() {
+        return Err(error.into());
+    }
+    Ok(if source.bindle { Some(image) } else { None })
+}
+
+/// The URL of the invoice of the bindle with the given ID on the server. The parts of
+/// the ID are escaped as path segments, so an ID can't point elsewhere on the server
+fn invoice_url(server: &Url, id: &str) -> anyhow::Result<Url> {
+    if id
+        .split('/')
+        .any(|part| part.is_empty() || part == "." || part == "..")
+    {
+        anyhow::bail!("invalid bindle ID {:?}", id);
+    }
+    let mut url = server.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Bindle server URL {} can't have a path", server))?
+        .pop_if_empty()
+        .push("_i")
+        .extend(id.split('/'));
+    Ok(url)
+}
+
+#[derive(Debug, Deserialize)]
+struct Invoice {
+    #[serde(default)]
+    parcel: Vec<Parcel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Parcel {
+    label: Label,
+    conditions: Option<Conditions>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Label {
+    sha256: String,
+    media_type: String,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Conditions {
+    #[serde(default)]
+    member_of: Vec<String>,
+}
+
+impl Invoice {
+    /// The application's module: the first WebAssembly parcel that is not part of a
+    /// group, as grouped parcels are optional
+    fn module(&self) -> Option<&Label> {
+        self.parcel
+            .iter()
+            .filter(|parcel| {
+                parcel
+                    .conditions
+                    .as_ref()
+                    .map(|conditions| conditions.member_of.is_empty())
+                    .unwrap_or(true)
+            })
+            .map(|parcel| &parcel.label)
+            .find(|label| label.media_type == WASM_MEDIA_TYPE)
+    }
+}
+
+/// A module store that fetches the modules of bindles from a Bindle server, and all
+/// other modules from another store.
+///
+/// Pods with bindle images fail to start if no server is configured.
+pub struct BindleModuleStore<S> {
+    store: S,
+    server: Option<Url>,
+    cache_dir: Option<PathBuf>,
+    http: reqwest::Client,
+}
+
+impl<S> BindleModuleStore<S> {
+    /// Create a new `BindleModuleStore` that fetches OCI images from the given store
+    pub fn new(store: S) -> Self {
+        BindleModuleStore {
+            store,
+            server: None,
+            cache_dir: None,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Fetch bindles from the server with the given URL, such as
+    /// `https://bindle.example.com/v1`
+    pub fn with_server(mut self, server: Url) -> Self {
+        self.server = Some(server);
+        self
+    }
+
+    /// Keep the parcels fetched from the server in the given directory, by digest
+    pub fn with_cache_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.cache_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    async fn fetch_bindle(&self, id: &str) -> anyhow::Result<Vec<u8>> {
+        let server = self.server.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("{} names a bindle but no Bindle server is configured", id)
+        })?;
+        let invoice_url = invoice_url(server, id)?;
+
+        debug!("Fetching invoice of bindle {} from {}", id, invoice_url);
+        let invoice = self
+            .http
+            .get(invoice_url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let invoice: Invoice = toml::from_str(&invoice)
+            .map_err(|e| anyhow::anyhow!("invalid invoice for bindle {}: {}", id, e))?;
+        let label = invoice
+            .module()
+            .ok_or_else(|| anyhow::anyhow!("bindle {} has no {} parcel", id, WASM_MEDIA_TYPE))?;
+        if label.sha256.is_empty() || !label.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!(
+                "parcel {} of bindle {} has an invalid digest {:?}",
+                label.name,
+                id,
+                label.sha256
+            );
+        }
+
+        if let Some(parcel) = self.cached_parcel(&label.sha256).await {
+            debug!("Using cached parcel {} of bindle {}", label.name, id);
+            return Ok(parcel);
+        }
+
+        debug!("Fetching parcel {} of bindle {}", label.name, id);
+        // The parcel is addressed as `<invoice>@<digest>`
+        let version = id.rsplit('/').next().unwrap_or_default();
+        let mut parcel_url = invoice_url;
+        parcel_url
+            .path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("invalid invoice URL for bindle {}", id))?
+            .pop()
+            .push(&format!("{}@{}", version, label.sha256));
+        let parcel = self
+            .http
+            .get(parcel_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let digest = format!("{:x}", Sha256::digest(&parcel));
+        if digest != label.sha256 {
+            anyhow::bail!(
+                "parcel {} of bindle {} has digest {}, expected {}",
+                label.name,
+                id,
+                digest,
+                label.sha256
+            );
+        }
+        if let Err(e) = self.cache_parcel(&digest, &parcel).await {
+            warn!(
+                "Unable to cache parcel {} of bindle {}: {}",
+                label.name, id, e
+            );
+        }
+        Ok(parcel.to_vec())
+    }
+
+    /// Read the parcel with the given digest from the cache, if it is there and intact
+    async fn cached_parcel(&self, sha256: &str) -> Option<Vec<u8>> {
+        let path = self.cache_dir.as_ref()?.join(sha256);
+        let parcel = tokio::fs::read(&path).await.ok()?;
+        if format!("{:x}", Sha256::digest(&parcel)) == sha256 {
+            Some(parcel)
+        } else {
+            warn!("Cached parcel {:?} is corrupt, fetching it again", path);
+            None
+        }
+    }
+
+    /// Write a parcel to the cache. It is written under another name first, so a
+    /// parcel that is only partly written is never read
+    async fn cache_parcel(&self, sha256: &str, parcel: &[u8]) -> anyhow::Result<()> {
+        let dir = match &self.cache_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        tokio::fs::create_dir_all(dir).await?;
+        let partial = dir.join(format!("{}.partial-{}", sha256, rand::random::<u32>()));
+        tokio::fs::write(&partial, parcel).await?;
+        tokio::fs::rename(&partial, dir.join(sha256)).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: ModuleStore + Send + Sync> ModuleStore for BindleModuleStore<S> {
+    async fn get(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
+        self.store.get(image_ref).await
+    }
+
+    async fn fetch_container_module(&self, pod: &Pod, image: &str) -> anyhow::Result<Vec<u8>> {
+        match bindle_id(pod, image)? {
+            Some(id) => {
+                pod.concurrency()
+                    .run(Operation::ImagePull, self.fetch_bindle(id))
+                    .await
+            }
+            None => self.store.fetch_container_module(pod, image).await,
+        }
+    }
+
+    async fn image_digest(&self, pod: &Pod, image: &str) -> Option<String> {
+        match bindle_id(pod, image) {
+            Ok(None) => self.store.image_digest(pod, image).await,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+    use hyper::service::service_fn;
+    use hyper::{server::conn::Http, Body, Request, Response};
+    use k8s_openapi::api::core::v1::Container as KubeContainer;
+    use tokio::stream::StreamExt;
+
+    const MODULE: &[u8] = b"\0asm module";
+
+    struct OciStore;
+
+    #[async_trait]
+    impl ModuleStore for OciStore {
+        async fn get(&self, _image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
+            Ok(b"oci".to_vec())
+        }
+    }
+
+    fn pod(images: &[&str], source: Option<&str>) -> Pod {
+        let mut pod = fake_pod("app", "default");
+        pod.spec.as_mut().unwrap().containers = images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| KubeContainer {
+                name: format!("container{}", i),
+                image: Some((*image).to_owned()),
+                ..Default::default()
+            })
+            .collect();
+        if let Some(source) = source {
+            pod.metadata.as_mut().unwrap().annotations = Some(
+                vec![(
+                    "alpha.krustlet.dev/module-source".to_owned(),
+                    source.to_owned(),
+                )]
+                .into_iter()
+                .collect(),
+            );
+        }
+        Pod::new(pod)
+    }
+
+    /// Start a Bindle server that serves the `example.com/hello/1.0.0` bindle, whose
+    /// module parcel has the given contents
+    async fn bindle_server(parcel: &'static [u8]) -> Url {
+        let address = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let sha256 = format!("{:x}", Sha256::digest(MODULE));
+        let invoice = format!(
+            r#"
+bindleVersion = "1.0.0"
+
+[bindle]
+name = "example.com/hello"
+version = "1.0.0"
+
+[[parcel]]
+[parcel.label]
+sha256 = "0000"
+mediaType = "application/wasm"
+name = "plugin.wasm"
+size = 4
+[parcel.conditions]
+memberOf = ["plugins"]
+
+[[parcel]]
+[parcel.label]
+sha256 = "{}"
+mediaType = "application/wasm"
+name = "hello.wasm"
+size = {}
+"#,
+            sha256,
+            MODULE.len()
+        );
+        tokio::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(Ok(conn)) = incoming.next().await {
+                let invoice = invoice.clone();
+                let parcel_path = format!("/v1/_i/example.com/hello/1.0.0@{}", sha256);
+                tokio::spawn(async move {
+                    let service = service_fn(move |req: Request<Body>| {
+                        let response = match req.uri().path() {
+                            "/v1/_i/example.com/hello/1.0.0" => {
+                                Response::new(Body::from(invoice.clone()))
+                            }
+                            path if path == parcel_path => Response::new(Body::from(parcel)),
+                            _ => Response::builder().status(404).body(Body::empty()).unwrap(),
+                        };
+                        async move { Ok::<_, hyper::Error>(response) }
+                    });
+                    let _ = Http::new().serve_connection(conn, service).await;
+                });
+            }
+        });
+        Url::parse(&format!("http://{}/v1", address)).unwrap()
+    }
+
+    #[test]
+    fn test_bindle_id() {
+        let oci = pod(&[], None);
+        assert_eq!(
+            Some("example.com/hello/1.0.0"),
+            bindle_id(&oci, "bindle:example.com/hello/1.0.0").unwrap()
+        );
+        assert_eq!(None, bindle_id(&oci, "example.com/hello:v1").unwrap());
+
+        let bindle = pod(&[], Some("bindle"));
+        assert_eq!(
+            Some("example.com/hello/1.0.0"),
+            bindle_id(&bindle, "example.com/hello/1.0.0").unwrap()
+        );
+        let invalid = pod(&[], Some("ftp"));
+        let error = bindle_id(&invalid, "example.com/hello:v1").unwrap_err();
+        assert!(error.to_string().contains("unknown module source"));
+    }
+
+    #[test]
+    fn test_invoice_url() {
+        let server = Url::parse("https://bindle.example.com/v1/").unwrap();
+        assert_eq!(
+            "https://bindle.example.com/v1/_i/example.com/hello/1.0.0",
+            invoice_url(&server, "example.com/hello/1.0.0")
+                .unwrap()
+                .as_str()
+        );
+        assert_eq!(
+            "https://bindle.example.com/v1/_i/hello/1.0.0%3Fyank=true%23",
+            invoice_url(&server, "hello/1.0.0?yank=true#")
+                .unwrap()
+                .as_str()
+        );
+        assert!(invoice_url(&server, "../admin/1.0.0").is_err());
+        assert!(invoice_url(&server, "hello//1.0.0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_pod_modules() {
+        let server = bindle_server(MODULE).await;
+        let store = BindleModuleStore::new(OciStore).with_server(server);
+
+        let modules = store
+            .fetch_pod_modules(&pod(
+                &["bindle:example.com/hello/1.0.0", "example.com/hello:v1"],
+                None,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(MODULE, modules["container0"].as_slice());
+        assert_eq!(b"oci", modules["container1"].as_slice());
+
+        let modules = store
+            .fetch_pod_modules(&pod(&["example.com/hello/1.0.0"], Some("bindle")))
+            .await
+            .unwrap();
+        assert_eq!(MODULE, modules["container0"].as_slice());
+
+        let error = store
+            .fetch_pod_modules(&pod(&["bindle:example.com/missing/1.0.0"], None))
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("bindle:example.com/missing/1.0.0"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_source_fails_the_pod() {
+        let server = bindle_server(MODULE).await;
+        let store = BindleModuleStore::new(OciStore).with_server(server);
+        let error = store
+            .fetch_pod_modules(&pod(&["example.com/hello:v1"], Some("ftp")))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("unknown module source"));
+    }
+
+    #[tokio::test]
+    async fn test_parcels_are_cached() {
+        let dir = std::env::temp_dir().join(format!("krustlet-parcels-{}", std::process::id()));
+        let server = bindle_server(MODULE).await;
+        let store = BindleModuleStore::new(OciStore)
+            .with_server(server)
+            .with_cache_dir(&dir);
+        let image = "bindle:example.com/hello/1.0.0";
+        assert_eq!(
+            MODULE,
+            store
+                .fetch_container_module(&pod(&[], None), image)
+                .await
+                .unwrap()
+                .as_slice()
+        );
+        let cached = dir.join(format!("{:x}", Sha256::digest(MODULE)));
+        assert_eq!(MODULE, std::fs::read(&cached).unwrap().as_slice());
+
+        // A server that now serves a tampered parcel isn't asked for it again
+        let tampered = BindleModuleStore::new(OciStore)
+            .with_server(bindle_server(b"tampered").await)
+            .with_cache_dir(&dir);
+        assert_eq!(
+            MODULE,
+            tampered
+                .fetch_container_module(&pod(&[], None), image)
+                .await
+                .unwrap()
+                .as_slice()
+        );
+
+        // A corrupt cached parcel is fetched again
+        std::fs::write(&cached, b"corrupt").unwrap();
+        assert!(tampered
+            .fetch_container_module(&pod(&[], None), image)
+            .await
+            .is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_parcel_digest_is_verified() {
+        let server = bindle_server(b"tampered").await;
+        let store = BindleModuleStore::new(OciStore).with_server(server);
+        let error = store
+            .fetch_container_module(&pod(&[], None), "bindle:example.com/hello/1.0.0")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("hello.wasm"));
+    }
+
+    #[tokio::test]
+    async fn test_no_server() {
+        let store = BindleModuleStore::new(OciStore);
+        let error = store
+            .fetch_container_module(&pod(&[], None), "bindle:example.com/hello/1.0.0")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("no Bindle server"));
+    }
+}
@@ -0,0 +1,447 @@
+//! A module store backed by an S3-compatible object store
+//!
+//! Nodes that share a bucket share their cache: a module is only pulled from its
+//! registry by the first node that needs it, and every other node reads it from the
+//! bucket. The store talks to any service that implements the S3 object API with
+//! path-style addressing and AWS Signature Version 4, such as AWS S3 or MinIO.
+//!
+//! Reading from the bucket is a network request, so the store is usually layered
+//! under a [`FileModuleStore`](super::FileModuleStore), which keeps a copy of each
+//! module on the node's disk:
+//!
+//! ```rust,no_run
+//! use kubelet::module_store::s3::{S3Config, S3ModuleStore};
+//! use kubelet::module_store::FileModuleStore;
+//!
+//! let config = S3Config::new(
+//!     reqwest::Url::parse("https://s3.us-east-1.amazonaws.com").unwrap(),
+//!     "krustlet-modules",
+//!     "us-east-1",
+//!     &std::env::var("AWS_ACCESS_KEY_ID").unwrap(),
+//!     &std::env::var("AWS_SECRET_ACCESS_KEY").unwrap(),
+//! );
+//! let bucket = S3ModuleStore::new(config, oci_distribution::Client::default());
+//! let store = FileModuleStore::new(bucket, "/var/lib/krustlet/modules");
+//! ```
+use async_trait::async_trait;
+use log::{debug, warn};
+use oci_distribution::Reference;
+use reqwest::{Method, StatusCode, Url};
+use rusoto_signature::credential::AwsCredentials;
+use rusoto_signature::{Region, SignedRequest};
+use std::convert::TryFrom;
+
+use super::ModuleStore;
+use crate::concurrency::{Limits, Operation};
+use crate::image_client::ImageClient;
+use crate::pod::Pod;
+
+/// Where a [`S3ModuleStore`] keeps its modules and how it authenticates
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// The URL of the object store, such as `https://s3.us-east-1.amazonaws.com`
+    pub endpoint: Url,
+    /// The bucket modules are stored in
+    pub bucket: String,
+    /// The region of the bucket, used to sign requests
+    pub region: String,
+    /// The ID of the access key requests are signed with
+    pub access_key_id: String,
+    /// The secret of the access key requests are signed with
+    pub secret_access_key: String,
+    /// A prefix for the keys of the modules, such as `modules/`. Empty by default
+    pub prefix: String,
+}
+
+impl S3Config {
+    /// Create a config for the given bucket, storing modules at the root of the bucket
+    pub fn new(
+        endpoint: Url,
+        bucket: &str,
+        region: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+    ) -> Self {
+        S3Config {
+            endpoint,
+            bucket: bucket.to_owned(),
+            region: region.to_owned(),
+            access_key_id: access_key_id.to_owned(),
+            secret_access_key: secret_access_key.to_owned(),
+            prefix: String::new(),
+        }
+    }
+
+    /// Store modules under the given prefix
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_owned();
+        self
+    }
+}
+
+/// A module store that keeps modules in an S3-compatible bucket.
+///
+/// Modules are stored under the digest of their image's manifest, which the
+/// [`ImageClient`] looks up each time a module is fetched, so a tag that has moved to
+/// another image is not served from the bucket's copy of the old one. Modules that are
+/// not in the bucket yet are pulled with the client and uploaded. If the bucket can't
+/// be reached, or the client can't tell the digest of an image, modules are pulled
+/// with the client instead, so an outage of the shared cache does not stop pods from
+/// starting.
+///
+/// The store is itself an [`ImageClient`], so it can be used as the client of another
+/// store.
+pub struct S3ModuleStore<C> {
+    config: S3Config,
+    client: C,
+    http: reqwest::Client,
+}
+
+impl<C> S3ModuleStore<C> {
+    /// Create a new `S3ModuleStore` that pulls missing modules with the given client
+    pub fn new(config: S3Config, client: C) -> Self {
+        S3ModuleStore {
+            config,
+            client,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// The key of the object the module of the image with the given manifest digest is
+    /// stored in
+    fn key(&self, image_ref: &Reference, digest: &str) -> String {
+        format!(
+            "{}{}/{}/{}/module.wasm",
+            self.config.prefix,
+            image_ref.registry(),
+            image_ref.repository(),
+            digest
+        )
+    }
+
+    /// Send a request for an object, signed with AWS Signature Version 4
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Vec<u8>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let region = Region::Custom {
+            name: self.config.region.clone(),
+            endpoint: self
+                .config
+                .endpoint
+                .as_str()
+                .trim_end_matches('/')
+                .to_owned(),
+        };
+        let mut signed = SignedRequest::new(
+            method.as_str(),
+            "s3",
+            &region,
+            &format!("/{}/{}", self.config.bucket, key),
+        );
+        signed.set_payload(Some(body.clone()));
+        signed.sign(&AwsCredentials::new(
+            self.config.access_key_id.clone(),
+            self.config.secret_access_key.clone(),
+            None,
+            None,
+        ));
+
+        let url = Url::parse(&format!(
+            "{}://{}{}",
+            signed.scheme(),
+            signed.hostname(),
+            signed.canonical_uri()
+        ))?;
+        let mut request = self.http.request(method, url).body(body);
+        for (name, values) in signed.headers() {
+            // The client sets these from the URL and the body
+            if name == "host" || name == "content-length" {
+                continue;
+            }
+            for value in values {
+                request = request.header(name.as_str(), value.as_slice());
+            }
+        }
+        Ok(request.send().await?)
+    }
+
+    /// Read a module from the bucket, or `None` if it is not there
+    async fn download(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let response = self.send(Method::GET, key, Vec::new()).await?;
+        match response.status() {
+            StatusCode::OK => Ok(Some(response.bytes().await?.to_vec())),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => anyhow::bail!("object store returned {} for {}", status, key),
+        }
+    }
+
+    /// Write a module to the bucket
+    async fn upload(&self, key: &str, module: &[u8]) -> anyhow::Result<()> {
+        let response = self.send(Method::PUT, key, module.to_vec()).await?;
+        if !response.status().is_success() {
+            anyhow::bail!("object store returned {} for {}", response.status(), key);
+        }
+        Ok(())
+    }
+}
+
+impl<C: ImageClient + Send + Sync> S3ModuleStore<C> {
+    /// Pull the image with the client under the given limits, without going through the
+    /// bucket
+    async fn pull_from_registry(
+        &self,
+        image_ref: &Reference,
+        limits: &Limits,
+    ) -> anyhow::Result<Vec<u8>> {
+        limits
+            .run(Operation::ImagePull, self.client.pull(image_ref))
+            .await
+    }
+
+    /// Get a module from the bucket, or pull it under the given limits if it isn't
+    /// there
+    async fn fetch(&self, image_ref: &Reference, limits: &Limits) -> anyhow::Result<Vec<u8>> {
+        let digest = match self.client.digest(image_ref).await {
+            Ok(Some(digest)) => digest,
+            Ok(None) => {
+                debug!(
+                    "The digest of image ref '{:?}' is unknown, pulling from the registry",
+                    image_ref
+                );
+                return self.pull_from_registry(image_ref, limits).await;
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to look up the digest of image ref '{:?}', pulling from the registry instead: {:?}",
+                    image_ref, e
+                );
+                return self.pull_from_registry(image_ref, limits).await;
+            }
+        };
+        let key = self.key(image_ref, &digest);
+        let cached = match self.download(&key).await {
+            Ok(cached) => cached,
+            Err(e) => {
+                warn!(
+                    "Unable to read {} from bucket {}, pulling from the registry instead: {:?}",
+                    key, self.config.bucket, e
+                );
+                return self.pull_from_registry(image_ref, limits).await;
+            }
+        };
+        if let Some(module) = cached {
+            debug!("Fetched image ref '{:?}' from the object store", image_ref);
+            return Ok(module);
+        }
+
+        debug!(
+            "Image ref '{:?}' doesn't exist in the object store. Fetching remotely...",
+            image_ref
+        );
+        let (module, pulled) = limits
+            .run(
+                Operation::ImagePull,
+                self.client.pull_with_digest(image_ref, None),
+            )
+            .await?;
+        // The tag may have moved since the digest was looked up
+        let key = match pulled {
+            Some(pulled) if pulled != digest => self.key(image_ref, &pulled),
+            _ => key,
+        };
+        if let Err(e) = self.upload(&key, &module).await {
+            warn!(
+                "Unable to write {} to bucket {}: {:?}",
+                key, self.config.bucket, e
+            );
+        }
+        Ok(module)
+    }
+}
+
+#[async_trait]
+impl<C: ImageClient + Send + Sync> ModuleStore for S3ModuleStore<C> {
+    async fn get(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
+        self.fetch(image_ref, &Limits::default()).await
+    }
+
+    /// Modules that are not in the bucket are pulled under the pod's limits
+    async fn fetch_container_module(&self, pod: &Pod, image: &str) -> anyhow::Result<Vec<u8>> {
+        self.fetch(&Reference::try_from(image)?, pod.concurrency())
+            .await
+    }
+}
+
+/// Layered under another store, the store that pulls from the bucket limits the pull
+#[async_trait]
+impl<C: ImageClient + Send + Sync> ImageClient for S3ModuleStore<C> {
+    async fn pull(&self, image: &Reference) -> anyhow::Result<Vec<u8>> {
+        self.get(image).await
+    }
+
+    async fn digest(&self, image: &Reference) -> anyhow::Result<Option<String>> {
+        self.client.digest(image).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::service::service_fn;
+    use hyper::{server::conn::Http, Body, Request, Response};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::stream::StreamExt;
+
+    /// A client whose image is the digest the tag points to, or that doesn't know
+    /// digests when there is none
+    struct CountingClient {
+        pulls: Arc<AtomicUsize>,
+        digest: Arc<Mutex<Option<String>>>,
+    }
+
+    #[async_trait]
+    impl ImageClient for CountingClient {
+        async fn pull(&self, _image: &Reference) -> anyhow::Result<Vec<u8>> {
+            self.pulls.fetch_add(1, Ordering::SeqCst);
+            let digest = self.digest.lock().unwrap().clone();
+            Ok(digest.unwrap_or_default().into_bytes())
+        }
+
+        async fn digest(&self, _image: &Reference) -> anyhow::Result<Option<String>> {
+            Ok(self.digest.lock().unwrap().clone())
+        }
+    }
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Start an object store that keeps objects in memory, refusing unsigned requests
+    async fn object_store() -> (Url, Objects) {
+        let address = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let objects: Objects = Default::default();
+        let server_objects = objects.clone();
+        tokio::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(Ok(conn)) = incoming.next().await {
+                let objects = server_objects.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req: Request<Body>| {
+                        let objects = objects.clone();
+                        async move {
+                            let signed = req
+                                .headers()
+                                .get("Authorization")
+                                .map(|value| {
+                                    let value = value.to_str().unwrap();
+                                    value.starts_with("AWS4-HMAC-SHA256 Credential=id/")
+                                        && value.contains("/us-east-1/s3/aws4_request")
+                                        && req.headers().contains_key("x-amz-date")
+                                        && req.headers().contains_key("x-amz-content-sha256")
+                                })
+                                .unwrap_or_default();
+                            let path = req.uri().path().to_owned();
+                            let (status, body) = if !signed {
+                                (403, Vec::new())
+                            } else if req.method() == hyper::Method::PUT {
+                                let body = hyper::body::to_bytes(req.into_body()).await?;
+                                objects.lock().unwrap().insert(path, body.to_vec());
+                                (200, Vec::new())
+                            } else {
+                                match objects.lock().unwrap().get(&path) {
+                                    Some(object) => (200, object.clone()),
+                                    None => (404, Vec::new()),
+                                }
+                            };
+                            Ok::<_, hyper::Error>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(Body::from(body))
+                                    .unwrap(),
+                            )
+                        }
+                    });
+                    let _ = Http::new().serve_connection(conn, service).await;
+                });
+            }
+        });
+        let url = Url::parse(&format!("http://{}", address)).unwrap();
+        (url, objects)
+    }
+
+    fn store(
+        endpoint: Url,
+        pulls: Arc<AtomicUsize>,
+        digest: Arc<Mutex<Option<String>>>,
+    ) -> S3ModuleStore<CountingClient> {
+        let config =
+            S3Config::new(endpoint, "modules", "us-east-1", "id", "secret").with_prefix("cache/");
+        S3ModuleStore::new(config, CountingClient { pulls, digest })
+    }
+
+    #[tokio::test]
+    async fn test_nodes_share_modules() {
+        let (endpoint, objects) = object_store().await;
+        let pulls = Arc::new(AtomicUsize::new(0));
+        let digest = Arc::new(Mutex::new(Some("sha256:one".to_owned())));
+        let image_ref = Reference::try_from("localhost:5000/hello:v1").unwrap();
+
+        let first = store(endpoint.clone(), pulls.clone(), digest.clone());
+        assert_eq!(b"sha256:one".to_vec(), first.get(&image_ref).await.unwrap());
+        assert_eq!(1, pulls.load(Ordering::SeqCst));
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key("/modules/cache/localhost%3A5000/hello/sha256%3Aone/module.wasm"));
+
+        // Another node reads the module from the bucket
+        let second = store(endpoint, pulls.clone(), digest);
+        assert_eq!(
+            b"sha256:one".to_vec(),
+            second.get(&image_ref).await.unwrap()
+        );
+        assert_eq!(1, pulls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_moved_tag_is_pulled_again() {
+        let (endpoint, objects) = object_store().await;
+        let pulls = Arc::new(AtomicUsize::new(0));
+        let digest = Arc::new(Mutex::new(Some("sha256:one".to_owned())));
+        let image_ref = Reference::try_from("example.com/hello:latest").unwrap();
+        let store = store(endpoint, pulls.clone(), digest.clone());
+        store.get(&image_ref).await.unwrap();
+
+        // The tag now points to another image, which the bucket doesn't have yet
+        *digest.lock().unwrap() = Some("sha256:two".to_owned());
+        assert_eq!(b"sha256:two".to_vec(), store.get(&image_ref).await.unwrap());
+        assert_eq!(2, pulls.load(Ordering::SeqCst));
+        assert_eq!(2, objects.lock().unwrap().len());
+
+        // Without a digest the bucket isn't used at all
+        *digest.lock().unwrap() = None;
+        store.get(&image_ref).await.unwrap();
+        assert_eq!(3, pulls.load(Ordering::SeqCst));
+        assert_eq!(2, objects.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_store_pulls_from_registry() {
+        // Nothing listens on the port once the listener is dropped
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+        let pulls = Arc::new(AtomicUsize::new(0));
+        let digest = Arc::new(Mutex::new(Some("sha256:one".to_owned())));
+        let image_ref = Reference::try_from("example.com/hello:v1").unwrap();
+
+        let store = store(endpoint, pulls.clone(), digest);
+        assert_eq!(b"sha256:one".to_vec(), store.get(&image_ref).await.unwrap());
+        assert_eq!(1, pulls.load(Ordering::SeqCst));
+    }
+}
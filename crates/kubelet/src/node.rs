@@ -1,13 +1,33 @@
-use crate::config::Config;
+use crate::autoscaling::{
+    AVAILABLE_PODS_ANNOTATION, PENDING_PODS_ANNOTATION, PODS_PENDING_CONDITION,
+};
+use crate::config::{AdoptionPolicy, Config};
+use crate::error::KubeletError;
+use crate::events::EventRecorder;
+use crate::lifecycle::Lifecycle;
+use crate::rate_limit::RateLimits;
+use crate::stats::parse_quantity;
+use crate::status::{apply_params, apply_params_as};
+use crate::Provider;
+use anyhow::Context;
 use chrono::prelude::*;
 use k8s_openapi::api::coordination::v1::Lease;
-use k8s_openapi::api::core::v1::Node;
+use k8s_openapi::api::core::v1::{
+    DaemonEndpoint, Node, NodeAddress, NodeCondition, NodeDaemonEndpoints, NodeSpec, NodeStatus,
+    NodeSystemInfo, Taint,
+};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
-use kube::api::{Api, DeleteParams, PatchParams, PostParams};
+use k8s_openapi::apimachinery::pkg::version::Info;
+use kube::api::{Api, ObjectMeta, PatchParams, PostParams};
 use kube::error::ErrorResponse;
 use kube::Error;
 use log::{debug, error, info, warn};
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 macro_rules! retry {
     ($action:expr, times: $num_times:expr, error: $on_err:expr) => {{
@@ -49,55 +69,145 @@ macro_rules! retry {
     };
 }
 
+/// The annotation recording which Kubelet instance registered a node, as the instance ID
+/// the Kubelet keeps in its data directory
+pub const NODE_OWNER_ANNOTATION: &str = "alpha.krustlet.dev/owner";
+
+/// The file in the data directory the ID of the Kubelet instance is kept in
+const INSTANCE_ID_FILE: &str = "instance-id";
+
+/// The labels the node reports the provider's architecture in, the current one and the
+/// deprecated one that older workloads still select
+pub(crate) const ARCH_LABELS: &[&str] = &["kubernetes.io/arch", "beta.kubernetes.io/arch"];
+/// The labels the node reports the provider's operating system in
+pub(crate) const OS_LABELS: &[&str] = &["kubernetes.io/os", "beta.kubernetes.io/os"];
+
+/// How many times the node is registered again when an existing node with its name
+/// turns out to be deleted
+const REGISTRATION_ATTEMPTS: u32 = 5;
+
 /// Create a node
 ///
-/// This creates a Kubernetes Node that describes our Kubelet, failing with a log message
-/// if one already exists. If one does exist, we simply re-use it. You may call that
-/// hacky, but I call it... hacky.
+/// This creates a Kubernetes Node that describes our Kubelet. If one already exists, the
+/// Kubelet's [`AdoptionPolicy`] decides whether it is adopted. An adopted node has the node
+/// definition applied to it with server-side apply, which leaves fields managed by
+/// anyone else (such as labels added by other tools) in place, unless the policy is to
+/// overwrite them. Either way, the pod CIDRs and provider ID the control plane assigned
+/// to the node are kept. A node that may not be adopted is returned as a
+/// [`KubeletError::NodeConflict`], and any other failure as a
+/// [`KubeletError::Registration`].
+///
+/// When the existing node is being deleted, or is deleted before it can be adopted,
+/// registration is tried again with a backoff so the node is created afresh.
 ///
 /// A node comes with a lease, and we maintain the lease to tell Kubernetes that the
 /// node remains alive and functional. Note that this will not work in
 /// versions of Kubernetes prior to 1.14.
-pub async fn create_node(client: &kube::Client, config: &Config, arch: &str) {
+///
+/// Returns the node definition that was applied, which should be passed to
+/// [`update_node`] so it can tell when the node status has changed.
+pub async fn create_node<P: Provider + Sync>(
+    client: &kube::Client,
+    config: &Config,
+    limits: &RateLimits,
+    provider: &P,
+) -> Result<Node, KubeletError> {
+    // The node can't be shutting down before it is registered
+    let node = build_node(config, provider, &Readiness::default(), None)
+        .await
+        .map_err(KubeletError::NodeDefinition)?;
+
+    let mut backoff = Duration::from_millis(100);
+    for attempt in 1..=REGISTRATION_ATTEMPTS {
+        match register(client, config, limits, &node).await? {
+            Registration::Done => {
+                info!("Successfully created node '{}'", &config.node_name);
+                return Ok(node);
+            }
+            Registration::Deleted if attempt < REGISTRATION_ATTEMPTS => {
+                info!(
+                    "Existing node '{}' is being deleted, registering again in {:?}",
+                    &config.node_name, backoff
+                );
+                tokio::time::delay_for(backoff).await;
+                backoff *= 2;
+            }
+            Registration::Deleted => (),
+        }
+    }
+    Err(KubeletError::NodeConflict {
+        node_name: config.node_name.clone(),
+        reason: "it is still being deleted".to_owned(),
+    })
+}
+
+/// The outcome of an attempt at registering the node
+enum Registration {
+    /// The node was created or adopted
+    Done,
+    /// The existing node is being deleted, or was deleted before it could be adopted
+    Deleted,
+}
+
+async fn register(
+    client: &kube::Client,
+    config: &Config,
+    limits: &RateLimits,
+    node: &Node,
+) -> Result<Registration, KubeletError> {
     let node_client: Api<Node> = Api::all(client.clone());
-    let node = node_definition(config, arch);
-    let node =
-        serde_json::from_value(node).expect("failed to deserialize node from node definition JSON");
+    let registration_error = |source| KubeletError::Registration {
+        node_name: config.node_name.clone(),
+        source,
+    };
 
-    match retry!(node_client.create(&PostParams::default(), &node).await, times: 4, break_on: &Error::Api(ErrorResponse { code: 409, .. }))
+    match retry!(limits.limited(node_client.create(&PostParams::default(), node)).await, times: 4, break_on: &Error::Api(ErrorResponse { code: 409, .. }))
     {
         Ok(node) => {
             let node_uid = node.metadata.unwrap().uid.unwrap();
-            if let Err(e) = create_lease(&node_uid, &config.node_name, &client).await {
+            if let Err(e) = create_lease(&node_uid, &config.node_name, &client, limits).await {
                 error!("Failed to create lease: {}", e);
-                return;
+                return Err(registration_error(e));
             }
+            Ok(Registration::Done)
         }
         Err(Error::Api(ErrorResponse { code: 409, .. })) => {
-            debug!(
-                "Node '{}' exists already. Going to fetch existing node...",
-                &config.node_name
-            );
-
-            if let Err(e) = retry!(node_client.get(&config.node_name).await, times: 4, log_error: |e| debug!(
-                "Error fetching node after failed create: {}. Retrying...",
-                e
-            )) {
-                error!(
-                    "Exhausted retries fetching node after failed create: {}. Not retrying.",
-                    e
-                );
-                return;
+            let existing = match limits.limited(node_client.get(&config.node_name)).await {
+                Ok(existing) => existing,
+                Err(Error::Api(ErrorResponse { code: 404, .. })) => {
+                    return Ok(Registration::Deleted)
+                }
+                Err(e) => return Err(registration_error(e)),
+            };
+            let metadata = existing.metadata.clone().unwrap_or_default();
+            if metadata.deletion_timestamp.is_some() {
+                return Ok(Registration::Deleted);
+            }
+            if let Some(reason) = adoption_conflict(config, &existing, node) {
+                error!("Node '{}' cannot be adopted: {}", &config.node_name, reason);
+                return Err(KubeletError::NodeConflict {
+                    node_name: config.node_name.clone(),
+                    reason,
+                });
             }
-
             debug!(
-                "Node '{}' found, updating current node definition...",
+                "Node '{}' exists already, applying current node definition...",
                 &config.node_name
             );
+            let node = keep_assigned_fields(node, existing);
 
-            if let Err(e) = replace_node(client, &config.node_name, &node).await {
-                error!("Failed to replace node: {}.", e);
-                return;
+            let applied = if config.node_adoption == AdoptionPolicy::Overwrite {
+                overwrite_node(client, limits, &config.node_name, metadata, &node).await
+            } else {
+                apply_node(client, limits, &config.node_name, &node).await
+            };
+            match applied {
+                Ok(()) => Ok(Registration::Done),
+                Err(Error::Api(ErrorResponse { code: 404, .. })) => Ok(Registration::Deleted),
+                Err(e) => {
+                    error!("Failed to apply node: {}.", e);
+                    Err(registration_error(e))
+                }
             }
         }
         Err(e) => {
@@ -105,29 +215,676 @@ pub async fn create_node(client: &kube::Client, config: &Config, arch: &str) {
                 "Exhausted retries creating node after failed create: {}. Not retrying.",
                 e
             );
+            Err(registration_error(e))
+        }
+    }
+}
+
+/// The node definition with the pod CIDRs and provider ID of the existing node, which
+/// are assigned to it by the control plane and can't be changed once they are set
+fn keep_assigned_fields(node: &Node, existing: Node) -> Node {
+    let mut node = node.clone();
+    if let Some(existing) = existing.spec {
+        let spec = node.spec.get_or_insert_with(Default::default);
+        if existing.pod_cidr.is_some() {
+            spec.pod_cidr = existing.pod_cidr;
+            spec.pod_cidrs = existing.pod_cidrs;
+        }
+        if existing.provider_id.is_some() {
+            spec.provider_id = existing.provider_id;
+        }
+    }
+    node
+}
+
+/// Returns why the existing node may not be adopted under the Kubelet's adoption
+/// policy, if it may not
+fn adoption_conflict(config: &Config, existing: &Node, node: &Node) -> Option<String> {
+    let existing = existing.metadata.as_ref();
+    match config.node_adoption {
+        AdoptionPolicy::Adopt | AdoptionPolicy::Overwrite => None,
+        AdoptionPolicy::MatchingLabels => {
+            let labels = existing.and_then(|m| m.labels.as_ref());
+            node.metadata
+                .as_ref()
+                .and_then(|m| m.labels.as_ref())
+                .into_iter()
+                .flatten()
+                .find(|(key, value)| labels.and_then(|l| l.get(*key)) != Some(value))
+                .map(|(key, value)| format!("it does not have the label {}={}", key, value))
+        }
+        AdoptionPolicy::Unowned => {
+            fn owner(metadata: Option<&ObjectMeta>) -> Option<&String> {
+                metadata?.annotations.as_ref()?.get(NODE_OWNER_ANNOTATION)
+            }
+            let existing_owner = owner(existing)?;
+            if owner(node.metadata.as_ref()) == Some(existing_owner) {
+                None
+            } else {
+                Some(format!("it is owned by {}", existing_owner))
+            }
+        }
+    }
+}
+
+/// The owner recorded on the node in its [`NODE_OWNER_ANNOTATION`]: the ID of the
+/// Kubelet instance, which is generated the first time the Kubelet runs with its data
+/// directory. Unlike the hostname, it tells apart Kubelets on machines that share a
+/// name, and it stays the same when the node is renamed.
+fn node_owner(config: &Config) -> std::io::Result<String> {
+    let path = config.data_dir.join(INSTANCE_ID_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(id) if !id.trim().is_empty() => return Ok(id.trim().to_owned()),
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    let id: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(32)
+        .collect();
+    std::fs::create_dir_all(&config.data_dir)?;
+    std::fs::write(&path, &id)?;
+    Ok(id)
+}
+
+/// How many minor versions the API server can be ahead of the Kubernetes version the
+/// Kubelet reports, following the Kubernetes version skew policy
+const MAX_MINOR_VERSION_SKEW: u32 = 2;
+
+/// Record a `Starting` event on the registered node with the Krustlet version and
+/// provider, so operators can tell which nodes of a fleet run what.
+///
+/// The Kubernetes version the node reports is also compared with the API server's. If
+/// the node is newer, or more than two minor versions older, the skew is unsupported
+/// and an `UnsupportedVersionSkew` warning is logged and recorded on the node.
+pub(crate) async fn record_registration<P: Provider>(
+    events: &EventRecorder,
+    client: &kube::Client,
+    config: &Config,
+    limits: &RateLimits,
+    node: &Node,
+) {
+    let kubelet_version = node
+        .status
+        .as_ref()
+        .and_then(|s| s.node_info.as_ref())
+        .map(|info| info.kubelet_version.as_str())
+        .unwrap_or_default();
+    let message = format!(
+        "Starting krustlet {} with provider {} (Kubernetes {})",
+        crate::version::info(),
+        P::ARCH,
+        kubelet_version
+    );
+    crate::events::record_node_event(
+        events,
+        client,
+        limits,
+        &config.node_name,
+        "Normal",
+        "Starting",
+        &message,
+    )
+    .await;
+
+    let request = hyper::Request::get("/version")
+        .body(Vec::new())
+        .expect("version request should always build");
+    let api_server: Info = match limits.limited(client.request(request)).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!("Unable to check the API server version: {}", e);
             return;
         }
     };
+    if let Some(skew) = version_skew(kubelet_version, &api_server) {
+        warn!("{}", skew);
+        crate::events::record_node_event(
+            events,
+            client,
+            limits,
+            &config.node_name,
+            "Warning",
+            "UnsupportedVersionSkew",
+            &skew,
+        )
+        .await;
+    }
+}
 
-    info!("Successfully created node '{}'", &config.node_name);
+/// Describes the skew between the Kubernetes version of the node and the API server
+/// version, if it is unsupported. Versions that cannot be parsed are not compared
+fn version_skew(kubelet_version: &str, api_server: &Info) -> Option<String> {
+    let kubelet = parse_version(kubelet_version.trim_start_matches('v'))?;
+    let server = parse_version(&format!("{}.{}", api_server.major, api_server.minor))?;
+    let supported = kubelet.0 == server.0
+        && kubelet.1 <= server.1
+        && server.1 - kubelet.1 <= MAX_MINOR_VERSION_SKEW;
+    if supported {
+        return None;
+    }
+    Some(format!(
+        "Kubernetes {} reported by the node is not supported by API server version {}: \
+         the node may be at most {} minor versions older than the API server, and not newer",
+        kubelet_version, api_server.git_version, MAX_MINOR_VERSION_SKEW
+    ))
 }
 
-/// Update the timestamps on the Node object.
+/// Parses the major and minor version from a version such as `1.17.0`. Managed
+/// clusters report minor versions such as `17+`, so anything after the digits of each
+/// part is ignored
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.').map(|part| {
+        let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+        digits.parse::<u32>().ok()
+    });
+    Some((parts.next()??, parts.next()??))
+}
+
+/// Renew the node lease and bring the node status up to date.
 ///
-/// This is how we report liveness to the upstream.
+/// This is how we report liveness to the upstream. The heartbeat goes over the
+/// lease, and the node status is only patched when the provider's node
+/// definition differs from `last_applied` in something other than condition
+/// timestamps. `last_applied` is updated whenever a patch succeeds, so a fleet of
+/// idle nodes does not rewrite its node objects on every heartbeat.
+///
+/// Labels and taints of the node definition that were removed from the node or changed
+/// are restored, so the pods' scheduling constraints keep working. Labels and taints
+/// added by others are left in place. See [`restore_labels`] and [`restore_taints`].
 ///
 /// We trap errors because... well... quite frankly there is nothing useful
 /// to do if the Kubernetes API is unavailable, and we can merrily continue
 /// doing our processing of the pod queue.
-pub async fn update_node(client: &kube::Client, node_name: &str) {
+///
+/// The requests are heartbeats, so they are made under the heartbeat rate limit.
+pub async fn update_node<P: Provider + Sync>(
+    client: &kube::Client,
+    config: &Config,
+    limits: &RateLimits,
+    provider: &P,
+    readiness: &Readiness,
+    last_applied: &mut Node,
+    refusals: &mut Refusals,
+) {
+    let limits = &limits.for_heartbeats();
+    let node_name = &config.node_name;
     debug!("Updating node '{}'", node_name);
     let node_client: Api<Node> = Api::all(client.clone());
-    if let Ok(node) = retry!(node_client.get(node_name).await, times: 4, log_error: |e| error!("Failed to get node to update: {:?}", e))
+    let node = match retry!(limits.limited(node_client.get(node_name)).await, times: 4, log_error: |e| error!("Failed to get node to update: {:?}", e))
     {
-        debug!("Node to update '{}' fetched.", node_name);
-        let uid = node.metadata.and_then(|m| m.uid).unwrap();
-        retry!(update_lease(&uid, node_name, client).await, times: 4)
-            .expect("Could not update lease");
+        Ok(node) => node,
+        Err(_) => return,
+    };
+    debug!("Node to update '{}' fetched.", node_name);
+    let uid = node.metadata.as_ref().and_then(|m| m.uid.clone()).unwrap();
+    retry!(update_lease(&uid, node_name, client, limits).await, times: 4)
+        .expect("Could not update lease");
+
+    let mut desired = match build_node(config, provider, readiness, Some(last_applied)).await {
+        Ok(node) => node,
+        Err(e) => {
+            error!("Failed to build node definition for '{}': {}", node_name, e);
+            return;
+        }
+    };
+    // Restored apart, so a node that may not change its taints still restores its labels
+    if !refusals.labels {
+        if let Some(patch) = restore_labels(&node, &desired) {
+            refusals.labels = restore(
+                &node_client,
+                limits,
+                node_name,
+                "labels",
+                LABELS_FIELD_MANAGER,
+                patch,
+            )
+            .await;
+        }
+    }
+    if !refusals.taints {
+        if let Some(patch) = restore_taints(&node, &desired) {
+            refusals.taints = restore(
+                &node_client,
+                limits,
+                node_name,
+                "taints",
+                TAINTS_FIELD_MANAGER,
+                patch,
+            )
+            .await;
+        }
+    }
+    let annotations = scaling_annotations(&desired);
+    if annotations != scaling_annotations(last_applied) {
+        // Left for the next update if it fails, which doesn't hold up the status
+        match patch_annotations(client, limits, node_name, annotations).await {
+            Ok(_) => {
+                debug!("Patched scaling hints of node '{}'", node_name);
+                last_applied.metadata = desired.metadata.clone();
+            }
+            Err(e) => error!(
+                "Failed to patch scaling hints of node '{}': {}",
+                node_name, e
+            ),
+        }
+    }
+    if !status_changed(last_applied, &desired) {
+        debug!("Status of node '{}' unchanged, skipping patch", node_name);
+        return;
+    }
+    keep_transition_times(last_applied, &mut desired);
+    match apply_node_status(client, limits, node_name, &desired).await {
+        Ok(_) => {
+            debug!("Patched status of node '{}'", node_name);
+            // The metadata is only brought up to date by the patches above
+            desired.metadata = last_applied.metadata.take();
+            *last_applied = desired;
+        }
+        Err(e) => error!("Failed to patch status of node '{}': {}", node_name, e),
+    }
+}
+
+/// How long the watch on the node's pods can be disconnected before the node is
+/// reported as not ready, as the Kubelet no longer learns about pods scheduled to it
+const INFORMER_DISCONNECT_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// What the Kubelet knows about whether its node can run pods, beyond the provider's
+/// health. Clones share the same state
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Readiness {
+    /// Where changes to whether the node is ready are sent
+    lifecycle: Lifecycle,
+    shutting_down: Arc<AtomicBool>,
+    /// When the watch on the node's pods was disconnected, if it is
+    informer_disconnected_since: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Readiness {
+    /// Send whether the node is ready to the subscribers of the given lifecycle when
+    /// it changes
+    pub(crate) fn with_lifecycle(mut self, lifecycle: Lifecycle) -> Self {
+        self.lifecycle = lifecycle;
+        self
+    }
+
+    /// Record whether the watch on the node's pods is connected
+    pub(crate) fn set_informer_connected(&self, connected: bool) {
+        let mut since = self.informer_disconnected_since.lock().unwrap();
+        if connected {
+            *since = None;
+        } else if since.is_none() {
+            *since = Some(Instant::now());
+        }
+    }
+
+    /// How long the watch on the node's pods has been disconnected, if it is
+    fn informer_disconnected_for(&self) -> Option<Duration> {
+        self.informer_disconnected_since
+            .lock()
+            .unwrap()
+            .map(|since| since.elapsed())
+    }
+
+    /// Set whether the node is shutting down. A node that is shutting down is reported
+    /// as not ready so no new pods are scheduled to it
+    pub(crate) fn set_shutting_down(&self, shutting_down: bool) {
+        self.shutting_down.store(shutting_down, Ordering::SeqCst);
+    }
+
+    fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+/// Build the definition of the node. If the provider can't report its node conditions
+/// or scaling hints, the ones in `last_applied` are kept, so they aren't removed from
+/// the node
+async fn build_node<P: Provider + Sync>(
+    config: &Config,
+    provider: &P,
+    readiness: &Readiness,
+    last_applied: Option<&Node>,
+) -> anyhow::Result<Node> {
+    let mut builder = node_definition(config, P::ARCH, P::OS);
+    let owner = node_owner(config).context("unable to read the Kubelet's instance ID")?;
+    builder.add_annotation(NODE_OWNER_ANNOTATION, &owner);
+    provider.node(&mut builder).await?;
+    // Reservations apply to the capacity the provider reports
+    for (resource, quantity) in config.system_reserved.iter().chain(&config.kube_reserved) {
+        if let Err(e) = builder.reserve(resource, quantity) {
+            warn!("Unable to reserve {} of {}: {}", quantity, resource, e);
+        }
+    }
+    match provider.node_conditions().await {
+        Ok(conditions) => {
+            let now = Time(Utc::now());
+            for mut condition in conditions {
+                condition
+                    .last_heartbeat_time
+                    .get_or_insert_with(|| now.clone());
+                condition
+                    .last_transition_time
+                    .get_or_insert_with(|| now.clone());
+                builder.add_condition(condition);
+            }
+        }
+        Err(e) => {
+            warn!("Unable to get node conditions from provider: {}", e);
+            let previous = last_applied
+                .and_then(|node| node.status.as_ref()?.conditions.as_ref())
+                .into_iter()
+                .flatten();
+            for condition in previous {
+                let kubelet_condition = condition.type_ == PODS_PENDING_CONDITION
+                    || builder
+                        .conditions
+                        .iter()
+                        .any(|c| c.type_ == condition.type_);
+                if !kubelet_condition {
+                    builder.add_condition(condition.clone());
+                }
+            }
+        }
+    }
+    let last_pending = last_applied
+        .and_then(|node| node.status.as_ref()?.conditions.as_ref())
+        .into_iter()
+        .flatten()
+        .find(|c| c.type_ == PODS_PENDING_CONDITION);
+    match provider.scaling_hints().await {
+        Ok(Some(hints)) => {
+            for (key, value) in hints.annotations() {
+                builder.add_annotation(&key, &value);
+            }
+            builder.add_condition(hints.condition(last_pending));
+        }
+        Ok(None) => (),
+        Err(e) => {
+            // The hints last published are kept rather than removed, as the provider
+            // may still have pods waiting
+            warn!("Unable to get scaling hints from provider: {}", e);
+            let annotations = scaling_annotations(last_applied.unwrap_or(&Node::default()));
+            for (key, value) in annotations {
+                if let Some(value) = value.as_str() {
+                    builder.add_annotation(&key, value);
+                }
+            }
+            if let Some(condition) = last_pending {
+                builder.add_condition(condition.clone());
+            }
+        }
+    }
+    let not_ready = if readiness.shutting_down() {
+        Some("node is shutting down".to_owned())
+    } else {
+        not_ready_message(
+            provider.health().await,
+            readiness.informer_disconnected_for(),
+        )
+    };
+    readiness.lifecycle.node_ready(not_ready.as_deref());
+    if let Some(message) = not_ready {
+        let now = Time(Utc::now());
+        builder.add_condition(NodeCondition {
+            type_: "Ready".to_owned(),
+            status: "False".to_owned(),
+            reason: Some("KubeletNotReady".to_owned()),
+            message: Some(message),
+            last_heartbeat_time: Some(now.clone()),
+            last_transition_time: Some(now),
+        });
+    }
+    Ok(builder.build())
+}
+
+/// Returns why the node can't run pods, given the health of the provider and how
+/// long the pod watch has been disconnected, or `None` if it can
+fn not_ready_message(
+    health: anyhow::Result<()>,
+    informer_disconnected_for: Option<Duration>,
+) -> Option<String> {
+    if let Err(e) = health {
+        return Some(format!("provider is unhealthy: {}", e));
+    }
+    match informer_disconnected_for {
+        Some(disconnected) if disconnected >= INFORMER_DISCONNECT_THRESHOLD => Some(format!(
+            "the watch on the node's pods has been disconnected for {}s",
+            disconnected.as_secs()
+        )),
+        _ => None,
+    }
+}
+
+/// The scaling hint annotations of a node as a JSON merge patch, with the ones it
+/// doesn't have set to null so they are removed
+fn scaling_annotations(node: &Node) -> serde_json::Map<String, serde_json::Value> {
+    let annotations = node.metadata.as_ref().and_then(|m| m.annotations.as_ref());
+    [AVAILABLE_PODS_ANNOTATION, PENDING_PODS_ANNOTATION]
+        .iter()
+        .map(|key| {
+            let value = annotations
+                .and_then(|a| a.get(*key))
+                .map_or(serde_json::Value::Null, |v| v.as_str().into());
+            ((*key).to_owned(), value)
+        })
+        .collect()
+}
+
+async fn patch_annotations(
+    client: &kube::Client,
+    limits: &RateLimits,
+    node_name: &str,
+    annotations: serde_json::Map<String, serde_json::Value>,
+) -> Result<Node, Error> {
+    let node_client: Api<Node> = Api::all(client.clone());
+    let patch = serde_json::json!({ "metadata": { "annotations": annotations } });
+    let data = serde_json::to_vec(&patch).expect("Node annotations should always be serializable");
+    limits
+        .limited(node_client.patch(node_name, &PatchParams::default(), data))
+        .await
+}
+
+/// The field manager the Kubelet restores the labels of its node as. It is not the one
+/// the node is registered as, as an apply drops the fields its manager applied before
+/// and left out
+const LABELS_FIELD_MANAGER: &str = "krustlet-labels";
+
+/// The field manager the Kubelet restores the taints of its node as
+const TAINTS_FIELD_MANAGER: &str = "krustlet-taints";
+
+/// The kinds of restores the API server refused, which are not tried again. With the
+/// NodeRestriction admission plugin a node may not change its own taints, or set some
+/// labels, so these would fail on every update
+#[derive(Debug, Default)]
+pub(crate) struct Refusals {
+    labels: bool,
+    taints: bool,
+}
+
+/// The apply that restores the labels of the node definition, if any of them were
+/// removed or changed, or the Kubelet owns labels it no longer defines.
+///
+/// The API server tracks which field manager owns each label, so the apply removes the
+/// labels the Kubelet applied before and no longer defines, unless someone else took
+/// them over by changing them. Labels added by anyone else are left alone.
+fn restore_labels(existing: &Node, desired: &Node) -> Option<serde_json::Value> {
+    let existing_labels = labels(existing);
+    let desired_labels = labels(desired);
+    let owned: BTreeSet<String> =
+        owned_fields(existing, LABELS_FIELD_MANAGER, "f:metadata", "f:labels")
+            .filter(|f| f.starts_with("f:"))
+            .map(|f| f["f:".len()..].to_owned())
+            .collect();
+    let drifted = desired_labels
+        .iter()
+        .any(|(key, value)| existing_labels.get(key) != Some(value));
+    if !drifted && desired_labels.keys().eq(owned.iter()) {
+        return None;
+    }
+    Some(serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Node",
+        "metadata": { "name": desired.metadata.as_ref().and_then(|m| m.name.clone()), "labels": desired_labels },
+    }))
+}
+
+/// The apply that restores the taints of the node definition, if any of them were
+/// removed or changed, or the Kubelet owns taints it no longer defines. Taints are
+/// owned by their key and effect, so those of others are left alone like labels are
+fn restore_taints(existing: &Node, desired: &Node) -> Option<serde_json::Value> {
+    let existing_taints = taints(existing);
+    let desired_taints = taints(desired);
+    let owned: BTreeSet<(String, String)> =
+        owned_fields(existing, TAINTS_FIELD_MANAGER, "f:spec", "f:taints")
+            .filter(|f| f.starts_with("k:"))
+            .filter_map(|f| {
+                let keys: serde_json::Value = serde_json::from_str(&f["k:".len()..]).ok()?;
+                Some((
+                    keys["key"].as_str()?.to_owned(),
+                    keys["effect"].as_str()?.to_owned(),
+                ))
+            })
+            .collect();
+    let defined: BTreeSet<(String, String)> = desired_taints
+        .iter()
+        .map(|t| (t.key.clone(), t.effect.clone()))
+        .collect();
+    let drifted = desired_taints.iter().any(|d| {
+        !existing_taints
+            .iter()
+            .any(|t| same_taint(d, t) && d.value == t.value)
+    });
+    if !drifted && defined == owned {
+        return None;
+    }
+    Some(serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Node",
+        "metadata": { "name": desired.metadata.as_ref().and_then(|m| m.name.clone()) },
+        "spec": { "taints": desired_taints },
+    }))
+}
+
+/// The names of the fields under the given object the field manager applied to the
+/// node, as in its managed fields, such as `f:<label>` under `f:metadata` and `f:labels`
+fn owned_fields<'a>(
+    node: &'a Node,
+    manager: &'a str,
+    parent: &'a str,
+    field: &'a str,
+) -> impl Iterator<Item = &'a String> + 'a {
+    node.metadata
+        .iter()
+        .flat_map(|m| m.managed_fields.iter().flatten())
+        .filter(move |entry| {
+            entry.manager.as_deref() == Some(manager) && entry.operation.as_deref() == Some("Apply")
+        })
+        .filter_map(move |entry| {
+            entry
+                .fields_v1
+                .as_ref()?
+                .0
+                .get(parent)?
+                .get(field)?
+                .as_object()
+        })
+        .flat_map(|fields| fields.keys())
+}
+
+/// Apply the labels or taints of the node as the given field manager. Returns true if
+/// the API server refused it, so it is not tried again
+async fn restore(
+    node_client: &Api<Node>,
+    limits: &RateLimits,
+    node_name: &str,
+    what: &str,
+    field_manager: &str,
+    patch: serde_json::Value,
+) -> bool {
+    warn!(
+        "The {} of node '{}' differ from its definition, restoring them",
+        what, node_name
+    );
+    let data = serde_json::to_vec(&patch).expect("Node patch should always be serializable");
+    let params = apply_params_as(field_manager);
+    // Tried again on the next update if it fails for any other reason
+    match retry!(limits.limited(node_client.patch(node_name, &params, data.clone())).await, times: 4, break_on: &Error::Api(ErrorResponse { code: 403, .. }))
+    {
+        Ok(_) => false,
+        Err(Error::Api(e)) if e.code == 403 => {
+            warn!(
+                "Node '{}' is not allowed to change its {}, so they are no longer restored: {}",
+                node_name, what, e.message
+            );
+            true
+        }
+        Err(e) => {
+            error!(
+                "Failed to restore the {} of node '{}': {}",
+                what, node_name, e
+            );
+            false
+        }
+    }
+}
+
+fn labels(node: &Node) -> &BTreeMap<String, String> {
+    lazy_static::lazy_static! {
+        static ref NONE: BTreeMap<String, String> = BTreeMap::new();
+    }
+    node.metadata
+        .as_ref()
+        .and_then(|m| m.labels.as_ref())
+        .unwrap_or(&NONE)
+}
+
+fn taints(node: &Node) -> &[Taint] {
+    node.spec
+        .as_ref()
+        .and_then(|s| s.taints.as_deref())
+        .unwrap_or(&[])
+}
+
+/// Returns true if the taints have the same key and effect, so one replaces the other
+fn same_taint(a: &Taint, b: &Taint) -> bool {
+    a.key == b.key && a.effect == b.effect
+}
+
+/// Returns true if the statuses of the two nodes differ in anything but the
+/// condition timestamps, which are refreshed every time a node is built
+fn status_changed(old: &Node, new: &Node) -> bool {
+    fn without_timestamps(node: &Node) -> Option<NodeStatus> {
+        let mut status = node.status.clone()?;
+        for condition in status.conditions.iter_mut().flatten() {
+            condition.last_heartbeat_time = None;
+            condition.last_transition_time = None;
+        }
+        Some(status)
+    }
+    without_timestamps(old) != without_timestamps(new)
+}
+
+/// Carries over the transition time of every condition whose status has not changed
+fn keep_transition_times(old: &Node, new: &mut Node) {
+    let old_conditions = match old.status.as_ref().and_then(|s| s.conditions.as_ref()) {
+        Some(c) => c,
+        None => return,
+    };
+    let new_conditions = new
+        .status
+        .as_mut()
+        .and_then(|s| s.conditions.as_mut())
+        .into_iter()
+        .flatten();
+    for condition in new_conditions {
+        if let Some(old) = old_conditions
+            .iter()
+            .find(|c| c.type_ == condition.type_ && c.status == condition.status)
+        {
+            condition.last_transition_time = old.last_transition_time.clone();
+        }
     }
 }
 
@@ -139,7 +896,12 @@ pub async fn update_node(client: &kube::Client, node_name: &str) {
 ///
 /// As far as I can tell, leases ALWAYS go in the 'kube-node-lease'
 /// namespace, no exceptions.
-async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) -> Result<(), Error> {
+async fn create_lease(
+    node_uid: &str,
+    node_name: &str,
+    client: &kube::Client,
+    limits: &RateLimits,
+) -> Result<(), Error> {
     debug!("Creating lease for node '{}'", node_name);
     let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
 
@@ -148,7 +910,7 @@ async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) ->
         .expect("failed to deserialize lease from lease definition JSON");
 
     let resp = retry!(
-        leases.create(&PostParams::default(), &lease).await,
+        limits.limited(leases.create(&PostParams::default(), &lease)).await,
         times: 4,
         log_error: |e| debug!("Lease could not be created: {}. Retrying...", e),
         break_on: &Error::Api(ErrorResponse { code: 409, .. })
@@ -181,6 +943,7 @@ async fn update_lease(
     node_uid: &str,
     node_name: &str,
     client: &kube::Client,
+    limits: &RateLimits,
 ) -> Result<Lease, Error> {
     debug!("Updating lease for node '{}'...", node_name);
     let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
@@ -189,8 +952,8 @@ async fn update_lease(
     let lease_data =
         serde_json::to_vec(&lease).expect("Lease should always be serializable to JSON");
 
-    let resp = leases
-        .patch(node_name, &PatchParams::default(), lease_data)
+    let resp = limits
+        .limited(leases.patch(node_name, &PatchParams::default(), lease_data))
         .await;
     match &resp {
         Ok(_) => debug!("Lease updated for '{}'", node_name),
@@ -199,42 +962,336 @@ async fn update_lease(
     resp
 }
 
-async fn replace_node(client: &kube::Client, node_name: &str, node: &Node) -> Result<(), Error> {
-    debug!("Replacing existing node '{}'", node_name);
+/// Apply the node definition to an existing node, then apply its status
+async fn apply_node(
+    client: &kube::Client,
+    limits: &RateLimits,
+    node_name: &str,
+    node: &Node,
+) -> Result<(), Error> {
+    debug!("Applying definition of existing node '{}'", node_name);
     let node_client: Api<Node> = Api::all(client.clone());
-
-    // HACK WARNING: So it turns out we need to have the proper
-    // permissions in order to update the node status, so this
-    // is a hacky workaround for now where we delete and
-    // recreate the node. This is being tracked in https://github.com/deislabs/krustlet/issues/150
-
-    // Delete the node
-    debug!(
-        "Deleting existing node '{}' in order to recreate it",
-        node_name
-    );
+    let data = serde_json::to_vec(node).expect("Node should always be serializable to JSON");
+    let applied = retry!(
+        limits.limited(node_client.patch(node_name, &apply_params(), data.clone())).await,
+        times: 4,
+        log_error: |e| debug!("Could not apply node: {}", e),
+        break_on: &Error::Api(ErrorResponse { code: 404, .. })
+    )?;
     retry!(
-        node_client
-            .delete(node_name, &DeleteParams::default())
-            .await,
+        apply_node_status(client, limits, node_name, node).await,
         times: 4,
-        log_error: |e| debug!("Could not delete node during replacement: {}", e)
+        log_error: |e| debug!("Could not apply node status: {}", e)
     )?;
-    debug!("Recreating recently deleted existing node '{}'", node_name);
-    // Create the node
-    let node = retry!(node_client.create(&PostParams::default(), node).await, times: 4, log_error: |e| debug!("Could not create node during replacement: {}", e))?;
     // Create the lease
     create_lease(
-        &node.metadata.and_then(|m| m.uid).unwrap(),
+        &applied.metadata.and_then(|m| m.uid).unwrap(),
         node_name,
         &client,
+        limits,
     )
     .await?;
 
-    debug!("Successfully replaced node '{}'", node_name);
+    debug!("Successfully applied node '{}'", node_name);
     Ok(())
 }
 
+/// Replace the labels, annotations and spec of an existing node with the node
+/// definition, keeping its finalizers, then apply the definition as [`apply_node`] does
+async fn overwrite_node(
+    client: &kube::Client,
+    limits: &RateLimits,
+    node_name: &str,
+    existing: ObjectMeta,
+    node: &Node,
+) -> Result<(), Error> {
+    debug!("Overwriting existing node '{}'", node_name);
+    let node_client: Api<Node> = Api::all(client.clone());
+    let mut replacement = node.clone();
+    replacement.status = None;
+    if let Some(metadata) = replacement.metadata.as_mut() {
+        metadata.resource_version = existing.resource_version;
+        metadata.finalizers = existing.finalizers;
+    }
+    limits
+        .limited(node_client.replace(node_name, &PostParams::default(), &replacement))
+        .await?;
+    apply_node(client, limits, node_name, node).await
+}
+
+async fn apply_node_status(
+    client: &kube::Client,
+    limits: &RateLimits,
+    node_name: &str,
+    node: &Node,
+) -> Result<Node, Error> {
+    let node_client: Api<Node> = Api::all(client.clone());
+    let status = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": "Node",
+        "metadata": { "name": node_name },
+        "status": node.status,
+    });
+    let data = serde_json::to_vec(&status).expect("Node status should always be serializable");
+    limits
+        .limited(node_client.patch_status(node_name, &apply_params(), data))
+        .await
+}
+
+/// A builder for the Node object that describes this Kubelet to Kubernetes.
+///
+/// The Kubelet fills in the defaults from its configuration before handing the
+/// builder to [`Provider::node`](crate::Provider::node) so that providers can add
+/// their own labels, taints, capacity and the like. The same built node is used
+/// when creating a new node and when replacing an existing one.
+///
+/// # Example
+/// ```rust
+/// use kubelet::NodeBuilder;
+///
+/// let mut builder = NodeBuilder::new();
+/// builder.set_name("krustlet");
+/// builder.add_label("example.com/gpu", "false");
+/// builder.add_taint("NoSchedule", "example.com/dedicated", "true");
+/// builder.add_capacity("pods", "10");
+/// let node = builder.build();
+/// assert_eq!("krustlet", node.metadata.unwrap().name.unwrap());
+/// ```
+#[derive(Clone, Debug)]
+pub struct NodeBuilder {
+    name: String,
+    annotations: BTreeMap<String, String>,
+    labels: BTreeMap<String, String>,
+    pod_cidr: String,
+    taints: Vec<Taint>,
+    node_info: NodeSystemInfo,
+    capacity: BTreeMap<String, Quantity>,
+    allocatable: BTreeMap<String, Quantity>,
+    conditions: Vec<NodeCondition>,
+    addresses: Vec<NodeAddress>,
+    port: i32,
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        let ts = Time(Utc::now());
+        let mut annotations = BTreeMap::new();
+        annotations.insert("node.alpha.kubernetes.io/ttl".to_owned(), "0".to_owned());
+        annotations.insert(
+            "volumes.kubernetes.io/controller-managed-attach-detach".to_owned(),
+            "true".to_owned(),
+        );
+        NodeBuilder {
+            name: String::from("krustlet"),
+            annotations,
+            labels: BTreeMap::new(),
+            pod_cidr: String::from("10.244.0.0/24"),
+            taints: Vec::new(),
+            node_info: NodeSystemInfo {
+                architecture: "wasm-wasi".to_owned(),
+                container_runtime_version: format!(
+                    "krustlet://{}",
+                    crate::version::info().full_version()
+                ),
+                kube_proxy_version: "v1.17.0".to_owned(),
+                kubelet_version: "v1.17.0".to_owned(),
+                operating_system: "linux".to_owned(),
+                ..Default::default()
+            },
+            capacity: default_resources(),
+            allocatable: default_resources(),
+            conditions: vec![
+                NodeCondition {
+                    type_: "Ready".to_owned(),
+                    status: "True".to_owned(),
+                    last_heartbeat_time: Some(ts.clone()),
+                    last_transition_time: Some(ts.clone()),
+                    reason: Some("KubeletReady".to_owned()),
+                    message: Some("kubelet is ready".to_owned()),
+                },
+                NodeCondition {
+                    type_: "OutOfDisk".to_owned(),
+                    status: "False".to_owned(),
+                    last_heartbeat_time: Some(ts.clone()),
+                    last_transition_time: Some(ts),
+                    reason: Some("KubeletHasSufficientDisk".to_owned()),
+                    message: Some("kubelet has sufficient disk space available".to_owned()),
+                },
+            ],
+            addresses: Vec::new(),
+            port: 3000,
+        }
+    }
+}
+
+impl NodeBuilder {
+    /// Create a new builder with default values
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the name of the node
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_owned();
+    }
+
+    /// Add an annotation to the node
+    pub fn add_annotation(&mut self, key: &str, value: &str) {
+        self.annotations.insert(key.to_owned(), value.to_owned());
+    }
+
+    /// Add a label to the node, replacing any existing value
+    pub fn add_label(&mut self, key: &str, value: &str) {
+        self.labels.insert(key.to_owned(), value.to_owned());
+    }
+
+    /// Set the pod CIDR for the node
+    pub fn set_pod_cidr(&mut self, pod_cidr: &str) {
+        self.pod_cidr = pod_cidr.to_owned();
+    }
+
+    /// Add a taint to the node
+    pub fn add_taint(&mut self, effect: &str, key: &str, value: &str) {
+        self.taints.push(Taint {
+            effect: effect.to_owned(),
+            key: key.to_owned(),
+            value: Some(value.to_owned()),
+            ..Default::default()
+        });
+    }
+
+    /// Set the architecture reported in the node info
+    pub fn set_architecture(&mut self, architecture: &str) {
+        self.node_info.architecture = architecture.to_owned();
+    }
+
+    /// Set the operating system reported in the node info
+    pub fn set_operating_system(&mut self, operating_system: &str) {
+        self.node_info.operating_system = operating_system.to_owned();
+    }
+
+    /// Set the kubelet version reported in the node info
+    pub fn set_kubelet_version(&mut self, version: &str) {
+        self.node_info.kubelet_version = version.to_owned();
+    }
+
+    /// Set the container runtime version reported in the node info
+    pub fn set_container_runtime_version(&mut self, version: &str) {
+        self.node_info.container_runtime_version = version.to_owned();
+    }
+
+    /// Set the full node info, replacing the defaults
+    pub fn set_node_info(&mut self, node_info: NodeSystemInfo) {
+        self.node_info = node_info;
+    }
+
+    /// Set the capacity of the node for the given resource. The allocatable amount
+    /// of the resource is set to the same value
+    pub fn add_capacity(&mut self, resource: &str, quantity: &str) {
+        self.capacity
+            .insert(resource.to_owned(), Quantity(quantity.to_owned()));
+        self.allocatable
+            .insert(resource.to_owned(), Quantity(quantity.to_owned()));
+    }
+
+    /// Set the allocatable amount of the given resource
+    pub fn add_allocatable(&mut self, resource: &str, quantity: &str) {
+        self.allocatable
+            .insert(resource.to_owned(), Quantity(quantity.to_owned()));
+    }
+
+    /// Reserve an amount of the given resource for something other than pods, by
+    /// subtracting it from the resource's allocatable amount. The allocatable amount
+    /// does not go below zero
+    pub fn reserve(&mut self, resource: &str, quantity: &str) -> anyhow::Result<()> {
+        let reserved = parse_quantity(&Quantity(quantity.to_owned()))
+            .ok_or_else(|| anyhow::anyhow!("invalid quantity {:?}", quantity))?;
+        let allocatable = self
+            .allocatable
+            .get(resource)
+            .or_else(|| self.capacity.get(resource))
+            .ok_or_else(|| anyhow::anyhow!("the node has no {} capacity", resource))?;
+        let allocatable = parse_quantity(allocatable)
+            .ok_or_else(|| anyhow::anyhow!("invalid allocatable quantity {:?}", allocatable.0))?;
+        self.allocatable.insert(
+            resource.to_owned(),
+            format_quantity((allocatable - reserved).max(0.0)),
+        );
+        Ok(())
+    }
+
+    /// Add a condition to the node, replacing any existing condition of the same type
+    pub fn add_condition(&mut self, condition: NodeCondition) {
+        self.conditions.retain(|c| c.type_ != condition.type_);
+        self.conditions.push(condition);
+    }
+
+    /// Add an address of the given type (e.g. `InternalIP` or `Hostname`)
+    pub fn add_address(&mut self, address_type: &str, address: &str) {
+        self.addresses.push(NodeAddress {
+            type_: address_type.to_owned(),
+            address: address.to_owned(),
+        });
+    }
+
+    /// Set the port the kubelet's webserver is listening on
+    pub fn set_port(&mut self, port: i32) {
+        self.port = port;
+    }
+
+    /// Build the node
+    pub fn build(self) -> Node {
+        Node {
+            metadata: Some(ObjectMeta {
+                name: Some(self.name),
+                labels: Some(self.labels),
+                annotations: Some(self.annotations),
+                ..Default::default()
+            }),
+            spec: Some(NodeSpec {
+                pod_cidr: Some(self.pod_cidr),
+                taints: Some(self.taints),
+                ..Default::default()
+            }),
+            status: Some(NodeStatus {
+                node_info: Some(self.node_info),
+                capacity: Some(self.capacity),
+                allocatable: Some(self.allocatable),
+                conditions: Some(self.conditions),
+                addresses: Some(self.addresses),
+                daemon_endpoints: Some(NodeDaemonEndpoints {
+                    kubelet_endpoint: Some(DaemonEndpoint { port: self.port }),
+                }),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Format a resource amount as a quantity, in thousandths if it is fractional (such as
+/// `3500m` CPUs)
+fn format_quantity(value: f64) -> Quantity {
+    if value.fract().abs() < 1e-9 {
+        Quantity(format!("{}", value.round() as u64))
+    } else {
+        Quantity(format!("{}m", (value * 1000.0).round() as u64))
+    }
+}
+
+fn default_resources() -> BTreeMap<String, Quantity> {
+    vec![
+        ("cpu", "4"),
+        ("ephemeral-storage", "61255492Ki"),
+        ("hugepages-1Gi", "0"),
+        ("hugepages-2Mi", "0"),
+        ("memory", "4032800Ki"),
+        ("pods", "30"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_owned(), Quantity(v.to_owned())))
+    .collect()
+}
+
 /// Define a new node that will handle WASM load.
 ///
 /// The most important part of this spec is the set of labels, which control
@@ -243,101 +1300,24 @@ async fn replace_node(client: &kube::Client, node_name: &str, node: &Node) -> Re
 /// the OS field. I have seen 'emscripten' used for this field, but in our case
 /// the runtime is not emscripten, and besides... specifying which runtime we
 /// use seems like a misstep. Ideally, we'll be able to support multiple runtimes.
-fn node_definition(config: &Config, arch: &str) -> serde_json::Value {
-    let ts = Time(Utc::now());
-    let mut json = serde_json::json!({
-        "apiVersion": "v1",
-        "kind": "Node",
-        "metadata": {
-            "name": config.node_name,
-            "labels": {},
-            "annotations": {
-                "node.alpha.kubernetes.io/ttl": "0",
-                "volumes.kubernetes.io/controller-managed-attach-detach": "true"
-            }
-        },
-        "spec": {
-            "podCIDR": "10.244.0.0/24",
-            "taints": [
-                {
-                    "effect": "NoExecute",
-                    "key": "krustlet/arch",
-                    "value": arch
-                }
-            ]
-        },
-        "status": {
-            "nodeInfo": {
-                "architecture": "wasm-wasi",
-                "bootID": "",
-                "containerRuntimeVersion": "mvp",
-                "kernelVersion": "",
-                "kubeProxyVersion": "v1.17.0",
-                "kubeletVersion": "v1.17.0",
-                "machineID": "",
-                "operatingSystem": "linux",
-                "osImage": "",
-                "systemUUID": ""
-            },
-            "capacity": {
-                "cpu": "4",
-                "ephemeral-storage": "61255492Ki",
-                "hugepages-1Gi": "0",
-                "hugepages-2Mi": "0",
-                "memory": "4032800Ki",
-                "pods": "30"
-            },
-            "allocatable": {
-                "cpu": "4",
-                "ephemeral-storage": "61255492Ki",
-                "hugepages-1Gi": "0",
-                "hugepages-2Mi": "0",
-                "memory": "4032800Ki",
-                "pods": "30"
-            },
-            "conditions": [
-                {
-                    "type": "Ready",
-                    "status": "True",
-                    "lastHeartbeatTime":  ts,
-                    "lastTransitionTime": ts,
-                    "reason":             "KubeletReady",
-                    "message":            "kubelet is ready",
-                },
-                {
-                    "type": "OutOfDisk",
-                    "status": "False",
-                    "lastHeartbeatTime":  ts,
-                    "lastTransitionTime": ts,
-                    "reason":             "KubeletHasSufficientDisk",
-                    "message":            "kubelet has sufficient disk space available",
-                },
-            ],
-            "addresses": [
-                {
-                    "type": "InternalIP",
-                    "address": config.node_ip
-                },
-                {
-                    "type": "Hostname",
-                    "address": config.hostname
-                }
-            ],
-            "daemonEndpoints": {
-                "kubeletEndpoint": {
-                    "Port": config.server_config.port
-                }
-            }
-        }
-    });
+///
+/// The arch and OS labels are those of the provider, which the Kubelet also checks the
+/// node selectors of pods against, see [`admission`](crate::admission).
+fn node_definition(config: &Config, arch: &str, os: &str) -> NodeBuilder {
+    let mut builder = NodeBuilder::new();
+    builder.set_name(&config.node_name);
+    builder.set_operating_system(os);
+    builder.add_taint("NoExecute", "krustlet/arch", arch);
+    builder.add_address("InternalIP", &config.node_ip.to_string());
+    builder.add_address("Hostname", &config.hostname);
+    builder.set_port(config.server_config.port as i32);
 
-    let node_labels = node_labels_definition(arch, &config);
     // extra labels from config
-    for (key, val) in node_labels {
-        json["metadata"]["labels"][key] = serde_json::json!(val);
+    for (key, val) in node_labels_definition(arch, os, &config) {
+        builder.add_label(&key, &val);
     }
 
-    json
+    builder
 }
 
 /// Define a new coordination.Lease object for Kubernetes
@@ -387,16 +1367,18 @@ fn lease_spec_definition(node_name: &str) -> serde_json::Value {
 /// Defines the labels that will be applied to this node
 ///
 /// Default values and passed node-labels arguments are injected by config.
-fn node_labels_definition(arch: &str, config: &Config) -> HashMap<String, String> {
+fn node_labels_definition(arch: &str, os: &str, config: &Config) -> HashMap<String, String> {
     // Add mandatory static labels
     let mut labels = HashMap::new();
-    labels.insert("beta.kubernetes.io/os".to_owned(), "linux".to_owned());
-    labels.insert("kubernetes.io/os".to_owned(), "linux".to_owned());
     labels.insert("kubernetes.io/role".to_owned(), "agent".to_owned());
     labels.insert("type".to_owned(), "krustlet".to_owned());
     // add the mandatory labels that are dependent on injected values
-    labels.insert("beta.kubernetes.io/arch".to_owned(), arch.to_owned());
-    labels.insert("kubernetes.io/arch".to_owned(), arch.to_owned());
+    for label in ARCH_LABELS {
+        labels.insert((*label).to_owned(), arch.to_owned());
+    }
+    for label in OS_LABELS {
+        labels.insert((*label).to_owned(), os.to_owned());
+    }
     labels.insert(
         "kubernetes.io/hostname".to_owned(),
         config.hostname.to_owned(),
@@ -453,10 +1435,58 @@ fn node_labels_definition(arch: &str, config: &Config) -> HashMap<String, String
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::autoscaling::ScalingHints;
     use crate::config::{Config, ServerConfig};
+    use crate::testing::{FakeProvider, MockApiServer, Operation, FAKE_ARCH};
     use std::net::{IpAddr, Ipv4Addr};
     use std::path::PathBuf;
 
+    fn test_config(node_labels: HashMap<String, String>) -> Config {
+        Config {
+            node_ip: IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
+            hostname: String::from("foo"),
+            node_name: String::from("bar"),
+            server_config: ServerConfig {
+                addr: IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
+                port: 8080,
+                pfx_password: String::new(),
+                pfx_path: PathBuf::new(),
+                cert_file: None,
+                private_key_file: None,
+                auth: Default::default(),
+                read_only: false,
+                worker_threads: None,
+            },
+            data_dir: std::env::temp_dir().join(format!("krustlet-node-{}", std::process::id())),
+            node_labels,
+            node_adoption: Default::default(),
+            kubeconfig: None,
+            leader_election: None,
+            api_client: Default::default(),
+            registry_client: Default::default(),
+            concurrency: Default::default(),
+            namespaces: Default::default(),
+            namespace_quotas: HashMap::new(),
+            shutdown_grace_period: Default::default(),
+            shutdown_grace_period_critical_pods: Default::default(),
+            pod_label_selector: None,
+            pod_field_selector: None,
+            bindle_server: None,
+            system_reserved: HashMap::new(),
+            kube_reserved: HashMap::new(),
+            pod_log_dir: None,
+            cluster_domain: "cluster.local".to_owned(),
+            max_wasm_stack: 1024 * 1024,
+            wasi_sandbox: Default::default(),
+            audit_log: false,
+            skip_crd_registration: false,
+            admin_socket: None,
+            prefetch: Default::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
+        }
+    }
+
     #[test]
     fn test_node_labels_definition() {
         let mut node_labels = HashMap::new();
@@ -475,21 +1505,9 @@ mod test {
         );
         node_labels.insert("beta.kubernetes.io/os".to_owned(), "managed".to_owned());
 
-        let config = Config {
-            node_ip: IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
-            hostname: String::from("foo"),
-            node_name: String::from("bar"),
-            server_config: ServerConfig {
-                addr: IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
-                port: 8080,
-                pfx_password: String::new(),
-                pfx_path: PathBuf::new(),
-            },
-            data_dir: PathBuf::new(),
-            node_labels,
-        };
+        let config = test_config(node_labels);
 
-        let result = node_labels_definition("linux", &config);
+        let result = node_labels_definition(FAKE_ARCH, "linux", &config);
 
         assert!(result.contains_key("kubernetes.io/role"));
         assert!(result.contains_key("foo"));
@@ -498,5 +1516,777 @@ mod test {
         assert!(result.contains_key("kubernetes.io/instance-type"));
         assert!(!result.get("beta.kubernetes.io/os").unwrap().eq("managed"));
         assert!(result.get("beta.kubernetes.io/os").unwrap().eq("linux"));
+        assert_eq!(FAKE_ARCH, result["beta.kubernetes.io/arch"]);
+        assert_eq!(FAKE_ARCH, result["kubernetes.io/arch"]);
+    }
+
+    #[tokio::test]
+    async fn test_create_node_registers_node_and_lease() {
+        let server = MockApiServer::start().await.unwrap();
+        let client = server.client();
+        let config = test_config(HashMap::new());
+        let provider = FakeProvider::new();
+        let mut last_applied = create_node(&client, &config, &RateLimits::default(), &provider)
+            .await
+            .expect("node should be registered");
+
+        let node = server.get("/api/v1/nodes/bar").expect("node should exist");
+        assert_eq!(FAKE_ARCH, node["metadata"]["labels"]["kubernetes.io/arch"]);
+        assert_eq!("linux", node["metadata"]["labels"]["kubernetes.io/os"]);
+        assert_eq!("linux", node["status"]["nodeInfo"]["operatingSystem"]);
+        assert_eq!(
+            format!("krustlet://{}", crate::version::info().full_version()),
+            node["status"]["nodeInfo"]["containerRuntimeVersion"]
+        );
+        let lease = server
+            .get("/apis/coordination.k8s.io/v1/namespaces/kube-node-lease/leases/bar")
+            .expect("lease should exist");
+        assert_eq!(
+            node["metadata"]["uid"],
+            lease["metadata"]["ownerReferences"][0]["uid"]
+        );
+
+        update_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &provider,
+            &Readiness::default(),
+            &mut last_applied,
+            &mut Refusals::default(),
+        )
+        .await;
+        assert_eq!(
+            1,
+            server
+                .requests_to(
+                    hyper::Method::PATCH,
+                    "/apis/coordination.k8s.io/v1/namespaces/kube-node-lease/leases/bar"
+                )
+                .len()
+        );
+        // Only the heartbeat changed, so the node itself should be left alone
+        assert!(server
+            .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar/status")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_version_skew() {
+        let server = |major: &str, minor: &str| Info {
+            major: major.to_owned(),
+            minor: minor.to_owned(),
+            git_version: format!("v{}.{}.0", major, minor),
+            ..Default::default()
+        };
+        assert_eq!(None, version_skew("v1.17.0", &server("1", "17")));
+        assert_eq!(None, version_skew("v1.17.0", &server("1", "19+")));
+        assert!(version_skew("v1.17.0", &server("1", "20"))
+            .unwrap()
+            .contains("v1.20.0"));
+        assert!(version_skew("v1.17.0", &server("1", "16")).is_some());
+        assert!(version_skew("v1.17.0", &server("2", "17")).is_some());
+        assert_eq!(None, version_skew("mvp", &server("1", "17")));
+        assert_eq!(Some((1, 17)), parse_version("1.17+"));
+    }
+
+    #[tokio::test]
+    async fn test_record_registration() {
+        let server = MockApiServer::start().await.unwrap();
+        server.set_version("1", "20");
+        let client = server.client();
+        let config = test_config(HashMap::new());
+        let node = create_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        .expect("node should be registered");
+        record_registration::<FakeProvider>(
+            &EventRecorder::default(),
+            &client,
+            &config,
+            &RateLimits::default(),
+            &node,
+        )
+        .await;
+
+        let api: Api<k8s_openapi::api::core::v1::Event> = Api::namespaced(client, "default");
+        let events = api.list(&Default::default()).await.unwrap().items;
+        let reasons: Vec<_> = events.iter().map(|e| e.reason.as_deref()).collect();
+        assert_eq!(
+            vec![Some("Starting"), Some("UnsupportedVersionSkew")],
+            reasons
+        );
+        assert_eq!("Node", events[0].involved_object.kind.as_deref().unwrap());
+        assert_eq!("bar", events[0].involved_object.name.as_deref().unwrap());
+        assert!(events[0].message.as_deref().unwrap().contains(FAKE_ARCH));
+    }
+
+    #[tokio::test]
+    async fn test_update_node_patches_changed_status() {
+        let server = MockApiServer::start().await.unwrap();
+        let client = server.client();
+        let config = test_config(HashMap::new());
+        let mut last_applied = create_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        .expect("node should be registered");
+        let transition_time = last_applied
+            .status
+            .as_ref()
+            .unwrap()
+            .conditions
+            .as_ref()
+            .unwrap()[0]
+            .last_transition_time
+            .clone();
+
+        // Pretend the node was registered with less capacity than the provider now reports
+        last_applied
+            .status
+            .as_mut()
+            .unwrap()
+            .capacity
+            .as_mut()
+            .unwrap()
+            .insert("pods".to_owned(), Quantity("1".to_owned()));
+        update_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+            &Readiness::default(),
+            &mut last_applied,
+            &mut Refusals::default(),
+        )
+        .await;
+        assert_eq!(
+            1,
+            server
+                .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar/status")
+                .len()
+        );
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        assert_eq!("30", node["status"]["capacity"]["pods"]);
+        assert_eq!(
+            transition_time,
+            last_applied
+                .status
+                .as_ref()
+                .unwrap()
+                .conditions
+                .as_ref()
+                .unwrap()[0]
+                .last_transition_time
+        );
+
+        update_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+            &Readiness::default(),
+            &mut last_applied,
+            &mut Refusals::default(),
+        )
+        .await;
+        assert_eq!(
+            1,
+            server
+                .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar/status")
+                .len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_node_restores_labels_and_taints() {
+        let server = MockApiServer::start().await.unwrap();
+        let client = server.client();
+        let mut labels = HashMap::new();
+        labels.insert("krustlet.dev/pool".to_owned(), "edge".to_owned());
+        labels.insert("krustlet.dev/zone".to_owned(), "a".to_owned());
+        let config = test_config(labels);
+        let mut last_applied = create_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        .expect("node should be registered");
+        let mut refusals = Refusals::default();
+        let update = |config: Config, mut last_applied: Node, mut refusals: Refusals| {
+            let client = client.clone();
+            async move {
+                update_node(
+                    &client,
+                    &config,
+                    &RateLimits::default(),
+                    &FakeProvider::new(),
+                    &Readiness::default(),
+                    &mut last_applied,
+                    &mut refusals,
+                )
+                .await;
+                (last_applied, refusals)
+            }
+        };
+        let (applied, refused) = update(config.clone(), last_applied, refusals).await;
+        last_applied = applied;
+        refusals = refused;
+
+        // Someone strips the Kubelet's labels and taints, adding their own
+        server.modify(
+            "/api/v1/nodes/bar",
+            &serde_json::json!({
+                "metadata": { "labels": { "kubernetes.io/arch": null, "team": "edge" } },
+                "spec": { "taints": [
+                    { "key": "example.com/maintenance", "value": "true", "effect": "NoSchedule" },
+                ] },
+            }),
+        );
+        let (applied, refused) = update(config.clone(), last_applied, refusals).await;
+        last_applied = applied;
+        refusals = refused;
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        assert_eq!(FAKE_ARCH, node["metadata"]["labels"]["kubernetes.io/arch"]);
+        assert_eq!("edge", node["metadata"]["labels"]["team"]);
+        let taints: Vec<&str> = node["spec"]["taints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["key"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["example.com/maintenance", "krustlet/arch"], taints);
+
+        // Nothing is patched while the node matches its definition
+        let patches = server
+            .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar")
+            .len();
+        let (applied, refused) = update(config.clone(), last_applied, refusals).await;
+        last_applied = applied;
+        refusals = refused;
+        assert_eq!(
+            patches,
+            server
+                .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar")
+                .len()
+        );
+
+        // Labels the Kubelet no longer defines are removed, unless someone took them over
+        server.modify(
+            "/api/v1/nodes/bar",
+            &serde_json::json!({ "metadata": { "labels": { "krustlet.dev/zone": "b" } } }),
+        );
+        update(test_config(HashMap::new()), last_applied, refusals).await;
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        assert!(node["metadata"]["labels"]
+            .get("krustlet.dev/pool")
+            .is_none());
+        assert_eq!("b", node["metadata"]["labels"]["krustlet.dev/zone"]);
+        assert_eq!("edge", node["metadata"]["labels"]["team"]);
+    }
+
+    #[tokio::test]
+    async fn test_update_node_stops_restoring_refused_taints() {
+        let server = MockApiServer::start().await.unwrap();
+        let client = server.client();
+        let config = test_config(HashMap::new());
+        let mut last_applied = create_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        .expect("node should be registered");
+        // As with NodeRestriction, the node may not change its taints
+        server.deny_changes("/api/v1/nodes", "/spec/taints");
+        server.modify(
+            "/api/v1/nodes/bar",
+            &serde_json::json!({
+                "metadata": { "labels": { "kubernetes.io/arch": null } },
+                "spec": { "taints": null },
+            }),
+        );
+
+        let mut refusals = Refusals::default();
+        for _ in 0..2 {
+            update_node(
+                &client,
+                &config,
+                &RateLimits::default(),
+                &FakeProvider::new(),
+                &Readiness::default(),
+                &mut last_applied,
+                &mut refusals,
+            )
+            .await;
+        }
+        // The labels are still restored, and the taints are only tried once
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        assert_eq!(FAKE_ARCH, node["metadata"]["labels"]["kubernetes.io/arch"]);
+        assert!(node["spec"]["taints"].is_null());
+        let taint_restores = server
+            .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar")
+            .into_iter()
+            .filter(|r| {
+                r.query
+                    .as_deref()
+                    .unwrap_or_default()
+                    .contains(TAINTS_FIELD_MANAGER)
+            })
+            .count();
+        assert_eq!(1, taint_restores);
+    }
+
+    #[tokio::test]
+    async fn test_create_node_adopts_existing_node() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/nodes",
+            serde_json::json!({"metadata": {"name": "bar", "labels": {"stale": "true"}}}),
+        );
+        create_node(
+            &server.client(),
+            &test_config(HashMap::new()),
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        .expect("node should be adopted");
+
+        // Labels managed by someone else are left alone
+        let node = server.get("/api/v1/nodes/bar").expect("node should exist");
+        assert_eq!("true", node["metadata"]["labels"]["stale"]);
+        assert_eq!(FAKE_ARCH, node["metadata"]["labels"]["kubernetes.io/arch"]);
+        assert!(server
+            .requests_to(hyper::Method::DELETE, "/api/v1/nodes/bar")
+            .is_empty());
+        let apply = server
+            .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar")
+            .pop()
+            .expect("node should be applied");
+        let query = apply.query.unwrap_or_default();
+        assert!(query.contains("fieldManager=krustlet"));
+        assert!(query.contains("force=true"));
+    }
+
+    #[tokio::test]
+    async fn test_create_node_adoption_policies() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/nodes",
+            serde_json::json!({"metadata": {
+                "name": "bar",
+                "labels": {"stale": "true"},
+                "annotations": {NODE_OWNER_ANNOTATION: "elsewhere"},
+            }, "spec": {
+                "podCIDR": "10.244.1.0/24",
+                "podCIDRs": ["10.244.1.0/24"],
+                "providerID": "krustlet://bar",
+            }}),
+        );
+        let client = server.client();
+        let mut config = test_config(HashMap::new());
+
+        config.node_adoption = AdoptionPolicy::Unowned;
+        match create_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        {
+            Err(KubeletError::NodeConflict { reason, .. }) => {
+                assert!(reason.contains("elsewhere"))
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        config.node_adoption = AdoptionPolicy::MatchingLabels;
+        match create_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        {
+            Err(KubeletError::NodeConflict { reason, .. }) => assert!(reason.contains("label")),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(server
+            .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar")
+            .is_empty());
+
+        config.node_adoption = AdoptionPolicy::Overwrite;
+        create_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        .expect("node should be overwritten");
+        let node = server.get("/api/v1/nodes/bar").expect("node should exist");
+        assert!(node["metadata"]["labels"].get("stale").is_none());
+        // What the control plane assigned to the node is kept
+        assert_eq!("10.244.1.0/24", node["spec"]["podCIDR"]);
+        assert_eq!("10.244.1.0/24", node["spec"]["podCIDRs"][0]);
+        assert_eq!("krustlet://bar", node["spec"]["providerID"]);
+        assert_eq!(
+            node_owner(&config).unwrap(),
+            node["metadata"]["annotations"][NODE_OWNER_ANNOTATION]
+        );
+
+        // The node is now owned by this Kubelet, with its labels
+        for policy in &[AdoptionPolicy::Unowned, AdoptionPolicy::MatchingLabels] {
+            config.node_adoption = *policy;
+            create_node(
+                &client,
+                &config,
+                &RateLimits::default(),
+                &FakeProvider::new(),
+            )
+            .await
+            .expect("node should be adopted");
+        }
+    }
+
+    #[test]
+    fn test_node_owner_is_kept_in_data_dir() {
+        let mut config = test_config(HashMap::new());
+        config.data_dir =
+            std::env::temp_dir().join(format!("krustlet-owner-{}", std::process::id()));
+        let owner = node_owner(&config).unwrap();
+        assert_eq!(32, owner.len());
+        assert_eq!(owner, node_owner(&config).unwrap());
+
+        // Another data directory is another Kubelet instance, even on the same host
+        let mut other = test_config(HashMap::new());
+        other.data_dir = config.data_dir.join("other");
+        assert_ne!(owner, node_owner(&other).unwrap());
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_node_waits_for_deletion() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/nodes",
+            serde_json::json!({"metadata": {
+                "name": "bar",
+                "uid": "old",
+                "deletionTimestamp": "2020-01-01T00:00:00Z",
+            }}),
+        );
+        let deleting = server.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(150)).await;
+            deleting.remove("/api/v1/nodes/bar");
+        });
+        create_node(
+            &server.client(),
+            &test_config(HashMap::new()),
+            &RateLimits::default(),
+            &FakeProvider::new(),
+        )
+        .await
+        .expect("node should be registered once the old one is gone");
+
+        let node = server.get("/api/v1/nodes/bar").expect("node should exist");
+        assert_ne!("old", node["metadata"]["uid"]);
+        assert!(server
+            .requests_to(hyper::Method::PATCH, "/api/v1/nodes/bar")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_provider_node_conditions() {
+        let server = MockApiServer::start().await.unwrap();
+        let client = server.client();
+        let config = test_config(HashMap::new());
+        let provider = FakeProvider::new();
+        let registry_reachable = |status: &str| NodeCondition {
+            type_: "RegistryReachable".to_owned(),
+            status: status.to_owned(),
+            ..Default::default()
+        };
+        provider.set_node_conditions(vec![registry_reachable("True")]);
+        let mut last_applied = create_node(&client, &config, &RateLimits::default(), &provider)
+            .await
+            .unwrap();
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        let condition = node["status"]["conditions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["type"] == "RegistryReachable")
+            .expect("provider condition should be reported")
+            .clone();
+        assert_eq!("True", condition["status"]);
+        assert!(!condition["lastHeartbeatTime"].is_null());
+        assert!(!condition["lastTransitionTime"].is_null());
+
+        // A change in provider health is patched onto the node
+        provider.set_node_conditions(vec![registry_reachable("False")]);
+        update_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &provider,
+            &Readiness::default(),
+            &mut last_applied,
+            &mut Refusals::default(),
+        )
+        .await;
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        let conditions = node["status"]["conditions"].as_array().unwrap();
+        assert!(conditions
+            .iter()
+            .any(|c| c["type"] == "RegistryReachable" && c["status"] == "False"));
+        // The default conditions are still there
+        assert!(conditions.iter().any(|c| c["type"] == "Ready"));
+
+        // The last conditions are kept while the provider can't report them
+        provider.fail(Operation::NodeConditions, "registry check timed out");
+        let node = build_node(
+            &config,
+            &provider,
+            &Readiness::default(),
+            Some(&last_applied),
+        )
+        .await
+        .unwrap();
+        let conditions = node.status.unwrap().conditions.unwrap();
+        let kept: Vec<&NodeCondition> = conditions
+            .iter()
+            .filter(|c| c.type_ == "RegistryReachable")
+            .collect();
+        assert_eq!(1, kept.len());
+        assert_eq!("False", kept[0].status);
+        assert!(conditions.iter().any(|c| c.type_ == "Ready"));
+    }
+
+    #[tokio::test]
+    async fn test_scaling_hints() {
+        let server = MockApiServer::start().await.unwrap();
+        let client = server.client();
+        let config = test_config(HashMap::new());
+        let provider = FakeProvider::new();
+        provider.set_scaling_hints(Some(ScalingHints {
+            available_pods: Some(10),
+            ..Default::default()
+        }));
+        let mut last_applied = create_node(&client, &config, &RateLimits::default(), &provider)
+            .await
+            .unwrap();
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        assert_eq!(
+            "10",
+            node["metadata"]["annotations"][AVAILABLE_PODS_ANNOTATION]
+        );
+        assert_eq!("", node["metadata"]["annotations"][PENDING_PODS_ANNOTATION]);
+
+        // The backend filled up, so pods are waiting on it
+        let mut pending_pods = BTreeMap::new();
+        pending_pods.insert("BackendCapacity".to_owned(), 2);
+        provider.set_scaling_hints(Some(ScalingHints {
+            available_pods: Some(0),
+            pending_pods,
+        }));
+        update_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &provider,
+            &Readiness::default(),
+            &mut last_applied,
+            &mut Refusals::default(),
+        )
+        .await;
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        let annotations = &node["metadata"]["annotations"];
+        assert_eq!("0", annotations[AVAILABLE_PODS_ANNOTATION]);
+        assert_eq!("BackendCapacity=2", annotations[PENDING_PODS_ANNOTATION]);
+        // Other annotations are kept
+        assert!(!annotations[NODE_OWNER_ANNOTATION].is_null());
+        let condition = node["status"]["conditions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["type"] == PODS_PENDING_CONDITION)
+            .expect("pending condition should be reported")
+            .clone();
+        assert_eq!("True", condition["status"]);
+        assert_eq!("BackendCapacity", condition["reason"]);
+
+        // Hints the provider fails to report are kept, as is the time pods started
+        // to wait
+        provider.fail(Operation::ScalingHints, "backend unreachable");
+        update_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &provider,
+            &Readiness::default(),
+            &mut last_applied,
+            &mut Refusals::default(),
+        )
+        .await;
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        assert_eq!(
+            "BackendCapacity=2",
+            node["metadata"]["annotations"][PENDING_PODS_ANNOTATION]
+        );
+        let kept = node["status"]["conditions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["type"] == PODS_PENDING_CONDITION)
+            .expect("pending condition should be kept")
+            .clone();
+        assert_eq!(condition["lastTransitionTime"], kept["lastTransitionTime"]);
+        provider.succeed(Operation::ScalingHints);
+
+        // Hints that are no longer reported are removed
+        provider.set_scaling_hints(None);
+        update_node(
+            &client,
+            &config,
+            &RateLimits::default(),
+            &provider,
+            &Readiness::default(),
+            &mut last_applied,
+            &mut Refusals::default(),
+        )
+        .await;
+        let node = server.get("/api/v1/nodes/bar").unwrap();
+        assert!(node["metadata"]["annotations"][AVAILABLE_PODS_ANNOTATION].is_null());
+        assert!(!node["status"]["conditions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|c| c["type"] == PODS_PENDING_CONDITION));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut builder = NodeBuilder::new();
+        builder.add_capacity("cpu", "4");
+        builder.reserve("cpu", "500m").unwrap();
+        builder.reserve("memory", "1Gi").unwrap();
+        builder.reserve("pods", "40").unwrap();
+        assert!(builder.reserve("gpu", "1").is_err());
+        assert!(builder.reserve("cpu", "lots").is_err());
+
+        let status = builder.build().status.unwrap();
+        let allocatable = status.allocatable.unwrap();
+        assert_eq!("3500m", allocatable["cpu"].0);
+        assert_eq!("3055845376", allocatable["memory"].0);
+        assert_eq!("0", allocatable["pods"].0);
+        assert_eq!("4", status.capacity.unwrap()["cpu"].0);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_resources() {
+        let mut config = test_config(HashMap::new());
+        config
+            .system_reserved
+            .insert("cpu".to_owned(), "1".to_owned());
+        config
+            .kube_reserved
+            .insert("cpu".to_owned(), "250m".to_owned());
+        config
+            .kube_reserved
+            .insert("pods".to_owned(), "5".to_owned());
+        let node = build_node(&config, &FakeProvider::new(), &Readiness::default(), None)
+            .await
+            .unwrap();
+        let allocatable = node.status.unwrap().allocatable.unwrap();
+        assert_eq!("2750m", allocatable["cpu"].0);
+        assert_eq!("25", allocatable["pods"].0);
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_provider_is_not_ready() {
+        let provider = FakeProvider::new();
+        provider.fail(Operation::Health, "runtime is not responding");
+        let node = build_node(
+            &test_config(HashMap::new()),
+            &provider,
+            &Readiness::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let conditions = node.status.unwrap().conditions.unwrap();
+        let ready = conditions.iter().find(|c| c.type_ == "Ready").unwrap();
+        assert_eq!("False", ready.status);
+        assert_eq!(Some("KubeletNotReady"), ready.reason.as_deref());
+        assert_eq!(
+            Some("provider is unhealthy: runtime is not responding"),
+            ready.message.as_deref()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutting_down_node_is_not_ready() {
+        let readiness = Readiness::default();
+        let shared = readiness.clone();
+        shared.set_shutting_down(true);
+        let config = test_config(HashMap::new());
+        let node = build_node(&config, &FakeProvider::new(), &readiness, None)
+            .await
+            .unwrap();
+        let conditions = node.status.unwrap().conditions.unwrap();
+        let ready = conditions.iter().find(|c| c.type_ == "Ready").unwrap();
+        assert_eq!("False", ready.status);
+        assert_eq!(Some("node is shutting down"), ready.message.as_deref());
+
+        // Other Kubelets are not affected
+        let node = build_node(&config, &FakeProvider::new(), &Readiness::default(), None)
+            .await
+            .unwrap();
+        let conditions = node.status.unwrap().conditions.unwrap();
+        let ready = conditions.iter().find(|c| c.type_ == "Ready").unwrap();
+        assert_eq!("True", ready.status);
+    }
+
+    #[test]
+    fn test_informer_disconnect_is_per_kubelet() {
+        let readiness = Readiness::default();
+        let shared = readiness.clone();
+        shared.set_informer_connected(false);
+        assert!(readiness.informer_disconnected_for().is_some());
+        assert_eq!(None, Readiness::default().informer_disconnected_for());
+
+        shared.set_informer_connected(true);
+        assert_eq!(None, readiness.informer_disconnected_for());
+    }
+
+    #[test]
+    fn test_not_ready_message() {
+        assert_eq!(None, not_ready_message(Ok(()), None));
+        assert_eq!(
+            None,
+            not_ready_message(Ok(()), Some(Duration::from_secs(10)))
+        );
+        assert_eq!(
+            Some("the watch on the node's pods has been disconnected for 90s".to_owned()),
+            not_ready_message(Ok(()), Some(Duration::from_secs(90)))
+        );
+        assert_eq!(
+            Some("provider is unhealthy: broken".to_owned()),
+            not_ready_message(Err(anyhow::anyhow!("broken")), None)
+        );
     }
 }
@@ -0,0 +1,474 @@
+//! A shared cache of the secrets and config maps used by pods on this node
+//!
+//! Resolving environment variables and populating volumes would otherwise get every
+//! secret and config map from the API each time a pod is synced. Like the upstream
+//! Kubelet's watch based secret and config map managers, the [`ObjectManager`] watches
+//! each object referenced by a pod on the node for as long as the pod is there, and
+//! serves the cached copy instead.
+//!
+//! The pods the Kubelet hands to providers carry its manager, so [`get_secret`] and
+//! [`get_config_map`] serve cached copies to providers too. Objects not referenced by a
+//! registered pod are fetched from the API as before.
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use kube::api::{Api, ListParams, Meta, WatchEvent};
+use kube::error::ErrorResponse;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use tokio::sync::oneshot;
+
+use crate::container::from_ephemeral;
+use crate::pod::Pod;
+use crate::rate_limit::RateLimits;
+use crate::redact;
+
+/// How long to wait before watching an object again after listing it failed
+const RELIST_DELAY: Duration = Duration::from_secs(5);
+
+/// Get a secret in the pod's namespace, from the cache of the pod's manager if the
+/// secret is watched. The secret's values are redacted from the pod's errors, see
+/// [`redact`](crate::redact)
+pub(crate) async fn get_secret(
+    client: &kube::Client,
+    pod: &Pod,
+    name: &str,
+) -> Result<Secret, kube::Error> {
+    let namespace = pod.namespace();
+    let secret = match pod
+        .objects()
+        .and_then(|m| m.secrets.cached(namespace, name))
+    {
+        Some(cached) => cached,
+        None => {
+            let api = Api::<Secret>::namespaced(client.clone(), namespace);
+            pod.rate_limits().limited(api.get(name)).await
+        }
+    };
+    if let Ok(secret) = &secret {
+        for value in secret.data.iter().flat_map(|data| data.values()) {
+            if let Ok(value) = std::str::from_utf8(&value.0) {
+                redact::register(pod, value);
+            }
+        }
+    }
+    secret
+}
+
+/// Get a config map in the pod's namespace, from the cache of the pod's manager if the
+/// config map is watched
+pub(crate) async fn get_config_map(
+    client: &kube::Client,
+    pod: &Pod,
+    name: &str,
+) -> Result<ConfigMap, kube::Error> {
+    let namespace = pod.namespace();
+    if let Some(cached) = pod
+        .objects()
+        .and_then(|m| m.config_maps.cached(namespace, name))
+    {
+        return cached;
+    }
+    let api = Api::<ConfigMap>::namespaced(client.clone(), namespace);
+    pod.rate_limits().limited(api.get(name)).await
+}
+
+/// Watches the secrets and config maps referenced by registered pods
+#[derive(Clone)]
+pub(crate) struct ObjectManager {
+    secrets: Cache<Secret>,
+    config_maps: Cache<ConfigMap>,
+}
+
+impl ObjectManager {
+    /// Create a manager that watches objects with the given client, under the given
+    /// rate limits. The client should not have a request timeout shorter than a watch
+    pub(crate) fn new(client: kube::Client, limits: RateLimits) -> Self {
+        ObjectManager {
+            secrets: Cache::new(client.clone(), limits.clone()),
+            config_maps: Cache::new(client, limits),
+        }
+    }
+
+    /// Start watching the objects referenced by the pod, if they aren't already
+    pub(crate) fn register_pod(&self, pod: &Pod) {
+        let (secrets, config_maps) = references(pod);
+        for name in secrets {
+            self.secrets.add_ref(pod.namespace(), &name);
+        }
+        for name in config_maps {
+            self.config_maps.add_ref(pod.namespace(), &name);
+        }
+    }
+
+    /// Stop watching the objects referenced by the pod, unless other pods still use them
+    pub(crate) fn unregister_pod(&self, pod: &Pod) {
+        let (secrets, config_maps) = references(pod);
+        for name in secrets {
+            self.secrets.remove_ref(pod.namespace(), &name);
+        }
+        for name in config_maps {
+            self.config_maps.remove_ref(pod.namespace(), &name);
+        }
+    }
+
+    /// Whether the secret is watched for some pod
+    #[cfg(test)]
+    pub(crate) fn watches_secret(&self, namespace: &str, name: &str) -> bool {
+        let key = (namespace.to_owned(), name.to_owned());
+        self.secrets.entries.lock().unwrap().contains_key(&key)
+    }
+}
+
+impl Debug for ObjectManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectManager").finish()
+    }
+}
+
+/// Returns the names of the secrets and config maps a pod refers to
+fn references(pod: &Pod) -> (Vec<String>, Vec<String>) {
+    let mut secrets = Vec::new();
+    let mut config_maps = Vec::new();
+    for volume in pod.volumes().map(|v| v.as_slice()).unwrap_or_default() {
+        if let Some(name) = volume.secret.as_ref().and_then(|s| s.secret_name.clone()) {
+            secrets.push(name);
+        }
+        if let Some(name) = volume.config_map.as_ref().and_then(|c| c.name.clone()) {
+            config_maps.push(name);
+        }
+    }
+    let ephemeral: Vec<_> = pod
+        .ephemeral_containers()
+        .iter()
+        .map(from_ephemeral)
+        .collect();
+    let sources = pod
+        .containers()
+        .iter()
+        .chain(ephemeral.iter())
+        .flat_map(|c| c.env.iter().flatten())
+        .filter_map(|e| e.value_from.as_ref());
+    for source in sources {
+        if let Some(name) = source.secret_key_ref.as_ref().and_then(|s| s.name.clone()) {
+            secrets.push(name);
+        }
+        if let Some(name) = source
+            .config_map_key_ref
+            .as_ref()
+            .and_then(|c| c.name.clone())
+        {
+            config_maps.push(name);
+        }
+    }
+    secrets.sort();
+    secrets.dedup();
+    config_maps.sort();
+    config_maps.dedup();
+    (secrets, config_maps)
+}
+
+/// The namespace and name of an object
+type ObjectKey = (String, String);
+
+/// What is known about a watched object
+#[derive(Clone)]
+enum Cached<K> {
+    /// The object has not been listed yet
+    Unknown,
+    Present(K),
+    Missing,
+}
+
+struct Entry<K> {
+    /// The number of registered pods referring to the object
+    pods: usize,
+    object: Arc<Mutex<Cached<K>>>,
+    /// Stops the watch when dropped
+    _stop: oneshot::Sender<()>,
+}
+
+struct Cache<K> {
+    client: kube::Client,
+    limits: RateLimits,
+    entries: Arc<Mutex<HashMap<ObjectKey, Entry<K>>>>,
+}
+
+impl<K> Clone for Cache<K> {
+    fn clone(&self) -> Self {
+        Cache {
+            client: self.client.clone(),
+            limits: self.limits.clone(),
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<K> Cache<K>
+where
+    K: k8s_openapi::Resource + Clone + DeserializeOwned + Meta + Debug + Send + Sync + 'static,
+{
+    fn new(client: kube::Client, limits: RateLimits) -> Self {
+        Cache {
+            client,
+            limits,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the cached object, or `None` if it isn't watched or hasn't been listed yet
+    fn cached(&self, namespace: &str, name: &str) -> Option<Result<K, kube::Error>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&(namespace.to_owned(), name.to_owned()))?;
+        let object = entry.object.lock().unwrap().clone();
+        match object {
+            Cached::Unknown => None,
+            Cached::Present(object) => Some(Ok(object)),
+            Cached::Missing => Some(Err(not_found::<K>(name))),
+        }
+    }
+
+    fn add_ref(&self, namespace: &str, name: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (namespace.to_owned(), name.to_owned());
+        if let Some(entry) = entries.get_mut(&key) {
+            entry.pods += 1;
+            return;
+        }
+        debug!("Watching {} {} in namespace {}", K::KIND, name, namespace);
+        let object = Arc::new(Mutex::new(Cached::Unknown));
+        let (stop, stopped) = oneshot::channel();
+        let api = Api::<K>::namespaced(self.client.clone(), namespace);
+        let watch = watch_object(api, self.limits.clone(), name.to_owned(), object.clone());
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = watch => (),
+                _ = stopped => (),
+            }
+        });
+        entries.insert(
+            key,
+            Entry {
+                pods: 1,
+                object,
+                _stop: stop,
+            },
+        );
+    }
+
+    fn remove_ref(&self, namespace: &str, name: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let key = (namespace.to_owned(), name.to_owned());
+        let unused = match entries.get_mut(&key) {
+            Some(entry) => {
+                entry.pods -= 1;
+                entry.pods == 0
+            }
+            None => false,
+        };
+        if unused {
+            debug!(
+                "No longer watching {} {} in namespace {}",
+                K::KIND,
+                name,
+                namespace
+            );
+            entries.remove(&key);
+        }
+    }
+}
+
+/// Keep the cached copy of an object up to date, listing it and then watching it for
+/// changes until the watch ends, over and over. Each list and watch is a request under
+/// the rate limits
+async fn watch_object<K>(
+    api: Api<K>,
+    limits: RateLimits,
+    name: String,
+    object: Arc<Mutex<Cached<K>>>,
+) where
+    K: k8s_openapi::Resource + Clone + DeserializeOwned + Meta + Debug + Send + Sync,
+{
+    let params = ListParams {
+        field_selector: Some(format!("metadata.name={}", name)),
+        ..Default::default()
+    };
+    loop {
+        let version = match limits.limited(api.list(&params)).await {
+            Ok(list) => {
+                *object.lock().unwrap() = match list.items.into_iter().next() {
+                    Some(o) => Cached::Present(o),
+                    None => Cached::Missing,
+                };
+                list.metadata.resource_version.unwrap_or_default()
+            }
+            Err(e) => {
+                warn!("Unable to list {} {}: {}", K::KIND, name, e);
+                // Don't serve a copy that may be stale while we can't watch it
+                *object.lock().unwrap() = Cached::Unknown;
+                tokio::time::delay_for(RELIST_DELAY).await;
+                continue;
+            }
+        };
+        let mut events = match limits.limited(api.watch(&params, &version)).await {
+            Ok(events) => events.boxed(),
+            Err(e) => {
+                warn!("Unable to watch {} {}: {}", K::KIND, name, e);
+                tokio::time::delay_for(RELIST_DELAY).await;
+                continue;
+            }
+        };
+        loop {
+            match events.try_next().await {
+                Ok(Some(WatchEvent::Added(o))) | Ok(Some(WatchEvent::Modified(o))) => {
+                    *object.lock().unwrap() = Cached::Present(o)
+                }
+                Ok(Some(WatchEvent::Deleted(_))) => *object.lock().unwrap() = Cached::Missing,
+                Ok(Some(WatchEvent::Bookmark(_))) => (),
+                Ok(Some(WatchEvent::Error(e))) => {
+                    debug!("Watch of {} {} ended: {}", K::KIND, name, e);
+                    break;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("Watch of {} {} failed: {}", K::KIND, name, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn not_found<K: k8s_openapi::Resource>(name: &str) -> kube::Error {
+    kube::Error::Api(ErrorResponse {
+        status: "Failure".to_owned(),
+        message: format!("{} \"{}\" not found", K::KIND, name),
+        reason: "NotFound".to_owned(),
+        code: 404,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+    use k8s_openapi::api::core::v1::{
+        EnvVar, EnvVarSource, SecretKeySelector, SecretVolumeSource, Volume,
+    };
+
+    const SECRET_PATH: &str = "/api/v1/namespaces/default/secrets/creds";
+
+    fn pod_using_secret(name: &str) -> Pod {
+        let mut pod = fake_pod(name, "default");
+        let spec = pod.spec.as_mut().unwrap();
+        spec.containers[0].env = Some(vec![EnvVar {
+            name: "PASSWORD".to_owned(),
+            value_from: Some(EnvVarSource {
+                secret_key_ref: Some(SecretKeySelector {
+                    name: Some("creds".to_owned()),
+                    key: "password".to_owned(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }]);
+        spec.volumes = Some(vec![Volume {
+            name: "creds".to_owned(),
+            secret: Some(SecretVolumeSource {
+                secret_name: Some("creds".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }]);
+        pod.into()
+    }
+
+    fn password(secret: &Secret) -> String {
+        let data = secret.data.as_ref().unwrap();
+        String::from_utf8(data["password"].0.clone()).unwrap()
+    }
+
+    async fn wait_for<F: Fn() -> bool>(condition: F) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("condition was not met in time");
+    }
+
+    #[test]
+    fn test_references() {
+        let (secrets, config_maps) = references(&pod_using_secret("foo"));
+        assert_eq!(vec!["creds".to_owned()], secrets);
+        assert!(config_maps.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_watched_objects_are_cached() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/secrets",
+            serde_json::json!({
+                "metadata": { "name": "creds", "namespace": "default" },
+                "data": { "password": "aHVudGVyMg==" },
+            }),
+        );
+        let manager = ObjectManager::new(server.client(), RateLimits::default());
+        let first = pod_using_secret("first");
+        let second = pod_using_secret("second");
+        manager.register_pod(&first);
+        manager.register_pod(&second);
+        let secrets = &manager.secrets;
+        wait_for(|| secrets.cached("default", "creds").is_some()).await;
+        let secret = secrets.cached("default", "creds").unwrap().unwrap();
+        assert_eq!("hunter2", password(&secret));
+        // Both pods share a single watch, and nothing is fetched directly
+        let watches = server
+            .requests_to(hyper::Method::GET, "/api/v1/namespaces/default/secrets")
+            .into_iter()
+            .filter(|r| {
+                r.query
+                    .as_deref()
+                    .unwrap_or_default()
+                    .contains("watch=true")
+            })
+            .count();
+        assert_eq!(1, watches);
+        // Pods that carry the manager are served from its cache
+        let carrying = first.clone().with_objects(manager.clone());
+        let secret = get_secret(&server.client(), &carrying, "creds").await;
+        assert_eq!("hunter2", password(&secret.unwrap()));
+        assert!(server
+            .requests_to(hyper::Method::GET, SECRET_PATH)
+            .is_empty());
+
+        // Changes come in through the watch
+        assert!(server.modify(
+            SECRET_PATH,
+            &serde_json::json!({ "data": { "password": "c3dvcmRmaXNo" } })
+        ));
+        wait_for(|| password(&secrets.cached("default", "creds").unwrap().unwrap()) == "swordfish")
+            .await;
+        server.remove(SECRET_PATH);
+        wait_for(|| {
+            matches!(
+                secrets.cached("default", "creds"),
+                Some(Err(kube::Error::Api(ErrorResponse { code: 404, .. })))
+            )
+        })
+        .await;
+
+        // The object is watched until the last pod using it is gone
+        manager.unregister_pod(&first);
+        assert!(secrets.cached("default", "creds").is_some());
+        manager.unregister_pod(&second);
+        assert!(secrets.cached("default", "creds").is_none());
+    }
+}
@@ -1,25 +1,239 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::status::{Phase, Status};
+use crate::audit::AuditLog;
+use crate::concurrency::Limits;
+use crate::events::EventRecorder;
+use crate::lifecycle::Lifecycle;
+use crate::module_store::{FetchedImages, ImageInfo};
+use crate::object_manager::ObjectManager;
+use crate::pod_dirs::PodDirs;
+use crate::rate_limit::RateLimits;
+use crate::shutdown::ShutdownStop;
+use crate::status::annotations::AnnotationWriter;
+use crate::status::{Phase, Status, StatusPatch};
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::{
-    Container as KubeContainer, ContainerStatus as KubeContainerStatus, Pod as KubePod,
-    Volume as KubeVolume,
+    Container as KubeContainer, ContainerPort, ContainerStatus as KubeContainerStatus,
+    EphemeralContainer, Pod as KubePod, PodSpec, Toleration, Volume as KubeVolume,
 };
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
 use kube::api::{Api, Meta, PatchParams};
 use log::{debug, error};
+use thiserror::Error;
+
+/// The finalizer the Kubelet adds to pods it admits so they are not removed from the API
+/// before their workloads have been stopped
+pub const POD_FINALIZER: &str = "krustlet/cleanup";
+
+/// The cluster domain used for pod domain names unless another is configured
+pub const DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+
+/// The lowest priority of critical pods, that of the built in `system-cluster-critical`
+/// priority class
+const SYSTEM_CRITICAL_PRIORITY: i32 = 2_000_000_000;
+
+/// The longest hostname a pod can have, which is a DNS label
+const MAX_HOSTNAME_LENGTH: usize = 63;
+
+/// How many times the finalizers of a pod are read again after they changed while
+/// they were being updated
+const FINALIZER_CONFLICT_RETRIES: usize = 5;
+
+/// The restart policy of a pod
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Always restart containers when they exit. This is the Kubernetes default
+    #[default]
+    Always,
+    /// Only restart containers that exit with a non-zero exit code
+    OnFailure,
+    /// Never restart containers
+    Never,
+}
+
+impl RestartPolicy {
+    /// Whether a container that exited with the given exit code should be restarted
+    pub fn restarts(self, exit_code: i32) -> bool {
+        match self {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => exit_code != 0,
+            RestartPolicy::Never => false,
+        }
+    }
+}
 
 /// A Kubernetes Pod
 ///
 /// This is a new type around the k8s_openapi Pod definition
-/// providing convenient accessor methods
+/// providing convenient accessor methods. The definition is shared, so
+/// cloning a Pod is cheap no matter how large the pod is.
+///
+/// Pods the Kubelet hands to providers make their requests under the Kubelet's rate
+/// limits, run their heavy operations under its concurrency limits, and are served the
+/// secrets and config maps they use from its cache. The modules fetched for them are
+/// remembered by the Kubelet until they are deleted and recorded in its audit log, the
+/// annotations published for them are written by the Kubelet, and their domain names end
+/// with the Kubelet's cluster domain.
+#[derive(Default, Debug, Clone)]
+pub struct Pod(Arc<KubePod>, Shared);
+
+/// What the Kubelet handling a pod shares with it
 #[derive(Default, Debug, Clone)]
-pub struct Pod(KubePod);
+struct Shared {
+    limits: RateLimits,
+    concurrency: Limits,
+    objects: Option<ObjectManager>,
+    images: FetchedImages,
+    /// Set on the stop of a pod because the node shuts down
+    shutdown: Option<ShutdownStop>,
+    events: EventRecorder,
+    audit: AuditLog,
+    lifecycle: Lifecycle,
+    annotations: Option<AnnotationWriter>,
+    /// `None` for the default cluster domain
+    cluster_domain: Option<Arc<str>>,
+}
 
 impl Pod {
     /// Construct a new Pod
     pub fn new(inner: KubePod) -> Self {
-        Self(inner)
+        Self(Arc::new(inner), Shared::default())
+    }
+
+    /// Make the requests for the pod under the given rate limits
+    pub(crate) fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.1.limits = limits;
+        self
+    }
+
+    /// The rate limits the requests for the pod are made under
+    pub(crate) fn rate_limits(&self) -> &RateLimits {
+        &self.1.limits
+    }
+
+    /// Run the heavy operations for the pod under the given limits
+    pub(crate) fn with_concurrency(mut self, limits: Limits) -> Self {
+        self.1.concurrency = limits;
+        self
+    }
+
+    /// The limits that pulls, compilations and volume setups for the pod run under, see
+    /// [`concurrency`](crate::concurrency). Pods that were not handed out by a Kubelet
+    /// are not limited
+    pub fn concurrency(&self) -> &Limits {
+        &self.1.concurrency
+    }
+
+    /// Serve the secrets and config maps the pod uses from the given manager
+    pub(crate) fn with_objects(mut self, objects: ObjectManager) -> Self {
+        self.1.objects = Some(objects);
+        self
+    }
+
+    /// The manager the secrets and config maps the pod uses are served from, if any
+    pub(crate) fn objects(&self) -> Option<&ObjectManager> {
+        self.1.objects.as_ref()
+    }
+
+    /// Remember the modules fetched for the pod in the given images
+    pub(crate) fn with_images(mut self, images: FetchedImages) -> Self {
+        self.1.images = images;
+        self
+    }
+
+    /// Record the info of the module fetched for one of the pod's containers
+    pub(crate) fn record_image(&self, container: &str, image: &str, info: ImageInfo) {
+        self.1.images.record(self, container, image, info)
+    }
+
+    /// Get the info of the module fetched for one of the pod's containers by
+    /// [`ModuleStore::fetch_pod_modules`](crate::module_store::ModuleStore::fetch_pod_modules),
+    /// if it was fetched for the image the container has now
+    pub fn image_info(&self, container: &str) -> Option<ImageInfo> {
+        let image = self.container_image(container)?;
+        self.1.images.get(self, container, &image)
+    }
+
+    /// The same pod with its definition changed by `f`, still sharing what the Kubelet
+    /// shares with the pod
+    pub(crate) fn map_definition(&self, f: impl FnOnce(&mut KubePod)) -> Pod {
+        let mut inner = (*self.0).clone();
+        f(&mut inner);
+        Pod(Arc::new(inner), self.1.clone())
+    }
+
+    /// End the pod's domain names with the given cluster domain
+    pub(crate) fn with_cluster_domain(mut self, domain: Arc<str>) -> Self {
+        self.1.cluster_domain = Some(domain);
+        self
+    }
+
+    /// The cluster domain that the pod's domain names, and those of the services it
+    /// looks up, end with
+    pub fn cluster_domain(&self) -> &str {
+        self.1
+            .cluster_domain
+            .as_deref()
+            .unwrap_or(DEFAULT_CLUSTER_DOMAIN)
+    }
+
+    /// Record the events about the pod with the given recorder
+    pub(crate) fn with_events(mut self, events: EventRecorder) -> Self {
+        self.1.events = events;
+        self
+    }
+
+    /// The recorder the events about the pod are recorded with
+    pub(crate) fn events(&self) -> &EventRecorder {
+        &self.1.events
+    }
+
+    /// Record what is done with the pod in the given audit log
+    pub(crate) fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.1.audit = audit;
+        self
+    }
+
+    /// The audit log what is done with the pod is recorded in
+    pub(crate) fn audit(&self) -> &AuditLog {
+        &self.1.audit
+    }
+
+    /// Send what happens to the pod to the subscribers of the given lifecycle
+    pub(crate) fn with_lifecycle(mut self, lifecycle: Lifecycle) -> Self {
+        self.1.lifecycle = lifecycle;
+        self
+    }
+
+    /// The lifecycle what happens to the pod is sent to, see
+    /// [`lifecycle`](crate::lifecycle)
+    pub(crate) fn lifecycle(&self) -> &Lifecycle {
+        &self.1.lifecycle
+    }
+
+    /// Write the annotations published for the pod with the given writer
+    pub(crate) fn with_annotation_writer(mut self, writer: AnnotationWriter) -> Self {
+        self.1.annotations = Some(writer);
+        self
+    }
+
+    /// The writer the annotations published for the pod are written with, see
+    /// [`annotations`](crate::status::annotations)
+    pub(crate) fn annotation_writer(&self) -> Option<&AnnotationWriter> {
+        self.1.annotations.as_ref()
+    }
+
+    /// Mark the pod as being stopped because the node shuts down
+    pub(crate) fn with_shutdown(mut self, stop: ShutdownStop) -> Self {
+        self.1.shutdown = Some(stop);
+        self
+    }
+
+    /// Returns how to tell the node shutdown that the pod stopped, if it is being
+    /// stopped for one
+    pub(crate) fn shutdown(&self) -> Option<&ShutdownStop> {
+        self.1.shutdown.as_ref()
     }
 
     /// Get the name of the pod
@@ -32,13 +246,12 @@ impl Pod {
     }
 
     /// Get the pod's namespace
-    ///
-    /// Returns "default" if no namespace was explictily set
     pub fn namespace(&self) -> &str {
-        let metadata = self.0.metadata.as_ref();
-        metadata
+        self.0
+            .metadata
+            .as_ref()
             .and_then(|m| m.namespace.as_deref())
-            .unwrap_or("default")
+            .expect("Pod namespace should always be set but was not")
     }
 
     /// Get the pod's node_selector map
@@ -46,12 +259,102 @@ impl Pod {
         self.0.spec.as_ref()?.node_selector.as_ref()
     }
 
+    /// Get the pod's uid
+    pub fn uid(&self) -> Option<&str> {
+        self.0.meta().uid.as_deref()
+    }
+
+    /// Get the directories the pod's data is kept in, in the Kubelet's data directory.
+    /// See [`pod_dirs`](crate::pod_dirs)
+    pub fn data_dirs(&self, data_dir: &std::path::Path) -> PodDirs {
+        PodDirs::new(data_dir, self)
+    }
+
+    /// Get the pod's tolerations
+    pub fn tolerations(&self) -> &[Toleration] {
+        self.0
+            .spec
+            .as_ref()
+            .and_then(|s| s.tolerations.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Get the pod's restart policy
+    ///
+    /// Returns [`RestartPolicy::Always`] if no policy was set or the policy is not recognized
+    pub fn restart_policy(&self) -> RestartPolicy {
+        let policy = self
+            .0
+            .spec
+            .as_ref()
+            .and_then(|s| s.restart_policy.as_deref());
+        match policy {
+            Some("OnFailure") => RestartPolicy::OnFailure,
+            Some("Never") => RestartPolicy::Never,
+            _ => RestartPolicy::Always,
+        }
+    }
+
+    /// Get the ports exposed by all of the pod's containers along with the name
+    /// of the container exposing them
+    pub fn ports(&self) -> Vec<(&str, &ContainerPort)> {
+        self.containers()
+            .iter()
+            .flat_map(|c| c.ports.iter().flatten().map(move |p| (c.name.as_str(), p)))
+            .collect()
+    }
+
+    /// Get the pod's owner references
+    pub fn owner_references(&self) -> &[OwnerReference] {
+        self.0
+            .meta()
+            .owner_references
+            .as_deref()
+            .unwrap_or_default()
+    }
+
     /// Get the pod's service account name
     pub fn service_account_name(&self) -> Option<&str> {
         let spec = self.0.spec.as_ref()?;
         spec.service_account_name.as_deref()
     }
 
+    /// Get the pod's hostname. This is `spec.hostname`, or the pod's name cut to the
+    /// 63 characters a hostname can have
+    pub fn hostname(&self) -> String {
+        if let Some(hostname) = self.0.spec.as_ref().and_then(|s| s.hostname.as_deref()) {
+            return hostname.to_owned();
+        }
+        let name = self.name();
+        if name.len() <= MAX_HOSTNAME_LENGTH {
+            return name.to_owned();
+        }
+        // As with the Kubernetes kubelet, a cut name must not end in a separator
+        name[..MAX_HOSTNAME_LENGTH]
+            .trim_end_matches(&['-', '.'][..])
+            .to_owned()
+    }
+
+    /// Get the pod's subdomain
+    pub fn subdomain(&self) -> Option<&str> {
+        let spec = self.0.spec.as_ref()?;
+        spec.subdomain.as_deref().filter(|s| !s.is_empty())
+    }
+
+    /// Get the pod's fully qualified domain name:
+    /// `<hostname>.<subdomain>.<namespace>.svc.<cluster domain>`, which the headless
+    /// service named after its subdomain resolves. Only pods with a subdomain have one
+    pub fn fqdn(&self) -> Option<String> {
+        let subdomain = self.subdomain()?;
+        Some(format!(
+            "{}.{}.{}.svc.{}",
+            self.hostname(),
+            subdomain,
+            self.namespace(),
+            self.cluster_domain()
+        ))
+    }
+
     /// Get the pod volumes
     pub fn volumes(&self) -> Option<&Vec<KubeVolume>> {
         let spec = self.0.spec.as_ref()?;
@@ -89,16 +392,169 @@ impl Pod {
         Some(self.annotations().get(key)?.as_str())
     }
 
+    /// Get the creationTimestamp if it exists
+    pub fn creation_timestamp(&self) -> Option<&DateTime<Utc>> {
+        self.0.meta().creation_timestamp.as_ref().map(|t| &t.0)
+    }
+
+    /// Get the pod's scheduling priority. Higher values are more important
+    ///
+    /// The value is normally filled in from the pod's priority class when it is created.
+    /// If it is missing, the built in system priority classes are recognized by name and
+    /// anything else is given the default priority of zero
+    pub fn priority(&self) -> i32 {
+        let spec = match self.0.spec.as_ref() {
+            Some(s) => s,
+            None => return 0,
+        };
+        if let Some(priority) = spec.priority {
+            return priority;
+        }
+        match spec.priority_class_name.as_deref() {
+            Some("system-node-critical") => 2_000_001_000,
+            Some("system-cluster-critical") => SYSTEM_CRITICAL_PRIORITY,
+            _ => 0,
+        }
+    }
+
+    /// Whether the pod is critical to the node, such as a node agent. As in Kubernetes,
+    /// pods are critical when their priority is at least that of the built in
+    /// `system-cluster-critical` priority class
+    pub fn is_critical(&self) -> bool {
+        self.priority() >= SYSTEM_CRITICAL_PRIORITY
+    }
+
     /// Get the deletionTimestamp if it exists
     pub fn deletion_timestamp(&self) -> Option<&DateTime<Utc>> {
         self.0.meta().deletion_timestamp.as_ref().map(|t| &t.0)
     }
 
+    /// Get the grace period given to the pod when it was marked for deletion, if it has been
+    pub fn deletion_grace_period_seconds(&self) -> Option<i64> {
+        self.0.meta().deletion_grace_period_seconds
+    }
+
+    /// Get the number of seconds the pod should be given to terminate gracefully
+    ///
+    /// Returns the Kubernetes default of 30 seconds if none was set
+    pub fn termination_grace_period_seconds(&self) -> i64 {
+        self.0
+            .spec
+            .as_ref()
+            .and_then(|s| s.termination_grace_period_seconds)
+            .unwrap_or(30)
+    }
+
+    /// Get a hash of the pod's spec
+    ///
+    /// The hash can be used to check whether the spec has changed between two versions
+    /// of a pod. It is only stable for the lifetime of the process
+    pub fn spec_hash(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        serde_json::to_string(&self.0.spec)
+            .expect("Pod spec should always serialize")
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns true if the given pod has the same spec as this one
+    pub fn spec_eq(&self, other: &Pod) -> bool {
+        self.0.spec == other.0.spec
+    }
+
+    /// Get the pod's finalizers
+    pub fn finalizers(&self) -> &[String] {
+        self.0.meta().finalizers.as_deref().unwrap_or_default()
+    }
+
+    /// Returns true if the pod has the Kubelet's [`POD_FINALIZER`]
+    pub fn has_kubelet_finalizer(&self) -> bool {
+        self.finalizers().iter().any(|f| f == POD_FINALIZER)
+    }
+
+    /// Add the Kubelet's [`POD_FINALIZER`] to the pod if it does not already have it
+    pub(crate) async fn add_finalizer(&self, client: kube::Client) -> Result<(), kube::Error> {
+        self.update_finalizers(client, |finalizers| {
+            if finalizers.iter().any(|f| f == POD_FINALIZER) {
+                return None;
+            }
+            let mut finalizers = finalizers.to_vec();
+            finalizers.push(POD_FINALIZER.to_owned());
+            Some(finalizers)
+        })
+        .await
+    }
+
+    /// Remove the Kubelet's [`POD_FINALIZER`] from the pod
+    pub(crate) async fn remove_finalizer(&self, client: kube::Client) -> Result<(), kube::Error> {
+        self.update_finalizers(client, |finalizers| {
+            if !finalizers.iter().any(|f| f == POD_FINALIZER) {
+                return None;
+            }
+            Some(
+                finalizers
+                    .iter()
+                    .filter(|f| *f != POD_FINALIZER)
+                    .cloned()
+                    .collect(),
+            )
+        })
+        .await
+    }
+
+    /// Replace the finalizers of the pod with those `update` returns for its current
+    /// ones, if any.
+    ///
+    /// The patch only applies to the version of the pod the finalizers were read from,
+    /// so finalizers that other controllers change at the same time are not lost. If
+    /// the pod changed in between, it is read again and the update retried.
+    async fn update_finalizers<F>(&self, client: kube::Client, update: F) -> Result<(), kube::Error>
+    where
+        F: Fn(&[String]) -> Option<Vec<String>>,
+    {
+        let api: Api<KubePod> = Api::namespaced(client, self.namespace());
+        let mut current = self.0.clone();
+        let mut retries = 0;
+        loop {
+            let finalizers = match update(current.meta().finalizers.as_deref().unwrap_or_default())
+            {
+                Some(f) => f,
+                None => return Ok(()),
+            };
+            let patch = serde_json::json!({
+                "metadata": {
+                    "finalizers": finalizers,
+                    "resourceVersion": current.meta().resource_version,
+                }
+            });
+            let data = serde_json::to_vec(&patch).expect("Should always serialize");
+            match self
+                .rate_limits()
+                .limited(api.patch(self.name(), &PatchParams::default(), data))
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(kube::Error::Api(kube::error::ErrorResponse { code: 409, .. }))
+                    if retries < FINALIZER_CONFLICT_RETRIES =>
+                {
+                    retries += 1;
+                    debug!(
+                        "Pod {} changed while updating its finalizers, retrying",
+                        self.name()
+                    );
+                    current = Arc::new(self.rate_limits().limited(api.get(self.name())).await?);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// Patch the pod status using the given status information.
     pub async fn patch_status(&self, client: kube::Client, status: Status) {
         let name = self.name();
-        let api: Api<KubePod> = Api::namespaced(client, self.namespace());
-        let current_status = match api.get(name).await {
+        let api: Api<KubePod> = Api::namespaced(client.clone(), self.namespace());
+        let current_status = match self.rate_limits().limited(api.get(name)).await {
             Ok(p) => match p.status {
                 Some(s) => s,
                 None => {
@@ -112,30 +568,44 @@ impl Pod {
             }
         };
 
+        // A pod that has run stays running while its containers wait to restart
+        let has_run = current_status.phase.as_deref() == Some("Running");
+
         // This section figures out what the current phase of the pod should be
         // based on the container statuses
-        let current_statuses = status
+        let mut current_statuses = status
             .container_statuses
             .into_iter()
             .map(|s| (s.0.clone(), s.1.to_kubernetes(s.0)))
             .collect::<HashMap<String, KubeContainerStatus>>();
+        let previous_statuses = current_status.container_statuses.unwrap_or_default();
+        for (container, status) in current_statuses.iter_mut() {
+            let previous = previous_statuses.iter().find(|s| &s.name == container);
+            self.fill_image(container, status, previous);
+            if let Some(previous) = previous {
+                carry_restarts(status, previous);
+            }
+        }
         // Filter out any ones we are updating and then combine them all together
-        let mut container_statuses = current_status
-            .container_statuses
-            .unwrap_or_default()
+        let mut container_statuses = previous_statuses
             .into_iter()
             .filter(|s| !current_statuses.contains_key(&s.name))
             .collect::<Vec<KubeContainerStatus>>();
         container_statuses.extend(current_statuses.into_iter().map(|(_, v)| v));
         let mut num_succeeded: usize = 0;
         let mut failed = false;
+        let restart_policy = self.restart_policy();
         // TODO(thomastaylor312): Add inferring a message from these container
         // statuses if there is no message passed in the Status object
         for status in container_statuses.iter() {
             // Basically anything is considered running phase in kubernetes
             // unless it is explicitly exited, so don't worry about considering
-            // that state. We only really need to check terminated
+            // that state. We only really need to check terminated containers that
+            // will not be restarted
             if let Some(terminated) = &status.state.as_ref().unwrap().terminated {
+                if restart_policy.restarts(terminated.exit_code) {
+                    continue;
+                }
                 if terminated.exit_code != 0 {
                     failed = true;
                     break;
@@ -149,32 +619,59 @@ impl Pod {
             Phase::Succeeded
         } else if failed {
             Phase::Failed
+        } else if !has_run
+            && container_statuses
+                .iter()
+                .all(|s| s.state.as_ref().and_then(|s| s.waiting.as_ref()).is_some())
+        {
+            Phase::Pending
         } else {
             Phase::Running
         };
 
-        let json_status = serde_json::json!(
-            {
-                "metadata": {
-                    "resourceVersion": "",
-                },
-                "status": {
-                    "phase": phase,
-                    "message": status.message,
-                    "containerStatuses": container_statuses
-                }
-            }
-        );
-
-        debug!("Setting pod status for {} using {:?}", name, json_status);
+        let mut patch = StatusPatch::new()
+            .phase(phase)
+            .container_statuses(container_statuses);
+        if let Some(message) = status.message {
+            patch = patch.message(message);
+        }
 
-        let data = serde_json::to_vec(&json_status).expect("Should always serialize");
-        match api.patch_status(&name, &PatchParams::default(), data).await {
-            Ok(o) => debug!("Pod status returned: {:#?}", o.status),
-            Err(e) => error!("Pod status update failed for {}: {}", name, e),
+        debug!("Setting pod status for {} using {:?}", name, patch);
+        if let Err(e) = patch.apply_for(client, self).await {
+            error!("Pod status update failed for {}: {}", name, e);
         }
     }
 
+    /// Set the image and image ID of a container status from the image the container
+    /// runs, keeping any image ID already reported for it if its module was not fetched
+    fn fill_image(
+        &self,
+        container: &str,
+        status: &mut KubeContainerStatus,
+        previous: Option<&KubeContainerStatus>,
+    ) {
+        let image = self.container_image(container).unwrap_or_default();
+        status.image_id = match self.image_info(container).and_then(|i| i.image_id(&image)) {
+            Some(image_id) => image_id,
+            None => previous.map(|s| s.image_id.clone()).unwrap_or_default(),
+        };
+        status.image = image;
+    }
+
+    /// The image of one of the pod's containers or ephemeral containers
+    pub(crate) fn container_image(&self, container: &str) -> Option<String> {
+        self.containers()
+            .iter()
+            .find(|c| c.name == container)
+            .and_then(|c| c.image.clone())
+            .or_else(|| {
+                self.ephemeral_containers()
+                    .iter()
+                    .find(|c| c.name == container)
+                    .and_then(|c| c.image.clone())
+            })
+    }
+
     /// Get a pod's containers
     pub fn containers(&self) -> &Vec<KubeContainer> {
         self.0
@@ -184,9 +681,20 @@ impl Pod {
             .unwrap_or_else(|| &EMPTY_VEC)
     }
 
-    /// Turn the Pod into the Kubernetes API version of a Pod
-    pub fn into_kube_pod(self) -> KubePod {
+    /// Get the pod's ephemeral containers
+    pub fn ephemeral_containers(&self) -> &[EphemeralContainer] {
         self.0
+            .spec
+            .as_ref()
+            .and_then(|s| s.ephemeral_containers.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Turn the Pod into the Kubernetes API version of a Pod.
+    ///
+    /// This only copies the definition if it is still shared with other clones of the Pod
+    pub fn into_kube_pod(self) -> KubePod {
+        Arc::try_unwrap(self.0).unwrap_or_else(|pod| (*pod).clone())
     }
 
     /// Turn a reference to a Pod into a reference to the Kubernetes API version of a Pod
@@ -197,7 +705,7 @@ impl Pod {
 
 impl std::convert::From<KubePod> for Pod {
     fn from(api_pod: KubePod) -> Self {
-        Self(api_pod)
+        Self::new(api_pod)
     }
 }
 
@@ -208,11 +716,429 @@ impl<'a> std::convert::From<&'a Pod> for &'a KubePod {
 }
 impl std::convert::From<Pod> for KubePod {
     fn from(pod: Pod) -> Self {
+        pod.into_kube_pod()
+    }
+}
+
+/// A pod with the metadata the Kubelet needs to act on it: a name, an explicitly set
+/// namespace, a UID and a spec.
+///
+/// Pods from the API server always have these, but the API types can't promise it.
+/// Pods are checked once when they reach the Kubelet, so the code that patches them or
+/// records events about them never falls back to an empty UID or to the `default`
+/// namespace, which would address the wrong object. It dereferences to the [`Pod`].
+#[derive(Clone, Debug)]
+pub(crate) struct ValidatedPod(Pod);
+
+/// Why a pod can't be a [`ValidatedPod`]
+#[derive(Debug, Error, PartialEq)]
+#[error("pod {name:?} in namespace {namespace:?} has no {missing}")]
+pub(crate) struct InvalidPod {
+    name: String,
+    namespace: String,
+    missing: &'static str,
+}
+
+impl ValidatedPod {
+    /// Check that the pod has the metadata the Kubelet needs
+    pub(crate) fn new(pod: Pod) -> Result<Self, InvalidPod> {
+        let metadata = pod.0.metadata.as_ref();
+        let present = |value: Option<&String>| value.filter(|v| !v.is_empty()).is_some();
+        let missing = if !present(metadata.and_then(|m| m.name.as_ref())) {
+            Some("name")
+        } else if !present(metadata.and_then(|m| m.namespace.as_ref())) {
+            Some("namespace")
+        } else if !present(metadata.and_then(|m| m.uid.as_ref())) {
+            Some("uid")
+        } else if pod.0.spec.is_none() {
+            Some("spec")
+        } else {
+            None
+        };
+        match missing {
+            Some(missing) => Err(InvalidPod {
+                name: metadata.and_then(|m| m.name.clone()).unwrap_or_default(),
+                namespace: metadata
+                    .and_then(|m| m.namespace.clone())
+                    .unwrap_or_default(),
+                missing,
+            }),
+            None => Ok(ValidatedPod(pod)),
+        }
+    }
+
+    /// Get the pod's UID
+    pub(crate) fn uid(&self) -> &str {
+        self.0.uid().expect("validated pods have a uid")
+    }
+
+    /// Get the pod's spec
+    pub(crate) fn spec(&self) -> &PodSpec {
+        self.0
+            .as_kube_pod()
+            .spec
+            .as_ref()
+            .expect("validated pods have a spec")
+    }
+}
+
+impl From<ValidatedPod> for Pod {
+    fn from(pod: ValidatedPod) -> Self {
         pod.0
     }
 }
 
+impl std::ops::Deref for ValidatedPod {
+    type Target = Pod;
+
+    fn deref(&self) -> &Pod {
+        &self.0
+    }
+}
+
 lazy_static::lazy_static! {
     static ref EMPTY_MAP: std::collections::BTreeMap<String, String> = std::collections::BTreeMap::new();
     static ref EMPTY_VEC: Vec<KubeContainer> = Vec::new();
 }
+
+/// Carry the restart count and last state of a container over from its previous
+/// status, counting a restart if it was terminated and is now waiting or running again
+fn carry_restarts(status: &mut KubeContainerStatus, previous: &KubeContainerStatus) {
+    let terminated = |s: &KubeContainerStatus| {
+        s.state
+            .as_ref()
+            .map(|state| state.terminated.is_some())
+            .unwrap_or(false)
+    };
+    status.restart_count = previous.restart_count;
+    status.last_state = previous.last_state.clone();
+    if terminated(previous) && !terminated(status) {
+        status.restart_count += 1;
+        status.last_state = previous.state.clone();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module_store::ModuleStore;
+    use crate::status::ContainerStatus;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    #[test]
+    fn test_validated_pod() {
+        let pod = ValidatedPod::new(Pod::new(fake_pod("foo", "apps"))).unwrap();
+        assert_eq!("apps-foo-uid", pod.uid());
+        assert_eq!("apps", pod.namespace());
+        assert_eq!(1, pod.spec().containers.len());
+
+        let mut kube_pod = fake_pod("foo", "apps");
+        kube_pod.metadata.as_mut().unwrap().namespace = None;
+        let error = ValidatedPod::new(Pod::new(kube_pod)).unwrap_err();
+        assert_eq!(
+            "pod \"foo\" in namespace \"\" has no namespace",
+            error.to_string()
+        );
+
+        let mut kube_pod = fake_pod("foo", "apps");
+        kube_pod.metadata.as_mut().unwrap().uid = Some(String::new());
+        assert_eq!(
+            "uid",
+            ValidatedPod::new(Pod::new(kube_pod)).unwrap_err().missing
+        );
+
+        let mut kube_pod = fake_pod("foo", "apps");
+        kube_pod.spec = None;
+        assert_eq!(
+            "spec",
+            ValidatedPod::new(Pod::new(kube_pod)).unwrap_err().missing
+        );
+        assert_eq!(
+            "name",
+            ValidatedPod::new(Pod::default()).unwrap_err().missing
+        );
+    }
+
+    #[test]
+    fn test_spec_accessors() {
+        let mut kube_pod = fake_pod("foo", "default");
+        let spec = kube_pod.spec.as_mut().unwrap();
+        spec.restart_policy = Some("OnFailure".to_owned());
+        spec.tolerations = Some(vec![Toleration {
+            key: Some("krustlet/arch".to_owned()),
+            ..Default::default()
+        }]);
+        spec.containers[0].ports = Some(vec![ContainerPort {
+            container_port: 8080,
+            ..Default::default()
+        }]);
+        let pod = Pod::new(kube_pod);
+
+        assert_eq!(Some("default-foo-uid"), pod.uid());
+        assert_eq!(RestartPolicy::OnFailure, pod.restart_policy());
+        assert_eq!(1, pod.tolerations().len());
+        let ports = pod.ports();
+        assert_eq!(1, ports.len());
+        assert_eq!("foo", ports[0].0);
+        assert_eq!(8080, ports[0].1.container_port);
+        assert!(pod.owner_references().is_empty());
+        assert_eq!(None, pod.deletion_grace_period_seconds());
+        assert_eq!(30, pod.termination_grace_period_seconds());
+        assert_eq!(0, pod.priority());
+        assert_eq!(
+            RestartPolicy::Always,
+            Pod::new(fake_pod("bar", "default")).restart_policy()
+        );
+    }
+
+    #[test]
+    fn test_hostname() {
+        let pod = Pod::new(fake_pod("foo", "default"));
+        assert_eq!("foo", pod.hostname());
+        assert_eq!(None, pod.subdomain());
+        assert_eq!(None, pod.fqdn());
+
+        let mut kube_pod = fake_pod("foo", "default");
+        let spec = kube_pod.spec.as_mut().unwrap();
+        spec.hostname = Some("db-0".to_owned());
+        spec.subdomain = Some("db".to_owned());
+        let pod = Pod::new(kube_pod);
+        assert_eq!("db-0", pod.hostname());
+        assert_eq!(
+            Some("db-0.db.default.svc.cluster.local"),
+            pod.fqdn().as_deref()
+        );
+        let pod = pod.with_cluster_domain("example.com".into());
+        assert_eq!(
+            Some("db-0.db.default.svc.example.com"),
+            pod.fqdn().as_deref()
+        );
+
+        let long_name = format!("{}-{}", "a".repeat(62), "b".repeat(10));
+        let pod = Pod::new(fake_pod(&long_name, "default"));
+        assert_eq!("a".repeat(62), pod.hostname());
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut kube_pod = fake_pod("foo", "default");
+        let spec = kube_pod.spec.as_mut().unwrap();
+        spec.priority_class_name = Some("system-node-critical".to_owned());
+        assert_eq!(2_000_001_000, Pod::new(kube_pod.clone()).priority());
+        assert!(Pod::new(kube_pod.clone()).is_critical());
+
+        // The value resolved by the API server wins over the class name
+        kube_pod.spec.as_mut().unwrap().priority = Some(100);
+        assert_eq!(100, Pod::new(kube_pod.clone()).priority());
+        assert!(!Pod::new(kube_pod).is_critical());
+    }
+
+    #[test]
+    fn test_clone_shares_definition() {
+        let pod = Pod::new(fake_pod("foo", "default"));
+        let cloned = pod.clone();
+        assert!(std::ptr::eq(pod.as_kube_pod(), cloned.as_kube_pod()));
+
+        // Converting a shared pod copies it and leaves the other clone intact
+        let kube_pod = pod.into_kube_pod();
+        assert_eq!(&kube_pod, cloned.as_kube_pod());
+        assert_eq!(
+            Some("foo".to_owned()),
+            cloned.into_kube_pod().metadata.unwrap().name
+        );
+    }
+
+    #[test]
+    fn test_spec_comparison() {
+        let pod = Pod::new(fake_pod("foo", "default"));
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.metadata.as_mut().unwrap().labels = Some(
+            vec![("app".to_owned(), "foo".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        let relabeled = Pod::new(kube_pod.clone());
+        assert!(pod.spec_eq(&relabeled));
+        assert_eq!(pod.spec_hash(), relabeled.spec_hash());
+
+        kube_pod.spec.as_mut().unwrap().containers[0].image = Some("foo:v2".to_owned());
+        let changed = Pod::new(kube_pod);
+        assert!(!pod.spec_eq(&changed));
+        assert_ne!(pod.spec_hash(), changed.spec_hash());
+    }
+
+    async fn patch_container(pod: &Pod, server: &MockApiServer, status: ContainerStatus) {
+        let mut container_statuses = HashMap::new();
+        container_statuses.insert(pod.name().to_owned(), status);
+        pod.patch_status(
+            server.client(),
+            Status {
+                message: None,
+                container_statuses,
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_patch_status_follows_restart_policy() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut kube_pod = fake_pod("job", "default");
+        kube_pod.spec.as_mut().unwrap().restart_policy = Some("OnFailure".to_owned());
+        kube_pod.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", kube_pod.clone());
+        let pod = Pod::new(kube_pod);
+        let terminated = |exit_code| ContainerStatus::Terminated {
+            timestamp: Utc::now(),
+            message: "done".to_owned(),
+            exit_code,
+        };
+        let current = || server.get("/api/v1/namespaces/default/pods/job").unwrap();
+
+        // A failed container will be restarted, so the pod keeps running
+        patch_container(&pod, &server, terminated(2)).await;
+        let status = &current()["status"];
+        assert_eq!("Running", status["phase"]);
+        let container = &status["containerStatuses"][0];
+        assert_eq!(2, container["state"]["terminated"]["exitCode"]);
+        assert_eq!("Error", container["state"]["terminated"]["reason"]);
+        assert!(container["state"]["terminated"]["finishedAt"].is_string());
+
+        patch_container(
+            &pod,
+            &server,
+            ContainerStatus::Running {
+                timestamp: Utc::now(),
+            },
+        )
+        .await;
+        let container = &current()["status"]["containerStatuses"][0];
+        assert_eq!(1, container["restartCount"]);
+        assert_eq!(2, container["lastState"]["terminated"]["exitCode"]);
+
+        patch_container(&pod, &server, terminated(0)).await;
+        let status = &current()["status"];
+        assert_eq!("Succeeded", status["phase"]);
+        assert_eq!(
+            "Completed",
+            status["containerStatuses"][0]["state"]["terminated"]["reason"]
+        );
+        assert_eq!(1, status["containerStatuses"][0]["restartCount"]);
+
+        assert!(RestartPolicy::Always.restarts(0));
+        assert!(!RestartPolicy::OnFailure.restarts(0));
+        assert!(!RestartPolicy::Never.restarts(1));
+    }
+
+    #[tokio::test]
+    async fn test_waiting_containers_keep_a_running_pod_running() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut kube_pod = fake_pod("waits", "default");
+        kube_pod.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", kube_pod.clone());
+        let pod = Pod::new(kube_pod);
+        let waiting = || ContainerStatus::waiting("Starting container");
+        let phase = || {
+            server.get("/api/v1/namespaces/default/pods/waits").unwrap()["status"]["phase"].clone()
+        };
+
+        // Containers that have yet to start leave the pod pending
+        patch_container(&pod, &server, waiting()).await;
+        assert_eq!("Pending", phase());
+
+        patch_container(
+            &pod,
+            &server,
+            ContainerStatus::Running {
+                timestamp: Utc::now(),
+            },
+        )
+        .await;
+        assert_eq!("Running", phase());
+        // Waiting to start again doesn't move the pod back to pending
+        patch_container(&pod, &server, waiting()).await;
+        assert_eq!("Running", phase());
+    }
+
+    struct StaticStore;
+
+    #[async_trait::async_trait]
+    impl ModuleStore for StaticStore {
+        async fn get(&self, _image_ref: &oci_distribution::Reference) -> anyhow::Result<Vec<u8>> {
+            Ok(b"module".to_vec())
+        }
+
+        async fn image_digest(&self, _pod: &Pod, _image: &str) -> Option<String> {
+            Some("sha256:manifest".to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_patch_status_reports_image_id() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut kube_pod = fake_pod("imaged", "default");
+        kube_pod.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", kube_pod.clone());
+        let pod = Pod::new(kube_pod);
+
+        StaticStore.fetch_pod_modules(&pod).await.unwrap();
+        assert_eq!(
+            Some(ImageInfo {
+                digest: Some("sha256:manifest".to_owned()),
+                size: 6,
+            }),
+            pod.image_info("imaged")
+        );
+
+        let mut container_statuses = HashMap::new();
+        container_statuses.insert(
+            "imaged".to_owned(),
+            ContainerStatus::Running {
+                timestamp: Utc::now(),
+            },
+        );
+        pod.patch_status(
+            server.client(),
+            Status {
+                message: None,
+                container_statuses,
+            },
+        )
+        .await;
+
+        let patched = server
+            .get("/api/v1/namespaces/default/pods/imaged")
+            .expect("pod should exist");
+        let status = &patched["status"]["containerStatuses"][0];
+        assert_eq!("fake.registry.io/imaged:v1", status["image"]);
+        assert_eq!("fake.registry.io/imaged@sha256:manifest", status["imageID"]);
+    }
+
+    #[tokio::test]
+    async fn test_finalizers_kept_on_conflict() {
+        const PATH: &str = "/api/v1/namespaces/default/pods/foo";
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("foo", "default"),
+        );
+        let stale: KubePod = serde_json::from_value(server.get(PATH).unwrap()).unwrap();
+        // Another controller adds its finalizer after the Kubelet read the pod
+        server.modify(
+            PATH,
+            &serde_json::json!({ "metadata": { "finalizers": ["other"] } }),
+        );
+
+        let pod = Pod::new(stale);
+        pod.add_finalizer(server.client()).await.unwrap();
+        let finalizers = &server.get(PATH).unwrap()["metadata"]["finalizers"];
+        assert_eq!(&serde_json::json!(["other", POD_FINALIZER]), finalizers);
+
+        let pod = Pod::new(serde_json::from_value(server.get(PATH).unwrap()).unwrap());
+        let added = serde_json::json!({ "metadata": { "finalizers": ["other", POD_FINALIZER, "another"] } });
+        server.modify(PATH, &added);
+        pod.remove_finalizer(server.client()).await.unwrap();
+        let finalizers = &server.get(PATH).unwrap()["metadata"]["finalizers"];
+        assert_eq!(&serde_json::json!(["other", "another"]), finalizers);
+    }
+}
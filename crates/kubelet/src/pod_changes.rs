@@ -0,0 +1,224 @@
+//! What changed in a pod's definition between two updates
+//!
+//! Most of a running pod's spec can't be changed, but its container images, labels,
+//! annotations and `activeDeadlineSeconds` can, and the environment of its containers
+//! may be changed by tools that recreate pods in place. When a running pod is modified,
+//! the Kubelet passes [`PodChanges`] from the definition it last gave the provider to
+//! [`Provider::modify_with_changes`], so providers can update only what changed:
+//!
+//! ```rust,no_run
+//! # use kubelet::pod_changes::PodChanges;
+//! # fn restart(container: &str) {}
+//! # fn example(changes: PodChanges) {
+//! for image in &changes.images {
+//!     restart(&image.container);
+//! }
+//! if changes.labels.is_empty() && changes.annotations.is_empty() {
+//!     // Nothing the workload can see about itself changed
+//! }
+//! # }
+//! ```
+//!
+//! [`Provider::modify_with_changes`]: crate::provider::Provider::modify_with_changes
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::Container as KubeContainer;
+
+use crate::pod::Pod;
+
+/// A value that changed, with what it was before and what it is now
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change<T> {
+    /// The value before the change
+    pub from: T,
+    /// The value after the change
+    pub to: T,
+}
+
+/// The image of a container that changed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageChange {
+    /// The name of the container
+    pub container: String,
+    /// The image the container had before
+    pub from: Option<String>,
+    /// The image the container has now
+    pub to: Option<String>,
+}
+
+/// The changes to a map of labels or annotations
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MapChanges {
+    /// The keys that were added or changed, with their new values
+    pub changed: BTreeMap<String, String>,
+    /// The keys that were removed
+    pub removed: Vec<String>,
+}
+
+impl MapChanges {
+    /// Returns true if no keys were added, changed or removed
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    fn between(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Self {
+        MapChanges {
+            changed: new
+                .iter()
+                .filter(|(key, value)| old.get(*key) != Some(*value))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            removed: old
+                .keys()
+                .filter(|key| !new.contains_key(*key))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// What changed in a pod's definition
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PodChanges {
+    /// The containers and init containers whose image changed
+    pub images: Vec<ImageChange>,
+    /// The names of the containers and init containers whose `env` or `envFrom` changed
+    pub env: Vec<String>,
+    /// The changes to the pod's labels
+    pub labels: MapChanges,
+    /// The changes to the pod's annotations
+    pub annotations: MapChanges,
+    /// The change to the pod's `activeDeadlineSeconds`, if it changed
+    pub active_deadline_seconds: Option<Change<Option<i64>>>,
+}
+
+impl PodChanges {
+    /// The changes from the old definition of a pod to the new one. Containers are
+    /// matched by name, and containers that were added or removed are not included as
+    /// the containers of a pod can't change once it is created
+    pub fn between(old: &Pod, new: &Pod) -> Self {
+        let old_containers = containers(old);
+        let mut images = Vec::new();
+        let mut env = Vec::new();
+        for container in containers(new) {
+            let previous = match old_containers.iter().find(|c| c.name == container.name) {
+                Some(previous) => previous,
+                None => continue,
+            };
+            if previous.image != container.image {
+                images.push(ImageChange {
+                    container: container.name.clone(),
+                    from: previous.image.clone(),
+                    to: container.image.clone(),
+                });
+            }
+            if previous.env != container.env || previous.env_from != container.env_from {
+                env.push(container.name.clone());
+            }
+        }
+
+        let deadline = |pod: &Pod| {
+            pod.as_kube_pod()
+                .spec
+                .as_ref()
+                .and_then(|s| s.active_deadline_seconds)
+        };
+        let (from, to) = (deadline(old), deadline(new));
+        PodChanges {
+            images,
+            env,
+            labels: MapChanges::between(old.labels(), new.labels()),
+            annotations: MapChanges::between(old.annotations(), new.annotations()),
+            active_deadline_seconds: if from != to {
+                Some(Change { from, to })
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns true if nothing that is tracked changed
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+            && self.env.is_empty()
+            && self.labels.is_empty()
+            && self.annotations.is_empty()
+            && self.active_deadline_seconds.is_none()
+    }
+}
+
+/// The init containers and containers of a pod
+fn containers(pod: &Pod) -> Vec<&KubeContainer> {
+    let init_containers = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.init_containers.as_ref());
+    init_containers
+        .into_iter()
+        .flatten()
+        .chain(pod.containers())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+    use k8s_openapi::api::core::v1::EnvVar;
+
+    #[test]
+    fn test_pod_changes() {
+        let mut old = fake_pod("foo", "default");
+        {
+            let metadata = old.metadata.as_mut().unwrap();
+            let mut labels = BTreeMap::new();
+            labels.insert("app".to_owned(), "foo".to_owned());
+            labels.insert("tier".to_owned(), "web".to_owned());
+            metadata.labels = Some(labels);
+        }
+        let old = Pod::new(old);
+        assert!(PodChanges::between(&old, &old).is_empty());
+
+        let mut new = old.as_kube_pod().clone();
+        {
+            let metadata = new.metadata.as_mut().unwrap();
+            let labels = metadata.labels.as_mut().unwrap();
+            labels.insert("app".to_owned(), "bar".to_owned());
+            labels.remove("tier");
+            let spec = new.spec.as_mut().unwrap();
+            spec.active_deadline_seconds = Some(60);
+            let container = &mut spec.containers[0];
+            container.image = Some("example.com/foo:v2".to_owned());
+            container.env = Some(vec![EnvVar {
+                name: "DEBUG".to_owned(),
+                value: Some("1".to_owned()),
+                ..Default::default()
+            }]);
+        }
+        let new = Pod::new(new);
+        let changes = PodChanges::between(&old, &new);
+        let container = &old.containers()[0];
+        assert_eq!(
+            vec![ImageChange {
+                container: container.name.clone(),
+                from: container.image.clone(),
+                to: Some("example.com/foo:v2".to_owned()),
+            }],
+            changes.images
+        );
+        assert_eq!(vec![container.name.clone()], changes.env);
+        assert_eq!(Some(&"bar".to_owned()), changes.labels.changed.get("app"));
+        assert_eq!(1, changes.labels.changed.len());
+        assert_eq!(vec!["tier".to_owned()], changes.labels.removed);
+        assert!(changes.annotations.is_empty());
+        assert_eq!(
+            Some(Change {
+                from: None,
+                to: Some(60)
+            }),
+            changes.active_deadline_seconds
+        );
+        assert!(!changes.is_empty());
+    }
+}
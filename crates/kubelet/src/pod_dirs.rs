@@ -0,0 +1,268 @@
+//! The directories each pod's data is kept in on the node
+//!
+//! Everything the Kubelet and providers keep on the node for a pod lives in a directory
+//! named after the pod's UID in the Kubelet's data directory, so a pod that is deleted
+//! and created again with the same name never sees the data of the one before it:
+//!
+//! ```text
+//! <data dir>/pods/<pod UID>/
+//!     volumes/<volume>/    the volumes of the pod, see VolumeRef
+//!     logs/                the logs of its containers kept by the provider
+//! ```
+//!
+//! Service account tokens are mounted into pods as secret volumes, so they are kept
+//! with the other volumes of the pod.
+//!
+//! Providers find the directories of a pod with [`Pod::data_dirs`]. The Kubelet
+//! regularly removes the directories of pods that are no longer bound to the node, so
+//! providers don't have to clean up after pods that were force deleted or removed
+//! while the Kubelet was not running. Providers that kept pod data in directories of
+//! their own before remove them with [`remove_legacy_dirs`] when they start.
+//!
+//! ```rust,no_run
+//! # async fn example(config: kubelet::config::Config, pod: kubelet::Pod) -> anyhow::Result<()> {
+//! let dirs = pod.data_dirs(&config.data_dir);
+//! dirs.create().await?;
+//! let log_file = dirs.logs().join("main.log");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Pod::data_dirs`]: crate::Pod::data_dirs
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, ListParams};
+use log::{debug, info, warn};
+
+use crate::pod::Pod;
+use crate::rate_limit::RateLimits;
+use crate::registry::PodRegistry;
+
+/// The directory in the data directory that holds the directories of every pod
+pub const PODS_DIR: &str = "pods";
+
+/// How often the directories of pods that no longer exist are removed
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The directories of a pod
+#[derive(Clone, Debug, PartialEq)]
+pub struct PodDirs {
+    root: PathBuf,
+}
+
+impl PodDirs {
+    /// The directories of the given pod in the given data directory
+    pub fn new(data_dir: &Path, pod: &Pod) -> Self {
+        PodDirs {
+            root: data_dir.join(PODS_DIR).join(dir_name(pod)),
+        }
+    }
+
+    /// The directory that holds all of the pod's data
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The directory the pod's volumes are set up in, each in a directory of its own
+    pub fn volumes(&self) -> PathBuf {
+        self.root.join("volumes")
+    }
+
+    /// The directory the logs of the pod's containers are kept in
+    pub fn logs(&self) -> PathBuf {
+        self.root.join("logs")
+    }
+
+    /// Create all of the pod's directories if they don't exist yet
+    pub async fn create(&self) -> std::io::Result<()> {
+        for dir in &[self.volumes(), self.logs()] {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of a pod's directory. Pods always have a UID once they are created, but
+/// pods made up by providers or tests may not, so they are named after their
+/// namespace and name instead, which can't be mistaken for a UID
+fn dir_name(pod: &Pod) -> String {
+    match pod.uid() {
+        Some(uid) => uid.to_owned(),
+        None => format!("{}_{}", pod.namespace(), pod.name()),
+    }
+}
+
+/// The directory in the data directory that the volumes of every pod were set up in
+/// before they were kept in the pod directories
+pub const LEGACY_VOLUMES_DIR: &str = "volumes";
+
+/// Remove the given directories of the data directory, which held the data of pods
+/// before it was kept in the pod directories. The pods whose data they held are set up
+/// again in their own directories, so nothing in them is used anymore.
+pub async fn remove_legacy_dirs(data_dir: &Path, names: &[&str]) {
+    for name in names {
+        let dir = data_dir.join(name);
+        match tokio::fs::remove_dir_all(&dir).await {
+            Ok(()) => info!("Removed pod data kept in {} before", dir.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => warn!("Unable to remove pod data kept in {}: {}", dir.display(), e),
+        }
+    }
+}
+
+/// Regularly remove the directories of pods that are neither known to the Kubelet nor
+/// bound to the node
+pub(crate) async fn collect_garbage(
+    client: kube::Client,
+    limits: RateLimits,
+    data_dir: PathBuf,
+    node_name: String,
+    registry: PodRegistry,
+) {
+    loop {
+        tokio::time::delay_for(GC_INTERVAL).await;
+        if let Err(e) = remove_orphans(&client, &limits, &data_dir, &node_name, &registry).await {
+            warn!("Unable to remove the data of deleted pods: {}", e);
+        }
+    }
+}
+
+/// Remove the directories of pods that don't exist anymore, returning how many were
+/// removed
+async fn remove_orphans(
+    client: &kube::Client,
+    limits: &RateLimits,
+    data_dir: &Path,
+    node_name: &str,
+    registry: &PodRegistry,
+) -> anyhow::Result<usize> {
+    let root = data_dir.join(PODS_DIR);
+    // The directories are read before the pods are listed, so the pod of every
+    // directory found was created before the list
+    let mut dirs = Vec::new();
+    let mut entries = match tokio::fs::read_dir(&root).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            dirs.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    if dirs.is_empty() {
+        return Ok(0);
+    }
+
+    let api: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams {
+        field_selector: Some(format!("spec.nodeName={}", node_name)),
+        ..Default::default()
+    };
+    let mut existing: HashSet<String> = limits
+        .limited(api.list(&params))
+        .await?
+        .items
+        .into_iter()
+        .map(|pod| dir_name(&Pod::new(pod)))
+        .collect();
+    existing.extend(registry.pods().iter().map(|entry| dir_name(&entry.pod)));
+
+    let mut removed = 0;
+    for dir in dirs.iter().filter(|dir| !existing.contains(*dir)) {
+        debug!("Removing the data of deleted pod {}", dir);
+        match tokio::fs::remove_dir_all(root.join(dir)).await {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("Unable to remove the data of deleted pod {}: {}", dir, e),
+        }
+    }
+    if removed > 0 {
+        info!("Removed the data of {} deleted pods", removed);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::State;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    fn with_uid(name: &str, uid: &str, node_name: &str) -> KubePod {
+        let mut pod = fake_pod(name, "default");
+        pod.metadata.as_mut().unwrap().uid = Some(uid.to_owned());
+        pod.spec.as_mut().unwrap().node_name = Some(node_name.to_owned());
+        pod
+    }
+
+    #[test]
+    fn test_pod_dirs() {
+        let data_dir = Path::new("/var/lib/krustlet");
+        let dirs = Pod::new(with_uid("foo", "1234", "node")).data_dirs(data_dir);
+        assert_eq!(Path::new("/var/lib/krustlet/pods/1234"), dirs.root());
+        assert_eq!(
+            Path::new("/var/lib/krustlet/pods/1234/volumes"),
+            dirs.volumes()
+        );
+        let mut without_uid = fake_pod("foo", "apps");
+        without_uid.metadata.as_mut().unwrap().uid = None;
+        let dirs = Pod::new(without_uid).data_dirs(data_dir);
+        assert_eq!(Path::new("/var/lib/krustlet/pods/apps_foo"), dirs.root());
+    }
+
+    #[tokio::test]
+    async fn test_remove_legacy_dirs() {
+        let data_dir =
+            std::env::temp_dir().join(format!("krustlet-legacy-dirs-{}", std::process::id()));
+        let legacy = data_dir.join(LEGACY_VOLUMES_DIR).join("foo-default");
+        std::fs::create_dir_all(&legacy).unwrap();
+        std::fs::write(legacy.join("creds"), "hunter2").unwrap();
+        let dirs = Pod::new(with_uid("foo", "1234", "node")).data_dirs(&data_dir);
+        dirs.create().await.unwrap();
+
+        remove_legacy_dirs(&data_dir, &[LEGACY_VOLUMES_DIR, "wasi-logs"]).await;
+        assert!(!data_dir.join(LEGACY_VOLUMES_DIR).exists());
+        assert!(dirs.volumes().exists());
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_orphans() {
+        let data_dir =
+            std::env::temp_dir().join(format!("krustlet-pod-dirs-{}", std::process::id()));
+        let server = MockApiServer::start().await.unwrap();
+        let running = with_uid("running", "1", "node");
+        let elsewhere = with_uid("elsewhere", "2", "other");
+        let deleted = with_uid("deleted", "3", "node");
+        let known = with_uid("known", "4", "node");
+        server.insert("/api/v1/namespaces/default/pods", running.clone());
+        server.insert("/api/v1/namespaces/default/pods", elsewhere.clone());
+        let registry = PodRegistry::default();
+        registry.set_state(&Pod::new(known.clone()), State::Running);
+        for pod in &[&running, &elsewhere, &deleted, &known] {
+            let dirs = Pod::new((*pod).clone()).data_dirs(&data_dir);
+            dirs.create().await.unwrap();
+            std::fs::write(dirs.logs().join("main.log"), "hello").unwrap();
+        }
+
+        let removed = remove_orphans(
+            &server.client(),
+            &RateLimits::default(),
+            &data_dir,
+            "node",
+            &registry,
+        )
+        .await
+        .unwrap();
+        assert_eq!(2, removed);
+        let remaining = |pod: &KubePod| Pod::new(pod.clone()).data_dirs(&data_dir).root().exists();
+        assert!(remaining(&running));
+        assert!(remaining(&known));
+        assert!(!remaining(&elsewhere));
+        assert!(!remaining(&deleted));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+//! Writing container logs to files for node level log shippers
+//!
+//! Like the Kubernetes kubelet, the Kubelet can write the logs of each container to
+//! `<dir>/<namespace>_<pod name>_<pod uid>/<container>/0.log`, with `/var/log/pods` as
+//! the directory, where log shippers such as Fluent Bit or Promtail collect them
+//! without any configuration. Each line is in the CRI logging format,
+//! `<RFC 3339 timestamp> <stream> <tag> <log line>`, where the tag is `F` for a full
+//! line.
+//!
+//! Providers that use a [`PodHandle`](crate::handle::PodHandle) get these files by
+//! calling [`PodHandle::with_log_files`](crate::handle::PodHandle::with_log_files)
+//! with the configured [`pod_log_dir`](crate::config::Config::pod_log_dir).
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use log::warn;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+
+use crate::Pod;
+
+/// The directory the Kubernetes kubelet writes container logs to
+pub const DEFAULT_DIR: &str = "/var/log/pods";
+
+/// The name of a container's log file in its directory. The Kubernetes kubelet counts
+/// up from here as the container restarts, but restarted modules write to the same file
+pub const LOG_FILE: &str = "0.log";
+
+/// How often a container's output is checked for new lines. Lines are timestamped when
+/// they are copied, so this is also how late their timestamps can be
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a pod's log directory is kept once its logs are written, so log shippers
+/// can still collect the last lines
+const REMOVAL_DELAY: Duration = Duration::from_secs(60);
+
+/// Returns the directory, within the given log directory, that holds the log files of
+/// a pod's containers
+pub fn pod_log_dir(dir: &Path, pod: &Pod) -> PathBuf {
+    dir.join(format!(
+        "{}_{}_{}",
+        pod.namespace(),
+        pod.name(),
+        pod.uid().unwrap_or_default()
+    ))
+}
+
+/// Returns the log file of a pod's container within the given log directory
+pub fn container_log_path(dir: &Path, pod: &Pod, container_name: &str) -> PathBuf {
+    pod_log_dir(dir, pod).join(container_name).join(LOG_FILE)
+}
+
+/// The log files of a pod's containers.
+///
+/// Output is copied to the files until this is dropped. Once everything the containers
+/// wrote before then has been copied, the pod's log directory is removed a minute
+/// later.
+pub struct PodLogFiles {
+    dir: Arc<PodLogDir>,
+    /// Dropped to tell the copying tasks to finish
+    running: Arc<()>,
+}
+
+/// Removes a pod's log directory, after a delay, when the last task writing to it is
+/// done
+struct PodLogDir {
+    path: PathBuf,
+    removal_delay: Duration,
+}
+
+impl Drop for PodLogDir {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        let delay = self.removal_delay;
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    tokio::time::delay_for(delay).await;
+                    remove_pod_log_dir(&path).await;
+                });
+            }
+            // Outside of a runtime there is nothing to wait on
+            Err(_) => {
+                if let Err(e) = std::fs::remove_dir_all(&path) {
+                    warn_unremoved(&path, e);
+                }
+            }
+        }
+    }
+}
+
+async fn remove_pod_log_dir(path: &Path) {
+    if let Err(e) = tokio::fs::remove_dir_all(path).await {
+        warn_unremoved(path, e);
+    }
+}
+
+fn warn_unremoved(path: &Path, e: std::io::Error) {
+    if e.kind() != std::io::ErrorKind::NotFound {
+        warn!(
+            "Unable to remove pod log directory {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+impl PodLogFiles {
+    /// Create the log files of a pod's containers in the given log directory
+    pub fn new(dir: &Path, pod: &Pod) -> Self {
+        Self::with_removal_delay(dir, pod, REMOVAL_DELAY)
+    }
+
+    fn with_removal_delay(dir: &Path, pod: &Pod, removal_delay: Duration) -> Self {
+        PodLogFiles {
+            dir: Arc::new(PodLogDir {
+                path: pod_log_dir(dir, pod),
+                removal_delay,
+            }),
+            running: Arc::new(()),
+        }
+    }
+
+    /// Copy the output of a container, read from the start, to its log file
+    pub fn write<R>(&self, container_name: &str, output: R)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let dir = self.dir.clone();
+        let container_dir = dir.path.join(container_name);
+        let running = Arc::downgrade(&self.running);
+        tokio::spawn(async move {
+            if let Err(e) = copy_lines(output, container_dir.clone(), running).await {
+                warn!(
+                    "Unable to write container log file in {}: {}",
+                    container_dir.display(),
+                    e
+                );
+            }
+            // Only remove the directory once nothing is writing to it
+            drop(dir);
+        });
+    }
+}
+
+/// Copy lines from the output to the log file in the given directory until the output
+/// has no more lines and `running` is dropped
+async fn copy_lines<R: AsyncRead + Unpin>(
+    output: R,
+    dir: PathBuf,
+    running: Weak<()>,
+) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(&dir).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(LOG_FILE))
+        .await?;
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    let mut stopping = false;
+    loop {
+        if output.read_until(b'\n', &mut line).await? > 0 {
+            // A line without a newline is still being written
+            if line.ends_with(b"\n") {
+                file.write_all(&cri_line(&line)).await?;
+                line.clear();
+            }
+            continue;
+        }
+        if stopping {
+            if !line.is_empty() {
+                file.write_all(&cri_line(&line)).await?;
+            }
+            return file.flush().await;
+        }
+        // Writes to the file only finish once it is flushed
+        file.flush().await?;
+        // Read once more after being stopped, in case anything was written in between
+        stopping = running.upgrade().is_none();
+        if !stopping {
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Formats a line of output in the CRI logging format. Providers do not tell stdout
+/// and stderr apart, so all output is reported as stdout
+fn cri_line(line: &[u8]) -> Vec<u8> {
+    let line = if line.ends_with(b"\n") {
+        &line[..line.len() - 1]
+    } else {
+        line
+    };
+    let mut formatted = format!(
+        "{} stdout F ",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true)
+    )
+    .into_bytes();
+    formatted.extend_from_slice(line);
+    formatted.push(b'\n');
+    formatted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+
+    async fn wait_for<F: Fn() -> bool>(condition: F) {
+        for _ in 0..50 {
+            if condition() {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        panic!("condition was not met in time");
+    }
+
+    fn log_lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| {
+                let mut parts = line.splitn(4, ' ');
+                let timestamp = parts.next().unwrap();
+                chrono::DateTime::parse_from_rfc3339(timestamp).unwrap();
+                assert_eq!(Some("stdout"), parts.next());
+                assert_eq!(Some("F"), parts.next());
+                parts.next().unwrap().to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_container_log_path() {
+        let mut pod = fake_pod("hello", "default");
+        pod.metadata.as_mut().unwrap().uid = Some("1234".to_owned());
+        assert_eq!(
+            PathBuf::from("/var/log/pods/default_hello_1234/greet/0.log"),
+            container_log_path(Path::new(DEFAULT_DIR), &pod.into(), "greet")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_lines() {
+        let dir = std::env::temp_dir().join(format!("krustlet-copy-lines-{}", std::process::id()));
+        let output = dir.join("output");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&output, "hello\nwor").unwrap();
+
+        let running = Arc::new(());
+        let copy = tokio::spawn(copy_lines(
+            tokio::fs::File::open(&output).await.unwrap(),
+            dir.join("greet"),
+            Arc::downgrade(&running),
+        ));
+        let log_path = dir.join("greet").join(LOG_FILE);
+        wait_for(|| log_lines(&log_path) == vec!["hello"]).await;
+
+        // Lines are only written once they are complete, and the rest of the output is
+        // written once copying stops
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&output)
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"ld\ngoodbye").unwrap();
+        wait_for(|| log_lines(&log_path) == vec!["hello", "world"]).await;
+        drop(running);
+        copy.await.unwrap().unwrap();
+        assert_eq!(vec!["hello", "world", "goodbye"], log_lines(&log_path));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let dir = std::env::temp_dir().join(format!("krustlet-pod-logs-{}", std::process::id()));
+        let output = dir.join("output");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&output, "hello\n").unwrap();
+
+        let pod = Pod::new(fake_pod("hello", "default"));
+        let files = PodLogFiles::with_removal_delay(&dir, &pod, Duration::from_millis(500));
+        files.write("greet", tokio::fs::File::open(&output).await.unwrap());
+        let log_path = container_log_path(&dir, &pod, "greet");
+        wait_for(|| log_lines(&log_path) == vec!["hello"]).await;
+
+        // The pod's directory is kept for a while once its logs are written, so log
+        // shippers can collect the last lines, and then removed
+        drop(files);
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(pod_log_dir(&dir, &pod).exists());
+        wait_for(|| !pod_log_dir(&dir, &pod).exists()).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! Fetching modules before the pods that use them arrive
+//!
+//! Nodes that are known to run certain workloads, such as newly provisioned edge nodes,
+//! can fetch their modules as soon as the Kubelet starts, so the first pods to use them
+//! don't wait on a pull (or, with providers that compile modules, on a compilation).
+//! The images to prefetch come from the [`PrefetchConfig`]:
+//!
+//! - `images` lists image references, as in `--prefetch-images`
+//! - with `from_node`, the [`PREFETCH_IMAGES_ANNOTATION`] of the Kubelet's node lists
+//!   more references, separated by commas or new lines. Blank entries and lines
+//!   starting with `#` are ignored. The node is read again every minute, so images
+//!   added to the annotation later are prefetched too. The node authorizer only lets
+//!   a Kubelet read the config maps of its pods, so the list is kept on the node,
+//!   where operators can set it with `kubectl annotate node`
+//!
+//! Each image is passed to [`Provider::prefetch`] once. Images that fail to prefetch,
+//! for example because the registry is not reachable yet, are tried again a minute later.
+//!
+//! [`PrefetchConfig`]: crate::config::PrefetchConfig
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::Api;
+use log::{debug, info, warn};
+
+use crate::config::PrefetchConfig;
+use crate::provider::{NotImplementedError, Provider};
+use crate::rate_limit::RateLimits;
+
+/// The annotation of the node that lists more images to prefetch
+pub const PREFETCH_IMAGES_ANNOTATION: &str = "alpha.krustlet.dev/prefetch-images";
+
+/// How often the node is read again and failed images are retried
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Prefetch the configured images, and those added to the node's annotation later
+pub(crate) async fn run<P: Provider + Sync + Send>(
+    client: kube::Client,
+    limits: RateLimits,
+    node_name: String,
+    config: PrefetchConfig,
+    provider: Arc<P>,
+) {
+    if config.images.is_empty() && !config.from_node {
+        return;
+    }
+    let mut prefetched = HashSet::new();
+    loop {
+        let mut images = config.images.clone();
+        if config.from_node {
+            match listed_images(&client, &limits, &node_name).await {
+                Ok(listed) => images.extend(listed),
+                Err(e) => warn!(
+                    "Unable to read the images to prefetch from node {}: {}",
+                    node_name, e
+                ),
+            }
+        }
+        if !prefetch_images(provider.as_ref(), &images, &mut prefetched).await {
+            info!("The provider does not prefetch images, not prefetching any");
+            return;
+        }
+        // Without the annotation no more images will be added, so there is nothing left
+        // to do once all of them are prefetched
+        if !config.from_node && images.iter().all(|i| prefetched.contains(i)) {
+            return;
+        }
+        tokio::time::delay_for(REFRESH_INTERVAL).await;
+    }
+}
+
+/// Prefetch the images that haven't been yet, recording the ones that succeed.
+/// Returns false if the provider doesn't prefetch images
+async fn prefetch_images<P: Provider + Sync>(
+    provider: &P,
+    images: &[String],
+    prefetched: &mut HashSet<String>,
+) -> bool {
+    for image in images {
+        if prefetched.contains(image) {
+            continue;
+        }
+        debug!("Prefetching image {}", image);
+        match provider.prefetch(image).await {
+            Ok(()) => {
+                info!("Prefetched image {}", image);
+                prefetched.insert(image.clone());
+            }
+            Err(e) if e.is::<NotImplementedError>() => return false,
+            Err(e) => warn!(
+                "Unable to prefetch image {}, trying again later: {:#}",
+                image, e
+            ),
+        }
+    }
+    true
+}
+
+/// The images listed in the annotation of the node. A missing node lists none, as it
+/// is only registered once the Kubelet has started
+async fn listed_images(
+    client: &kube::Client,
+    limits: &RateLimits,
+    node_name: &str,
+) -> anyhow::Result<Vec<String>> {
+    let api: Api<KubeNode> = Api::all(client.clone());
+    let node = match limits.limited(api.get(node_name)).await {
+        Ok(node) => node,
+        Err(kube::Error::Api(e)) if e.code == 404 => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(node
+        .metadata
+        .and_then(|metadata| metadata.annotations)
+        .and_then(|mut annotations| annotations.remove(PREFETCH_IMAGES_ANNOTATION))
+        .map(|list| parse_image_list(&list))
+        .unwrap_or_default())
+}
+
+fn parse_image_list(list: &str) -> Vec<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|image| !image.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{FakeProvider, MockApiServer, Operation};
+
+    #[tokio::test]
+    async fn test_prefetch_images() {
+        let provider = FakeProvider::new();
+        provider.fail_image("unreachable.io/app:v1", "connection refused");
+        let images = vec![
+            "example.com/app:v1".to_owned(),
+            "unreachable.io/app:v1".to_owned(),
+        ];
+        let mut prefetched = HashSet::new();
+        assert!(prefetch_images(&provider, &images, &mut prefetched).await);
+        assert_eq!(1, prefetched.len());
+
+        // Only the image that failed is tried again
+        assert!(prefetch_images(&provider, &images, &mut prefetched).await);
+        assert_eq!(1, prefetched.len());
+        assert_eq!(
+            vec![
+                "example.com/app:v1",
+                "unreachable.io/app:v1",
+                "unreachable.io/app:v1"
+            ],
+            provider.prefetches()
+        );
+
+        // Providers that don't prefetch are only asked once
+        let provider = FakeProvider::new();
+        provider.not_implemented(Operation::Prefetch);
+        assert!(!prefetch_images(&provider, &images, &mut HashSet::new()).await);
+        assert_eq!(vec!["example.com/app:v1"], provider.prefetches());
+    }
+
+    #[tokio::test]
+    async fn test_listed_images() {
+        let server = MockApiServer::start().await.unwrap();
+        let limits = RateLimits::default();
+        assert!(listed_images(&server.client(), &limits, "krustlet")
+            .await
+            .unwrap()
+            .is_empty());
+        server.insert(
+            "/api/v1/nodes",
+            serde_json::json!({
+                "metadata": {
+                    "name": "krustlet",
+                    "annotations": {
+                        PREFETCH_IMAGES_ANNOTATION: "# edge workloads\nexample.com/app:v1\n\n  example.com/sensor:v2, example.com/gateway:v1,\n",
+                    },
+                },
+            }),
+        );
+        assert_eq!(
+            vec![
+                "example.com/app:v1",
+                "example.com/sensor:v2",
+                "example.com/gateway:v1"
+            ],
+            listed_images(&server.client(), &limits, "krustlet")
+                .await
+                .unwrap()
+        );
+    }
+}
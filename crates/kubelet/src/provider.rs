@@ -1,14 +1,30 @@
 //! Traits and types need to create backend providers for a Kubelet
 use async_trait::async_trait;
-use k8s_openapi::api::core::v1::{ConfigMap, Container, EnvVarSource, Pod as KubePod, Secret};
-use kube::api::{Api, WatchEvent};
+use hyper::{Body, Request, Response};
+use k8s_openapi::api::core::v1::{Container, EnvVarSource, NodeCondition, Pod as KubePod};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use k8s_openapi::ByteString;
+use kube::api::WatchEvent;
+use kube::error::ErrorResponse;
 use log::{error, info};
 use thiserror::Error;
 
+use crate::autoscaling::ScalingHints;
+use crate::error::PodSyncError;
 use crate::logs::LogSender;
+use crate::node::NodeBuilder;
+use crate::object_manager::{get_config_map, get_secret};
 use crate::pod::Pod;
+use crate::pod_changes::PodChanges;
+use crate::stats::PodStats;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+mod composite;
+pub mod sdk;
+
+pub use composite::CompositeProvider;
 
 /// A back-end for a Kubelet.
 ///
@@ -49,15 +65,141 @@ pub trait Provider {
     /// Arch returns a string specifying what architecture this provider supports
     const ARCH: &'static str;
 
+    /// The operating system the node reports in its `kubernetes.io/os` labels and node
+    /// info. Wasm workloads don't depend on the host's operating system, so this is
+    /// `linux` unless the provider runs workloads that do
+    const OS: &'static str = "linux";
+
+    /// Customize the Node object registered for this Kubelet.
+    ///
+    /// The builder already contains the defaults derived from the Kubelet
+    /// configuration. The default implementation leaves them unchanged.
+    async fn node(&self, _builder: &mut NodeBuilder) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// The custom resource definitions the provider depends on, such as the capability
+    /// bindings of its actors.
+    ///
+    /// The Kubelet applies them when it starts, before it registers the node, and waits
+    /// for the API server to establish them. With
+    /// [`Config::skip_crd_registration`](crate::config::Config::skip_crd_registration)
+    /// they are only checked to be installed already. Either way the Kubelet does not
+    /// start without them, or if this returns an error. The default implementation
+    /// depends on none.
+    fn custom_resource_definitions(&self) -> anyhow::Result<Vec<CustomResourceDefinition>> {
+        Ok(Vec::new())
+    }
+
+    /// Report custom conditions about the health of the provider, such as
+    /// `RegistryReachable`, to add to the node's status.
+    ///
+    /// This is called every time the node status is updated. A condition replaces any
+    /// default condition of the same type, and its heartbeat and transition times are
+    /// filled in by the Kubelet when they are not set. Errors are logged and leave
+    /// out the provider's conditions for that update. The default implementation
+    /// reports no conditions.
+    async fn node_conditions(&self) -> anyhow::Result<Vec<NodeCondition>> {
+        Ok(Vec::new())
+    }
+
+    /// Report how many more pods the provider can run and why pods are waiting on it,
+    /// for cluster autoscalers to decide whether to scale up.
+    ///
+    /// This is called every time the node status is updated, and the hints are
+    /// published on the node as described in [`autoscaling`](crate::autoscaling).
+    /// Errors are logged and leave out the hints for that update. The default
+    /// implementation reports no hints.
+    async fn scaling_hints(&self) -> anyhow::Result<Option<ScalingHints>> {
+        Ok(None)
+    }
+
+    /// Check whether the provider is able to run pods, such as whether its runtime is
+    /// working.
+    ///
+    /// This is called every time the node status is updated. While it returns an error,
+    /// the node's `Ready` condition is reported as `False` with the error as its
+    /// message, so no new pods are scheduled to the node.
+    ///
+    /// A provider that is restarting, or can't serve its running pods for a while,
+    /// returns [`ProviderError::Unavailable`]: the `Ready` condition of its running pods
+    /// is then reported as `False` with the reason `ProviderUnavailable` until the
+    /// provider is healthy again. The default implementation always reports the provider
+    /// as healthy.
+    async fn health(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Report the resources currently used by a pod's containers.
+    ///
+    /// This is called whenever usage is collected, such as for the Summary API, and is
+    /// compared by the Kubelet against the pod's requests and limits. Usage that the
+    /// runtime cannot measure can be left out. The default implementation reports that
+    /// this feature is not available.
+    async fn pod_stats(&self, _pod: &Pod) -> anyhow::Result<PodStats> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Fetch the module of an image before any pod uses it, and prepare it to run if the
+    /// runtime can, such as by compiling it.
+    ///
+    /// This is called for each image the Kubelet is configured to prefetch (see
+    /// [`prefetch`](crate::prefetch)), so pods that use the image later start without
+    /// waiting for it. The default implementation reports that this feature is not
+    /// available.
+    async fn prefetch(&self, _image: &str) -> anyhow::Result<()> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Set up what a pod as a whole needs before its containers can start, such as
+    /// its network, volumes and service account token.
+    ///
+    /// This is called once for each new pod, before [`Provider::add`]. If it fails, the
+    /// pod's containers are not started and the pod is reported as having failed to set
+    /// up rather than as having a failed container. The default implementation does
+    /// nothing, for providers that set pods up as part of starting their containers.
+    async fn setup_pod(&self, _pod: &Pod) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Undo what [`Provider::setup_pod`] set up for a pod.
+    ///
+    /// This is called once the pod's workload has been deleted with
+    /// [`Provider::delete`], including when [`Provider::add`] failed. Errors are only
+    /// logged. The default implementation does nothing.
+    async fn teardown_pod(&self, _pod: &Pod) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     /// Given a Pod definition, execute the workload.
+    ///
+    /// If the workload can't start yet, for example because it waits for an external
+    /// resource, return a [`PodSyncError::Requeue`](crate::error::PodSyncError::Requeue)
+    /// to have the pod added again later rather than waiting here.
     async fn add(&self, pod: Pod) -> anyhow::Result<()>;
 
     /// Given an updated Pod definition, update the given workload.
     ///
     /// Pods that are sent to this function have already met certain criteria for modification.
     /// For example, updates to the `status` of a Pod will not be sent into this function.
+    ///
+    /// When a pod is marked for deletion, this is called once with the `deletionTimestamp` set
+    /// and should stop the workload. The Kubelet waits up to the pod's grace period for it to
+    /// return before reporting the containers as terminated and removing the pod.
     async fn modify(&self, pod: Pod) -> anyhow::Result<()>;
 
+    /// Given an updated Pod definition and what changed since the definition last passed
+    /// to the provider, update the given workload.
+    ///
+    /// The Kubelet calls this in place of [`Provider::modify`] for pods the provider has
+    /// already added, so providers can update only what changed, such as restarting the
+    /// containers whose image changed, rather than comparing entire pods themselves.
+    /// Pods marked for deletion are still passed to [`Provider::modify`]. The default
+    /// implementation ignores the changes and calls [`Provider::modify`].
+    async fn modify_with_changes(&self, pod: Pod, _changes: PodChanges) -> anyhow::Result<()> {
+        self.modify(pod).await
+    }
+
     /// Given the definition of a deleted Pod, remove the workload from the runtime.
     ///
     /// This does not need to actually delete the Pod definition -- just destroy the
@@ -81,29 +223,58 @@ pub trait Provider {
         Err(NotImplementedError.into())
     }
 
+    /// Start an ephemeral container in an already running pod.
+    ///
+    /// This is called when a new container is added to the pod's ephemeral containers (for
+    /// example by `kubectl debug`). The ephemeral container has already been converted into a
+    /// regular container spec, and should be given access to the pod's volumes and
+    /// environment, which can be resolved with
+    /// [`Container::resolve`](crate::container::Container::resolve).
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn add_ephemeral_container(
+        &self,
+        _pod: Pod,
+        _container: Container,
+    ) -> anyhow::Result<()> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Whether the provider has its own routes on the Kubelet server under the given name.
+    ///
+    /// Requests to `/providers/<name>/...` are passed to [`Provider::route`] when this
+    /// returns true, so providers can serve debug or control endpoints without running
+    /// a server of their own. The default implementation has no routes.
+    fn has_routes(&self, _name: &str) -> bool {
+        false
+    }
+
+    /// Serve a request to one of the provider's routes.
+    ///
+    /// `name` is the name the routes are registered under and `path` is the rest of the
+    /// request path after `/providers/<name>`, such as `/debug`. Requests have already
+    /// been authenticated and authorized by the Kubelet. Returning a
+    /// [`NotImplementedError`] responds with a 404. The default implementation serves
+    /// no routes.
+    async fn route(
+        &self,
+        _name: &str,
+        _path: &str,
+        _req: Request<Body>,
+    ) -> anyhow::Result<Response<Body>> {
+        Err(NotImplementedError.into())
+    }
+
     /// Determine what to do when a new event comes in.
     ///
     /// In most cases, this should not be overridden. It is exposed for rare cases when
     /// the underlying event handling needs to change.
-    async fn handle_event(&self, event: WatchEvent<KubePod>) -> anyhow::Result<()> {
+    async fn handle_event(&self, event: PodEvent) -> anyhow::Result<()> {
         match event {
-            WatchEvent::Added(pod) => {
-                let pod = pod.into();
-                self.add(pod).await
-            }
-            WatchEvent::Modified(pod) => {
-                let pod = pod.into();
-                self.modify(pod).await
-            }
-            WatchEvent::Deleted(pod) => {
-                let pod = pod.into();
-                self.delete(pod).await
-            }
-            WatchEvent::Error(e) => {
-                error!("Event error: {}", e);
-                Err(e.into())
-            }
-            WatchEvent::Bookmark(_) => Ok(()),
+            PodEvent::Added(pod) => self.add(pod).await,
+            PodEvent::Modified(pod) => self.modify(pod).await,
+            PodEvent::Deleted(pod) => self.delete(pod).await,
         }
     }
 
@@ -113,108 +284,219 @@ pub trait Provider {
     /// environment variable resolution in a special way, such as allowing
     /// custom Downward API fields.
     ///
+    /// Secrets and config maps are looked up through the given context, which
+    /// caches them so they are only fetched once for all of a pod's containers.
+    /// Variables that refer to a missing object or key are left out if the reference
+    /// is optional, and otherwise fail with a
+    /// [`PodSyncError::ContainerConfig`](crate::error::PodSyncError::ContainerConfig),
+    /// so the pod is retried until the object appears.
+    ///
     /// It is safe to call from within your own providers.
     async fn env_vars(
         container: &Container,
-        pod: &Pod,
-        client: &kube::Client,
-    ) -> HashMap<String, String> {
+        _pod: &Pod,
+        context: &ResolutionContext,
+    ) -> anyhow::Result<HashMap<String, String>> {
         let mut env = HashMap::new();
         let vars = match container.env.as_ref() {
             Some(e) => e,
-            None => return env,
+            None => return Ok(env),
         };
 
         for env_var in vars.clone().into_iter() {
             let key = env_var.name;
             let value = match env_var.value {
-                Some(v) => v,
-                None => {
-                    on_missing_env_value(
-                        env_var.value_from,
-                        client,
-                        pod.namespace(),
-                        &field_map(pod),
-                    )
-                    .await
-                }
+                Some(v) => Some(v),
+                None => on_missing_env_value(env_var.value_from, context).await?,
             };
-            env.insert(key, value);
+            if let Some(value) = value {
+                env.insert(key, value);
+            }
         }
-        env
+        Ok(env)
+    }
+}
+
+/// The objects looked up while resolving the environment of a pod's containers.
+///
+/// Each secret and config map is fetched at most once, no matter how many
+/// containers or variables refer to it, and the Downward API fields are only
+/// computed once. A new context should be used for each sync of a pod so that
+/// changes to the referenced objects are picked up.
+pub struct ResolutionContext {
+    client: kube::Client,
+    pod: Pod,
+    fields: HashMap<String, String>,
+    config_maps: Mutex<HashMap<String, Option<BTreeMap<String, String>>>>,
+    secrets: Mutex<HashMap<String, Option<BTreeMap<String, ByteString>>>>,
+}
+
+impl ResolutionContext {
+    /// Create a new context for resolving the environment of the given pod
+    pub fn new(client: kube::Client, pod: &Pod) -> Self {
+        ResolutionContext {
+            client,
+            pod: pod.clone(),
+            fields: field_map(pod),
+            config_maps: Default::default(),
+            secrets: Default::default(),
+        }
+    }
+
+    /// Get the client used to look up objects
+    pub fn client(&self) -> &kube::Client {
+        &self.client
+    }
+
+    /// Get the Downward API fields available to the pod, keyed by their field path
+    pub fn fields(&self) -> &HashMap<String, String> {
+        &self.fields
+    }
+
+    /// Get a key from a config map in the pod's namespace. Returns `None` if the
+    /// config map or key does not exist, and an error if the config map could not be
+    /// fetched
+    pub async fn config_map_value(&self, name: &str, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .config_map(name)
+            .await?
+            .and_then(|mut data| data.remove(key)))
+    }
+
+    /// Get a key from a secret in the pod's namespace, decoded as UTF-8. Returns
+    /// `None` if the secret or key does not exist, and an error if the secret could not
+    /// be fetched. The values of the secret are redacted from the pod's errors, see
+    /// [`redact`](crate::redact)
+    pub async fn secret_value(&self, name: &str, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .secret(name)
+            .await?
+            .and_then(|mut data| data.remove(key))
+            .map(|s| String::from_utf8(s.0).unwrap_or_default()))
+    }
+
+    /// The data of a config map, or `None` if it does not exist. Only objects that
+    /// were found or do not exist are cached, so failed lookups are tried again
+    async fn config_map(&self, name: &str) -> anyhow::Result<Option<BTreeMap<String, String>>> {
+        if let Some(data) = self.config_maps.lock().unwrap().get(name).cloned() {
+            return Ok(data);
+        }
+        let data = match get_config_map(&self.client, &self.pod, name).await {
+            Ok(cfgmap) => Some(cfgmap.data.unwrap_or_default()),
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => None,
+            Err(e) => {
+                error!("Error fetching config map {}: {}", name, e);
+                return Err(e.into());
+            }
+        };
+        self.config_maps
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), data.clone());
+        Ok(data)
+    }
+
+    /// The data of a secret, or `None` if it does not exist
+    async fn secret(&self, name: &str) -> anyhow::Result<Option<BTreeMap<String, ByteString>>> {
+        if let Some(data) = self.secrets.lock().unwrap().get(name).cloned() {
+            return Ok(data);
+        }
+        let data = match get_secret(&self.client, &self.pod, name).await {
+            Ok(secret) => Some(secret.data.unwrap_or_default()),
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => None,
+            Err(e) => {
+                error!("Error fetching secret {}: {}", name, e);
+                return Err(e.into());
+            }
+        };
+        self.secrets
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), data.clone());
+        Ok(data)
     }
 }
 
 /// Called when an env var does not have a value associated with.
 ///
-/// This follows the env_var_source to get the value
+/// This follows the env_var_source to get the value. Returns `None` if the variable
+/// should be left out because the object or key it optionally refers to is missing
 #[doc(hidden)]
 async fn on_missing_env_value(
     env_var_source: Option<EnvVarSource>,
-    client: &kube::Client,
-    ns: &str,
-    fields: &HashMap<String, String>,
-) -> String {
+    context: &ResolutionContext,
+) -> anyhow::Result<Option<String>> {
     let env_src = match env_var_source {
         Some(env_src) => env_src,
-        None => return String::new(),
+        None => return Ok(Some(String::new())),
     };
 
     // ConfigMaps
     if let Some(cfkey) = env_src.config_map_key_ref.as_ref() {
         let name = cfkey.name.as_deref().unwrap_or_default();
-        match Api::<ConfigMap>::namespaced(client.clone(), ns)
-            .get(name)
-            .await
-        {
-            Ok(cfgmap) => {
-                // I am not totally clear on what the outcome should
-                // be of a cfgmap key miss. So for now just return an
-                // empty default.
-                return cfgmap
-                    .data
-                    .unwrap_or_default()
-                    .get(&cfkey.key)
-                    .cloned()
-                    .unwrap_or_default();
-            }
-            Err(e) => {
-                error!("Error fetching config map {}: {}", name, e);
-                return "".to_string();
-            }
-        }
+        let value = match context.config_map(name).await.map_err(config_error)? {
+            Some(mut data) => data.remove(&cfkey.key).ok_or_else(|| {
+                format!(
+                    "couldn't find key {} in ConfigMap {}/{}",
+                    cfkey.key,
+                    context.pod.namespace(),
+                    name
+                )
+            }),
+            None => Err(format!("configmap {:?} not found", name)),
+        };
+        return optional(value, cfkey.optional);
     }
     // Secrets
     if let Some(seckey) = env_src.secret_key_ref.as_ref() {
         let name = seckey.name.as_deref().unwrap_or_default();
-        match Api::<Secret>::namespaced(client.clone(), ns)
-            .get(name)
-            .await
-        {
-            Ok(secret) => {
-                // I am not totally clear on what the outcome should
-                // be of a secret key miss. So for now just return an
-                // empty default.
-                return secret
-                    .data
-                    .unwrap_or_default()
-                    .remove(&seckey.key)
-                    .map(|s| String::from_utf8(s.0).unwrap_or_default())
-                    .unwrap_or_default();
-            }
-            Err(e) => {
-                error!("Error fetching secret {}: {}", name, e);
-                return String::new();
-            }
-        }
+        let value = match context.secret(name).await.map_err(config_error)? {
+            Some(_) => context
+                .secret_value(name, &seckey.key)
+                .await
+                .map_err(config_error)?
+                .ok_or_else(|| {
+                    format!(
+                        "couldn't find key {} in Secret {}/{}",
+                        seckey.key,
+                        context.pod.namespace(),
+                        name
+                    )
+                }),
+            None => Err(format!("secret {:?} not found", name)),
+        };
+        return optional(value, seckey.optional);
     }
     // Downward API (Field Refs)
     if let Some(cfkey) = env_src.field_ref.as_ref() {
-        return fields.get(&cfkey.field_path).cloned().unwrap_or_default();
+        return Ok(Some(
+            context
+                .fields()
+                .get(&cfkey.field_path)
+                .cloned()
+                .unwrap_or_default(),
+        ));
     }
     // Reource Fields (Not implementable just yet... need more of a model.)
 
-    String::new()
+    Ok(Some(String::new()))
+}
+
+/// Leave out a variable whose optional object or key is missing, and fail for required
+/// ones
+fn optional(
+    value: Result<String, String>,
+    optional: Option<bool>,
+) -> anyhow::Result<Option<String>> {
+    match value {
+        Ok(value) => Ok(Some(value)),
+        Err(_) if optional.unwrap_or(false) => Ok(None),
+        Err(missing) => Err(config_error(anyhow::anyhow!(missing))),
+    }
+}
+
+fn config_error(error: anyhow::Error) -> anyhow::Error {
+    PodSyncError::ContainerConfig(error).into()
 }
 
 /// Build the map of allowable field_ref values.
@@ -247,6 +529,46 @@ fn field_map(pod: &Pod) -> HashMap<String, String> {
     map
 }
 
+/// A change to a pod assigned to this node
+#[derive(Clone, Debug)]
+pub enum PodEvent {
+    /// The pod was added
+    Added(Pod),
+    /// The pod was modified
+    Modified(Pod),
+    /// The pod was deleted
+    Deleted(Pod),
+}
+
+impl PodEvent {
+    /// Convert an event from a Kubernetes watch. Errors and bookmarks do not refer to a
+    /// pod, so they return `None`
+    pub fn from_watch_event(event: WatchEvent<KubePod>) -> Option<Self> {
+        match event {
+            WatchEvent::Added(pod) => Some(PodEvent::Added(pod.into())),
+            WatchEvent::Modified(pod) => Some(PodEvent::Modified(pod.into())),
+            WatchEvent::Deleted(pod) => Some(PodEvent::Deleted(pod.into())),
+            WatchEvent::Bookmark(_) | WatchEvent::Error(_) => None,
+        }
+    }
+
+    /// Get the pod the event is for
+    pub fn pod(&self) -> &Pod {
+        match self {
+            PodEvent::Added(pod) | PodEvent::Modified(pod) | PodEvent::Deleted(pod) => pod,
+        }
+    }
+
+    /// Replace the pod of the event with the one `f` returns for it
+    pub(crate) fn map_pod<F: FnOnce(Pod) -> Pod>(self, f: F) -> Self {
+        match self {
+            PodEvent::Added(pod) => PodEvent::Added(f(pod)),
+            PodEvent::Modified(pod) => PodEvent::Modified(f(pod)),
+            PodEvent::Deleted(pod) => PodEvent::Deleted(f(pod)),
+        }
+    }
+}
+
 /// A Provider error
 #[derive(Debug, Error)]
 pub enum ProviderError {
@@ -264,6 +586,12 @@ pub enum ProviderError {
         /// The container's name
         container_name: String,
     },
+    /// The provider is restarting or otherwise can't serve its pods for a while
+    #[error("provider is unavailable: {}", message)]
+    Unavailable {
+        /// Why the provider is unavailable
+        message: String,
+    },
 }
 
 /// A specific operation is not implemented
@@ -0,0 +1,560 @@
+//! A provider that delegates each pod to one of several child providers
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use hyper::{Body, Request, Response};
+use k8s_openapi::api::core::v1::{Container, NodeCondition};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use log::debug;
+use tokio::sync::RwLock;
+
+use super::{Provider, ResolutionContext};
+use crate::autoscaling::ScalingHints;
+use crate::crds;
+use crate::handle::{key_from_pod, pod_key};
+use crate::logs::LogSender;
+use crate::node::NodeBuilder;
+use crate::pod::Pod;
+use crate::pod_changes::PodChanges;
+use crate::stats::PodStats;
+
+type Predicate = Box<dyn Fn(&Pod) -> bool + Send + Sync>;
+
+/// An object safe version of [`Provider`] so providers of different types can be stored
+/// together
+#[async_trait]
+trait ChildProvider: Send + Sync {
+    async fn node(&self, builder: &mut NodeBuilder) -> anyhow::Result<()>;
+    fn custom_resource_definitions(&self) -> anyhow::Result<Vec<CustomResourceDefinition>>;
+    async fn node_conditions(&self) -> anyhow::Result<Vec<NodeCondition>>;
+    async fn scaling_hints(&self) -> anyhow::Result<Option<ScalingHints>>;
+    async fn health(&self) -> anyhow::Result<()>;
+    async fn pod_stats(&self, pod: &Pod) -> anyhow::Result<PodStats>;
+    async fn prefetch(&self, image: &str) -> anyhow::Result<()>;
+    async fn setup_pod(&self, pod: &Pod) -> anyhow::Result<()>;
+    async fn teardown_pod(&self, pod: &Pod) -> anyhow::Result<()>;
+    async fn add(&self, pod: Pod) -> anyhow::Result<()>;
+    async fn modify(&self, pod: Pod) -> anyhow::Result<()>;
+    async fn modify_with_changes(&self, pod: Pod, changes: PodChanges) -> anyhow::Result<()>;
+    async fn delete(&self, pod: Pod) -> anyhow::Result<()>;
+    async fn logs(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        sender: LogSender,
+    ) -> anyhow::Result<()>;
+    async fn exec(&self, pod: Pod, command: String) -> anyhow::Result<Vec<String>>;
+    async fn add_ephemeral_container(&self, pod: Pod, container: Container) -> anyhow::Result<()>;
+    fn has_routes(&self, name: &str) -> bool;
+    async fn route(
+        &self,
+        name: &str,
+        path: &str,
+        req: Request<Body>,
+    ) -> anyhow::Result<Response<Body>>;
+}
+
+#[async_trait]
+impl<P: Provider + Send + Sync> ChildProvider for P {
+    async fn node(&self, builder: &mut NodeBuilder) -> anyhow::Result<()> {
+        Provider::node(self, builder).await
+    }
+
+    fn custom_resource_definitions(&self) -> anyhow::Result<Vec<CustomResourceDefinition>> {
+        Provider::custom_resource_definitions(self)
+    }
+
+    async fn node_conditions(&self) -> anyhow::Result<Vec<NodeCondition>> {
+        Provider::node_conditions(self).await
+    }
+
+    async fn scaling_hints(&self) -> anyhow::Result<Option<ScalingHints>> {
+        Provider::scaling_hints(self).await
+    }
+
+    async fn health(&self) -> anyhow::Result<()> {
+        Provider::health(self).await
+    }
+
+    async fn pod_stats(&self, pod: &Pod) -> anyhow::Result<PodStats> {
+        Provider::pod_stats(self, pod).await
+    }
+
+    async fn prefetch(&self, image: &str) -> anyhow::Result<()> {
+        Provider::prefetch(self, image).await
+    }
+
+    async fn setup_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        Provider::setup_pod(self, pod).await
+    }
+
+    async fn teardown_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        Provider::teardown_pod(self, pod).await
+    }
+
+    async fn add(&self, pod: Pod) -> anyhow::Result<()> {
+        Provider::add(self, pod).await
+    }
+
+    async fn modify(&self, pod: Pod) -> anyhow::Result<()> {
+        Provider::modify(self, pod).await
+    }
+
+    async fn modify_with_changes(&self, pod: Pod, changes: PodChanges) -> anyhow::Result<()> {
+        Provider::modify_with_changes(self, pod, changes).await
+    }
+
+    async fn delete(&self, pod: Pod) -> anyhow::Result<()> {
+        Provider::delete(self, pod).await
+    }
+
+    async fn logs(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        sender: LogSender,
+    ) -> anyhow::Result<()> {
+        Provider::logs(self, namespace, pod, container, sender).await
+    }
+
+    async fn exec(&self, pod: Pod, command: String) -> anyhow::Result<Vec<String>> {
+        Provider::exec(self, pod, command).await
+    }
+
+    async fn add_ephemeral_container(&self, pod: Pod, container: Container) -> anyhow::Result<()> {
+        Provider::add_ephemeral_container(self, pod, container).await
+    }
+
+    fn has_routes(&self, name: &str) -> bool {
+        Provider::has_routes(self, name)
+    }
+
+    async fn route(
+        &self,
+        name: &str,
+        path: &str,
+        req: Request<Body>,
+    ) -> anyhow::Result<Response<Body>> {
+        Provider::route(self, name, path, req).await
+    }
+}
+
+/// Identifies which provider a pod was scheduled on. `None` is the default provider
+type Assignment = Option<usize>;
+
+/// A [`Provider`] that delegates each pod to one of several child providers.
+///
+/// Child providers are checked in the order they were added and a pod is given to the
+/// first one whose `can_schedule` predicate returns true. Pods that no child accepts go to
+/// the default provider, which also determines the architecture reported for the node and
+/// how environment variables are resolved.
+///
+/// The provider that was chosen for a pod is remembered until the pod is deleted, so the
+/// pod is routed the same way even if its labels or annotations change later.
+///
+/// # Example
+/// ```rust
+/// use kubelet::provider::CompositeProvider;
+/// use kubelet::Provider;
+///
+/// fn route_by_annotation<W, C>(wasi: W, cri: C) -> CompositeProvider<W>
+/// where
+///     W: Provider + Send + Sync + 'static,
+///     C: Provider + Send + Sync + 'static,
+/// {
+///     let mut provider = CompositeProvider::new(wasi);
+///     provider.add_provider(
+///         |pod| pod.get_annotation("krustlet/runtime") == Some("cri"),
+///         cri,
+///     );
+///     provider
+/// }
+/// ```
+pub struct CompositeProvider<P> {
+    default: P,
+    children: Vec<(Predicate, Box<dyn ChildProvider>)>,
+    assignments: RwLock<HashMap<String, Assignment>>,
+}
+
+impl<P: Provider + Send + Sync + 'static> CompositeProvider<P> {
+    /// Create a new composite provider that sends every pod to the given default provider
+    /// until child providers are added
+    pub fn new(default: P) -> Self {
+        CompositeProvider {
+            default,
+            children: Vec::new(),
+            assignments: Default::default(),
+        }
+    }
+
+    /// Add a child provider that will run any pod for which `can_schedule` returns true and
+    /// that was not accepted by a provider added before it
+    pub fn add_provider<C, F>(&mut self, can_schedule: F, provider: C) -> &mut Self
+    where
+        C: Provider + Send + Sync + 'static,
+        F: Fn(&Pod) -> bool + Send + Sync + 'static,
+    {
+        self.children
+            .push((Box::new(can_schedule), Box::new(provider)));
+        self
+    }
+
+    fn provider(&self, assignment: Assignment) -> &dyn ChildProvider {
+        match assignment {
+            Some(i) => self.children[i].1.as_ref(),
+            None => &self.default,
+        }
+    }
+
+    fn schedule(&self, pod: &Pod) -> Assignment {
+        self.children
+            .iter()
+            .position(|(can_schedule, _)| can_schedule(pod))
+    }
+
+    /// Returns the provider a pod was assigned to, or the one it would be assigned to if it
+    /// has not been seen yet
+    async fn assigned(&self, pod: &Pod) -> &dyn ChildProvider {
+        let assignment = match self.assignments.read().await.get(&key_from_pod(pod)) {
+            Some(a) => *a,
+            None => self.schedule(pod),
+        };
+        self.provider(assignment)
+    }
+}
+
+#[async_trait]
+impl<P: Provider + Send + Sync + 'static> Provider for CompositeProvider<P> {
+    const ARCH: &'static str = P::ARCH;
+    const OS: &'static str = P::OS;
+
+    async fn node(&self, builder: &mut NodeBuilder) -> anyhow::Result<()> {
+        Provider::node(&self.default, builder).await?;
+        for (_, child) in self.children.iter() {
+            child.node(builder).await?;
+        }
+        Ok(())
+    }
+
+    /// The definitions of all of the providers. Providers that depend on the same
+    /// definition share it, but it is an error for them to declare it differently
+    fn custom_resource_definitions(&self) -> anyhow::Result<Vec<CustomResourceDefinition>> {
+        let mut crds = Provider::custom_resource_definitions(&self.default)?;
+        for (_, child) in self.children.iter() {
+            for crd in child.custom_resource_definitions()? {
+                match crds.iter().find(|c| crds::name(c) == crds::name(&crd)) {
+                    Some(declared) if declared.spec != crd.spec => {
+                        return Err(anyhow::anyhow!(
+                            "providers declare the custom resource definition {} differently",
+                            crds::name(&crd)
+                        ))
+                    }
+                    Some(_) => (),
+                    None => crds.push(crd),
+                }
+            }
+        }
+        Ok(crds)
+    }
+
+    async fn node_conditions(&self) -> anyhow::Result<Vec<NodeCondition>> {
+        let mut conditions = Provider::node_conditions(&self.default).await?;
+        for (_, child) in self.children.iter() {
+            conditions.extend(child.node_conditions().await?);
+        }
+        Ok(conditions)
+    }
+
+    /// The hints of all of the providers that report them, added together
+    async fn scaling_hints(&self) -> anyhow::Result<Option<ScalingHints>> {
+        let mut hints = Provider::scaling_hints(&self.default).await?;
+        for (_, child) in self.children.iter() {
+            if let Some(child_hints) = child.scaling_hints().await? {
+                match hints.as_mut() {
+                    Some(hints) => hints.merge(child_hints),
+                    None => hints = Some(child_hints),
+                }
+            }
+        }
+        Ok(hints)
+    }
+
+    /// The node can only run pods if all of the providers can
+    async fn health(&self) -> anyhow::Result<()> {
+        Provider::health(&self.default).await?;
+        for (_, child) in self.children.iter() {
+            child.health().await?;
+        }
+        Ok(())
+    }
+
+    async fn pod_stats(&self, pod: &Pod) -> anyhow::Result<PodStats> {
+        self.assigned(pod).await.pod_stats(pod).await
+    }
+
+    /// Images are prefetched by every provider that prefetches, as pods using them may be
+    /// scheduled on any of them. This succeeds if any provider prefetched the image
+    async fn prefetch(&self, image: &str) -> anyhow::Result<()> {
+        let mut result = Provider::prefetch(&self.default, image).await;
+        for (_, child) in self.children.iter() {
+            let child_result = child.prefetch(image).await;
+            result = match (result, child_result) {
+                (Ok(()), _) | (_, Ok(())) => Ok(()),
+                (Err(e), Err(child_e)) if e.is::<super::NotImplementedError>() => Err(child_e),
+                (Err(e), Err(_)) => Err(e),
+            };
+        }
+        result
+    }
+
+    /// Pods are set up by the provider they will be scheduled on when they are added
+    async fn setup_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        self.assigned(pod).await.setup_pod(pod).await
+    }
+
+    async fn teardown_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        self.assigned(pod).await.teardown_pod(pod).await
+    }
+
+    async fn add(&self, pod: Pod) -> anyhow::Result<()> {
+        let assignment = self.schedule(&pod);
+        debug!(
+            "Scheduling pod {} in namespace {} on {}",
+            pod.name(),
+            pod.namespace(),
+            match assignment {
+                Some(i) => format!("child provider {}", i),
+                None => "the default provider".to_owned(),
+            }
+        );
+        self.assignments
+            .write()
+            .await
+            .insert(key_from_pod(&pod), assignment);
+        self.provider(assignment).add(pod).await
+    }
+
+    async fn modify(&self, pod: Pod) -> anyhow::Result<()> {
+        self.assigned(&pod).await.modify(pod).await
+    }
+
+    async fn modify_with_changes(&self, pod: Pod, changes: PodChanges) -> anyhow::Result<()> {
+        self.assigned(&pod)
+            .await
+            .modify_with_changes(pod, changes)
+            .await
+    }
+
+    async fn delete(&self, pod: Pod) -> anyhow::Result<()> {
+        let provider = self.assigned(&pod).await;
+        let key = key_from_pod(&pod);
+        provider.delete(pod).await?;
+        self.assignments.write().await.remove(&key);
+        Ok(())
+    }
+
+    async fn logs(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        sender: LogSender,
+    ) -> anyhow::Result<()> {
+        // Logs only identify the pod by name, so pods that were never added go to the
+        // default provider, which will report them as not found
+        let assignment = self
+            .assignments
+            .read()
+            .await
+            .get(&pod_key(&namespace, &pod))
+            .copied()
+            .flatten();
+        self.provider(assignment)
+            .logs(namespace, pod, container, sender)
+            .await
+    }
+
+    async fn exec(&self, pod: Pod, command: String) -> anyhow::Result<Vec<String>> {
+        self.assigned(&pod).await.exec(pod, command).await
+    }
+
+    async fn add_ephemeral_container(&self, pod: Pod, container: Container) -> anyhow::Result<()> {
+        self.assigned(&pod)
+            .await
+            .add_ephemeral_container(pod, container)
+            .await
+    }
+
+    fn has_routes(&self, name: &str) -> bool {
+        Provider::has_routes(&self.default, name)
+            || self.children.iter().any(|(_, c)| c.has_routes(name))
+    }
+
+    /// Routes are served by the default provider if it has routes under the name, and
+    /// otherwise by the first child provider that does
+    async fn route(
+        &self,
+        name: &str,
+        path: &str,
+        req: Request<Body>,
+    ) -> anyhow::Result<Response<Body>> {
+        if Provider::has_routes(&self.default, name) {
+            return Provider::route(&self.default, name, path, req).await;
+        }
+        match self.children.iter().find(|(_, c)| c.has_routes(name)) {
+            Some((_, child)) => child.route(name, path, req).await,
+            None => Err(super::NotImplementedError.into()),
+        }
+    }
+
+    async fn env_vars(
+        container: &Container,
+        pod: &Pod,
+        context: &ResolutionContext,
+    ) -> anyhow::Result<HashMap<String, String>> {
+        P::env_vars(container, pod, context).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CompositeProvider;
+    use crate::logs::LogSender;
+    use crate::pod::Pod;
+    use crate::provider::Provider;
+    use crate::testing::{fake_pod, Call, FakeProvider, Operation};
+    use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+
+    fn cri_pod(name: &str) -> Pod {
+        let mut pod = fake_pod(name, "default");
+        pod.metadata
+            .as_mut()
+            .unwrap()
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert("runtime".to_owned(), "cri".to_owned());
+        pod.into()
+    }
+
+    fn call(operation: Operation, pod_name: &str) -> Call {
+        Call {
+            operation,
+            namespace: "default".to_owned(),
+            pod_name: pod_name.to_owned(),
+        }
+    }
+
+    fn composite() -> (CompositeProvider<FakeProvider>, FakeProvider, FakeProvider) {
+        let default = FakeProvider::new();
+        let cri = FakeProvider::new();
+        let mut provider = CompositeProvider::new(default.clone());
+        provider.add_provider(
+            |pod| pod.get_annotation("runtime") == Some("cri"),
+            cri.clone(),
+        );
+        (provider, default, cri)
+    }
+
+    fn crd(name: &str, group: &str) -> CustomResourceDefinition {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": name },
+            "spec": {
+                "group": group,
+                "names": { "kind": "Binding", "plural": "bindings" },
+                "scope": "Namespaced",
+                "versions": [{ "name": "v1", "served": true, "storage": true }],
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_custom_resource_definitions() {
+        let (provider, default, cri) = composite();
+        default.set_custom_resource_definitions(vec![crd("bindings.wascc.dev", "wascc.dev")]);
+        cri.set_custom_resource_definitions(vec![
+            crd("bindings.wascc.dev", "wascc.dev"),
+            crd("bindings.cri.dev", "cri.dev"),
+        ]);
+        let crds = provider.custom_resource_definitions().unwrap();
+        assert_eq!(
+            vec!["bindings.wascc.dev", "bindings.cri.dev"],
+            crds.iter().map(crate::crds::name).collect::<Vec<_>>()
+        );
+
+        // Providers can't depend on different definitions of the same name
+        cri.set_custom_resource_definitions(vec![crd("bindings.wascc.dev", "other.dev")]);
+        let error = provider.custom_resource_definitions().unwrap_err();
+        assert_eq!(
+            "providers declare the custom resource definition bindings.wascc.dev differently",
+            error.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_predicate() {
+        let (provider, default, cri) = composite();
+        provider
+            .add(fake_pod("plain", "default").into())
+            .await
+            .unwrap();
+        provider.add(cri_pod("container")).await.unwrap();
+
+        assert_eq!(vec![call(Operation::Add, "plain")], default.calls());
+        assert_eq!(vec![call(Operation::Add, "container")], cri.calls());
+    }
+
+    #[tokio::test]
+    async fn test_first_matching_child_wins() {
+        let (mut provider, default, cri) = composite();
+        let other = FakeProvider::new();
+        provider.add_provider(|_| true, other.clone());
+
+        provider.add(cri_pod("container")).await.unwrap();
+        provider
+            .add(fake_pod("plain", "default").into())
+            .await
+            .unwrap();
+
+        assert!(default.calls().is_empty());
+        assert_eq!(vec![call(Operation::Add, "container")], cri.calls());
+        assert_eq!(vec![call(Operation::Add, "plain")], other.calls());
+    }
+
+    #[tokio::test]
+    async fn test_assignment_is_kept_until_deleted() {
+        let (provider, default, cri) = composite();
+        provider.add(cri_pod("container")).await.unwrap();
+
+        // Removing the annotation should not move the pod to another provider
+        let pod: Pod = fake_pod("container", "default").into();
+        provider.modify(pod.clone()).await.unwrap();
+        let (sender, _body) = hyper::Body::channel();
+        provider
+            .logs(
+                "default".to_owned(),
+                "container".to_owned(),
+                "container".to_owned(),
+                LogSender::new(sender, None, false),
+            )
+            .await
+            .unwrap();
+        provider.delete(pod.clone()).await.unwrap();
+        assert!(default.calls().is_empty());
+        assert_eq!(
+            vec![
+                call(Operation::Add, "container"),
+                call(Operation::Modify, "container"),
+                call(Operation::Logs, "container"),
+                call(Operation::Delete, "container"),
+            ],
+            cri.calls()
+        );
+
+        // Once deleted, the pod is scheduled again from scratch
+        provider.add(pod).await.unwrap();
+        assert_eq!(vec![call(Operation::Add, "container")], default.calls());
+    }
+}
@@ -0,0 +1,194 @@
+//! Writing providers with less boilerplate
+//!
+//! Most providers implement parts of [`Provider`] the same way: they drive their pods
+//! through a [`StateMachine`], which reports the statuses of the pods as they go, keep
+//! a [`PodHandle`] for each running pod to serve its logs from, and pull modules from a
+//! [`ModuleStore`]. The [`provider`] attribute writes those parts of a `Provider`
+//! implementation, with the helper traits of this module, given these arguments:
+//!
+//! - `arch = "..."` sets [`Provider::ARCH`]
+//! - `states` writes `add`, `modify` and `delete` with the state machine of
+//!   [`StateMachineProvider`]
+//! - `handles` writes `logs` with the pod handles of [`HandleProvider`]
+//! - `modules` writes `prefetch` with the module store of [`ModuleProvider`], whose
+//!   [`pull_modules`](ModuleProvider::pull_modules) also pulls the modules of a pod for
+//!   [`PodLifecycle::image_pull`]
+//!
+//! Methods the implementation has already are kept, so a provider can still write any
+//! of them itself, and `#[async_trait]` is added unless the implementation has it. The
+//! environment of containers is resolved with [`Provider::env_vars`] by
+//! [`Container::resolve_all`](crate::container::Container::resolve_all), as for any other
+//! provider.
+//!
+//! # Example
+//! ```rust
+//! use std::collections::HashMap;
+//!
+//! use kubelet::handle::{LogHandleFactory, Stop};
+//! use kubelet::module_store::ModuleStore;
+//! use kubelet::provider::sdk::{
+//!     provider, HandleProvider, ModuleProvider, PodHandles, StateMachineProvider,
+//! };
+//! use kubelet::state::{PodLifecycle, StateMachine, SyncResult};
+//! use kubelet::{Pod, Provider};
+//!
+//! struct Stopper;
+//!
+//! #[async_trait::async_trait]
+//! impl Stop for Stopper {
+//!     async fn stop(&mut self) -> anyhow::Result<()> { Ok(()) }
+//!     async fn wait(&mut self) -> anyhow::Result<()> { Ok(()) }
+//! }
+//!
+//! struct Logs;
+//!
+//! impl LogHandleFactory<tokio::fs::File> for Logs {
+//!     fn new_handle(&self) -> tokio::fs::File { todo!() }
+//! }
+//!
+//! struct MyProvider<S> {
+//!     states: StateMachine,
+//!     handles: PodHandles<Stopper, Logs>,
+//!     store: S,
+//! }
+//!
+//! #[provider(arch = "my-arch", states, handles, modules)]
+//! impl<S: ModuleStore + Send + Sync> Provider for MyProvider<S> {}
+//!
+//! impl<S: ModuleStore + Send + Sync> StateMachineProvider for MyProvider<S> {
+//!     fn state_machine(&self) -> &StateMachine {
+//!         &self.states
+//!     }
+//! }
+//!
+//! impl<S: ModuleStore + Send + Sync> HandleProvider for MyProvider<S> {
+//!     type Stopper = Stopper;
+//!     type Reader = tokio::fs::File;
+//!     type LogHandles = Logs;
+//!
+//!     fn pod_handles(&self) -> &PodHandles<Stopper, Logs> {
+//!         &self.handles
+//!     }
+//! }
+//!
+//! impl<S: ModuleStore + Send + Sync> ModuleProvider for MyProvider<S> {
+//!     type Store = S;
+//!
+//!     fn module_store(&self) -> &S {
+//!         &self.store
+//!     }
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl<S: ModuleStore + Send + Sync> PodLifecycle for MyProvider<S> {
+//!     type Pulled = HashMap<String, Vec<u8>>;
+//!
+//!     async fn image_pull(&self, pod: &Pod) -> anyhow::Result<Self::Pulled> {
+//!         self.pull_modules(pod).await
+//!     }
+//!
+//!     async fn starting(&self, pod: &Pod, modules: Self::Pulled) -> anyhow::Result<SyncResult> {
+//!         // Run the modules and keep a handle to them in `self.handles` ...
+//!         # Ok(SyncResult::Done)
+//!     }
+//!
+//!     async fn terminated(&self, pod: &Pod) -> anyhow::Result<()> {
+//!         // Stop the modules and remove their handle ...
+//!         # Ok(())
+//!     }
+//! }
+//! ```
+//!
+//! [`Provider`]: crate::Provider
+//! [`Provider::ARCH`]: crate::Provider::ARCH
+//! [`Provider::env_vars`]: crate::Provider::env_vars
+//! [`PodLifecycle::image_pull`]: crate::state::PodLifecycle::image_pull
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use oci_distribution::Reference;
+use tokio::io::{AsyncRead, AsyncSeek};
+use tokio::sync::RwLock;
+
+use crate::handle::{pod_key, LogHandleFactory, PodHandle, Stop};
+use crate::logs::LogSender;
+use crate::module_store::ModuleStore;
+use crate::pod::Pod;
+use crate::provider::ProviderError;
+use crate::state::{PodLifecycle, StateMachine};
+
+pub use kubelet_derive::provider;
+
+/// What the code written by [`provider`] refers to, so providers don't need to depend
+/// on the same crates
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow::Result;
+    pub use async_trait::async_trait;
+}
+
+/// A provider that drives its pods through a [`StateMachine`]
+pub trait StateMachineProvider: PodLifecycle {
+    /// The state machine the provider's pods are driven through
+    fn state_machine(&self) -> &StateMachine;
+}
+
+/// The handles of a provider's running pods, keyed by
+/// [`key_from_pod`](crate::handle::key_from_pod)
+pub type PodHandles<S, H> = Arc<RwLock<HashMap<String, PodHandle<S, H>>>>;
+
+/// A provider that keeps a [`PodHandle`] for each of its running pods
+#[async_trait]
+pub trait HandleProvider: Sync {
+    /// What stops the containers of the pods
+    type Stopper: Stop + Send + Sync;
+    /// What the logs of the containers are read with
+    type Reader: AsyncRead + AsyncSeek + Unpin + Send + 'static;
+    /// What creates the readers of the logs
+    type LogHandles: LogHandleFactory<Self::Reader>;
+
+    /// The handles of the provider's running pods
+    fn pod_handles(&self) -> &PodHandles<Self::Stopper, Self::LogHandles>;
+
+    /// Send the logs of a container of a running pod to the sender
+    async fn pod_logs(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        sender: LogSender,
+    ) -> anyhow::Result<()> {
+        let mut handles = self.pod_handles().write().await;
+        let handle = handles
+            .get_mut(&pod_key(&namespace, &pod_name))
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.output(&container_name, sender).await
+    }
+}
+
+/// A provider that runs modules from a [`ModuleStore`]
+#[async_trait]
+pub trait ModuleProvider: Sync {
+    /// The store the modules are pulled into
+    type Store: ModuleStore + Send + Sync;
+
+    /// The store the provider's modules are pulled into
+    fn module_store(&self) -> &Self::Store;
+
+    /// Pull the module of each of the pod's containers, keyed by container name
+    async fn pull_modules(&self, pod: &Pod) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+        self.module_store().fetch_pod_modules(pod).await
+    }
+
+    /// Pull the module of an image into the store before any pod uses it
+    async fn prefetch_module(&self, image: &str) -> anyhow::Result<()> {
+        self.module_store()
+            .get(&Reference::try_from(image)?)
+            .await
+            .map(drop)
+    }
+}
@@ -0,0 +1,236 @@
+//! Backing off from image pulls that keep failing
+//!
+//! Providers only report that the images of a pod could not be pulled, by returning a
+//! [`PodSyncError::ImagePull`](crate::error::PodSyncError::ImagePull) when the pod is
+//! added. As with the Kubernetes kubelet, the pod's containers are then reported as
+//! waiting with the `ErrImagePull` reason and the pull is tried again after a backoff
+//! that doubles with each failure, with the containers reported as `ImagePullBackOff`
+//! in the meantime. The pod is never failed because of a pull, and the backoff starts
+//! over when the pod's spec changes, such as when its image is fixed.
+//!
+//! Pods whose containers' configuration could not be resolved, such as when a secret
+//! they require does not exist yet, are backed off from the same way, with the
+//! containers reported as waiting with the `CreateContainerConfigError` reason until
+//! the configuration can be resolved.
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::time::Instant;
+
+use crate::error::{REASON_CONTAINER_CONFIG, REASON_IMAGE_PULL};
+use crate::pod::Pod;
+use crate::status::{ContainerStatus, Status};
+
+/// The reason containers are reported waiting with while a pull is backed off
+pub(crate) const REASON_BACK_OFF: &str = "ImagePullBackOff";
+
+/// How long to wait before pulling again after the first failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+/// The longest time to wait before pulling again
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A pod whose images could not be pulled, or whose containers' configuration could not
+/// be resolved
+pub(crate) struct PullBackOff {
+    /// The newest version of the pod, which is added again once the backoff is over
+    pod: Pod,
+    spec_hash: u64,
+    /// The image that could not be pulled, or `None` if the configuration could not be
+    /// resolved
+    image: Option<String>,
+    failures: u32,
+    retry_at: Instant,
+    /// Whether the containers have been reported as backing off since the last failure
+    reported: bool,
+}
+
+impl PullBackOff {
+    /// Record a failure to pull the given image of a pod, reporting it on the pod's
+    /// containers. The backoff continues from the previous failure unless the pod's
+    /// spec has changed since
+    pub(crate) async fn failed(
+        previous: Option<PullBackOff>,
+        client: &kube::Client,
+        pod: &Pod,
+        image: &str,
+        message: String,
+    ) -> Self {
+        report(client, pod, Some(image), REASON_IMAGE_PULL, message).await;
+        Self::after(previous, pod, Some(image.to_owned()))
+    }
+
+    /// Record a failure to resolve the configuration of a pod's containers, reporting it
+    /// on all of them. The backoff continues from the previous failure unless the pod's
+    /// spec has changed since
+    pub(crate) async fn config_failed(
+        previous: Option<PullBackOff>,
+        client: &kube::Client,
+        pod: &Pod,
+        message: String,
+    ) -> Self {
+        report(client, pod, None, REASON_CONTAINER_CONFIG, message).await;
+        Self::after(previous, pod, None)
+    }
+
+    fn after(previous: Option<PullBackOff>, pod: &Pod, image: Option<String>) -> Self {
+        let spec_hash = pod.spec_hash();
+        let failures = match previous {
+            Some(previous) if previous.spec_hash == spec_hash => previous.failures + 1,
+            _ => 1,
+        };
+        PullBackOff {
+            pod: pod.clone(),
+            spec_hash,
+            // Containers keep reporting why their configuration could not be resolved
+            reported: image.is_none(),
+            image,
+            failures,
+            retry_at: Instant::now() + backoff(failures),
+        }
+    }
+
+    /// The pod to add again once the backoff is over
+    pub(crate) fn pod(&self) -> &Pod {
+        &self.pod
+    }
+
+    /// When to pull the pod's images again
+    pub(crate) fn retry_at(&self) -> Instant {
+        self.retry_at
+    }
+
+    /// Handle a modification of the pod while the pull is backed off. Returns false if
+    /// the pod's spec changed, in which case its images should be pulled again straight
+    /// away
+    pub(crate) async fn modified(&mut self, client: &kube::Client, pod: &Pod) -> bool {
+        if pod.spec_hash() != self.spec_hash {
+            return false;
+        }
+        self.pod = pod.clone();
+        // Reporting the failure comes back as a modification, after which the
+        // containers are reported as backing off, as the Kubernetes kubelet does on its
+        // next sync
+        if !self.reported {
+            self.reported = true;
+            let image = self.image.as_deref();
+            let message = format!("Back-off pulling image {:?}", image.unwrap_or_default());
+            report(client, pod, image, REASON_BACK_OFF, message).await;
+        }
+        true
+    }
+}
+
+/// Report the containers that use the image as waiting for the given reason. All of
+/// the pod's containers are reported if there is no image or none of them use it, as
+/// the failure is then for the pod as a whole
+async fn report(
+    client: &kube::Client,
+    pod: &Pod,
+    image: Option<&str>,
+    reason: &str,
+    message: String,
+) {
+    let waiting = ContainerStatus::Waiting {
+        timestamp: Utc::now(),
+        reason: Some(reason.to_owned()),
+        message,
+    };
+    let mut containers: Vec<&str> = pod
+        .containers()
+        .iter()
+        .filter(|c| image.is_some() && c.image.as_deref() == image)
+        .map(|c| c.name.as_str())
+        .collect();
+    if containers.is_empty() {
+        containers = pod.containers().iter().map(|c| c.name.as_str()).collect();
+    }
+    let status = Status {
+        message: None,
+        container_statuses: containers
+            .into_iter()
+            .map(|name| (name.to_owned(), waiting.clone()))
+            .collect(),
+    };
+    pod.patch_status(client.clone(), status).await;
+}
+
+/// How long to wait before pulling again after the given number of failures in a row
+fn backoff(failures: u32) -> Duration {
+    INITIAL_BACKOFF
+        .checked_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Duration::from_secs(10), backoff(1));
+        assert_eq!(Duration::from_secs(40), backoff(3));
+        assert_eq!(MAX_BACKOFF, backoff(6));
+        assert_eq!(MAX_BACKOFF, backoff(40));
+    }
+
+    #[tokio::test]
+    async fn test_backoff_resets_on_spec_change() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", &kube_pod);
+        let client = server.client();
+        let pod = Pod::new(kube_pod.clone());
+        let image = "fake.registry.io/foo:v1";
+
+        let first = PullBackOff::failed(None, &client, &pod, image, "not found".into()).await;
+        let second =
+            PullBackOff::failed(Some(first), &client, &pod, image, "not found".into()).await;
+        assert_eq!(2, second.failures);
+        let status = server.get("/api/v1/namespaces/default/pods/foo").unwrap()["status"].clone();
+        assert_eq!("Pending", status["phase"]);
+        let waiting = &status["containerStatuses"][0]["state"]["waiting"];
+        assert_eq!("ErrImagePull", waiting["reason"]);
+        assert_eq!("not found", waiting["message"]);
+
+        let mut backing_off = second;
+        assert!(backing_off.modified(&client, &pod).await);
+        let status = server.get("/api/v1/namespaces/default/pods/foo").unwrap()["status"].clone();
+        let waiting = &status["containerStatuses"][0]["state"]["waiting"];
+        assert_eq!("ImagePullBackOff", waiting["reason"]);
+
+        kube_pod.spec.as_mut().unwrap().containers[0].image = Some("foo:v2".to_owned());
+        let fixed = Pod::new(kube_pod);
+        assert!(!backing_off.modified(&client, &fixed).await);
+        let third = PullBackOff::failed(
+            Some(backing_off),
+            &client,
+            &fixed,
+            "foo:v2",
+            "denied".into(),
+        )
+        .await;
+        assert_eq!(1, third.failures);
+    }
+
+    #[tokio::test]
+    async fn test_config_backoff() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", &kube_pod);
+        let client = server.client();
+        let pod = Pod::new(kube_pod);
+
+        let message = "secret \"creds\" not found".to_owned();
+        let mut backing_off = PullBackOff::config_failed(None, &client, &pod, message).await;
+        assert!(backing_off.modified(&client, &pod).await);
+        // The containers keep reporting the missing configuration
+        let status = server.get("/api/v1/namespaces/default/pods/foo").unwrap()["status"].clone();
+        let waiting = &status["containerStatuses"][0]["state"]["waiting"];
+        assert_eq!("CreateContainerConfigError", waiting["reason"]);
+        assert_eq!("secret \"creds\" not found", waiting["message"]);
+    }
+}
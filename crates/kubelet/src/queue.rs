@@ -1,119 +1,1694 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
 
-use k8s_openapi::api::core::v1::Pod as KubePod;
-use kube::api::{Meta, WatchEvent};
-use log::{debug, error};
-use tokio::sync::{mpsc::Sender, watch};
+use chrono::Utc;
+use futures::FutureExt;
+use k8s_openapi::api::core::v1::{Pod as KubePod, PodCondition};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::api::{Api, DeleteParams, WatchEvent};
+use kube::error::ErrorResponse;
+use log::{debug, error, info, warn};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-use crate::handle::pod_key;
+use crate::admission::{self, Admission};
+use crate::audit::AuditLog;
+use crate::concurrency::Limits;
+use crate::container::from_ephemeral;
+use crate::error::PodSyncError;
+use crate::events::EventRecorder;
+use crate::failures::FailureReporter;
+use crate::handle::key_from_pod;
+use crate::lifecycle::Lifecycle;
+use crate::module_store::FetchedImages;
+use crate::object_manager::ObjectManager;
+use crate::pod::{Pod, ValidatedPod};
+use crate::pod_changes::PodChanges;
+use crate::provider::{NotImplementedError, PodEvent};
+use crate::pull_backoff::PullBackOff;
+use crate::rate_limit::RateLimits;
+use crate::redact::{self, redact};
+use crate::registry::PodRegistry;
+use crate::sandbox;
+use crate::shutdown::{self, ShutdownStop, StopRequest};
+use crate::start_queue::StartQueue;
+use crate::state::State;
+use crate::stats::ResourceAccounting;
+use crate::status::annotations::{self, AnnotationWriter};
+use crate::status::{ContainerStatus, Phase, StatusPatch};
 use crate::Provider;
 
+/// The pod condition set while a pod is being stopped because it was deleted or evicted
+const DISRUPTION_TARGET: &str = "DisruptionTarget";
+
+/// How many pods can be started by the provider at once
+const MAX_CONCURRENT_STARTS: usize = 10;
+
+/// How long a pod start runs at a time while other pods are waiting to start
+const START_TIME_SLICE: Duration = Duration::from_secs(30);
+
 /// A per-pod queue that takes incoming Kubernetes events and broadcasts them to the correct queue
 /// for that pod.
 ///
-/// It will also send a error out on the given sender that can be handled in another process (namely
-/// the main kubelet process). This queue will only handle the latest update. So if a modify comes
+/// It will also report errors to the given [`FailureReporter`] so they can be patched onto the pod
+/// status. This queue will only handle the latest update. So if a modify comes
 /// in while it is still handling a create and then another modify comes in after, only the second
-/// modify will be handled, which is ok given that each event contains the whole pod object
+/// modify will be handled, which is ok given that each event contains the whole pod object.
+///
+/// Pods are given the Kubelet's finalizer when they are added and are stopped by the queue once
+/// they are marked for deletion, so providers only see a single modify for a terminating pod
+/// followed by the delete once the pod is removed from the API.
+///
+/// Pods that fail the Kubelet's admission checks, such as when their namespace is not
+/// allowed or a host port they bind is already bound, are rejected without a worker
+/// being started for them. They are rejected once, and their later events are ignored
+/// until they are deleted.
+///
+/// A worker stops once its pod is deleted. A pod created again with the same name gets
+/// a new worker, and is checked again, once the worker of the deleted pod is done.
+///
+/// Only a limited number of pods are started at once. When more are waiting, such as when the
+/// Kubelet starts up, the pods with the highest priority are started first. Starts that take
+/// long, such as slow image pulls, are paused after a time slice to let the next pod have a
+/// turn, and a pod deleted while it waits for its turn is stopped straight away.
 pub struct PodQueue<P> {
     provider: Arc<P>,
+    client: kube::Client,
     handlers: HashMap<String, Worker>,
-    error_sender: Sender<(KubePod, anyhow::Error)>,
+    /// The workers of deleted pods, by pod key, that may still be handling the deletion
+    finishing: HashMap<String, JoinHandle<()>>,
+    /// The UIDs of the pods that were rejected, by pod key, so their later events are
+    /// ignored rather than rejecting them again
+    rejected: HashMap<String, String>,
+    failures: FailureReporter,
+    starts: StartQueue,
+    objects: ObjectManager,
+    accounting: ResourceAccounting,
+    registry: PodRegistry,
+    admission: Admission,
+    limits: RateLimits,
+    concurrency: Limits,
+    images: FetchedImages,
+    events: EventRecorder,
+    audit: AuditLog,
+    lifecycle: Lifecycle,
+    annotations: Option<AnnotationWriter>,
+    cluster_domain: Option<Arc<str>>,
 }
 
 struct Worker {
-    sender: watch::Sender<WatchEvent<KubePod>>,
-    _worker: JoinHandle<()>,
+    sender: watch::Sender<PodEvent>,
+    worker: JoinHandle<()>,
 }
 
 impl Worker {
+    /// Start a worker for the pod of the initial event, sharing the queue's state. The
+    /// worker waits for the previous worker of a pod with the same key, if it is still
+    /// handling that pod's deletion
     fn create<P>(
-        initial_event: WatchEvent<KubePod>,
-        provider: Arc<P>,
-        mut error_sender: Sender<(KubePod, anyhow::Error)>,
+        initial_event: PodEvent,
+        queue: &PodQueue<P>,
+        previous: Option<JoinHandle<()>>,
     ) -> Self
     where
         P: 'static + Provider + Sync + Send,
     {
+        let provider = queue.provider.clone();
+        let client = queue.client.clone();
+        let failures = queue.failures.clone();
+        let starts = queue.starts.clone();
+        let objects = queue.objects.clone();
+        let accounting = queue.accounting.clone();
+        let registry = queue.registry.clone();
+        let admission = queue.admission.clone();
+        let images = queue.images.clone();
         let (sender, mut receiver) = watch::channel(initial_event);
         let worker = tokio::spawn(async move {
-            while let Some(event) = receiver.recv().await {
-                // Watch errors are handled before an event ever gets here, so it should always have
-                // a pod
-                let pod = pod_from_event(&event).unwrap();
-                if let Err(e) = provider.handle_event(event).await {
-                    if let Err(e) = error_sender.send((pod, e)).await {
-                        error!("Unable to send error to status updater: {:?}", e)
+            // The state kept for the pod, such as in the registry, is kept by key, so the
+            // deleted pod is forgotten before this one is handled
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            // Whether the pod has already been stopped because it was marked for deletion
+            let mut terminated = false;
+            // Whether the pod has been stopped because the node shuts down. It is still
+            // stopped as usual once it is marked for deletion
+            let mut shut_down = false;
+            // The ephemeral containers that have already been passed to the provider
+            let mut ephemeral_containers = HashSet::new();
+            // Whether the usage of the pod is being accounted for
+            let mut registered = false;
+            // The definition whose secrets and config maps are being watched for the pod,
+            // until it stops or is deleted
+            let mut watched: Option<Pod> = None;
+            // Whether the provider has set the pod up, so it needs tearing down once the
+            // pod is deleted
+            let mut set_up = false;
+            // The definition last passed to the provider, which modifications are compared
+            // with
+            let mut passed: Option<Pod> = None;
+            // Set while the images of the pod could not be pulled or its configuration could
+            // not be resolved, see `pull_backoff`
+            let mut pull_backoff: Option<PullBackOff> = None;
+            // Set while the provider asked for the pod to be added again later, with when
+            // to add it
+            let mut requeue: Option<(Instant, Pod)> = None;
+            // An event that came in while the pod was waiting for its turn to start
+            let mut received: Option<Option<PodEvent>> = None;
+            loop {
+                let retry = match (&pull_backoff, &requeue) {
+                    (Some(backoff), _) => Some((backoff.retry_at(), backoff.pod().clone())),
+                    (None, Some((retry_at, pod))) => Some((*retry_at, pod.clone())),
+                    (None, None) => None,
+                };
+                let event = match (received.take(), retry) {
+                    (Some(event), _) => event,
+                    // Add the pod again once the backoff is over, unless it changes first
+                    (None, Some((retry_at, pod))) => tokio::select! {
+                        event = receiver.recv() => event,
+                        _ = tokio::time::delay_until(retry_at) => Some(PodEvent::Added(pod)),
+                    },
+                    (None, None) => receiver.recv().await,
+                };
+                let event = match (event, pull_backoff.as_mut()) {
+                    (None, _) => break,
+                    (Some(PodEvent::Modified(pod)), Some(backoff))
+                        if pod.deletion_timestamp().is_none() =>
+                    {
+                        registry.update_pod(&pod);
+                        if backoff.modified(&client, &pod).await {
+                            continue;
+                        }
+                        // The spec changed, for example to fix the image, so pull again
+                        // straight away
+                        PodEvent::Added(pod)
+                    }
+                    (Some(PodEvent::Modified(pod)), None)
+                        if requeue.is_some() && pod.deletion_timestamp().is_none() =>
+                    {
+                        registry.update_pod(&pod);
+                        match requeue.as_mut() {
+                            Some((_, waiting)) if waiting.spec_hash() == pod.spec_hash() => {
+                                *waiting = pod;
+                                continue;
+                            }
+                            _ => PodEvent::Added(pod),
+                        }
+                    }
+                    (Some(event), _) => event,
+                };
+                // Cloning the pod only clones a reference to the shared definition. The
+                // queue only sends the worker pods it validated, and pods it was sent
+                let pod = match ValidatedPod::new(event.pod().clone()) {
+                    Ok(pod) => pod,
+                    Err(e) => {
+                        warn!("Ignoring event for invalid pod: {}", e);
+                        continue;
+                    }
+                };
+                let deleted = matches!(event, PodEvent::Deleted(_));
+                let adding =
+                    matches!(event, PodEvent::Added(_)) && pod.deletion_timestamp().is_none();
+                let result = match event {
+                    // Status patches made while terminating come back as modifications, so we
+                    // ignore everything until the pod is gone
+                    PodEvent::Modified(_) if terminated => Ok(()),
+                    PodEvent::Added(_) | PodEvent::Modified(_) if pod.shutdown().is_some() => {
+                        if !shut_down {
+                            registry.set_state(&pod, State::Terminated);
+                            shutdown::stop_pod(provider.as_ref(), &client, &pod).await;
+                            shut_down = true;
+                            if set_up {
+                                pod.audit().pod_stopped(&pod);
+                            }
+                            admission.release(&pod);
+                        }
+                        if let Some(stop) = pod.shutdown() {
+                            stop.stopped();
+                        }
+                        Ok(())
+                    }
+                    // A pod stopped for a shutdown that was cancelled is not started again
+                    PodEvent::Added(_) | PodEvent::Modified(_)
+                        if shut_down && pod.deletion_timestamp().is_none() =>
+                    {
+                        Ok(())
+                    }
+                    PodEvent::Added(_) | PodEvent::Modified(_)
+                        if pod.deletion_timestamp().is_some() =>
+                    {
+                        registry.set_state(&pod, State::Terminated);
+                        let result = terminate(provider.as_ref(), &client, pod.clone()).await;
+                        // A pod that could not be stopped is stopped again on its next
+                        // update, such as the one reporting the failure
+                        terminated = result.is_ok();
+                        if terminated {
+                            if let Some(watched) = watched.take() {
+                                objects.unregister_pod(&watched);
+                            }
+                        }
+                        if set_up {
+                            pod.audit().pod_stopped(&pod);
+                        }
+                        admission.release(&pod);
+                        result
+                    }
+                    PodEvent::Added(_) => {
+                        registry.set_state(&pod, State::Registered);
+                        if !registered {
+                            accounting.register_pod(&pod);
+                            registered = true;
+                        }
+                        if watched.is_none() {
+                            objects.register_pod(&pod);
+                            watched = Some(Pod::clone(&pod));
+                        }
+                        if let Err(e) = pod.add_finalizer(client.clone()).await {
+                            error!(
+                                "Unable to add finalizer to pod {} in namespace {}: {}",
+                                pod.name(),
+                                pod.namespace(),
+                                e
+                            );
+                        }
+                        let permit = tokio::select! {
+                            permit = starts.start(&pod) => permit,
+                            _ = wait_for_stop(&mut receiver, &mut received) => {
+                                debug!(
+                                    "Pod {} in namespace {} was deleted while waiting to start",
+                                    pod.name(),
+                                    pod.namespace()
+                                );
+                                continue;
+                            }
+                        };
+                        let started = permit
+                            .run(async {
+                                registry.set_state(&pod, State::Starting);
+                                let setup = if set_up {
+                                    Ok(())
+                                } else {
+                                    sandbox::set_up(provider.as_ref(), &client, &pod).await
+                                };
+                                match setup {
+                                    Ok(()) => {
+                                        set_up = true;
+                                        passed = Some(Pod::clone(&pod));
+                                        handle_event(provider.as_ref(), event).await
+                                    }
+                                    Err(e) => Err(e),
+                                }
+                            })
+                            .await;
+                        match started {
+                            Ok(()) => {
+                                registry.set_state(&pod, State::Running);
+                                pod.audit().pod_started(&pod);
+                                start_ephemeral_containers(
+                                    provider.as_ref(),
+                                    &pod,
+                                    &mut ephemeral_containers,
+                                )
+                                .await;
+                                Ok(())
+                            }
+                            Err(e) => {
+                                registry.set_state(&pod, State::Error);
+                                Err(e)
+                            }
+                        }
+                    }
+                    PodEvent::Modified(_) => {
+                        registry.update_pod(&pod);
+                        if registered {
+                            accounting.register_pod(&pod);
+                        }
+                        // Pods that have finished no longer bind their ports or count
+                        // against their namespace's quota, though they are only deleted
+                        // later
+                        if admission::finished(&pod) {
+                            admission.release(&pod);
+                        }
+                        // Ephemeral containers can refer to other objects. Those still
+                        // referred to are registered before they are unregistered, so
+                        // their watches carry on
+                        if let Some(watched) = watched.as_mut() {
+                            objects.register_pod(&pod);
+                            objects.unregister_pod(watched);
+                            *watched = Pod::clone(&pod);
+                        }
+                        start_ephemeral_containers(
+                            provider.as_ref(),
+                            &pod,
+                            &mut ephemeral_containers,
+                        )
+                        .await;
+                        let modified = match &passed {
+                            Some(previous) => {
+                                modify_with_changes(provider.as_ref(), previous, Pod::clone(&pod))
+                                    .await
+                            }
+                            None => handle_event(provider.as_ref(), event).await,
+                        };
+                        // A failed modification is passed again with the next one
+                        if modified.is_ok() {
+                            passed = Some(Pod::clone(&pod));
+                        }
+                        modified
+                    }
+                    PodEvent::Deleted(_) => {
+                        passed = None;
+                        let result = handle_event(provider.as_ref(), event).await;
+                        pod.lifecycle().pod_removed(&pod);
+                        // Pods that were force deleted were not stopped beforehand
+                        if set_up && !terminated {
+                            pod.audit().pod_stopped(&pod);
+                        }
+                        if set_up {
+                            sandbox::tear_down(provider.as_ref(), &pod).await;
+                            set_up = false;
+                        }
+                        if registered {
+                            accounting.unregister_pod(&pod);
+                            registered = false;
+                        }
+                        if let Some(watched) = watched.take() {
+                            objects.unregister_pod(&watched);
+                        }
+                        registry.remove(&pod);
+                        admission.release(&pod);
+                        images.forget(&pod);
+                        result
+                    }
+                };
+                if adding {
+                    requeue = None;
+                }
+                match result {
+                    Ok(()) => {
+                        if adding {
+                            pull_backoff = None;
+                        }
+                        registry.set_error(&pod, None);
+                        failures.resolve(&pod)
+                    }
+                    Err(e) => {
+                        let error: PodSyncError = e.into();
+                        let message = redact(&pod, &error.to_string());
+                        registry.set_error(&pod, Some(message.clone()));
+                        match &error {
+                            // Pods are not failed when their images cannot be pulled, they
+                            // are added again after a backoff
+                            PodSyncError::ImagePull { image, .. } if adding => {
+                                registry.set_state(&pod, State::ImagePull);
+                                failures.resolve(&pod);
+                                pull_backoff = Some(
+                                    PullBackOff::failed(
+                                        pull_backoff.take(),
+                                        &client,
+                                        &pod,
+                                        image,
+                                        message,
+                                    )
+                                    .await,
+                                );
+                            }
+                            // Neither are pods whose configuration is missing, as it
+                            // may appear later
+                            PodSyncError::ContainerConfig(_) if adding => {
+                                registry.set_state(&pod, State::Starting);
+                                failures.resolve(&pod);
+                                pull_backoff = Some(
+                                    PullBackOff::config_failed(
+                                        pull_backoff.take(),
+                                        &client,
+                                        &pod,
+                                        message,
+                                    )
+                                    .await,
+                                );
+                            }
+                            // The provider is waiting for something, so the pod is added
+                            // again later without being reported as failed
+                            PodSyncError::Requeue(after) if adding => {
+                                registry.set_state(&pod, State::Starting);
+                                registry.set_error(&pod, None);
+                                failures.resolve(&pod);
+                                pull_backoff = None;
+                                requeue = Some((Instant::now() + *after, Pod::clone(&pod)));
+                            }
+                            PodSyncError::Requeue(_) => {
+                                registry.set_error(&pod, None);
+                                debug!(
+                                    "Ignoring requeue of pod {} that is not being added",
+                                    pod.name()
+                                );
+                            }
+                            _ => failures.report(pod.clone(), error),
+                        }
                     }
                 }
+                if deleted || terminated || shut_down {
+                    pull_backoff = None;
+                    requeue = None;
+                }
+                if deleted {
+                    redact::forget(&pod);
+                }
+            }
+            // The queue is draining, so nothing is served from the watches anymore
+            if let Some(watched) = watched.take() {
+                objects.unregister_pod(&watched);
             }
         });
-        Worker {
-            sender,
-            _worker: worker,
+        Worker { sender, worker }
+    }
+}
+
+/// Keep the events that come in for a pod waiting to start, returning once one of them
+/// means the pod should be stopped rather than started, or the queue is draining. The
+/// other events are handled once the pod has started, as usual
+async fn wait_for_stop(
+    receiver: &mut watch::Receiver<PodEvent>,
+    received: &mut Option<Option<PodEvent>>,
+) {
+    loop {
+        let event = receiver.recv().await;
+        let stop = match &event {
+            Some(PodEvent::Deleted(_)) | None => true,
+            Some(event) => event.pod().deletion_timestamp().is_some(),
+        };
+        *received = Some(event);
+        if stop {
+            return;
+        }
+    }
+}
+
+/// Pass an event to the provider, unless a failure is injected in its place
+async fn handle_event<P: Provider + Sync>(provider: &P, event: PodEvent) -> anyhow::Result<()> {
+    #[cfg(feature = "fault-injection")]
+    event.pod().rate_limits().faults().provider_call()?;
+    provider.handle_event(event).await
+}
+
+/// Pass a modified pod to the provider along with what changed since the previous
+/// definition it was given
+async fn modify_with_changes<P: Provider + Sync>(
+    provider: &P,
+    previous: &Pod,
+    pod: Pod,
+) -> anyhow::Result<()> {
+    let changes = PodChanges::between(previous, &pod);
+    // Writes of the annotations published for the pod come back as modifications, which
+    // the provider has no use for
+    if annotations::only_published(&changes) {
+        return Ok(());
+    }
+    #[cfg(feature = "fault-injection")]
+    pod.rate_limits().faults().provider_call()?;
+    provider.modify_with_changes(pod, changes).await
+}
+
+/// Pass any ephemeral containers of the pod that have not been seen before to the provider.
+///
+/// Failing to start an ephemeral container does not affect the rest of the pod, so errors
+/// are only logged.
+async fn start_ephemeral_containers<P: Provider + Sync>(
+    provider: &P,
+    pod: &Pod,
+    started: &mut HashSet<String>,
+) {
+    for ephemeral in pod.ephemeral_containers() {
+        if !started.insert(ephemeral.name.clone()) {
+            continue;
+        }
+        info!(
+            "Starting ephemeral container {} in pod {}",
+            ephemeral.name,
+            pod.name()
+        );
+        match provider
+            .add_ephemeral_container(pod.clone(), from_ephemeral(ephemeral))
+            .await
+        {
+            Ok(()) => debug!("Started ephemeral container {}", ephemeral.name),
+            Err(e) if e.is::<NotImplementedError>() => warn!(
+                "Provider does not support ephemeral containers, ignoring {} in pod {}",
+                ephemeral.name,
+                pod.name()
+            ),
+            Err(e) => error!(
+                "Unable to start ephemeral container {} in pod {}: {}",
+                ephemeral.name,
+                pod.name(),
+                redact(pod, &e.to_string())
+            ),
+        }
+    }
+}
+
+/// Gracefully stop a pod that has been marked for deletion.
+///
+/// This is the same for pods deleted directly and pods evicted through the Eviction API
+/// (e.g. by `kubectl drain`). A `DisruptionTarget` condition is reported first, then the
+/// provider is given the pod's grace period to stop the workload (using
+/// [`Provider::modify`]), after which the containers are reported as terminated
+/// and the Kubelet's finalizer is removed so the pod can be deleted.
+async fn terminate<P: Provider + Sync>(
+    provider: &P,
+    client: &kube::Client,
+    pod: ValidatedPod,
+) -> anyhow::Result<()> {
+    let grace_period = pod
+        .deletion_grace_period_seconds()
+        .unwrap_or_else(|| pod.termination_grace_period_seconds())
+        .max(0) as u64;
+    info!(
+        "Stopping pod {} in namespace {} with a grace period of {}s",
+        pod.name(),
+        pod.namespace(),
+        grace_period
+    );
+    // Let anything watching the pod, such as `kubectl drain`, know that the pod is going away
+    // before we start stopping it
+    let mut conditions = termination_conditions(&pod, grace_period);
+    let patch = StatusPatch::new().conditions(conditions.clone());
+    if let Err(e) = patch.apply_for(client.clone(), &pod).await {
+        warn!("Unable to report disruption for pod {}: {}", pod.name(), e);
+    }
+
+    let stopped = tokio::time::timeout(
+        Duration::from_secs(grace_period),
+        provider.modify(Pod::clone(&pod)),
+    )
+    .await;
+    let (message, failed) = match stopped {
+        Ok(Ok(())) => ("Pod stopped".to_owned(), false),
+        Ok(Err(e)) => {
+            let e = redact(&pod, &e.to_string());
+            error!("Error while stopping pod {}: {}", pod.name(), e);
+            (format!("Error while stopping pod: {}", e), true)
         }
+        // Without a grace period the pod isn't waited for, the provider stops it when it
+        // is deleted
+        Err(_) if grace_period == 0 => ("Pod was stopped immediately".to_owned(), false),
+        Err(_) => {
+            warn!(
+                "Pod {} did not stop within its grace period of {}s, removing it anyway",
+                pod.name(),
+                grace_period
+            );
+            ("Pod did not stop within its grace period".to_owned(), true)
+        }
+    };
+
+    let terminated = ContainerStatus::Terminated {
+        timestamp: Utc::now(),
+        message: message.clone(),
+        exit_code: failed as i32,
+    };
+    conditions.retain(|c| c.type_ != "Ready");
+    conditions.push(PodCondition {
+        type_: "Ready".to_owned(),
+        status: "False".to_owned(),
+        reason: Some("PodCompleted".to_owned()),
+        last_transition_time: Some(Time(Utc::now())),
+        ..Default::default()
+    });
+    let patch = StatusPatch::new()
+        .message(message)
+        .phase(if failed {
+            Phase::Failed
+        } else {
+            Phase::Succeeded
+        })
+        .container_statuses(
+            pod.containers()
+                .iter()
+                .map(|c| terminated.to_kubernetes(c.name.clone())),
+        )
+        .conditions(conditions);
+    if let Err(e) = patch.apply_for(client.clone(), &pod).await {
+        // The pod may already be gone if it was force deleted
+        warn!(
+            "Unable to report terminated status for pod {}: {}",
+            pod.name(),
+            e
+        );
+    }
+
+    pod.remove_finalizer(client.clone()).await?;
+    let pod_client: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let dp = DeleteParams {
+        grace_period_seconds: Some(0),
+        ..Default::default()
+    };
+    match pod
+        .rate_limits()
+        .limited(pod_client.delete(pod.name(), &dp))
+        .await
+    {
+        Ok(_) | Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => Ok(()),
+        Err(e) => Err(e.into()),
     }
 }
 
+/// Returns the pod's conditions with a `DisruptionTarget` condition added, unless one was
+/// already set (for example by the API server when the pod was evicted)
+fn termination_conditions(pod: &Pod, grace_period: u64) -> Vec<PodCondition> {
+    let mut conditions = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.conditions.clone())
+        .unwrap_or_default();
+    if !conditions.iter().any(|c| c.type_ == DISRUPTION_TARGET) {
+        conditions.push(PodCondition {
+            type_: DISRUPTION_TARGET.to_owned(),
+            status: "True".to_owned(),
+            reason: Some("TerminationByKubelet".to_owned()),
+            message: Some(format!(
+                "Pod is being terminated with a grace period of {}s",
+                grace_period
+            )),
+            last_transition_time: Some(Time(Utc::now())),
+            ..Default::default()
+        });
+    }
+    conditions
+}
+
 impl<P: 'static + Provider + Sync + Send> PodQueue<P> {
-    pub fn new(provider: Arc<P>, error_sender: Sender<(KubePod, anyhow::Error)>) -> Self {
+    pub fn new(
+        provider: Arc<P>,
+        client: kube::Client,
+        failures: FailureReporter,
+        objects: ObjectManager,
+        accounting: ResourceAccounting,
+        registry: PodRegistry,
+        admission: Admission,
+    ) -> Self {
         PodQueue {
             provider,
+            client,
             handlers: HashMap::new(),
-            error_sender,
+            finishing: HashMap::new(),
+            rejected: HashMap::new(),
+            failures,
+            starts: StartQueue::new(MAX_CONCURRENT_STARTS, START_TIME_SLICE),
+            objects,
+            accounting,
+            registry,
+            admission,
+            limits: RateLimits::default(),
+            concurrency: Limits::default(),
+            images: FetchedImages::default(),
+            events: EventRecorder::default(),
+            audit: AuditLog::default(),
+            lifecycle: Lifecycle::default(),
+            annotations: None,
+            cluster_domain: None,
         }
     }
 
+    /// Make the requests for the queued pods under the given rate limits
+    pub(crate) fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Run the heavy operations for the queued pods under the given limits
+    pub(crate) fn with_concurrency(mut self, limits: Limits) -> Self {
+        self.concurrency = limits;
+        self
+    }
+
+    /// Record the events about the queued pods with the given recorder
+    pub(crate) fn with_events(mut self, events: EventRecorder) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Record what is done with the queued pods in the given audit log
+    pub(crate) fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Send what happens to the queued pods to the subscribers of the given lifecycle
+    pub(crate) fn with_lifecycle(mut self, lifecycle: Lifecycle) -> Self {
+        self.lifecycle = lifecycle;
+        self
+    }
+
+    /// Write the annotations published for the queued pods with the given writer
+    pub(crate) fn with_annotation_writer(mut self, writer: AnnotationWriter) -> Self {
+        self.annotations = Some(writer);
+        self
+    }
+
+    /// End the domain names of the queued pods with the given cluster domain
+    pub(crate) fn with_cluster_domain(mut self, domain: &str) -> Self {
+        self.cluster_domain = Some(domain.into());
+        self
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn set_admission(&mut self, admission: Admission) {
+        self.admission = admission;
+    }
+
+    /// Stop handing events to the pod workers and wait up to `timeout` for them to
+    /// finish the events they are handling, so no pod is left with a status update cut
+    /// off halfway. The pods themselves are left running
+    pub(crate) async fn drain(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut unfinished = 0;
+        // A worker stops once its sender is dropped and its current event is handled
+        let workers = self
+            .handlers
+            .into_iter()
+            .map(|(_, Worker { sender, worker })| {
+                drop(sender);
+                worker
+            })
+            .chain(self.finishing.into_values());
+        for worker in workers {
+            if tokio::time::timeout_at(deadline, worker).await.is_err() {
+                unfinished += 1;
+            }
+        }
+        if unfinished > 0 {
+            warn!(
+                "{} pod workers did not finish within {:?}, stopping without them",
+                unfinished, timeout
+            );
+        }
+    }
+
+    /// Have the workers of the pods that are running stop them because the node shuts
+    /// down, either the critical pods or the others, replying with a receiver for each
+    /// pod that completes once the pod has stopped.
+    ///
+    /// Each pod gets its termination grace period, up to the budget of the request. The
+    /// stops are handed to the workers in order of priority, the lowest first, and the
+    /// pods then stop at the same time
+    pub(crate) fn stop_for_shutdown(&mut self, request: StopRequest) {
+        let mut pods: Vec<Pod> = self
+            .registry
+            .pods()
+            .into_iter()
+            .map(|entry| entry.pod)
+            .filter(|pod| {
+                pod.is_critical() == request.critical
+                    && pod.deletion_timestamp().is_none()
+                    && !shutdown::finished(pod)
+            })
+            .collect();
+        pods.sort_by_key(Pod::priority);
+        let mut stopping = Vec::with_capacity(pods.len());
+        for pod in pods {
+            let worker = match self.handlers.get(&key_from_pod(&pod)) {
+                Some(worker) => worker,
+                None => continue,
+            };
+            let (stop, stopped) = ShutdownStop::new();
+            let event = PodEvent::Modified(shutdown::stopping(&pod, request.budget, stop));
+            if worker.sender.broadcast(event).is_ok() {
+                stopping.push(stopped);
+            }
+        }
+        // The shutdown may have given up waiting already
+        let _ = request.stopping.send(stopping);
+    }
+
     pub async fn enqueue(&mut self, event: WatchEvent<KubePod>) -> anyhow::Result<()> {
-        match &event {
-            WatchEvent::Added(pod)
-            | WatchEvent::Bookmark(pod)
-            | WatchEvent::Deleted(pod)
-            | WatchEvent::Modified(pod) => {
-                let pod_name = pod.name();
-                let pod_namespace = pod.namespace().unwrap_or_default();
-                let key = pod_key(&pod_namespace, &pod_name);
-                // We are explicitly not using the entry api here to insert to avoid the need for a
-                // mutex
-                let handler = match self.handlers.get(&key) {
-                    Some(h) => h,
-                    None => {
-                        self.handlers.insert(
-                            key.clone(),
-                            Worker::create(
-                                event.clone(),
-                                self.provider.clone(),
-                                self.error_sender.clone(),
-                            ),
-                        );
-                        self.handlers.get(&key).unwrap()
+        let event = match event {
+            WatchEvent::Error(e) => return Err(e.into()),
+            // Bookmarks only carry a resource version, so there is nothing to do for them
+            WatchEvent::Bookmark(_) => return Ok(()),
+            // The pod is converted once here and shared with the worker from then on. The
+            // requests for it are made under the Kubelet's rate limits and its heavy
+            // operations under its concurrency limits, the objects it uses are served
+            // from the queue's cache, the modules fetched for it are remembered across
+            // its events, its events are correlated with the Kubelet's others, what is
+            // done with it is audited and sent to the Kubelet's subscribers, the
+            // annotations published for it are written by the Kubelet, and its domain
+            // names end with the Kubelet's cluster domain
+            event => PodEvent::from_watch_event(event)
+                .expect("events other than errors and bookmarks always have a pod")
+                .map_pod(|pod| {
+                    let pod = pod
+                        .with_rate_limits(self.limits.clone())
+                        .with_concurrency(self.concurrency.clone())
+                        .with_objects(self.objects.clone())
+                        .with_images(self.images.clone())
+                        .with_events(self.events.clone())
+                        .with_audit(self.audit.clone())
+                        .with_lifecycle(self.lifecycle.clone());
+                    let pod = match &self.annotations {
+                        Some(writer) => pod.with_annotation_writer(writer.clone()),
+                        None => pod,
+                    };
+                    match &self.cluster_domain {
+                        Some(domain) => pod.with_cluster_domain(domain.clone()),
+                        None => pod,
                     }
-                };
-                match handler.sender.broadcast(event) {
-                    Ok(_) => debug!(
-                        "successfully sent event to handler for pod {} in namespace {}",
-                        pod_name, pod_namespace
-                    ),
-                    Err(e) => error!(
-                        "error while sending event. Will retry on next event: {:?}",
-                        e
-                    ),
+                }),
+        };
+        // Pods without a name, namespace, UID or spec can't be tracked or patched, so
+        // their events are dropped
+        let pod = match ValidatedPod::new(event.pod().clone()) {
+            Ok(pod) => pod,
+            Err(e) => {
+                warn!("Ignoring event for invalid pod: {}", e);
+                return Ok(());
+            }
+        };
+        let key = key_from_pod(&pod);
+        // We are explicitly not using the entry api here to insert to avoid the need for a
+        // mutex
+        let deleted = matches!(event, PodEvent::Deleted(_));
+        let handler = match self.handlers.get(&key) {
+            Some(h) => h,
+            None => {
+                if self.rejected.get(&key).map(String::as_str) == Some(pod.uid()) {
+                    if deleted {
+                        self.rejected.remove(&key);
+                    }
+                    return Ok(());
+                }
+                if let Err(rejection) = self.admission.check(&pod) {
+                    if !deleted {
+                        self.rejected.insert(key, pod.uid().to_owned());
+                        let client = self.client.clone();
+                        let pod = pod.clone();
+                        tokio::spawn(async move {
+                            admission::reject(&client, &pod, rejection).await;
+                        });
+                    }
+                    return Ok(());
                 }
-                Ok(())
+                pod.lifecycle().pod_admitted(&pod);
+                let previous = self.finishing.remove(&key);
+                let worker = Worker::create(event.clone(), self, previous);
+                self.handlers.insert(key.clone(), worker);
+                self.handlers.get(&key).unwrap()
             }
-            WatchEvent::Error(e) => Err(e.clone().into()),
+        };
+        let pod_name = pod.name().to_owned();
+        let pod_namespace = pod.namespace().to_owned();
+        match handler.sender.broadcast(event) {
+            Ok(_) => debug!(
+                "successfully sent event to handler for pod {} in namespace {}",
+                pod_name, pod_namespace
+            ),
+            Err(e) => error!(
+                "error while sending event. Will retry on next event: {:?}",
+                e
+            ),
         }
+        // The worker stops once it has handled the deletion, so a pod created again with
+        // the same name starts afresh. What the deleted pod reserved is released straight
+        // away, as the pod created again is checked before the worker is done
+        if deleted {
+            self.admission.release(&pod);
+            if let Some(Worker { sender, worker }) = self.handlers.remove(&key) {
+                drop(sender);
+                self.finishing
+                    .retain(|_, worker| worker.now_or_never().is_none());
+                self.finishing.insert(key, worker);
+            }
+        }
+        Ok(())
     }
 }
 
-fn pod_from_event(event: &WatchEvent<KubePod>) -> Option<KubePod> {
-    match event {
-        WatchEvent::Added(pod)
-        | WatchEvent::Bookmark(pod)
-        | WatchEvent::Deleted(pod)
-        | WatchEvent::Modified(pod) => Some(pod.clone()),
-        WatchEvent::Error(_) => None,
+#[cfg(test)]
+mod test {
+    use super::MAX_CONCURRENT_STARTS;
+    use crate::admission::Admission;
+    use crate::config::NamespaceFilter;
+    use crate::pod::POD_FINALIZER;
+    use crate::state::State;
+    use crate::testing::{fake_pod, FakeProvider, MockApiServer, Operation, QueueHarness};
+    use chrono::Utc;
+    use k8s_openapi::api::core::v1::{
+        ContainerPort, EphemeralContainer, Pod as KubePod, PodStatus, SecretVolumeSource, Volume,
+    };
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const POD_PATH: &str = "/api/v1/namespaces/default/pods/foo";
+
+    fn terminating(mut pod: KubePod, grace_period: i64) -> KubePod {
+        let meta = pod.metadata.as_mut().unwrap();
+        meta.deletion_timestamp = Some(Time(Utc::now()));
+        meta.deletion_grace_period_seconds = Some(grace_period);
+        meta.finalizers = Some(vec![POD_FINALIZER.to_owned()]);
+        pod
+    }
+
+    async fn wait_for_removal(server: &MockApiServer) -> bool {
+        let wait = async {
+            while server.get(POD_PATH).is_some() {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, wait).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_events_dispatched_to_provider() {
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::new(provider.clone());
+
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+        harness.delete(fake_pod("foo", "default")).await.unwrap();
+        assert!(
+            provider
+                .wait_for_calls(Operation::TeardownPod, 1, TIMEOUT)
+                .await
+        );
+
+        let calls = provider.calls();
+        assert!(calls.iter().all(|c| c.pod_name == "foo"));
+        let operations: Vec<Operation> = calls.iter().map(|c| c.operation).collect();
+        assert_eq!(
+            vec![
+                Operation::SetupPod,
+                Operation::Add,
+                Operation::Delete,
+                Operation::TeardownPod
+            ],
+            operations
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registry_tracks_pod_states() {
+        let provider = Arc::new(FakeProvider::new());
+        provider.fail(Operation::Add, "no runtime");
+        let mut harness = QueueHarness::new(provider.clone());
+        let state = |harness: &QueueHarness<FakeProvider>| {
+            harness
+                .pods()
+                .get("default", "foo")
+                .map(|p| (p.state, p.error))
+        };
+
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        harness.next_error(TIMEOUT).await.expect("add should fail");
+        assert_eq!(
+            Some((State::Error, Some("no runtime".to_owned()))),
+            state(&harness)
+        );
+
+        provider.succeed(Operation::Add);
+        harness.add(fake_pod("bar", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 2, TIMEOUT).await);
+        let running = async {
+            while harness.pods().get("default", "bar").map(|p| p.state) != Some(State::Running) {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(tokio::time::timeout(TIMEOUT, running).await.is_ok());
+        let names: Vec<String> = harness
+            .pods()
+            .pods()
+            .iter()
+            .map(|p| p.pod.name().to_owned())
+            .collect();
+        assert_eq!(vec!["bar".to_owned(), "foo".to_owned()], names);
+
+        harness.delete(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Delete, 1, TIMEOUT).await);
+        let removed = async {
+            while state(&harness).is_some() {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(tokio::time::timeout(TIMEOUT, removed).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pod_setup_failure_is_not_a_container_failure() {
+        let provider = Arc::new(FakeProvider::new());
+        provider.fail(Operation::SetupPod, "no network");
+        let mut harness = QueueHarness::new(provider.clone());
+
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        let (pod, error) = harness
+            .next_error(TIMEOUT)
+            .await
+            .expect("setup should fail");
+        assert_eq!("foo", pod.name());
+        assert_eq!(crate::error::REASON_POD_SETUP, error.reason());
+        assert_eq!("failed to set up pod: no network", error.to_string());
+        assert!(provider.calls_for(Operation::Add).is_empty());
+
+        // Nothing was set up, so there is nothing to tear down
+        harness.delete(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Delete, 1, TIMEOUT).await);
+        assert!(provider.calls_for(Operation::TeardownPod).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_pod_does_not_block_others() {
+        let provider = Arc::new(FakeProvider::new());
+        provider.delay(Operation::Add, Duration::from_secs(60));
+        let mut harness = QueueHarness::new(provider.clone());
+
+        harness.add(fake_pod("slow", "default")).await.unwrap();
+        harness.modify(fake_pod("fast", "other")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 1, TIMEOUT).await);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_workers() {
+        let provider = Arc::new(FakeProvider::new());
+        provider.delay(Operation::Add, Duration::from_millis(200));
+        let mut harness = QueueHarness::new(provider.clone());
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+
+        let pods = harness.pods().clone();
+        tokio::time::timeout(TIMEOUT, harness.drain(TIMEOUT))
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(State::Running),
+            pods.get("default", "foo").map(|p| p.state)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pod_deleted_while_waiting_to_start_is_stopped() {
+        let provider = Arc::new(FakeProvider::new());
+        provider.delay(Operation::Add, Duration::from_secs(20));
+        let mut harness = QueueHarness::new(provider.clone());
+        for i in 0..MAX_CONCURRENT_STARTS {
+            harness
+                .add(fake_pod(&format!("slow-{}", i), "default"))
+                .await
+                .unwrap();
+        }
+        assert!(
+            provider
+                .wait_for_calls(Operation::Add, MAX_CONCURRENT_STARTS, TIMEOUT)
+                .await
+        );
+
+        harness.add(fake_pod("waiting", "default")).await.unwrap();
+        harness
+            .modify(terminating(fake_pod("waiting", "default"), 0))
+            .await
+            .unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 1, TIMEOUT).await);
+        let calls: Vec<Operation> = provider
+            .calls()
+            .iter()
+            .filter(|c| c.pod_name == "waiting")
+            .map(|c| c.operation)
+            .collect();
+        assert_eq!(vec![Operation::Modify], calls);
+    }
+
+    #[tokio::test]
+    async fn test_provider_errors_are_reported() {
+        let provider = Arc::new(FakeProvider::new());
+        provider.fail(Operation::Add, "boom");
+        let mut harness = QueueHarness::new(provider.clone());
+
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        let (pod, err) = harness.next_error(TIMEOUT).await.expect("an error");
+        assert_eq!("foo", pod.name());
+        assert_eq!("boom", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_image_pull_backoff() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", &kube_pod);
+        let provider = Arc::new(FakeProvider::new());
+        provider.fail_image_pull(Operation::Add, "fake.registry.io/foo:v1", "not found");
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+
+        harness.add(kube_pod.clone()).await.unwrap();
+        let reported = async {
+            loop {
+                let status = server.get(POD_PATH).unwrap()["status"].clone();
+                if !status["containerStatuses"].is_null() {
+                    return status;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        let status = tokio::time::timeout(TIMEOUT, reported).await.unwrap();
+        assert_eq!("Pending", status["phase"]);
+        let waiting = &status["containerStatuses"][0]["state"]["waiting"];
+        assert_eq!("ErrImagePull", waiting["reason"]);
+        // The pod is not failed
+        assert!(harness
+            .next_error(Duration::from_millis(100))
+            .await
+            .is_none());
+        assert_eq!(
+            Some(State::ImagePull),
+            harness.pods().get("default", "foo").map(|p| p.state)
+        );
+
+        // Fixing the image pulls again without waiting for the backoff
+        provider.succeed(Operation::Add);
+        kube_pod.spec.as_mut().unwrap().containers[0].image = Some("foo:v2".to_owned());
+        harness.modify(kube_pod).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 2, TIMEOUT).await);
+    }
+
+    #[tokio::test]
+    async fn test_requeue() {
+        let provider = Arc::new(FakeProvider::new());
+        // Long enough that the pod is not being added again while its state is checked
+        provider.requeue(Operation::Add, Duration::from_millis(500));
+        let mut harness = QueueHarness::new(provider.clone());
+
+        // The pod is added again once the provider is ready, without being failed
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 2, TIMEOUT).await);
+        assert!(harness
+            .next_error(Duration::from_millis(100))
+            .await
+            .is_none());
+        assert_eq!(
+            Some(State::Starting),
+            harness.pods().get("default", "foo").map(|p| p.state)
+        );
+
+        provider.succeed(Operation::Add);
+        let running = async {
+            while harness.pods().get("default", "foo").map(|p| p.state) != Some(State::Running) {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, running).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_pods_are_ignored() {
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::new(provider.clone());
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.metadata.as_mut().unwrap().uid = None;
+        harness.add(kube_pod).await.unwrap();
+        harness.add(KubePod::default()).await.unwrap();
+        harness.add(fake_pod("bar", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+        assert!(harness.pods().get("default", "foo").is_none());
+        assert_eq!(1, provider.calls_for(Operation::Add).len());
+    }
+
+    #[tokio::test]
+    async fn test_namespace_rejection() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", &kube_pod);
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client())
+            .with_namespaces(NamespaceFilter {
+                allowed: Vec::new(),
+                blocked: vec!["default".to_owned()],
+            });
+
+        // The pod is failed without reaching the provider or being given the finalizer
+        harness.add(kube_pod.clone()).await.unwrap();
+        let failed = async {
+            while server.get(POD_PATH).unwrap()["status"]["phase"] != "Failed" {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, failed).await.unwrap();
+        let pod = server.get(POD_PATH).unwrap();
+        assert_eq!("NamespaceNotAllowed", pod["status"]["reason"]);
+        assert!(pod["metadata"]["finalizers"].is_null());
+        assert!(provider.calls().is_empty());
+        assert!(harness.pods().get("default", "foo").is_none());
+
+        // Later events of the rejected pod, such as those sent before its rejection was
+        // reported, don't reject it again
+        let requests = || server.requests().len();
+        let rejected = requests();
+        harness.modify(kube_pod.clone()).await.unwrap();
+        harness.modify(kube_pod.clone()).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(rejected, requests());
+
+        // A pod of the same name created after it was deleted is checked again
+        harness.delete(kube_pod.clone()).await.unwrap();
+        let mut recreated = kube_pod;
+        recreated.metadata.as_mut().unwrap().uid = Some("recreated".to_owned());
+        recreated.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", &recreated);
+        harness.add(recreated).await.unwrap();
+        tokio::time::timeout(TIMEOUT, async {
+            while requests() == rejected {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_finished_pods_release_host_ports() {
+        let with_host_port = |name: &str| {
+            let mut pod = fake_pod(name, "default");
+            pod.spec.as_mut().unwrap().containers[0].ports = Some(vec![ContainerPort {
+                container_port: 8080,
+                host_port: Some(80),
+                ..Default::default()
+            }]);
+            pod
+        };
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::new(provider.clone());
+        let mut first = with_host_port("first");
+        harness.add(first.clone()).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+
+        // The port is free for other pods once the first has finished, before it is deleted
+        first.status = Some(PodStatus {
+            phase: Some("Succeeded".to_owned()),
+            ..Default::default()
+        });
+        harness.modify(first).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 1, TIMEOUT).await);
+        harness.add(with_host_port("second")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 2, TIMEOUT).await);
+
+        // A pod created again with the name of the first is checked again, and the
+        // second pod binds the port now
+        harness.delete(with_host_port("first")).await.unwrap();
+        let mut recreated = with_host_port("first");
+        recreated.metadata.as_mut().unwrap().uid = Some("recreated".to_owned());
+        harness.add(recreated).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(2, provider.calls_for(Operation::Add).len());
+    }
+
+    #[tokio::test]
+    async fn test_finished_pods_release_quota() {
+        let mut quotas = std::collections::HashMap::new();
+        let caps = vec![("pods".to_owned(), "1".to_owned())]
+            .into_iter()
+            .collect();
+        quotas.insert("default".to_owned(), caps);
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::new(provider.clone())
+            .with_admission(Admission::default().with_namespace_quotas(&quotas));
+        let mut first = fake_pod("first", "default");
+        harness.add(first.clone()).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+
+        // The namespace can run another pod once the first has finished, before it is
+        // deleted
+        first.status = Some(PodStatus {
+            phase: Some("Failed".to_owned()),
+            ..Default::default()
+        });
+        harness.modify(first).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 1, TIMEOUT).await);
+        harness.add(fake_pod("second", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 2, TIMEOUT).await);
+
+        // A pod created again with the name of the first is checked against the quota
+        // again, which the second pod uses up now
+        let recreate = |name: &str| {
+            let mut pod = fake_pod(name, "default");
+            pod.metadata.as_mut().unwrap().uid = Some(format!("recreated-{}", name));
+            pod
+        };
+        harness.delete(fake_pod("first", "default")).await.unwrap();
+        harness.add(recreate("first")).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(2, provider.calls_for(Operation::Add).len());
+
+        // Once the second pod is deleted, one created again in its place fits the quota
+        harness.delete(fake_pod("second", "default")).await.unwrap();
+        harness.add(recreate("second")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 3, TIMEOUT).await);
+    }
+
+    #[tokio::test]
+    async fn test_recreated_pods_are_checked_against_quota() {
+        let mut quotas = std::collections::HashMap::new();
+        let caps = vec![("pods".to_owned(), "1".to_owned())]
+            .into_iter()
+            .collect();
+        quotas.insert("default".to_owned(), caps);
+        let provider = Arc::new(FakeProvider::new());
+        provider.delay(Operation::TeardownPod, Duration::from_millis(200));
+        let mut harness = QueueHarness::new(provider.clone())
+            .with_admission(Admission::default().with_namespace_quotas(&quotas));
+        let recreate = |name: &str| {
+            let mut pod = fake_pod(name, "default");
+            pod.metadata.as_mut().unwrap().uid = Some(format!("recreated-{}", name));
+            pod
+        };
+        harness.add(fake_pod("first", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+        harness.add(fake_pod("second", "default")).await.unwrap();
+
+        // The first pod is created again while the deleted one is torn down, and the
+        // deleted one being released doesn't free the quota the recreated one uses
+        harness.delete(fake_pod("first", "default")).await.unwrap();
+        harness.add(recreate("first")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 2, TIMEOUT).await);
+        harness.delete(fake_pod("second", "default")).await.unwrap();
+        harness.add(recreate("second")).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(2, provider.calls_for(Operation::Add).len());
+        assert!(harness.pods().get("default", "second").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_graceful_deletion() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("foo", "default"),
+        );
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+        let pod = server.get(POD_PATH).expect("pod should exist");
+        assert_eq!(POD_FINALIZER, pod["metadata"]["finalizers"][0]);
+
+        let pod = terminating(fake_pod("foo", "default"), 30);
+        harness.modify(pod.clone()).await.unwrap();
+        assert!(wait_for_removal(&server).await);
+        assert_eq!(1, provider.calls_for(Operation::Modify).len());
+        let status_patch = server
+            .requests_to(hyper::Method::PATCH, &format!("{}/status", POD_PATH))
+            .pop()
+            .and_then(|r| r.body)
+            .expect("terminated status should be reported");
+        assert_eq!("Succeeded", status_patch["status"]["phase"]);
+        assert!(status_patch["status"]["containerStatuses"][0]["state"]["terminated"].is_object());
+
+        // Later modifications while terminating are ignored
+        harness.modify(pod).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(1, provider.calls_for(Operation::Modify).len());
+    }
+
+    #[tokio::test]
+    async fn test_pod_recreated_with_same_name() {
+        let server = MockApiServer::start().await.unwrap();
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+        let mut recreated = fake_pod("foo", "default");
+        recreated.metadata.as_mut().unwrap().uid = Some("recreated-uid".to_owned());
+
+        for (i, pod) in [fake_pod("foo", "default"), recreated].iter().enumerate() {
+            server.insert("/api/v1/namespaces/default/pods", pod.clone());
+            harness.add(pod.clone()).await.unwrap();
+            assert!(
+                provider
+                    .wait_for_calls(Operation::Add, i + 1, TIMEOUT)
+                    .await
+            );
+            harness.modify(terminating(pod.clone(), 30)).await.unwrap();
+            assert!(wait_for_removal(&server).await);
+            harness.delete(pod.clone()).await.unwrap();
+            assert!(
+                provider
+                    .wait_for_calls(Operation::TeardownPod, i + 1, TIMEOUT)
+                    .await
+            );
+        }
+        let operations: Vec<Operation> = provider.calls().iter().map(|c| c.operation).collect();
+        let lifetime = vec![
+            Operation::SetupPod,
+            Operation::Add,
+            Operation::Modify,
+            Operation::Delete,
+            Operation::TeardownPod,
+        ];
+        assert_eq!([lifetime.clone(), lifetime].concat(), operations);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_pods_stop_watching_objects() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut pod = fake_pod("foo", "default");
+        pod.spec.as_mut().unwrap().volumes = Some(vec![Volume {
+            name: "creds".to_owned(),
+            secret: Some(SecretVolumeSource {
+                secret_name: Some("creds".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }]);
+        server.insert("/api/v1/namespaces/default/pods", pod.clone());
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+
+        harness.add(pod.clone()).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+        let objects = harness
+            .pods()
+            .get("default", "foo")
+            .and_then(|entry| entry.pod.objects().cloned())
+            .expect("queued pods should carry the object manager");
+        assert!(objects.watches_secret("default", "creds"));
+
+        // The watch stops once the pod is stopped, before it is deleted
+        harness.modify(terminating(pod, 30)).await.unwrap();
+        assert!(wait_for_removal(&server).await);
+        let unwatched = async {
+            while objects.watches_secret("default", "creds") {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        assert!(tokio::time::timeout(TIMEOUT, unwatched).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_force_deletion_does_not_wait_for_provider() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            terminating(fake_pod("foo", "default"), 0),
+        );
+        let provider = Arc::new(FakeProvider::new());
+        provider.delay(Operation::Modify, Duration::from_secs(60));
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+
+        harness
+            .modify(terminating(fake_pod("foo", "default"), 0))
+            .await
+            .unwrap();
+        assert!(wait_for_removal(&server).await);
+        // Stopping the pod straight away is what was asked for, so it isn't a failure
+        let status_patch = server
+            .requests_to(hyper::Method::PATCH, &format!("{}/status", POD_PATH))
+            .pop()
+            .and_then(|r| r.body)
+            .unwrap();
+        assert_eq!("Succeeded", status_patch["status"]["phase"]);
+    }
+
+    #[tokio::test]
+    async fn test_termination_keeps_status() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut pod = serde_json::to_value(terminating(fake_pod("foo", "default"), 30)).unwrap();
+        pod["status"] = serde_json::json!({
+            "phase": "Running",
+            "hostIP": "10.0.0.1",
+            "conditions": [{ "type": "PodReadyToStartContainers", "status": "True" }],
+        });
+        server.insert("/api/v1/namespaces/default/pods", &pod);
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+
+        harness
+            .modify(serde_json::from_value(pod).unwrap())
+            .await
+            .unwrap();
+        assert!(wait_for_removal(&server).await);
+        let status_patches: Vec<serde_json::Value> = server
+            .requests_to(hyper::Method::PATCH, &format!("{}/status", POD_PATH))
+            .into_iter()
+            .filter_map(|r| r.body)
+            .collect();
+        let conditions = |patch: &serde_json::Value| -> Vec<String> {
+            patch["status"]["conditions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|c| c["type"].as_str().unwrap().to_owned())
+                .collect()
+        };
+        // The disruption is reported without changing the rest of the status
+        let disruption = &status_patches[0];
+        assert_eq!("Running", disruption["status"]["phase"]);
+        assert_eq!("10.0.0.1", disruption["status"]["hostIP"]);
+        assert_eq!(
+            vec!["PodReadyToStartContainers", "DisruptionTarget"],
+            conditions(disruption)
+        );
+        let terminated = status_patches.last().unwrap();
+        assert_eq!("Succeeded", terminated["status"]["phase"]);
+        assert_eq!("10.0.0.1", terminated["status"]["hostIP"]);
+        assert_eq!(
+            vec!["PodReadyToStartContainers", "DisruptionTarget", "Ready"],
+            conditions(terminated)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("foo", "default"),
+        );
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+
+        // Evict the pod like `kubectl drain` does and pass on the resulting update
+        let eviction = serde_json::json!({
+            "apiVersion": "policy/v1beta1",
+            "kind": "Eviction",
+            "metadata": { "name": "foo", "namespace": "default" },
+            "deleteOptions": { "gracePeriodSeconds": 10 },
+        });
+        reqwest::Client::new()
+            .post(
+                server
+                    .url()
+                    .join(&format!("{}/eviction", POD_PATH))
+                    .unwrap(),
+            )
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&eviction).unwrap())
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .expect("eviction should be accepted");
+        let evicted: KubePod = serde_json::from_value(server.get(POD_PATH).unwrap()).unwrap();
+        assert_eq!(
+            Some(10),
+            evicted
+                .metadata
+                .as_ref()
+                .unwrap()
+                .deletion_grace_period_seconds
+        );
+        harness.modify(evicted).await.unwrap();
+        assert!(wait_for_removal(&server).await);
+
+        let status_patches: Vec<serde_json::Value> = server
+            .requests_to(hyper::Method::PATCH, &format!("{}/status", POD_PATH))
+            .into_iter()
+            .filter_map(|r| r.body)
+            .collect();
+        let condition = |patch: &serde_json::Value, type_: &str| {
+            patch["status"]["conditions"]
+                .as_array()
+                .and_then(|c| c.iter().find(|c| c["type"] == type_).cloned())
+        };
+        // The pod is reported as set up before it is stopped
+        let setup = condition(&status_patches[0], "PodReadyToStartContainers").unwrap();
+        assert_eq!("True", setup["status"]);
+        let disruption = condition(&status_patches[1], "DisruptionTarget").unwrap();
+        assert_eq!("True", disruption["status"]);
+        assert!(condition(status_patches.last().unwrap(), "DisruptionTarget").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_containers_started_once() {
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::new(provider.clone());
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+
+        let mut pod = fake_pod("foo", "default");
+        pod.spec.as_mut().unwrap().ephemeral_containers = Some(vec![EphemeralContainer {
+            name: "debugger".to_owned(),
+            image: Some("fake.registry.io/debugger:v1".to_owned()),
+            ..Default::default()
+        }]);
+        harness.modify(pod.clone()).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 1, TIMEOUT).await);
+        harness.modify(pod).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 2, TIMEOUT).await);
+        assert_eq!(
+            1,
+            provider.calls_for(Operation::AddEphemeralContainer).len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modifications_passed_with_changes() {
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::new(provider.clone());
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+
+        let mut pod = fake_pod("foo", "default");
+        pod.spec.as_mut().unwrap().containers[0].image = Some("fake.registry.io/foo:v2".to_owned());
+        harness.modify(pod.clone()).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 1, TIMEOUT).await);
+        // Changes are relative to the definition last passed to the provider
+        harness.modify(pod).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 2, TIMEOUT).await);
+
+        let changes = provider.changes();
+        assert_eq!(2, changes.len());
+        assert_eq!(
+            Some("fake.registry.io/foo:v2"),
+            changes[0].images[0].to.as_deref()
+        );
+        assert!(changes[1].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_modification_passed_again() {
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::new(provider.clone());
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Add, 1, TIMEOUT).await);
+
+        let mut pod = fake_pod("foo", "default");
+        pod.spec.as_mut().unwrap().containers[0].image = Some("fake.registry.io/foo:v2".to_owned());
+        provider.fail(Operation::Modify, "boom");
+        harness.modify(pod.clone()).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 1, TIMEOUT).await);
+        provider.succeed(Operation::Modify);
+        // The change the provider failed to make is still a change the next time
+        harness.modify(pod).await.unwrap();
+        assert!(provider.wait_for_calls(Operation::Modify, 2, TIMEOUT).await);
+
+        let changes = provider.changes();
+        assert_eq!(2, changes.len());
+        assert_eq!(
+            Some("fake.registry.io/foo:v2"),
+            changes[1].images[0].to.as_deref()
+        );
     }
 }
@@ -0,0 +1,205 @@
+//! Client side rate limiting of requests to the Kubernetes API
+//!
+//! The kube client has no way of limiting requests, so requests are made through the
+//! [`RateLimits`] of the Kubelet, which it sets up from its [`ApiClientConfig`] when
+//! it starts. Node heartbeats, the node status and lease renewals that tell the control
+//! plane the node is alive, have a bucket of their own, so a burst of pod updates can't
+//! hold them up. With the `fault-injection` feature, the limits also carry the faults
+//! injected into the Kubelet's requests, see [`faults`](crate::faults).
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::ApiClientConfig;
+
+/// The rate limits of a Kubelet's requests. Cloning them shares the buckets, and the
+/// default doesn't limit requests at all
+#[derive(Clone, Default)]
+pub(crate) struct RateLimits {
+    requests: Option<Arc<RateLimiter>>,
+    heartbeats: Option<Arc<RateLimiter>>,
+    #[cfg(feature = "fault-injection")]
+    faults: crate::faults::FaultInjector,
+}
+
+impl RateLimits {
+    /// Create the buckets for the configured rate limit. Heartbeats get a bucket of the
+    /// same size as other requests
+    pub(crate) fn new(config: &ApiClientConfig) -> Self {
+        let limiter = || {
+            if config.qps > 0.0 {
+                Some(Arc::new(RateLimiter::new(config.qps, config.burst)))
+            } else {
+                None
+            }
+        };
+        RateLimits {
+            requests: limiter(),
+            heartbeats: limiter(),
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
+        }
+    }
+
+    /// Inject the given faults into the requests made under the limits
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn with_faults(mut self, faults: crate::faults::FaultInjector) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// The faults injected into the requests made under the limits
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn faults(&self) -> &crate::faults::FaultInjector {
+        &self.faults
+    }
+
+    /// Make a request once the rate limit allows it. The request is not sent until it
+    /// is awaited, so it can be created before waiting.
+    pub(crate) async fn limited<F: Future>(&self, request: F) -> F::Output {
+        if let Some(limiter) = &self.requests {
+            limiter.acquire().await;
+        }
+        request.await
+    }
+
+    /// The limits node heartbeats are made under, whose requests are limited by the
+    /// heartbeat bucket
+    pub(crate) fn for_heartbeats(&self) -> RateLimits {
+        RateLimits {
+            requests: self.heartbeats.clone(),
+            heartbeats: self.heartbeats.clone(),
+            #[cfg(feature = "fault-injection")]
+            faults: self.faults.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for RateLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimits")
+            .field("limited", &self.requests.is_some())
+            .finish()
+    }
+}
+
+/// A token bucket that allows `burst` requests at once, refilled at `qps` tokens per
+/// second
+struct RateLimiter {
+    qps: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Goes negative when requests are waiting for tokens that have been promised to
+    /// them, but never below `-burst`
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(qps: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            qps,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    async fn acquire(&self) {
+        loop {
+            let (wait, promised) = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let refilled = now.duration_since(bucket.last).as_secs_f64() * self.qps;
+                bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+                bucket.last = now;
+                if bucket.tokens - 1.0 >= -self.burst {
+                    // The token is taken now, even if it has yet to be refilled
+                    bucket.tokens -= 1.0;
+                    (bucket.tokens.min(0.0), true)
+                } else {
+                    // As many tokens as the bucket holds are promised already, so
+                    // wait for some of them to be refilled before asking again
+                    (bucket.tokens + self.burst - 1.0, false)
+                }
+            };
+            if wait < 0.0 {
+                tokio::time::delay_for(Duration::from_secs_f64(-wait / self.qps)).await;
+            }
+            if promised {
+                return;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn tokens(&self) -> f64 {
+        self.bucket.lock().unwrap().tokens
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_burst_then_qps() {
+        let limiter = RateLimiter::new(20.0, 2);
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(20));
+
+        // Each further request has to wait for a new token
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_are_spaced_out() {
+        let limiter = RateLimiter::new(50.0, 1);
+        let start = Instant::now();
+        futures::future::join_all((0..4).map(|_| limiter.acquire())).await;
+        assert!(start.elapsed() >= Duration::from_millis(55));
+    }
+
+    #[tokio::test]
+    async fn test_promised_tokens_are_bounded() {
+        let limiter = Arc::new(RateLimiter::new(10.0, 2));
+        // Far more requests wait than the bucket holds
+        for _ in 0..20 {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire().await });
+        }
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        assert!(limiter.tokens() >= -2.0);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_have_their_own_bucket() {
+        let limits = RateLimits::new(&ApiClientConfig {
+            qps: 1.0,
+            burst: 1,
+            ..Default::default()
+        });
+        limits.limited(async {}).await;
+        // The requests bucket is empty, but heartbeats still go through straight away
+        let start = Instant::now();
+        limits.for_heartbeats().limited(async {}).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Without a rate limit nothing waits
+        let unlimited = RateLimits::default();
+        for _ in 0..10 {
+            unlimited.limited(async {}).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
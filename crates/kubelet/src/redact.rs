@@ -0,0 +1,121 @@
+//! Keeping sensitive values out of logs and pod statuses
+//!
+//! Errors returned by providers can contain the values of environment variables or
+//! registry credentials, and those errors end up in the Kubelet's logs and in the
+//! messages of pod statuses, where anyone who can read the pod can see them. The
+//! Kubelet records the secret values it resolves for a pod, and providers can
+//! [`register`] any other sensitive values they use for it, such as auth tokens.
+//! Every occurrence of these values is replaced with [`REDACTED`] before an error is
+//! logged or reported on the pod's status.
+//!
+//! ```rust
+//! # let pod: k8s_openapi::api::core::v1::Pod =
+//! #     serde_json::from_value(serde_json::json!({"metadata": {"name": "app", "namespace": "default"}})).unwrap();
+//! # let pod = kubelet::Pod::new(pod);
+//! kubelet::redact::register(&pod, "hunter22");
+//! assert_eq!(
+//!     "login failed with password [REDACTED]",
+//!     kubelet::redact::redact(&pod, "login failed with password hunter22")
+//! );
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+
+use crate::handle::key_from_pod;
+use crate::pod::Pod;
+
+/// What sensitive values are replaced with
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Values shorter than this are not redacted, as they would mangle unrelated parts
+/// of messages
+const MIN_LENGTH: usize = 4;
+
+/// How many values are kept for each pod. Secrets that change while the pod runs add
+/// their new values, so once a pod has this many the values it registered longest ago
+/// are dropped
+const MAX_VALUES: usize = 256;
+
+lazy_static::lazy_static! {
+    static ref VALUES: RwLock<HashMap<String, VecDeque<String>>> = RwLock::new(HashMap::new());
+}
+
+/// Record a sensitive value used by a pod, so it is redacted from the pod's errors
+pub fn register(pod: &Pod, value: &str) {
+    // Secrets often end with a newline, which is not what shows up in messages
+    let value = value.trim();
+    if value.len() < MIN_LENGTH {
+        return;
+    }
+    let mut values = VALUES.write().unwrap();
+    let values = values.entry(key_from_pod(pod)).or_default();
+    // A value registered again is kept as the newest
+    values.retain(|v| v != value);
+    values.push_back(value.to_owned());
+    if values.len() > MAX_VALUES {
+        values.pop_front();
+    }
+}
+
+/// Replace the sensitive values of a pod in a message
+pub fn redact(pod: &Pod, message: &str) -> String {
+    let values = VALUES.read().unwrap();
+    let mut values: Vec<&String> = match values.get(&key_from_pod(pod)) {
+        Some(values) => values.iter().collect(),
+        None => return message.to_owned(),
+    };
+    // Longer values go first so a value containing another is replaced as a whole
+    values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+    values
+        .into_iter()
+        .fold(message.to_owned(), |message, value| {
+            message.replace(value.as_str(), REDACTED)
+        })
+}
+
+/// Forget the sensitive values of a pod that was deleted
+pub(crate) fn forget(pod: &Pod) {
+    VALUES.write().unwrap().remove(&key_from_pod(pod));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+
+    #[test]
+    fn test_values_are_bounded() {
+        let pod: Pod = fake_pod("rotated", "default").into();
+        register(&pod, "first secret");
+        for i in 0..MAX_VALUES {
+            register(&pod, &format!("secret {}", i));
+        }
+        register(&pod, "secret 0");
+
+        assert_eq!(
+            MAX_VALUES,
+            VALUES.read().unwrap()[&key_from_pod(&pod)].len()
+        );
+        assert_eq!("first secret", redact(&pod, "first secret"));
+        assert_eq!(REDACTED, redact(&pod, "secret 0"));
+        forget(&pod);
+    }
+
+    #[test]
+    fn test_redact() {
+        let pod: Pod = fake_pod("redacted", "default").into();
+        let other: Pod = fake_pod("other", "default").into();
+        register(&pod, "hunter2\n");
+        register(&pod, "hunter22");
+        register(&pod, "abc");
+
+        assert_eq!(
+            "bad password [REDACTED] ([REDACTED]), abc",
+            redact(&pod, "bad password hunter22 (hunter2), abc")
+        );
+        assert_eq!("hunter2", redact(&other, "hunter2"));
+
+        forget(&pod);
+        assert_eq!("hunter2", redact(&pod, "hunter2"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Inspecting the pods known to a running Kubelet
+//!
+//! Applications that embed the Kubelet, such as tests or control UIs, can get a
+//! [`PodRegistry`] with [`Kubelet::pods`](crate::Kubelet::pods) to see which pods the
+//! Kubelet is handling and what it is doing with each of them.
+//!
+//! ```rust,no_run
+//! # async fn example<P: kubelet::Provider + Send + Sync + 'static>(kubelet: kubelet::Kubelet<P>) {
+//! let registry = kubelet.pods();
+//! for entry in registry.pods() {
+//!     println!("{}/{}: {:?}", entry.pod.namespace(), entry.pod.name(), entry.state);
+//! }
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Utc};
+
+use crate::handle::{key_from_pod, pod_key};
+use crate::pod::Pod;
+use crate::state::State;
+
+/// A pod known to the Kubelet and the state the Kubelet has it in
+#[derive(Clone, Debug)]
+pub struct PodEntry {
+    /// The latest definition of the pod
+    pub pod: Pod,
+    /// The state of the pod. Image pulls happen in the provider, so the Kubelet reports
+    /// pods as [`State::Starting`] while their images are pulled
+    pub state: State,
+    /// When the pod entered its state
+    pub since: DateTime<Utc>,
+    /// The last error the provider returned for the pod, if the pod has not synced
+    /// successfully since
+    pub error: Option<String>,
+}
+
+/// The pods known to a Kubelet.
+///
+/// The registry can be cloned cheaply, and clones see the same pods.
+#[derive(Clone, Default)]
+pub struct PodRegistry {
+    pods: Arc<RwLock<HashMap<String, PodEntry>>>,
+}
+
+impl PodRegistry {
+    /// Returns all pods the Kubelet currently knows, ordered by namespace and name
+    pub fn pods(&self) -> Vec<PodEntry> {
+        let mut pods: Vec<PodEntry> = self.pods.read().unwrap().values().cloned().collect();
+        pods.sort_by(|a, b| {
+            (a.pod.namespace(), a.pod.name()).cmp(&(b.pod.namespace(), b.pod.name()))
+        });
+        pods
+    }
+
+    /// Returns the pod with the given name, if the Kubelet knows it
+    pub fn get(&self, namespace: &str, name: &str) -> Option<PodEntry> {
+        self.pods
+            .read()
+            .unwrap()
+            .get(&pod_key(namespace, name))
+            .cloned()
+    }
+
+    /// Record that a pod moved to a new state, clearing any error
+    pub(crate) fn set_state(&self, pod: &Pod, state: State) {
+        self.pods.write().unwrap().insert(
+            key_from_pod(pod),
+            PodEntry {
+                pod: pod.clone(),
+                state,
+                since: Utc::now(),
+                error: None,
+            },
+        );
+    }
+
+    /// Record the latest definition of a pod
+    pub(crate) fn update_pod(&self, pod: &Pod) {
+        if let Some(entry) = self.pods.write().unwrap().get_mut(&key_from_pod(pod)) {
+            entry.pod = pod.clone();
+        }
+    }
+
+    /// Record whether the provider last succeeded in syncing a pod
+    pub(crate) fn set_error(&self, pod: &Pod, error: Option<String>) {
+        if let Some(entry) = self.pods.write().unwrap().get_mut(&key_from_pod(pod)) {
+            entry.error = error;
+        }
+    }
+
+    /// Forget about a pod that was deleted
+    pub(crate) fn remove(&self, pod: &Pod) {
+        self.pods.write().unwrap().remove(&key_from_pod(pod));
+    }
+}
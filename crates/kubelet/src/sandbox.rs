@@ -0,0 +1,120 @@
+//! Setting pods up before their containers start
+//!
+//! Before a pod's containers are started, its provider sets up what the pod as a whole
+//! needs, such as its network, volumes and service account token, with
+//! [`Provider::setup_pod`]. Whether this has happened is reported with the
+//! `PodReadyToStartContainers` condition, and failures are reported with their own
+//! reason so they can be told apart from failed containers. Once the pod's workload is
+//! deleted, the setup is undone with [`Provider::teardown_pod`].
+use chrono::Utc;
+use k8s_openapi::api::core::v1::PodCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use log::{debug, info, warn};
+
+use crate::error::PodSyncError;
+use crate::pod::{Pod, ValidatedPod};
+use crate::status::StatusPatch;
+use crate::Provider;
+
+/// The pod condition that reports whether a pod has been set up for its containers to
+/// start
+pub(crate) const READY_TO_START_CONTAINERS: &str = "PodReadyToStartContainers";
+
+/// Returns the `PodReadyToStartContainers` condition for a pod that has been set up or
+/// that failed to set up with the given message
+pub(crate) fn ready_condition(ready: bool, message: Option<String>) -> PodCondition {
+    PodCondition {
+        type_: READY_TO_START_CONTAINERS.to_owned(),
+        status: if ready { "True" } else { "False" }.to_owned(),
+        message,
+        last_transition_time: Some(Time(Utc::now())),
+        ..Default::default()
+    }
+}
+
+/// Have the provider set up a pod and report that its containers can start.
+///
+/// Errors are returned as [`PodSyncError::PodSetup`], unless the provider returned a
+/// more specific [`PodSyncError`].
+pub(crate) async fn set_up<P: Provider + Sync>(
+    provider: &P,
+    client: &kube::Client,
+    pod: &ValidatedPod,
+) -> anyhow::Result<()> {
+    debug!(
+        "Setting up pod {} in namespace {}",
+        pod.name(),
+        pod.namespace()
+    );
+    if let Err(e) = provider.setup_pod(pod).await {
+        let error = match PodSyncError::from(e) {
+            PodSyncError::Provider(e) => PodSyncError::PodSetup(e),
+            e => e,
+        };
+        return Err(error.into());
+    }
+    info!(
+        "Pod {} in namespace {} is ready to start containers",
+        pod.name(),
+        pod.namespace()
+    );
+    let patch = StatusPatch::new().condition(ready_condition(true, None));
+    if let Err(e) = patch.apply_for(client.clone(), pod).await {
+        warn!(
+            "Unable to report that pod {} is ready to start containers: {}",
+            pod.name(),
+            e
+        );
+    }
+    Ok(())
+}
+
+/// Have the provider undo the setup of a pod whose workload was deleted
+pub(crate) async fn tear_down<P: Provider + Sync>(provider: &P, pod: &Pod) {
+    match provider.teardown_pod(pod).await {
+        Ok(()) => debug!(
+            "Tore down pod {} in namespace {}",
+            pod.name(),
+            pod.namespace()
+        ),
+        Err(e) => warn!(
+            "Unable to tear down pod {} in namespace {}: {}",
+            pod.name(),
+            pod.namespace(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, FakeProvider, MockApiServer};
+
+    #[tokio::test]
+    async fn test_set_up_keeps_status() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut pod = serde_json::to_value(fake_pod("foo", "default")).unwrap();
+        pod["status"] = serde_json::json!({
+            "phase": "Running",
+            "conditions": [{ "type": "Ready", "status": "True" }],
+            "containerStatuses": [{ "name": "a", "ready": true, "restartCount": 0, "image": "", "imageID": "" }],
+        });
+        server.insert("/api/v1/namespaces/default/pods", &pod);
+
+        let pod = ValidatedPod::new(Pod::new(serde_json::from_value(pod).unwrap())).unwrap();
+        set_up(&FakeProvider::new(), &server.client(), &pod)
+            .await
+            .unwrap();
+        let status = &server.get("/api/v1/namespaces/default/pods/foo").unwrap()["status"];
+        assert_eq!("Running", status["phase"]);
+        assert_eq!("a", status["containerStatuses"][0]["name"]);
+        let conditions: Vec<&str> = status["conditions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["Ready", READY_TO_START_CONTAINERS], conditions);
+    }
+}
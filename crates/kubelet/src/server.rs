@@ -5,69 +5,370 @@ use anyhow::Context;
 use hyper::service::service_fn;
 use hyper::{server::conn::Http, Body, Method, Request, Response, StatusCode};
 use log::{debug, error, info, warn};
-use native_tls::{Identity, TlsAcceptor};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::stream::StreamExt;
 
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use crate::auth::{Authenticator, Denied};
 use crate::config::ServerConfig;
+use crate::log_filter::LogFilter;
 use crate::logs::LogSender;
 use crate::provider::{NotImplementedError, Provider};
+use crate::registry::PodRegistry;
+use crate::stats::{summary, ResourceAccounting};
+use crate::tls::Acceptor;
+
+/// The paths, after the leading `/`, of the endpoints that give interactive access to
+/// containers, which a read-only server refuses. Provider routes are refused too, as
+/// the server can't tell what a provider does with a request
+const INTERACTIVE_ENDPOINTS: &[&str] = &["exec", "attach", "portForward", "run", "providers"];
+
+/// How often the certificate, client CA and token files of the webserver are checked
+/// for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What request handlers share
+struct Server<T> {
+    provider: Arc<T>,
+    /// Replaced when the token file changes on disk
+    authenticator: RwLock<Arc<Authenticator>>,
+    accounting: ResourceAccounting,
+    registry: PodRegistry,
+    node_name: String,
+    read_only: bool,
+    debug: DebugFlags,
+}
+
+/// What the Kubelet lets its server change while it runs, under `/debug`
+#[derive(Clone, Default)]
+pub(crate) struct DebugFlags {
+    log: LogFilter,
+    #[cfg(feature = "fault-injection")]
+    faults: crate::faults::FaultInjector,
+}
+
+impl DebugFlags {
+    /// Serve and replace the directives of the Kubelet's log filter
+    pub(crate) fn with_log_filter(mut self, log: LogFilter) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Serve and replace the faults injected into the Kubelet
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn with_faults(mut self, faults: crate::faults::FaultInjector) -> Self {
+        self.faults = faults;
+        self
+    }
+}
 
 /// Start the Krustlet HTTP(S) server
 ///
 /// This is a primitive implementation of an HTTP provider for the internal API.
+/// Pod stats for the Summary API are collected through the given accounting, and the
+/// pods are listed from the given registry. The certificate, which is read from a pfx
+/// bundle or from PEM certificate and key files, the client CA and the token file are
+/// reloaded when they change on disk. Clients authenticate with a token or, when a
+/// client CA is configured, a client certificate. The `/debug` endpoints change the
+/// given flags.
 /// TODO: Support TLS/SSL.
 pub async fn start_webserver<T: 'static + Provider + Send + Sync>(
     provider: Arc<T>,
     config: &ServerConfig,
+    accounting: ResourceAccounting,
+    registry: PodRegistry,
+    node_name: String,
+    debug: DebugFlags,
 ) -> anyhow::Result<()> {
-    let identity = tokio::fs::read(&config.pfx_path)
-        .await
-        .with_context(|| format!("Could not read file {:?}", config.pfx_path))?;
-    let identity = Identity::from_pkcs12(&identity, &config.pfx_password)?;
-
-    let acceptor = tokio_tls::TlsAcceptor::from(TlsAcceptor::new(identity)?);
+    let mut reloader = Reloader::new(config);
+    for file in reloader.certificate_files() {
+        file.changed()
+            .await
+            .with_context(|| format!("Could not read file {:?}", file.path))?;
+    }
+    let acceptor = Arc::new(RwLock::new(Arc::new(reloader.acceptor()?)));
 
-    let acceptor = Arc::new(acceptor);
+    // The token file is watched before it is loaded, so a change in between is noticed
+    if let Some(token_file) = reloader.token_file.as_mut() {
+        let _ = token_file.changed().await;
+    }
+    let server = Arc::new(Server {
+        provider,
+        authenticator: RwLock::new(Arc::new(Authenticator::load(&config.auth).await?)),
+        accounting,
+        registry,
+        node_name,
+        read_only: config.read_only,
+        debug,
+    });
+    if config.read_only {
+        info!("The webserver is read-only");
+    }
 
     let address = std::net::SocketAddr::new(config.addr, config.port);
     let mut listener = TcpListener::bind(&address).await.unwrap();
 
     info!("Starting webserver at: {}", address);
 
-    let mut incoming = listener.incoming();
-
-    while let Some(conn) = incoming.try_next().await? {
+    let reload = {
         let acceptor = acceptor.clone();
-        let provider = provider.clone();
+        let server = server.clone();
+        async move {
+            loop {
+                tokio::time::delay_for(RELOAD_CHECK_INTERVAL).await;
+                reloader.check_certificate(&acceptor).await;
+                reloader.check_token_file(&server).await;
+            }
+        }
+    };
+    let serve = async move {
+        let mut incoming = listener.incoming();
+        while let Some(conn) = incoming.try_next().await? {
+            // Connections keep the certificate they were accepted with
+            let acceptor = acceptor.read().unwrap().clone();
+            let server = server.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(conn, acceptor, server).await {
+                    error!("Error handling server connection: {}", e);
+                }
+            });
+        }
+        Ok(())
+    };
+
+    tokio::select! {
+        result = serve => result,
+        _ = reload => Ok(()),
+    }
+}
+
+/// Notices when a file the webserver is configured from is replaced, such as by a
+/// certificate manager rotating the serving certificate. The whole file is read on each
+/// check, which also catches secret volumes switching the symlink the file is behind
+struct FileWatch {
+    path: PathBuf,
+    contents: Option<Vec<u8>>,
+}
+
+impl FileWatch {
+    /// Watch the file. The first check returns what it holds
+    fn new(path: &Path) -> Self {
+        FileWatch {
+            path: path.to_owned(),
+            contents: None,
+        }
+    }
+
+    /// Returns what the file holds if it changed since the last check
+    async fn changed(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let contents = tokio::fs::read(&self.path).await?;
+        if self.contents.as_ref() == Some(&contents) {
+            return Ok(None);
+        }
+        self.contents = Some(contents.clone());
+        Ok(Some(contents))
+    }
+
+    /// What the file held when it was last checked
+    fn contents(&self) -> &[u8] {
+        self.contents.as_deref().unwrap_or_default()
+    }
+}
+
+/// The files the webserver's certificate is read from
+enum CertificateFiles {
+    /// A PKCS #12 bundle of the certificate and its key
+    Pfx(FileWatch),
+    /// PEM files of the certificate, followed by its chain, and of its key
+    Pem { cert: FileWatch, key: FileWatch },
+}
+
+/// Swaps in the webserver's certificate and tokens when their files change, so they
+/// can be rotated without restarting the Kubelet. New connections are accepted with
+/// the new certificate, while open ones keep theirs. A file that can't be read, such
+/// as while it is being replaced, is checked again until it can be. A certificate that
+/// is not valid, such as one whose key has yet to be replaced too, leaves the previous
+/// certificate or tokens in use until a file changes again
+struct Reloader {
+    config: ServerConfig,
+    certificate: CertificateFiles,
+    client_ca: Option<FileWatch>,
+    /// Whether a file of the certificate changed since the certificate was last loaded
+    certificate_changed: bool,
+    token_file: Option<FileWatch>,
+}
+
+impl Reloader {
+    /// Watch the files the server is configured with. Their first check returns what
+    /// they hold
+    fn new(config: &ServerConfig) -> Self {
+        let certificate = match (&config.cert_file, &config.private_key_file) {
+            (Some(cert), Some(key)) => CertificateFiles::Pem {
+                cert: FileWatch::new(cert),
+                key: FileWatch::new(key),
+            },
+            _ => CertificateFiles::Pfx(FileWatch::new(&config.pfx_path)),
+        };
+        Reloader {
+            config: config.clone(),
+            certificate,
+            client_ca: config.auth.client_ca_file.as_deref().map(FileWatch::new),
+            certificate_changed: false,
+            token_file: config.auth.token_file.as_deref().map(FileWatch::new),
+        }
+    }
+
+    /// The files the acceptor is made from
+    fn certificate_files(&mut self) -> Vec<&mut FileWatch> {
+        let mut files = match &mut self.certificate {
+            CertificateFiles::Pfx(pfx) => vec![pfx],
+            CertificateFiles::Pem { cert, key } => vec![cert, key],
+        };
+        files.extend(self.client_ca.as_mut());
+        files
+    }
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(conn, acceptor, provider).await {
-                error!("Error handling server connection: {}", e);
+    /// An acceptor for the certificate the files held when they were last checked
+    fn acceptor(&self) -> anyhow::Result<Acceptor> {
+        let client_ca = self.config.auth.client_ca_file.as_deref();
+        match &self.certificate {
+            CertificateFiles::Pfx(pfx) => {
+                Acceptor::new(pfx.contents(), &self.config.pfx_password, client_ca)
             }
-        });
+            CertificateFiles::Pem { cert, key } => {
+                Acceptor::from_pem(cert.contents(), key.contents(), client_ca)
+            }
+        }
     }
 
-    Ok(())
+    async fn check_certificate(&mut self, acceptor: &RwLock<Arc<Acceptor>>) {
+        let mut changed = self.certificate_changed;
+        let mut unreadable = false;
+        for file in self.certificate_files() {
+            match file.changed().await {
+                Ok(contents) => changed |= contents.is_some(),
+                Err(e) => {
+                    debug!(
+                        "Unable to check the webserver certificate {:?}: {}",
+                        file.path, e
+                    );
+                    unreadable = true;
+                    break;
+                }
+            }
+        }
+        // The certificate is only loaded once all of its files can be read
+        self.certificate_changed = changed && unreadable;
+        if !changed || unreadable {
+            return;
+        }
+        match self.acceptor() {
+            Ok(reloaded) => {
+                *acceptor.write().unwrap() = Arc::new(reloaded);
+                info!("Reloaded the webserver certificate");
+            }
+            Err(e) => warn!(
+                "Unable to reload the webserver certificate, still serving the previous one: {:#}",
+                e
+            ),
+        }
+    }
+
+    async fn check_token_file<T>(&mut self, server: &Server<T>) {
+        let token_file = match self.token_file.as_mut() {
+            Some(token_file) => token_file,
+            None => return,
+        };
+        match token_file.changed().await {
+            Ok(Some(_)) => match Authenticator::load(&self.config.auth).await {
+                Ok(reloaded) => {
+                    *server.authenticator.write().unwrap() = Arc::new(reloaded);
+                    info!("Reloaded the webserver tokens from {:?}", token_file.path);
+                }
+                Err(e) => warn!(
+                    "Unable to reload the webserver tokens, still using the previous ones: {:#}",
+                    e
+                ),
+            },
+            Ok(None) => (),
+            Err(e) => debug!(
+                "Unable to check the token file {:?}: {}",
+                token_file.path, e
+            ),
+        }
+    }
+}
+
+/// Run the webserver on a Tokio runtime of its own with the given number of worker
+/// threads, so its connections don't compete with the Kubelet's other tasks for the
+/// runtime the Kubelet runs on
+pub(crate) async fn run_isolated<F, T>(worker_threads: usize, webserver: F) -> anyhow::Result<T>
+where
+    F: std::future::Future<Output = anyhow::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .core_threads(worker_threads)
+        .thread_name("krustlet-webserver")
+        .enable_all()
+        .build()
+        .context("Unable to start the webserver runtime")?;
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new()
+        .name("krustlet-webserver".to_owned())
+        .spawn(move || {
+            let _ = tx.send(runtime.block_on(webserver));
+        })
+        .context("Unable to start the webserver runtime")?;
+    info!(
+        "Running the webserver on its own runtime with {} worker threads",
+        worker_threads
+    );
+    rx.await.unwrap_or_else(|_| {
+        Err(anyhow::anyhow!(
+            "The webserver runtime stopped unexpectedly"
+        ))
+    })
 }
 
 async fn handle_connection<T>(
     conn: TcpStream,
-    acceptor: Arc<tokio_tls::TlsAcceptor>,
-    provider: Arc<T>,
+    acceptor: Arc<Acceptor>,
+    server: Arc<Server<T>>,
 ) -> anyhow::Result<()>
 where
     T: Provider + Send + Sync + 'static,
 {
-    let io = acceptor.accept(conn).await?;
+    let (io, client) = acceptor.accept(conn).await?;
+    let client = Arc::new(client);
     Http::new()
         .serve_connection(
             io,
             service_fn(move |req| {
-                let provider = provider.clone();
-                async move { handle_request(req, provider).await }
+                let server = server.clone();
+                let client = client.clone();
+                async move {
+                    let authenticator = server.authenticator.read().unwrap().clone();
+                    match authenticator.check(&req, client.as_ref().as_ref()) {
+                        Ok(user) => {
+                            if let Some(user) = user {
+                                debug!(
+                                    "Request to {} authenticated as {} (uid {})",
+                                    req.uri().path(),
+                                    user.name,
+                                    user.uid
+                                );
+                            }
+                            handle_request(req, &server).await
+                        }
+                        Err(denied) => Ok(denied_response(&req, denied)),
+                    }
+                }
             }),
         )
         .await?;
@@ -75,13 +376,25 @@ where
     Ok(())
 }
 
-async fn handle_request<T>(req: Request<Body>, provider: Arc<T>) -> anyhow::Result<Response<Body>>
+async fn handle_request<T>(req: Request<Body>, server: &Server<T>) -> anyhow::Result<Response<Body>>
 where
     T: Provider + Send + Sync + 'static,
 {
     let path: Vec<&str> = req.uri().path().split('/').collect();
+    let provider = server.provider.as_ref();
+
+    if server.read_only && !is_read_only(&req, &path) {
+        return Ok(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from(
+                "Forbidden: the Kubelet server is read-only, so only requests that read from the node are allowed",
+            ))
+            .unwrap());
+    }
 
     let response = match (req.method(), path.as_slice()) {
+        (&Method::GET, [_, "pods"]) => get_pods(&server.registry),
+        (&Method::GET, [_, "version"]) => get_version(),
         (_, path) if path.len() <= 2 => get_ping(),
         (&Method::GET, [_, "containerLogs", namespace, pod, container]) => {
             let params: std::collections::HashMap<String, String> = req
@@ -112,7 +425,7 @@ where
                 }
             }
             get_container_logs(
-                &*provider,
+                provider,
                 &req,
                 (*namespace).to_string(),
                 (*pod).to_string(),
@@ -122,7 +435,27 @@ where
             )
             .await
         }
-        (&Method::POST, [_, "exec", _, _, _]) => post_exec(&*provider, &req),
+        (&Method::POST, [_, "exec", _, _, _]) => post_exec(provider, &req),
+        (_, [_, "providers", name, rest @ ..]) if provider.has_routes(name) => {
+            let path = format!("/{}", rest.join("/"));
+            let name = (*name).to_owned();
+            provider_route(provider, &name, &path, req).await
+        }
+        (&Method::GET, [_, "stats", "summary"]) => {
+            get_stats_summary(provider, &server.accounting, &server.node_name).await
+        }
+        (&Method::GET, [_, "debug", "flags", "log"]) => {
+            Response::new(Body::from(server.debug.log.get()))
+        }
+        (&Method::PUT, [_, "debug", "flags", "log"]) => {
+            put_log_filter(&server.debug.log, req).await
+        }
+        #[cfg(feature = "fault-injection")]
+        (&Method::GET, [_, "debug", "faults"]) => {
+            Response::new(Body::from(server.debug.faults.get()))
+        }
+        #[cfg(feature = "fault-injection")]
+        (&Method::PUT, [_, "debug", "faults"]) => put_faults(&server.debug.faults, req).await,
         _ => Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(Body::from("Not Found"))
@@ -132,6 +465,80 @@ where
     Ok(response)
 }
 
+/// Whether a request only reads from the node, so a read-only server can answer it
+fn is_read_only(req: &Request<Body>, path: &[&str]) -> bool {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return false;
+    }
+    if path.len() > 1 && INTERACTIVE_ENDPOINTS.contains(&path[1]) {
+        return false;
+    }
+    // Following logs keeps a connection open to the container
+    let query = req.uri().query().unwrap_or_default();
+    !url::form_urlencoded::parse(query.as_bytes()).any(|(k, v)| k == "follow" && v == "true")
+}
+
+/// Respond to a request that failed authentication or authorization
+fn denied_response(req: &Request<Body>, denied: Denied) -> Response<Body> {
+    let (status, message) = match denied {
+        Denied::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized"),
+        Denied::Forbidden(user) => {
+            warn!(
+                "User {} is not allowed to access {}",
+                user,
+                req.uri().path()
+            );
+            (StatusCode::FORBIDDEN, "Forbidden")
+        }
+    };
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+/// Replace the directives that choose which log entries are written
+///
+/// Implements the kubelet path /debug/flags/log
+async fn put_log_filter(filter: &LogFilter, req: Request<Body>) -> Response<Body> {
+    let directives = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => String::from_utf8_lossy(&body).trim().to_owned(),
+        Err(e) => return bad_request(format!("Unable to read request body: {}", e)),
+    };
+    match filter.set(&directives) {
+        Ok(()) => {
+            info!("Log filter changed to {:?}", filter.get());
+            Response::new(Body::from(filter.get()))
+        }
+        Err(e) => bad_request(format!("Invalid log filter: {}", e)),
+    }
+}
+
+/// Replace the failures injected into the Kubelet
+///
+/// Implements the kubelet path /debug/faults
+#[cfg(feature = "fault-injection")]
+async fn put_faults(injector: &crate::faults::FaultInjector, req: Request<Body>) -> Response<Body> {
+    let faults = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => String::from_utf8_lossy(&body).trim().to_owned(),
+        Err(e) => return bad_request(format!("Unable to read request body: {}", e)),
+    };
+    match injector.set(&faults) {
+        Ok(()) => {
+            warn!("Injected faults changed to {:?}", injector.get());
+            Response::new(Body::from(injector.get()))
+        }
+        Err(e) => bad_request(format!("Invalid faults: {}", e)),
+    }
+}
+
+fn bad_request(message: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(message))
+        .unwrap()
+}
+
 /// Return a simple status message
 fn get_ping() -> Response<Body> {
     Response::new(Body::from("this is the Krustlet HTTP server"))
@@ -179,6 +586,81 @@ async fn get_container_logs<T: Provider + Sync>(
         }
     }
 }
+/// Pass a request to a route registered by the provider
+///
+/// Implements the kubelet path /providers/{name}/...
+async fn provider_route<T: Provider + Sync>(
+    provider: &T,
+    name: &str,
+    path: &str,
+    req: Request<Body>,
+) -> Response<Body> {
+    match provider.route(name, path, req).await {
+        Ok(response) => response,
+        Err(e) if e.is::<NotImplementedError>() => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not Found"))
+            .unwrap(),
+        Err(e) => {
+            error!("Error serving provider route {}{}: {}", name, path, e);
+            let mut res = Response::new(Body::from(format!("Server error: {}", e)));
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            res
+        }
+    }
+}
+
+/// Get the usage of the pods on the node
+///
+/// Implements the kubelet path /stats/summary
+async fn get_stats_summary<T: Provider + Sync>(
+    provider: &T,
+    accounting: &ResourceAccounting,
+    node_name: &str,
+) -> Response<Body> {
+    let usage = accounting.collect(provider).await;
+    let node = accounting.node_usage(&usage).await;
+    let body =
+        serde_json::to_vec(&summary(node_name, node, &usage)).expect("Should always serialize");
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// List the pods known to the Kubelet
+///
+/// Implements the kubelet path /pods
+fn get_pods(registry: &PodRegistry) -> Response<Body> {
+    let pods: Vec<_> = registry
+        .pods()
+        .into_iter()
+        .map(|entry| entry.pod.as_kube_pod().clone())
+        .collect();
+    let body = serde_json::to_vec(&serde_json::json!({
+        "kind": "PodList",
+        "apiVersion": "v1",
+        "metadata": {},
+        "items": pods,
+    }))
+    .expect("Should always serialize");
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Describe what the Kubelet was built from
+///
+/// Implements the kubelet path /version
+fn get_version() -> Response<Body> {
+    let body = serde_json::to_vec(&crate::version::info()).expect("Should always serialize");
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
 /// Run a pod exec command and get the output
 ///
 /// Implements the kubelet path /exec/{namespace}/{pod}/{container}
@@ -187,3 +669,333 @@ fn post_exec<T: Provider>(_provider: &T, _req: &Request<Body>) -> Response<Body>
     *res.status_mut() = StatusCode::NOT_IMPLEMENTED;
     res
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::State;
+    use crate::testing::{fake_pod, FakeProvider};
+
+    #[tokio::test]
+    async fn test_run_isolated() {
+        let thread = run_isolated(2, async {
+            // Tasks spawned by the webserver run on its runtime too
+            let spawned = tokio::spawn(async { std::thread::current().name().map(String::from) });
+            Ok(spawned.await?)
+        })
+        .await
+        .unwrap();
+        assert_eq!(Some("krustlet-webserver"), thread.as_deref());
+
+        let error = run_isolated(1, async { Err::<(), _>(anyhow::anyhow!("no certificate")) })
+            .await
+            .unwrap_err();
+        assert_eq!("no certificate", error.to_string());
+    }
+
+    async fn server(read_only: bool) -> Server<FakeProvider> {
+        let provider = FakeProvider::new();
+        provider
+            .serve("debug", "/info", "debug info")
+            .fail_route("debug", "/broken", "broken");
+        Server {
+            provider: Arc::new(provider),
+            authenticator: RwLock::new(Arc::new(
+                Authenticator::load(&Default::default()).await.unwrap(),
+            )),
+            accounting: ResourceAccounting::default(),
+            registry: PodRegistry::default(),
+            node_name: "krustlet".to_owned(),
+            read_only,
+            debug: DebugFlags::default(),
+        }
+    }
+
+    async fn request(
+        server: &Server<FakeProvider>,
+        method: Method,
+        path: &str,
+    ) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = handle_request(req, server).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn get(path: &str) -> (StatusCode, String) {
+        request(&server(false).await, Method::GET, path).await
+    }
+
+    #[tokio::test]
+    async fn test_provider_routes() {
+        assert_eq!(
+            (StatusCode::OK, "debug info".to_owned()),
+            get("/providers/debug/info").await
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get("/providers/debug/missing").await.0
+        );
+        assert_eq!(StatusCode::NOT_FOUND, get("/providers/other/info").await.0);
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            get("/providers/debug/broken").await.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pods() {
+        let server = server(false).await;
+        server
+            .registry
+            .set_state(&fake_pod("foo", "default").into(), State::Running);
+        let (status, body) = request(&server, Method::GET, "/pods").await;
+        assert_eq!(StatusCode::OK, status);
+        let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!("PodList", list["kind"]);
+        assert_eq!("foo", list["items"][0]["metadata"]["name"]);
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let (status, body) = get("/version").await;
+        assert_eq!(StatusCode::OK, status);
+        let info: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(env!("CARGO_PKG_VERSION"), info["version"]);
+        assert!(info["gitCommit"].is_string());
+        assert!(info["buildDate"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_log_filter() {
+        let server = server(false).await;
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri("/debug/flags/log")
+            .body(Body::from("info,kubelet::queue=trace\n"))
+            .unwrap();
+        let response = handle_request(req, &server).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            (StatusCode::OK, "info,kubelet::queue=trace".to_owned()),
+            request(&server, Method::GET, "/debug/flags/log").await
+        );
+
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri("/debug/flags/log")
+            .body(Body::from("kubelet=loud"))
+            .unwrap();
+        let response = handle_request(req, &server).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+        assert_eq!("info,kubelet::queue=trace", server.debug.log.get());
+        // The filter is the server's Kubelet's own
+        assert_eq!(
+            (StatusCode::OK, String::new()),
+            get("/debug/flags/log").await
+        );
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[tokio::test]
+    async fn test_faults() {
+        let server = server(false).await;
+        let put = |faults: &'static str| {
+            Request::builder()
+                .method(Method::PUT)
+                .uri("/debug/faults")
+                .body(Body::from(faults))
+                .unwrap()
+        };
+        let response = handle_request(put("fail-provider-calls=often"), &server)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let response = handle_request(put("fail-provider-calls=3\n"), &server)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            (StatusCode::OK, "fail-provider-calls=3".to_owned()),
+            request(&server, Method::GET, "/debug/faults").await
+        );
+        // The faults are the server's Kubelet's own
+        assert_eq!((StatusCode::OK, String::new()), get("/debug/faults").await);
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let read_only = server(true).await;
+        let allowed = [
+            "/pods",
+            "/stats/summary",
+            "/containerLogs/default/foo/bar?tailLines=10",
+        ];
+        for path in allowed.iter() {
+            assert_ne!(
+                StatusCode::FORBIDDEN,
+                request(&read_only, Method::GET, path).await.0,
+                "{} should be allowed",
+                path
+            );
+        }
+        let refused = [
+            (Method::POST, "/exec/default/foo/bar"),
+            (Method::GET, "/attach/default/foo/bar"),
+            (Method::GET, "/portForward/default/foo"),
+            (Method::GET, "/containerLogs/default/foo/bar?follow=true"),
+            (Method::GET, "/providers/debug/info"),
+            (Method::POST, "/providers/debug/info"),
+            (Method::PUT, "/debug/flags/log"),
+        ];
+        for (method, path) in refused.iter() {
+            assert_eq!(
+                StatusCode::FORBIDDEN,
+                request(&read_only, method.clone(), path).await.0,
+                "{} {} should be refused",
+                method,
+                path
+            );
+        }
+
+        // Without read-only mode, exec reaches the provider
+        assert_eq!(
+            StatusCode::NOT_IMPLEMENTED,
+            request(&server(false).await, Method::POST, "/exec/default/foo/bar")
+                .await
+                .0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_token_file() {
+        let dir =
+            std::env::temp_dir().join(format!("krustlet-server-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tokens.csv");
+        std::fs::write(&path, "first,alice,1\n").unwrap();
+        let config = ServerConfig {
+            addr: "127.0.0.1".parse().unwrap(),
+            port: 0,
+            pfx_path: dir.join("missing.pfx"),
+            pfx_password: String::new(),
+            cert_file: None,
+            private_key_file: None,
+            auth: crate::config::AuthConfig {
+                token_file: Some(path.clone()),
+                ..Default::default()
+            },
+            read_only: false,
+            worker_threads: None,
+        };
+        let mut reloader = Reloader::new(&config);
+        let token_file = reloader.token_file.as_mut().unwrap();
+        assert!(token_file.changed().await.unwrap().is_some());
+        let mut server = server(false).await;
+        server.authenticator =
+            RwLock::new(Arc::new(Authenticator::load(&config.auth).await.unwrap()));
+        let accepts = |server: &Server<FakeProvider>, token: &str| {
+            let req = Request::builder()
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            server
+                .authenticator
+                .read()
+                .unwrap()
+                .check(&req, None)
+                .is_ok()
+        };
+
+        reloader.check_token_file(&server).await;
+        assert!(accepts(&server, "first"));
+
+        std::fs::write(&path, "second,bob,2\n").unwrap();
+        reloader.check_token_file(&server).await;
+        assert!(accepts(&server, "second"));
+        assert!(!accepts(&server, "first"));
+
+        // Invalid token files leave the previous tokens in use
+        std::fs::write(&path, "broken\n").unwrap();
+        reloader.check_token_file(&server).await;
+        assert!(accepts(&server, "second"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// The common name of the certificate a new connection is accepted with
+    #[cfg(target_os = "linux")]
+    async fn served(listener: &mut TcpListener, acceptor: &RwLock<Arc<Acceptor>>) -> String {
+        use openssl::nid::Nid;
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            let stream = connector.build().connect("localhost", stream).unwrap();
+            let cert = stream.ssl().peer_certificate().unwrap();
+            let name = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next();
+            name.unwrap().data().as_utf8().unwrap().to_string()
+        });
+        let acceptor = acceptor.read().unwrap().clone();
+        let (conn, _) = listener.accept().await.unwrap();
+        let _conn = acceptor.accept(conn).await.unwrap();
+        client.join().unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reload_certificate() {
+        use crate::tls::test::certificate;
+
+        let dir = std::env::temp_dir().join(format!("krustlet-server-cert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_file = dir.join("tls.crt");
+        let key_file = dir.join("tls.key");
+        let write = |name: &str| {
+            let (cert, key) = certificate(&[("CN", name)], None);
+            std::fs::write(&cert_file, cert.to_pem().unwrap()).unwrap();
+            std::fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        };
+        write("first");
+        let config = ServerConfig {
+            addr: "127.0.0.1".parse().unwrap(),
+            port: 0,
+            pfx_path: dir.join("missing.pfx"),
+            pfx_password: String::new(),
+            cert_file: Some(cert_file.clone()),
+            private_key_file: Some(key_file.clone()),
+            auth: Default::default(),
+            read_only: false,
+            worker_threads: None,
+        };
+        let mut reloader = Reloader::new(&config);
+        for file in reloader.certificate_files() {
+            file.changed().await.unwrap();
+        }
+        let acceptor = RwLock::new(Arc::new(reloader.acceptor().unwrap()));
+
+        let mut listener = TcpListener::bind(&std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        assert_eq!("first", served(&mut listener, &acceptor).await);
+
+        write("second");
+        reloader.check_certificate(&acceptor).await;
+        assert_eq!("second", served(&mut listener, &acceptor).await);
+
+        // A key that doesn't match the certificate leaves the previous one in use
+        let (_, other_key) = certificate(&[("CN", "third")], None);
+        std::fs::write(&key_file, other_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        reloader.check_certificate(&acceptor).await;
+        assert_eq!("second", served(&mut listener, &acceptor).await);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
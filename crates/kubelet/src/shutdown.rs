@@ -0,0 +1,546 @@
+//! Graceful node shutdown
+//!
+//! On Linux, the Kubelet takes a delay inhibitor lock from systemd-logind so the host
+//! waits for it before shutting down. When logind announces a shutdown, the node is
+//! reported as not ready and the pods on it are stopped within the configured
+//! [`shutdown_grace_period`](crate::config::Config::shutdown_grace_period), after
+//! which the lock is released so the shutdown can go ahead.
+//!
+//! As with the Kubernetes kubelet, the last
+//! [`shutdown_grace_period_critical_pods`](crate::config::Config::shutdown_grace_period_critical_pods)
+//! of the grace period is kept for critical pods, such as node agents, which are only
+//! stopped once the other pods have stopped or run out of time. Without time kept for
+//! them, critical pods share what is left of the grace period once the other pods
+//! have stopped. The pods of a group are stopped at the same time, each within its
+//! own termination grace period.
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::{Node, PodCondition};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use log::{error, info, warn};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::Config;
+use crate::node::{update_node, Readiness, Refusals};
+use crate::pod::{Pod, ValidatedPod};
+use crate::rate_limit::RateLimits;
+use crate::status::{ContainerStatus, Phase, StatusPatch};
+use crate::Provider;
+
+/// The reason reported on pods that were stopped because the node shut down
+const SHUTDOWN_REASON: &str = "Terminated";
+/// The message reported on pods that were stopped because the node shut down
+const SHUTDOWN_MESSAGE: &str = "Pod was terminated in response to imminent node shutdown.";
+
+/// Stop the node's pods whenever the host shuts down. The pods are stopped by their
+/// workers, which the pod queue is asked to do through `stops`.
+///
+/// This does nothing if the shutdown grace period is zero, and only logs a warning on
+/// platforms other than Linux.
+pub(crate) async fn watch<P: Provider + Sync + Send + 'static>(
+    client: kube::Client,
+    config: Config,
+    limits: RateLimits,
+    provider: Arc<P>,
+    readiness: Readiness,
+    stops: mpsc::Sender<StopRequest>,
+) {
+    if config.shutdown_grace_period == Duration::from_secs(0) {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    linux::watch(client, config, limits, provider, readiness, stops).await;
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (client, limits, provider, readiness, stops);
+        warn!(
+            "Graceful node shutdown is only supported on Linux, ignoring the shutdown grace period"
+        );
+    }
+}
+
+/// Report the node as not ready (or ready again, if a shutdown was cancelled) without
+/// waiting for the next heartbeat
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+async fn set_shutting_down<P: Provider + Sync>(
+    client: &kube::Client,
+    config: &Config,
+    limits: &RateLimits,
+    provider: &P,
+    readiness: &Readiness,
+    shutting_down: bool,
+) {
+    readiness.set_shutting_down(shutting_down);
+    // Nothing has been applied or refused as far as this update knows, so it always
+    // patches
+    update_node(
+        client,
+        config,
+        limits,
+        provider,
+        readiness,
+        &mut Node::default(),
+        &mut Refusals::default(),
+    )
+    .await;
+}
+
+/// How long the pods have to stop when the node shuts down
+#[derive(Clone, Copy, Debug)]
+struct ShutdownBudget {
+    /// The whole shutdown grace period
+    total: Duration,
+    /// The part of it kept for critical pods
+    critical: Duration,
+}
+
+impl ShutdownBudget {
+    fn new(config: &Config) -> Self {
+        ShutdownBudget {
+            total: config.shutdown_grace_period,
+            critical: config
+                .shutdown_grace_period_critical_pods
+                .min(config.shutdown_grace_period),
+        }
+    }
+
+    /// The time the other pods have to stop before critical pods are stopped
+    fn regular(&self) -> Duration {
+        self.total - self.critical
+    }
+}
+
+/// Asks the pod queue to stop a group of the node's pods because the node shuts down.
+/// See [`PodQueue::stop_for_shutdown`](crate::queue::PodQueue::stop_for_shutdown)
+pub(crate) struct StopRequest {
+    /// Whether the critical pods are stopped, or the other pods
+    pub(crate) critical: bool,
+    /// How long the pods have to stop
+    pub(crate) budget: Duration,
+    /// Receives a receiver for each pod being stopped, which completes once it stopped
+    pub(crate) stopping: oneshot::Sender<Vec<oneshot::Receiver<()>>>,
+}
+
+/// Tells the node shutdown that a pod it asked to stop has stopped. Clones of the pod
+/// share it, and the shutdown stops waiting for the pod once they are all dropped
+#[derive(Clone, Debug)]
+pub(crate) struct ShutdownStop(Arc<Mutex<Option<oneshot::Sender<()>>>>);
+
+impl ShutdownStop {
+    pub(crate) fn new() -> (Self, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+        (ShutdownStop(Arc::new(Mutex::new(Some(sender)))), receiver)
+    }
+
+    /// Report that the pod stopped
+    pub(crate) fn stopped(&self) {
+        if let Some(sender) = self.0.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+/// Stop all of the Kubelet's pods that have not finished, giving each its termination
+/// grace period but stopping no later than when the budget of its group runs out.
+/// Critical pods are stopped after the others, with the rest of the grace period if
+/// none of it is kept for them
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+async fn stop_pods(stops: &mut mpsc::Sender<StopRequest>, budget: ShutdownBudget) {
+    let deadline = std::time::Instant::now() + budget.total;
+    stop_group(stops, false, "regular", budget.regular()).await;
+    let critical = if budget.critical > Duration::from_secs(0) {
+        budget.critical
+    } else {
+        deadline.saturating_duration_since(std::time::Instant::now())
+    };
+    stop_group(stops, true, "critical", critical).await;
+}
+
+/// Stop a group of pods within the group's budget
+async fn stop_group(
+    stops: &mut mpsc::Sender<StopRequest>,
+    critical: bool,
+    group: &str,
+    budget: Duration,
+) {
+    let (stopping, receiver) = oneshot::channel();
+    let request = StopRequest {
+        critical,
+        budget,
+        stopping,
+    };
+    if stops.send(request).await.is_err() {
+        warn!(
+            "Unable to stop {} pods for node shutdown, the pod queue has stopped",
+            group
+        );
+        return;
+    }
+    let pods = match receiver.await {
+        Ok(pods) if !pods.is_empty() => pods,
+        _ => return,
+    };
+    info!(
+        "Stopping {} {} pods within {}s",
+        pods.len(),
+        group,
+        budget.as_secs()
+    );
+    if tokio::time::timeout(budget, futures::future::join_all(pods))
+        .await
+        .is_err()
+    {
+        warn!(
+            "Not all {} pods stopped within their shutdown grace period of {}s",
+            group,
+            budget.as_secs()
+        );
+    }
+}
+
+/// Whether the pod has finished running, so it needn't be stopped
+pub(crate) fn finished(pod: &Pod) -> bool {
+    let phase = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.phase.as_deref());
+    phase == Some("Succeeded") || phase == Some("Failed")
+}
+
+/// The definition of a pod that is stopped because the node shuts down, which is
+/// marked for deletion with its termination grace period cut down to the budget, as
+/// providers stop pods that are marked for deletion
+pub(crate) fn stopping(pod: &Pod, budget: Duration, stop: ShutdownStop) -> Pod {
+    let grace_period =
+        (pod.termination_grace_period_seconds().max(0) as u64).min(budget.as_secs()) as i64;
+    pod.map_definition(|pod| {
+        let metadata = pod.metadata.get_or_insert_with(Default::default);
+        metadata.deletion_timestamp = Some(Time(Utc::now()));
+        metadata.deletion_grace_period_seconds = Some(grace_period);
+    })
+    .with_shutdown(stop)
+}
+
+/// Stop a pod for the node's shutdown and report it as failed. Unlike pods that are
+/// deleted, the pod is left in the API so its controller can replace it
+pub(crate) async fn stop_pod<P: Provider + Sync>(
+    provider: &P,
+    client: &kube::Client,
+    pod: &ValidatedPod,
+) {
+    let grace_period = pod.deletion_grace_period_seconds().unwrap_or(0).max(0) as u64;
+    info!(
+        "Stopping pod {} in namespace {} for node shutdown with a grace period of {}s",
+        pod.name(),
+        pod.namespace(),
+        grace_period
+    );
+    // Without a grace period the provider is still told to stop the pod, as the pod is
+    // not deleted, but isn't waited for beyond the budget of its group
+    let stopped = if grace_period == 0 {
+        Ok(provider.modify(Pod::clone(pod)).await)
+    } else {
+        tokio::time::timeout(
+            Duration::from_secs(grace_period),
+            provider.modify(Pod::clone(pod)),
+        )
+        .await
+    };
+    match stopped {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Error while stopping pod {}: {}", pod.name(), e),
+        Err(_) => warn!(
+            "Pod {} did not stop within its grace period of {}s",
+            pod.name(),
+            grace_period
+        ),
+    }
+
+    let now = Utc::now();
+    let terminated = ContainerStatus::Terminated {
+        timestamp: now,
+        message: SHUTDOWN_MESSAGE.to_owned(),
+        exit_code: 1,
+    };
+    let patch = StatusPatch::new()
+        .phase(Phase::Failed)
+        .reason(SHUTDOWN_REASON)
+        .message(SHUTDOWN_MESSAGE)
+        .container_statuses(
+            pod.containers()
+                .iter()
+                .map(|c| terminated.to_kubernetes(c.name.clone())),
+        )
+        .condition(PodCondition {
+            type_: "Ready".to_owned(),
+            status: "False".to_owned(),
+            reason: Some("PodCompleted".to_owned()),
+            last_transition_time: Some(Time(now)),
+            ..Default::default()
+        });
+    if let Err(e) = patch.apply_for(client.clone(), pod).await {
+        warn!(
+            "Unable to report shutdown status for pod {}: {}",
+            pod.name(),
+            e
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use log::{debug, info, warn};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    use super::{set_shutting_down, stop_pods, ShutdownBudget, StopRequest};
+    use crate::config::Config;
+    use crate::logind::ShutdownInhibitor;
+    use crate::node::Readiness;
+    use crate::rate_limit::RateLimits;
+    use crate::Provider;
+
+    /// Why the Kubelet delays shutdowns, as shown by `systemd-inhibit --list`
+    const WHY: &str = "Kubelet needs time to stop pods";
+
+    pub(super) async fn watch<P: Provider + Sync + Send + 'static>(
+        client: kube::Client,
+        config: Config,
+        limits: RateLimits,
+        provider: Arc<P>,
+        readiness: Readiness,
+        mut stops: tokio::sync::mpsc::Sender<StopRequest>,
+    ) {
+        let budget = ShutdownBudget::new(&config);
+        let (events, mut shutdowns) = unbounded_channel();
+        let (stopped, wait_stopped) = mpsc::channel();
+        // The bus is read with blocking calls, so it gets a thread of its own
+        std::thread::spawn(move || {
+            if let Err(e) = inhibit(budget.total, events, wait_stopped) {
+                warn!(
+                    "Unable to delay node shutdown, pods will not be stopped gracefully: {}",
+                    e
+                );
+            }
+        });
+        while let Some(shutting_down) = shutdowns.recv().await {
+            if shutting_down {
+                info!(
+                    "Node is shutting down, stopping pods within {}s, the last {}s of which are kept for critical pods",
+                    budget.total.as_secs(),
+                    budget.critical.as_secs()
+                );
+                set_shutting_down(
+                    &client,
+                    &config,
+                    &limits,
+                    provider.as_ref(),
+                    &readiness,
+                    true,
+                )
+                .await;
+                stop_pods(&mut stops, budget).await;
+                info!("Pods stopped, letting node shutdown continue");
+                let _ = stopped.send(());
+            } else {
+                info!("Node shutdown was cancelled");
+                set_shutting_down(
+                    &client,
+                    &config,
+                    &limits,
+                    provider.as_ref(),
+                    &readiness,
+                    false,
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Hold the inhibitor lock, sending whether the host is shutting down whenever
+    /// logind announces a change. The lock is released once pods are stopped and taken
+    /// again if the shutdown is cancelled
+    fn inhibit(
+        budget: Duration,
+        events: UnboundedSender<bool>,
+        stopped: mpsc::Receiver<()>,
+    ) -> std::io::Result<()> {
+        let mut inhibitor = ShutdownInhibitor::connect()?;
+        match inhibitor.max_delay() {
+            Ok(max) if max < budget => warn!(
+                "The shutdown grace period of {}s is longer than logind allows (InhibitDelayMaxSec={}s), so pods may not have time to stop",
+                budget.as_secs(),
+                max.as_secs()
+            ),
+            Ok(_) => {}
+            Err(e) => debug!("Unable to read logind's InhibitDelayMaxSec: {}", e),
+        }
+        inhibitor.acquire(WHY)?;
+        info!("Delaying node shutdowns by up to {}s", budget.as_secs());
+        loop {
+            let shutting_down = inhibitor.wait()?;
+            if events.send(shutting_down).is_err() {
+                return Ok(());
+            }
+            if shutting_down {
+                let _ = stopped.recv();
+                inhibitor.release();
+            } else {
+                inhibitor.acquire(WHY)?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, FakeProvider, MockApiServer, Operation, QueueHarness};
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Stop the pods of the harness's queue as the Kubelet does on shutdown
+    async fn stop_pods_of(harness: &mut QueueHarness<FakeProvider>, budget: ShutdownBudget) {
+        let (mut stops, mut requests) = mpsc::channel(1);
+        let stopping = async move {
+            stop_pods(&mut stops, budget).await;
+        };
+        let serving = async {
+            while let Some(request) = requests.recv().await {
+                harness.stop_for_shutdown(request);
+            }
+        };
+        futures::future::join(stopping, serving).await;
+    }
+
+    async fn add_pods(
+        server: &MockApiServer,
+        harness: &mut QueueHarness<FakeProvider>,
+        provider: &FakeProvider,
+        pods: Vec<KubePod>,
+    ) {
+        let count = pods.len();
+        for pod in pods {
+            server.insert("/api/v1/namespaces/default/pods", &pod);
+            harness.add(pod).await.unwrap();
+        }
+        assert!(
+            provider
+                .wait_for_calls(Operation::Add, count, TIMEOUT)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stop_pods_for_shutdown() {
+        let server = MockApiServer::start().await.unwrap();
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+        let running = fake_pod("foo", "default");
+        let mut done = fake_pod("bar", "default");
+        done.status = Some(k8s_openapi::api::core::v1::PodStatus {
+            phase: Some("Succeeded".to_owned()),
+            ..Default::default()
+        });
+        add_pods(&server, &mut harness, &provider, vec![running, done]).await;
+
+        let budget = ShutdownBudget {
+            total: Duration::from_secs(5),
+            critical: Duration::from_secs(0),
+        };
+        stop_pods_of(&mut harness, budget).await;
+
+        // The pods are stopped by their workers, which pass them to the provider marked
+        // for deletion, rather than deleted
+        let stopped = provider.calls_for(Operation::Modify);
+        let stopped: Vec<&str> = stopped.iter().map(|c| c.pod_name.as_str()).collect();
+        assert_eq!(vec!["foo"], stopped);
+        assert!(provider.calls_for(Operation::Delete).is_empty());
+        let entry = harness.pods().get("default", "foo").unwrap();
+        assert_eq!(crate::state::State::Terminated, entry.state);
+
+        let pod = server.get("/api/v1/namespaces/default/pods/foo").unwrap();
+        assert_eq!("Failed", pod["status"]["phase"]);
+        assert_eq!(SHUTDOWN_REASON, pod["status"]["reason"]);
+        assert_eq!(SHUTDOWN_MESSAGE, pod["status"]["message"]);
+        assert!(pod["status"]["containerStatuses"][0]["state"]["terminated"].is_object());
+        let pod = server.get("/api/v1/namespaces/default/pods/bar").unwrap();
+        assert_eq!("Succeeded", pod["status"]["phase"]);
+
+        // A pod that was stopped is not started again if the shutdown is cancelled
+        harness.add(fake_pod("foo", "default")).await.unwrap();
+        stop_pods_of(&mut harness, budget).await;
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(2, provider.calls_for(Operation::Add).len());
+        assert_eq!(1, provider.calls_for(Operation::Modify).len());
+    }
+
+    #[tokio::test]
+    async fn test_critical_pods_are_stopped_last() {
+        let server = MockApiServer::start().await.unwrap();
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+        let pod = |name: &str, priority_class: Option<&str>, priority: Option<i32>| {
+            let mut pod = fake_pod(name, "default");
+            let spec = pod.spec.as_mut().unwrap();
+            spec.priority_class_name = priority_class.map(str::to_owned);
+            spec.priority = priority;
+            pod
+        };
+        let pods = vec![
+            pod("agent", Some("system-node-critical"), None),
+            pod("web", None, Some(100)),
+            pod("batch", None, None),
+        ];
+        add_pods(&server, &mut harness, &provider, pods).await;
+
+        let budget = ShutdownBudget {
+            total: Duration::from_secs(10),
+            critical: Duration::from_secs(4),
+        };
+        assert_eq!(Duration::from_secs(6), budget.regular());
+        stop_pods_of(&mut harness, budget).await;
+
+        let stopped = provider.calls_for(Operation::Modify);
+        let mut stopped: Vec<&str> = stopped.iter().map(|c| c.pod_name.as_str()).collect();
+        // The other pods are stopped at the same time, and critical pods once they are
+        assert_eq!(Some("agent"), stopped.pop());
+        stopped.sort_unstable();
+        assert_eq!(vec!["batch", "web"], stopped);
+    }
+
+    #[tokio::test]
+    async fn test_critical_pods_share_the_rest_without_a_budget() {
+        let server = MockApiServer::start().await.unwrap();
+        let provider = Arc::new(FakeProvider::new());
+        let mut harness = QueueHarness::with_client(provider.clone(), server.client());
+        let mut agent = fake_pod("agent", "default");
+        let spec = agent.spec.as_mut().unwrap();
+        spec.priority_class_name = Some("system-node-critical".to_owned());
+        spec.termination_grace_period_seconds = Some(30);
+        add_pods(&server, &mut harness, &provider, vec![agent]).await;
+        provider.delay(Operation::Modify, Duration::from_millis(200));
+
+        let budget = ShutdownBudget {
+            total: Duration::from_secs(5),
+            critical: Duration::from_secs(0),
+        };
+        let start = std::time::Instant::now();
+        stop_pods_of(&mut harness, budget).await;
+
+        // The shutdown waited for the critical pod to stop
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let stopped = provider.calls_for(Operation::Modify);
+        assert_eq!(1, stopped.len());
+        assert_eq!("agent", stopped[0].pod_name);
+        let pod = server.get("/api/v1/namespaces/default/pods/agent").unwrap();
+        assert_eq!(SHUTDOWN_REASON, pod["status"]["reason"]);
+    }
+}
@@ -0,0 +1,360 @@
+//! Ordering pod starts by priority
+//!
+//! Each pod has its own worker, so without any coordination every pod queued at
+//! once (such as when the Kubelet starts or pods are rescheduled in bulk) would be
+//! started at the same time in no particular order. The [`StartQueue`] limits how
+//! many pods are started at once and hands out the free slots to the waiting pod
+//! with the highest priority, oldest first, so critical pods are not stuck behind
+//! batch jobs.
+//!
+//! A start only holds its slot for a time slice while other pods are waiting. Starts
+//! that take longer, such as ones pulling a large image, are paused and queued again
+//! behind the pods of the same priority that have had fewer time slices, so pods take
+//! turns and a few slow pods can't hold up everything queued behind them. A paused
+//! start is resumed where it left off once it gets a slot again, and no more than the
+//! queue's number of starts ever run at once.
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use tokio::sync::oneshot;
+
+use crate::pod::Pod;
+
+/// Limits how many pods start at once, letting the most important ones go first
+#[derive(Clone)]
+pub(crate) struct StartQueue {
+    inner: Arc<Mutex<Inner>>,
+    time_slice: Duration,
+}
+
+struct Inner {
+    available: usize,
+    waiting: BinaryHeap<Waiter>,
+    /// The waiters that were handed a slot they have yet to take
+    granted: HashSet<u64>,
+    next_seq: u64,
+}
+
+/// Where a start stands in the queue
+#[derive(Clone)]
+struct Turn {
+    priority: i32,
+    /// Starts that have had fewer time slices go first
+    slices: Reverse<u32>,
+    created: Reverse<DateTime<Utc>>,
+}
+
+struct Waiter {
+    turn: Turn,
+    seq: Reverse<u64>,
+    wake: oneshot::Sender<()>,
+}
+
+impl Waiter {
+    fn key(&self) -> (i32, Reverse<u32>, &Reverse<DateTime<Utc>>, &Reverse<u64>) {
+        (
+            self.turn.priority,
+            self.turn.slices,
+            &self.turn.created,
+            &self.seq,
+        )
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// A start waiting for a slot. If it gives up waiting after the slot was handed to it,
+/// the slot goes to the next waiting start
+struct Waiting {
+    queue: StartQueue,
+    seq: u64,
+    /// Cleared once the wait is over
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            let mut inner = self.queue.inner.lock().unwrap();
+            // Once closed under the lock, the slot can't be handed to this start any more
+            receiver.close();
+            if inner.granted.remove(&self.seq) {
+                inner.release();
+            }
+        }
+    }
+}
+
+/// Allows a pod to start. The slot is given to the next waiting pod when this is dropped
+pub(crate) struct StartPermit {
+    queue: StartQueue,
+    turn: Turn,
+}
+
+impl StartQueue {
+    /// Create a queue that lets the given number of pods start at once, each holding its
+    /// slot for `time_slice` at a time while others are waiting
+    pub(crate) fn new(concurrency: usize, time_slice: Duration) -> Self {
+        StartQueue {
+            inner: Arc::new(Mutex::new(Inner {
+                available: concurrency.max(1),
+                waiting: BinaryHeap::new(),
+                granted: HashSet::new(),
+                next_seq: 0,
+            })),
+            time_slice,
+        }
+    }
+
+    /// Wait until the given pod may start
+    pub(crate) async fn start(&self, pod: &Pod) -> StartPermit {
+        let turn = Turn {
+            priority: pod.priority(),
+            slices: Reverse(0),
+            // Pods that haven't been given a creation time yet count as brand new
+            created: Reverse(pod.creation_timestamp().cloned().unwrap_or_else(Utc::now)),
+        };
+        self.wait_for_turn(turn).await
+    }
+
+    async fn wait_for_turn(&self, turn: Turn) -> StartPermit {
+        let mut waiting = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.available > 0 {
+                inner.available -= 1;
+                return StartPermit {
+                    queue: self.clone(),
+                    turn,
+                };
+            }
+            let (wake, receiver) = oneshot::channel();
+            let seq = inner.next_seq;
+            inner.next_seq += 1;
+            inner.waiting.push(Waiter {
+                turn: turn.clone(),
+                seq: Reverse(seq),
+                wake,
+            });
+            Waiting {
+                queue: self.clone(),
+                seq,
+                receiver: Some(receiver),
+            }
+        };
+        // The sender is only dropped without sending once the slot has been handed over
+        if let Some(receiver) = waiting.receiver.as_mut() {
+            let _ = receiver.await;
+        }
+        // Taking the slot and clearing the wait happen together, so a cancelled wait
+        // either took the slot or hands it on
+        let mut inner = self.inner.lock().unwrap();
+        inner.granted.remove(&waiting.seq);
+        waiting.receiver = None;
+        drop(inner);
+        StartPermit {
+            queue: self.clone(),
+            turn,
+        }
+    }
+
+    fn is_contended(&self) -> bool {
+        !self.inner.lock().unwrap().waiting.is_empty()
+    }
+}
+
+impl Inner {
+    fn release(&mut self) {
+        // Waiters that have given up no longer need the slot
+        while let Some(waiter) = self.waiting.pop() {
+            if waiter.wake.send(()).is_ok() {
+                self.granted.insert(waiter.seq.0);
+                return;
+            }
+        }
+        self.available += 1;
+    }
+}
+
+impl StartPermit {
+    /// Run the start of a pod. Whenever it runs for longer than the queue's time slice
+    /// while other starts are waiting, it is paused and queued again behind them
+    pub(crate) async fn run<F: Future>(self, start: F) -> F::Output {
+        tokio::pin!(start);
+        let mut permit = self;
+        loop {
+            tokio::select! {
+                output = &mut start => return output,
+                _ = tokio::time::delay_for(permit.queue.time_slice) => (),
+            }
+            if !permit.queue.is_contended() {
+                continue;
+            }
+            debug!(
+                "Pod start has run for {:?}, letting the next pod have a turn",
+                permit.queue.time_slice
+            );
+            let queue = permit.queue.clone();
+            let mut turn = permit.turn.clone();
+            turn.slices = Reverse(turn.slices.0.saturating_add(1));
+            drop(permit);
+            permit = queue.wait_for_turn(turn).await;
+        }
+    }
+}
+
+impl Drop for StartPermit {
+    fn drop(&mut self) {
+        self.queue.inner.lock().unwrap().release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::fake_pod;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::time::Duration;
+
+    fn pod(name: &str, priority: i32, age_secs: i64) -> Pod {
+        let mut pod = fake_pod(name, "default");
+        pod.spec.as_mut().unwrap().priority = Some(priority);
+        pod.metadata.as_mut().unwrap().creation_timestamp =
+            Some(Time(Utc::now() - chrono::Duration::seconds(age_secs)));
+        pod.into()
+    }
+
+    #[tokio::test]
+    async fn test_highest_priority_starts_first() {
+        let queue = StartQueue::new(1, Duration::from_secs(60));
+        let running = queue.start(&pod("running", 0, 0)).await;
+
+        let (sender, mut order) = tokio::sync::mpsc::unbounded_channel();
+        for pod in [
+            pod("batch", 0, 10),
+            pod("newer-critical", 1000, 1),
+            pod("critical", 1000, 5),
+        ] {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let _permit = queue.start(&pod).await;
+                sender.send(pod.name().to_owned()).unwrap();
+            });
+            // Make sure every pod is waiting before the slot is freed
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        drop(running);
+
+        let mut started = Vec::new();
+        for _ in 0..3 {
+            started.push(order.recv().await.unwrap());
+        }
+        assert_eq!(vec!["critical", "newer-critical", "batch"], started);
+    }
+
+    #[tokio::test]
+    async fn test_slow_starts_take_turns() {
+        let queue = StartQueue::new(1, Duration::from_millis(50));
+        let quick = queue.start(&pod("quick", 0, 0)).await;
+        let finished = quick.run(tokio::time::delay_for(Duration::from_millis(20)));
+        tokio::time::timeout(Duration::from_secs(1), finished)
+            .await
+            .unwrap();
+
+        let progress = Arc::new(AtomicUsize::new(0));
+        let slow = queue.start(&pod("slow", 1000, 0)).await;
+        let counter = progress.clone();
+        tokio::spawn(slow.run(async move {
+            loop {
+                counter.fetch_add(1, SeqCst);
+                tokio::time::delay_for(Duration::from_millis(5)).await;
+            }
+        }));
+        // Nobody is waiting, so the slow start keeps its slot past its time slice
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        let next = tokio::time::timeout(Duration::from_secs(1), queue.start(&pod("next", 0, 0)))
+            .await
+            .expect("the slow start should have let the next pod have a turn");
+
+        // The slow start is paused while the only slot is taken, and a pod that has yet
+        // to have a turn goes before it
+        let paused = progress.load(SeqCst);
+        let (sender, mut started) = tokio::sync::mpsc::unbounded_channel();
+        let third_queue = queue.clone();
+        let third_progress = progress.clone();
+        tokio::spawn(async move {
+            let _permit = third_queue.start(&pod("third", 1000, 0)).await;
+            sender.send(third_progress.load(SeqCst)).unwrap();
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        });
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert_eq!(paused, progress.load(SeqCst));
+        drop(next);
+        assert_eq!(paused, started.recv().await.unwrap());
+
+        // Once the others are done, the slow start carries on where it left off
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(progress.load(SeqCst) > paused);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_waiters_are_skipped() {
+        let queue = StartQueue::new(1, Duration::from_secs(60));
+        let running = queue.start(&pod("running", 0, 0)).await;
+        // Giving up on waiting is what happens when a worker is cancelled
+        let abandoned = pod("abandoned", 1000, 0);
+        let abandoned = queue.start(&abandoned);
+        assert!(tokio::time::timeout(Duration::from_millis(20), abandoned)
+            .await
+            .is_err());
+        drop(running);
+
+        tokio::time::timeout(Duration::from_secs(1), queue.start(&pod("next", 0, 0)))
+            .await
+            .expect("the slot should not be held by the abandoned waiter");
+    }
+
+    #[tokio::test]
+    async fn test_waiters_abandoned_after_waking_hand_on_the_slot() {
+        let queue = StartQueue::new(1, Duration::from_secs(60));
+        let running = queue.start(&pod("running", 0, 0)).await;
+        let abandoned = pod("abandoned", 1000, 0);
+        let mut abandoned = Box::pin(queue.start(&abandoned));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), &mut abandoned)
+                .await
+                .is_err()
+        );
+        // The slot is handed to the waiter, which is cancelled before it sees it
+        drop(running);
+        drop(abandoned);
+
+        tokio::time::timeout(Duration::from_secs(1), queue.start(&pod("next", 0, 0)))
+            .await
+            .expect("the abandoned waiter should have handed on the slot");
+    }
+}
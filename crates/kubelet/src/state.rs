@@ -0,0 +1,704 @@
+//! A state machine for the lifecycle of pods
+//!
+//! Rather than implementing all of [`Provider::add`](crate::Provider::add),
+//! [`Provider::modify`](crate::Provider::modify) and
+//! [`Provider::delete`](crate::Provider::delete) itself, a provider can implement a hook
+//! for each state a pod goes through with [`PodLifecycle`], and have a [`StateMachine`]
+//! drive its pods through them:
+//!
+//! ```text
+//! Registered → ImagePull → Starting → Running → Terminated
+//!                   ↖________↙
+//!                     retry
+//! ```
+//!
+//! A pod can move to `Terminated` from any state, and to `Error` from any state before
+//! it is running. The state machine reports the pod's status as it goes and retries
+//! failed starts with a backoff, so partial progress, retries and status reporting work
+//! the same way for every provider. A start is retried by handing the pod back to the
+//! Kubelet to add again once the backoff is over, so nothing waits for it meanwhile. A failed image pull moves the pod to `Error` straight
+//! away with a [`PodSyncError::ImagePull`], after which the Kubelet reports the pod as
+//! `ImagePullBackOff` and adds it again once the backoff is over. A start that fails with
+//! a [`PodSyncError::ContainerConfig`], such as for a missing secret, is backed off from
+//! the same way.
+//!
+//! The [`registered`](PodLifecycle::registered) and
+//! [`starting`](PodLifecycle::starting) hooks return a [`SyncResult`], so a pod that
+//! waits for something outside of the provider, such as an external resource, can be
+//! polled for with [`SyncResult::RequeueAfter`] rather than by holding up a worker, and
+//! a pod that can never start can fail straight away with [`SyncResult::Failed`].
+//!
+//! # Example
+//! ```rust
+//! use std::collections::HashMap;
+//!
+//! use kubelet::state::{PodLifecycle, StateMachine, SyncResult};
+//! use kubelet::Pod;
+//!
+//! struct Runtime {
+//!     states: StateMachine,
+//! }
+//!
+//! #[async_trait::async_trait]
+//! impl PodLifecycle for Runtime {
+//!     // The modules pulled for each container
+//!     type Pulled = HashMap<String, Vec<u8>>;
+//!
+//!     async fn image_pull(&self, pod: &Pod) -> anyhow::Result<Self::Pulled> {
+//!         // Fetch the module of each container ...
+//!         # Ok(HashMap::new())
+//!     }
+//!
+//!     async fn starting(&self, pod: &Pod, modules: Self::Pulled) -> anyhow::Result<SyncResult> {
+//!         // Run the modules ...
+//!         # Ok(SyncResult::Done)
+//!     }
+//!
+//!     async fn terminated(&self, pod: &Pod) -> anyhow::Result<()> {
+//!         // Stop the modules ...
+//!         # Ok(())
+//!     }
+//! }
+//!
+//! // The provider then hands its pods to the state machine
+//! impl Runtime {
+//!     async fn add(&self, pod: Pod) -> anyhow::Result<()> {
+//!         self.states.add(self, pod).await
+//!     }
+//! }
+//! ```
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+
+use crate::error::PodSyncError;
+use crate::handle::key_from_pod;
+use crate::pod::Pod;
+use crate::redact::redact;
+use crate::status::{ContainerStatus, Status};
+
+/// How many times starts are attempted by default
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// How long to wait before the first retry by default. The wait doubles with each retry
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The states a pod goes through on a provider
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// The pod has been handed to the provider
+    Registered,
+    /// The images of the pod's containers are being pulled
+    ImagePull,
+    /// The pod's containers are being started
+    Starting,
+    /// The pod's containers have started
+    Running,
+    /// The pod's containers have been stopped
+    Terminated,
+    /// The pod could not be started
+    Error,
+}
+
+impl State {
+    /// The message reported on containers that are waiting in this state
+    fn waiting_message(self) -> Option<&'static str> {
+        match self {
+            State::ImagePull => Some("Pulling image"),
+            State::Starting => Some("Starting container"),
+            _ => None,
+        }
+    }
+}
+
+/// What a hook of a [`PodLifecycle`] decided about a pod
+#[derive(Debug)]
+pub enum SyncResult {
+    /// The pod can move on to its next state
+    Done,
+    /// The pod is not ready to move on yet. The Kubelet adds it again after the given
+    /// time, starting over from [`State::Registered`], and nothing waits for the pod in
+    /// the meantime
+    RequeueAfter(Duration),
+    /// The pod can never start, so it fails without being retried
+    Failed(anyhow::Error),
+}
+
+/// The hooks a provider implements for each state of a pod's lifecycle.
+///
+/// Each hook is called when the pod enters its state. If a hook returns an error, the
+/// pod moves to the [`State::Error`] state and the error is reported on the pod, so
+/// providers can return a [`PodSyncError`](crate::error::PodSyncError) to give a more
+/// specific reason.
+#[async_trait]
+pub trait PodLifecycle: Sync {
+    /// What the image pull hands over to the start of the containers, such as the
+    /// pulled modules
+    type Pulled: Send;
+
+    /// Called when the pod is handed to the provider, for example to check that it can
+    /// run the pod. Errors are not retried. The default implementation does nothing
+    async fn registered(&self, _pod: &Pod) -> anyhow::Result<SyncResult> {
+        Ok(SyncResult::Done)
+    }
+
+    /// Pull the images of the pod's containers. Errors that are not a
+    /// [`PodSyncError`](crate::error::PodSyncError) are reported as a failure to pull
+    /// the pod's images
+    async fn image_pull(&self, pod: &Pod) -> anyhow::Result<Self::Pulled>;
+
+    /// Start the pod's containers from the pulled images. If this fails, the images
+    /// are pulled again before it is retried
+    async fn starting(&self, pod: &Pod, pulled: Self::Pulled) -> anyhow::Result<SyncResult>;
+
+    /// Called once the pod's containers have started. The default implementation does
+    /// nothing
+    async fn running(&self, _pod: &Pod) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Stop the pod's containers and release what they use.
+    ///
+    /// This is called once when the pod is marked for deletion, or when it is deleted
+    /// if it was not stopped before, whatever state the pod was in.
+    async fn terminated(&self, pod: &Pod) -> anyhow::Result<()>;
+
+    /// Called when the pod could not be started, before the error is reported, for
+    /// clean up of partial progress. The default implementation does nothing
+    async fn error(&self, _pod: &Pod, _error: &anyhow::Error) {}
+}
+
+/// Drives pods through the states of a [`PodLifecycle`] and keeps track of the state
+/// each pod is in.
+///
+/// The state machine can be cloned cheaply, and clones share the states of the pods.
+#[derive(Clone)]
+pub struct StateMachine {
+    client: kube::Client,
+    states: Arc<RwLock<HashMap<String, State>>>,
+    /// How many times each pod's start has failed so far
+    attempts: Arc<RwLock<HashMap<String, u32>>>,
+    max_attempts: u32,
+    backoff: Duration,
+}
+
+impl StateMachine {
+    /// Create a state machine that reports pod statuses with the given client
+    pub fn new(client: kube::Client) -> Self {
+        StateMachine {
+            client,
+            states: Default::default(),
+            attempts: Default::default(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+        }
+    }
+
+    /// Set how many times starts are attempted before the pod fails, and how long the
+    /// Kubelet waits before adding the pod again for the first retry. The wait doubles
+    /// with each retry
+    pub fn with_retries(mut self, max_attempts: u32, backoff: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    /// Returns the state of a pod, or `None` if the pod is not known
+    pub fn state(&self, pod: &Pod) -> Option<State> {
+        self.states.read().unwrap().get(&key_from_pod(pod)).copied()
+    }
+
+    /// Drive a new pod through its states until its containers are running or it fails.
+    /// A start that fails but can be retried returns a
+    /// [`PodSyncError::Requeue`](crate::error::PodSyncError::Requeue) for the backoff
+    pub async fn add<L: PodLifecycle>(&self, lifecycle: &L, pod: Pod) -> anyhow::Result<()> {
+        self.enter(&pod, State::Registered).await;
+        match lifecycle.registered(&pod).await {
+            Ok(SyncResult::Done) => (),
+            Ok(result) => return self.stop(lifecycle, &pod, result).await,
+            Err(e) => return self.fail(lifecycle, &pod, e).await,
+        }
+        self.enter(&pod, State::ImagePull).await;
+        let pulled = match lifecycle.image_pull(&pod).await {
+            Ok(pulled) => pulled,
+            // The Kubelet backs off from failed pulls, across restarts of the pod
+            Err(e) => return self.fail(lifecycle, &pod, image_pull_error(&pod, e)).await,
+        };
+        self.enter(&pod, State::Starting).await;
+        match lifecycle.starting(&pod, pulled).await {
+            Ok(SyncResult::Done) => (),
+            Ok(result) => return self.stop(lifecycle, &pod, result).await,
+            // Like image pulls, the Kubelet backs off from missing configuration until
+            // it appears
+            Err(e) if is_container_config_error(&e) => return self.fail(lifecycle, &pod, e).await,
+            Err(e) => return self.retry(lifecycle, &pod, e).await,
+        }
+        self.attempts.write().unwrap().remove(&key_from_pod(&pod));
+        self.enter(&pod, State::Running).await;
+        if let Err(e) = lifecycle.running(&pod).await {
+            return self.fail(lifecycle, &pod, e).await;
+        }
+        Ok(())
+    }
+
+    /// Stop a pod once it is marked for deletion. Other changes are ignored
+    pub async fn modify<L: PodLifecycle>(&self, lifecycle: &L, pod: Pod) -> anyhow::Result<()> {
+        if pod.deletion_timestamp().is_some() {
+            self.terminate(lifecycle, &pod).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stop a deleted pod if it was not stopped already, and forget about it
+    pub async fn delete<L: PodLifecycle>(&self, lifecycle: &L, pod: Pod) -> anyhow::Result<()> {
+        let result = self.terminate(lifecycle, &pod).await;
+        let key = key_from_pod(&pod);
+        self.states.write().unwrap().remove(&key);
+        self.attempts.write().unwrap().remove(&key);
+        result
+    }
+
+    async fn terminate<L: PodLifecycle>(&self, lifecycle: &L, pod: &Pod) -> anyhow::Result<()> {
+        let previous = self.state(pod);
+        if previous == Some(State::Terminated) {
+            return Ok(());
+        }
+        self.enter(pod, State::Terminated).await;
+        let result = lifecycle.terminated(pod).await;
+        if result.is_err() {
+            // The pod is put back in the state it was in, so stopping it is tried again
+            let mut states = self.states.write().unwrap();
+            match previous {
+                Some(state) => states.insert(key_from_pod(pod), state),
+                None => states.remove(&key_from_pod(pod)),
+            };
+        }
+        result
+    }
+
+    /// Hand a pod whose start failed back to the Kubelet to add again after a backoff,
+    /// cleaning up what it started first, or fail it once it is out of attempts
+    async fn retry<L: PodLifecycle>(
+        &self,
+        lifecycle: &L,
+        pod: &Pod,
+        error: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        let attempt = {
+            let mut attempts = self.attempts.write().unwrap();
+            let attempt = attempts.entry(key_from_pod(pod)).or_insert(0);
+            *attempt += 1;
+            *attempt
+        };
+        if attempt >= self.max_attempts {
+            return self.fail(lifecycle, pod, error).await;
+        }
+        let delay = self.backoff * 2u32.saturating_pow(attempt - 1);
+        warn!(
+            "Pod {} in namespace {} failed in state {:?}, retrying in {:?}: {}",
+            pod.name(),
+            pod.namespace(),
+            self.state(pod).unwrap_or(State::Registered),
+            delay,
+            redact(pod, &error.to_string())
+        );
+        lifecycle.error(pod, &error).await;
+        Err(PodSyncError::Requeue(delay).into())
+    }
+
+    /// Stop driving a pod whose hook decided it can't move on
+    async fn stop<L: PodLifecycle>(
+        &self,
+        lifecycle: &L,
+        pod: &Pod,
+        result: SyncResult,
+    ) -> anyhow::Result<()> {
+        match result {
+            SyncResult::Done => Ok(()),
+            SyncResult::RequeueAfter(after) => {
+                info!(
+                    "Pod {} in namespace {} is not ready in state {:?}, trying again in {:?}",
+                    pod.name(),
+                    pod.namespace(),
+                    self.state(pod).unwrap_or(State::Registered),
+                    after
+                );
+                Err(PodSyncError::Requeue(after).into())
+            }
+            SyncResult::Failed(e) => self.fail(lifecycle, pod, e).await,
+        }
+    }
+
+    async fn fail<L: PodLifecycle>(
+        &self,
+        lifecycle: &L,
+        pod: &Pod,
+        error: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        error!(
+            "Pod {} in namespace {} failed in state {:?}: {}",
+            pod.name(),
+            pod.namespace(),
+            self.state(pod).unwrap_or(State::Registered),
+            redact(pod, &error.to_string())
+        );
+        self.attempts.write().unwrap().remove(&key_from_pod(pod));
+        self.enter(pod, State::Error).await;
+        lifecycle.error(pod, &error).await;
+        Err(error)
+    }
+
+    /// Move a pod to a new state, reporting its containers as waiting in the states
+    /// before they start
+    async fn enter(&self, pod: &Pod, state: State) {
+        debug!(
+            "Pod {} in namespace {} entered state {:?}",
+            pod.name(),
+            pod.namespace(),
+            state
+        );
+        self.states
+            .write()
+            .unwrap()
+            .insert(key_from_pod(pod), state);
+        let message = match state.waiting_message() {
+            Some(m) => m,
+            None => return,
+        };
+        info!("{} for pod {}", message, pod.name());
+        let waiting = ContainerStatus::waiting(message);
+        let status = Status {
+            message: None,
+            container_statuses: pod
+                .containers()
+                .iter()
+                .map(|c| (c.name.clone(), waiting.clone()))
+                .collect(),
+        };
+        pod.patch_status(self.client.clone(), status).await;
+    }
+}
+
+fn is_container_config_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<PodSyncError>(),
+        Some(PodSyncError::ContainerConfig(_))
+    )
+}
+
+/// Make an error from an image pull an image pull failure, unless the provider already
+/// said what went wrong
+fn image_pull_error(pod: &Pod, error: anyhow::Error) -> anyhow::Error {
+    if error.is::<PodSyncError>() {
+        return error;
+    }
+    let images: Vec<&str> = pod
+        .containers()
+        .iter()
+        .filter_map(|c| c.image.as_deref())
+        .collect();
+    PodSyncError::ImagePull {
+        image: images.join(", "),
+        source: error,
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+    use chrono::Utc;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        hooks: Mutex<Vec<String>>,
+        pull_failures: Mutex<u32>,
+        start_failures: Mutex<u32>,
+        stop_failures: Mutex<u32>,
+        config_missing: Mutex<bool>,
+        start_result: Mutex<Option<SyncResult>>,
+    }
+
+    impl Recorder {
+        fn record(&self, hook: &str) {
+            self.hooks.lock().unwrap().push(hook.to_owned());
+        }
+
+        fn hooks(&self) -> Vec<String> {
+            self.hooks.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl PodLifecycle for Recorder {
+        type Pulled = &'static str;
+
+        async fn registered(&self, _pod: &Pod) -> anyhow::Result<SyncResult> {
+            self.record("registered");
+            Ok(SyncResult::Done)
+        }
+
+        async fn image_pull(&self, _pod: &Pod) -> anyhow::Result<Self::Pulled> {
+            self.record("image_pull");
+            let mut failures = self.pull_failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(anyhow::anyhow!("registry unavailable"));
+            }
+            Ok("module")
+        }
+
+        async fn starting(&self, _pod: &Pod, pulled: Self::Pulled) -> anyhow::Result<SyncResult> {
+            self.record(&format!("starting {}", pulled));
+            if let Some(result) = self.start_result.lock().unwrap().take() {
+                return Ok(result);
+            }
+            if *self.config_missing.lock().unwrap() {
+                return Err(PodSyncError::ContainerConfig(anyhow::anyhow!(
+                    "secret \"creds\" not found"
+                ))
+                .into());
+            }
+            let mut failures = self.start_failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(anyhow::anyhow!("out of memory"));
+            }
+            Ok(SyncResult::Done)
+        }
+
+        async fn running(&self, _pod: &Pod) -> anyhow::Result<()> {
+            self.record("running");
+            Ok(())
+        }
+
+        async fn terminated(&self, _pod: &Pod) -> anyhow::Result<()> {
+            self.record("terminated");
+            let mut failures = self.stop_failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(anyhow::anyhow!("not stopped"));
+            }
+            Ok(())
+        }
+
+        async fn error(&self, _pod: &Pod, error: &anyhow::Error) {
+            self.record(&format!("error {}", error));
+        }
+    }
+
+    async fn machine() -> (MockApiServer, StateMachine, Pod) {
+        let server = MockApiServer::start().await.unwrap();
+        let mut pod = fake_pod("foo", "default");
+        pod.status = Some(Default::default());
+        server.insert("/api/v1/namespaces/default/pods", &pod);
+        let machine = StateMachine::new(server.client()).with_retries(2, Duration::from_millis(10));
+        (server, machine, Pod::new(pod))
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let (server, machine, pod) = machine().await;
+        let recorder = Recorder::default();
+        *recorder.start_failures.lock().unwrap() = 1;
+
+        // The failed start is retried by the Kubelet adding the pod again after a backoff
+        let error = machine.add(&recorder, pod.clone()).await.unwrap_err();
+        assert!(
+            matches!(PodSyncError::from(error), PodSyncError::Requeue(d) if d == Duration::from_millis(10))
+        );
+        machine.add(&recorder, pod.clone()).await.unwrap();
+        assert_eq!(Some(State::Running), machine.state(&pod));
+        let status = server.get("/api/v1/namespaces/default/pods/foo").unwrap()["status"].clone();
+        assert_eq!("Pending", status["phase"]);
+        assert_eq!(
+            "Starting container",
+            status["containerStatuses"][0]["state"]["waiting"]["message"]
+        );
+
+        // Stopping a pod that is deleted after being marked for deletion only happens once
+        let mut terminating = pod.clone().into_kube_pod();
+        terminating.metadata.as_mut().unwrap().deletion_timestamp = Some(
+            k8s_openapi::apimachinery::pkg::apis::meta::v1::Time(Utc::now()),
+        );
+        machine
+            .modify(&recorder, Pod::new(terminating))
+            .await
+            .unwrap();
+        assert_eq!(Some(State::Terminated), machine.state(&pod));
+        machine.delete(&recorder, pod.clone()).await.unwrap();
+        assert_eq!(None, machine.state(&pod));
+
+        assert_eq!(
+            vec![
+                "registered",
+                "image_pull",
+                "starting module",
+                "error out of memory",
+                "registered",
+                "image_pull",
+                "starting module",
+                "running",
+                "terminated"
+            ],
+            recorder.hooks()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_exhausted() {
+        let (_server, machine, pod) = machine().await;
+        let recorder = Recorder::default();
+        *recorder.start_failures.lock().unwrap() = 2;
+
+        machine.add(&recorder, pod.clone()).await.unwrap_err();
+        let error = machine.add(&recorder, pod.clone()).await.unwrap_err();
+        assert_eq!("out of memory", error.to_string());
+        assert_eq!(Some(State::Error), machine.state(&pod));
+        assert_eq!(
+            vec![
+                "registered",
+                "image_pull",
+                "starting module",
+                "error out of memory",
+                "registered",
+                "image_pull",
+                "starting module",
+                "error out of memory"
+            ],
+            recorder.hooks()
+        );
+
+        // A pod that never started is still stopped when it is deleted, in case it made
+        // partial progress
+        machine.delete(&recorder, pod).await.unwrap();
+        assert_eq!(
+            Some("terminated"),
+            recorder.hooks().last().map(|h| h.as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_stop_is_retried() {
+        let (_server, machine, pod) = machine().await;
+        let recorder = Recorder::default();
+        *recorder.stop_failures.lock().unwrap() = 1;
+        machine.add(&recorder, pod.clone()).await.unwrap();
+
+        let mut terminating = pod.clone().into_kube_pod();
+        terminating.metadata.as_mut().unwrap().deletion_timestamp = Some(
+            k8s_openapi::apimachinery::pkg::apis::meta::v1::Time(Utc::now()),
+        );
+        let terminating = Pod::new(terminating);
+        machine
+            .modify(&recorder, terminating.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(Some(State::Running), machine.state(&pod));
+        machine.modify(&recorder, terminating).await.unwrap();
+        assert_eq!(Some(State::Terminated), machine.state(&pod));
+        assert_eq!(
+            2,
+            recorder
+                .hooks()
+                .iter()
+                .filter(|h| h.as_str() == "terminated")
+                .count()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_image_pull_failure() {
+        let (_server, machine, pod) = machine().await;
+        let recorder = Recorder::default();
+        *recorder.pull_failures.lock().unwrap() = 1;
+
+        // Pulls are not retried here, as the Kubelet backs off from them
+        let error = machine.add(&recorder, pod.clone()).await.unwrap_err();
+        assert_eq!(Some(State::Error), machine.state(&pod));
+        match PodSyncError::from(error) {
+            PodSyncError::ImagePull { image, .. } => assert_eq!("fake.registry.io/foo:v1", image),
+            other => panic!("expected an image pull error, got {:?}", other),
+        }
+        assert_eq!(
+            vec![
+                "registered",
+                "image_pull",
+                "error failed to pull image fake.registry.io/foo:v1: registry unavailable"
+            ],
+            recorder.hooks()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_container_config_failure() {
+        let (_server, machine, pod) = machine().await;
+        let recorder = Recorder::default();
+        *recorder.config_missing.lock().unwrap() = true;
+
+        // Missing configuration is not retried here, as the Kubelet backs off from it
+        let error = machine.add(&recorder, pod.clone()).await.unwrap_err();
+        assert_eq!(Some(State::Error), machine.state(&pod));
+        assert_eq!(
+            "CreateContainerConfigError",
+            PodSyncError::from(error).reason()
+        );
+        assert_eq!(
+            vec![
+                "registered",
+                "image_pull",
+                "starting module",
+                "error failed to create container configuration: secret \"creds\" not found"
+            ],
+            recorder.hooks()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requeue() {
+        let (_server, machine, pod) = machine().await;
+        let recorder = Recorder::default();
+        let after = Duration::from_secs(30);
+        *recorder.start_result.lock().unwrap() = Some(SyncResult::RequeueAfter(after));
+
+        // The pod is handed back to the Kubelet to add again later, without failing
+        let error = machine.add(&recorder, pod.clone()).await.unwrap_err();
+        assert!(matches!(PodSyncError::from(error), PodSyncError::Requeue(d) if d == after));
+        assert_eq!(Some(State::Starting), machine.state(&pod));
+        assert_eq!(
+            vec!["registered", "image_pull", "starting module"],
+            recorder.hooks()
+        );
+
+        // Adding it again starts over
+        machine.add(&recorder, pod.clone()).await.unwrap();
+        assert_eq!(Some(State::Running), machine.state(&pod));
+    }
+
+    #[tokio::test]
+    async fn test_permanent_failure() {
+        let (_server, machine, pod) = machine().await;
+        let recorder = Recorder::default();
+        *recorder.start_result.lock().unwrap() =
+            Some(SyncResult::Failed(anyhow::anyhow!("unsupported module")));
+
+        // Permanent failures are not retried
+        let error = machine.add(&recorder, pod.clone()).await.unwrap_err();
+        assert_eq!("unsupported module", error.to_string());
+        assert_eq!(Some(State::Error), machine.state(&pod));
+        assert_eq!(
+            vec![
+                "registered",
+                "image_pull",
+                "starting module",
+                "error unsupported module"
+            ],
+            recorder.hooks()
+        );
+    }
+}
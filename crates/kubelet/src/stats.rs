@@ -0,0 +1,813 @@
+//! Accounting of the resources used by pods
+//!
+//! Wasm runtimes do not run workloads in cgroups, so the Kubelet cannot measure what
+//! pods use itself. Instead providers report usage through [`Provider::pod_stats`],
+//! and the Kubelet compares it against what the pods requested and are limited to.
+//!
+//! The node's usage in the Summary API is the total usage of its pods. Where providers
+//! can't report it, the Kubelet measures its own process and the processes it started
+//! instead, which is where Wasm workloads run, so `kubectl top node` still works. This
+//! is only measured on Linux.
+//!
+//! The Kubelet measures the local storage pods use itself, which is the size of their
+//! `emptyDir` volumes and of the logs of their containers, and reports it as their
+//! `ephemeral-storage` usage. Pods that use more than they are limited to are evicted
+//! by the [`eviction`](crate::eviction) task.
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::ResourceRequirements;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use log::{debug, warn};
+
+use crate::handle::key_from_pod;
+use crate::pod::Pod;
+use crate::pod_dirs::PodDirs;
+use crate::provider::Provider;
+
+/// An amount of CPU, memory and local storage. Amounts that are not known or not set
+/// are `None`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Resources {
+    /// CPU in billionths of a core
+    pub cpu_nano_cores: Option<u64>,
+    /// Memory in bytes
+    pub memory_bytes: Option<u64>,
+    /// Local storage in bytes
+    pub ephemeral_storage_bytes: Option<u64>,
+}
+
+impl Resources {
+    /// Adds the amounts of `other` to these, keeping amounts that are only known on
+    /// one side
+    pub(crate) fn add(&mut self, other: Resources) {
+        let sum = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        self.cpu_nano_cores = sum(self.cpu_nano_cores, other.cpu_nano_cores);
+        self.memory_bytes = sum(self.memory_bytes, other.memory_bytes);
+        self.ephemeral_storage_bytes =
+            sum(self.ephemeral_storage_bytes, other.ephemeral_storage_bytes);
+    }
+
+    /// Parse the CPU, memory and local storage of a Kubernetes resource list
+    fn from_quantities(list: Option<&BTreeMap<String, Quantity>>) -> Resources {
+        let get = |name: &str| list.and_then(|l| l.get(name)).and_then(parse_quantity);
+        Resources {
+            cpu_nano_cores: get("cpu").map(|cores| (cores * 1e9).round() as u64),
+            memory_bytes: get("memory").map(|bytes| bytes.round() as u64),
+            ephemeral_storage_bytes: get("ephemeral-storage").map(|bytes| bytes.round() as u64),
+        }
+    }
+}
+
+/// The usage of a pod reported by its provider
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PodStats {
+    /// The usage of each of the pod's containers, keyed by container name
+    pub containers: HashMap<String, Resources>,
+}
+
+/// The local storage used by a pod, as measured by the Kubelet
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PodStorage {
+    /// The bytes used by each of the pod's `emptyDir` volumes, keyed by volume name
+    pub volumes: BTreeMap<String, u64>,
+    /// The bytes used by the logs of the pod's containers
+    pub logs_bytes: u64,
+}
+
+impl PodStorage {
+    /// The bytes the pod uses in total
+    pub fn total_bytes(&self) -> u64 {
+        self.volumes.values().sum::<u64>() + self.logs_bytes
+    }
+
+    /// Measure the storage the pod uses in the given pod directories
+    pub(crate) async fn measure(pod: &Pod, dirs: PodDirs) -> Self {
+        let volumes: Vec<String> = pod
+            .volumes()
+            .into_iter()
+            .flatten()
+            .filter(|v| v.empty_dir.is_some())
+            .map(|v| v.name.clone())
+            .collect();
+        let measure = move || PodStorage {
+            volumes: volumes
+                .into_iter()
+                .map(|name| {
+                    let size = dir_size(&dirs.volumes().join(&name));
+                    (name, size)
+                })
+                .collect(),
+            logs_bytes: dir_size(&dirs.logs()),
+        };
+        // Walking the directories blocks, and pods may have written many files
+        tokio::task::spawn_blocking(measure)
+            .await
+            .unwrap_or_default()
+    }
+}
+
+/// The total size of the files in a directory and the directories below it. Symbolic
+/// links are not followed, and files that disappear while they are counted are left
+/// out
+fn dir_size(dir: &Path) -> u64 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut size = 0;
+    for entry in entries.flatten() {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            size += dir_size(&entry.path());
+        } else if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    size
+}
+
+/// The usage of a pod compared to what it requested and is limited to
+#[derive(Clone, Debug)]
+pub struct PodUsage {
+    /// The pod that is being accounted for
+    pub pod: Pod,
+    /// When the usage was collected
+    pub timestamp: DateTime<Utc>,
+    /// The usage reported for each container
+    pub containers: HashMap<String, Resources>,
+    /// The local storage the pod uses, if it was measured
+    pub storage: Option<PodStorage>,
+    /// The total usage of the pod's containers, and the local storage of the pod
+    pub usage: Resources,
+    /// The total resources requested by the pod's containers
+    pub requests: Resources,
+    /// The total limits of the pod's containers
+    pub limits: Resources,
+}
+
+impl PodUsage {
+    fn new(pod: Pod, stats: PodStats, storage: Option<PodStorage>) -> Self {
+        let mut usage = Resources::default();
+        for container in stats.containers.values() {
+            usage.add(*container);
+        }
+        if let Some(storage) = &storage {
+            usage.ephemeral_storage_bytes = Some(storage.total_bytes());
+        }
+        let mut requests = Resources::default();
+        let mut limits = Resources::default();
+        for container in pod.containers() {
+            let resources = container.resources.as_ref();
+            let (request, limit) = requirements(resources);
+            requests.add(request);
+            limits.add(limit);
+        }
+        PodUsage {
+            pod,
+            timestamp: Utc::now(),
+            containers: stats.containers,
+            storage,
+            usage,
+            requests,
+            limits,
+        }
+    }
+
+    /// Whether the pod uses more CPU, memory or local storage than its limits allow
+    pub fn exceeds_limits(&self) -> bool {
+        let exceeds = |used: Option<u64>, limit: Option<u64>| match (used, limit) {
+            (Some(used), Some(limit)) => used > limit,
+            _ => false,
+        };
+        exceeds(self.usage.cpu_nano_cores, self.limits.cpu_nano_cores)
+            || exceeds(self.usage.memory_bytes, self.limits.memory_bytes)
+            || exceeds(
+                self.usage.ephemeral_storage_bytes,
+                self.limits.ephemeral_storage_bytes,
+            )
+    }
+}
+
+/// Returns the requests and limits of a container. As in Kubernetes, a container
+/// that only sets a limit requests the same amount
+pub(crate) fn requirements(resources: Option<&ResourceRequirements>) -> (Resources, Resources) {
+    let limits = Resources::from_quantities(resources.and_then(|r| r.limits.as_ref()));
+    let mut requests = Resources::from_quantities(resources.and_then(|r| r.requests.as_ref()));
+    requests.cpu_nano_cores = requests.cpu_nano_cores.or(limits.cpu_nano_cores);
+    requests.memory_bytes = requests.memory_bytes.or(limits.memory_bytes);
+    requests.ephemeral_storage_bytes = requests
+        .ephemeral_storage_bytes
+        .or(limits.ephemeral_storage_bytes);
+    (requests, limits)
+}
+
+/// Parses a Kubernetes quantity such as `250m`, `1.5` or `128Mi` into its value
+pub(crate) fn parse_quantity(quantity: &Quantity) -> Option<f64> {
+    let value = quantity.0.trim();
+    let split = value
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    // A decimal exponent such as `1e3`, which a bare `E` (exa) is not
+    let exponent = match suffix.chars().next() {
+        Some('e') | Some('E') if suffix.len() > 1 => suffix[1..].parse::<i32>().ok(),
+        _ => None,
+    };
+    if let Some(exponent) = exponent {
+        return number.parse::<f64>().ok().map(|n| n * 10f64.powi(exponent));
+    }
+    let multiplier = match suffix {
+        "" => 1.0,
+        "n" => 1e-9,
+        "u" => 1e-6,
+        "m" => 1e-3,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        "Ki" => 1024.0,
+        "Mi" => 1024f64.powi(2),
+        "Gi" => 1024f64.powi(3),
+        "Ti" => 1024f64.powi(4),
+        "Pi" => 1024f64.powi(5),
+        "Ei" => 1024f64.powi(6),
+        _ => return None,
+    };
+    number.parse::<f64>().ok().map(|n| n * multiplier)
+}
+
+/// Build the response of the Summary API (`/stats/summary`) from the usage of the node
+/// and of the pods on it
+pub(crate) fn summary(node_name: &str, node: Resources, pods: &[PodUsage]) -> serde_json::Value {
+    let pods: Vec<serde_json::Value> = pods
+        .iter()
+        .map(|p| {
+            let containers: Vec<serde_json::Value> = p
+                .containers
+                .iter()
+                .map(|(name, usage)| {
+                    let mut container = serde_json::json!({
+                        "name": name,
+                        "cpu": cpu_stats(p.timestamp, usage),
+                        "memory": memory_stats(p.timestamp, usage),
+                    });
+                    // The Summary API has no fields for images, so these are the
+                    // Kubelet's own
+                    if let Some(info) = p.pod.image_info(name) {
+                        let image = p.pod.container_image(name).unwrap_or_default();
+                        container["imageID"] = serde_json::json!(info.image_id(&image));
+                        container["imageSizeBytes"] = info.size.into();
+                    }
+                    container
+                })
+                .collect();
+            serde_json::json!({
+                "podRef": {
+                    "name": p.pod.name(),
+                    "namespace": p.pod.namespace(),
+                    "uid": p.pod.uid(),
+                },
+                "containers": containers,
+                "cpu": cpu_stats(p.timestamp, &p.usage),
+                "memory": memory_stats(p.timestamp, &p.usage),
+                "volume": volume_stats(p.timestamp, p.storage.as_ref()),
+                "ephemeral-storage": {
+                    "time": p.timestamp,
+                    "usedBytes": p.usage.ephemeral_storage_bytes,
+                },
+            })
+        })
+        .collect();
+    let now = Utc::now();
+    serde_json::json!({
+        "node": {
+            "nodeName": node_name,
+            "cpu": cpu_stats(now, &node),
+            "memory": memory_stats(now, &node),
+        },
+        "pods": pods,
+    })
+}
+
+fn cpu_stats(time: DateTime<Utc>, usage: &Resources) -> serde_json::Value {
+    serde_json::json!({ "time": time, "usageNanoCores": usage.cpu_nano_cores })
+}
+
+fn memory_stats(time: DateTime<Utc>, usage: &Resources) -> serde_json::Value {
+    // Wasm runtimes do not distinguish memory that could be reclaimed, so all of it
+    // counts towards the working set
+    serde_json::json!({
+        "time": time,
+        "usageBytes": usage.memory_bytes,
+        "workingSetBytes": usage.memory_bytes,
+    })
+}
+
+fn volume_stats(time: DateTime<Utc>, storage: Option<&PodStorage>) -> serde_json::Value {
+    let volumes: Vec<serde_json::Value> = match storage {
+        Some(storage) => storage
+            .volumes
+            .iter()
+            .map(
+                |(name, used)| serde_json::json!({ "name": name, "time": time, "usedBytes": used }),
+            )
+            .collect(),
+        None => Vec::new(),
+    };
+    serde_json::json!(volumes)
+}
+
+/// Tracks the pods running on the node so their usage can be collected from the
+/// provider
+#[derive(Clone, Default)]
+pub(crate) struct ResourceAccounting {
+    pods: Arc<RwLock<HashMap<String, Pod>>>,
+    /// The Kubelet's data directory, which the local storage of the pods is measured in.
+    /// Their storage is not measured without it
+    data_dir: Option<PathBuf>,
+    /// The last CPU time measured for the Kubelet's processes, which the next usage is
+    /// measured from
+    last_cpu: Arc<Mutex<Option<CpuSample>>>,
+}
+
+impl ResourceAccounting {
+    /// Account for pods whose data is kept in the given data directory
+    pub(crate) fn new(data_dir: PathBuf) -> Self {
+        ResourceAccounting {
+            data_dir: Some(data_dir),
+            ..Default::default()
+        }
+    }
+
+    /// Start accounting for a pod, or update the definition of one already accounted for
+    pub(crate) fn register_pod(&self, pod: &Pod) {
+        self.pods
+            .write()
+            .unwrap()
+            .insert(key_from_pod(pod), pod.clone());
+    }
+
+    /// Stop accounting for a pod
+    pub(crate) fn unregister_pod(&self, pod: &Pod) {
+        self.pods.write().unwrap().remove(&key_from_pod(pod));
+    }
+
+    /// The pods being accounted for
+    pub(crate) fn pods(&self) -> Vec<Pod> {
+        self.pods.read().unwrap().values().cloned().collect()
+    }
+
+    /// Measure the local storage a pod uses, or `None` if there is no data directory to
+    /// measure it in
+    pub(crate) async fn storage(&self, pod: &Pod) -> Option<PodStorage> {
+        let dirs = pod.data_dirs(self.data_dir.as_ref()?);
+        Some(PodStorage::measure(pod, dirs).await)
+    }
+
+    /// Collect the current usage of all pods from the provider, along with the local
+    /// storage they use.
+    ///
+    /// Pods the provider cannot report stats for are left out, and pods that use more
+    /// than their limits are logged.
+    pub(crate) async fn collect<P: Provider + Sync>(&self, provider: &P) -> Vec<PodUsage> {
+        let pods = self.pods();
+        let mut usage = Vec::with_capacity(pods.len());
+        for pod in pods {
+            match provider.pod_stats(&pod).await {
+                Ok(stats) => {
+                    let storage = self.storage(&pod).await;
+                    let pod_usage = PodUsage::new(pod, stats, storage);
+                    if pod_usage.exceeds_limits() {
+                        warn!(
+                            "Pod {} in namespace {} is using {:?}, which exceeds its limits of {:?}",
+                            pod_usage.pod.name(),
+                            pod_usage.pod.namespace(),
+                            pod_usage.usage,
+                            pod_usage.limits
+                        );
+                    }
+                    usage.push(pod_usage);
+                }
+                Err(e) => debug!(
+                    "Unable to get stats for pod {} in namespace {}: {}",
+                    pod.name(),
+                    pod.namespace(),
+                    e
+                ),
+            }
+        }
+        usage
+    }
+
+    /// Returns the usage of the node, which is the total usage of the given pods. Amounts
+    /// that no pod reported are measured from the Kubelet's processes instead
+    pub(crate) async fn node_usage(&self, pods: &[PodUsage]) -> Resources {
+        let mut node = Resources::default();
+        for pod in pods {
+            node.add(pod.usage);
+        }
+        if node.memory_bytes.is_none() {
+            node.memory_bytes = probe::processes().map(|p| p.memory_bytes);
+        }
+        if node.cpu_nano_cores.is_none() {
+            node.cpu_nano_cores = self.process_cpu().await;
+        }
+        node
+    }
+
+    /// Measures the CPU the Kubelet's processes used since it was last measured, or over
+    /// a short window if that was too recent
+    async fn process_cpu(&self) -> Option<u64> {
+        let last = self.last_cpu.lock().unwrap().take();
+        let start = match last {
+            Some(last) if last.at.elapsed() >= MIN_CPU_WINDOW => last,
+            _ => {
+                let start = CpuSample::now()?;
+                tokio::time::delay_for(MIN_CPU_WINDOW).await;
+                start
+            }
+        };
+        let end = CpuSample::now()?;
+        let usage = end.usage_since(&start);
+        *self.last_cpu.lock().unwrap() = Some(end);
+        usage
+    }
+}
+
+/// The shortest time CPU usage is measured over
+const MIN_CPU_WINDOW: Duration = Duration::from_millis(100);
+
+/// The CPU time the Kubelet's processes had used at some point
+#[derive(Clone, Copy, Debug)]
+struct CpuSample {
+    at: Instant,
+    cpu_time: Duration,
+}
+
+impl CpuSample {
+    fn now() -> Option<Self> {
+        probe::processes().map(|p| CpuSample {
+            at: Instant::now(),
+            cpu_time: p.cpu_time,
+        })
+    }
+
+    fn of_thread(thread: i64) -> Option<Self> {
+        probe::thread_cpu_time(thread).map(|cpu_time| CpuSample {
+            at: Instant::now(),
+            cpu_time,
+        })
+    }
+
+    /// The CPU used between an earlier sample and this one, in billionths of a core
+    fn usage_since(&self, earlier: &CpuSample) -> Option<u64> {
+        let elapsed = self.at.checked_duration_since(earlier.at)?.as_nanos();
+        if elapsed == 0 {
+            return None;
+        }
+        // Processes that exit take their CPU time with them, so the total can drop
+        let used = self
+            .cpu_time
+            .checked_sub(earlier.cpu_time)
+            .unwrap_or_default();
+        Some((used.as_nanos() * 1_000_000_000 / elapsed) as u64)
+    }
+}
+
+/// Measures the CPU used by a thread, for providers that run each container on a
+/// thread of their own and report it as the container's usage in
+/// [`pod_stats`](crate::Provider::pod_stats). Clones measure the same thread
+#[derive(Clone, Debug, Default)]
+pub struct ThreadCpu(Arc<Mutex<MeasuredThread>>);
+
+#[derive(Debug, Default)]
+struct MeasuredThread {
+    /// The thread being measured, if any
+    thread: Option<i64>,
+    last: Option<CpuSample>,
+}
+
+impl ThreadCpu {
+    /// Measure the calling thread while `f` runs on it. The thread is no longer
+    /// measured once `f` returns, as pooled threads go on to run other work
+    pub fn measure<T>(&self, f: impl FnOnce() -> T) -> T {
+        *self.0.lock().unwrap() = MeasuredThread {
+            thread: probe::current_thread(),
+            last: None,
+        };
+        let result = f();
+        *self.0.lock().unwrap() = MeasuredThread::default();
+        result
+    }
+
+    /// The CPU the thread used since this was last called, or over a short window if
+    /// that was too recent, in billionths of a core. Returns `None` if no thread is
+    /// measured or its CPU can't be measured on this host
+    pub async fn usage(&self) -> Option<u64> {
+        let (thread, last) = {
+            let measured = self.0.lock().unwrap();
+            (measured.thread?, measured.last)
+        };
+        let start = match last {
+            Some(last) if last.at.elapsed() >= MIN_CPU_WINDOW => last,
+            _ => {
+                let start = CpuSample::of_thread(thread)?;
+                tokio::time::delay_for(MIN_CPU_WINDOW).await;
+                start
+            }
+        };
+        let end = CpuSample::of_thread(thread)?;
+        let mut measured = self.0.lock().unwrap();
+        // The thread may have stopped being measured while this waited
+        if measured.thread != Some(thread) {
+            return None;
+        }
+        measured.last = Some(end);
+        end.usage_since(&start)
+    }
+}
+
+/// Measuring what processes use from the host
+mod probe {
+    use std::time::Duration;
+
+    /// What a tree of processes uses
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub(super) struct ProcessUsage {
+        /// The CPU time the processes have used
+        pub(super) cpu_time: Duration,
+        /// The memory the processes have resident
+        pub(super) memory_bytes: u64,
+    }
+
+    /// Returns what the Kubelet's process and its descendants use, or `None` if that
+    /// can't be measured on this host
+    #[cfg(target_os = "linux")]
+    pub(super) fn processes() -> Option<ProcessUsage> {
+        use std::collections::{HashMap, HashSet};
+
+        // Safe, as sysconf only reads configuration
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if ticks_per_second <= 0 || page_size <= 0 {
+            return None;
+        }
+        let mut stats = HashMap::new();
+        for entry in std::fs::read_dir("/proc").ok()? {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue,
+            };
+            // Processes may exit while they are being read
+            if let Some(stat) = std::fs::read_to_string(path.join("stat"))
+                .ok()
+                .and_then(|s| parse_stat(&s))
+            {
+                stats.insert(stat.pid, stat);
+            }
+        }
+        let mut tree: HashSet<u32> = std::iter::once(std::process::id()).collect();
+        // Add children until nothing changes, as the processes are in no useful order
+        loop {
+            let before = tree.len();
+            for stat in stats.values() {
+                if tree.contains(&stat.ppid) {
+                    tree.insert(stat.pid);
+                }
+            }
+            if tree.len() == before {
+                break;
+            }
+        }
+        let mut usage = ProcessUsage::default();
+        for stat in tree.iter().filter_map(|pid| stats.get(pid)) {
+            usage.cpu_time +=
+                Duration::from_nanos(stat.cpu_ticks * 1_000_000_000 / ticks_per_second as u64);
+            usage.memory_bytes += stat.rss_pages * page_size as u64;
+        }
+        Some(usage)
+    }
+
+    /// Returns what the Kubelet's process and its descendants use, or `None` if that
+    /// can't be measured on this host
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn processes() -> Option<ProcessUsage> {
+        None
+    }
+
+    /// Returns the ID of the calling thread, or `None` if threads can't be measured on
+    /// this host
+    #[cfg(target_os = "linux")]
+    pub(super) fn current_thread() -> Option<i64> {
+        // Safe, as gettid only returns the ID of the calling thread
+        Some(unsafe { libc::syscall(libc::SYS_gettid) })
+    }
+
+    /// Returns the ID of the calling thread, or `None` if threads can't be measured on
+    /// this host
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn current_thread() -> Option<i64> {
+        None
+    }
+
+    /// Returns the CPU time a thread of the Kubelet's process has used
+    #[cfg(target_os = "linux")]
+    pub(super) fn thread_cpu_time(thread: i64) -> Option<Duration> {
+        // Safe, as sysconf only reads configuration
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if ticks_per_second <= 0 {
+            return None;
+        }
+        let stat = std::fs::read_to_string(format!("/proc/self/task/{}/stat", thread)).ok()?;
+        let ticks = parse_stat(&stat)?.cpu_ticks;
+        Some(Duration::from_nanos(
+            ticks * 1_000_000_000 / ticks_per_second as u64,
+        ))
+    }
+
+    /// Returns the CPU time a thread of the Kubelet's process has used
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn thread_cpu_time(_thread: i64) -> Option<Duration> {
+        None
+    }
+
+    /// The fields of `/proc/<pid>/stat` that are needed to measure a process
+    #[derive(Debug, PartialEq)]
+    pub(super) struct ProcStat {
+        pub(super) pid: u32,
+        pub(super) ppid: u32,
+        /// User and system CPU time, in clock ticks
+        pub(super) cpu_ticks: u64,
+        pub(super) rss_pages: u64,
+    }
+
+    /// Parses `/proc/<pid>/stat`. The command name is in parentheses and may itself
+    /// contain spaces and parentheses, so the fields are counted from the last `)`
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(super) fn parse_stat(stat: &str) -> Option<ProcStat> {
+        let pid = stat.split(' ').next()?.parse().ok()?;
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+        let field = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+        Some(ProcStat {
+            pid,
+            ppid: field(1)? as u32,
+            cpu_ticks: field(11)? + field(12)?,
+            rss_pages: field(21)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::module_store::ImageInfo;
+    use crate::testing::fake_pod;
+
+    #[test]
+    fn test_parse_quantity() {
+        let parse = |q: &str| parse_quantity(&Quantity(q.to_owned()));
+        assert_eq!(Some(0.25), parse("250m"));
+        assert_eq!(Some(1.5), parse("1.5"));
+        assert_eq!(Some(128.0 * 1024.0 * 1024.0), parse("128Mi"));
+        assert_eq!(Some(2e9), parse("2G"));
+        assert_eq!(Some(1e3), parse("1e3"));
+        assert_eq!(Some(1e3), parse("1E3"));
+        assert_eq!(Some(1.5e-3), parse("1.5e-3"));
+        assert_eq!(Some(2e18), parse("2E"));
+        assert_eq!(None, parse("1e"));
+        assert_eq!(None, parse("12Zi"));
+        assert_eq!(None, parse("lots"));
+    }
+
+    #[tokio::test]
+    async fn test_usage_against_limits() {
+        let mut kube_pod = fake_pod("foo", "default");
+        let quantities = |cpu: &str, memory: &str| {
+            Some(
+                vec![
+                    ("cpu".to_owned(), Quantity(cpu.to_owned())),
+                    ("memory".to_owned(), Quantity(memory.to_owned())),
+                ]
+                .into_iter()
+                .collect(),
+            )
+        };
+        kube_pod.spec.as_mut().unwrap().containers[0].resources = Some(ResourceRequirements {
+            limits: quantities("500m", "64Mi"),
+            requests: None,
+        });
+        let pod = Pod::new(kube_pod);
+        let info = ImageInfo {
+            digest: Some("sha256:manifest".to_owned()),
+            size: 6,
+        };
+        pod.record_image("foo", "fake.registry.io/foo:v1", info);
+        let stats = |memory_bytes| PodStats {
+            containers: vec![(
+                "foo".to_owned(),
+                Resources {
+                    cpu_nano_cores: Some(100_000_000),
+                    memory_bytes: Some(memory_bytes),
+                    ephemeral_storage_bytes: None,
+                },
+            )]
+            .into_iter()
+            .collect(),
+        };
+
+        let usage = PodUsage::new(pod.clone(), stats(1024), None);
+        assert_eq!(Some(500_000_000), usage.limits.cpu_nano_cores);
+        // Requests default to the limits
+        assert_eq!(usage.limits, usage.requests);
+        assert_eq!(Some(1024), usage.usage.memory_bytes);
+        assert!(!usage.exceeds_limits());
+
+        let usage = PodUsage::new(pod, stats(65 * 1024 * 1024), None);
+        assert!(usage.exceeds_limits());
+
+        let pods = [usage.clone(), usage];
+        let node = ResourceAccounting::default().node_usage(&pods).await;
+        let summary = summary("krustlet", node, &pods);
+        assert_eq!("krustlet", summary["node"]["nodeName"]);
+        assert_eq!(200_000_000, summary["node"]["cpu"]["usageNanoCores"]);
+        let pod = &summary["pods"][0];
+        assert_eq!("foo", pod["podRef"]["name"]);
+        assert_eq!("foo", pod["containers"][0]["name"]);
+        assert_eq!(
+            65 * 1024 * 1024,
+            pod["containers"][0]["memory"]["workingSetBytes"]
+        );
+        assert_eq!(
+            "fake.registry.io/foo@sha256:manifest",
+            pod["containers"][0]["imageID"]
+        );
+        assert_eq!(6, pod["containers"][0]["imageSizeBytes"]);
+    }
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (my (odd) name) S 1 4242 4242 0 -1 4194560 1234 0 0 0 150 50 0 0 20 0 \
+                    12 0 98765 123456789 2048 18446744073709551615";
+        assert_eq!(
+            Some(probe::ProcStat {
+                pid: 4242,
+                ppid: 1,
+                cpu_ticks: 200,
+                rss_pages: 2048,
+            }),
+            probe::parse_stat(stat)
+        );
+        assert_eq!(None, probe::parse_stat("4242 (truncated) S 1"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_node_usage_falls_back_to_processes() {
+        // Without any pod usage, the node reports what the Kubelet's processes use
+        let accounting = ResourceAccounting::default();
+        let node = accounting.node_usage(&[]).await;
+        assert!(node.memory_bytes.unwrap() > 0);
+        assert!(node.cpu_nano_cores.is_some());
+        let summary = summary("krustlet", node, &[]);
+        assert!(
+            summary["node"]["memory"]["workingSetBytes"]
+                .as_u64()
+                .unwrap()
+                > 0
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test(threaded_scheduler)]
+    async fn test_thread_cpu() {
+        let cpu = ThreadCpu::default();
+        assert_eq!(None, cpu.usage().await);
+
+        let measured = cpu.clone();
+        let (started, running) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            measured.measure(|| {
+                started.send(()).unwrap();
+                let until = Instant::now() + Duration::from_millis(500);
+                while Instant::now() < until {}
+            })
+        });
+        running.recv().unwrap();
+        assert!(cpu.usage().await.is_some());
+        thread.join().unwrap();
+        // A thread that is done is no longer measured
+        assert_eq!(None, cpu.usage().await);
+    }
+}
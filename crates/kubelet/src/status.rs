@@ -1,13 +1,52 @@
-//! Container statuses
+//! Pod and container statuses
+//!
+//! Providers report the state of their containers with a [`Status`], which the Kubelet
+//! turns into a pod status. For anything else, a [`StatusPatch`] builds a pod status
+//! out of Kubernetes types, so no status has to be written as raw JSON:
+//!
+//! ```rust,no_run
+//! use kubelet::status::{ContainerState, Phase, StatusPatch};
+//!
+//! # async {
+//! # let client: kube::Client = todo!();
+//! let exited = ContainerState::Terminated {
+//!     exit_code: 0,
+//!     signal: None,
+//!     reason: Some("Completed".to_owned()),
+//!     message: None,
+//!     started_at: None,
+//!     finished_at: Some(chrono::Utc::now()),
+//! };
+//! StatusPatch::new()
+//!     .phase(Phase::Succeeded)
+//!     .message("All done")
+//!     .container_state("greet", exited)
+//!     .apply(client, "default", "hello")
+//!     .await?;
+//! # Ok::<(), kubelet::error::KubeletError>(())
+//! # };
+//! ```
+use crate::error::KubeletError;
+use crate::lifecycle::Lifecycle;
+use crate::pod::Pod;
+use crate::rate_limit::RateLimits;
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::{
-    ContainerState, ContainerStateRunning, ContainerStateTerminated, ContainerStateWaiting,
-    ContainerStatus as KubeContainerStatus, Pod as KubePod,
+    ContainerState as KubeContainerState, ContainerStateRunning, ContainerStateTerminated,
+    ContainerStateWaiting, ContainerStatus as KubeContainerStatus, Pod as KubePod, PodCondition,
+    PodStatus as KubePodStatus,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
-use kube::{api::PatchParams, Api};
+use kube::{
+    api::{PatchParams, PatchStrategy},
+    error::ErrorResponse,
+    Api,
+};
 
 use std::collections::HashMap;
+use std::fmt;
+
+pub mod annotations;
 
 /// Describe the status of a workload.
 #[derive(Clone, Debug, Default)]
@@ -26,10 +65,14 @@ pub struct Status {
 /// to a Kubernetes API container status
 #[derive(Clone, Debug)]
 pub enum ContainerStatus {
-    /// The container is in a waiting state
+    /// The container is in a waiting state. Create it with [`ContainerStatus::waiting`]
+    /// outside of the kubelet, as more may be reported about waiting containers
+    #[non_exhaustive]
     Waiting {
         /// The timestamp of when this status was reported
         timestamp: DateTime<Utc>,
+        /// A brief CamelCase reason for why it is waiting, such as `ImagePullBackOff`
+        reason: Option<String>,
         /// A human readable string describing the why it is in a waiting status
         message: String,
     },
@@ -44,55 +87,194 @@ pub enum ContainerStatus {
         timestamp: DateTime<Utc>,
         /// A human readable string describing the why it is in a terminating status
         message: String,
-        /// Should be set to true if the process exited with an error
-        failed: bool,
+        /// The exit code of the process. Anything other than 0 means the process
+        /// exited with an error
+        exit_code: i32,
     },
 }
 
 impl ContainerStatus {
+    /// A container that is waiting, as of now, for the reason the message describes
+    pub fn waiting(message: impl Into<String>) -> Self {
+        Self::Waiting {
+            timestamp: Utc::now(),
+            reason: None,
+            message: message.into(),
+        }
+    }
+
     /// Convert the container status to a Kubernetes API compatible type
     pub fn to_kubernetes(&self, container_name: String) -> KubeContainerStatus {
-        let mut state = ContainerState::default();
+        container_status(container_name, ContainerState::from(self))
+    }
+}
+
+/// Returns the Kubernetes status of a container in the given state
+fn container_status(container_name: String, state: ContainerState) -> KubeContainerStatus {
+    let ready = state.is_running();
+    KubeContainerStatus {
+        state: Some(state.into()),
+        name: container_name,
+        // Right now we don't have a way to probe, so just set to ready if
+        // in a running state
+        ready,
+        // This is always true if startupProbe is not defined. When we
+        // handle probes, this should be updated accordingly
+        started: Some(true),
+        // The rest of the items in status (see docs here:
+        // https://kubernetes.io/docs/reference/generated/kubernetes-api/v1.17/#containerstatus-v1-core)
+        // either don't matter for us or we have not implemented the
+        // functionality yet
+        ..Default::default()
+    }
+}
+
+/// The state of a container, as Kubernetes describes it. Unlike a [`ContainerStatus`],
+/// this carries everything Kubernetes reports about each state
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContainerState {
+    /// The container is not running yet
+    Waiting {
+        /// A brief CamelCase reason for why it is waiting, such as `ImagePullBackOff`
+        reason: Option<String>,
+        /// A human readable description of why it is waiting
+        message: Option<String>,
+    },
+    /// The container is running
+    Running {
+        /// When the container started
+        started_at: Option<DateTime<Utc>>,
+    },
+    /// The container has exited
+    Terminated {
+        /// The exit code of the container
+        exit_code: i32,
+        /// The signal that stopped the container, if any
+        signal: Option<i32>,
+        /// A brief CamelCase reason for why it exited, such as `Completed`
+        reason: Option<String>,
+        /// A human readable description of why it exited
+        message: Option<String>,
+        /// When the container started
+        started_at: Option<DateTime<Utc>>,
+        /// When the container exited
+        finished_at: Option<DateTime<Utc>>,
+    },
+}
+
+impl ContainerState {
+    /// Whether the container is running
+    pub fn is_running(&self) -> bool {
+        matches!(self, Self::Running { .. })
+    }
+
+    /// Whether the container is waiting to run
+    pub fn is_waiting(&self) -> bool {
+        matches!(self, Self::Waiting { .. })
+    }
+
+    /// The exit code of the container, if it has exited
+    pub fn exit_code(&self) -> Option<i32> {
         match self {
-            Self::Waiting { message, .. } => {
-                state.waiting.replace(ContainerStateWaiting {
-                    message: Some(message.clone()),
-                    ..Default::default()
-                });
-            }
-            Self::Running { timestamp } => {
-                state.running.replace(ContainerStateRunning {
-                    started_at: Some(Time(*timestamp)),
-                });
-            }
-            Self::Terminated {
+            Self::Terminated { exit_code, .. } => Some(*exit_code),
+            _ => None,
+        }
+    }
+}
+
+impl From<&ContainerStatus> for ContainerState {
+    fn from(status: &ContainerStatus) -> Self {
+        match status {
+            ContainerStatus::Waiting {
+                reason, message, ..
+            } => Self::Waiting {
+                reason: reason.clone(),
+                message: Some(message.clone()),
+            },
+            ContainerStatus::Running { timestamp } => Self::Running {
+                started_at: Some(*timestamp),
+            },
+            ContainerStatus::Terminated {
                 timestamp,
                 message,
-                failed,
+                exit_code,
             } => {
-                state.terminated.replace(ContainerStateTerminated {
-                    finished_at: Some(Time(*timestamp)),
+                let reason = if *exit_code == 0 {
+                    "Completed"
+                } else {
+                    "Error"
+                };
+                Self::Terminated {
+                    exit_code: *exit_code,
+                    signal: None,
+                    reason: Some(reason.to_owned()),
                     message: Some(message.clone()),
-                    exit_code: *failed as i32,
-                    ..Default::default()
-                });
+                    started_at: None,
+                    finished_at: Some(*timestamp),
+                }
             }
-        };
-        let ready = state.running.is_some();
-        KubeContainerStatus {
-            state: Some(state),
-            name: container_name,
-            // Right now we don't have a way to probe, so just set to ready if
-            // in a running state
-            ready,
-            // This is always true if startupProbe is not defined. When we
-            // handle probes, this should be updated accordingly
-            started: Some(true),
-            // The rest of the items in status (see docs here:
-            // https://kubernetes.io/docs/reference/generated/kubernetes-api/v1.17/#containerstatus-v1-core)
-            // either don't matter for us or we have not implemented the
-            // functionality yet
-            ..Default::default()
+        }
+    }
+}
+
+impl From<ContainerState> for KubeContainerState {
+    fn from(state: ContainerState) -> Self {
+        match state {
+            ContainerState::Waiting { reason, message } => KubeContainerState {
+                waiting: Some(ContainerStateWaiting { reason, message }),
+                ..Default::default()
+            },
+            ContainerState::Running { started_at } => KubeContainerState {
+                running: Some(ContainerStateRunning {
+                    started_at: started_at.map(Time),
+                }),
+                ..Default::default()
+            },
+            ContainerState::Terminated {
+                exit_code,
+                signal,
+                reason,
+                message,
+                started_at,
+                finished_at,
+            } => KubeContainerState {
+                terminated: Some(ContainerStateTerminated {
+                    exit_code,
+                    signal,
+                    reason,
+                    message,
+                    started_at: started_at.map(Time),
+                    finished_at: finished_at.map(Time),
+                    container_id: None,
+                }),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl From<KubeContainerState> for ContainerState {
+    /// A state with none of its fields set is waiting, as Kubernetes defines it
+    fn from(state: KubeContainerState) -> Self {
+        if let Some(terminated) = state.terminated {
+            return Self::Terminated {
+                exit_code: terminated.exit_code,
+                signal: terminated.signal,
+                reason: terminated.reason,
+                message: terminated.message,
+                started_at: terminated.started_at.map(|t| t.0),
+                finished_at: terminated.finished_at.map(|t| t.0),
+            };
+        }
+        if let Some(running) = state.running {
+            return Self::Running {
+                started_at: running.started_at.map(|t| t.0),
+            };
+        }
+        let waiting = state.waiting.unwrap_or_default();
+        Self::Waiting {
+            reason: waiting.reason,
+            message: waiting.message,
         }
     }
 }
@@ -100,8 +282,10 @@ impl ContainerStatus {
 /// Describe the lifecycle phase of a workload.
 ///
 /// This is specified by Kubernetes itself.
-#[derive(Clone, Debug, serde::Serialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum Phase {
+    /// The workload has been accepted, but none of its containers have started.
+    Pending,
     /// The workload is currently executing.
     Running,
     /// The workload has exited with an error.
@@ -109,30 +293,479 @@ pub enum Phase {
     /// The workload has exited without error.
     Succeeded,
     /// The lifecycle phase of the workload cannot be determined.
+    #[default]
     Unknown,
 }
 
-impl Default for Phase {
-    fn default() -> Self {
-        Self::Unknown
+impl Phase {
+    /// The name Kubernetes gives the phase
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "Pending",
+            Self::Running => "Running",
+            Self::Failed => "Failed",
+            Self::Succeeded => "Succeeded",
+            Self::Unknown => "Unknown",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<&str> for Phase {
+    /// Phases Kubernetes doesn't define are unknown
+    fn from(phase: &str) -> Self {
+        match phase {
+            "Pending" => Self::Pending,
+            "Running" => Self::Running,
+            "Failed" => Self::Failed,
+            "Succeeded" => Self::Succeeded,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Builds a patch of a pod's status out of Kubernetes types.
+///
+/// [`StatusPatch::apply`] merges the patch into the pod's current status, so the status
+/// fields the patch doesn't set are kept.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct StatusPatch {
+    status: KubePodStatus,
+    /// The types of the conditions removed from the current status
+    #[serde(skip)]
+    removed_conditions: Vec<String>,
+}
+
+impl StatusPatch {
+    /// Create a patch that sets nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the phase of the pod
+    pub fn phase(mut self, phase: Phase) -> Self {
+        self.status.phase = Some(phase.as_str().to_owned());
+        self
+    }
+
+    /// Set a brief CamelCase reason for the pod being in its phase, such as `Evicted`
+    pub fn reason(mut self, reason: impl Into<String>) -> Self {
+        self.status.reason = Some(reason.into());
+        self
+    }
+
+    /// Set a human readable message about the pod being in its phase
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.status.message = Some(message.into());
+        self
+    }
+
+    /// Add the status of a container in the given state
+    pub fn container_state(self, container_name: &str, state: ContainerState) -> Self {
+        self.container_status(container_status(container_name.to_owned(), state))
+    }
+
+    /// Add the Kubernetes status of a container
+    pub fn container_status(mut self, status: KubeContainerStatus) -> Self {
+        self.status
+            .container_statuses
+            .get_or_insert_with(Vec::new)
+            .push(status);
+        self
+    }
+
+    /// Add the Kubernetes statuses of containers
+    pub fn container_statuses(
+        mut self,
+        statuses: impl IntoIterator<Item = KubeContainerStatus>,
+    ) -> Self {
+        self.status
+            .container_statuses
+            .get_or_insert_with(Vec::new)
+            .extend(statuses);
+        self
+    }
+
+    /// Add a condition of the pod
+    pub fn condition(self, condition: PodCondition) -> Self {
+        self.conditions(Some(condition))
+    }
+
+    /// Add conditions of the pod
+    pub fn conditions(mut self, conditions: impl IntoIterator<Item = PodCondition>) -> Self {
+        self.status
+            .conditions
+            .get_or_insert_with(Vec::new)
+            .extend(conditions);
+        self
+    }
+
+    /// Remove the pod's condition of the given type
+    pub(crate) fn without_condition(mut self, type_: &str) -> Self {
+        self.removed_conditions.push(type_.to_owned());
+        self
+    }
+
+    /// Apply the patch to the status of the given pod.
+    ///
+    /// The patch is merged into the current status of the pod, which is then applied as
+    /// a whole: conditions and container statuses replace those of the same type or
+    /// name, and the reason and message are replaced along with the phase. If the pod
+    /// changes in between, its status is read again and the patch merged again.
+    ///
+    /// The requests are not rate limited. [`Pod::patch_status`](crate::Pod::patch_status)
+    /// makes them under the Kubelet's rate limits.
+    pub async fn apply(
+        &self,
+        client: kube::Client,
+        ns: &str,
+        pod_name: &str,
+    ) -> Result<(), KubeletError> {
+        self.apply_with(client, ns, pod_name, &RateLimits::default(), None)
+            .await
+    }
+
+    /// Apply the patch like [`StatusPatch::apply`] to a pod the Kubelet handed out,
+    /// making the requests under its rate limits and sending the containers that
+    /// started and whether it failed to its lifecycle
+    pub(crate) async fn apply_for(
+        &self,
+        client: kube::Client,
+        pod: &Pod,
+    ) -> Result<(), KubeletError> {
+        self.apply_with(
+            client,
+            pod.namespace(),
+            pod.name(),
+            pod.rate_limits(),
+            Some(pod.lifecycle()),
+        )
+        .await
+    }
+
+    async fn apply_with(
+        &self,
+        client: kube::Client,
+        ns: &str,
+        pod_name: &str,
+        limits: &RateLimits,
+        lifecycle: Option<&Lifecycle>,
+    ) -> Result<(), KubeletError> {
+        let pod_client: Api<KubePod> = Api::namespaced(client.clone(), ns);
+        let mut retries = 0;
+        loop {
+            let current = limits
+                .limited(pod_client.get(pod_name))
+                .await
+                .map_err(|source| KubeletError::StatusPatch {
+                    pod_name: pod_name.to_owned(),
+                    source,
+                })?;
+            let version = current.metadata.and_then(|m| m.resource_version);
+            let merged = StatusPatch::from(self.merged(current.status.unwrap_or_default()));
+            match apply_pod_status(client.clone(), ns, pod_name, &merged, version, limits).await {
+                Err(KubeletError::StatusPatch {
+                    source: kube::Error::Api(ErrorResponse { code: 409, .. }),
+                    ..
+                }) if retries < CONFLICT_RETRIES => retries += 1,
+                Ok(()) => {
+                    if let Some(lifecycle) = lifecycle {
+                        let status = serde_json::to_value(&merged.status).unwrap_or_default();
+                        lifecycle.status_applied(ns, pod_name, &status);
+                    }
+                    return Ok(());
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Merge the patch into the given status
+    fn merged(&self, mut status: KubePodStatus) -> KubePodStatus {
+        let patch = self.status.clone();
+        // The reason and message describe the phase, so they don't outlive it
+        if patch.phase.is_some() {
+            status.phase = patch.phase;
+            status.reason = patch.reason;
+            status.message = patch.message;
+        } else {
+            status.reason = patch.reason.or(status.reason);
+            status.message = patch.message.or(status.message);
+        }
+        if let Some(patched) = patch.container_statuses {
+            let statuses = status.container_statuses.get_or_insert_with(Vec::new);
+            for container in patched {
+                match statuses.iter_mut().find(|s| s.name == container.name) {
+                    Some(existing) => *existing = container,
+                    None => statuses.push(container),
+                }
+            }
+        }
+        if let Some(conditions) = status.conditions.as_mut() {
+            conditions.retain(|c| !self.removed_conditions.contains(&c.type_));
+        }
+        if let Some(patched) = patch.conditions {
+            let conditions = status.conditions.get_or_insert_with(Vec::new);
+            for condition in patched {
+                match conditions.iter_mut().find(|c| c.type_ == condition.type_) {
+                    Some(existing) => *existing = condition,
+                    None => conditions.push(condition),
+                }
+            }
+        }
+        status
+    }
+}
+
+impl From<KubePodStatus> for StatusPatch {
+    fn from(status: KubePodStatus) -> Self {
+        StatusPatch {
+            status,
+            removed_conditions: Vec::new(),
+        }
+    }
+}
+
+impl From<StatusPatch> for KubePodStatus {
+    fn from(patch: StatusPatch) -> Self {
+        patch.status
+    }
+}
+
+/// The field manager the Kubelet uses for server-side apply.
+///
+/// Fields the Kubelet applies are owned by this manager, so other controllers and
+/// tools can manage their own fields (such as extra node labels) on the same objects
+/// without their changes being overwritten.
+pub const FIELD_MANAGER: &str = "krustlet";
+
+/// How often a status patch is merged again when the pod changed while it was merged
+const CONFLICT_RETRIES: usize = 5;
+
+/// Returns the parameters for a server-side apply as the Kubelet's field manager.
+///
+/// Conflicts are forced, so the Kubelet always wins for the fields it applies.
+pub(crate) fn apply_params() -> PatchParams {
+    apply_params_as(FIELD_MANAGER)
+}
+
+/// Returns the parameters for a forced server-side apply as the given field manager
+pub(crate) fn apply_params_as(field_manager: &str) -> PatchParams {
+    PatchParams {
+        patch_strategy: PatchStrategy::Apply,
+        force: true,
+        field_manager: Some(field_manager.to_owned()),
+        ..Default::default()
     }
 }
 
 /// A helper for updating pod status. The given data should be a pod status object and be
-/// serializable by serde
+/// serializable by serde.
+///
+/// The status is sent as a server-side apply, so any status fields previously applied by
+/// the Kubelet that are missing from `data` are removed. The request is not rate limited.
 pub async fn update_pod_status<T: serde::Serialize>(
     client: kube::Client,
     ns: &str,
     pod_name: &str,
     data: &T,
-) -> anyhow::Result<()> {
-    let data = serde_json::to_vec(data)?;
+) -> Result<(), KubeletError> {
+    let limits = RateLimits::default();
+    apply_pod_status(client, ns, pod_name, data, None, &limits).await
+}
+
+/// Apply the pod status as the Kubelet's field manager, only to the given version of
+/// the pod if there is one
+async fn apply_pod_status<T: serde::Serialize>(
+    client: kube::Client,
+    ns: &str,
+    pod_name: &str,
+    data: &T,
+    resource_version: Option<String>,
+    limits: &RateLimits,
+) -> Result<(), KubeletError> {
+    let status_error = |source| KubeletError::StatusPatch {
+        pod_name: pod_name.to_owned(),
+        source,
+    };
+    let mut object = serde_json::to_value(data).map_err(|e| status_error(e.into()))?;
+    // An apply has to identify the object it is for, and must not carry a resource
+    // version unless it should fail on conflicting writes
+    object["apiVersion"] = "v1".into();
+    object["kind"] = "Pod".into();
+    object["metadata"] = serde_json::json!({ "name": pod_name, "namespace": ns });
+    if let Some(version) = resource_version {
+        object["metadata"]["resourceVersion"] = version.into();
+    }
+    let data = serde_json::to_vec(&object).map_err(|e| status_error(e.into()))?;
+    #[cfg(feature = "fault-injection")]
+    limits.faults().status_patch().map_err(status_error)?;
     let pod_client: Api<KubePod> = Api::namespaced(client, ns);
-    if let Err(e) = pod_client
-        .patch_status(pod_name, &PatchParams::default(), data)
+    let params = apply_params();
+    if let Err(e) = limits
+        .limited(pod_client.patch_status(pod_name, &params, data))
         .await
     {
-        return Err(e.into());
+        return Err(status_error(e));
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    #[tokio::test]
+    async fn test_update_pod_status() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("foo", "default"),
+        );
+
+        let status = serde_json::json!({"status": {"phase": Phase::Failed, "message": "boom"}});
+        update_pod_status(server.client(), "default", "foo", &status)
+            .await
+            .expect("status should be patched");
+
+        let pod = server
+            .get("/api/v1/namespaces/default/pods/foo")
+            .expect("pod should exist");
+        assert_eq!("Failed", pod["status"]["phase"]);
+        assert_eq!("boom", pod["status"]["message"]);
+        let request = server
+            .requests_to(
+                hyper::Method::PATCH,
+                "/api/v1/namespaces/default/pods/foo/status",
+            )
+            .pop()
+            .unwrap();
+        let query = request.query.unwrap_or_default();
+        assert!(query.contains("fieldManager=krustlet"));
+        assert!(query.contains("force=true"));
+        assert_eq!("Pod", request.body.unwrap()["kind"]);
+
+        update_pod_status(server.client(), "default", "missing", &status)
+            .await
+            .expect_err("missing pods should fail");
+    }
+
+    #[tokio::test]
+    async fn test_status_patch_merges_into_current_status() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut pod = serde_json::to_value(fake_pod("foo", "default")).unwrap();
+        pod["status"] = serde_json::json!({
+            "phase": "Failed",
+            "reason": "Evicted",
+            "hostIP": "10.0.0.1",
+            "conditions": [{ "type": "Initialized", "status": "True" }],
+            "containerStatuses": [{ "name": "a", "ready": false, "restartCount": 0, "image": "", "imageID": "" }],
+        });
+        server.insert("/api/v1/namespaces/default/pods", &pod);
+        let ready = PodCondition {
+            type_: "Ready".to_owned(),
+            status: "True".to_owned(),
+            ..Default::default()
+        };
+
+        StatusPatch::new()
+            .condition(ready.clone())
+            .apply(server.client(), "default", "foo")
+            .await
+            .unwrap();
+        let status = &server.get("/api/v1/namespaces/default/pods/foo").unwrap()["status"];
+        assert_eq!("Failed", status["phase"]);
+        assert_eq!("Evicted", status["reason"]);
+        assert_eq!("10.0.0.1", status["hostIP"]);
+        assert_eq!(2, status["conditions"].as_array().unwrap().len());
+        assert_eq!("a", status["containerStatuses"][0]["name"]);
+
+        // The reason goes along with the phase it was given for
+        StatusPatch::new()
+            .phase(Phase::Running)
+            .container_state(
+                "a",
+                ContainerState::Running {
+                    started_at: Some(Utc::now()),
+                },
+            )
+            .condition(ready)
+            .apply(server.client(), "default", "foo")
+            .await
+            .unwrap();
+        let status = &server.get("/api/v1/namespaces/default/pods/foo").unwrap()["status"];
+        assert_eq!("Running", status["phase"]);
+        assert!(status["reason"].is_null());
+        assert_eq!(2, status["conditions"].as_array().unwrap().len());
+        let containers = status["containerStatuses"].as_array().unwrap();
+        assert_eq!(1, containers.len());
+        assert!(containers[0]["state"]["running"].is_object());
+    }
+
+    #[test]
+    fn test_container_state() {
+        let now = Utc::now();
+        let terminated = ContainerState::Terminated {
+            exit_code: 137,
+            signal: Some(9),
+            reason: Some("Error".to_owned()),
+            message: None,
+            started_at: None,
+            finished_at: Some(now),
+        };
+        let kube_state = KubeContainerState::from(terminated.clone());
+        assert_eq!(137, kube_state.terminated.as_ref().unwrap().exit_code);
+        assert_eq!(terminated, ContainerState::from(kube_state));
+        assert_eq!(Some(137), terminated.exit_code());
+
+        // Kubernetes treats a state without anything set as waiting
+        assert!(ContainerState::from(KubeContainerState::default()).is_waiting());
+
+        let running = ContainerState::from(&ContainerStatus::Running { timestamp: now });
+        assert_eq!(
+            ContainerState::Running {
+                started_at: Some(now)
+            },
+            running
+        );
+        assert!(running.is_running());
+    }
+
+    #[test]
+    fn test_phase() {
+        assert_eq!(Phase::Succeeded, Phase::from(Phase::Succeeded.as_str()));
+        assert_eq!(Phase::Unknown, Phase::from("Sleeping"));
+        assert_eq!("Failed", Phase::Failed.to_string());
+    }
+
+    #[test]
+    fn test_status_patch() {
+        let patch = StatusPatch::new()
+            .phase(Phase::Running)
+            .message("hello")
+            .container_state(
+                "greet",
+                ContainerState::Running {
+                    started_at: Some(Utc::now()),
+                },
+            );
+        let json = serde_json::to_value(&patch).unwrap();
+        assert_eq!("Running", json["status"]["phase"]);
+        assert_eq!("hello", json["status"]["message"]);
+        let container = &json["status"]["containerStatuses"][0];
+        assert_eq!("greet", container["name"]);
+        assert_eq!(true, container["ready"]);
+        // Fields that aren't set are left out, rather than cleared
+        assert!(json["status"].get("reason").is_none());
+        assert!(json["status"].get("conditions").is_none());
+
+        let status = KubePodStatus::from(patch);
+        assert_eq!(Some("Running".to_owned()), status.phase);
+    }
+}
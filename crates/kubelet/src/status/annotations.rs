@@ -0,0 +1,461 @@
+//! Publishing what providers learn about pods at runtime
+//!
+//! Providers find out things about their pods that other controllers may need, such as
+//! the ports an actor bound, its public key or the ID of the instance running it.
+//! [`publish`] records them as annotations on the pod, so controllers can read them from
+//! the API server without a client for each provider, and [`unpublish`] removes them
+//! once they are no longer true, such as when the container stops:
+//!
+//! ```rust
+//! use kubelet::status::annotations::{self, InvalidKey};
+//!
+//! fn started(pod: &kubelet::Pod, port: u16) -> Result<(), InvalidKey> {
+//!     // Published as `runtime.krustlet.dev/web.port`
+//!     annotations::publish(pod, &annotations::key("web", "port"), &port.to_string())
+//! }
+//! ```
+//!
+//! Keys are made with [`key`], which puts them under [`PREFIX`] along with the name of
+//! the container they describe. The annotations under [`PREFIX`] are the Kubelet's, so
+//! when they are all that changed in a pod the provider is not told about it.
+//!
+//! Annotations are written in the background with a merge patch of the pod's status,
+//! which the pod's node is allowed to write, so publishing never waits on the API
+//! server. Annotations published for a pod while an earlier write is waiting are written
+//! along with it, and each pod is written at most once every [`MIN_WRITE_INTERVAL`], so
+//! a chatty provider can't flood the API server. Writes that fail are tried again until
+//! the pod is gone, unless the API server refused them. Annotations published for pods
+//! that were not handed out by a Kubelet are dropped.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, PatchParams};
+use kube::error::ErrorResponse;
+use log::{debug, warn};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::cancellation::CancellationToken;
+use crate::handle::key_from_pod;
+use crate::pod::Pod;
+use crate::pod_changes::PodChanges;
+use crate::rate_limit::RateLimits;
+
+/// The prefix of the keys of published annotations
+pub const PREFIX: &str = "runtime.krustlet.dev/";
+
+/// How often the annotations of a pod are written at most
+pub const MIN_WRITE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait before trying a failed write again
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A key that can't be used for an annotation
+#[derive(Debug, Error)]
+#[error("invalid annotation key {key:?}: {reason}")]
+pub struct InvalidKey {
+    key: String,
+    reason: &'static str,
+}
+
+/// The key of a detail of one of the pod's containers, such as `port`
+pub fn key(container_name: &str, name: &str) -> String {
+    format!("{}{}.{}", PREFIX, container_name, name)
+}
+
+/// Set an annotation on the pod, replacing any value it had
+pub fn publish(pod: &Pod, key: &str, value: &str) -> Result<(), InvalidKey> {
+    write(pod, key, Some(value))
+}
+
+/// Remove an annotation from the pod, such as when the detail is no longer true
+pub fn unpublish(pod: &Pod, key: &str) -> Result<(), InvalidKey> {
+    write(pod, key, None)
+}
+
+fn write(pod: &Pod, key: &str, value: Option<&str>) -> Result<(), InvalidKey> {
+    validate(key)?;
+    match pod.annotation_writer() {
+        Some(writer) => writer.add(pod, key, value),
+        None => debug!(
+            "Pod {} was not handed out by a Kubelet, dropping annotation {}",
+            pod.name(),
+            key
+        ),
+    }
+    Ok(())
+}
+
+/// Returns true if the only changes to a pod are to its published annotations, such as
+/// when a write of them comes back from the pod watch
+pub(crate) fn only_published(changes: &PodChanges) -> bool {
+    let published = |key: &String| key.starts_with(PREFIX);
+    let others = PodChanges {
+        annotations: Default::default(),
+        ..changes.clone()
+    };
+    !changes.annotations.is_empty()
+        && others.is_empty()
+        && changes.annotations.changed.keys().all(published)
+        && changes.annotations.removed.iter().all(published)
+}
+
+fn validate(key: &str) -> Result<(), InvalidKey> {
+    let invalid = |reason| {
+        Err(InvalidKey {
+            key: key.to_owned(),
+            reason,
+        })
+    };
+    if !key.starts_with(PREFIX) {
+        return invalid("it is not under runtime.krustlet.dev/");
+    }
+    let name = &key[PREFIX.len()..];
+    let alphanumeric = |c: Option<char>| matches!(c, Some(c) if c.is_ascii_alphanumeric());
+    if name.len() > 63
+        || !alphanumeric(name.chars().next())
+        || !alphanumeric(name.chars().last())
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return invalid("its name must be up to 63 alphanumeric characters, '-', '_' or '.'");
+    }
+    Ok(())
+}
+
+/// The annotations of a pod that are waiting to be written. `None` removes one
+struct Pending {
+    namespace: String,
+    name: String,
+    annotations: BTreeMap<String, Option<String>>,
+}
+
+struct Shared {
+    pending: Mutex<HashMap<String, Pending>>,
+    wake: mpsc::UnboundedSender<String>,
+}
+
+/// The sending half of the annotation writer, which providers publish through
+#[derive(Clone)]
+pub(crate) struct AnnotationWriter {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for AnnotationWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnnotationWriter").finish()
+    }
+}
+
+/// The receiving half of the annotation writer, which writes the annotations
+pub(crate) struct AnnotationQueue {
+    writer: AnnotationWriter,
+    wake: mpsc::UnboundedReceiver<String>,
+    retry_delay: Duration,
+}
+
+impl AnnotationWriter {
+    pub(crate) fn new() -> (Self, AnnotationQueue) {
+        let (wake, receiver) = mpsc::unbounded_channel();
+        let writer = AnnotationWriter {
+            shared: Arc::new(Shared {
+                pending: Mutex::new(HashMap::new()),
+                wake,
+            }),
+        };
+        let queue = AnnotationQueue {
+            writer: writer.clone(),
+            wake: receiver,
+            retry_delay: RETRY_DELAY,
+        };
+        (writer, queue)
+    }
+
+    fn add(&self, pod: &Pod, key: &str, value: Option<&str>) {
+        let pod_key = key_from_pod(pod);
+        let mut map = self.shared.pending.lock().unwrap();
+        let newly_pending = !map.contains_key(&pod_key);
+        map.entry(pod_key.clone())
+            .or_insert_with(|| Pending {
+                namespace: pod.namespace().to_owned(),
+                name: pod.name().to_owned(),
+                annotations: BTreeMap::new(),
+            })
+            .annotations
+            .insert(key.to_owned(), value.map(str::to_owned));
+        drop(map);
+        if newly_pending {
+            // The queue only goes away when the Kubelet is stopping
+            let _ = self.shared.wake.send(pod_key);
+        }
+    }
+
+    /// Put back annotations whose write failed, unless newer values were published
+    /// since they were taken
+    fn retry(&self, pod_key: String, pending: Pending) {
+        let Pending {
+            namespace,
+            name,
+            annotations,
+        } = pending;
+        let mut map = self.shared.pending.lock().unwrap();
+        let newly_pending = !map.contains_key(&pod_key);
+        let entry = map.entry(pod_key.clone()).or_insert_with(|| Pending {
+            namespace,
+            name,
+            annotations: BTreeMap::new(),
+        });
+        for (key, value) in annotations {
+            entry.annotations.entry(key).or_insert(value);
+        }
+        drop(map);
+        if newly_pending {
+            let _ = self.shared.wake.send(pod_key);
+        }
+    }
+
+    fn take(&self, pod_key: &str) -> Option<Pending> {
+        self.shared.pending.lock().unwrap().remove(pod_key)
+    }
+}
+
+impl AnnotationQueue {
+    /// Write the published annotations onto their pods until `stop` is cancelled, then
+    /// write the ones still waiting a last time. The writes are made under the rate
+    /// limits
+    pub(crate) async fn run(
+        mut self,
+        client: kube::Client,
+        limits: RateLimits,
+        stop: CancellationToken,
+    ) {
+        let mut written: HashMap<String, Instant> = HashMap::new();
+        while let Some(Some(pod_key)) = stop.run_until_cancelled(self.wake.recv()).await {
+            // Wait out the interval of a pod written recently in the background, so the
+            // other pods aren't held up
+            if let Some(since) = written.get(&pod_key).map(Instant::elapsed) {
+                if since < MIN_WRITE_INTERVAL {
+                    self.wake_later(pod_key, MIN_WRITE_INTERVAL - since);
+                    continue;
+                }
+            }
+            let pending = match self.writer.take(&pod_key) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            written.retain(|_, at| at.elapsed() < MIN_WRITE_INTERVAL);
+            written.insert(pod_key.clone(), Instant::now());
+            match write_annotations(&client, &limits, &pending).await {
+                Ok(()) => (),
+                // Writing them again would only be refused again
+                Err(e) if is_refusal(&e) => warn!(
+                    "Annotations of pod {} were refused, dropping them: {}",
+                    pending.name, e
+                ),
+                Err(e) => {
+                    warn!(
+                        "Unable to write annotations of pod {}, retrying in {:?}: {}",
+                        pending.name, self.retry_delay, e
+                    );
+                    let writer = self.writer.clone();
+                    let delay = self.retry_delay;
+                    tokio::spawn(async move {
+                        tokio::time::delay_for(delay).await;
+                        writer.retry(pod_key, pending);
+                    });
+                }
+            }
+        }
+        let pending: Vec<Pending> = self
+            .writer
+            .shared
+            .pending
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, pending)| pending)
+            .collect();
+        for pending in pending {
+            if let Err(e) = write_annotations(&client, &limits, &pending).await {
+                warn!(
+                    "Unable to write annotations of pod {} before stopping: {}",
+                    pending.name, e
+                );
+            }
+        }
+    }
+
+    fn wake_later(&self, pod_key: String, delay: Duration) {
+        let wake = self.writer.shared.wake.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(delay).await;
+            let _ = wake.send(pod_key);
+        });
+    }
+}
+
+/// Returns true for the errors of writes the API server won't take however often they are
+/// made, because the Kubelet may not make them or the annotations are invalid
+fn is_refusal(error: &kube::Error) -> bool {
+    matches!(error, kube::Error::Api(ErrorResponse { code, .. }) if *code == 403 || *code == 422)
+}
+
+/// Merge the annotations into the pod's through its status, which unlike the pod itself
+/// its node may write. A pod that no longer exists needs none
+async fn write_annotations(
+    client: &kube::Client,
+    limits: &RateLimits,
+    pending: &Pending,
+) -> Result<(), kube::Error> {
+    let api: Api<KubePod> = Api::namespaced(client.clone(), &pending.namespace);
+    let patch = serde_json::json!({ "metadata": { "annotations": pending.annotations } });
+    let data = serde_json::to_vec(&patch).expect("Pod annotations should always be serializable");
+    match limits
+        .limited(api.patch_status(&pending.name, &PatchParams::default(), data))
+        .await
+    {
+        Ok(_) => {
+            debug!(
+                "Wrote {} annotations of pod {}",
+                pending.annotations.len(),
+                pending.name
+            );
+            Ok(())
+        }
+        Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&key("web", "port")).is_ok());
+        assert!(validate("runtime.krustlet.dev/instance-id").is_ok());
+        for invalid in &[
+            "port",
+            "example.com/instance-id",
+            "node.kubernetes.io/port",
+            "runtime.krustlet.dev/",
+            "runtime.krustlet.dev/-port",
+            &key("web", &"x".repeat(60)),
+        ] {
+            assert!(validate(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_annotations_are_merged_and_rate_limited() {
+        let server = MockApiServer::start().await.unwrap();
+        let mut pod = fake_pod("foo", "default");
+        pod.metadata.as_mut().unwrap().annotations = Some(
+            vec![("owner".to_owned(), "team".to_owned())]
+                .into_iter()
+                .collect(),
+        );
+        server.insert("/api/v1/namespaces/default/pods", pod.clone());
+        let (writer, queue) = AnnotationWriter::new();
+        let pod = Pod::from(pod).with_annotation_writer(writer);
+        publish(&pod, &key("web", "port"), "8080").unwrap();
+        publish(&pod, &key("web", "actor-key"), "MABC").unwrap();
+        publish(&pod, &key("web", "port"), "8081").unwrap();
+        let stop = CancellationToken::new();
+        tokio::spawn(queue.run(server.client(), RateLimits::default(), stop.clone()));
+
+        let annotations = |server: &MockApiServer| {
+            server.get("/api/v1/namespaces/default/pods/foo").unwrap()["metadata"]["annotations"]
+                .clone()
+        };
+        let written = async {
+            while annotations(&server)[key("web", "port")] != "8081" {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, written).await.unwrap();
+        assert_eq!("MABC", annotations(&server)[key("web", "actor-key")]);
+        assert_eq!("team", annotations(&server)["owner"]);
+        let patches = || {
+            server
+                .requests_to(
+                    hyper::Method::PATCH,
+                    "/api/v1/namespaces/default/pods/foo/status",
+                )
+                .len()
+        };
+        assert_eq!(1, patches());
+
+        // The next write waits for the interval, unless the Kubelet stops first
+        unpublish(&pod, &key("web", "port")).unwrap();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(1, patches());
+        stop.cancel();
+        let removed = async {
+            while annotations(&server).get(key("web", "port")).is_some() {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(TIMEOUT, removed).await.unwrap();
+        assert_eq!(2, patches());
+    }
+
+    #[tokio::test]
+    async fn test_refused_annotations_are_dropped() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/pods",
+            fake_pod("foo", "default"),
+        );
+        server.deny("/api/v1/namespaces/default/pods/foo/status");
+        let (writer, mut queue) = AnnotationWriter::new();
+        queue.retry_delay = Duration::from_millis(10);
+        let pod = Pod::from(fake_pod("foo", "default")).with_annotation_writer(writer);
+        publish(&pod, &key("web", "port"), "8080").unwrap();
+        let stop = CancellationToken::new();
+        tokio::spawn(queue.run(server.client(), RateLimits::default(), stop.clone()));
+
+        // A refused write is not tried again, not even when the Kubelet stops
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        stop.cancel();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        let patches = server.requests_to(
+            hyper::Method::PATCH,
+            "/api/v1/namespaces/default/pods/foo/status",
+        );
+        assert_eq!(1, patches.len());
+    }
+
+    #[test]
+    fn test_only_published() {
+        let pod = |annotations: &[(&str, &str)], image: &str| {
+            let mut pod = fake_pod("foo", "default");
+            pod.metadata.as_mut().unwrap().annotations = Some(
+                annotations
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            );
+            pod.spec.as_mut().unwrap().containers[0].image = Some(image.to_owned());
+            Pod::from(pod)
+        };
+        let port = key("web", "port");
+        let old = pod(&[("owner", "team")], "web:v1");
+        let published = pod(&[("owner", "team"), (&port, "8080")], "web:v1");
+        assert!(only_published(&PodChanges::between(&old, &published)));
+        assert!(only_published(&PodChanges::between(&published, &old)));
+        // Nothing changing is not a write coming back
+        assert!(!only_published(&PodChanges::between(&old, &old)));
+        let owner = pod(&[("owner", "ops"), (&port, "8080")], "web:v1");
+        assert!(!only_published(&PodChanges::between(&old, &owner)));
+        let image = pod(&[("owner", "team"), (&port, "8080")], "web:v2");
+        assert!(!only_published(&PodChanges::between(&old, &image)));
+    }
+}
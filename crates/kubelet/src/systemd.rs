@@ -0,0 +1,209 @@
+//! Integration with systemd for running the Kubelet as a unit
+//!
+//! When started by systemd, the Kubelet reports readiness once its node is
+//! registered and pings the service watchdog from the node heartbeat loop. These
+//! use the [notify protocol](https://www.freedesktop.org/software/systemd/man/sd_notify.html)
+//! and do nothing when the process was not started with a notify socket, so they
+//! are safe to call unconditionally.
+//!
+//! [`JournalLogger`] can be used instead of a stderr logger to send structured
+//! log entries straight to journald.
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::time::Duration;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+const JOURNALD_ENV: &str = "KRUSTLET_LOG_JOURNALD";
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Send a raw state string such as `READY=1` to the service manager.
+///
+/// Returns `Ok(false)` if the process was not started with a notify socket.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let socket = match std::env::var_os(NOTIFY_SOCKET_ENV) {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+    send_datagram(Path::new(&socket), state.as_bytes())?;
+    Ok(true)
+}
+
+/// Tell the service manager that startup is complete
+pub fn notify_ready() -> io::Result<bool> {
+    notify("READY=1")
+}
+
+/// Ping the service watchdog to show that the Kubelet is still alive
+pub fn notify_watchdog() -> io::Result<bool> {
+    notify("WATCHDOG=1")
+}
+
+/// Returns how often the watchdog expects to be pinged, if it is enabled for this process.
+///
+/// systemd restarts the unit if no ping arrives within this interval, so pings should be
+/// sent at least twice as often.
+pub fn watchdog_interval() -> Option<Duration> {
+    // The watchdog settings are inherited by children, so only honor them if they were
+    // meant for this process
+    if let Ok(pid) = std::env::var(WATCHDOG_PID_ENV) {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var(WATCHDOG_USEC_ENV).ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Returns true if journald logging was requested by setting `KRUSTLET_LOG_JOURNALD` to
+/// `true` or `1`
+pub fn journald_requested() -> bool {
+    match std::env::var(JOURNALD_ENV) {
+        Ok(v) => v == "1" || v.eq_ignore_ascii_case("true"),
+        Err(_) => false,
+    }
+}
+
+/// A logger that writes entries to journald using its native protocol.
+///
+/// Each entry includes the log level as the journal priority along with the target,
+/// file, and line it came from, so it can be filtered with `journalctl`.
+pub struct JournalLogger {
+    socket: UnixDatagram,
+    level: LevelFilter,
+    identifier: String,
+}
+
+impl JournalLogger {
+    /// Create a logger that writes entries at or above the given level
+    pub fn new(level: LevelFilter) -> io::Result<Self> {
+        let identifier = std::env::current_exe()
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "krustlet".to_owned());
+        Ok(JournalLogger {
+            socket: UnixDatagram::unbound()?,
+            level,
+            identifier,
+        })
+    }
+
+    /// Install a journal logger as the global logger.
+    ///
+    /// Entries are filtered by the directives in `RUST_LOG`, and are written at `info`
+    /// and above when it is not set. The directives can be changed while the Kubelet
+    /// runs with the returned filter, see [`log_filter`](crate::log_filter).
+    pub fn init() -> anyhow::Result<crate::log_filter::LogFilter> {
+        crate::log_filter::init(Box::new(JournalLogger::new(LevelFilter::Trace)?), "info")
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let entry = journal_entry(record, &self.identifier);
+        // There is nowhere to report a failure to log, so drop the entry
+        let _ = self.socket.send_to(&entry, JOURNAL_SOCKET);
+    }
+
+    fn flush(&self) {}
+}
+
+fn send_datagram(path: &Path, message: &[u8]) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(message, path)?;
+    Ok(())
+}
+
+/// Maps a log level to a syslog priority
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn journal_entry(record: &Record, identifier: &str) -> Vec<u8> {
+    let mut entry = Vec::new();
+    add_field(&mut entry, "MESSAGE", &record.args().to_string());
+    add_field(
+        &mut entry,
+        "PRIORITY",
+        &priority(record.level()).to_string(),
+    );
+    add_field(&mut entry, "SYSLOG_IDENTIFIER", identifier);
+    add_field(&mut entry, "TARGET", record.target());
+    if let Some(file) = record.file() {
+        add_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        add_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    entry
+}
+
+/// Appends a field in the journal export format. Values containing newlines must be
+/// sent with an explicit length instead of being newline terminated
+fn add_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_send_datagram() {
+        let path = std::env::temp_dir().join(format!("krustlet-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        send_datagram(&path, b"READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(b"READY=1", &buf[..len]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_journal_entry() {
+        let entry = journal_entry(
+            &Record::builder()
+                .args(format_args!("hello\nworld"))
+                .level(Level::Warn)
+                .target("kubelet::node")
+                .line(Some(42))
+                .build(),
+            "krustlet-wasi",
+        );
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&11u64.to_le_bytes());
+        expected.extend_from_slice(
+            b"hello\nworld\nPRIORITY=4\nSYSLOG_IDENTIFIER=krustlet-wasi\nTARGET=kubelet::node\nCODE_LINE=42\n",
+        );
+        assert_eq!(expected, entry);
+    }
+}
@@ -0,0 +1,708 @@
+//! Helpers for testing providers and kubelet internals without a cluster
+//!
+//! This module is only available with the "testing" feature enabled. It provides
+//! a scriptable [`FakeProvider`] that records every call made to it and can be
+//! told to delay or fail specific operations, as well as a [`QueueHarness`] for
+//! feeding synthetic pod events through the same per-pod queue the Kubelet uses.
+//! For code that talks to Kubernetes, [`MockApiServer`] serves an in-memory API
+//! that a real `kube::Client` can be pointed at, and the [`conformance`] suite
+//! runs a provider through the scenarios every provider is expected to handle.
+//!
+//! # Example
+//! ```rust
+//! use kubelet::testing::{fake_pod, FakeProvider, Operation, QueueHarness};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let provider = Arc::new(FakeProvider::new());
+//! provider.fail(Operation::Add, "image not found");
+//!
+//! let mut harness = QueueHarness::new(provider.clone());
+//! harness.add(fake_pod("hello", "default")).await.unwrap();
+//!
+//! let (pod, err) = harness
+//!     .next_error(Duration::from_secs(1))
+//!     .await
+//!     .expect("add should have failed");
+//! assert_eq!(pod.name(), "hello");
+//! assert_eq!(err.to_string(), "image not found");
+//! # }
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use hyper::{Body, Request, Response};
+use k8s_openapi::api::core::v1::{Container, NodeCondition, Pod as KubePod, PodSpec};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::api::{ObjectMeta, WatchEvent};
+
+use crate::admission::Admission;
+use crate::autoscaling::ScalingHints;
+use crate::config::NamespaceFilter;
+use crate::error::PodSyncError;
+use crate::failures::{FailureQueue, FailureReporter};
+use crate::handle::{key_from_pod, pod_key};
+use crate::logs::LogSender;
+use crate::object_manager::ObjectManager;
+use crate::pod::Pod;
+use crate::pod_changes::PodChanges;
+use crate::provider::{NotImplementedError, Provider, ProviderError};
+use crate::queue::PodQueue;
+use crate::rate_limit::RateLimits;
+use crate::registry::PodRegistry;
+use crate::stats::ResourceAccounting;
+use crate::status::{ContainerStatus, Status};
+
+mod api_server;
+pub mod conformance;
+
+pub use api_server::{MockApiServer, RecordedRequest};
+
+/// The architecture reported by the [`FakeProvider`]
+pub const FAKE_ARCH: &str = "fake";
+
+/// The provider operations that can be scripted on a [`FakeProvider`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// [`Provider::setup_pod`]
+    SetupPod,
+    /// [`Provider::teardown_pod`]
+    TeardownPod,
+    /// [`Provider::add`]
+    Add,
+    /// [`Provider::modify`]
+    Modify,
+    /// [`Provider::delete`]
+    Delete,
+    /// [`Provider::logs`]
+    Logs,
+    /// [`Provider::add_ephemeral_container`]
+    AddEphemeralContainer,
+    /// [`Provider::node_conditions`], which is not recorded as a call
+    NodeConditions,
+    /// [`Provider::health`], which is not recorded as a call
+    Health,
+    /// [`Provider::scaling_hints`], which is not recorded as a call
+    ScalingHints,
+    /// [`Provider::prefetch`], whose images are recorded instead of calls, see
+    /// [`FakeProvider::prefetches`]
+    Prefetch,
+}
+
+/// A single recorded call to the [`FakeProvider`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Call {
+    /// The operation that was called
+    pub operation: Operation,
+    /// The namespace of the pod the operation was called for
+    pub namespace: String,
+    /// The name of the pod the operation was called for
+    pub pod_name: String,
+}
+
+#[derive(Default)]
+struct Script {
+    calls: Vec<Call>,
+    changes: Vec<PodChanges>,
+    delays: HashMap<Operation, Duration>,
+    errors: HashMap<Operation, Failure>,
+    logs: HashMap<String, String>,
+    /// The pods that were added and not deleted since, by pod key
+    pods: HashSet<String>,
+    /// The messages pulling each image fails with
+    failing_images: HashMap<String, String>,
+    /// The codes the containers of each image exit with
+    exiting_images: HashMap<String, i32>,
+    node_conditions: Vec<NodeCondition>,
+    custom_resource_definitions: Vec<CustomResourceDefinition>,
+    scaling_hints: Option<ScalingHints>,
+    /// The images prefetch was called with, in order
+    prefetches: Vec<String>,
+    /// The body, or the error message, each route responds with, by name and path
+    routes: HashMap<(String, String), Result<String, String>>,
+}
+
+/// An error a [`FakeProvider`] operation is scripted to return
+#[derive(Clone)]
+enum Failure {
+    Provider(String),
+    ImagePull { image: String, message: String },
+    Requeue(Duration),
+    NotImplemented,
+}
+
+/// A scriptable in-memory provider.
+///
+/// Every call is recorded and can be inspected with [`FakeProvider::calls`]. Each
+/// operation can be configured to sleep before returning (to simulate slow
+/// workloads or image pulls) and/or to return an error. The provider can be
+/// safely shared between the test and the kubelet using an `Arc`.
+///
+/// Like a real provider, it only returns logs for the pods it was given and not
+/// told to delete since, and it fails to pull the images set with
+/// [`FakeProvider::fail_image`]. Given a client, it reports the statuses of the
+/// containers set to exit with [`FakeProvider::exit_image`], restarting them as the
+/// pod's restart policy says, so it passes the [`conformance`] suite.
+#[derive(Clone, Default)]
+pub struct FakeProvider {
+    script: Arc<Mutex<Script>>,
+    client: Option<kube::Client>,
+}
+
+impl FakeProvider {
+    /// Create a new provider that succeeds immediately on every operation
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new provider that reports the statuses of its containers through the
+    /// given client
+    pub fn with_client(client: kube::Client) -> Self {
+        FakeProvider {
+            client: Some(client),
+            ..Default::default()
+        }
+    }
+
+    /// Delay the given operation by `duration` every time it is called
+    pub fn delay(&self, operation: Operation, duration: Duration) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .delays
+            .insert(operation, duration);
+        self
+    }
+
+    /// Make the given operation return an error with the given message every
+    /// time it is called
+    pub fn fail(&self, operation: Operation, message: &str) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .errors
+            .insert(operation, Failure::Provider(message.to_owned()));
+        self
+    }
+
+    /// Make the given operation return a failure to pull the given image, with the
+    /// given message, every time it is called
+    pub fn fail_image_pull(&self, operation: Operation, image: &str, message: &str) -> &Self {
+        self.script.lock().unwrap().errors.insert(
+            operation,
+            Failure::ImagePull {
+                image: image.to_owned(),
+                message: message.to_owned(),
+            },
+        );
+        self
+    }
+
+    /// Make the given operation ask for the pod to be added again after the given time,
+    /// every time it is called
+    pub fn requeue(&self, operation: Operation, after: Duration) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .errors
+            .insert(operation, Failure::Requeue(after));
+        self
+    }
+
+    /// Make adding a pod with a container of the given image, or prefetching the image,
+    /// fail to pull it, with the given message
+    pub fn fail_image(&self, image: &str, message: &str) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .failing_images
+            .insert(image.to_owned(), message.to_owned());
+        self
+    }
+
+    /// Make the containers of the given image exit with the given code as soon as their
+    /// pod is added. The exit is only reported if the provider has a client
+    pub fn exit_image(&self, image: &str, exit_code: i32) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .exiting_images
+            .insert(image.to_owned(), exit_code);
+        self
+    }
+
+    /// Make the given operation report that the provider doesn't implement it, as
+    /// providers do for the optional parts of [`Provider`]
+    pub fn not_implemented(&self, operation: Operation) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .errors
+            .insert(operation, Failure::NotImplemented);
+        self
+    }
+
+    /// Stop failing the given operation
+    pub fn succeed(&self, operation: Operation) -> &Self {
+        self.script.lock().unwrap().errors.remove(&operation);
+        self
+    }
+
+    /// Set the log output returned for the given container
+    pub fn set_logs(&self, container_name: &str, logs: &str) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .logs
+            .insert(container_name.to_owned(), logs.to_owned());
+        self
+    }
+
+    /// Set the node conditions the provider reports
+    pub fn set_node_conditions(&self, conditions: Vec<NodeCondition>) -> &Self {
+        self.script.lock().unwrap().node_conditions = conditions;
+        self
+    }
+
+    /// Set the custom resource definitions the provider depends on
+    pub fn set_custom_resource_definitions(&self, crds: Vec<CustomResourceDefinition>) -> &Self {
+        self.script.lock().unwrap().custom_resource_definitions = crds;
+        self
+    }
+
+    /// Set the scaling hints the provider reports, which it reports none of by default
+    pub fn set_scaling_hints(&self, hints: Option<ScalingHints>) -> &Self {
+        self.script.lock().unwrap().scaling_hints = hints;
+        self
+    }
+
+    /// Serve the given body from the provider route of the given name and path, such
+    /// as `/info`
+    pub fn serve(&self, name: &str, path: &str, body: &str) -> &Self {
+        self.route_to(name, path, Ok(body.to_owned()))
+    }
+
+    /// Make the provider route of the given name and path fail with the given message
+    pub fn fail_route(&self, name: &str, path: &str, message: &str) -> &Self {
+        self.route_to(name, path, Err(message.to_owned()))
+    }
+
+    fn route_to(&self, name: &str, path: &str, response: Result<String, String>) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .routes
+            .insert((name.to_owned(), path.to_owned()), response);
+        self
+    }
+
+    /// Returns a copy of all the calls recorded so far, in the order they were made
+    pub fn calls(&self) -> Vec<Call> {
+        self.script.lock().unwrap().calls.clone()
+    }
+
+    /// Returns all recorded calls of the given operation
+    pub fn calls_for(&self, operation: Operation) -> Vec<Call> {
+        self.calls()
+            .into_iter()
+            .filter(|c| c.operation == operation)
+            .collect()
+    }
+
+    /// Wait until at least `count` calls of the given operation have been
+    /// recorded. Returns false if the timeout elapsed first
+    pub async fn wait_for_calls(
+        &self,
+        operation: Operation,
+        count: usize,
+        timeout: Duration,
+    ) -> bool {
+        let wait = async {
+            while self.calls_for(operation).len() < count {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+
+    /// Get the images passed to [`Provider::prefetch`], in order
+    pub fn prefetches(&self) -> Vec<String> {
+        self.script.lock().unwrap().prefetches.clone()
+    }
+
+    /// Get the changes passed to [`Provider::modify_with_changes`], in order
+    pub fn changes(&self) -> Vec<PodChanges> {
+        self.script.lock().unwrap().changes.clone()
+    }
+
+    async fn record(
+        &self,
+        operation: Operation,
+        namespace: &str,
+        pod_name: &str,
+    ) -> anyhow::Result<()> {
+        self.script.lock().unwrap().calls.push(Call {
+            operation,
+            namespace: namespace.to_owned(),
+            pod_name: pod_name.to_owned(),
+        });
+        self.scripted(operation).await
+    }
+
+    /// Delay and fail the operation as it is scripted to
+    async fn scripted(&self, operation: Operation) -> anyhow::Result<()> {
+        let (delay, error) = {
+            let script = self.script.lock().unwrap();
+            (
+                script.delays.get(&operation).cloned(),
+                script.errors.get(&operation).cloned(),
+            )
+        };
+        if let Some(delay) = delay {
+            tokio::time::delay_for(delay).await;
+        }
+        match error {
+            Some(Failure::Provider(message)) => Err(anyhow::anyhow!(message)),
+            Some(Failure::ImagePull { image, message }) => Err(PodSyncError::ImagePull {
+                image,
+                source: anyhow::anyhow!(message),
+            }
+            .into()),
+            Some(Failure::Requeue(after)) => Err(PodSyncError::Requeue(after).into()),
+            Some(Failure::NotImplemented) => Err(NotImplementedError.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Report the exit of the containers set to exit, and then their restart if the
+    /// restart policy of the pod asks for one
+    async fn run_containers(&self, pod: &Pod) {
+        let client = match &self.client {
+            Some(client) => client,
+            None => return,
+        };
+        let exits: Vec<(String, i32)> = {
+            let script = self.script.lock().unwrap();
+            pod.containers()
+                .iter()
+                .filter_map(|c| {
+                    let code = script.exiting_images.get(c.image.as_deref()?)?;
+                    Some((c.name.clone(), *code))
+                })
+                .collect()
+        };
+        if exits.is_empty() {
+            return;
+        }
+        let exited = exits
+            .iter()
+            .map(|(name, exit_code)| {
+                let status = ContainerStatus::Terminated {
+                    timestamp: Utc::now(),
+                    message: format!("exited with code {}", exit_code),
+                    exit_code: *exit_code,
+                };
+                (name.clone(), status)
+            })
+            .collect();
+        pod.patch_status(client.clone(), status(exited)).await;
+        let policy = pod.restart_policy();
+        let restarted: HashMap<String, ContainerStatus> = exits
+            .into_iter()
+            .filter(|(_, exit_code)| policy.restarts(*exit_code))
+            .map(|(name, _)| {
+                let status = ContainerStatus::Running {
+                    timestamp: Utc::now(),
+                };
+                (name, status)
+            })
+            .collect();
+        if !restarted.is_empty() {
+            pod.patch_status(client.clone(), status(restarted)).await;
+        }
+    }
+}
+
+fn status(container_statuses: HashMap<String, ContainerStatus>) -> Status {
+    Status {
+        message: None,
+        container_statuses,
+    }
+}
+
+#[async_trait]
+impl Provider for FakeProvider {
+    const ARCH: &'static str = FAKE_ARCH;
+
+    fn custom_resource_definitions(&self) -> anyhow::Result<Vec<CustomResourceDefinition>> {
+        Ok(self
+            .script
+            .lock()
+            .unwrap()
+            .custom_resource_definitions
+            .clone())
+    }
+
+    async fn node_conditions(&self) -> anyhow::Result<Vec<NodeCondition>> {
+        self.scripted(Operation::NodeConditions).await?;
+        Ok(self.script.lock().unwrap().node_conditions.clone())
+    }
+
+    async fn health(&self) -> anyhow::Result<()> {
+        self.scripted(Operation::Health).await
+    }
+
+    async fn scaling_hints(&self) -> anyhow::Result<Option<ScalingHints>> {
+        self.scripted(Operation::ScalingHints).await?;
+        Ok(self.script.lock().unwrap().scaling_hints.clone())
+    }
+
+    async fn prefetch(&self, image: &str) -> anyhow::Result<()> {
+        self.script
+            .lock()
+            .unwrap()
+            .prefetches
+            .push(image.to_owned());
+        self.scripted(Operation::Prefetch).await?;
+        match self.script.lock().unwrap().failing_images.get(image) {
+            Some(message) => Err(PodSyncError::ImagePull {
+                image: image.to_owned(),
+                source: anyhow::anyhow!(message.clone()),
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    fn has_routes(&self, name: &str) -> bool {
+        let script = self.script.lock().unwrap();
+        script.routes.keys().any(|(route, _)| route == name)
+    }
+
+    async fn route(
+        &self,
+        name: &str,
+        path: &str,
+        _req: Request<Body>,
+    ) -> anyhow::Result<Response<Body>> {
+        let key = (name.to_owned(), path.to_owned());
+        match self.script.lock().unwrap().routes.get(&key) {
+            Some(Ok(body)) => Ok(Response::new(Body::from(body.clone()))),
+            Some(Err(message)) => Err(anyhow::anyhow!(message.clone())),
+            None => Err(NotImplementedError.into()),
+        }
+    }
+
+    async fn setup_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        self.record(Operation::SetupPod, pod.namespace(), pod.name())
+            .await
+    }
+
+    async fn teardown_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        self.record(Operation::TeardownPod, pod.namespace(), pod.name())
+            .await
+    }
+
+    async fn add(&self, pod: Pod) -> anyhow::Result<()> {
+        self.record(Operation::Add, pod.namespace(), pod.name())
+            .await?;
+        {
+            let mut script = self.script.lock().unwrap();
+            let failure = pod.containers().iter().find_map(|c| {
+                let image = c.image.as_deref()?;
+                let message = script.failing_images.get(image)?;
+                Some((image.to_owned(), message.clone()))
+            });
+            if let Some((image, message)) = failure {
+                return Err(PodSyncError::ImagePull {
+                    image,
+                    source: anyhow::anyhow!(message),
+                }
+                .into());
+            }
+            script.pods.insert(key_from_pod(&pod));
+        }
+        self.run_containers(&pod).await;
+        Ok(())
+    }
+
+    async fn modify(&self, pod: Pod) -> anyhow::Result<()> {
+        self.record(Operation::Modify, pod.namespace(), pod.name())
+            .await
+    }
+
+    async fn modify_with_changes(&self, pod: Pod, changes: PodChanges) -> anyhow::Result<()> {
+        self.script.lock().unwrap().changes.push(changes);
+        self.modify(pod).await
+    }
+
+    async fn delete(&self, pod: Pod) -> anyhow::Result<()> {
+        self.record(Operation::Delete, pod.namespace(), pod.name())
+            .await?;
+        self.script.lock().unwrap().pods.remove(&key_from_pod(&pod));
+        Ok(())
+    }
+
+    async fn add_ephemeral_container(&self, pod: Pod, _container: Container) -> anyhow::Result<()> {
+        self.record(
+            Operation::AddEphemeralContainer,
+            pod.namespace(),
+            pod.name(),
+        )
+        .await
+    }
+
+    async fn logs(
+        &self,
+        namespace: String,
+        pod: String,
+        container: String,
+        mut sender: LogSender,
+    ) -> anyhow::Result<()> {
+        self.record(Operation::Logs, &namespace, &pod).await?;
+        let logs = {
+            let script = self.script.lock().unwrap();
+            if !script.pods.contains(&pod_key(&namespace, &pod)) {
+                return Err(ProviderError::PodNotFound { pod_name: pod }.into());
+            }
+            script.logs.get(&container).cloned()
+        };
+        if let Some(logs) = logs {
+            sender.send(logs).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Build a minimal Kubernetes pod with a single container named after the pod
+pub fn fake_pod(name: &str, namespace: &str) -> KubePod {
+    KubePod {
+        metadata: Some(ObjectMeta {
+            name: Some(name.to_owned()),
+            namespace: Some(namespace.to_owned()),
+            uid: Some(format!("{}-{}-uid", namespace, name)),
+            ..Default::default()
+        }),
+        spec: Some(PodSpec {
+            containers: vec![Container {
+                name: name.to_owned(),
+                image: Some(format!("fake.registry.io/{}:v1", name)),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }),
+        status: Default::default(),
+    }
+}
+
+/// A harness wrapping the Kubelet's per-pod queue for feeding it synthetic events.
+///
+/// Errors returned by the provider are captured instead of being patched onto the
+/// pod status and can be inspected with [`QueueHarness::next_error`].
+pub struct QueueHarness<P> {
+    queue: PodQueue<P>,
+    errors: FailureQueue,
+    registry: PodRegistry,
+}
+
+impl<P: 'static + Provider + Sync + Send> QueueHarness<P> {
+    /// Create a new harness that dispatches events to the given provider.
+    ///
+    /// The queue is given a client that cannot connect to anything, so any updates
+    /// it makes to pods (such as adding finalizers) will fail and be logged. Use
+    /// [`QueueHarness::with_client`] to observe those updates.
+    pub fn new(provider: Arc<P>) -> Self {
+        let client = kube::Client::new(kube::Config::new(
+            reqwest::Url::parse("http://127.0.0.1:1").unwrap(),
+        ));
+        Self::with_client(provider, client)
+    }
+
+    /// Create a new harness that dispatches events to the given provider and
+    /// updates pods using the given client, such as one from a [`MockApiServer`]
+    pub fn with_client(provider: Arc<P>, client: kube::Client) -> Self {
+        let (failures, errors) = FailureReporter::new();
+        let registry = PodRegistry::default();
+        QueueHarness {
+            queue: PodQueue::new(
+                provider,
+                client.clone(),
+                failures,
+                ObjectManager::new(client, RateLimits::default()),
+                ResourceAccounting::default(),
+                registry.clone(),
+                Admission::default(),
+            ),
+            errors,
+            registry,
+        }
+    }
+
+    /// Reject pods from namespaces the given filter does not allow, as the Kubelet does
+    /// with its [`namespaces`](crate::config::Config::namespaces) configuration
+    pub fn with_namespaces(mut self, namespaces: NamespaceFilter) -> Self {
+        self.queue
+            .set_admission(Admission::default().with_namespaces(namespaces));
+        self
+    }
+
+    /// Check pods with the given admission checks instead of admitting every pod
+    #[cfg(test)]
+    pub(crate) fn with_admission(mut self, admission: Admission) -> Self {
+        self.queue.set_admission(admission);
+        self
+    }
+
+    /// Returns the registry of the pods the queue is handling
+    pub fn pods(&self) -> &PodRegistry {
+        &self.registry
+    }
+
+    /// Enqueue an arbitrary watch event
+    pub async fn send(&mut self, event: WatchEvent<KubePod>) -> anyhow::Result<()> {
+        self.queue.enqueue(event).await
+    }
+
+    /// Enqueue an `Added` event for the given pod
+    pub async fn add(&mut self, pod: KubePod) -> anyhow::Result<()> {
+        self.send(WatchEvent::Added(pod)).await
+    }
+
+    /// Enqueue a `Modified` event for the given pod
+    pub async fn modify(&mut self, pod: KubePod) -> anyhow::Result<()> {
+        self.send(WatchEvent::Modified(pod)).await
+    }
+
+    /// Enqueue a `Deleted` event for the given pod
+    pub async fn delete(&mut self, pod: KubePod) -> anyhow::Result<()> {
+        self.send(WatchEvent::Deleted(pod)).await
+    }
+
+    /// Have the pod workers stop pods because the node shuts down, as the Kubelet does
+    /// when the shutdown watch asks it to
+    #[cfg(test)]
+    pub(crate) fn stop_for_shutdown(&mut self, request: crate::shutdown::StopRequest) {
+        self.queue.stop_for_shutdown(request)
+    }
+
+    /// Stop the queue as the Kubelet does, waiting up to `timeout` for the pod workers
+    /// to finish the events they are handling
+    pub async fn drain(self, timeout: Duration) {
+        self.queue.drain(timeout).await
+    }
+
+    /// Wait for the next error reported by a pod worker, returning `None` if
+    /// the timeout elapses first. Only the newest unreported error is kept for each pod
+    pub async fn next_error(&mut self, timeout: Duration) -> Option<(Pod, PodSyncError)> {
+        tokio::time::timeout(timeout, self.errors.next())
+            .await
+            .ok()
+            .flatten()
+            .map(|(pod, error)| (pod.into(), error))
+    }
+}
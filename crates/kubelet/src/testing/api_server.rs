@@ -0,0 +1,1068 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use hyper::service::service_fn;
+use hyper::{server::conn::Http, Body, Method, Request, Response, StatusCode};
+use log::{debug, error};
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tokio::stream::StreamExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+/// A request received by the [`MockApiServer`]
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    /// The HTTP method of the request
+    pub method: Method,
+    /// The request path, without the query string
+    pub path: String,
+    /// The raw query string, if any
+    pub query: Option<String>,
+    /// The request body parsed as JSON, if it was JSON
+    pub body: Option<Value>,
+    /// The request headers
+    pub headers: hyper::HeaderMap,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ObjectKey {
+    api: String,
+    resource: String,
+    namespace: Option<String>,
+    name: String,
+}
+
+/// A parsed API path such as `/api/v1/namespaces/default/pods/foo/status`
+#[derive(Debug)]
+struct ApiPath {
+    api: String,
+    resource: String,
+    namespace: Option<String>,
+    name: Option<String>,
+    subresource: Option<String>,
+}
+
+impl ApiPath {
+    fn parse(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let (api, rest) = match segments.as_slice() {
+            ["api", version, rest @ ..] => (format!("/api/{}", version), rest),
+            ["apis", group, version, rest @ ..] => (format!("/apis/{}/{}", group, version), rest),
+            _ => return None,
+        };
+        let (namespace, rest) = match rest {
+            ["namespaces", ns, rest @ ..] if !rest.is_empty() => (Some((*ns).to_owned()), rest),
+            rest => (None, rest),
+        };
+        let resource = (*rest.get(0)?).to_owned();
+        Some(ApiPath {
+            api,
+            resource,
+            namespace,
+            name: rest.get(1).map(|s| (*s).to_owned()),
+            subresource: rest.get(2).map(|s| (*s).to_owned()),
+        })
+    }
+
+    fn key(&self, name: &str) -> ObjectKey {
+        ObjectKey {
+            api: self.api.clone(),
+            resource: self.resource.clone(),
+            namespace: self.namespace.clone(),
+            name: name.to_owned(),
+        }
+    }
+
+    /// Whether the given object key belongs to the collection this path refers to. A
+    /// path without a namespace matches objects in all namespaces
+    fn matches(&self, key: &ObjectKey) -> bool {
+        key.api == self.api
+            && key.resource == self.resource
+            && (self.namespace.is_none() || self.namespace == key.namespace)
+    }
+}
+
+struct Watcher {
+    path: ApiPath,
+    field_selector: Option<String>,
+    label_selector: Option<String>,
+    sender: UnboundedSender<Result<hyper::body::Bytes, std::convert::Infallible>>,
+}
+
+#[derive(Default)]
+struct State {
+    objects: BTreeMap<ObjectKey, Value>,
+    requests: Vec<RecordedRequest>,
+    watchers: Vec<Watcher>,
+    resource_version: u64,
+    /// The major and minor version served from `/version`, if not the default
+    version: Option<(String, String)>,
+    /// The path prefixes requests to are refused
+    denied: Vec<String>,
+    /// The path prefixes and fields, as JSON pointers, writes that set them are refused
+    denied_changes: Vec<(String, String)>,
+    /// The fields each field manager applied to an object, by object, manager and
+    /// whether they were applied to the status subresource
+    managed: BTreeMap<(ObjectKey, String, bool), Vec<FieldPath>>,
+}
+
+/// The path to a field of an object. An item of a list that is merged by its keys,
+/// such as a condition by its type, is named `k:` followed by its keys as JSON, as in
+/// the managed fields of a real API server
+type FieldPath = Vec<String>;
+
+/// The fields identifying the items of the lists that are merged by their items rather
+/// than replaced
+fn list_keys(name: &str) -> Option<&'static [&'static str]> {
+    match name {
+        "conditions" => Some(&["type"]),
+        "taints" => Some(&["key", "effect"]),
+        _ => None,
+    }
+}
+
+/// The name of a list item in a field path
+fn item_name(item: &Value, keys: &[&str]) -> String {
+    let keys: serde_json::Map<String, Value> = keys
+        .iter()
+        .map(|k| ((*k).to_owned(), item[*k].clone()))
+        .collect();
+    format!("k:{}", Value::Object(keys))
+}
+
+/// Whether a list item is the one with the given name in a field path
+fn is_item(item: &Value, name: &str) -> bool {
+    if !name.starts_with("k:") {
+        return false;
+    }
+    match serde_json::from_str::<serde_json::Map<String, Value>>(&name[2..]) {
+        Ok(keys) => keys.iter().all(|(k, v)| &item[k.as_str()] == v),
+        Err(_) => false,
+    }
+}
+
+impl State {
+    fn next_version(&mut self) -> String {
+        self.resource_version += 1;
+        self.resource_version.to_string()
+    }
+
+    fn store(&mut self, key: ObjectKey, mut object: Value, event_type: &str) -> Value {
+        if event_type == "ADDED" {
+            // A new object has no fields applied to it yet
+            self.managed.retain(|(k, _, _), _| k != &key);
+        }
+        let version = self.next_version();
+        if object["metadata"]["uid"].is_null() {
+            object["metadata"]["uid"] = Value::String(format!("mock-uid-{}", version));
+        }
+        object["metadata"]["resourceVersion"] = Value::String(version);
+        let managed_fields = self.managed_fields(&key);
+        match object["metadata"].as_object_mut() {
+            Some(metadata) if managed_fields.is_empty() => {
+                metadata.remove("managedFields");
+            }
+            Some(metadata) => {
+                metadata.insert("managedFields".to_owned(), managed_fields.into());
+            }
+            None => (),
+        }
+        self.objects.insert(key.clone(), object.clone());
+        self.notify(&key, event_type, &object);
+        object
+    }
+
+    /// The managed fields of an object, which list the fields each field manager applied
+    fn managed_fields(&self, key: &ObjectKey) -> Vec<Value> {
+        self.managed
+            .iter()
+            .filter(|((k, _, _), owned)| k == key && !owned.is_empty())
+            .map(|((_, manager, _), owned)| {
+                let mut fields = Value::Object(Default::default());
+                for path in owned {
+                    let mut target = &mut fields;
+                    for name in path {
+                        let name = if name.starts_with("k:") {
+                            name.clone()
+                        } else {
+                            format!("f:{}", name)
+                        };
+                        target = target
+                            .as_object_mut()
+                            .expect("managed fields are always objects")
+                            .entry(name)
+                            .or_insert_with(|| Value::Object(Default::default()));
+                    }
+                }
+                serde_json::json!({
+                    "manager": manager,
+                    "operation": "Apply",
+                    "apiVersion": "v1",
+                    "fieldsType": "FieldsV1",
+                    "fieldsV1": fields,
+                })
+            })
+            .collect()
+    }
+
+    /// Stop counting the fields an update that isn't an apply changed as applied by
+    /// anyone, as the one updating them takes them over
+    fn release_changed(&mut self, key: &ObjectKey, old: &Value, new: &Value) {
+        for ((k, _, _), owned) in self.managed.iter_mut() {
+            if k == key {
+                owned.retain(|path| field(old, path) == field(new, path));
+            }
+        }
+    }
+
+    /// Store a modified object, removing it instead if it is marked for deletion and
+    /// has no finalizers left
+    fn update(&mut self, key: ObjectKey, object: Value) -> Value {
+        if !object["metadata"]["deletionTimestamp"].is_null() && !has_finalizers(&object) {
+            self.objects.remove(&key);
+            self.notify(&key, "DELETED", &object);
+            return object;
+        }
+        self.store(key, object, "MODIFIED")
+    }
+
+    /// Mark an object for deletion with the given grace period. Objects without
+    /// finalizers are removed straight away when the grace period is zero
+    fn begin_deletion(&mut self, key: ObjectKey, mut object: Value, grace_period: i64) -> Value {
+        if grace_period == 0 && !has_finalizers(&object) {
+            self.objects.remove(&key);
+            self.notify(&key, "DELETED", &object);
+            return object;
+        }
+        if object["metadata"]["deletionTimestamp"].is_null() {
+            object["metadata"]["deletionTimestamp"] = Value::String(
+                chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            );
+        }
+        object["metadata"]["deletionGracePeriodSeconds"] = grace_period.into();
+        self.store(key, object, "MODIFIED")
+    }
+
+    /// Apply an object as the field manager, the way a server-side apply does. The
+    /// fields the manager applied before but leaves out now are removed, unless another
+    /// manager applied them too, and the manager takes over the fields it applies
+    fn apply(
+        &mut self,
+        key: &ObjectKey,
+        status: bool,
+        manager: &str,
+        object: &mut Value,
+        patch: &Value,
+    ) {
+        let applied = if status {
+            serde_json::json!({ "status": patch.get("status").cloned().unwrap_or_default() })
+        } else {
+            let mut applied = patch.clone();
+            if let Some(fields) = applied.as_object_mut() {
+                for field in &["apiVersion", "kind", "status"] {
+                    fields.remove(*field);
+                }
+            }
+            if let Some(metadata) = applied["metadata"].as_object_mut() {
+                for field in &["name", "namespace", "resourceVersion"] {
+                    metadata.remove(*field);
+                }
+            }
+            applied
+        };
+        let fields = field_paths(&applied);
+        let owner = (key.clone(), manager.to_owned(), status);
+        let previous = self.managed.remove(&owner).unwrap_or_default();
+        for field in previous.iter().filter(|f| !fields.contains(f)) {
+            let shared = self
+                .managed
+                .iter()
+                .any(|((k, _, _), owned)| k == key && owned.contains(field));
+            if !shared {
+                remove_field(object, field);
+            }
+        }
+        for ((k, _, _), owned) in self.managed.iter_mut() {
+            if k == key {
+                owned.retain(|f| !fields.contains(f));
+            }
+        }
+        apply_merge(object, &applied);
+        self.managed.insert(owner, fields);
+    }
+
+    fn notify(&mut self, key: &ObjectKey, event_type: &str, object: &Value) {
+        let mut line =
+            serde_json::to_vec(&serde_json::json!({ "type": event_type, "object": object }))
+                .expect("watch events should always serialize");
+        line.push(b'\n');
+        self.watchers.retain(|w| {
+            if !w.path.matches(key)
+                || !matches_field_selector(object, w.field_selector.as_deref())
+                || !matches_label_selector(object, w.label_selector.as_deref())
+            {
+                return true;
+            }
+            w.sender.send(Ok(line.clone().into())).is_ok()
+        });
+    }
+}
+
+/// A lightweight in-memory stand-in for the Kubernetes API server.
+///
+/// The server stores arbitrary JSON objects keyed by their API path and supports
+/// the basic REST verbs used by the kubelet (`GET`, `POST`, `PUT`, `PATCH` (as a
+/// JSON merge patch or a server-side apply), `DELETE`) as well as list and watch
+/// requests on collections. A server-side apply removes the fields its field manager
+/// applied before but left out, as a real API server does, with conditions merged by
+/// their type, taints by their key and effect and other lists replaced. The fields each
+/// manager applied are listed in the object's `managedFields`, and writes that aren't
+/// applies take over the fields they change. Watch requests stay open and receive an
+/// event whenever a matching object is created, modified or deleted, so informers work
+/// against it. Equality-based field selectors and label selectors are honored for list
+/// and watch.
+///
+/// Deleting an object with finalizers (or with a grace period) only marks it
+/// with a `deletionTimestamp`, and it is removed once its finalizers are
+/// cleared. `POST`ing to the `eviction` subresource of a pod marks it for
+/// deletion the same way the Eviction API does.
+///
+/// `/version` reports the server as Kubernetes 1.17, unless another version is set
+/// with [`MockApiServer::set_version`]. Requests can be refused as forbidden with
+/// [`MockApiServer::deny`].
+///
+/// It binds to a random port on localhost, making it suitable for running many
+/// tests in parallel.
+///
+/// # Example
+/// ```rust
+/// use kubelet::testing::MockApiServer;
+/// use k8s_openapi::api::core::v1::Secret;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let server = MockApiServer::start().await.unwrap();
+/// server.insert(
+///     "/api/v1/namespaces/default/secrets",
+///     serde_json::json!({"metadata": {"name": "creds"}, "data": {}}),
+/// );
+///
+/// let api: kube::Api<Secret> = kube::Api::namespaced(server.client(), "default");
+/// let secret = api.get("creds").await.unwrap();
+/// assert!(secret.data.unwrap().is_empty());
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MockApiServer {
+    address: std::net::SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockApiServer {
+    /// Start a new server listening on a random localhost port
+    pub async fn start() -> anyhow::Result<Self> {
+        let address = std::net::SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut listener = TcpListener::bind(&address).await?;
+        let address = listener.local_addr()?;
+        let state: Arc<Mutex<State>> = Default::default();
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(conn) = incoming.next().await {
+                let conn = match conn {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Mock API server failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| handle(req, state.clone()));
+                    if let Err(e) = Http::new().serve_connection(conn, service).await {
+                        debug!("Mock API server connection closed with error: {}", e);
+                    }
+                });
+            }
+        });
+        debug!("Mock API server listening on {}", address);
+        Ok(MockApiServer { address, state })
+    }
+
+    /// The base URL of the server
+    pub fn url(&self) -> reqwest::Url {
+        reqwest::Url::parse(&format!("http://{}", self.address))
+            .expect("mock server address should always be a valid URL")
+    }
+
+    /// A kube configuration that points at this server
+    pub fn kube_config(&self) -> kube::Config {
+        kube::Config::new(self.url())
+    }
+
+    /// A kube client that talks to this server
+    pub fn client(&self) -> kube::Client {
+        kube::Client::new(self.kube_config())
+    }
+
+    /// Insert (or replace) an object in the given collection, such as
+    /// `/api/v1/namespaces/default/pods`. Any open watches on the collection will
+    /// receive an `ADDED` event.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the collection path cannot be parsed or the object has no name
+    pub fn insert<T: serde::Serialize>(&self, collection: &str, object: T) {
+        let path = ApiPath::parse(collection).expect("invalid collection path");
+        let object = serde_json::to_value(object).expect("object should serialize to JSON");
+        let name = object["metadata"]["name"]
+            .as_str()
+            .expect("object must have a name")
+            .to_owned();
+        self.state
+            .lock()
+            .unwrap()
+            .store(path.key(&name), object, "ADDED");
+    }
+
+    /// Apply a JSON merge patch to an existing object at the given path, sending a
+    /// `MODIFIED` event to open watches. Returns false if no object exists at the path
+    pub fn modify(&self, path: &str, patch: &Value) -> bool {
+        let path = match ApiPath::parse(path) {
+            Some(p) => p,
+            None => return false,
+        };
+        let key = match &path.name {
+            Some(name) => path.key(name),
+            None => return false,
+        };
+        let mut state = self.state.lock().unwrap();
+        match state.objects.get(&key).cloned() {
+            Some(existing) => {
+                let mut object = existing.clone();
+                merge_patch(&mut object, patch);
+                state.release_changed(&key, &existing, &object);
+                state.store(key, object, "MODIFIED");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the object at the given path, sending a `DELETED` event to open
+    /// watches. Returns the removed object, if any
+    pub fn remove(&self, path: &str) -> Option<Value> {
+        let path = ApiPath::parse(path)?;
+        let key = path.key(path.name.as_deref()?);
+        let mut state = self.state.lock().unwrap();
+        let object = state.objects.remove(&key)?;
+        state.notify(&key, "DELETED", &object);
+        Some(object)
+    }
+
+    /// Get a copy of the object stored at the given path
+    pub fn get(&self, path: &str) -> Option<Value> {
+        let path = ApiPath::parse(path)?;
+        let key = path.key(path.name.as_deref()?);
+        self.state.lock().unwrap().objects.get(&key).cloned()
+    }
+
+    /// Set the Kubernetes version the server reports, such as `("1", "19")`
+    pub fn set_version(&self, major: &str, minor: &str) {
+        self.state.lock().unwrap().version = Some((major.to_owned(), minor.to_owned()));
+    }
+
+    /// Refuse requests to paths starting with the prefix with `403 Forbidden`, as if
+    /// the client were not allowed to make them
+    pub fn deny(&self, prefix: &str) {
+        self.state.lock().unwrap().denied.push(prefix.to_owned());
+    }
+
+    /// Refuse writes to paths starting with the prefix that set the given field, a JSON
+    /// pointer such as `/spec/taints`, with `403 Forbidden`, as admission plugins like
+    /// NodeRestriction refuse some changes
+    pub fn deny_changes(&self, prefix: &str, field: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .denied_changes
+            .push((prefix.to_owned(), field.to_owned()));
+    }
+
+    /// All requests received so far, in the order they were received
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// All requests received so far with the given method and path
+    pub fn requests_to(&self, method: Method, path: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.method == method && r.path == path)
+            .collect()
+    }
+}
+
+async fn handle(
+    req: Request<Body>,
+    state: Arc<Mutex<State>>,
+) -> Result<Response<Body>, hyper::Error> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let query = req.uri().query().map(|q| q.to_owned());
+    let headers = req.headers().clone();
+    let apply = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .map(|t| t.as_bytes())
+        == Some(b"application/apply-patch+yaml");
+    let bytes = hyper::body::to_bytes(req.into_body()).await?;
+    let body: Option<Value> = serde_json::from_slice(&bytes).ok();
+    let params: HashMap<String, String> = query
+        .as_deref()
+        .map(|q| {
+            url::form_urlencoded::parse(q.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default();
+    debug!("Mock API server handling {} {}", method, path);
+
+    let mut state = state.lock().unwrap();
+    state.requests.push(RecordedRequest {
+        method: method.clone(),
+        path: path.clone(),
+        query,
+        body: body.clone(),
+        headers,
+    });
+
+    if state.denied.iter().any(|prefix| path.starts_with(prefix)) {
+        return Ok(status_response(StatusCode::FORBIDDEN, "Forbidden", &path));
+    }
+    let writes = method == Method::PUT || method == Method::PATCH;
+    if let Some(body) = body.as_ref().filter(|_| writes) {
+        let denied = state
+            .denied_changes
+            .iter()
+            .any(|(prefix, field)| path.starts_with(prefix) && body.pointer(field).is_some());
+        if denied {
+            return Ok(status_response(StatusCode::FORBIDDEN, "Forbidden", &path));
+        }
+    }
+    if path == "/version" {
+        let (major, minor) = state
+            .version
+            .clone()
+            .unwrap_or_else(|| ("1".to_owned(), "17".to_owned()));
+        let version = serde_json::json!({
+            "major": major,
+            "minor": minor,
+            "gitVersion": format!("v{}.{}.0", major, minor),
+            "gitCommit": "",
+            "gitTreeState": "clean",
+            "buildDate": "",
+            "goVersion": "",
+            "compiler": "gc",
+            "platform": "linux/amd64",
+        });
+        return Ok(json_response(StatusCode::OK, &version));
+    }
+    let api_path = match ApiPath::parse(&path) {
+        Some(p) => p,
+        None => return Ok(status_response(StatusCode::NOT_FOUND, "NotFound", &path)),
+    };
+    let field_selector = params.get("fieldSelector").cloned();
+    let label_selector = params.get("labelSelector").cloned();
+
+    let response = match (&method, &api_path.name) {
+        (&Method::GET, None) if params.get("watch").map(|w| w == "true") == Some(true) => {
+            let (sender, receiver) = unbounded_channel();
+            state.watchers.push(Watcher {
+                path: api_path,
+                field_selector,
+                label_selector,
+                sender,
+            });
+            Response::new(Body::wrap_stream(receiver))
+        }
+        (&Method::GET, None) => {
+            let items: Vec<Value> = state
+                .objects
+                .iter()
+                .filter(|(k, v)| {
+                    api_path.matches(k)
+                        && matches_field_selector(v, field_selector.as_deref())
+                        && matches_label_selector(v, label_selector.as_deref())
+                })
+                .map(|(_, v)| v.clone())
+                .collect();
+            let resource_version = state.resource_version.to_string();
+            json_response(
+                StatusCode::OK,
+                &serde_json::json!({
+                    "kind": "List",
+                    "apiVersion": "v1",
+                    "metadata": { "resourceVersion": resource_version },
+                    "items": items,
+                }),
+            )
+        }
+        (&Method::POST, None) => {
+            let object = body.unwrap_or_default();
+            match object["metadata"]["name"].as_str() {
+                Some(name) => {
+                    let key = api_path.key(name);
+                    if state.objects.contains_key(&key) {
+                        status_response(StatusCode::CONFLICT, "AlreadyExists", name)
+                    } else {
+                        let created = state.store(key, object, "ADDED");
+                        json_response(StatusCode::CREATED, &created)
+                    }
+                }
+                None => status_response(StatusCode::BAD_REQUEST, "BadRequest", "name is required"),
+            }
+        }
+        (method, Some(name)) => {
+            let key = api_path.key(name);
+            let existing = state.objects.get(&key).cloned();
+            match (method, existing) {
+                (_, None) if *method != Method::PUT => {
+                    status_response(StatusCode::NOT_FOUND, "NotFound", name)
+                }
+                (&Method::GET, Some(object)) => json_response(StatusCode::OK, &object),
+                // Writing an object with a stale resource version fails, just as it does
+                // against a real API server
+                (&Method::PUT, Some(existing)) | (&Method::PATCH, Some(existing))
+                    if is_stale(body.as_ref(), &existing) =>
+                {
+                    status_response(StatusCode::CONFLICT, "Conflict", name)
+                }
+                (&Method::PUT, existing) => {
+                    let object = body.unwrap_or_default();
+                    if let Some(existing) = existing {
+                        state.release_changed(&key, &existing, &object);
+                    }
+                    let object = state.update(key, object);
+                    json_response(StatusCode::OK, &object)
+                }
+                (&Method::POST, Some(object))
+                    if api_path.subresource.as_deref() == Some("eviction") =>
+                {
+                    let grace_period = body
+                        .as_ref()
+                        .and_then(|b| b["deleteOptions"]["gracePeriodSeconds"].as_i64())
+                        .unwrap_or_else(|| default_grace_period(&object));
+                    let object = state.begin_deletion(key, object, grace_period);
+                    json_response(StatusCode::CREATED, &object)
+                }
+                (&Method::PATCH, Some(existing)) => {
+                    let mut object = existing.clone();
+                    if let Some(patch) = body.as_ref() {
+                        // A patch to the status subresource should only touch the status
+                        let status = api_path.subresource.as_deref() == Some("status");
+                        match params.get("fieldManager") {
+                            Some(manager) if apply => {
+                                state.apply(&key, status, manager, &mut object, patch)
+                            }
+                            // Like the API server's, it takes the pod's labels and
+                            // annotations along with the status
+                            _ if status => {
+                                merge_patch(&mut object["status"], &patch["status"]);
+                                for field in &["labels", "annotations"] {
+                                    let patched = &patch["metadata"][*field];
+                                    if !patched.is_null() {
+                                        merge_patch(&mut object["metadata"][*field], patched);
+                                    }
+                                }
+                            }
+                            _ => merge_patch(&mut object, patch),
+                        }
+                        if !apply {
+                            state.release_changed(&key, &existing, &object);
+                        }
+                    }
+                    let object = state.update(key, object);
+                    json_response(StatusCode::OK, &object)
+                }
+                (&Method::DELETE, Some(object)) => {
+                    let grace_period = body
+                        .as_ref()
+                        .and_then(|b| b["gracePeriodSeconds"].as_i64())
+                        .or_else(|| params.get("gracePeriodSeconds")?.parse().ok())
+                        .unwrap_or(0);
+                    let object = state.begin_deletion(key, object, grace_period);
+                    json_response(StatusCode::OK, &object)
+                }
+                _ => status_response(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", name),
+            }
+        }
+        _ => status_response(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", &path),
+    };
+    Ok(response)
+}
+
+fn has_finalizers(object: &Value) -> bool {
+    object["metadata"]["finalizers"]
+        .as_array()
+        .map(|f| !f.is_empty())
+        .unwrap_or(false)
+}
+
+fn default_grace_period(object: &Value) -> i64 {
+    object["spec"]["terminationGracePeriodSeconds"]
+        .as_i64()
+        .unwrap_or(30)
+}
+
+fn json_response(code: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            serde_json::to_vec(value).expect("JSON values should always serialize"),
+        ))
+        .unwrap()
+}
+
+/// Returns true if the body sets a resource version that does not match the existing object
+fn is_stale(body: Option<&Value>, existing: &Value) -> bool {
+    match body.and_then(|b| b["metadata"]["resourceVersion"].as_str()) {
+        Some(version) if !version.is_empty() => {
+            existing["metadata"]["resourceVersion"].as_str() != Some(version)
+        }
+        _ => false,
+    }
+}
+
+fn status_response(code: StatusCode, reason: &str, message: &str) -> Response<Body> {
+    json_response(
+        code,
+        &serde_json::json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "status": "Failure",
+            "message": message,
+            "reason": reason,
+            "code": code.as_u16(),
+        }),
+    )
+}
+
+/// Checks a comma separated list of `path.to.field=value` selectors against the object
+fn matches_field_selector(object: &Value, selector: Option<&str>) -> bool {
+    let selector = match selector {
+        Some(s) if !s.is_empty() => s,
+        _ => return true,
+    };
+    selector.split(',').all(|requirement| {
+        let (field, expected, negate) = match requirement.find("!=") {
+            Some(i) => (&requirement[..i], &requirement[i + 2..], true),
+            None => {
+                let mut split = requirement.splitn(2, '=');
+                let field = split.next().unwrap_or_default();
+                let value = split.next().unwrap_or_default().trim_start_matches('=');
+                (field, value, false)
+            }
+        };
+        let actual = field
+            .split('.')
+            .fold(object, |v, segment| &v[segment])
+            .as_str()
+            .unwrap_or_default();
+        (actual == expected) != negate
+    })
+}
+
+/// Checks a label selector against the object's labels. Equality (`key=value`,
+/// `key!=value`), set (`key in (a,b)`, `key notin (a,b)`) and existence (`key`,
+/// `!key`) requirements are supported
+fn matches_label_selector(object: &Value, selector: Option<&str>) -> bool {
+    let selector = match selector {
+        Some(s) if !s.trim().is_empty() => s,
+        _ => return true,
+    };
+    let labels = &object["metadata"]["labels"];
+    split_requirements(selector).into_iter().all(|requirement| {
+        let requirement = requirement.trim();
+        if let Some(i) = requirement.find(" notin ") {
+            let values = label_values(&requirement[i + 7..]);
+            return !matches!(labels[requirement[..i].trim()].as_str(), Some(a) if values.contains(&a));
+        }
+        if let Some(i) = requirement.find(" in ") {
+            let values = label_values(&requirement[i + 4..]);
+            return matches!(labels[requirement[..i].trim()].as_str(), Some(a) if values.contains(&a));
+        }
+        if let Some(i) = requirement.find("!=") {
+            let actual = labels[requirement[..i].trim()].as_str();
+            return actual != Some(requirement[i + 2..].trim());
+        }
+        if let Some(i) = requirement.find('=') {
+            let actual = labels[requirement[..i].trim()].as_str();
+            return actual == Some(requirement[i + 1..].trim_start_matches('=').trim());
+        }
+        if requirement.starts_with('!') {
+            return labels[requirement.trim_start_matches('!').trim()].is_null();
+        }
+        !labels[requirement].is_null()
+    })
+}
+
+/// Splits a label selector on the commas that aren't part of a set of values
+fn split_requirements(selector: &str) -> Vec<&str> {
+    let mut requirements = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in selector.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                requirements.push(&selector[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    requirements.push(&selector[start..]);
+    requirements
+}
+
+/// Parses a set of values such as `(a, b)`
+fn label_values(values: &str) -> Vec<&str> {
+    values
+        .trim()
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+        .map(str::trim)
+        .collect()
+}
+
+/// Apply a JSON merge patch ([RFC 7386](https://tools.ietf.org/html/rfc7386))
+fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        patch => *target = patch.clone(),
+    }
+}
+
+/// The paths of the fields an applied object sets
+fn field_paths(applied: &Value) -> Vec<FieldPath> {
+    let mut paths = Vec::new();
+    collect_field_paths(applied, &mut Vec::new(), &mut paths);
+    paths
+}
+
+fn collect_field_paths(value: &Value, path: &mut FieldPath, paths: &mut Vec<FieldPath>) {
+    let fields = match value.as_object() {
+        Some(fields) => fields,
+        None => return paths.push(path.clone()),
+    };
+    for (name, value) in fields {
+        path.push(name.clone());
+        match (value, list_keys(name)) {
+            (Value::Array(items), Some(keys)) => {
+                for item in items {
+                    let mut item_path = path.clone();
+                    item_path.push(item_name(item, keys));
+                    paths.push(item_path);
+                }
+            }
+            (Value::Object(fields), _) if fields.is_empty() => (),
+            (value, _) => collect_field_paths(value, path, paths),
+        }
+        path.pop();
+    }
+}
+
+fn remove_field(object: &mut Value, path: &[String]) {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut target = object;
+    for name in parents {
+        target = match target.get_mut(name.as_str()) {
+            Some(field) => field,
+            None => return,
+        };
+    }
+    if last.starts_with("k:") {
+        if let Some(items) = target.as_array_mut() {
+            items.retain(|item| !is_item(item, last));
+        }
+    } else if let Some(fields) = target.as_object_mut() {
+        fields.remove(last);
+    }
+}
+
+/// The value of the field at the given path, if the object has it
+fn field<'a>(object: &'a Value, path: &[String]) -> Option<&'a Value> {
+    let mut target = object;
+    for name in path {
+        target = if name.starts_with("k:") {
+            target.as_array()?.iter().find(|item| is_item(item, name))?
+        } else {
+            target.get(name.as_str())?
+        };
+    }
+    Some(target)
+}
+
+/// Merge an applied object into an existing one. Conditions are merged by their type,
+/// taints by their key and effect, and other lists are replaced
+fn apply_merge(target: &mut Value, applied: &Value) {
+    let fields = match applied.as_object() {
+        Some(fields) => fields,
+        None => {
+            *target = applied.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    for (name, value) in fields {
+        let existing = &mut target[name.as_str()];
+        match (value.as_array(), list_keys(name)) {
+            (Some(items), Some(keys)) => {
+                if !existing.is_array() {
+                    *existing = Value::Array(Vec::new());
+                }
+                let existing = existing.as_array_mut().unwrap();
+                for item in items {
+                    let name = item_name(item, keys);
+                    match existing.iter_mut().find(|e| is_item(e, &name)) {
+                        Some(e) => *e = item.clone(),
+                        None => existing.push(item.clone()),
+                    }
+                }
+            }
+            _ => apply_merge(existing, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        let p = ApiPath::parse("/api/v1/namespaces/default/pods/foo/status").unwrap();
+        assert_eq!("/api/v1", p.api);
+        assert_eq!("pods", p.resource);
+        assert_eq!(Some("default".to_owned()), p.namespace);
+        assert_eq!(Some("foo".to_owned()), p.name);
+        assert_eq!(Some("status".to_owned()), p.subresource);
+
+        let p = ApiPath::parse("/apis/coordination.k8s.io/v1/namespaces/kube-node-lease/leases")
+            .unwrap();
+        assert_eq!("/apis/coordination.k8s.io/v1", p.api);
+        assert_eq!("leases", p.resource);
+        assert_eq!(None, p.name);
+
+        let p = ApiPath::parse("/api/v1/namespaces/default").unwrap();
+        assert_eq!("namespaces", p.resource);
+        assert_eq!(None, p.namespace);
+        assert_eq!(Some("default".to_owned()), p.name);
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut target = serde_json::json!({"a": {"b": 1, "c": 2}, "d": [1]});
+        merge_patch(
+            &mut target,
+            &serde_json::json!({"a": {"b": null, "e": 3}, "d": [2]}),
+        );
+        assert_eq!(serde_json::json!({"a": {"c": 2, "e": 3}, "d": [2]}), target);
+    }
+
+    #[test]
+    fn test_server_side_apply() {
+        let mut state = State::default();
+        let key = ApiPath::parse("/api/v1/namespaces/default/pods")
+            .unwrap()
+            .key("foo");
+        let ready = |status| serde_json::json!({ "type": "Ready", "status": status });
+        let mut pod = serde_json::json!({
+            "metadata": { "name": "foo" },
+            "status": { "phase": "Pending", "hostIP": "10.0.0.1" },
+        });
+
+        let patch = serde_json::json!({ "status": {
+            "phase": "Running",
+            "containerStatuses": [{ "name": "a" }],
+            "conditions": [ready("True")],
+        }});
+        state.apply(&key, true, "krustlet", &mut pod, &patch);
+        let patch = serde_json::json!({ "status": {
+            "conditions": [{ "type": "Outage", "status": "True" }],
+        }});
+        state.apply(&key, true, "availability", &mut pod, &patch);
+        assert_eq!(2, pod["status"]["conditions"].as_array().unwrap().len());
+
+        // The fields krustlet leaves out are removed, the others' fields are kept
+        let patch = serde_json::json!({ "status": { "conditions": [ready("False")] }});
+        state.apply(&key, true, "krustlet", &mut pod, &patch);
+        assert_eq!(
+            serde_json::json!({
+                "hostIP": "10.0.0.1",
+                "conditions": [ready("False"), { "type": "Outage", "status": "True" }],
+            }),
+            pod["status"]
+        );
+    }
+
+    #[test]
+    fn test_field_selector() {
+        let pod = serde_json::json!({"spec": {"nodeName": "krustlet"}});
+        assert!(matches_field_selector(&pod, Some("spec.nodeName=krustlet")));
+        assert!(matches_field_selector(
+            &pod,
+            Some("spec.nodeName==krustlet")
+        ));
+        assert!(!matches_field_selector(&pod, Some("spec.nodeName=other")));
+        assert!(matches_field_selector(&pod, Some("spec.nodeName!=other")));
+        assert!(matches_field_selector(&pod, None));
+        let pod = serde_json::json!({"status": {"hostIP": "fd00::10"}});
+        assert!(matches_field_selector(&pod, Some("status.hostIP=fd00::10")));
+        assert!(!matches_field_selector(&pod, Some("status.hostIP=fd00::1")));
+    }
+
+    #[test]
+    fn test_label_selector() {
+        let pod = serde_json::json!({"metadata": {"labels": {"app": "web", "tier": "frontend"}}});
+        assert!(matches_label_selector(&pod, Some("app=web")));
+        assert!(matches_label_selector(&pod, Some("app==web,tier=frontend")));
+        assert!(!matches_label_selector(&pod, Some("app=web,tier=backend")));
+        assert!(matches_label_selector(&pod, Some("app!=db")));
+        assert!(matches_label_selector(
+            &pod,
+            Some("tier in (frontend, backend)")
+        ));
+        assert!(!matches_label_selector(
+            &pod,
+            Some("tier notin (frontend,backend)")
+        ));
+        assert!(matches_label_selector(&pod, Some("app,!canary")));
+        assert!(!matches_label_selector(&pod, Some("canary")));
+        assert!(!matches_label_selector(
+            &serde_json::json!({"metadata": {}}),
+            Some("app in (web)")
+        ));
+        assert!(matches_label_selector(&pod, None));
+    }
+}
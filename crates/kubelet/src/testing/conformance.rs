@@ -0,0 +1,399 @@
+//! A reusable conformance suite for [`Provider`] implementations
+//!
+//! The suite runs a provider through a set of standard scenarios against a
+//! [`MockApiServer`] and reports which of them passed. Provider authors can run
+//! it from their own tests to verify that their provider behaves the way the
+//! Kubelet expects.
+//!
+//! # Example
+//! ```rust
+//! use kubelet::testing::conformance::{ConformanceConfig, ConformanceSuite};
+//! use kubelet::testing::FakeProvider;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let config = ConformanceConfig {
+//!     image: "webassembly.azurecr.io/hello-wasm:v1".to_owned(),
+//!     failing_image: "webassembly.azurecr.io/does-not-exist:v1".to_owned(),
+//!     exiting_image: "webassembly.azurecr.io/exit-one:v1".to_owned(),
+//!     ..Default::default()
+//! };
+//! // A real provider would be created from the kube configuration here, and would
+//! // fetch and run the images itself
+//! let report = ConformanceSuite::new(config.clone())
+//!     .run(|kubeconfig| {
+//!         let config = config.clone();
+//!         async move {
+//!             let provider = FakeProvider::with_client(kube::Client::new(kubeconfig));
+//!             provider.fail_image(&config.failing_image, "manifest unknown");
+//!             provider.exit_image(&config.exiting_image, 1);
+//!             Ok(provider)
+//!         }
+//!     })
+//!     .await;
+//! report.assert_passed();
+//! # }
+//! ```
+use std::future::Future;
+use std::time::Duration;
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use log::info;
+use serde_json::Value;
+
+use super::{fake_pod, MockApiServer};
+use crate::logs::LogSender;
+use crate::pod::Pod;
+use crate::provider::Provider;
+
+/// Configuration for a run of the [`ConformanceSuite`]
+#[derive(Clone, Debug)]
+pub struct ConformanceConfig {
+    /// An image reference the provider is able to fetch and run
+    pub image: String,
+    /// An image reference the provider will fail to fetch
+    pub failing_image: String,
+    /// An image reference the provider is able to fetch whose container exits with a
+    /// non-zero exit code straight away
+    pub exiting_image: String,
+    /// The namespace to create test pods in
+    pub namespace: String,
+    /// How long to wait for a single provider operation before failing the scenario
+    pub operation_timeout: Duration,
+}
+
+impl Default for ConformanceConfig {
+    fn default() -> Self {
+        ConformanceConfig {
+            image: String::new(),
+            failing_image: String::new(),
+            exiting_image: String::new(),
+            namespace: "conformance".to_owned(),
+            operation_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The outcome of a single conformance scenario
+#[derive(Clone, Debug)]
+pub struct ScenarioResult {
+    /// The name of the scenario
+    pub name: &'static str,
+    /// The failure message if the scenario did not pass
+    pub failure: Option<String>,
+}
+
+/// The results of a conformance run
+#[derive(Clone, Debug, Default)]
+pub struct ConformanceReport {
+    /// The results of all scenarios in the order they were run
+    pub results: Vec<ScenarioResult>,
+}
+
+impl ConformanceReport {
+    /// Returns true if every scenario passed
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|r| r.failure.is_none())
+    }
+
+    /// Returns the scenarios that failed
+    pub fn failures(&self) -> Vec<&ScenarioResult> {
+        self.results
+            .iter()
+            .filter(|r| r.failure.is_some())
+            .collect()
+    }
+
+    /// Panics with a description of every failed scenario if any scenario failed
+    pub fn assert_passed(&self) {
+        let failures: Vec<String> = self
+            .failures()
+            .iter()
+            .map(|r| format!("{}: {}", r.name, r.failure.as_deref().unwrap_or_default()))
+            .collect();
+        if !failures.is_empty() {
+            panic!(
+                "{} conformance scenario(s) failed:\n{}",
+                failures.len(),
+                failures.join("\n")
+            );
+        }
+    }
+}
+
+/// The list of standard scenarios run by the suite
+const SCENARIOS: &[&str] = &[
+    "add_and_delete",
+    "delete_on_deletion_timestamp",
+    "delete_unknown_pod",
+    "logs_for_running_pod",
+    "logs_for_unknown_pod",
+    "failed_image",
+    "restart_policies",
+];
+
+/// A conformance suite for [`Provider`] implementations.
+///
+/// Every scenario gets a fresh provider created by the factory passed to
+/// [`ConformanceSuite::run`], which is given a kube configuration pointing at
+/// a mock API server that already contains the pods being tested.
+pub struct ConformanceSuite {
+    config: ConformanceConfig,
+}
+
+impl ConformanceSuite {
+    /// Create a new suite with the given configuration
+    pub fn new(config: ConformanceConfig) -> Self {
+        ConformanceSuite { config }
+    }
+
+    /// Returns the names of all the scenarios the suite will run
+    pub fn scenarios() -> &'static [&'static str] {
+        SCENARIOS
+    }
+
+    /// Run every scenario, creating a new provider for each one with the given factory
+    pub async fn run<P, F, Fut>(&self, factory: F) -> ConformanceReport
+    where
+        P: Provider + Send + Sync,
+        F: Fn(kube::Config) -> Fut,
+        Fut: Future<Output = anyhow::Result<P>>,
+    {
+        let mut report = ConformanceReport::default();
+        for name in SCENARIOS {
+            info!("Running conformance scenario {}", name);
+            let failure = match self.run_scenario(name, &factory).await {
+                Ok(()) => None,
+                Err(e) => Some(format!("{:#}", e)),
+            };
+            report.results.push(ScenarioResult { name, failure });
+        }
+        report
+    }
+
+    async fn run_scenario<P, F, Fut>(&self, name: &str, factory: &F) -> anyhow::Result<()>
+    where
+        P: Provider + Send + Sync,
+        F: Fn(kube::Config) -> Fut,
+        Fut: Future<Output = anyhow::Result<P>>,
+    {
+        let server = MockApiServer::start().await?;
+        let provider = factory(server.kube_config()).await?;
+        let timeout = self.config.operation_timeout;
+        match name {
+            "add_and_delete" => {
+                let pod = self.pod(&server, name, &self.config.image);
+                within(timeout, provider.add(pod.clone())).await?;
+                within(timeout, provider.delete(pod)).await
+            }
+            "delete_on_deletion_timestamp" => {
+                let mut pod = self.pod(&server, name, &self.config.image);
+                within(timeout, provider.add(pod.clone())).await?;
+                let mut kube_pod = pod.into_kube_pod();
+                kube_pod
+                    .metadata
+                    .get_or_insert_with(Default::default)
+                    .deletion_timestamp = Some(Time(Utc::now()));
+                pod = kube_pod.into();
+                within(timeout, provider.modify(pod.clone())).await?;
+                within(timeout, provider.delete(pod)).await
+            }
+            "delete_unknown_pod" => {
+                let pod = self.pod(&server, name, &self.config.image);
+                within(timeout, provider.delete(pod))
+                    .await
+                    .map_err(|e| anyhow::anyhow!("deleting an unknown pod should succeed: {}", e))
+            }
+            "logs_for_running_pod" => {
+                let pod = self.pod(&server, name, &self.config.image);
+                within(timeout, provider.add(pod.clone())).await?;
+                let container = pod.containers()[0].name.clone();
+                let (sender, _body) = hyper::Body::channel();
+                let result = within(
+                    timeout,
+                    provider.logs(
+                        pod.namespace().to_owned(),
+                        pod.name().to_owned(),
+                        container,
+                        LogSender::new(sender, None, false),
+                    ),
+                )
+                .await;
+                within(timeout, provider.delete(pod)).await?;
+                result
+            }
+            "logs_for_unknown_pod" => {
+                let (sender, _body) = hyper::Body::channel();
+                let result = within(
+                    timeout,
+                    provider.logs(
+                        self.config.namespace.clone(),
+                        "does-not-exist".to_owned(),
+                        "does-not-exist".to_owned(),
+                        LogSender::new(sender, None, false),
+                    ),
+                )
+                .await;
+                match result {
+                    Ok(()) => Err(anyhow::anyhow!(
+                        "fetching logs for an unknown pod should return an error"
+                    )),
+                    Err(_) => Ok(()),
+                }
+            }
+            "failed_image" => {
+                let pod = self.pod(&server, name, &self.config.failing_image);
+                match within(timeout, provider.add(pod)).await {
+                    Ok(()) => Err(anyhow::anyhow!(
+                        "adding a pod with an image that cannot be fetched should return an error"
+                    )),
+                    Err(_) => Ok(()),
+                }
+            }
+            "restart_policies" => {
+                for policy in &["Always", "OnFailure", "Never"] {
+                    let name = format!("restart-{}", policy.to_lowercase());
+                    let mut pod = fake_pod(&name, &self.config.namespace);
+                    pod.spec.get_or_insert_with(Default::default).restart_policy =
+                        Some((*policy).to_owned());
+                    let pod = self.store(&server, pod, &self.config.exiting_image);
+                    within(timeout, provider.add(pod.clone()))
+                        .await
+                        .map_err(|e| {
+                            anyhow::anyhow!("pod with restartPolicy {} failed: {}", policy, e)
+                        })?;
+                    let path =
+                        format!("/api/v1/namespaces/{}/pods/{}", self.config.namespace, name);
+                    if *policy == "Never" {
+                        if !wait_for_status(&server, &path, timeout, exited).await {
+                            return Err(anyhow::anyhow!(
+                                "the container of the pod with restartPolicy Never was not reported as exited"
+                            ));
+                        }
+                        if restarted(&server.get(&path).unwrap_or_default()) {
+                            return Err(anyhow::anyhow!(
+                                "the pod with restartPolicy Never was restarted"
+                            ));
+                        }
+                    } else if !wait_for_status(&server, &path, timeout, restarted).await {
+                        return Err(anyhow::anyhow!(
+                            "the pod with restartPolicy {} was not restarted after its container exited",
+                            policy
+                        ));
+                    }
+                    within(timeout, provider.delete(pod)).await?;
+                }
+                Ok(())
+            }
+            other => Err(anyhow::anyhow!("unknown scenario {}", other)),
+        }
+    }
+
+    /// Build a test pod with the given image and store it in the mock server
+    fn pod(&self, server: &MockApiServer, name: &str, image: &str) -> Pod {
+        let name = name.replace('_', "-");
+        self.store(server, fake_pod(&name, &self.config.namespace), image)
+    }
+
+    /// Give the pod the image and store it in the mock server
+    fn store(&self, server: &MockApiServer, mut pod: KubePod, image: &str) -> Pod {
+        if let Some(spec) = pod.spec.as_mut() {
+            spec.containers[0].image = Some(image.to_owned());
+        }
+        // The API server always returns a status, which providers update
+        pod.status = Some(Default::default());
+        server.insert(
+            &format!("/api/v1/namespaces/{}/pods", self.config.namespace),
+            &pod,
+        );
+        pod.into()
+    }
+}
+
+async fn within<F: Future<Output = anyhow::Result<()>>>(
+    timeout: Duration,
+    f: F,
+) -> anyhow::Result<()> {
+    tokio::time::timeout(timeout, f)
+        .await
+        .map_err(|_| anyhow::anyhow!("operation timed out after {:?}", timeout))?
+}
+
+/// Wait until the stored pod at the path has a status that passes the check. Returns
+/// false if the timeout elapsed first
+async fn wait_for_status(
+    server: &MockApiServer,
+    path: &str,
+    timeout: Duration,
+    check: fn(&Value) -> bool,
+) -> bool {
+    let wait = async {
+        while !check(&server.get(path).unwrap_or_default()) {
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(timeout, wait).await.is_ok()
+}
+
+fn container_statuses(pod: &Value) -> impl Iterator<Item = &Value> {
+    pod["status"]["containerStatuses"]
+        .as_array()
+        .into_iter()
+        .flatten()
+}
+
+/// Whether a container of the pod is reported as exited
+fn exited(pod: &Value) -> bool {
+    container_statuses(pod).any(|s| s["state"]["terminated"].is_object())
+}
+
+/// Whether a container of the pod is reported as restarted
+fn restarted(pod: &Value) -> bool {
+    container_statuses(pod).any(|s| s["restartCount"].as_i64().unwrap_or(0) > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::FakeProvider;
+
+    fn config() -> ConformanceConfig {
+        ConformanceConfig {
+            image: "present".to_owned(),
+            failing_image: "missing".to_owned(),
+            exiting_image: "exits".to_owned(),
+            operation_timeout: Duration::from_secs(5),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conforming_provider_passes() {
+        let report = ConformanceSuite::new(config())
+            .run(|kubeconfig| async {
+                let provider = FakeProvider::with_client(kube::Client::new(kubeconfig));
+                provider.fail_image("missing", "image not found");
+                provider.exit_image("exits", 1);
+                Ok(provider)
+            })
+            .await;
+        report.assert_passed();
+        assert_eq!(ConformanceSuite::scenarios().len(), report.results.len());
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_reported() {
+        // Without a client, containers are never reported as exited, let alone restarted
+        let report = ConformanceSuite::new(config())
+            .run(|_| async {
+                let provider = FakeProvider::new();
+                provider.exit_image("exits", 1);
+                Ok(provider)
+            })
+            .await;
+        let failed: Vec<&str> = report.failures().iter().map(|r| r.name).collect();
+        assert_eq!(vec!["failed_image", "restart_policies"], failed);
+    }
+}
@@ -0,0 +1,296 @@
+//! Accepting TLS connections to the Kubelet server
+//!
+//! The server normally uses the platform's TLS library through `native-tls`, which can't
+//! ask clients for a certificate. When a client CA is configured, connections are
+//! accepted with OpenSSL instead, which requests a certificate and checks that the CA
+//! signed it. Like the API server, the user of a client certificate is its common name
+//! (CN) and its groups are its organizations (O). Client certificates are only
+//! supported on Linux.
+use std::path::Path;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::auth::UserInfo;
+
+/// A connection the server reads requests from and writes responses to
+pub(crate) trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Accepts TLS connections with the server's certificate
+pub(crate) enum Acceptor {
+    Native(tokio_tls::TlsAcceptor),
+    #[cfg(target_os = "linux")]
+    ClientCertificates(openssl::ssl::SslAcceptor),
+}
+
+impl Acceptor {
+    /// Create an acceptor for the certificate in the PKCS #12 archive, requesting client
+    /// certificates signed by the CAs in the file, if one is given
+    pub(crate) fn new(
+        pfx: &[u8],
+        password: &str,
+        client_ca: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        match client_ca {
+            None => {
+                let identity = native_tls::Identity::from_pkcs12(pfx, password)?;
+                Ok(Acceptor::Native(tokio_tls::TlsAcceptor::from(
+                    native_tls::TlsAcceptor::new(identity)?,
+                )))
+            }
+            #[cfg(target_os = "linux")]
+            Some(client_ca) => Ok(Acceptor::ClientCertificates(openssl_acceptor::new(
+                pfx, password, client_ca,
+            )?)),
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => Err(anyhow::anyhow!(
+                "client certificates are only supported on Linux"
+            )),
+        }
+    }
+
+    /// Create an acceptor for a PEM certificate, followed by its chain, and its PEM
+    /// private key, such as the `tls.crt` and `tls.key` that cert-manager writes
+    pub(crate) fn from_pem(
+        cert: &[u8],
+        key: &[u8],
+        client_ca: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            let pfx = openssl_acceptor::pkcs12_from_pem(cert, key)?;
+            Acceptor::new(&pfx, "", client_ca)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (cert, key, client_ca);
+            Err(anyhow::anyhow!(
+                "PEM certificates are only supported on Linux"
+            ))
+        }
+    }
+
+    /// Accept a connection, returning the user of the client certificate it was made
+    /// with, if any
+    pub(crate) async fn accept(
+        &self,
+        conn: TcpStream,
+    ) -> anyhow::Result<(Box<dyn Connection>, Option<UserInfo>)> {
+        match self {
+            Acceptor::Native(acceptor) => Ok((Box::new(acceptor.accept(conn).await?), None)),
+            #[cfg(target_os = "linux")]
+            Acceptor::ClientCertificates(acceptor) => {
+                let stream = tokio_openssl::accept(acceptor, conn).await?;
+                let user = stream
+                    .ssl()
+                    .peer_certificate()
+                    .and_then(|cert| openssl_acceptor::user(&cert));
+                Ok((Box::new(stream), user))
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod openssl_acceptor {
+    use std::path::Path;
+
+    use openssl::nid::Nid;
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+    use openssl::stack::Stack;
+    use openssl::x509::{X509Name, X509Ref, X509};
+
+    use crate::auth::UserInfo;
+
+    // `parse` is deprecated by newer openssl releases for `parse2`, which older ones lack
+    #[allow(deprecated)]
+    pub(super) fn new(pfx: &[u8], password: &str, client_ca: &Path) -> anyhow::Result<SslAcceptor> {
+        let identity = Pkcs12::from_der(pfx)?.parse(password)?;
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_private_key(&identity.pkey)?;
+        builder.set_certificate(&identity.cert)?;
+        for cert in identity.chain.into_iter().flatten() {
+            builder.add_extra_chain_cert(cert)?;
+        }
+        builder.check_private_key()?;
+        builder.set_ca_file(client_ca).map_err(|e| {
+            anyhow::anyhow!(
+                "unable to read client CA file {}: {}",
+                client_ca.display(),
+                e
+            )
+        })?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca)?);
+        // Clients without a certificate can still authenticate with a token, but
+        // certificates that are not signed by the CA fail the handshake
+        builder.set_verify(SslVerifyMode::PEER);
+        Ok(builder.build())
+    }
+
+    /// Bundle a PEM certificate, its chain and its private key into a PKCS #12 archive
+    /// without a password, as both kinds of acceptor read the certificate from one
+    #[allow(deprecated)]
+    pub(super) fn pkcs12_from_pem(cert: &[u8], key: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut certs = X509::stack_from_pem(cert)?.into_iter();
+        let cert = certs
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("the certificate file holds no certificate"))?;
+        let key = PKey::private_key_from_pem(key)?;
+        let mut chain = Stack::new()?;
+        for cert in certs {
+            chain.push(cert)?;
+        }
+        let mut builder = Pkcs12::builder();
+        builder.ca(chain);
+        Ok(builder.build("", "krustlet", &key, &cert)?.to_der()?)
+    }
+
+    /// Returns the user and groups of a client certificate
+    pub(super) fn user(cert: &X509Ref) -> Option<UserInfo> {
+        let entries = |nid| {
+            cert.subject_name()
+                .entries_by_nid(nid)
+                .filter_map(|entry| entry.data().as_utf8().ok().map(|s| s.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let name = entries(Nid::COMMONNAME).into_iter().next()?;
+        Some(UserInfo {
+            name,
+            uid: String::new(),
+            groups: entries(Nid::ORGANIZATIONNAME),
+        })
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) mod test {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+    use openssl::x509::{X509Name, X509};
+
+    /// A certificate for the subject and its key, signed by the issuer or self-signed
+    /// as a CA
+    pub(crate) fn certificate(
+        subject: &[(&str, &str)],
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        for (field, value) in subject {
+            name.append_entry_by_text(field, value).unwrap();
+        }
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match issuer {
+            Some((issuer, issuer_key)) => {
+                cert.set_issuer_name(issuer.subject_name()).unwrap();
+                cert.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                cert.set_issuer_name(&name).unwrap();
+                let mut constraints = openssl::x509::extension::BasicConstraints::new();
+                cert.append_extension(constraints.critical().ca().build().unwrap())
+                    .unwrap();
+                cert.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (cert.build(), key)
+    }
+
+    #[allow(deprecated)]
+    #[tokio::test]
+    async fn test_client_certificates() {
+        let (ca, ca_key) = certificate(&[("CN", "krustlet-ca")], None);
+        let (server, server_key) = certificate(&[("CN", "localhost")], Some((&ca, &ca_key)));
+        let pfx = Pkcs12::builder()
+            .build("", "server", &server_key, &server)
+            .unwrap()
+            .to_der()
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("krustlet-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_file = dir.join("ca.crt");
+        std::fs::write(&ca_file, ca.to_pem().unwrap()).unwrap();
+        let acceptor = Acceptor::new(&pfx, "", Some(&ca_file)).unwrap();
+
+        let mut listener =
+            tokio::net::TcpListener::bind(&std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = |cert: Option<(X509, PKey<Private>)>| {
+            std::thread::spawn(move || {
+                let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+                connector.set_verify(SslVerifyMode::NONE);
+                if let Some((cert, key)) = cert {
+                    connector.set_certificate(&cert).unwrap();
+                    connector.set_private_key(&key).unwrap();
+                }
+                let stream = std::net::TcpStream::connect(addr).unwrap();
+                let mut stream = connector.build().connect("localhost", stream).ok()?;
+                std::io::Write::write_all(&mut stream, b"hello").ok()
+            })
+        };
+
+        // A certificate signed by the CA is authenticated as its CN, with its
+        // organizations as groups
+        let signed = certificate(
+            &[
+                ("O", "system:nodes"),
+                ("O", "fleet"),
+                ("CN", "system:node:edge"),
+            ],
+            Some((&ca, &ca_key)),
+        );
+        let connected = client(Some(signed));
+        let (conn, _) = listener.accept().await.unwrap();
+        let (mut conn, user) = acceptor.accept(conn).await.unwrap();
+        let mut hello = [0; 5];
+        tokio::io::AsyncReadExt::read_exact(&mut conn, &mut hello)
+            .await
+            .unwrap();
+        assert_eq!(b"hello", &hello);
+        assert_eq!(
+            Some(UserInfo {
+                name: "system:node:edge".to_owned(),
+                uid: String::new(),
+                groups: vec!["system:nodes".to_owned(), "fleet".to_owned()],
+            }),
+            user
+        );
+        drop(conn);
+        assert!(connected.join().unwrap().is_some());
+
+        // Clients without a certificate are still accepted, so they can use a token
+        let connected = client(None);
+        let (conn, _) = listener.accept().await.unwrap();
+        let (_, user) = acceptor.accept(conn).await.unwrap();
+        assert_eq!(None, user);
+        assert!(connected.join().unwrap().is_some());
+
+        // Certificates of any other CA are refused
+        let (other_ca, other_key) = certificate(&[("CN", "other-ca")], None);
+        let unsigned = certificate(&[("CN", "intruder")], Some((&other_ca, &other_key)));
+        let connected = client(Some(unsigned));
+        let (conn, _) = listener.accept().await.unwrap();
+        assert!(acceptor.accept(conn).await.is_err());
+        assert!(connected.join().unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+//! The version of the Kubelet and what it was built from
+//!
+//! Fleet management tools can read the same information from a running Kubelet at the
+//! `/version` endpoint of its server, and from the `containerRuntimeVersion` in the
+//! `nodeInfo` of its node.
+//!
+//! ```rust
+//! let info = kubelet::version::info();
+//! println!("Running kubelet {} built from {}", info.version, info.git_commit);
+//! ```
+use serde::Serialize;
+
+/// The semver version of the Kubelet crate
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The git commit the Kubelet was built from, or `unknown` if it was not built from a
+/// git checkout
+pub const GIT_COMMIT: &str = env!("KRUSTLET_GIT_SHA");
+
+/// When the Kubelet was built, as an RFC 3339 timestamp
+pub const BUILD_DATE: &str = env!("KRUSTLET_BUILD_DATE");
+
+/// What a Kubelet was built from, as reported at its `/version` endpoint
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Info {
+    /// The semver version of the Kubelet crate
+    pub version: &'static str,
+    /// The git commit the Kubelet was built from
+    pub git_commit: &'static str,
+    /// When the Kubelet was built
+    pub build_date: &'static str,
+}
+
+impl Info {
+    /// The version with the abbreviated git commit as its build metadata, such as
+    /// `0.1.0+3f2c1ab`
+    pub fn full_version(&self) -> String {
+        if self.git_commit == "unknown" {
+            return self.version.to_owned();
+        }
+        let commit: String = self.git_commit.chars().take(7).collect();
+        format!("{}+{}", self.version, commit)
+    }
+}
+
+impl std::fmt::Display for Info {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (built {})", self.full_version(), self.build_date)
+    }
+}
+
+/// Returns what this Kubelet was built from
+pub fn info() -> Info {
+    Info {
+        version: VERSION,
+        git_commit: GIT_COMMIT,
+        build_date: BUILD_DATE,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_full_version() {
+        let mut info = Info {
+            version: "0.1.0",
+            git_commit: "3f2c1ab8d2e5c1f0a3b6c9d8e7f6a5b4c3d2e1f0",
+            build_date: "2020-06-01T12:00:00Z",
+        };
+        assert_eq!("0.1.0+3f2c1ab", info.full_version());
+        assert_eq!(
+            "0.1.0+3f2c1ab (built 2020-06-01T12:00:00Z)",
+            info.to_string()
+        );
+        info.git_commit = "unknown";
+        assert_eq!("0.1.0", info.full_version());
+        assert_eq!(
+            serde_json::json!({
+                "version": "0.1.0",
+                "gitCommit": "unknown",
+                "buildDate": "2020-06-01T12:00:00Z",
+            }),
+            serde_json::to_value(info).unwrap()
+        );
+    }
+}
@@ -1,26 +1,53 @@
 //! A module for use in managing volumes in providers. Use of this module is not mandatory to create
 //! a Provider, but it does provide common implementation logic for supported volume providers.
+//!
+//! Volumes work the same on Linux, macOS and Windows hosts. Secrets and config maps are
+//! written to plain files in the volume directory, as there is no tmpfs on every host,
+//! so the volume directory should only be readable by the Kubelet. The `defaultMode` of
+//! their volumes sets the permissions of the files where the host has Unix permissions.
+//! Windows only has a read only flag, which is set when the mode doesn't let the owner
+//! write. Keys that can't be used as a file name on the host, such as `CON` or `a:b` on
+//! Windows, fail the volume rather than being written somewhere else.
+//!
+//! `emptyDir` volumes are directories in the pod's volumes directory that keep their
+//! contents until the pod is gone. With no tmpfs, those with the `Memory` medium are
+//! kept on disk too. The files in them count towards the pod's `ephemeral-storage`.
 use std::collections::HashMap;
+use std::fs::Permissions;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use k8s_openapi::api::core::v1::Volume as KubeVolume;
-use k8s_openapi::api::core::v1::{ConfigMap, Secret};
 use k8s_openapi::ByteString;
-use kube::api::Api;
+use kube::error::ErrorResponse;
 use log::{debug, error};
+use tokio::io::AsyncWriteExt;
 
+use crate::concurrency::Operation;
+use crate::error::PodSyncError;
+use crate::object_manager::{get_config_map, get_secret};
 use crate::Pod;
 
+/// The mode of the files of secret and config map volumes that don't set a `defaultMode`
+const DEFAULT_MODE: u32 = 0o644;
+
+/// Names Windows reserves for devices, which can't be used as file names even with an
+/// extension
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
 #[derive(Debug)]
 enum VolumeType {
     ConfigMap,
     Secret,
     HostPath,
+    EmptyDir,
 }
 
-/// A smart wrapper around the location of a volume on the host system. If this is a ConfigMap or
-/// Secret volume, dropping this reference will clean up the temporary volume. [AsRef] and
+/// A smart wrapper around the location of a volume on the host system. If this is a ConfigMap,
+/// Secret or EmptyDir volume, dropping this reference will clean up the temporary volume. [AsRef] and
 /// [std::ops::Deref] are implemented for this type so you can still use it like a normal PathBuf
 #[derive(Debug)]
 pub struct VolumeRef {
@@ -32,19 +59,30 @@ impl VolumeRef {
     /// Resolves the volumes for a pod, including preparing temporary directories containing the
     /// contents of secrets and configmaps. Returns a HashMap of volume names to a PathBuf for the
     /// directory where the volume is mounted
+    ///
+    /// Volumes are set up in the pod's [volumes directory](crate::pod_dirs::PodDirs::volumes)
+    /// in the given data directory of the Kubelet. Note that this used to be the directory
+    /// the volumes of every pod were set up in, which providers that still pass it should
+    /// change to the data directory, and remove with
+    /// [`remove_legacy_dirs`](crate::pod_dirs::remove_legacy_dirs).
+    ///
+    /// A missing secret or configmap leaves its volume empty if the volume is optional, and
+    /// otherwise fails with a [`PodSyncError::ContainerConfig`] so the pod is retried until it
+    /// appears
     pub async fn volumes_from_pod(
-        volume_dir: &PathBuf,
+        data_dir: &Path,
         pod: &Pod,
         client: &kube::Client,
     ) -> anyhow::Result<HashMap<String, Self>> {
-        let base_path = volume_dir.join(pod_dir_name(pod));
+        let base_path = pod.data_dirs(data_dir).volumes();
         tokio::fs::create_dir_all(&base_path).await?;
         if let Some(vols) = pod.volumes() {
             let volumes = vols.iter().map(|v| {
                 let mut host_path = base_path.clone();
                 host_path.push(&v.name);
                 async move {
-                    let volume_type = configure(v, pod.namespace(), client, &host_path).await?;
+                    let setup = configure(v, pod, client, &host_path);
+                    let volume_type = pod.concurrency().run(Operation::VolumeSetup, setup).await?;
                     Ok((
                         v.name.to_owned(),
                         // Every other volume type should mount to the given host_path except for a
@@ -88,13 +126,16 @@ impl Deref for VolumeRef {
 
 impl Drop for VolumeRef {
     fn drop(&mut self) {
-        if matches!(self.volume_type, VolumeType::ConfigMap | VolumeType::Secret) {
+        if matches!(
+            self.volume_type,
+            VolumeType::ConfigMap | VolumeType::Secret | VolumeType::EmptyDir
+        ) {
             // TODO: Currently there is no way to do this async (though there is an async destructors proposal)
             debug!(
                 "deleting {:?} directory {:?}",
                 self.volume_type, self.host_path
             );
-            std::fs::remove_dir_all(&self.host_path).unwrap_or_else(|e| {
+            remove_dir(&self.host_path).unwrap_or_else(|e| {
                 error!(
                     "unable to delete directory {:?} on volume cleanup: {:?}",
                     self.host_path, e
@@ -108,7 +149,7 @@ impl Drop for VolumeRef {
 /// Because it isn't a HashMap, we need to check all fields individually
 async fn configure(
     vol: &KubeVolume,
-    namespace: &str,
+    pod: &Pod,
     client: &kube::Client,
     path: &PathBuf,
 ) -> anyhow::Result<VolumeType> {
@@ -117,7 +158,9 @@ async fn configure(
             &cm.name
                 .as_ref()
                 .ok_or_else(|| anyhow::anyhow!("no configmap name was given"))?,
-            namespace,
+            cm.optional.unwrap_or(false),
+            mode(cm.default_mode),
+            pod,
             client,
             path,
         )
@@ -127,7 +170,9 @@ async fn configure(
             &s.secret_name
                 .as_ref()
                 .ok_or_else(|| anyhow::anyhow!("no secret name was given"))?,
-            namespace,
+            s.optional.unwrap_or(false),
+            mode(s.default_mode),
+            pod,
             client,
             path,
         )
@@ -136,27 +181,44 @@ async fn configure(
         // Check the the directory exists on the host
         tokio::fs::metadata(&hostpath.path).await?;
         Ok(VolumeType::HostPath)
+    } else if vol.empty_dir.is_some() {
+        // The pod's directory is new for every pod, so anything already in the volume
+        // was written by the pod's containers and is kept
+        tokio::fs::create_dir_all(path).await?;
+        Ok(VolumeType::EmptyDir)
     } else {
         Err(anyhow::anyhow!(
-            "Unsupported volume type. Currently supported types: ConfigMap, Secret, and HostPath"
+            "Unsupported volume type. Currently supported types: ConfigMap, Secret, HostPath, and EmptyDir"
         ))
     }
 }
 
+/// Fill the volume with the keys of a secret. A missing optional secret leaves the volume
+/// empty
 async fn populate_from_secret(
     name: &str,
-    namespace: &str,
+    optional: bool,
+    mode: u32,
+    pod: &Pod,
     client: &kube::Client,
     path: &PathBuf,
 ) -> anyhow::Result<VolumeType> {
-    tokio::fs::create_dir_all(path).await?;
-    let secret_client: Api<Secret> = Api::namespaced(client.clone(), namespace);
-    let secret = secret_client.get(name).await?;
+    create_empty_dir(path).await?;
+    let secret = match get_secret(client, pod, name).await {
+        Ok(secret) => secret,
+        Err(kube::Error::Api(ErrorResponse { code: 404, .. })) if optional => {
+            return Ok(VolumeType::Secret)
+        }
+        Err(e) => return Err(config_error("secret", name, e)),
+    };
     let data = secret.data.unwrap_or_default();
-    let data = data.iter().map(|(key, ByteString(data))| async move {
-        let file_path = path.join(key);
-        tokio::fs::write(file_path, &data).await
-    });
+    let data = data
+        .iter()
+        .map(|(key, ByteString(data))| Ok((key_path(path, "secret", key)?, data)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let data = data
+        .into_iter()
+        .map(|(file_path, data)| write_key(file_path, data, mode));
     futures::future::join_all(data)
         .await
         .into_iter()
@@ -165,36 +227,306 @@ async fn populate_from_secret(
     Ok(VolumeType::Secret)
 }
 
+/// Fill the volume with the keys of a config map. A missing optional config map leaves
+/// the volume empty
 async fn populate_from_config_map(
     name: &str,
-    namespace: &str,
+    optional: bool,
+    mode: u32,
+    pod: &Pod,
     client: &kube::Client,
     path: &PathBuf,
 ) -> anyhow::Result<VolumeType> {
-    tokio::fs::create_dir_all(path).await?;
-    let cm_client: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
-    let config_map = cm_client.get(name).await?;
+    create_empty_dir(path).await?;
+    let config_map = match get_config_map(client, pod, name).await {
+        Ok(config_map) => config_map,
+        Err(kube::Error::Api(ErrorResponse { code: 404, .. })) if optional => {
+            return Ok(VolumeType::ConfigMap)
+        }
+        Err(e) => return Err(config_error("configmap", name, e)),
+    };
     let binary_data = config_map.binary_data.unwrap_or_default();
-    let binary_data = binary_data.iter().map(|(key, data)| async move {
-        let file_path = path.join(key);
-        tokio::fs::write(file_path, &data.0).await
-    });
-    let binary_data = futures::future::join_all(binary_data);
     let data = config_map.data.unwrap_or_default();
-    let data = data.iter().map(|(key, data)| async move {
-        let file_path = path.join(key);
-        tokio::fs::write(file_path, data).await
-    });
-    let data = futures::future::join_all(data);
-    let (binary_data, data) = futures::future::join(binary_data, data).await;
-    binary_data
+    let files = binary_data
+        .iter()
+        .map(|(key, data)| (key, data.0.as_slice()))
+        .chain(data.iter().map(|(key, data)| (key, data.as_bytes())))
+        .map(|(key, data)| Ok((key_path(path, "configmap", key)?, data)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let files = files
+        .into_iter()
+        .map(|(file_path, data)| write_key(file_path, data, mode));
+    futures::future::join_all(files)
+        .await
         .into_iter()
-        .chain(data)
         .collect::<tokio::io::Result<_>>()?;
 
     Ok(VolumeType::ConfigMap)
 }
 
-fn pod_dir_name(pod: &Pod) -> String {
-    format!("{}-{}", pod.name(), pod.namespace())
+/// An object a volume requires could not be fetched. The pod is retried, as the object
+/// may appear later
+fn config_error(kind: &str, name: &str, error: kube::Error) -> anyhow::Error {
+    let error = match error {
+        kube::Error::Api(ErrorResponse { code: 404, .. }) => {
+            anyhow::anyhow!("{} {:?} not found", kind, name)
+        }
+        e => anyhow::anyhow!("unable to fetch {} {:?}: {}", kind, name, e),
+    };
+    PodSyncError::ContainerConfig(error).into()
+}
+
+/// The mode of the files of a volume with the given `defaultMode`
+fn mode(default_mode: Option<i32>) -> u32 {
+    default_mode
+        .map(|m| m as u32 & 0o777)
+        .unwrap_or(DEFAULT_MODE)
+}
+
+/// Returns the file a key of a secret or config map is written to in the volume
+/// directory
+fn key_path(dir: &Path, kind: &str, key: &str) -> anyhow::Result<PathBuf> {
+    check_file_name(key, cfg!(windows)).map_err(|reason| {
+        anyhow::anyhow!(
+            "{} key {:?} can't be used as a file name: {}",
+            kind,
+            key,
+            reason
+        )
+    })?;
+    Ok(dir.join(key))
+}
+
+/// Checks that a key is a plain file name on the host, so it can't be written outside
+/// of the volume directory or to a device. Windows is checked for with `windows`, so
+/// its rules can be tested anywhere
+fn check_file_name(key: &str, windows: bool) -> Result<(), &'static str> {
+    if key.is_empty() || key == "." || key == ".." {
+        return Err("it is not a file name");
+    }
+    if key.contains('/') {
+        return Err("it contains a path separator");
+    }
+    if !windows {
+        return Ok(());
+    }
+    if key.contains('\\') {
+        return Err("it contains a path separator");
+    }
+    if key
+        .chars()
+        .any(|c| c.is_control() || "<>:\"|?*".contains(c))
+    {
+        return Err("it contains a character Windows doesn't allow in file names");
+    }
+    if key.ends_with(&['.', ' '][..]) {
+        return Err("Windows doesn't allow file names ending in a dot or space");
+    }
+    let stem = key.split('.').next().unwrap_or_default().trim_end();
+    if WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+    {
+        return Err("Windows reserves it for a device");
+    }
+    Ok(())
+}
+
+/// Create a volume directory, removing anything left in it from before, such as by a
+/// Kubelet that didn't stop cleanly
+async fn create_empty_dir(path: &Path) -> std::io::Result<()> {
+    let dir = path.to_owned();
+    tokio::task::spawn_blocking(move || remove_dir(&dir))
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
+    tokio::fs::create_dir_all(path).await
+}
+
+/// Remove a volume directory and its files, if it exists. Windows doesn't remove read only
+/// files, so they are made writable first
+fn remove_dir(path: &Path) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    #[cfg(windows)]
+    {
+        for entry in entries {
+            let path = entry?.path();
+            let mut permissions = std::fs::metadata(&path)?.permissions();
+            if permissions.readonly() {
+                permissions.set_readonly(false);
+                std::fs::set_permissions(&path, permissions)?;
+            }
+        }
+    }
+    #[cfg(not(windows))]
+    drop(entries);
+    std::fs::remove_dir_all(path)
+}
+
+/// Write a key of a secret or config map to its file with the given mode. The file is
+/// only readable by its owner until it has its mode, so nobody else can read the key in
+/// between
+async fn write_key(path: PathBuf, data: &[u8], mode: u32) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = tokio::fs::OpenOptions::from(options).open(&path).await?;
+    file.write_all(data).await?;
+    file.flush().await?;
+    drop(file);
+    let current = tokio::fs::metadata(&path).await?.permissions();
+    tokio::fs::set_permissions(&path, permissions(current, mode)).await
+}
+
+/// The permissions of a file with the given mode
+#[cfg(unix)]
+fn permissions(_current: Permissions, mode: u32) -> Permissions {
+    std::os::unix::fs::PermissionsExt::from_mode(mode)
+}
+
+/// The permissions of a file with the given mode. Only the owner's write permission
+/// means anything without Unix permissions
+#[cfg(not(unix))]
+fn permissions(mut current: Permissions, mode: u32) -> Permissions {
+    current.set_readonly(mode & 0o200 == 0);
+    current
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{fake_pod, MockApiServer};
+
+    #[test]
+    fn test_check_file_name() {
+        for windows in &[false, true] {
+            assert!(check_file_name("config.json", *windows).is_ok());
+            assert!(check_file_name("..", *windows).is_err());
+            assert!(check_file_name("", *windows).is_err());
+            assert!(check_file_name("../passwd", *windows).is_err());
+        }
+        for key in &[
+            "con",
+            "NUL.txt",
+            "lpt1 .log",
+            "a\\b",
+            "a:b",
+            "what?",
+            "trailing.",
+        ] {
+            assert!(check_file_name(key, false).is_ok(), "{}", key);
+            assert!(check_file_name(key, true).is_err(), "{}", key);
+        }
+        assert!(check_file_name("console", true).is_ok());
+        assert!(check_file_name(".hidden", true).is_ok());
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(0o644, mode(None));
+        assert_eq!(0o400, mode(Some(0o400)));
+        assert_eq!(0o755, mode(Some(0o4755)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_key() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("krustlet-write-key-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("password");
+        write_key(path.clone(), b"hunter2", 0o644).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o644, mode & 0o777);
+
+        // Writing the key again replaces it
+        write_key(path.clone(), b"new", 0o600).await.unwrap();
+        assert_eq!(b"new".to_vec(), std::fs::read(&path).unwrap());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_secret_volume() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/default/secrets",
+            serde_json::json!({
+                "metadata": { "name": "creds", "namespace": "default" },
+                "data": { "password": "aHVudGVyMg==" },
+            }),
+        );
+        let dir = std::env::temp_dir().join(format!("krustlet-volumes-{}", std::process::id()));
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.spec.as_mut().unwrap().volumes =
+            Some(vec![serde_json::from_value(serde_json::json!({
+                "name": "creds",
+                "secret": { "secretName": "creds", "defaultMode": 0o400 },
+            }))
+            .unwrap()]);
+        let pod = Pod::new(kube_pod);
+
+        // Files left over from before are removed
+        let host_path = pod.data_dirs(&dir).volumes().join("creds");
+        std::fs::create_dir_all(&host_path).unwrap();
+        std::fs::write(host_path.join("stale"), "").unwrap();
+
+        let volumes = VolumeRef::volumes_from_pod(&dir, &pod, &server.client())
+            .await
+            .unwrap();
+        let password = host_path.join("password");
+        assert_eq!("hunter2", std::fs::read_to_string(&password).unwrap());
+        assert!(!host_path.join("stale").exists());
+        // The values of the secret are kept out of the pod's errors
+        assert_eq!(
+            "bad password [REDACTED]",
+            crate::redact::redact(&pod, "bad password hunter2")
+        );
+        crate::redact::forget(&pod);
+        let permissions = std::fs::metadata(&password).unwrap().permissions();
+        assert!(permissions.readonly());
+        #[cfg(unix)]
+        assert_eq!(
+            0o400,
+            std::os::unix::fs::PermissionsExt::mode(&permissions) & 0o777
+        );
+
+        // Read only files are removed with the volume
+        drop(volumes);
+        assert!(!host_path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_dir_volume() {
+        let server = MockApiServer::start().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("krustlet-empty-dir-{}", std::process::id()));
+        let mut kube_pod = fake_pod("foo", "default");
+        kube_pod.spec.as_mut().unwrap().volumes =
+            Some(vec![serde_json::from_value(serde_json::json!({
+                "name": "scratch",
+                "emptyDir": { "sizeLimit": "1Mi" },
+            }))
+            .unwrap()]);
+        let pod = Pod::new(kube_pod);
+
+        // What the containers wrote before a restart is kept
+        let host_path = pod.data_dirs(&dir).volumes().join("scratch");
+        std::fs::create_dir_all(&host_path).unwrap();
+        std::fs::write(host_path.join("state"), "").unwrap();
+
+        let volumes = VolumeRef::volumes_from_pod(&dir, &pod, &server.client())
+            .await
+            .unwrap();
+        assert_eq!(&host_path, volumes["scratch"].as_ref());
+        assert!(host_path.join("state").exists());
+        drop(volumes);
+        assert!(!host_path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,171 @@
+//! Support for running the Kubelet as a Windows service
+//!
+//! [`run_as_service`] connects to the service control manager and runs the Kubelet
+//! until the service is stopped, logging to the Windows event log. When the
+//! process was not started by the service control manager, it returns
+//! `Ok(false)` so the caller can run the Kubelet normally from a console.
+//!
+//! # Example
+//! ```rust,no_run
+//! use std::future::Future;
+//!
+//! async fn run(shutdown: impl Future<Output = ()> + Send + 'static) -> anyhow::Result<()> {
+//!     // Build a Kubelet and call `start_with_shutdown(shutdown)` ...
+//!     # Ok(())
+//! }
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! if !kubelet::windows::run_as_service("krustlet", run).await? {
+//!     run(futures::future::pending()).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::ffi::OsString;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// The error returned when the dispatcher is started by a process that is not a service
+const ERROR_FAILED_SERVICE_CONTROLLER_CONNECT: i32 = 1063;
+
+/// A future that completes when the service has been asked to stop
+pub type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+type BoxedRun = Box<
+    dyn FnOnce(ShutdownSignal) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> + Send,
+>;
+
+/// The service to run, stored for the service main function which is called by Windows
+struct Service {
+    name: &'static str,
+    run: BoxedRun,
+    runtime: Handle,
+}
+
+lazy_static::lazy_static! {
+    static ref SERVICE: Mutex<Option<Service>> = Mutex::new(None);
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Run the given function as the Windows service with the given name.
+///
+/// The function is passed a [`ShutdownSignal`] that completes when the service is
+/// stopped or the machine is shutting down, which should be given to
+/// [`Kubelet::start_with_shutdown`](crate::Kubelet::start_with_shutdown). Log output is
+/// sent to the event log under the service name, so no logger should be installed by the
+/// function when running as a service.
+///
+/// Returns `Ok(false)` without running anything if the process was not started by the
+/// service control manager.
+pub async fn run_as_service<F, Fut>(name: &'static str, run: F) -> anyhow::Result<bool>
+where
+    F: FnOnce(ShutdownSignal) -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    *SERVICE.lock().unwrap() = Some(Service {
+        name,
+        run: Box::new(move |signal| Box::pin(run(signal))),
+        runtime: Handle::current(),
+    });
+    // The dispatcher blocks until the service has stopped, calling the service main
+    // function on a thread of its own
+    let result =
+        tokio::task::spawn_blocking(move || service_dispatcher::start(name, ffi_service_main))
+            .await?;
+    match result {
+        Ok(()) => Ok(true),
+        Err(windows_service::Error::Winapi(e))
+            if e.raw_os_error() == Some(ERROR_FAILED_SERVICE_CONTROLLER_CONNECT) =>
+        {
+            SERVICE.lock().unwrap().take();
+            Ok(false)
+        }
+        Err(e) => Err(anyhow::anyhow!("unable to start service dispatcher: {}", e)),
+    }
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let service = match SERVICE.lock().unwrap().take() {
+        Some(s) => s,
+        None => return,
+    };
+    if let Err(e) = winlog::init(service.name) {
+        eprintln!("Unable to initialize event log logging: {}", e);
+    }
+    if let Err(e) = run_service(service) {
+        error!("Service failed: {:#}", e);
+    }
+}
+
+fn run_service(service: Service) -> anyhow::Result<()> {
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    let mut shutdown_sender = Some(shutdown_sender);
+    let status_handle =
+        service_control_handler::register(service.name, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                info!("Received {:?} from the service control manager", control);
+                if let Some(sender) = shutdown_sender.take() {
+                    let _ = sender.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+    status_handle.set_service_status(status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        0,
+    ))?;
+
+    let signal: ShutdownSignal = Box::pin(async move {
+        let _ = shutdown_receiver.await;
+    });
+    let (done_sender, done_receiver) = std::sync::mpsc::channel();
+    let run = service.run;
+    service.runtime.spawn(async move {
+        let _ = done_sender.send(run(signal).await);
+    });
+    let result = done_receiver
+        .recv()
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("the Kubelet task was cancelled")));
+
+    let exit_code = match &result {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("Kubelet exited with an error: {:#}", e);
+            1
+        }
+    };
+    status_handle.set_service_status(status(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    ))?;
+    Ok(())
+}
+
+fn status(state: ServiceState, accept: ServiceControlAccept, exit_code: u32) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: accept,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
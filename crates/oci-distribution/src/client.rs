@@ -3,17 +3,26 @@
 //! *Note*: This client is very feature poor. We hope to expand this to be a complete
 //! OCI distribution client in the future.
 
+use crate::compression::{copy_layer, verify, Compression, LayerSize};
+use crate::credentials::{CredentialHelper, Credentials};
+use crate::delta::{apply, sha256_digest, Referrers, DELTA_LAYER_MEDIA_TYPE};
 use crate::errors::*;
-use crate::manifest::OciManifest;
+use crate::manifest::{diff_ids, OciDescriptor, OciManifest};
 use crate::Reference;
 
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
 use anyhow::Context;
+use bytes::Bytes;
 use futures_util::future;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{Stream, StreamExt};
 use hyperx::header::Header;
 use log::debug;
 use reqwest::header::HeaderMap;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::AsyncWrite;
 use www_authenticate::{Challenge, ChallengeFields, RawChallenge, WwwAuthenticate};
 
 const OCI_VERSION_KEY: &str = "Docker-Distribution-Api-Version";
@@ -32,44 +41,190 @@ const OCI_VERSION_KEY: &str = "Docker-Distribution-Api-Version";
 ///
 /// For true anonymous access, you can skip `auth()`. This is not recommended
 /// unless you are sure that the remote registry does not require Oauth2.
-#[derive(Default)]
+///
+/// A client keeps a pool of connections to each registry it talks to, so pulls
+/// reuse connections instead of setting up new ones. Cloning a client is cheap, and
+/// clones share the connection pool and the tokens, so a single client should be
+/// shared by everything that pulls images. Images can be pulled concurrently.
+///
+/// Registries that hand out short-lived credentials, such as those of cloud providers,
+/// are authenticated with a [`CredentialHelper`] added with
+/// [`Client::with_credential_helper`]. Tokens and credentials are fetched again before
+/// the pull after they expire.
+#[derive(Clone)]
 pub struct Client {
     config: ClientConfig,
-    token: Option<RegistryToken>,
+    /// The tokens for each repository, keyed by registry and repository
+    tokens: Arc<RwLock<HashMap<String, RegistryToken>>>,
     client: reqwest::Client,
+    /// The clients for registries with their own TLS settings, keyed by registry
+    registry_clients: HashMap<String, reqwest::Client>,
+    /// The clients for the token servers of registries with their own TLS settings,
+    /// keyed by registry. They trust the registry's CAs, but don't present its client
+    /// certificate
+    token_clients: HashMap<String, reqwest::Client>,
+    credentials: Credentials,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(ClientConfig::default())
+    }
 }
 
 impl Client {
     /// Create a new client with the supplied config
     pub fn new(config: ClientConfig) -> Self {
+        let client = http_client(&config, None);
+        let registry_clients = config
+            .registry_tls
+            .iter()
+            .map(|(registry, tls)| (registry.clone(), http_client(&config, Some(tls))))
+            .collect();
+        let token_clients = config
+            .registry_tls
+            .iter()
+            .map(|(registry, tls)| {
+                let tls = RegistryTlsConfig {
+                    root_certificates: tls.root_certificates.clone(),
+                    identity: None,
+                };
+                (registry.clone(), http_client(&config, Some(&tls)))
+            })
+            .collect();
         Self {
             config,
-            token: None,
-            client: reqwest::Client::new(),
+            tokens: Default::default(),
+            client,
+            registry_clients,
+            token_clients,
+            credentials: Credentials::default(),
+        }
+    }
+
+    /// Authenticate to the registries the helper handles with the credentials it
+    /// fetches. Helpers are asked in the order they were added
+    pub fn with_credential_helper<H: CredentialHelper + 'static>(mut self, helper: H) -> Self {
+        self.credentials.add(Arc::new(helper));
+        self
+    }
+
+    /// Authenticate to the cloud registries the crate was built with helpers for, with
+    /// the helpers set up from the node's environment, see [`credentials`]. The helpers
+    /// only fetch credentials for the registries of their cloud, so they can all be added
+    /// on any node
+    ///
+    /// [`credentials`]: crate::credentials
+    #[allow(clippy::let_and_return)]
+    pub fn with_cloud_credential_helpers(self) -> Self {
+        #[cfg(feature = "ecr")]
+        let client = match crate::credentials::ecr::EcrHelper::from_env() {
+            Ok(helper) => self.with_credential_helper(helper),
+            Err(e) => {
+                log::warn!("Unable to find AWS credentials for ECR registries: {}", e);
+                self
+            }
+        };
+        #[cfg(not(feature = "ecr"))]
+        let client = self;
+        #[cfg(feature = "gcr")]
+        let client = client.with_credential_helper(crate::credentials::gcr::GcrHelper::new());
+        #[cfg(feature = "acr")]
+        let client = {
+            let helper = crate::credentials::acr::AcrHelper::new();
+            // Nodes with several identities pick one like the Azure SDKs do
+            match std::env::var("AZURE_CLIENT_ID") {
+                Ok(client_id) => client.with_credential_helper(helper.with_client_id(&client_id)),
+                Err(_) => client.with_credential_helper(helper),
+            }
+        };
+        client
+    }
+
+    /// The HTTP client for requests to the given registry
+    fn http(&self, registry: &str) -> &reqwest::Client {
+        self.registry_clients.get(registry).unwrap_or(&self.client)
+    }
+
+    /// The HTTP client for requests to the token server of the given registry at the
+    /// realm URL. The registry's client certificate is only presented to the registry's
+    /// own host, as the token server can be another server, which is sent the client
+    /// certificate of its own TLS settings if it has them
+    fn token_http(&self, registry: &str, realm: &str) -> &reqwest::Client {
+        let host = reqwest::Url::parse(realm).ok().and_then(|url| {
+            url.host_str().map(|host| match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_owned(),
+            })
+        });
+        match host {
+            Some(host) if host == registry || self.registry_clients.contains_key(&host) => {
+                self.http(&host)
+            }
+            _ => self.token_clients.get(registry).unwrap_or(&self.client),
         }
     }
 
     /// Pull an image and return the bytes
     ///
-    /// The client will check if it's already been authenticated and if
-    /// not will attempt to do.
-    pub async fn pull_image(&mut self, image: &Reference) -> anyhow::Result<Vec<u8>> {
+    /// The client will check if it's already been authenticated for the image's
+    /// repository and if not will attempt to do. Compressed layers are decompressed,
+    /// and checked against the digests of the image config if it lists them.
+    pub async fn pull_image(&self, image: &Reference) -> anyhow::Result<Vec<u8>> {
+        Ok(self.pull_image_with_digest(image, None).await?.0)
+    }
+
+    /// Pull an image as [`Client::pull_image`] does, or as
+    /// [`Client::pull_image_from_base`] does when a base module is given, returning the
+    /// module along with the digest of the image's manifest, which identifies the image
+    pub async fn pull_image_with_digest(
+        &self,
+        image: &Reference,
+        base: Option<&[u8]>,
+    ) -> anyhow::Result<(Vec<u8>, String)> {
+        if let Some(base) = base {
+            match self.pull_delta(image, base).await {
+                Ok(Some(pulled)) => return Ok(pulled),
+                Ok(None) => debug!("No delta for image {:?}, pulling the whole image", image),
+                Err(e) => debug!(
+                    "Unable to pull delta for image {:?}, pulling the whole image: {}",
+                    image, e
+                ),
+            }
+        }
+        self.pull_whole_image(image).await
+    }
+
+    async fn pull_whole_image(&self, image: &Reference) -> anyhow::Result<(Vec<u8>, String)> {
         debug!("Pulling image: {:?}", image);
-        if self.token.is_none() {
+        if self.needs_auth(image) {
             self.auth(image, None).await?;
         }
 
-        let manifest = self.pull_manifest(image).await?;
+        let url = image.to_v2_manifest_url(self.config.protocol.as_str());
+        let (manifest, digest) = self.fetch_manifest(image, &url).await?;
 
-        let layers = manifest.layers.into_iter().map(|layer| {
-            // This avoids moving `self` which is &mut Self
-            // into the async block. We only want to capture
-            // as &Self
+        // Only the config knows the digests of uncompressed layers
+        let compressed = manifest
+            .layers
+            .iter()
+            .any(|layer| Compression::from_media_type(&layer.media_type) != Compression::None);
+        let mut layer_diff_ids = None;
+        if compressed {
+            let mut config = Vec::new();
+            self.pull_layer(image, &manifest.config.digest, &mut config)
+                .await?;
+            layer_diff_ids = diff_ids(&config).filter(|ids| ids.len() == manifest.layers.len());
+        }
+
+        let layers = manifest.layers.into_iter().enumerate().map(|(i, layer)| {
             let this = &self;
+            let diff_id = layer_diff_ids.as_ref().map(|ids| ids[i].clone());
             async move {
                 let mut out: Vec<u8> = Vec::new();
                 debug!("Pulling image layer");
-                this.pull_layer(image, &layer.digest, &mut out).await?;
+                this.pull_layer_decompressed(image, &layer, diff_id.as_deref(), &mut out)
+                    .await?;
                 Ok::<_, anyhow::Error>(out)
             }
         });
@@ -81,7 +236,112 @@ impl Client {
             result = layer;
         }
 
-        Ok(result)
+        Ok((result, digest))
+    }
+
+    /// Pull an image whose module the caller has an earlier version of, `base`
+    ///
+    /// When the registry stores a [delta](crate::delta) from the base module to the
+    /// image's module, only the delta is downloaded, and the module it builds is checked
+    /// against the digest of the image's layer. Otherwise, or if the delta can't be
+    /// pulled, the whole image is pulled as [`Client::pull_image`] does.
+    pub async fn pull_image_from_base(
+        &self,
+        image: &Reference,
+        base: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        Ok(self.pull_image_with_digest(image, Some(base)).await?.0)
+    }
+
+    /// Build the image's module from the base module and a delta, if the image is a
+    /// single uncompressed module and the registry stores a delta from the base. The
+    /// module is returned with the digest of the image's manifest
+    async fn pull_delta(
+        &self,
+        image: &Reference,
+        base: &[u8],
+    ) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+        if self.needs_auth(image) {
+            self.auth(image, None).await?;
+        }
+        let url = image.to_v2_manifest_url(self.config.protocol.as_str());
+        let (manifest, manifest_digest) = self.fetch_manifest(image, &url).await?;
+        // The module's size bounds what a delta can build, so it has to be known
+        let layer = match manifest.layers.as_slice() {
+            [layer]
+                if Compression::from_media_type(&layer.media_type) == Compression::None
+                    && layer.size > 0 =>
+            {
+                layer
+            }
+            _ => return Ok(None),
+        };
+
+        let referrers = self.pull_referrers(image, &manifest_digest).await?;
+        let delta_digest = match referrers.delta_from(&sha256_digest(base)) {
+            Some(digest) => digest,
+            None => return Ok(None),
+        };
+        let url = image.to_v2_manifest_digest_url(self.config.protocol.as_str(), delta_digest);
+        let (delta_manifest, _) = self.fetch_manifest(image, &url).await?;
+        let delta_layer = delta_manifest
+            .layers
+            .iter()
+            .find(|l| l.media_type == DELTA_LAYER_MEDIA_TYPE)
+            .ok_or_else(|| anyhow::anyhow!("delta {} has no delta layer", delta_digest))?;
+
+        let mut delta = Vec::new();
+        self.pull_layer(image, &delta_layer.digest, &mut delta)
+            .await?;
+        let module = apply(base, &delta, layer.size as u64)?;
+        verify(&module, &layer.digest, "module built from the delta")?;
+        debug!(
+            "Built image {:?} from a delta of {} bytes instead of pulling {} bytes",
+            image,
+            delta.len(),
+            layer.size
+        );
+        Ok(Some((module, manifest_digest)))
+    }
+
+    /// List the artifacts that refer to the manifest. Registries without the referrers
+    /// API have none
+    async fn pull_referrers(
+        &self,
+        image: &Reference,
+        manifest_digest: &str,
+    ) -> anyhow::Result<Referrers> {
+        let url = image.to_v2_referrers_url(self.config.protocol.as_str(), manifest_digest);
+        let mut headers = self.auth_headers(image);
+        headers.insert(
+            "Accept",
+            "application/vnd.oci.image.index.v1+json".parse().unwrap(),
+        );
+        let res = self
+            .http(image.registry())
+            .get(&url)
+            .headers(headers)
+            .send()
+            .await?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(res.json().await?),
+            reqwest::StatusCode::NOT_FOUND => Ok(Referrers::default()),
+            s => Err(anyhow::anyhow!(
+                "Unable to list referrers at {}: {}",
+                url,
+                s
+            )),
+        }
+    }
+
+    /// Returns true if there is no token for the image's repository, or the token or
+    /// the credentials it was granted for expire soon
+    fn needs_auth(&self, image: &Reference) -> bool {
+        let token = match self.tokens.read().unwrap().get(&token_key(image)) {
+            Some(token) => !token.expiring(),
+            None => false,
+        };
+        !token || self.credentials.needs_refresh(image.registry())
     }
 
     /// According to the v2 specification, 200 and 401 error codes MUST return the
@@ -92,7 +352,7 @@ impl Client {
     /// v2 is not supported.
     pub async fn version(&self, host: &str) -> anyhow::Result<String> {
         let url = format!("{}://{}/v2/", self.config.protocol.as_str(), host);
-        let res = self.client.get(&url).send().await?;
+        let res = self.http(host).get(&url).send().await?;
         let dist_hdr = res.headers().get(OCI_VERSION_KEY);
         let version = dist_hdr
             .ok_or_else(|| anyhow::anyhow!("no header v2 found"))?
@@ -104,16 +364,19 @@ impl Client {
     /// Perform an OAuth v2 auth request if necessary.
     ///
     /// This performs authorization and then stores the token internally to be used
-    /// on other requests.
-    pub async fn auth(&mut self, image: &Reference, _secret: Option<&str>) -> anyhow::Result<()> {
+    /// on other requests for the image's repository. If a credential helper handles the
+    /// image's registry, the token is requested with its credentials, and registries
+    /// that only take basic authentication are sent the credentials on every request.
+    pub async fn auth(&self, image: &Reference, _secret: Option<&str>) -> anyhow::Result<()> {
         debug!("Authorzing for image: {:?}", image);
+        let credential = self.credentials.get(image.registry()).await;
         // The version request will tell us where to go.
         let url = format!(
             "{}://{}/v2/",
             self.config.protocol.as_str(),
             image.registry()
         );
-        let res = self.client.get(&url).send().await?;
+        let res = self.http(image.registry()).get(&url).send().await?;
         let dist_hdr = match res.headers().get(reqwest::header::WWW_AUTHENTICATE) {
             Some(h) => h,
             None => return Ok(()),
@@ -136,21 +399,27 @@ impl Client {
 
         // TODO: At some point in the future, we should support sending a secret to the
         // server for auth. This particular workflow is for read-only public auth.
+        // Token servers of internal registries are usually signed by the same CA, so
+        // they are trusted the same way
         debug!("Making authentication call to {}", realm);
-        let auth_res = self
-            .client
+        let mut auth_req = self
+            .token_http(image.registry(), realm)
             .get(realm)
-            .query(&[("service", service), ("scope", &pull_perms)])
-            .send()
-            .await?;
+            .query(&[("service", service), ("scope", &pull_perms)]);
+        if let Some(credential) = credential {
+            auth_req = auth_req.basic_auth(credential.username, Some(credential.password));
+        }
+        let auth_res = auth_req.send().await?;
 
         match auth_res.status() {
             reqwest::StatusCode::OK => {
                 let text = auth_res.text().await?;
                 debug!("Recevied response from auth request: {}", text);
-                let docker_token: RegistryToken = serde_json::from_str(&text)
-                    .context("Failed to decode registry token from auth request")?;
-                self.token = Some(docker_token);
+                let docker_token = RegistryToken::parse(&text)?;
+                self.tokens
+                    .write()
+                    .unwrap()
+                    .insert(token_key(image), docker_token);
                 debug!("Succesfully authorized for image '{:?}'", image);
                 Ok(())
             }
@@ -168,24 +437,52 @@ impl Client {
     /// use the bearer token. Otherwise, this will attempt an anonymous pull.
     pub async fn pull_manifest(&self, image: &Reference) -> anyhow::Result<OciManifest> {
         let url = image.to_v2_manifest_url(self.config.protocol.as_str());
+        let (manifest, _) = self.fetch_manifest(image, &url).await?;
+        Ok(manifest)
+    }
+
+    /// Fetch the digest of the manifest the image's reference points to, which changes
+    /// when a tag is moved to another image
+    pub async fn fetch_manifest_digest(&self, image: &Reference) -> anyhow::Result<String> {
+        if self.needs_auth(image) {
+            self.auth(image, None).await?;
+        }
+        let url = image.to_v2_manifest_url(self.config.protocol.as_str());
+        let (_, digest) = self.fetch_manifest(image, &url).await?;
+        Ok(digest)
+    }
+
+    /// Pull the manifest at the URL, returning it with its digest
+    async fn fetch_manifest(
+        &self,
+        image: &Reference,
+        url: &str,
+    ) -> anyhow::Result<(OciManifest, String)> {
         debug!("Pulling image manifest from {}", url);
-        let request = self.client.get(&url);
+        let request = self.http(image.registry()).get(url);
 
-        let res = request.headers(self.auth_headers()).send().await?;
+        let res = request.headers(self.auth_headers(image)).send().await?;
 
         // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
         // Obviously, HTTP servers are going to send other codes. This tries to catch the
         // obvious ones (200, 4XX, 5XX). Anything else is just treated as an error.
         match res.status() {
             reqwest::StatusCode::OK => {
+                let digest = res
+                    .headers()
+                    .get("Docker-Content-Digest")
+                    .and_then(|d| d.to_str().ok())
+                    .map(str::to_owned);
                 let text = res.text().await?;
                 debug!("Parsing response as OciManifest: {}", text);
-                Ok(serde_json::from_str(&text).with_context(|| {
+                let manifest = serde_json::from_str(&text).with_context(|| {
                     format!(
                         "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
                         image
                     )
-                })?)
+                })?;
+                let digest = digest.unwrap_or_else(|| sha256_digest(text.as_bytes()));
+                Ok((manifest, digest))
             }
             s if s.is_client_error() => {
                 // According to the OCI spec, we should see an error in the message body.
@@ -208,50 +505,272 @@ impl Client {
     /// the given digest. The image reference is used to find the
     /// repository and the registry, but it is not used to verify that
     /// the digest is a layer inside of the image. (The manifest is
-    /// used for that.)
+    /// used for that.) The layer is written as the registry stores it, and
+    /// its digest is checked once it has been written. The pull fails as soon as
+    /// the layer is larger than [`ClientConfig::max_layer_size`].
     pub async fn pull_layer<T: AsyncWrite + Unpin>(
         &self,
         image: &Reference,
         digest: &str,
-        mut out: T,
+        out: T,
     ) -> anyhow::Result<()> {
+        let chunks = self.blob_chunks(image, digest).await?;
+        let size = LayerSize {
+            compressed: None,
+            max_uncompressed: self.max_layer_size(),
+        };
+        copy_layer(chunks, Compression::None, digest, None, size, out).await
+    }
+
+    /// Pull a single layer from an OCI registry and decompress it.
+    ///
+    /// The layer is decompressed as it is downloaded, as its media type says. The
+    /// digest of the compressed content is checked against the descriptor and, when
+    /// `diff_id` is given, the digest of the decompressed content against it. The
+    /// pull fails as soon as the layer is larger than its descriptor says, or
+    /// decompresses to more than [`ClientConfig::max_layer_size`].
+    pub async fn pull_layer_decompressed<T: AsyncWrite + Unpin>(
+        &self,
+        image: &Reference,
+        layer: &OciDescriptor,
+        diff_id: Option<&str>,
+        out: T,
+    ) -> anyhow::Result<()> {
+        let chunks = self.blob_chunks(image, &layer.digest).await?;
+        let compression = Compression::from_media_type(&layer.media_type);
+        let size = LayerSize {
+            // Descriptors without a size don't say how large the layer is
+            compressed: if layer.size > 0 {
+                Some(layer.size as u64)
+            } else {
+                None
+            },
+            max_uncompressed: self.max_layer_size(),
+        };
+        copy_layer(chunks, compression, &layer.digest, diff_id, size, out).await
+    }
+
+    /// The most a layer can be once it is decompressed
+    fn max_layer_size(&self) -> u64 {
+        self.config.max_layer_size.unwrap_or(DEFAULT_MAX_LAYER_SIZE)
+    }
+
+    /// Start downloading a blob, returning the chunks of its content
+    async fn blob_chunks(
+        &self,
+        image: &Reference,
+        digest: &str,
+    ) -> anyhow::Result<impl Stream<Item = std::io::Result<Bytes>> + Send> {
         let url = image.to_v2_blob_url(self.config.protocol.as_str(), digest);
-        let mut stream = self
-            .client
+        let res = self
+            .http(image.registry())
             .get(&url)
-            .headers(self.auth_headers())
+            .headers(self.auth_headers(image))
             .send()
-            .await?
-            .bytes_stream();
-
-        while let Some(bytes) = stream.next().await {
-            out.write_all(&bytes?).await?;
+            .await?;
+        if !res.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Unable to pull blob {} from {}: {}",
+                digest,
+                url,
+                res.status()
+            ));
         }
-
-        Ok(())
+        Ok(res
+            .bytes_stream()
+            .map(|chunk| chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))))
     }
 
     /// Generate the headers necessary for authentication.
     ///
-    /// If the struct has Some(bearer), this will insert the bearer token in an
-    /// Authorization header. It will also set the Accept header, which must
-    /// be set on all OCI Registry request.
-    fn auth_headers(&self) -> HeaderMap {
+    /// If the client has a token for the image's repository, this will insert the
+    /// bearer token in an Authorization header, or else the credentials of the
+    /// registry if a credential helper fetched them. It will also set the Accept header,
+    /// which must be set on all OCI Registry request.
+    fn auth_headers(&self, image: &Reference) -> HeaderMap {
         let mut headers = HeaderMap::new();
         headers.insert("Accept", "application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.oci.image.manifest.v1+json".parse().unwrap());
 
-        if let Some(bearer) = self.token.as_ref() {
+        if let Some(bearer) = self.tokens.read().unwrap().get(&token_key(image)) {
             headers.insert("Authorization", bearer.bearer_token().parse().unwrap());
+        } else if let Some(credential) = self.credentials.cached(image.registry()) {
+            headers.insert("Authorization", credential.basic_auth().parse().unwrap());
         }
         headers
     }
 }
 
+/// Build an HTTP client with the given config and TLS settings
+fn http_client(config: &ClientConfig, tls: Option<&RegistryTlsConfig>) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder();
+    if let Some(timeout) = config.timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = config.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(max) = config.max_idle_per_host {
+        builder = builder.max_idle_per_host(max);
+    }
+    if config.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(tls) = tls {
+        for certificate in &tls.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Some(identity) = &tls.identity {
+            let identity = reqwest::Identity::from_pkcs12_der(&identity.der, &identity.password)
+                .expect("client certificates are validated when they are read");
+            builder = builder.identity(identity);
+        }
+    }
+    builder
+        .build()
+        .expect("the HTTP client's TLS backend should always initialize")
+}
+
+/// The key a token for the image's repository is stored under
+fn token_key(image: &Reference) -> String {
+    format!("{}/{}", image.registry(), image.repository())
+}
+
 /// A client configuration
 #[derive(Debug, Clone, Default)]
 pub struct ClientConfig {
     /// Which protocol the client should use
     pub protocol: ClientProtocol,
+    /// How long a request, including reading its response, can take. There is no
+    /// timeout by default
+    pub timeout: Option<Duration>,
+    /// How long connecting to a registry can take. There is no timeout by default
+    pub connect_timeout: Option<Duration>,
+    /// How many idle connections to each registry are kept in the pool. There is no
+    /// limit by default
+    pub max_idle_per_host: Option<usize>,
+    /// Talk HTTP/2 to registries without negotiating it first, so that all requests
+    /// to a registry are multiplexed over a single connection. Only enable this for
+    /// registries that are known to support HTTP/2
+    pub http2_prior_knowledge: bool,
+    /// TLS settings for registries that need them, keyed by registry as it appears
+    /// in image references, such as `registry.internal:5000`. Other registries are
+    /// trusted if their certificates are signed by one of the system's CAs
+    pub registry_tls: HashMap<String, RegistryTlsConfig>,
+    /// The most bytes a layer can hold once it is decompressed, so that a small layer
+    /// can't fill up memory or disk. [`DEFAULT_MAX_LAYER_SIZE`] is used when it is not
+    /// set
+    pub max_layer_size: Option<u64>,
+}
+
+/// The most a layer can decompress to when [`ClientConfig::max_layer_size`] is not set
+pub const DEFAULT_MAX_LAYER_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The TLS settings for connecting to a registry
+#[derive(Debug, Clone, Default)]
+pub struct RegistryTlsConfig {
+    /// CA certificates that are trusted for the registry, in addition to the system's
+    root_certificates: Vec<reqwest::Certificate>,
+    /// The client certificate presented to the registry
+    identity: Option<Pkcs12>,
+}
+
+/// A PKCS #12 archive that is known to be valid. Identities can't be cloned, so the
+/// archive is kept to create one for each HTTP client
+#[derive(Clone)]
+struct Pkcs12 {
+    der: Vec<u8>,
+    password: String,
+}
+
+impl std::fmt::Debug for Pkcs12 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Pkcs12").finish()
+    }
+}
+
+/// The file in a registry's directory that holds its CA certificates
+const CA_BUNDLE_FILE: &str = "ca.crt";
+/// The file in a registry's directory that holds its client certificate and key
+const IDENTITY_FILE: &str = "client.pfx";
+/// The file in a registry's directory that holds the password of its client
+/// certificate. The password is empty if it is missing
+const IDENTITY_PASSWORD_FILE: &str = "client.pfx.password";
+
+impl RegistryTlsConfig {
+    /// Read the CA certificates of a PEM bundle, which can hold several certificates
+    pub fn with_ca_bundle(mut self, pem: &[u8]) -> anyhow::Result<Self> {
+        const END: &str = "-----END CERTIFICATE-----";
+        let pem = std::str::from_utf8(pem).context("CA bundle is not valid PEM")?;
+        let mut rest = pem;
+        while let Some(start) = rest.find("-----BEGIN CERTIFICATE-----") {
+            let end = rest[start..]
+                .find(END)
+                .map(|end| start + end + END.len())
+                .ok_or_else(|| anyhow::anyhow!("CA bundle has an unterminated certificate"))?;
+            self.root_certificates.push(reqwest::Certificate::from_pem(
+                &rest.as_bytes()[start..end],
+            )?);
+            rest = &rest[end..];
+        }
+        Ok(self)
+    }
+
+    /// Read the client certificate and private key of a PKCS #12 archive
+    pub fn with_identity(mut self, pkcs12: &[u8], password: &str) -> anyhow::Result<Self> {
+        reqwest::Identity::from_pkcs12_der(pkcs12, password)?;
+        self.identity = Some(Pkcs12 {
+            der: pkcs12.to_vec(),
+            password: password.to_owned(),
+        });
+        Ok(self)
+    }
+
+    /// Read the TLS settings of each registry from a directory with a subdirectory per
+    /// registry, similar to Docker's `certs.d`:
+    ///
+    /// ```text
+    /// registry.internal:5000/
+    ///     ca.crt               # the CA certificates, as a PEM bundle
+    ///     client.pfx           # the client certificate, as a PKCS #12 archive
+    ///     client.pfx.password  # the password of client.pfx, if it has one
+    /// ```
+    ///
+    /// Each of the files is optional.
+    pub fn load_dir(dir: &Path) -> anyhow::Result<HashMap<String, RegistryTlsConfig>> {
+        let mut registries = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let registry = match path.file_name().and_then(|name| name.to_str()) {
+                Some(registry) => registry.to_owned(),
+                None => continue,
+            };
+            let mut tls = RegistryTlsConfig::default();
+            let ca_bundle = path.join(CA_BUNDLE_FILE);
+            if ca_bundle.exists() {
+                tls = tls
+                    .with_ca_bundle(&std::fs::read(&ca_bundle)?)
+                    .with_context(|| format!("invalid CA bundle {}", ca_bundle.display()))?;
+            }
+            let identity = path.join(IDENTITY_FILE);
+            if identity.exists() {
+                let password = match std::fs::read_to_string(path.join(IDENTITY_PASSWORD_FILE)) {
+                    Ok(password) => password.trim_end().to_owned(),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                    Err(e) => return Err(e.into()),
+                };
+                tls = tls
+                    .with_identity(&std::fs::read(&identity)?, &password)
+                    .with_context(|| {
+                        format!("invalid client certificate {}", identity.display())
+                    })?;
+            }
+            registries.insert(registry, tls);
+        }
+        Ok(registries)
+    }
 }
 
 /// The protocol that the client should use to connect
@@ -282,9 +801,41 @@ impl ClientProtocol {
 #[derive(serde::Deserialize, Default)]
 struct RegistryToken {
     access_token: String,
+    /// The lifetime of the token in seconds, if the registry said
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(skip)]
+    expires_at: Option<Instant>,
 }
 
+/// How long a token is valid for when the registry doesn't say, which the token
+/// specification sets to 60 seconds
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+/// How long before it expires a token is requested again, so it doesn't expire during a
+/// pull
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
 impl RegistryToken {
+    /// Parse a token granted just now from the body of the auth response
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut token: RegistryToken = serde_json::from_str(text)
+            .context("Failed to decode registry token from auth request")?;
+        let lifetime = token
+            .expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+        token.expires_at = Some(Instant::now() + lifetime);
+        Ok(token)
+    }
+
+    fn expiring(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() + TOKEN_REFRESH_MARGIN >= expires_at,
+            None => false,
+        }
+    }
+
     fn bearer_token(&self) -> String {
         format!("Bearer {}", self.access_token)
     }
@@ -348,12 +899,13 @@ mod test {
     #[tokio::test]
     async fn test_auth() {
         let image = Reference::try_from(HELLO_IMAGE).expect("failed to parse reference");
-        let mut c = Client::default();
+        let c = Client::default();
         c.auth(&image, None)
             .await
             .expect("result from auth request");
 
-        let tok = c.token.expect("token is available");
+        let tokens = c.tokens.read().unwrap();
+        let tok = tokens.get(&token_key(&image)).expect("token is available");
         // We test that the token is longer than a minimal hash.
         assert!(tok.access_token.len() > 64);
     }
@@ -370,7 +922,7 @@ mod test {
         // But this should pass
         let image = Reference::try_from(HELLO_IMAGE).expect("failed to parse reference");
         // Currently, pull_manifest does not perform Authz, so this will fail.
-        let mut c = Client::default();
+        let c = Client::default();
         c.auth(&image, None).await.expect("authenticated");
         let manifest = c
             .pull_manifest(&image)
@@ -385,7 +937,7 @@ mod test {
     #[tokio::test]
     async fn test_pull_layer() {
         let image = Reference::try_from(HELLO_IMAGE).expect("failed to parse reference");
-        let mut c = Client::default();
+        let c = Client::default();
         c.auth(&image, None).await.expect("authenticated");
         let manifest = c
             .pull_manifest(&image)
@@ -407,10 +959,109 @@ mod test {
     #[tokio::test]
     async fn test_pull_image() {
         let image = Reference::try_from(HELLO_IMAGE).expect("failed to parse reference");
-        let mut c = Client::default();
+        let c = Client::default();
 
         let contents = c.pull_image(&image).await.expect("failed to pull manifest");
 
         assert!(contents.len() != 0);
     }
+
+    const CA_BUNDLE: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBgzCCASmgAwIBAgIUDew8htMZGGRmDcOR6lC/h0/5CjYwCgYIKoZIzj0EAwIw
+FjEUMBIGA1UEAwwLUmVnaXN0cnkgQ0EwIBcNMjYxMDE0MDc0NDEwWhgPMjEyNjA5
+MjAwNzQ0MTBaMBYxFDASBgNVBAMMC1JlZ2lzdHJ5IENBMFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAEnefFn03SjI5RbKneXjMZaJJz6Vk2565j4EevwrGDZHpfoOVV
+mRMpPDmXygIUNoP1/adDa+/TuJfrDe1ALuwL0KNTMFEwHQYDVR0OBBYEFGweVxHM
+x8bpLjT96ce1PBVJbvogMB8GA1UdIwQYMBaAFGweVxHMx8bpLjT96ce1PBVJbvog
+MA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIhAIyWZgPjm87/b4Zj
+W9CQDD9E095S/3BPAKvDemj56H6aAiBau7VnOsym/atMVe2mMW2QcoamVcRM9dOu
+RMefaNH3FA==
+-----END CERTIFICATE-----
+
+# The internal CA
+-----BEGIN CERTIFICATE-----
+MIIBgjCCASmgAwIBAgIUKB2X24Ey6FHIkSctRpo/87SJDIYwCgYIKoZIzj0EAwIw
+FjEUMBIGA1UEAwwLSW50ZXJuYWwgQ0EwIBcNMjYxMDE0MDc0NDA3WhgPMjEyNjA5
+MjAwNzQ0MDdaMBYxFDASBgNVBAMMC0ludGVybmFsIENBMFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAErkE+N3E3RnSuScjNiXrSO79SSjze2xsU90X33sWdbrax87ji
+NR84LJsMzyIGeWAw5KhTgVclqYGd3Y9se+l9d6NTMFEwHQYDVR0OBBYEFKF1Q7Hp
+ITAUIiFmFfxRwzQjLffVMB8GA1UdIwQYMBaAFKF1Q7HpITAUIiFmFfxRwzQjLffV
+MA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDRwAwRAIgeo9tpsykjCsxiEMW
+hbuB68zswOVUAiKHteU6vJ/xpE4CIBb2L2JapS6kauI4b50vz/RvUmUVLQut105z
+metJox0X
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_token_lifetime() {
+        // Tokens without a lifetime expire after the default one, not never
+        let token = RegistryToken::parse(r#"{"access_token": "abc"}"#).unwrap();
+        assert!(!token.expiring());
+        let expires_in = token.expires_at.unwrap() - Instant::now();
+        assert!(expires_in <= DEFAULT_TOKEN_LIFETIME);
+        assert!(expires_in > DEFAULT_TOKEN_LIFETIME - Duration::from_secs(5));
+
+        let token = RegistryToken::parse(r#"{"access_token": "abc", "expires_in": 10}"#).unwrap();
+        assert!(token.expiring());
+        assert!(RegistryToken::parse("not a token").is_err());
+    }
+
+    #[test]
+    fn test_ca_bundle() {
+        let tls = RegistryTlsConfig::default()
+            .with_ca_bundle(CA_BUNDLE.as_bytes())
+            .expect("CA bundle should parse");
+        assert_eq!(2, tls.root_certificates.len());
+
+        assert!(RegistryTlsConfig::default()
+            .with_ca_bundle(b"-----BEGIN CERTIFICATE-----\nMIIB")
+            .is_err());
+    }
+
+    #[test]
+    fn test_registry_tls() {
+        let dir = std::env::temp_dir().join(format!("oci-registry-tls-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("registry.internal:5000")).unwrap();
+        std::fs::write(dir.join("registry.internal:5000/ca.crt"), CA_BUNDLE).unwrap();
+        std::fs::create_dir_all(dir.join("mirror.internal")).unwrap();
+        std::fs::write(dir.join("README"), "not a registry").unwrap();
+
+        let registry_tls = RegistryTlsConfig::load_dir(&dir).expect("TLS settings should load");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            2,
+            registry_tls["registry.internal:5000"]
+                .root_certificates
+                .len()
+        );
+        assert!(registry_tls["mirror.internal"].root_certificates.is_empty());
+        assert_eq!(2, registry_tls.len());
+
+        let c = Client::new(ClientConfig {
+            registry_tls,
+            ..Default::default()
+        });
+        assert!(c.registry_clients.contains_key("registry.internal:5000"));
+        assert!(!c.registry_clients.contains_key("webassembly.azurecr.io"));
+
+        // Only the registry's own host is sent its client certificate
+        let registry = "registry.internal:5000";
+        assert!(std::ptr::eq(
+            c.http(registry),
+            c.token_http(registry, "https://registry.internal:5000/token")
+        ));
+        assert!(std::ptr::eq(
+            &c.token_clients[registry],
+            c.token_http(registry, "https://auth.example.com/token")
+        ));
+        assert!(std::ptr::eq(
+            c.http("mirror.internal"),
+            c.token_http(registry, "https://mirror.internal/token")
+        ));
+        assert!(std::ptr::eq(
+            &c.client,
+            c.token_http("webassembly.azurecr.io", "https://auth.example.com/token")
+        ));
+    }
 }
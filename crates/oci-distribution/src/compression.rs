@@ -0,0 +1,355 @@
+//! Compressed layers
+//!
+//! Registries serve layers compressed with gzip or zstd, as their media type says.
+//! [`Client::pull_layer_decompressed`](crate::Client::pull_layer_decompressed) decompresses
+//! them while they are downloaded, checking the digest of the compressed content against
+//! the layer's descriptor and, when it is known, the digest of the uncompressed content
+//! against the image config.
+use std::io;
+use std::pin::Pin;
+
+use bytes::Bytes;
+use futures_util::stream::{Stream, StreamExt};
+use sha2::digest::DynDigest;
+use sha2::{Sha256, Sha512};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// How a layer is compressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// The layer is not compressed
+    None,
+    /// The layer is compressed with gzip
+    Gzip,
+    /// The layer is compressed with zstd
+    Zstd,
+}
+
+impl Compression {
+    /// Returns how layers of the media type are compressed. Media types without a
+    /// compression suffix, such as Wasm layers, are not compressed
+    pub fn from_media_type(media_type: &str) -> Self {
+        if media_type.ends_with("+gzip") || media_type.ends_with(".tar.gzip") {
+            Compression::Gzip
+        } else if media_type.ends_with("+zstd") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Computes the digest of content as it goes by, to check it against the expected one
+struct Verifier {
+    expected: String,
+    hasher: Box<dyn DynDigest + Send>,
+}
+
+impl Verifier {
+    /// A verifier for the digest, which must be a `sha256` or `sha512` one
+    fn new(expected: &str) -> anyhow::Result<Self> {
+        let hasher: Box<dyn DynDigest + Send> = match expected.split(':').next() {
+            Some("sha256") => Box::new(Sha256::default()),
+            Some("sha512") => Box::new(Sha512::default()),
+            _ => return Err(anyhow::anyhow!("unsupported digest {}", expected)),
+        };
+        Ok(Verifier {
+            expected: expected.to_owned(),
+            hasher,
+        })
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.hasher.input(bytes);
+    }
+
+    fn verify(self, what: &str) -> anyhow::Result<()> {
+        let algorithm = self.expected.split(':').next().unwrap_or_default();
+        let digest: String = self
+            .hasher
+            .result()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let actual = format!("{}:{}", algorithm, digest);
+        if actual != self.expected {
+            return Err(anyhow::anyhow!(
+                "digest of the {} is {}, expected {}",
+                what,
+                actual,
+                self.expected
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Check the digest of content that was downloaded or built whole
+pub(crate) fn verify(content: &[u8], digest: &str, what: &str) -> anyhow::Result<()> {
+    let mut verifier = Verifier::new(digest)?;
+    verifier.update(content);
+    verifier.verify(what)
+}
+
+/// How large a layer is expected to be
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LayerSize {
+    /// The size of the layer as it is downloaded, from its descriptor, if it is known
+    pub(crate) compressed: Option<u64>,
+    /// The most the layer can decompress to
+    pub(crate) max_uncompressed: u64,
+}
+
+/// Write the chunks of a layer to `out`, decompressing them, and check the digest of
+/// the compressed chunks and, if given, the digest of what was written. The digests
+/// can only be checked once the whole layer is read, so the layer fails as soon as it
+/// grows past its expected size instead
+pub(crate) async fn copy_layer<S, W>(
+    chunks: S,
+    compression: Compression,
+    digest: &str,
+    diff_id: Option<&str>,
+    size: LayerSize,
+    mut out: W,
+) -> anyhow::Result<()>
+where
+    S: Stream<Item = io::Result<Bytes>> + Send,
+    W: AsyncWrite + Unpin,
+{
+    let mut compressed = Verifier::new(digest)?;
+    let mut uncompressed = diff_id.map(Verifier::new).transpose()?;
+    {
+        let mut read = 0u64;
+        let chunks = chunks.map(|chunk| {
+            let bytes = chunk?;
+            read += bytes.len() as u64;
+            if let Some(expected) = size.compressed {
+                if read > expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("layer is larger than its size of {} bytes", expected),
+                    ));
+                }
+            }
+            compressed.update(&bytes);
+            Ok(bytes)
+        });
+        let mut decompressed: Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + '_>> =
+            match compression {
+                Compression::None => Box::pin(chunks),
+                Compression::Gzip => Box::pin(async_compression::stream::GzipDecoder::new(chunks)),
+                Compression::Zstd => Box::pin(async_compression::stream::ZstdDecoder::new(chunks)),
+            };
+        let mut written = 0u64;
+        while let Some(bytes) = decompressed.next().await {
+            let bytes = bytes?;
+            written += bytes.len() as u64;
+            if written > size.max_uncompressed {
+                return Err(anyhow::anyhow!(
+                    "uncompressed layer is larger than the limit of {} bytes",
+                    size.max_uncompressed
+                ));
+            }
+            if let Some(uncompressed) = uncompressed.as_mut() {
+                uncompressed.update(&bytes);
+            }
+            out.write_all(&bytes).await?;
+        }
+    }
+    compressed.verify("layer")?;
+    match uncompressed {
+        Some(uncompressed) => uncompressed.verify("uncompressed layer"),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::stream;
+    use sha2::Digest;
+
+    const CONTENT: &[u8] = b"\0asm\x01\0\0\0 a module that is not very large";
+
+    fn sha256(bytes: &[u8]) -> String {
+        format!("sha256:{:x}", Sha256::digest(bytes))
+    }
+
+    async fn compress(compression: Compression) -> Vec<u8> {
+        let chunks = stream::iter(vec![Ok(Bytes::from_static(CONTENT))]);
+        let encoded: Pin<Box<dyn Stream<Item = io::Result<Bytes>>>> = match compression {
+            Compression::None => return CONTENT.to_vec(),
+            Compression::Gzip => Box::pin(async_compression::stream::GzipEncoder::new(chunks)),
+            Compression::Zstd => Box::pin(async_compression::stream::ZstdEncoder::new(chunks)),
+        };
+        let chunks: Vec<_> = encoded.collect().await;
+        chunks
+            .into_iter()
+            .flat_map(|c| c.unwrap().to_vec())
+            .collect()
+    }
+
+    /// The layer split in small chunks, as it would be downloaded
+    fn chunks(layer: &[u8]) -> impl Stream<Item = io::Result<Bytes>> + Send {
+        let chunks: Vec<_> = layer
+            .chunks(7)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        stream::iter(chunks)
+    }
+
+    /// The expected size of the compressed layer, with room for it to decompress
+    fn size(layer: &[u8]) -> LayerSize {
+        LayerSize {
+            compressed: Some(layer.len() as u64),
+            max_uncompressed: 1024,
+        }
+    }
+
+    #[test]
+    fn test_from_media_type() {
+        use crate::manifest::*;
+        assert_eq!(
+            Compression::Gzip,
+            Compression::from_media_type(IMAGE_LAYER_GZIP_MEDIA_TYPE)
+        );
+        assert_eq!(
+            Compression::Gzip,
+            Compression::from_media_type(IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE)
+        );
+        assert_eq!(
+            Compression::Zstd,
+            Compression::from_media_type(IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE)
+        );
+        assert_eq!(
+            Compression::None,
+            Compression::from_media_type(WASM_LAYER_MEDIA_TYPE)
+        );
+        assert_eq!(
+            Compression::None,
+            Compression::from_media_type(IMAGE_LAYER_MEDIA_TYPE)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_layer() {
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            let layer = compress(compression).await;
+            let mut out = Vec::new();
+            copy_layer(
+                chunks(&layer),
+                compression,
+                &sha256(&layer),
+                Some(&sha256(CONTENT)),
+                size(&layer),
+                &mut out,
+            )
+            .await
+            .unwrap_or_else(|e| panic!("{:?}: {}", compression, e));
+            assert_eq!(CONTENT, &out[..], "{:?}", compression);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_layer_verifies_digests() {
+        let layer = compress(Compression::Gzip).await;
+        let wrong = sha256(b"something else");
+
+        let error = copy_layer(
+            chunks(&layer),
+            Compression::Gzip,
+            &wrong,
+            None,
+            size(&layer),
+            Vec::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            format!(
+                "digest of the layer is {}, expected {}",
+                sha256(&layer),
+                wrong
+            ),
+            error.to_string()
+        );
+
+        let error = copy_layer(
+            chunks(&layer),
+            Compression::Gzip,
+            &sha256(&layer),
+            Some(&wrong),
+            size(&layer),
+            Vec::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("digest of the uncompressed layer"));
+
+        let error = copy_layer(
+            chunks(&layer),
+            Compression::Gzip,
+            "md5:1234",
+            None,
+            size(&layer),
+            Vec::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!("unsupported digest md5:1234", error.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_copy_layer_stops_past_expected_size() {
+        // A layer that decompresses to far more than it is allowed to
+        let bomb = vec![0u8; 64 * 1024];
+        let encoded = async_compression::stream::GzipEncoder::new(stream::iter(vec![Ok(
+            Bytes::from(bomb.clone()),
+        )]));
+        let encoded: Vec<_> = encoded.collect().await;
+        let layer: Vec<u8> = encoded
+            .into_iter()
+            .flat_map(|c| c.unwrap().to_vec())
+            .collect();
+        let mut out = Vec::new();
+        let error = copy_layer(
+            chunks(&layer),
+            Compression::Gzip,
+            &sha256(&layer),
+            Some(&sha256(&bomb)),
+            size(&layer),
+            &mut out,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            "uncompressed layer is larger than the limit of 1024 bytes",
+            error.to_string()
+        );
+        assert!(out.len() <= 1024);
+
+        // A layer that is larger than its descriptor says fails before all of it is read
+        let layer = compress(Compression::None).await;
+        let mut out = Vec::new();
+        let error = copy_layer(
+            chunks(&layer),
+            Compression::None,
+            &sha256(&layer),
+            None,
+            LayerSize {
+                compressed: Some(10),
+                max_uncompressed: 1024,
+            },
+            &mut out,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            "layer is larger than its size of 10 bytes",
+            error.to_string()
+        );
+        assert_eq!(7, out.len());
+    }
+}
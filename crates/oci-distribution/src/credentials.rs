@@ -0,0 +1,333 @@
+//! Credentials for registries that need them
+//!
+//! Cloud registries hand out short-lived credentials from their own APIs rather than
+//! taking a fixed username and password. A [`CredentialHelper`] fetches the credentials
+//! for the registries it handles, and the client keeps them until shortly before they
+//! expire, fetching new ones (and new tokens from the registry) on the next pull after
+//! that. Helpers for the common cloud registries are available with features:
+//!
+//! - `ecr`: [`ecr::EcrHelper`] for Amazon Elastic Container Registry
+//! - `gcr`: [`gcr::GcrHelper`] for Google Container Registry and Artifact Registry
+//! - `acr`: [`acr::AcrHelper`] for Azure Container Registry
+//!
+//! ```rust,no_run
+//! # #[cfg(feature = "gcr")]
+//! # fn example() {
+//! use oci_distribution::credentials::gcr::GcrHelper;
+//! use oci_distribution::Client;
+//!
+//! let client = Client::default().with_credential_helper(GcrHelper::new());
+//! # }
+//! ```
+//!
+//! [`Client::with_cloud_credential_helpers`](crate::Client::with_cloud_credential_helpers)
+//! adds the helpers of every cloud registry the crate was built with. Credentials that
+//! come from elsewhere, such as a file another process keeps up to date, can be fetched
+//! by a function with [`helper_fn`].
+//!
+//! If a helper fails to fetch credentials, the error is logged and the registry is
+//! accessed anonymously, so public images can still be pulled. Pulls that need
+//! credentials for the same registry at the same time wait for a single fetch.
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+
+#[cfg(feature = "acr")]
+pub mod acr;
+#[cfg(feature = "ecr")]
+pub mod ecr;
+#[cfg(feature = "gcr")]
+pub mod gcr;
+
+/// How long before they expire credentials are fetched again, so they don't expire
+/// during a pull
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// A username and password for a registry
+#[derive(Clone)]
+pub struct Credential {
+    /// The username
+    pub username: String,
+    /// The password, or the token used in its place
+    pub password: String,
+    /// When the credential expires, if it does
+    pub expires_at: Option<Instant>,
+}
+
+impl Credential {
+    /// The value of a basic `Authorization` header with the credential
+    pub(crate) fn basic_auth(&self) -> String {
+        format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", self.username, self.password))
+        )
+    }
+
+    fn expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() >= expires_at,
+            None => false,
+        }
+    }
+
+    /// Returns true if the credential expires soon, so it should be fetched again
+    fn expiring(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() + REFRESH_MARGIN >= expires_at,
+            None => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Credential")
+            .field("username", &self.username)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// Fetches credentials for registries
+#[async_trait]
+pub trait CredentialHelper: Send + Sync {
+    /// Returns true if the helper has credentials for the given registry, as it
+    /// appears in image references
+    fn handles(&self, registry: &str) -> bool;
+
+    /// Fetch a credential for the given registry
+    async fn credential(&self, registry: &str) -> anyhow::Result<Credential>;
+}
+
+/// Returns a helper that fetches credentials for the registries `handles` returns true
+/// for by calling `fetch` with the registry
+pub fn helper_fn<H, F, Fut>(handles: H, fetch: F) -> FnHelper<H, F>
+where
+    H: Fn(&str) -> bool + Send + Sync,
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = anyhow::Result<Credential>> + Send + 'static,
+{
+    FnHelper { handles, fetch }
+}
+
+/// A helper that fetches credentials by calling a function, see [`helper_fn`]
+pub struct FnHelper<H, F> {
+    handles: H,
+    fetch: F,
+}
+
+#[async_trait]
+impl<H, F, Fut> CredentialHelper for FnHelper<H, F>
+where
+    H: Fn(&str) -> bool + Send + Sync,
+    F: Fn(String) -> Fut + Send + Sync,
+    Fut: Future<Output = anyhow::Result<Credential>> + Send + 'static,
+{
+    fn handles(&self, registry: &str) -> bool {
+        (self.handles)(registry)
+    }
+
+    async fn credential(&self, registry: &str) -> anyhow::Result<Credential> {
+        (self.fetch)(registry.to_owned()).await
+    }
+}
+
+/// The credential helpers of a client and the credentials they fetched. Clones share
+/// the credentials
+#[derive(Clone, Default)]
+pub(crate) struct Credentials {
+    helpers: Vec<Arc<dyn CredentialHelper>>,
+    cache: Arc<Mutex<HashMap<String, Credential>>>,
+    /// Held while the credential for each registry is fetched, so concurrent pulls wait
+    /// for the same fetch
+    fetches: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl Credentials {
+    pub(crate) fn add(&mut self, helper: Arc<dyn CredentialHelper>) {
+        self.helpers.push(helper);
+    }
+
+    fn helper(&self, registry: &str) -> Option<&dyn CredentialHelper> {
+        self.helpers
+            .iter()
+            .find(|helper| helper.handles(registry))
+            .map(|helper| helper.as_ref())
+    }
+
+    /// The credential of a registry that was fetched before, if it has not expired
+    pub(crate) fn cached(&self, registry: &str) -> Option<Credential> {
+        self.cache
+            .lock()
+            .unwrap()
+            .get(registry)
+            .filter(|credential| !credential.expired())
+            .cloned()
+    }
+
+    /// The credential of a registry that was fetched before, if it doesn't expire soon
+    fn fresh(&self, registry: &str) -> Option<Credential> {
+        self.cached(registry)
+            .filter(|credential| !credential.expiring())
+    }
+
+    /// Returns true if a helper handles the registry, but has no credential for it
+    /// that is valid for a while yet
+    pub(crate) fn needs_refresh(&self, registry: &str) -> bool {
+        self.helper(registry).is_some() && self.fresh(registry).is_none()
+    }
+
+    /// The credential for a registry, fetching one if there is none or it expires soon.
+    /// Returns `None` if no helper handles the registry or the helper failed
+    pub(crate) async fn get(&self, registry: &str) -> Option<Credential> {
+        if let Some(credential) = self.fresh(registry) {
+            return Some(credential);
+        }
+        let helper = self.helper(registry)?;
+        let fetch = self
+            .fetches
+            .lock()
+            .unwrap()
+            .entry(registry.to_owned())
+            .or_default()
+            .clone();
+        let _fetching = fetch.lock().await;
+        // Another pull may have fetched the credential while this one waited
+        if let Some(credential) = self.fresh(registry) {
+            return Some(credential);
+        }
+        debug!("Fetching credentials for registry {}", registry);
+        match helper.credential(registry).await {
+            Ok(credential) => {
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(registry.to_owned(), credential.clone());
+                Some(credential)
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to fetch credentials for registry {}, pulling anonymously: {}",
+                    registry, e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// An OAuth access token, as returned by the metadata services of cloud providers
+#[cfg(any(feature = "acr", feature = "gcr"))]
+#[derive(serde::Deserialize)]
+pub(crate) struct AccessToken {
+    pub(crate) access_token: String,
+    /// The lifetime of the token in seconds, which some services send as a string
+    #[serde(default)]
+    expires_in: Option<serde_json::Value>,
+}
+
+#[cfg(any(feature = "acr", feature = "gcr"))]
+impl AccessToken {
+    /// When the token expires, if the service said
+    pub(crate) fn expires_at(&self) -> Option<Instant> {
+        let seconds = match self.expires_in.as_ref()? {
+            serde_json::Value::Number(n) => n.as_u64()?,
+            serde_json::Value::String(s) => s.parse().ok()?,
+            _ => return None,
+        };
+        Some(Instant::now() + Duration::from_secs(seconds))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Credentials with a helper that hands out numbered credentials valid for the
+    /// given time, or fails if that is zero, and how many it was asked for
+    fn counting(valid_for: Duration) -> (Credentials, Arc<AtomicUsize>) {
+        let fetched = Arc::new(AtomicUsize::new(0));
+        let count = fetched.clone();
+        let helper = helper_fn(
+            |registry| registry.ends_with(".example.com"),
+            move |_| {
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    // Long enough for concurrent pulls to ask in the meantime
+                    tokio::time::delay_for(Duration::from_millis(10)).await;
+                    if valid_for == Duration::from_secs(0) {
+                        anyhow::bail!("no credentials");
+                    }
+                    Ok(Credential {
+                        username: "user".to_owned(),
+                        password: format!("token-{}", n),
+                        expires_at: Some(Instant::now() + valid_for),
+                    })
+                }
+            },
+        );
+        let mut credentials = Credentials::default();
+        credentials.add(Arc::new(helper));
+        (credentials, fetched)
+    }
+
+    #[tokio::test]
+    async fn test_credentials_are_cached_until_they_expire() {
+        let (credentials, fetched) = counting(Duration::from_secs(3600));
+        assert!(credentials.needs_refresh("registry.example.com"));
+        let credential = credentials.get("registry.example.com").await.unwrap();
+        assert_eq!("token-0", credential.password);
+        assert_eq!(
+            "Basic dXNlcjp0b2tlbi0w",
+            credentials
+                .cached("registry.example.com")
+                .unwrap()
+                .basic_auth()
+        );
+        assert!(!credentials.needs_refresh("registry.example.com"));
+        credentials.get("registry.example.com").await.unwrap();
+        assert_eq!(1, fetched.load(Ordering::SeqCst));
+
+        // Other registries are left alone
+        assert!(credentials.get("docker.io").await.is_none());
+        assert!(!credentials.needs_refresh("docker.io"));
+
+        // Credentials that expire within the margin are fetched every time
+        let (credentials, fetched) = counting(Duration::from_secs(60));
+        credentials.get("registry.example.com").await.unwrap();
+        assert!(credentials.needs_refresh("registry.example.com"));
+        assert!(credentials.cached("registry.example.com").is_some());
+        let credential = credentials.get("registry.example.com").await.unwrap();
+        assert_eq!("token-1", credential.password);
+        assert_eq!(2, fetched.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_pulls_share_a_fetch() {
+        let (credentials, fetched) = counting(Duration::from_secs(3600));
+        let (a, b) = futures_util::future::join(
+            credentials.get("registry.example.com"),
+            credentials.get("registry.example.com"),
+        )
+        .await;
+        assert_eq!("token-0", a.unwrap().password);
+        assert_eq!("token-0", b.unwrap().password);
+        assert_eq!(1, fetched.load(Ordering::SeqCst));
+
+        // Another registry is fetched on its own
+        credentials.get("other.example.com").await.unwrap();
+        assert_eq!(2, fetched.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_failed_helper_falls_back_to_anonymous() {
+        let (credentials, _) = counting(Duration::from_secs(0));
+        assert!(credentials.get("registry.example.com").await.is_none());
+        assert!(credentials.needs_refresh("registry.example.com"));
+    }
+}
@@ -0,0 +1,145 @@
+//! Credentials for Azure Container Registry
+//!
+//! Registries at `<name>.azurecr.io` take a refresh token of the registry itself,
+//! which is exchanged for an Azure Active Directory token of the node's managed
+//! identity. The AAD token comes from the instance metadata service of the node's
+//! virtual machine, and the refresh token is fetched again when it expires.
+use async_trait::async_trait;
+
+use super::{AccessToken, Credential, CredentialHelper};
+
+/// Where the instance metadata service hands out tokens of the managed identity
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// The resource the AAD token is requested for, which ACR accepts
+const RESOURCE: &str = "https://management.azure.com/";
+
+/// The user refresh tokens are the password of
+const USERNAME: &str = "00000000-0000-0000-0000-000000000000";
+
+/// Fetches credentials for Azure registries with the managed identity of the node
+pub struct AcrHelper {
+    client_id: Option<String>,
+    token_url: String,
+    client: reqwest::Client,
+}
+
+impl Default for AcrHelper {
+    fn default() -> Self {
+        AcrHelper::new()
+    }
+}
+
+impl AcrHelper {
+    /// Create a helper that uses the system assigned identity of the virtual machine
+    pub fn new() -> Self {
+        AcrHelper {
+            client_id: None,
+            token_url: IMDS_TOKEN_URL.to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Use the user assigned identity with the given client ID instead
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = Some(client_id.to_owned());
+        self
+    }
+
+    /// Fetch AAD tokens from the given URL rather than the instance metadata service
+    pub fn with_token_url(mut self, token_url: &str) -> Self {
+        self.token_url = token_url.to_owned();
+        self
+    }
+
+    async fn aad_token(&self) -> anyhow::Result<AccessToken> {
+        let mut query = vec![("api-version", "2018-02-01"), ("resource", RESOURCE)];
+        if let Some(client_id) = &self.client_id {
+            query.push(("client_id", client_id));
+        }
+        let response = self
+            .client
+            .get(&self.token_url)
+            .query(&query)
+            .header("Metadata", "true")
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!(
+                "instance metadata service returned {}: {}",
+                status,
+                response.text().await?
+            );
+        }
+        Ok(response.json().await?)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ExchangeResponse {
+    refresh_token: String,
+}
+
+#[async_trait]
+impl CredentialHelper for AcrHelper {
+    fn handles(&self, registry: &str) -> bool {
+        let host = registry.split(':').next().unwrap_or(registry);
+        [".azurecr.io", ".azurecr.cn", ".azurecr.us"]
+            .iter()
+            .any(|suffix| host.ends_with(suffix))
+    }
+
+    async fn credential(&self, registry: &str) -> anyhow::Result<Credential> {
+        let aad_token = self.aad_token().await?;
+        let response = self
+            .client
+            .post(&format!("https://{}/oauth2/exchange", registry))
+            .form(&[
+                ("grant_type", "access_token"),
+                ("service", registry),
+                ("access_token", &aad_token.access_token),
+            ])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!(
+                "token exchange with {} failed with {}: {}",
+                registry,
+                status,
+                response.text().await?
+            );
+        }
+        let exchange: ExchangeResponse = response.json().await?;
+        // The refresh token is good for as long as the token it was exchanged for
+        Ok(Credential {
+            username: USERNAME.to_owned(),
+            password: exchange.refresh_token,
+            expires_at: aad_token.expires_at(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_handles() {
+        let helper = AcrHelper::new();
+        assert!(helper.handles("webassembly.azurecr.io"));
+        assert!(helper.handles("example.azurecr.cn:443"));
+        assert!(!helper.handles("gcr.io"));
+    }
+
+    #[test]
+    fn test_aad_token_expiry() {
+        // The instance metadata service sends the lifetime as a string
+        let token: AccessToken =
+            serde_json::from_str(r#"{"access_token":"token","expires_in":"3599"}"#).unwrap();
+        let valid_for = token.expires_at().unwrap() - Instant::now();
+        assert!(valid_for > Duration::from_secs(3500));
+    }
+}
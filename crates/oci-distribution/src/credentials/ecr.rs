@@ -0,0 +1,282 @@
+//! Credentials for Amazon Elastic Container Registry
+//!
+//! ECR registries are named `<account>.dkr.ecr.<region>.amazonaws.com`. Their
+//! credentials come from the ECR `GetAuthorizationToken` API, which is called with the
+//! node's AWS credentials and is valid for 12 hours. The AWS credentials are found the
+//! way the AWS SDKs find them, from the environment, the shared credentials file, the
+//! ECS container credentials or the EC2 instance metadata service, and are fetched
+//! again before they expire.
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusoto_signature::credential::{
+    DefaultCredentialsProvider, ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_signature::{Region, SignedRequest};
+
+use super::{Credential, CredentialHelper};
+
+/// The service name requests to the ECR API are signed for
+const SERVICE: &str = "ecr";
+/// The prefix of the hostnames of the ECR API in each region
+const ENDPOINT_PREFIX: &str = "api.ecr";
+/// The operation that returns registry credentials
+const TARGET: &str = "AmazonEC2ContainerRegistry_V20150921.GetAuthorizationToken";
+
+/// Fetches credentials for ECR registries with the node's AWS credentials
+pub struct EcrHelper {
+    credentials: Box<dyn ProvideAwsCredentials + Send + Sync>,
+    endpoint: Option<String>,
+    client: reqwest::Client,
+}
+
+impl EcrHelper {
+    /// Create a helper that calls the ECR API with the AWS credentials of the given
+    /// provider
+    pub fn new<P: ProvideAwsCredentials + Send + Sync + 'static>(credentials: P) -> Self {
+        EcrHelper {
+            credentials: Box::new(credentials),
+            endpoint: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Create a helper that calls the ECR API with the given access key. The session
+    /// token is needed for temporary access keys
+    pub fn with_access_key(
+        access_key_id: &str,
+        secret_access_key: &str,
+        session_token: Option<&str>,
+    ) -> Self {
+        EcrHelper::new(StaticProvider::new(
+            access_key_id.to_owned(),
+            secret_access_key.to_owned(),
+            session_token.map(str::to_owned),
+            None,
+        ))
+    }
+
+    /// Create a helper with the AWS credentials of the node, wherever the AWS SDKs
+    /// would find them
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(EcrHelper::new(DefaultCredentialsProvider::new()?))
+    }
+
+    /// Call the ECR API at the given URL rather than the public endpoint of the
+    /// registry's region, such as a VPC endpoint
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = Some(endpoint.to_owned());
+        self
+    }
+
+    /// The unsigned `GetAuthorizationToken` request for the registry of the given
+    /// account in the given region
+    fn request(&self, account: &str, region: &str) -> SignedRequest {
+        let body = serde_json::json!({ "registryIds": [account] }).to_string();
+        let mut request = SignedRequest::new("POST", SERVICE, &self.region(region), "/");
+        request.set_endpoint_prefix(ENDPOINT_PREFIX.to_owned());
+        request.set_content_type("application/x-amz-json-1.1".to_owned());
+        request.add_header("x-amz-target", TARGET);
+        request.set_payload(Some(body.into_bytes()));
+        request
+    }
+
+    /// The region requests for a registry in the named region are signed for and sent to
+    fn region(&self, name: &str) -> Region {
+        match &self.endpoint {
+            Some(endpoint) => Region::Custom {
+                name: name.to_owned(),
+                endpoint: endpoint.trim_end_matches('/').to_owned(),
+            },
+            // Regions newer than the signing library are called at their usual host
+            None => name.parse().unwrap_or_else(|_| Region::Custom {
+                name: name.to_owned(),
+                endpoint: format!("https://{}.{}.amazonaws.com", ENDPOINT_PREFIX, name),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialHelper for EcrHelper {
+    fn handles(&self, registry: &str) -> bool {
+        account_and_region(registry).is_some()
+    }
+
+    async fn credential(&self, registry: &str) -> anyhow::Result<Credential> {
+        let (account, region) = account_and_region(registry)
+            .ok_or_else(|| anyhow::anyhow!("{} is not an ECR registry", registry))?;
+        let mut signed = self.request(account, region);
+        let credentials = self.credentials.credentials().await?;
+        let now = Utc::now();
+        signed.sign(&credentials);
+
+        let url = reqwest::Url::parse(&format!(
+            "{}://{}{}",
+            signed.scheme(),
+            signed.hostname(),
+            signed.canonical_uri()
+        ))?;
+        let body = serde_json::json!({ "registryIds": [account] }).to_string();
+        let mut request = self.client.post(url).body(body);
+        for (name, values) in signed.headers() {
+            // The client sets these from the URL and the body
+            if name == "host" || name == "content-length" {
+                continue;
+            }
+            for value in values {
+                request = request.header(name.as_str(), value.as_slice());
+            }
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!(
+                "GetAuthorizationToken failed with {}: {}",
+                status,
+                response.text().await?
+            );
+        }
+        parse_authorization_token(&response.text().await?, now)
+    }
+}
+
+/// The account and region of an ECR registry
+fn account_and_region(registry: &str) -> Option<(&str, &str)> {
+    let host = registry.split(':').next()?;
+    let rest = host
+        .trim_end_matches(".amazonaws.com.cn")
+        .trim_end_matches(".amazonaws.com");
+    if rest.len() == host.len() {
+        return None;
+    }
+    let mut labels = rest.split('.');
+    match (labels.next(), labels.next(), labels.next(), labels.next()) {
+        (Some(account), Some("dkr"), Some("ecr"), Some(region))
+            if labels.next().is_none() && !account.is_empty() && !region.is_empty() =>
+        {
+            Some((account, region))
+        }
+        _ => None,
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizationResponse {
+    authorization_data: Vec<AuthorizationData>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizationData {
+    /// `AWS:<password>`, base64 encoded
+    authorization_token: String,
+    /// When the token expires, in seconds since the epoch
+    expires_at: Option<f64>,
+}
+
+/// The credential in a `GetAuthorizationToken` response received at the given time
+fn parse_authorization_token(response: &str, now: DateTime<Utc>) -> anyhow::Result<Credential> {
+    let response: AuthorizationResponse = serde_json::from_str(response)?;
+    let data = response
+        .authorization_data
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("GetAuthorizationToken returned no token"))?;
+    let token = String::from_utf8(base64::decode(&data.authorization_token)?)?;
+    let separator = token
+        .find(':')
+        .ok_or_else(|| anyhow::anyhow!("authorization token has no username"))?;
+    let expires_at = data.expires_at.map(|expires_at| {
+        let remaining = expires_at - now.timestamp() as f64;
+        Instant::now() + Duration::from_secs(remaining.max(0.0) as u64)
+    });
+    Ok(Credential {
+        username: token[..separator].to_owned(),
+        password: token[separator + 1..].to_owned(),
+        expires_at,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_account_and_region() {
+        assert_eq!(
+            Some(("123456789012", "us-west-2")),
+            account_and_region("123456789012.dkr.ecr.us-west-2.amazonaws.com")
+        );
+        assert_eq!(
+            Some(("123456789012", "cn-north-1")),
+            account_and_region("123456789012.dkr.ecr.cn-north-1.amazonaws.com.cn")
+        );
+        assert_eq!(None, account_and_region("webassembly.azurecr.io"));
+        assert_eq!(None, account_and_region("dkr.ecr.us-west-2.amazonaws.com"));
+        assert_eq!(None, account_and_region("s3.us-west-2.amazonaws.com"));
+    }
+
+    #[test]
+    fn test_request() {
+        let helper = EcrHelper::with_access_key("AKIDEXAMPLE", "secret", None);
+        let hostname = |helper: &EcrHelper, region| helper.request("123", region).hostname();
+        assert_eq!(
+            "api.ecr.us-west-2.amazonaws.com",
+            hostname(&helper, "us-west-2")
+        );
+        assert_eq!(
+            "api.ecr.cn-north-1.amazonaws.com.cn",
+            hostname(&helper, "cn-north-1")
+        );
+        assert_eq!(
+            "api.ecr.xx-future-1.amazonaws.com",
+            hostname(&helper, "xx-future-1")
+        );
+        let helper = helper.with_endpoint("https://vpce-1.api.ecr.us-west-2.vpce.amazonaws.com/");
+        assert_eq!(
+            "vpce-1.api.ecr.us-west-2.vpce.amazonaws.com",
+            hostname(&helper, "us-west-2")
+        );
+
+        let mut request = helper.request("123", "us-west-2");
+        request.sign(&rusoto_signature::credential::AwsCredentials::new(
+            "AKIDEXAMPLE",
+            "secret",
+            Some("session".to_owned()),
+            None,
+        ));
+        let authorization =
+            String::from_utf8(request.headers()["authorization"][0].clone()).unwrap();
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(authorization.contains("/us-west-2/ecr/aws4_request"));
+        assert!(authorization.contains("x-amz-target"));
+        assert_eq!(
+            b"session".to_vec(),
+            request.headers()["x-amz-security-token"][0]
+        );
+    }
+
+    #[test]
+    fn test_parse_authorization_token() {
+        let now = Utc.timestamp(1_590_000_000, 0);
+        let response = serde_json::json!({
+            "authorizationData": [{
+                "authorizationToken": base64::encode("AWS:secret"),
+                "expiresAt": 1_590_043_200.0,
+                "proxyEndpoint": "https://123456789012.dkr.ecr.us-west-2.amazonaws.com",
+            }]
+        });
+        let credential = parse_authorization_token(&response.to_string(), now).unwrap();
+        assert_eq!("AWS", credential.username);
+        assert_eq!("secret", credential.password);
+        let valid_for = credential.expires_at.unwrap() - Instant::now();
+        assert!(valid_for > Duration::from_secs(11 * 3600));
+        assert!(valid_for <= Duration::from_secs(12 * 3600));
+
+        assert!(parse_authorization_token(r#"{"authorizationData":[]}"#, now).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Credentials for Google Container Registry and Artifact Registry
+//!
+//! Registries at `gcr.io`, its regional hosts such as `eu.gcr.io`, and
+//! `<region>-docker.pkg.dev` take an OAuth access token of the node's service account
+//! as the password of the `oauth2accesstoken` user. Tokens come from the metadata
+//! server of the node's Compute Engine instance and usually last an hour.
+use async_trait::async_trait;
+
+use super::{AccessToken, Credential, CredentialHelper};
+
+/// Where the metadata server hands out access tokens for the default service account
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// The user the access token is the password of
+const USERNAME: &str = "oauth2accesstoken";
+
+/// Fetches credentials for Google registries from the metadata server
+pub struct GcrHelper {
+    token_url: String,
+    client: reqwest::Client,
+}
+
+impl Default for GcrHelper {
+    fn default() -> Self {
+        GcrHelper::new()
+    }
+}
+
+impl GcrHelper {
+    /// Create a helper that fetches tokens for the instance's default service account
+    pub fn new() -> Self {
+        GcrHelper {
+            token_url: METADATA_TOKEN_URL.to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Fetch tokens from the given URL instead, such as the token URL of another
+    /// service account on the metadata server
+    pub fn with_token_url(mut self, token_url: &str) -> Self {
+        self.token_url = token_url.to_owned();
+        self
+    }
+}
+
+#[async_trait]
+impl CredentialHelper for GcrHelper {
+    fn handles(&self, registry: &str) -> bool {
+        let host = registry.split(':').next().unwrap_or(registry);
+        host == "gcr.io" || host.ends_with(".gcr.io") || host.ends_with("-docker.pkg.dev")
+    }
+
+    async fn credential(&self, _registry: &str) -> anyhow::Result<Credential> {
+        let response = self
+            .client
+            .get(&self.token_url)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            anyhow::bail!(
+                "metadata server returned {}: {}",
+                status,
+                response.text().await?
+            );
+        }
+        let token: AccessToken = response.json().await?;
+        Ok(Credential {
+            username: USERNAME.to_owned(),
+            expires_at: token.expires_at(),
+            password: token.access_token,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handles() {
+        let helper = GcrHelper::new();
+        assert!(helper.handles("gcr.io"));
+        assert!(helper.handles("eu.gcr.io"));
+        assert!(helper.handles("europe-west1-docker.pkg.dev"));
+        assert!(!helper.handles("webassembly.azurecr.io"));
+        assert!(!helper.handles("notgcr.io"));
+    }
+}
@@ -0,0 +1,264 @@
+//! Binary deltas between module versions
+//!
+//! Next to a module image, a registry can store deltas that turn the module of an
+//! earlier version into the image's module, so nodes on slow links download a few
+//! changed bytes instead of the whole module. A delta is an artifact of type
+//! [`DELTA_ARTIFACT_TYPE`] whose `subject` is the image's manifest, so the referrers API
+//! lists it, and whose [`BASE_ANNOTATION`] annotation is the `sha256` digest of the
+//! module it applies to. Its layer of media type [`DELTA_LAYER_MEDIA_TYPE`] holds the
+//! delta, as made by [`encode`].
+//!
+//! [`Client::pull_image_from_base`](crate::Client::pull_image_from_base) pulls a delta
+//! when the registry has one for a module the caller has already, and the whole image
+//! otherwise.
+use std::convert::TryInto;
+
+use sha2::{Digest, Sha256};
+
+/// The artifact type of deltas between module versions
+pub const DELTA_ARTIFACT_TYPE: &str = "application/vnd.krustlet.module.delta.v1";
+/// The media type of the layer that holds a delta
+pub const DELTA_LAYER_MEDIA_TYPE: &str = "application/vnd.krustlet.module.delta.v1.bin";
+/// The annotation of a delta artifact that holds the digest of the module it applies to
+pub const BASE_ANNOTATION: &str = "dev.krustlet.delta.base";
+
+/// The bytes every delta starts with
+const MAGIC: &[u8] = b"KRDELTA1";
+
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+
+/// A step in building the new module from the base module
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    /// Append `length` bytes of the base module, starting at `offset`
+    Copy {
+        /// Where the bytes start in the base module
+        offset: u64,
+        /// How many bytes are copied
+        length: u64,
+    },
+    /// Append the bytes, which are not in the base module
+    Insert(Vec<u8>),
+}
+
+/// Encode the instructions as a delta. Integers are written as little endian `u64`s,
+/// after a byte that says which instruction follows
+pub fn encode(instructions: &[Instruction]) -> Vec<u8> {
+    let mut delta = MAGIC.to_vec();
+    for instruction in instructions {
+        match instruction {
+            Instruction::Copy { offset, length } => {
+                delta.push(COPY);
+                delta.extend_from_slice(&offset.to_le_bytes());
+                delta.extend_from_slice(&length.to_le_bytes());
+            }
+            Instruction::Insert(bytes) => {
+                delta.push(INSERT);
+                delta.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+                delta.extend_from_slice(bytes);
+            }
+        }
+    }
+    delta
+}
+
+/// Build the new module by applying the delta to the base module. The new module is
+/// expected to be `size` bytes, and the delta fails as soon as it builds a larger one
+pub fn apply(base: &[u8], delta: &[u8], size: u64) -> anyhow::Result<Vec<u8>> {
+    if !delta.starts_with(MAGIC) {
+        return Err(anyhow::anyhow!("not a module delta"));
+    }
+    let mut reader = Reader(&delta[MAGIC.len()..]);
+    let mut module = Vec::new();
+    let too_large = || anyhow::anyhow!("delta builds a module larger than {} bytes", size);
+    while let Some(&instruction) = reader.0.first() {
+        reader.0 = &reader.0[1..];
+        match instruction {
+            COPY => {
+                let offset = reader.u64()?;
+                let length = reader.u64()?;
+                if length > size - module.len() as u64 {
+                    return Err(too_large());
+                }
+                let copied = offset
+                    .checked_add(length)
+                    .and_then(|end| base.get(offset as usize..end as usize))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "delta copies {} bytes at {}, past the end of the base module",
+                            length,
+                            offset
+                        )
+                    })?;
+                module.extend_from_slice(copied);
+            }
+            INSERT => {
+                let length = reader.u64()?;
+                if length > size - module.len() as u64 {
+                    return Err(too_large());
+                }
+                module.extend_from_slice(reader.take(length)?);
+            }
+            other => return Err(anyhow::anyhow!("unknown delta instruction {}", other)),
+        }
+    }
+    Ok(module)
+}
+
+/// Reads the integers and bytes of a delta
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: u64) -> anyhow::Result<&'a [u8]> {
+        if (self.0.len() as u64) < length {
+            return Err(anyhow::anyhow!("delta is truncated"));
+        }
+        let (taken, rest) = self.0.split_at(length as usize);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Returns the `sha256` digest of a module, as deltas name their base module
+pub(crate) fn sha256_digest(module: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(module))
+}
+
+/// The artifacts that refer to a manifest, as the referrers API lists them
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct Referrers {
+    #[serde(default)]
+    manifests: Vec<Referrer>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Referrer {
+    digest: String,
+    artifact_type: Option<String>,
+    annotations: Option<std::collections::HashMap<String, String>>,
+}
+
+impl Referrers {
+    /// Returns the digest of the manifest of the delta that applies to the base module
+    pub(crate) fn delta_from(&self, base_digest: &str) -> Option<&str> {
+        self.manifests
+            .iter()
+            .find(|r| {
+                r.artifact_type.as_deref() == Some(DELTA_ARTIFACT_TYPE)
+                    && matches!(
+                        r.annotations.as_ref().and_then(|a| a.get(BASE_ANNOTATION)),
+                        Some(base) if base == base_digest
+                    )
+            })
+            .map(|r| r.digest.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: &[u8] = b"\0asm\x01\0\0\0 version one of the module";
+
+    #[test]
+    fn test_apply() {
+        let delta = encode(&[
+            Instruction::Copy {
+                offset: 0,
+                length: 9,
+            },
+            Instruction::Insert(b"version two".to_vec()),
+            Instruction::Copy {
+                offset: 20,
+                length: 14,
+            },
+        ]);
+        assert_eq!(
+            b"\0asm\x01\0\0\0 version two of the module".to_vec(),
+            apply(BASE, &delta, 34).unwrap()
+        );
+        assert!(apply(BASE, &encode(&[]), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_apply_invalid_deltas() {
+        let error = |delta: &[u8]| apply(BASE, delta, 1024).unwrap_err().to_string();
+        assert_eq!("not a module delta", error(b"\0asm"));
+        assert_eq!(
+            "delta copies 10 bytes at 30, past the end of the base module",
+            error(&encode(&[Instruction::Copy {
+                offset: 30,
+                length: 10
+            }]))
+        );
+        let mut truncated = encode(&[Instruction::Insert(b"new bytes".to_vec())]);
+        truncated.pop();
+        assert_eq!("delta is truncated", error(&truncated));
+        let mut unknown = encode(&[]);
+        unknown.push(7);
+        assert_eq!("unknown delta instruction 7", error(&unknown));
+    }
+
+    #[test]
+    fn test_apply_stops_past_expected_size() {
+        // A small delta that copies the whole base module over and over
+        let copy = Instruction::Copy {
+            offset: 0,
+            length: BASE.len() as u64,
+        };
+        let delta = encode(&vec![copy; 1000]);
+        let size = BASE.len() as u64 * 2;
+        assert_eq!(
+            format!("delta builds a module larger than {} bytes", size),
+            apply(BASE, &delta, size).unwrap_err().to_string()
+        );
+        let insert = encode(&[Instruction::Insert(BASE.to_vec())]);
+        assert_eq!(
+            "delta builds a module larger than 10 bytes",
+            apply(BASE, &insert, 10).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_delta_from() {
+        let base = sha256_digest(BASE);
+        let referrers: Referrers = serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:signature",
+                    "size": 100,
+                    "artifactType": "application/vnd.example.signature",
+                    "annotations": { BASE_ANNOTATION: base },
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:other",
+                    "size": 100,
+                    "artifactType": DELTA_ARTIFACT_TYPE,
+                    "annotations": { BASE_ANNOTATION: "sha256:older" },
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:delta",
+                    "size": 100,
+                    "artifactType": DELTA_ARTIFACT_TYPE,
+                    "annotations": { BASE_ANNOTATION: base },
+                },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(Some("sha256:delta"), referrers.delta_from(&base));
+        assert_eq!(None, referrers.delta_from("sha256:unknown"));
+        assert_eq!(None, Referrers::default().delta_from(&base));
+    }
+}
@@ -2,6 +2,9 @@
 #![deny(missing_docs)]
 
 pub mod client;
+pub mod compression;
+pub mod credentials;
+pub mod delta;
 pub mod errors;
 pub mod manifest;
 mod reference;
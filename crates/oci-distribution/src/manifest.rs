@@ -13,12 +13,20 @@ pub const IMAGE_DOCKER_CONFIG_MEDIA_TYPE: &str = "application/vnd.docker.contain
 pub const IMAGE_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
 /// The mediatype for a layer that is gzipped.
 pub const IMAGE_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+/// The mediatype for a layer that is compressed with zstd.
+pub const IMAGE_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+/// The mediatype that Docker uses for gzipped layers.
+pub const IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE: &str =
+    "application/vnd.docker.image.rootfs.diff.tar.gzip";
 /// The mediatype for a layer that is nondistributable.
 pub const IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE: &str =
     "application/vnd.oci.image.layer.nondistributable.v1.tar";
 /// The mediatype for a layer that is nondistributable and gzipped.
 pub const IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE: &str =
     "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip";
+/// The mediatype for a layer that is nondistributable and compressed with zstd.
+pub const IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE: &str =
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd";
 
 // TODO: Annotation key constants. https://github.com/opencontainers/image-spec/blob/master/annotations.md#pre-defined-annotation-keys
 
@@ -126,6 +134,22 @@ impl Default for OciDescriptor {
     }
 }
 
+/// Returns the digests of the uncompressed layers listed in an image config, by layer,
+/// if the config lists them
+pub(crate) fn diff_ids(config: &[u8]) -> Option<Vec<String>> {
+    #[derive(serde::Deserialize)]
+    struct RootFs {
+        diff_ids: Vec<String>,
+    }
+    #[derive(serde::Deserialize)]
+    struct ImageConfig {
+        rootfs: RootFs,
+    }
+    serde_json::from_slice::<ImageConfig>(config)
+        .ok()
+        .map(|c| c.rootfs.diff_ids)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -180,4 +204,14 @@ mod test {
                 .len()
         );
     }
+
+    #[test]
+    fn test_diff_ids() {
+        let config = br#"{
+            "architecture": "wasm",
+            "rootfs": { "type": "layers", "diff_ids": ["sha256:1234"] }
+        }"#;
+        assert_eq!(Some(vec!["sha256:1234".to_owned()]), diff_ids(config));
+        assert_eq!(None, diff_ids(b"{}"));
+    }
 }
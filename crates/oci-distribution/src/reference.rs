@@ -50,6 +50,30 @@ impl Reference {
         )
     }
 
+    /// Convert a Reference to the v2 URL of another manifest of its repository, given
+    /// by digest.
+    pub fn to_v2_manifest_digest_url(&self, protocol: &str, digest: &str) -> String {
+        format!(
+            "{}://{}/v2/{}/manifests/{}",
+            protocol,
+            self.registry(),
+            self.repository(),
+            digest
+        )
+    }
+
+    /// Convert a Reference to the v2 URL that lists the artifacts referring to the
+    /// manifest with the given digest.
+    pub fn to_v2_referrers_url(&self, protocol: &str, digest: &str) -> String {
+        format!(
+            "{}://{}/v2/{}/referrers/{}",
+            protocol,
+            self.registry(),
+            self.repository(),
+            digest
+        )
+    }
+
     /// Convert a Reference to a v2 blob (layer) URL.
     pub fn to_v2_blob_url(&self, protocol: &str, digest: &str) -> String {
         format!(
@@ -132,5 +156,13 @@ mod tests {
             "https://webassembly.azurecr.io/v2/hello/manifests/v1",
             reference.to_v2_manifest_url("https")
         );
+        assert_eq!(
+            "https://webassembly.azurecr.io/v2/hello/manifests/sha256:abc",
+            reference.to_v2_manifest_digest_url("https", "sha256:abc")
+        );
+        assert_eq!(
+            "https://webassembly.azurecr.io/v2/hello/referrers/sha256:abc",
+            reference.to_v2_referrers_url("https", "sha256:abc")
+        );
     }
 }
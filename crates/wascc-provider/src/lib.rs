@@ -17,9 +17,10 @@
 //!
 //!     // Load a kubernetes configuration
 //!     let kubeconfig = kube::Config::infer().await.unwrap();
+//!     let client = kube::Client::new(kubeconfig.clone());
 //!
 //!     // Instantiate the provider type
-//!     let provider = WasccProvider::new(store, &kubelet_config, kubeconfig.clone()).await.unwrap();
+//!     let provider = WasccProvider::new(store, &kubelet_config, client).await.unwrap();
 //!
 //!     // Instantiate the Kubelet
 //!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config);
@@ -31,15 +32,16 @@
 #![deny(missing_docs)]
 
 use async_trait::async_trait;
-use k8s_openapi::api::core::v1::{ContainerStatus as KubeContainerStatus, Pod as KubePod};
-use kube::{api::DeleteParams, Api};
+use kubelet::concurrency::Operation;
+use kubelet::container::Container;
 use kubelet::handle::{key_from_pod, pod_key, PodHandle, RuntimeHandle, Stop};
 use kubelet::module_store::ModuleStore;
+use kubelet::pod_dirs::{remove_legacy_dirs, LEGACY_VOLUMES_DIR};
 use kubelet::provider::ProviderError;
-use kubelet::status::{update_pod_status, ContainerStatus, Phase, Status};
+use kubelet::status::{annotations, ContainerStatus, Status};
 use kubelet::volumes::VolumeRef;
 use kubelet::{Pod, Provider};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use tempfile::NamedTempFile;
 use tokio::sync::watch::{self, Receiver};
 use tokio::sync::RwLock;
@@ -49,7 +51,7 @@ use wascc_httpsrv::HttpServerProvider;
 use wascc_logging::{LoggingProvider, LOG_PATH_KEY};
 
 use std::collections::HashMap;
-use std::ops::Deref;
+use std::convert::TryInto;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
@@ -65,15 +67,13 @@ const HTTP_CAPABILITY: &str = "wascc:http_server";
 /// The name of the Logging capability.
 const LOG_CAPABILITY: &str = "wascc:logging";
 
-/// The root directory of waSCC logs.
-const LOG_DIR_NAME: &str = "wascc-logs";
+/// The directory in the data directory waSCC logs were kept in before they were kept
+/// with the rest of each pod's data
+const LEGACY_LOG_DIR: &str = "wascc-logs";
 
 /// The key used to define the root directory of the Filesystem capability.
 const FS_CONFIG_ROOTDIR: &str = "ROOT";
 
-/// The root directory of waSCC volumes.
-const VOLUME_DIR: &str = "volumes";
-
 /// Kubernetes' view of environment variables is an unordered map of string to string.
 type EnvVars = std::collections::HashMap<String, String>;
 
@@ -114,9 +114,11 @@ impl Stop for ActorStopper {
 pub struct WasccProvider<S> {
     handles: Arc<RwLock<HashMap<String, PodHandle<ActorStopper, LogHandleFactory>>>>,
     store: S,
-    volume_path: PathBuf,
-    log_path: PathBuf,
-    kubeconfig: kube::Config,
+    /// The Kubelet's data directory, which holds the volumes and logs of each pod
+    data_dir: PathBuf,
+    /// Where actor logs are also written for node level log shippers
+    pod_log_dir: Option<PathBuf>,
+    client: kube::Client,
     host: Arc<Mutex<WasccHost>>,
 }
 
@@ -126,13 +128,11 @@ impl<S: ModuleStore + Send + Sync> WasccProvider<S> {
     pub async fn new(
         store: S,
         config: &kubelet::config::Config,
-        kubeconfig: kube::Config,
+        client: kube::Client,
     ) -> anyhow::Result<Self> {
         let host = Arc::new(Mutex::new(WasccHost::new()));
-        let log_path = config.data_dir.join(LOG_DIR_NAME);
-        let volume_path = config.data_dir.join(VOLUME_DIR);
-        tokio::fs::create_dir_all(&log_path).await?;
-        tokio::fs::create_dir_all(&volume_path).await?;
+        tokio::fs::create_dir_all(&config.data_dir).await?;
+        remove_legacy_dirs(&config.data_dir, &[LEGACY_VOLUMES_DIR, LEGACY_LOG_DIR]).await;
 
         // wascc has native and portable capabilities.
         //
@@ -174,9 +174,9 @@ impl<S: ModuleStore + Send + Sync> WasccProvider<S> {
         Ok(Self {
             handles: Default::default(),
             store,
-            volume_path,
-            log_path,
-            kubeconfig,
+            data_dir: config.data_dir.clone(),
+            pod_log_dir: config.pod_log_dir.clone(),
+            client,
             host,
         })
     }
@@ -186,6 +186,12 @@ impl<S: ModuleStore + Send + Sync> WasccProvider<S> {
 impl<S: ModuleStore + Send + Sync> Provider for WasccProvider<S> {
     const ARCH: &'static str = TARGET_WASM32_WASCC;
 
+    /// Actors are only pulled into the store, as wasCC loads them when they start
+    async fn prefetch(&self, image: &str) -> anyhow::Result<()> {
+        self.store.get(&image.try_into()?).await?;
+        Ok(())
+    }
+
     async fn add(&self, pod: Pod) -> anyhow::Result<()> {
         // To run an Add event, we load the actor, and update the pod status
         // to Running.  The wascc runtime takes care of starting the actor.
@@ -196,53 +202,56 @@ impl<S: ModuleStore + Send + Sync> Provider for WasccProvider<S> {
         info!("Starting containers for pod {:?}", pod.name());
         let mut modules = self.store.fetch_pod_modules(&pod).await?;
         let mut container_handles = HashMap::new();
-        let client = kube::Client::new(self.kubeconfig.clone());
-        let volumes = VolumeRef::volumes_from_pod(&self.volume_path, &pod, &client).await?;
-        for container in pod.containers() {
-            let env = Self::env_vars(&container, &pod, &client).await;
-            let volume_bindings: Vec<VolumeBinding> =
-                if let Some(volume_mounts) = container.volume_mounts.as_ref() {
-                    volume_mounts
-                        .iter()
-                        .map(|vm| -> anyhow::Result<VolumeBinding> {
-                            // Check the volume exists first
-                            let vol = volumes.get(&vm.name).ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "no volume with the name of {} found for container {}",
-                                    vm.name,
-                                    container.name
-                                )
-                            })?;
-                            // We can safely assume that this should be valid UTF-8 because it would have
-                            // been validated by the k8s API
-                            Ok(VolumeBinding {
-                                name: vm.name.clone(),
-                                host_path: vol.deref().clone(),
-                            })
-                        })
-                        .collect::<anyhow::Result<_>>()?
-                } else {
-                    vec![]
-                };
+        let client = self.client.clone();
+        let dirs = pod.data_dirs(&self.data_dir);
+        dirs.create().await?;
+        let volumes = VolumeRef::volumes_from_pod(&self.data_dir, &pod, &client).await?;
+        for container in Container::resolve_all::<Self>(&pod, &client, &volumes).await? {
+            let mut env = container.env().clone();
+            // The HTTP server listens on the host's network, so it binds the container's
+            // host port when the container doesn't pick a port itself
+            if let Some(binding) = container
+                .port_bindings()
+                .into_iter()
+                .find(|b| b.protocol == "TCP")
+            {
+                env.entry("PORT".to_owned())
+                    .or_insert_with(|| binding.host_port.to_string());
+            }
+            let volume_bindings: Vec<VolumeBinding> = container
+                .volume_mounts()
+                .iter()
+                .map(|vm| VolumeBinding {
+                    name: vm.name.clone(),
+                    host_path: vm.host_path.clone(),
+                })
+                .collect();
 
-            debug!("Starting container {} on thread", container.name);
+            debug!("Starting container {} on thread", container.name());
 
             let module_data = modules
-                .remove(&container.name)
+                .remove(container.name())
                 .expect("FATAL ERROR: module map not properly populated");
-            let lp = self.log_path.clone();
-            let (status_sender, status_recv) = watch::channel(ContainerStatus::Waiting {
-                timestamp: chrono::Utc::now(),
-                message: "No status has been received from the process".into(),
-            });
+            let lp = dirs.logs();
+            let (status_sender, status_recv) = watch::channel(ContainerStatus::waiting(
+                "No status has been received from the process",
+            ));
             let host = self.host.clone();
+            let port = env.get("PORT").cloned();
+            // Loading the actor compiles its module
+            let compiling = pod
+                .concurrency()
+                .acquire(Operation::ModuleCompilation)
+                .await;
             let http_result = tokio::task::spawn_blocking(move || {
                 wascc_run_http(host, module_data, env, volume_bindings, &lp, status_recv)
             })
             .await?;
+            drop(compiling);
             match http_result {
-                Ok(handle) => {
-                    container_handles.insert(container.name.clone(), handle);
+                Ok((handle, public_key)) => {
+                    container_handles.insert(container.name().to_owned(), handle);
+                    publish_actor(&pod, container.name(), &public_key, port.as_deref());
                     status_sender
                         .broadcast(ContainerStatus::Running {
                             timestamp: chrono::Utc::now(),
@@ -254,10 +263,10 @@ impl<S: ModuleStore + Send + Sync> Provider for WasccProvider<S> {
                     // (it was never used in creating a runtime handle)
                     let mut container_statuses = HashMap::new();
                     container_statuses.insert(
-                        container.name.clone(),
+                        container.name().to_owned(),
                         ContainerStatus::Terminated {
                             timestamp: chrono::Utc::now(),
-                            failed: true,
+                            exit_code: 1,
                             message: format!("Error while starting container: {:?}", e),
                         },
                     );
@@ -276,11 +285,13 @@ impl<S: ModuleStore + Send + Sync> Provider for WasccProvider<S> {
         );
         // Wrap this in a block so the write lock goes out of scope when we are done
         {
+            let key = key_from_pod(&pod);
+            let mut handle = PodHandle::new(container_handles, pod, client, None)?;
+            if let Some(dir) = &self.pod_log_dir {
+                handle = handle.with_log_files(dir);
+            }
             let mut handles = self.handles.write().await;
-            handles.insert(
-                key_from_pod(&pod),
-                PodHandle::new(container_handles, pod, client, None)?,
-            );
+            handles.insert(key, handle);
         }
 
         Ok(())
@@ -308,57 +319,12 @@ impl<S: ModuleStore + Send + Sync> Provider for WasccProvider<S> {
             );
             let mut handles = self.handles.write().await;
             match handles.get_mut(&key_from_pod(&pod)) {
+                // The kubelet reports the terminated statuses and removes the pod once
+                // everything is stopped. The stopped actors can no longer be found
                 Some(h) => {
-                    h.stop().await?;
-
-                    debug!(
-                        "All actors stopped for pod {} in namespace {}, updating status",
-                        pod_name, pod_namespace
-                    );
-                    // Having to do this here isn't my favorite thing, but we need to update the
-                    // status of the container so it can be deleted. We will probably need to have
-                    // some sort of provider that can send a message about status to the Kube API
-                    let now = chrono::Utc::now();
-                    let terminated = ContainerStatus::Terminated {
-                        timestamp: now,
-                        message: "Pod stopped".to_owned(),
-                        failed: false,
-                    };
-
-                    let container_statuses: Vec<KubeContainerStatus> = pod
-                        .into_kube_pod()
-                        .spec
-                        .unwrap_or_default()
-                        .containers
-                        .into_iter()
-                        .map(|c| terminated.to_kubernetes(c.name))
-                        .collect();
-
-                    let json_status = serde_json::json!(
-                        {
-                            "metadata": {
-                                "resourceVersion": "",
-                            },
-                            "status": {
-                                "message": "Pod stopped",
-                                "phase": Phase::Succeeded,
-                                "containerStatuses": container_statuses,
-                            }
-                        }
-                    );
-                    let client = kube::client::Client::new(self.kubeconfig.clone());
-                    update_pod_status(client.clone(), &pod_namespace, &pod_name, &json_status)
-                        .await?;
-
-                    let pod_client: Api<KubePod> = Api::namespaced(client.clone(), &pod_namespace);
-                    let dp = DeleteParams {
-                        grace_period_seconds: Some(0),
-                        ..Default::default()
-                    };
-                    match pod_client.delete(&pod_name, &dp).await {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(e.into()),
-                    }
+                    let stopped = h.stop().await;
+                    unpublish_actors(&pod);
+                    stopped
                 }
                 None => {
                     // This isn't an error with the pod, so don't return an error (otherwise it will
@@ -412,6 +378,32 @@ impl<S: ModuleStore + Send + Sync> Provider for WasccProvider<S> {
     }
 }
 
+/// Publish the public key of a container's actor, and the port it serves HTTP on, as
+/// annotations of the pod so controllers can find the actor
+fn publish_actor(pod: &Pod, container_name: &str, public_key: &str, port: Option<&str>) {
+    let details = std::iter::once(("actor-key", public_key)).chain(port.map(|p| ("port", p)));
+    for (name, value) in details {
+        let key = annotations::key(container_name, name);
+        // Only container names too long for an annotation key can fail this
+        if let Err(e) = annotations::publish(pod, &key, value) {
+            warn!(
+                "Unable to publish {} of container {}: {}",
+                name, container_name, e
+            );
+        }
+    }
+}
+
+/// Remove what [`publish_actor`] published for the containers of the pod
+fn unpublish_actors(pod: &Pod) {
+    for container in pod.containers() {
+        for name in &["actor-key", "port"] {
+            // Keys that can't be published were never published
+            let _ = annotations::unpublish(pod, &annotations::key(&container.name, name));
+        }
+    }
+}
+
 struct VolumeBinding {
     name: String,
     host_path: PathBuf,
@@ -427,7 +419,7 @@ fn wascc_run_http(
     volumes: Vec<VolumeBinding>,
     log_path: &Path,
     status_recv: Receiver<ContainerStatus>,
-) -> anyhow::Result<RuntimeHandle<ActorStopper, LogHandleFactory>> {
+) -> anyhow::Result<(RuntimeHandle<ActorStopper, LogHandleFactory>, String)> {
     let mut caps: Vec<Capability> = Vec::new();
 
     caps.push(Capability {
@@ -461,7 +453,7 @@ impl kubelet::handle::LogHandleFactory<tokio::fs::File> for LogHandleFactory {
     }
 }
 
-/// Run the given WASM data as a waSCC actor with the given public key.
+/// Run the given WASM data as a waSCC actor, returning its handle and public key.
 ///
 /// The provided capabilities will be configured for this actor, but the capabilities
 /// must first be loaded into the host by some other process, such as register_native_capabilities().
@@ -472,7 +464,7 @@ fn wascc_run(
     volumes: Vec<VolumeBinding>,
     log_path: &Path,
     status_recv: Receiver<ContainerStatus>,
-) -> anyhow::Result<RuntimeHandle<ActorStopper, LogHandleFactory>> {
+) -> anyhow::Result<(RuntimeHandle<ActorStopper, LogHandleFactory>, String)> {
     info!("sending actor to wascc host");
     let log_output = NamedTempFile::new_in(log_path)?;
     let mut logenv: HashMap<String, String> = HashMap::new();
@@ -533,9 +525,13 @@ fn wascc_run(
     let log_handle_factory = LogHandleFactory { temp: log_output };
 
     info!("wascc actor executing");
-    Ok(RuntimeHandle::new(
-        ActorStopper { host, key: pk },
+    let handle = RuntimeHandle::new(
+        ActorStopper {
+            host,
+            key: pk.clone(),
+        },
         log_handle_factory,
         status_recv,
-    ))
+    );
+    Ok((handle, pk))
 }
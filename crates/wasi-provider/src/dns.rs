@@ -0,0 +1,258 @@
+//! A host capability that resolves cluster DNS names for modules
+//!
+//! WASI has no sockets yet, so modules cannot query the cluster's DNS server. Pods that
+//! list the capability in their `alpha.krustlet.dev/capabilities` annotation can
+//! import `resolve` from the `krustlet_dns` module instead:
+//!
+//! ```wat
+//! (import "krustlet_dns" "resolve"
+//!   (func $resolve (param $name i32) (param $name_len i32)
+//!                  (param $addresses i32) (param $addresses_len i32) (result i32)))
+//! ```
+//!
+//! The name is read from the module's `memory` as UTF-8, and is resolved with the
+//! pod's search domains and DNS policy as described in [`kubelet::dns`]. As many of
+//! its addresses as fit are written to the `addresses` buffer, each as 16 bytes in
+//! network order, with IPv4 addresses mapped to IPv6 (`::ffff:a.b.c.d`). The number
+//! of addresses found is returned, which may be more than fit in the buffer, or one of
+//! the negative `ERR_` codes.
+//!
+//! ```rust,no_run
+//! use std::sync::Arc;
+//! use kubelet::capabilities::Capabilities;
+//! use wasi_provider::dns::ClusterDns;
+//! use wasi_provider::host::HostCapability;
+//!
+//! # async {
+//! let client = kube::Client::try_default().await.unwrap();
+//! let mut capabilities: Capabilities<dyn HostCapability> = Capabilities::new();
+//! capabilities.register("dns", Arc::new(ClusterDns::new(client)));
+//! # };
+//! ```
+use std::net::IpAddr;
+
+use kubelet::dns::Resolver;
+use log::warn;
+use tokio::runtime::Handle;
+use wasmtime::{Caller, Extern, Func, Store};
+
+use crate::host::{HostCapability, HostContext};
+
+/// Returned when the module does not export its memory
+pub const ERR_NO_MEMORY: i32 = -1;
+/// Returned when the name or buffer are outside the module's memory, or the name is
+/// not UTF-8
+pub const ERR_INVALID_ARGUMENT: i32 = -2;
+/// Returned when the name could not be looked up, such as when the API is unavailable
+pub const ERR_LOOKUP_FAILED: i32 = -3;
+
+/// The size of each address written to the buffer
+const ADDRESS_SIZE: usize = 16;
+
+/// Resolves cluster DNS names for the modules of pods that opt in to it
+pub struct ClusterDns {
+    resolver: Resolver,
+    runtime: Handle,
+}
+
+impl ClusterDns {
+    /// Create a capability that looks up services with the given client. This must be
+    /// called from within the Kubelet's runtime, which lookups are run on
+    pub fn new(client: kube::Client) -> Self {
+        ClusterDns {
+            resolver: Resolver::new(client),
+            runtime: Handle::current(),
+        }
+    }
+}
+
+impl HostCapability for ClusterDns {
+    fn module(&self) -> &str {
+        "krustlet_dns"
+    }
+
+    fn resolve(&self, store: &Store, context: &HostContext, name: &str) -> Option<Extern> {
+        if name != "resolve" {
+            return None;
+        }
+        let resolver = self.resolver.clone();
+        let runtime = self.runtime.clone();
+        let pod = context.pod.clone();
+        let resolve = move |caller: Caller<'_>,
+                            name: i32,
+                            name_len: i32,
+                            addresses: i32,
+                            addresses_len: i32|
+              -> i32 {
+            let memory = match caller.get_export("memory").and_then(|e| e.into_memory()) {
+                Some(memory) => memory,
+                None => return ERR_NO_MEMORY,
+            };
+            // Modules are run on a blocking thread of their own, so the lookup can wait
+            // for the runtime
+            let lookup = |name: &str| {
+                runtime.enter(|| futures::executor::block_on(resolver.resolve(&pod, name)))
+            };
+            // The memory isn't touched by anything else while the module calls the host
+            let data = unsafe { memory.data_unchecked_mut() };
+            let name = match range(data.len(), name, name_len)
+                .and_then(|r| std::str::from_utf8(&data[r]).ok())
+            {
+                Some(name) => name.to_owned(),
+                None => return ERR_INVALID_ARGUMENT,
+            };
+            let buffer = match range(data.len(), addresses, addresses_len) {
+                Some(buffer) => buffer,
+                None => return ERR_INVALID_ARGUMENT,
+            };
+            let found = match lookup(&name) {
+                Ok(found) => found,
+                Err(e) => {
+                    warn!(
+                        "Unable to resolve {} for pod {} in namespace {}: {}",
+                        name,
+                        pod.name(),
+                        pod.namespace(),
+                        e
+                    );
+                    return ERR_LOOKUP_FAILED;
+                }
+            };
+            let buffer = &mut data[buffer];
+            for (address, slot) in found.iter().zip(buffer.chunks_exact_mut(ADDRESS_SIZE)) {
+                slot.copy_from_slice(&octets(address));
+            }
+            found.len() as i32
+        };
+        Some(Func::wrap(store, resolve).into())
+    }
+}
+
+/// The range of memory of the given length starting at the given offset, if it is all
+/// within memory of the given size
+fn range(size: usize, offset: i32, len: i32) -> Option<std::ops::Range<usize>> {
+    if offset < 0 || len < 0 {
+        return None;
+    }
+    let start = offset as usize;
+    let end = start.checked_add(len as usize)?;
+    if end > size {
+        return None;
+    }
+    Some(start..end)
+}
+
+fn octets(address: &IpAddr) -> [u8; ADDRESS_SIZE] {
+    match address {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kubelet::testing::{fake_pod, MockApiServer};
+    use kubelet::Pod;
+    use wasmtime::{Instance, Module, Val};
+
+    /// A module that passes its arguments on to `resolve`, with the names `db` at 0 and
+    /// `web` at 16 and a name that isn't UTF-8 at 8 in its memory
+    const MODULE: &str = r#"
+        (module
+          (import "krustlet_dns" "resolve" (func $resolve (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "db")
+          (data (i32.const 8) "\ff\fe")
+          (data (i32.const 16) "web")
+          (func (export "run") (param i32 i32 i32 i32) (result i32)
+            (call $resolve (local.get 0) (local.get 1) (local.get 2) (local.get 3))))
+    "#;
+
+    /// The same module without a memory for the host to read from
+    const NO_MEMORY: &str = r#"
+        (module
+          (import "krustlet_dns" "resolve" (func $resolve (param i32 i32 i32 i32) (result i32)))
+          (func (export "run") (param i32 i32 i32 i32) (result i32)
+            (call $resolve (local.get 0) (local.get 1) (local.get 2) (local.get 3))))
+    "#;
+
+    /// Where the addresses are written to
+    const ADDRESSES: i32 = 64;
+
+    /// Call `resolve` from the module with the given arguments, returning its result
+    /// and the first 32 bytes of the addresses buffer
+    fn call(dns: &ClusterDns, wat: &str, args: &[i32]) -> (i32, Vec<u8>) {
+        let store = Store::default();
+        let module = Module::new(&store, wat).unwrap();
+        let context = HostContext {
+            namespace: "apps".to_owned(),
+            pod_name: "foo".to_owned(),
+            container_name: "foo".to_owned(),
+            pod: Pod::new(fake_pod("foo", "apps")),
+        };
+        let resolve = dns.resolve(&store, &context, "resolve").unwrap();
+        let instance = Instance::new(&module, &[resolve]).unwrap();
+        let args: Vec<Val> = args.iter().map(|a| Val::I32(*a)).collect();
+        let result = instance.get_func("run").unwrap().call(&args).unwrap()[0].unwrap_i32();
+        let addresses = match instance.get_memory("memory") {
+            Some(memory) => {
+                let start = ADDRESSES as usize;
+                unsafe { memory.data_unchecked()[start..start + 32].to_vec() }
+            }
+            None => Vec::new(),
+        };
+        (result, addresses)
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_resolve_abi() {
+        let server = MockApiServer::start().await.unwrap();
+        server.insert(
+            "/api/v1/namespaces/apps/services",
+            serde_json::json!({
+                "metadata": { "name": "db", "namespace": "apps" },
+                "spec": { "clusterIP": "10.0.0.10" },
+            }),
+        );
+        server.deny("/api/v1/namespaces/apps/services/web");
+        let dns = ClusterDns::new(server.client());
+        // Modules call the host from a blocking thread, as they do when run by the provider
+        tokio::task::spawn_blocking(move || {
+            let (found, addresses) = call(&dns, MODULE, &[0, 2, ADDRESSES, 32]);
+            assert_eq!(1, found);
+            let expected = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 10];
+            assert_eq!(expected, addresses[..16]);
+            assert_eq!([0; 16], addresses[16..]);
+
+            // Addresses that don't fit in the buffer are counted without being written
+            let (found, addresses) = call(&dns, MODULE, &[0, 2, ADDRESSES, 8]);
+            assert_eq!(1, found);
+            assert_eq!(vec![0; 32], addresses);
+
+            assert_eq!(
+                ERR_INVALID_ARGUMENT,
+                call(&dns, MODULE, &[8, 2, ADDRESSES, 32]).0
+            );
+            assert_eq!(
+                ERR_INVALID_ARGUMENT,
+                call(&dns, MODULE, &[65535, 2, ADDRESSES, 32]).0
+            );
+            assert_eq!(ERR_INVALID_ARGUMENT, call(&dns, MODULE, &[0, 2, -1, 32]).0);
+            assert_eq!(
+                ERR_INVALID_ARGUMENT,
+                call(&dns, MODULE, &[0, 2, 65520, 32]).0
+            );
+            assert_eq!(
+                ERR_LOOKUP_FAILED,
+                call(&dns, MODULE, &[16, 3, ADDRESSES, 32]).0
+            );
+            assert_eq!(
+                ERR_NO_MEMORY,
+                call(&dns, NO_MEMORY, &[0, 2, ADDRESSES, 32]).0
+            );
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+//! Host functions that embedders link into the modules of pods
+//!
+//! A [`HostCapability`] provides the functions a module imports from one import
+//! module. Capabilities are registered with
+//! [`WasiProvider::with_capabilities`](crate::WasiProvider::with_capabilities) and are
+//! only linked into the modules of pods that list them in their
+//! `alpha.krustlet.dev/capabilities` annotation, see [`kubelet::capabilities`].
+//!
+//! # Example
+//! ```rust
+//! use std::sync::Arc;
+//! use kubelet::capabilities::Capabilities;
+//! use wasi_provider::host::{HostCapability, HostContext};
+//! use wasmtime::{Extern, Func, Store};
+//!
+//! /// Lets modules log a number with `krustlet_log.log_number`
+//! struct LogNumber;
+//!
+//! impl HostCapability for LogNumber {
+//!     fn module(&self) -> &str {
+//!         "krustlet_log"
+//!     }
+//!
+//!     fn resolve(&self, store: &Store, context: &HostContext, name: &str) -> Option<Extern> {
+//!         let pod = format!("{}/{}", context.namespace, context.pod_name);
+//!         match name {
+//!             "log_number" => Some(
+//!                 Func::wrap(store, move |n: i32| println!("{} logged {}", pod, n)).into(),
+//!             ),
+//!             _ => None,
+//!         }
+//!     }
+//! }
+//!
+//! let mut capabilities: Capabilities<dyn HostCapability> = Capabilities::new();
+//! capabilities.register("log", Arc::new(LogNumber));
+//! ```
+use kubelet::container::PortBinding;
+use kubelet::Pod;
+use wasmtime::{Extern, Store};
+
+/// A set of host functions that modules import from one import module
+pub trait HostCapability: Send + Sync {
+    /// The name of the import module the functions are imported from
+    fn module(&self) -> &str;
+
+    /// Create the function (or other extern) imported with the given name, or `None`
+    /// if there is no such function. This is called whenever a module is
+    /// instantiated, with the store it is instantiated in
+    fn resolve(&self, store: &Store, context: &HostContext, name: &str) -> Option<Extern>;
+}
+
+/// The container a module is instantiated for
+#[derive(Clone, Debug)]
+pub struct HostContext {
+    /// The namespace of the pod
+    pub namespace: String,
+    /// The name of the pod
+    pub pod_name: String,
+    /// The name of the container
+    pub container_name: String,
+    /// The pod itself, for capabilities that depend on more of its spec
+    pub pod: Pod,
+}
+
+impl HostContext {
+    /// The host ports bound by the container, which capabilities that listen on the
+    /// network should bind to
+    pub fn port_bindings(&self) -> Vec<PortBinding> {
+        self.pod
+            .containers()
+            .iter()
+            .find(|c| c.name == self.container_name)
+            .and_then(|c| c.ports.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(PortBinding::from_port)
+            .collect()
+    }
+}
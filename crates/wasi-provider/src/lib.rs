@@ -17,9 +17,10 @@
 //!
 //!     // Load a kubernetes configuration
 //!     let kubeconfig = kube::Config::infer().await.unwrap();
+//!     let client = kube::Client::new(kubeconfig.clone());
 //!
 //!     // Instantiate the provider type
-//!     let provider = WasiProvider::new(store, &kubelet_config, kubeconfig.clone()).await.unwrap();
+//!     let provider = WasiProvider::new(store, &kubelet_config, client).await.unwrap();
 //!     
 //!     // Instantiate the Kubelet
 //!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config);
@@ -30,144 +31,214 @@
 
 #![deny(missing_docs)]
 
+pub mod dns;
+pub mod host;
+pub mod sandbox;
 mod wasi_runtime;
 
 use std::collections::HashMap;
-use std::ops::Deref;
-use std::path::PathBuf;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use k8s_openapi::api::core::v1::Pod as KubePod;
-use kube::{api::DeleteParams, Api};
+use k8s_openapi::api::core::v1::Container as KubeContainer;
+use kubelet::annotations;
+use kubelet::capabilities::Capabilities;
+use kubelet::container::Container;
 use kubelet::module_store::ModuleStore;
+use kubelet::pod_dirs::{remove_legacy_dirs, LEGACY_VOLUMES_DIR};
+use kubelet::provider::sdk::{provider, HandleProvider, PodHandles, StateMachineProvider};
 use kubelet::provider::ProviderError;
+use kubelet::state::{PodLifecycle, StateMachine, SyncResult};
+use kubelet::stats::{PodStats, Resources, ThreadCpu};
 use kubelet::volumes::VolumeRef;
-use kubelet::{Pod, Provider};
-use log::{debug, error, info, trace};
+use kubelet::{Pod, Provider, RestartPolicy};
+use log::{debug, info, trace, warn};
+use oci_distribution::Reference;
 use tokio::sync::RwLock;
 
-use kubelet::handle::{key_from_pod, pod_key, PodHandle};
-use wasi_runtime::{HandleStopper, WasiRuntime};
+use host::{HostCapability, HostContext};
+use kubelet::handle::{key_from_pod, PodHandle};
+use sandbox::{SandboxPolicy, SandboxRequest};
+use wasi_runtime::{HandleStopper, WasiRuntime, WasmtimeConfig};
 
-const TARGET_WASM32_WASI: &str = "wasm32-wasi";
-const LOG_DIR_NAME: &str = "wasi-logs";
-const VOLUME_DIR: &str = "volumes";
+/// The directory in the data directory logs were kept in before they were kept with the
+/// rest of each pod's data
+const LEGACY_LOG_DIR: &str = "wasi-logs";
 
 /// WasiProvider provides a Kubelet runtime implementation that executes WASM
 /// binaries conforming to the WASI spec
 #[derive(Clone)]
 pub struct WasiProvider<S> {
-    handles: Arc<RwLock<HashMap<String, PodHandle<HandleStopper, wasi_runtime::LogHandleFactory>>>>,
+    handles: PodHandles<HandleStopper, wasi_runtime::LogHandleFactory>,
+    states: StateMachine,
+    /// The volumes of pods that have been set up but not added yet
+    volumes: Arc<RwLock<HashMap<String, HashMap<String, VolumeRef>>>>,
+    /// Measures the CPU of each container's thread, by pod key and container name
+    cpu: Arc<RwLock<HashMap<String, HashMap<String, ThreadCpu>>>>,
+    /// The host functions pods can opt in to
+    capabilities: Capabilities<dyn HostCapability>,
+    /// What the node allows the modules of pods to access
+    sandbox_policy: SandboxPolicy,
+    /// The largest stack the modules of pods may ask for, in bytes
+    max_wasm_stack: usize,
     store: S,
-    log_path: PathBuf,
-    kubeconfig: kube::Config,
-    volume_path: PathBuf,
+    /// The Kubelet's data directory, which holds the volumes and logs of each pod
+    data_dir: PathBuf,
+    /// Where container logs are also written for node level log shippers
+    pod_log_dir: Option<PathBuf>,
+    /// The wasmtime cache configuration that keeps compiled modules in the data
+    /// directory, unless it could not be written
+    cache_config: Option<PathBuf>,
+    client: kube::Client,
 }
 
 impl<S: ModuleStore + Send + Sync> WasiProvider<S> {
-    /// Create a new wasi provider from a module store and a kubelet config
+    /// Create a new wasi provider from a module store, a kubelet config and the client
+    /// it makes its requests to the Kubernetes API with
     pub async fn new(
         store: S,
         config: &kubelet::config::Config,
-        kubeconfig: kube::Config,
+        client: kube::Client,
     ) -> anyhow::Result<Self> {
-        let log_path = config.data_dir.join(LOG_DIR_NAME);
-        let volume_path = config.data_dir.join(VOLUME_DIR);
-        tokio::fs::create_dir_all(&log_path).await?;
-        tokio::fs::create_dir_all(&volume_path).await?;
+        tokio::fs::create_dir_all(&config.data_dir).await?;
+        remove_legacy_dirs(&config.data_dir, &[LEGACY_VOLUMES_DIR, LEGACY_LOG_DIR]).await;
+        let cache_config = match write_cache_config(&config.data_dir).await {
+            Ok(path) => Some(path),
+            Err(e) => {
+                warn!("Unable to set up the cache of compiled modules: {}", e);
+                None
+            }
+        };
         Ok(Self {
             handles: Default::default(),
+            states: StateMachine::new(client.clone()),
+            volumes: Default::default(),
+            cpu: Default::default(),
+            capabilities: Capabilities::new(),
+            sandbox_policy: SandboxPolicy::from(&config.wasi_sandbox),
+            max_wasm_stack: config.max_wasm_stack,
             store,
-            log_path,
-            volume_path,
-            kubeconfig,
+            data_dir: config.data_dir.clone(),
+            pod_log_dir: config.pod_log_dir.clone(),
+            cache_config,
+            client,
         })
     }
-}
 
-#[async_trait::async_trait]
-impl<S: ModuleStore + Send + Sync> Provider for WasiProvider<S> {
-    const ARCH: &'static str = TARGET_WASM32_WASI;
+    /// Offer the given host capabilities to the modules of pods, which opt in to them
+    /// with the `alpha.krustlet.dev/capabilities` annotation. See [`host`]
+    pub fn with_capabilities(mut self, capabilities: Capabilities<dyn HostCapability>) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
 
-    async fn add(&self, pod: Pod) -> anyhow::Result<()> {
-        // To run an Add event, we load the WASM, update the pod status to Running,
-        // and then execute the WASM, passing in the relevant data.
-        // When the pod finishes, we update the status to Succeeded unless it
-        // produces an error, in which case we mark it Failed.
+    /// Limit what the modules of pods can access, such as the node's environment and
+    /// directories, to what the given policy allows. See [`sandbox`]
+    pub fn with_sandbox_policy(mut self, policy: SandboxPolicy) -> Self {
+        self.sandbox_policy = policy;
+        self
+    }
 
-        let pod_name = pod.name();
-        let mut container_handles = HashMap::new();
+    /// The settings modules are compiled and run with, given the settings a pod asks
+    /// for, along with a message for the pod if the node doesn't allow all of them.
+    /// Prefetched modules are compiled with these too, so their cached compilation
+    /// matches the one of pods that don't ask for any settings
+    fn wasmtime_config(&self, requested: WasmtimeConfig) -> (WasmtimeConfig, Option<String>) {
+        requested
+            .with_cache(self.cache_config.clone())
+            .limit_stack(self.max_wasm_stack)
+    }
+}
 
-        let mut modules = self.store.fetch_pod_modules(&pod).await?;
-        let client = kube::Client::new(self.kubeconfig.clone());
-        let volumes = VolumeRef::volumes_from_pod(&self.volume_path, &pod, &client).await?;
-        info!("Starting containers for pod {:?}", pod_name);
-        for container in pod.containers() {
-            let env = Self::env_vars(&container, &pod, &client).await;
-            let module_data = modules
-                .remove(&container.name)
-                .expect("FATAL ERROR: module map not properly populated");
-            let container_volumes: HashMap<PathBuf, Option<PathBuf>> =
-                if let Some(volume_mounts) = container.volume_mounts.as_ref() {
-                    volume_mounts
-                        .iter()
-                        .map(|vm| -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
-                            // Check the volume exists first
-                            let vol = volumes.get(&vm.name).ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "no volume with the name of {} found for container {}",
-                                    vm.name,
-                                    container.name
-                                )
-                            })?;
-                            let mut guest_path = PathBuf::from(&vm.mount_path);
-                            if let Some(sub_path) = &vm.sub_path {
-                                guest_path.push(sub_path);
-                            }
-                            // We can safely assume that this should be valid UTF-8 because it would have
-                            // been validated by the k8s API
-                            Ok((vol.deref().clone(), Some(guest_path)))
-                        })
-                        .collect::<anyhow::Result<_>>()?
-                } else {
-                    HashMap::default()
-                };
-
-            let runtime = WasiRuntime::new(
-                module_data,
-                env,
-                Vec::default(),
-                container_volumes,
-                self.log_path.clone(),
-            )
-            .await?;
-
-            debug!("Starting container {} on thread", container.name);
-            let handle = runtime.start().await?;
-            container_handles.insert(container.name.clone(), handle);
+/// Write a wasmtime cache configuration that keeps compiled modules in the given data
+/// directory, returning its path
+async fn write_cache_config(data_dir: &Path) -> anyhow::Result<PathBuf> {
+    let dir = data_dir.join("wasmtime");
+    let cache_dir = dir.join("cache");
+    tokio::fs::create_dir_all(&cache_dir).await?;
+    let directory = cache_dir
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("the path {:?} is not valid UTF-8", cache_dir))?;
+    let mut cache = toml::value::Table::new();
+    cache.insert("enabled".to_owned(), toml::Value::Boolean(true));
+    cache.insert(
+        "directory".to_owned(),
+        toml::Value::String(directory.to_owned()),
+    );
+    let mut contents = toml::value::Table::new();
+    contents.insert("cache".to_owned(), toml::Value::Table(cache));
+    let path = dir.join("cache.toml");
+    tokio::fs::write(&path, toml::to_string(&contents)?).await?;
+    Ok(path)
+}
+
+#[provider(arch = "wasm32-wasi", states, handles)]
+impl<S: ModuleStore + Send + Sync> Provider for WasiProvider<S> {
+    /// Modules are pulled into the store and compiled with the settings of pods without
+    /// wasmtime annotations, so those pods start from the cached compilation
+    async fn prefetch(&self, image: &str) -> anyhow::Result<()> {
+        let module_data = self.store.get(&Reference::try_from(image)?).await?;
+        if self.cache_config.is_some() {
+            let (wasmtime, _) = self.wasmtime_config(WasmtimeConfig::default());
+            wasi_runtime::compile(module_data, wasmtime).await?;
         }
-        info!(
-            "All containers started for pod {:?}. Updating status",
-            pod_name
-        );
+        Ok(())
+    }
 
-        // Wrap this in a block so the write lock goes out of scope when we are done
-        {
-            // Grab the entry while we are creating things
-            let mut handles = self.handles.write().await;
-            handles.insert(
-                key_from_pod(&pod),
-                PodHandle::new(container_handles, pod, client, Some(volumes))?,
-            );
+    /// Each container's module runs on a thread of its own, whose CPU is the container's
+    /// usage. Memory is left out, as wasmtime doesn't tell how much a module uses
+    async fn pod_stats(&self, pod: &Pod) -> anyhow::Result<PodStats> {
+        let cpu = self.cpu.read().await.get(&key_from_pod(pod)).cloned();
+        let cpu = cpu.ok_or_else(|| ProviderError::PodNotFound {
+            pod_name: pod.name().to_owned(),
+        })?;
+        let mut containers = HashMap::new();
+        for (name, thread) in cpu {
+            let usage = Resources {
+                cpu_nano_cores: thread.usage().await,
+                ..Default::default()
+            };
+            containers.insert(name, usage);
         }
+        Ok(PodStats { containers })
+    }
+
+    async fn setup_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        let client = self.client.clone();
+        pod.data_dirs(&self.data_dir).create().await?;
+        let volumes = VolumeRef::volumes_from_pod(&self.data_dir, pod, &client).await?;
+        self.volumes
+            .write()
+            .await
+            .insert(key_from_pod(pod), volumes);
+        Ok(())
+    }
 
+    async fn teardown_pod(&self, pod: &Pod) -> anyhow::Result<()> {
+        // Volumes are normally cleaned up with the pod handle, but a pod that was never
+        // added still holds its volumes here
+        self.volumes.write().await.remove(&key_from_pod(pod));
         Ok(())
     }
 
+    async fn delete(&self, pod: Pod) -> anyhow::Result<()> {
+        let result = self.states.delete(self, pod.clone()).await;
+        if result.is_ok() {
+            debug!(
+                "Pod {} in namespace {} removed",
+                pod.name(),
+                pod.namespace()
+            );
+            self.handles.write().await.remove(&key_from_pod(&pod));
+        }
+        result
+    }
+
     async fn modify(&self, pod: Pod) -> anyhow::Result<()> {
         // The only things we care about are:
         // 1. metadata.deletionTimestamp => signal all containers to stop and then mark them
-        //    as terminated
+        //    as terminated, which the state machine does
         // 2. spec.containers[*].image, spec.initContainers[*].image => stop the currently
         //    running containers and start new ones?
         // 3. spec.activeDeadlineSeconds => Leaving unimplemented for now
@@ -178,74 +249,239 @@ impl<S: ModuleStore + Send + Sync> Provider for WasiProvider<S> {
             pod.namespace()
         );
         trace!("Modified pod spec: {:#?}", pod.as_kube_pod());
-        if let Some(_timestamp) = pod.deletion_timestamp() {
-            let mut handles = self.handles.write().await;
-            match handles.get_mut(&key_from_pod(&pod)) {
-                Some(h) => {
-                    h.stop().await?;
-                    // Follow up with a delete when everything is stopped
-                    let dp = DeleteParams {
-                        grace_period_seconds: Some(0),
-                        ..Default::default()
-                    };
-                    let pod_client: Api<KubePod> = Api::namespaced(
-                        kube::client::Client::new(self.kubeconfig.clone()),
-                        pod.namespace(),
-                    );
-                    match pod_client.delete(pod.name(), &dp).await {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(e.into()),
-                    }
+        self.states.modify(self, pod).await
+        // TODO: Implement behavior for stopping old containers and restarting when the container
+        // image changes
+    }
+
+    async fn add_ephemeral_container(&self, pod: Pod, spec: KubeContainer) -> anyhow::Result<()> {
+        let image = spec
+            .image
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("ephemeral container {} has no image", spec.name))?;
+        let module_data = self.store.fetch_container_module(&pod, &image).await?;
+        let client = self.client.clone();
+
+        let handles = self.handles.read().await;
+        let handle =
+            handles
+                .get(&key_from_pod(&pod))
+                .ok_or_else(|| ProviderError::PodNotFound {
+                    pod_name: pod.name().to_owned(),
+                })?;
+        // Ephemeral containers get the same view of the pod's volumes and environment as
+        // its other containers
+        let container = Container::resolve::<Self>(&spec, &pod, &client, handle.volumes()).await?;
+        // What the pod's annotations ask for that the node denies was already reported
+        // when the pod was added
+        let (sandbox, _) = self
+            .sandbox_policy
+            .sandbox(annotations::parse::<SandboxRequest>(&pod).0);
+        let (container_volumes, denied) = sandbox.preopens(
+            pod.data_dirs(&self.data_dir).root(),
+            container.volume_mounts(),
+        );
+        for message in denied {
+            annotations::report_denied(&client, &pod, &message).await;
+        }
+        let runtime = WasiRuntime::new(
+            module_data,
+            sandbox.env(container.env()),
+            container.spec().args.clone().unwrap_or_default(),
+            container_volumes,
+            // Ephemeral containers are never restarted
+            RestartPolicy::Never,
+            // Invalid annotations and stacks that are too large were already
+            // reported when the pod was added
+            self.wasmtime_config(annotations::parse::<WasmtimeConfig>(&pod).0)
+                .0,
+            pod.data_dirs(&self.data_dir).logs(),
+        )
+        .await?
+        .with_capabilities(
+            host_context(&pod, &container),
+            self.capabilities.requested(&pod).0,
+        )
+        .with_sandbox(sandbox)
+        .with_concurrency(pod.concurrency().clone());
+
+        debug!(
+            "Starting ephemeral container {} on thread",
+            container.name()
+        );
+        let runtime_handle = runtime.start().await?;
+        self.cpu
+            .write()
+            .await
+            .entry(key_from_pod(&pod))
+            .or_default()
+            .insert(container.name().to_owned(), runtime.cpu());
+        handle
+            .add_container(container.name().to_owned(), runtime_handle)
+            .await;
+        Ok(())
+    }
+}
+
+impl<S: ModuleStore + Send + Sync> StateMachineProvider for WasiProvider<S> {
+    fn state_machine(&self) -> &StateMachine {
+        &self.states
+    }
+}
+
+impl<S: ModuleStore + Send + Sync> HandleProvider for WasiProvider<S> {
+    type Stopper = HandleStopper;
+    type Reader = tokio::fs::File;
+    type LogHandles = wasi_runtime::LogHandleFactory;
+
+    fn pod_handles(&self) -> &PodHandles<HandleStopper, wasi_runtime::LogHandleFactory> {
+        &self.handles
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: ModuleStore + Send + Sync> PodLifecycle for WasiProvider<S> {
+    type Pulled = HashMap<String, Vec<u8>>;
+
+    async fn image_pull(&self, pod: &Pod) -> anyhow::Result<Self::Pulled> {
+        self.store.fetch_pod_modules(pod).await
+    }
+
+    async fn starting(&self, pod: &Pod, mut modules: Self::Pulled) -> anyhow::Result<SyncResult> {
+        // To start a pod, we execute the WASM of each container, passing in the relevant
+        // data. When the pod finishes, we update the status to Succeeded unless it
+        // produces an error, in which case we mark it Failed.
+        let pod_name = pod.name();
+        let mut container_handles = HashMap::new();
+        let mut container_cpu = HashMap::new();
+
+        let client = self.client.clone();
+        let (wasmtime, denied) =
+            self.wasmtime_config(annotations::load::<WasmtimeConfig>(&client, pod).await);
+        if let Some(message) = denied {
+            annotations::report_denied(&client, pod, &message).await;
+        }
+        let capabilities = self.capabilities.load(&client, pod).await;
+        let (sandbox, denied) = self
+            .sandbox_policy
+            .sandbox(annotations::load::<SandboxRequest>(&client, pod).await);
+        for message in denied {
+            annotations::report_denied(&client, pod, &message).await;
+        }
+        let dirs = pod.data_dirs(&self.data_dir);
+        dirs.create().await?;
+        let set_up = self.volumes.write().await.remove(&key_from_pod(pod));
+        let volumes = match set_up {
+            Some(volumes) => volumes,
+            None => VolumeRef::volumes_from_pod(&self.data_dir, pod, &client).await?,
+        };
+        info!("Starting containers for pod {:?}", pod_name);
+        let started: anyhow::Result<()> = async {
+            for container in Container::resolve_all::<Self>(pod, &client, &volumes).await? {
+                let module_data = modules
+                    .remove(container.name())
+                    .expect("FATAL ERROR: module map not properly populated");
+                let (container_volumes, denied) =
+                    sandbox.preopens(dirs.root(), container.volume_mounts());
+                for message in denied {
+                    annotations::report_denied(&client, pod, &message).await;
                 }
-                None => {
-                    // This isn't an error with the pod, so don't return an error (otherwise it will
-                    // get updated in its status). This is an unlikely case to get into and means
-                    // that something is likely out of sync, so just log the error
-                    error!(
-                        "Unable to find pod {} in namespace {} when trying to stop all containers",
-                        pod.name(),
-                        pod.namespace()
+
+                let runtime = WasiRuntime::new(
+                    module_data,
+                    sandbox.env(container.env()),
+                    Vec::default(),
+                    container_volumes,
+                    pod.restart_policy(),
+                    wasmtime.clone(),
+                    dirs.logs(),
+                )
+                .await?
+                .with_capabilities(host_context(pod, &container), capabilities.clone())
+                .with_sandbox(sandbox.clone())
+                .with_concurrency(pod.concurrency().clone());
+
+                debug!("Starting container {} on thread", container.name());
+                let handle = runtime.start().await?;
+                container_handles.insert(container.name().to_owned(), handle);
+                container_cpu.insert(container.name().to_owned(), runtime.cpu());
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = started {
+            // The containers that did start are stopped, so retrying the start doesn't
+            // leave them running
+            for (name, mut handle) in container_handles {
+                if let Err(stop_error) = handle.stop().await {
+                    warn!(
+                        "Unable to stop container {} of pod {} after its start failed: {}",
+                        name, pod_name, stop_error
                     );
-                    Ok(())
                 }
             }
-        } else {
-            Ok(())
+            return Err(e);
         }
-        // TODO: Implement behavior for stopping old containers and restarting when the container
-        // image changes
+        info!(
+            "All containers started for pod {:?}. Updating status",
+            pod_name
+        );
+
+        // Wrap this in a block so the write lock goes out of scope when we are done
+        {
+            // Grab the entry while we are creating things
+            let mut handle = PodHandle::new(container_handles, pod.clone(), client, Some(volumes))?;
+            if let Some(dir) = &self.pod_log_dir {
+                handle = handle.with_log_files(dir);
+            }
+            let mut handles = self.handles.write().await;
+            handles.insert(key_from_pod(pod), handle);
+        }
+        self.cpu
+            .write()
+            .await
+            .insert(key_from_pod(pod), container_cpu);
+
+        Ok(SyncResult::Done)
     }
 
-    async fn delete(&self, pod: Pod) -> anyhow::Result<()> {
+    async fn terminated(&self, pod: &Pod) -> anyhow::Result<()> {
+        self.cpu.write().await.remove(&key_from_pod(pod));
         let mut handles = self.handles.write().await;
-        match handles.remove(&key_from_pod(&pod)) {
-            Some(_) => debug!(
-                "Pod {} in namespace {} removed",
-                pod.name(),
-                pod.namespace()
-            ),
-            None => info!(
-                "unable to find pod {} in namespace {}, it was likely already deleted",
-                pod.name(),
-                pod.namespace()
-            ),
+        // The handle is kept until the pod is deleted, so the logs of its stopped
+        // containers can still be read
+        match handles.get_mut(&key_from_pod(pod)) {
+            // The kubelet reports the terminated statuses and removes the pod once
+            // everything is stopped
+            Some(h) => {
+                h.stop().await?;
+                debug!(
+                    "Pod {} in namespace {} stopped",
+                    pod.name(),
+                    pod.namespace()
+                );
+                Ok(())
+            }
+            None => {
+                // This isn't an error with the pod, so don't return an error (otherwise it will
+                // get updated in its status). This happens if the pod never started or
+                // was already removed
+                info!(
+                    "unable to find pod {} in namespace {}, it was likely already deleted",
+                    pod.name(),
+                    pod.namespace()
+                );
+                Ok(())
+            }
         }
-        Ok(())
     }
+}
 
-    async fn logs(
-        &self,
-        namespace: String,
-        pod_name: String,
-        container_name: String,
-        sender: kubelet::LogSender,
-    ) -> anyhow::Result<()> {
-        let mut handles = self.handles.write().await;
-        let handle = handles
-            .get_mut(&pod_key(&namespace, &pod_name))
-            .ok_or_else(|| ProviderError::PodNotFound {
-                pod_name: pod_name.clone(),
-            })?;
-        handle.output(&container_name, sender).await
+fn host_context(pod: &Pod, container: &Container) -> HostContext {
+    HostContext {
+        namespace: pod.namespace().to_owned(),
+        pod_name: pod.name().to_owned(),
+        container_name: container.name().to_owned(),
+        pod: pod.clone(),
     }
 }
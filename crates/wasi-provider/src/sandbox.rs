@@ -0,0 +1,375 @@
+//! What the WASI sandbox exposes to modules
+//!
+//! The node's [`SandboxPolicy`] comes from the Kubelet's
+//! [`WasiSandboxConfig`](kubelet::config::WasiSandboxConfig), set with the `--wasi-*`
+//! flags, or is set with [`WasiProvider::with_sandbox_policy`]. Pods ask for what their
+//! modules need with these annotations:
+//!
+//! - `alpha.krustlet.dev/wasi-env-passthrough` lists the node's environment variables,
+//!   separated by `,`, that are passed through to the modules. The container's own
+//!   environment takes precedence over them
+//! - `alpha.krustlet.dev/wasi-preopened-dirs` lists the host directories, separated by
+//!   `,`, that `hostPath` volumes may be preopened from. Volumes kept in the pod's own
+//!   directory, such as config maps and secrets, are always preopened
+//! - `alpha.krustlet.dev/wasi-wall-clock` is `false` to hide the time of day from the
+//!   modules. Other clocks then count from when the module started
+//! - `alpha.krustlet.dev/wasi-random` is `false` to deny the modules random numbers.
+//!   Note that many programs, such as those using Rust's `HashMap`, fail without them
+//!
+//! A pod gets what it asks for that the policy allows, and the policy's settings for
+//! what it doesn't ask for. Anything else it asks for is left out when its modules are
+//! instantiated and reported as a `PolicyDenied` warning event on the pod. Directories
+//! are compared once symlinks are resolved, so a link can't lead out of a directory
+//! the policy allows.
+//!
+//! [`WasiProvider::with_sandbox_policy`]: crate::WasiProvider::with_sandbox_policy
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use kubelet::annotations::{AnnotationConfig, Annotations};
+use kubelet::config::WasiSandboxConfig;
+use kubelet::container::VolumeMount;
+
+/// What the node allows the modules of its pods to access
+#[derive(Clone, Debug, PartialEq)]
+pub struct SandboxPolicy {
+    /// The node's environment variables that pods may pass through to their modules.
+    /// None are passed through unless a pod asks for them
+    pub env_passthrough: Vec<String>,
+    /// The host directories, and the directories below them, that `hostPath` volumes
+    /// may be preopened from. `None` allows any directory
+    pub preopened_dirs: Option<Vec<PathBuf>>,
+    /// Whether modules may read the wall clock
+    pub wall_clock: bool,
+    /// Whether modules may get random numbers
+    pub random: bool,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy {
+            env_passthrough: Vec::new(),
+            preopened_dirs: None,
+            wall_clock: true,
+            random: true,
+        }
+    }
+}
+
+impl From<&WasiSandboxConfig> for SandboxPolicy {
+    fn from(config: &WasiSandboxConfig) -> Self {
+        SandboxPolicy {
+            env_passthrough: config.env_passthrough.clone(),
+            preopened_dirs: config.preopened_dirs.clone(),
+            wall_clock: config.wall_clock,
+            random: config.random,
+        }
+    }
+}
+
+impl SandboxPolicy {
+    /// The sandbox of a pod that asked for the given access, alongside what the policy
+    /// denied it
+    pub(crate) fn sandbox(&self, request: SandboxRequest) -> (Sandbox, Vec<String>) {
+        let mut denied = Vec::new();
+        let mut env = HashMap::new();
+        for name in request.env_passthrough {
+            if !self.env_passthrough.contains(&name) {
+                denied.push(format!(
+                    "passing the node's environment variable {} through is not allowed",
+                    name
+                ));
+            } else if let Ok(value) = std::env::var(&name) {
+                env.insert(name, value);
+            }
+        }
+        let preopened_dirs = match request.preopened_dirs {
+            Some(dirs) => {
+                let (allowed, refused): (Vec<_>, Vec<_>) = dirs
+                    .into_iter()
+                    .partition(|dir| allows(&self.preopened_dirs, dir));
+                for dir in refused {
+                    denied.push(format!(
+                        "preopening directory {} is not allowed",
+                        dir.display()
+                    ));
+                }
+                Some(allowed)
+            }
+            None => self.preopened_dirs.clone(),
+        };
+        if request.wall_clock == Some(true) && !self.wall_clock {
+            denied.push("access to the wall clock is not allowed".to_owned());
+        }
+        if request.random == Some(true) && !self.random {
+            denied.push("access to random numbers is not allowed".to_owned());
+        }
+        let sandbox = Sandbox {
+            env,
+            preopened_dirs,
+            wall_clock: self.wall_clock && request.wall_clock.unwrap_or(true),
+            random: self.random && request.random.unwrap_or(true),
+        };
+        (sandbox, denied)
+    }
+}
+
+/// The access a pod asks for in its `alpha.krustlet.dev/wasi-*` annotations
+#[derive(Debug, Default)]
+pub(crate) struct SandboxRequest {
+    env_passthrough: Vec<String>,
+    preopened_dirs: Option<Vec<PathBuf>>,
+    wall_clock: Option<bool>,
+    random: Option<bool>,
+}
+
+impl AnnotationConfig for SandboxRequest {
+    fn from_annotations(annotations: &mut Annotations) -> Self {
+        SandboxRequest {
+            env_passthrough: annotations
+                .raw("wasi-env-passthrough")
+                .map(split_list)
+                .unwrap_or_default(),
+            preopened_dirs: annotations
+                .raw("wasi-preopened-dirs")
+                .map(|list| split_list(list).into_iter().map(PathBuf::from).collect()),
+            wall_clock: annotations.get("wasi-wall-clock"),
+            random: annotations.get("wasi-random"),
+        }
+    }
+}
+
+/// What the modules of a pod can access
+#[derive(Clone, Debug)]
+pub(crate) struct Sandbox {
+    /// The node's environment variables passed through, with their values
+    env: HashMap<String, String>,
+    /// The host directories `hostPath` volumes may be preopened from, or `None` for any
+    preopened_dirs: Option<Vec<PathBuf>>,
+    /// Whether modules can read the wall clock
+    pub(crate) wall_clock: bool,
+    /// Whether modules can get random numbers
+    pub(crate) random: bool,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        SandboxPolicy::default()
+            .sandbox(SandboxRequest::default())
+            .0
+    }
+}
+
+impl Sandbox {
+    /// The environment of a container's modules, which is its own environment and the
+    /// node's environment variables passed through
+    pub(crate) fn env(&self, container_env: &HashMap<String, String>) -> HashMap<String, String> {
+        let mut env = self.env.clone();
+        env.extend(container_env.clone());
+        env
+    }
+
+    /// The directories preopened for a container's volume mounts, as a map of host
+    /// directories to their paths in the module, alongside the mounts that may not be
+    /// preopened. Mounts of volumes in the pod's directory are always preopened
+    pub(crate) fn preopens(
+        &self,
+        pod_dir: &Path,
+        mounts: &[VolumeMount],
+    ) -> (HashMap<PathBuf, Option<PathBuf>>, Vec<String>) {
+        let mut preopens = HashMap::new();
+        let mut denied = Vec::new();
+        for mount in mounts {
+            let in_pod_dir = is_below(&mount.host_path, pod_dir);
+            if in_pod_dir || allows(&self.preopened_dirs, &mount.host_path) {
+                preopens.insert(mount.host_path.clone(), Some(mount.guest_path.clone()));
+            } else {
+                denied.push(format!(
+                    "preopening volume {} from {} is not allowed",
+                    mount.name,
+                    mount.host_path.display()
+                ));
+            }
+        }
+        (preopens, denied)
+    }
+}
+
+/// Whether the directory is one of the allowed directories or below one of them
+fn allows(allowed: &Option<Vec<PathBuf>>, dir: &Path) -> bool {
+    match allowed {
+        Some(allowed) => allowed.iter().any(|a| is_below(dir, a)),
+        None => true,
+    }
+}
+
+/// Whether the directory is the parent or below it, once the symlinks in both are
+/// resolved
+fn is_below(dir: &Path, parent: &Path) -> bool {
+    match (resolve(dir), resolve(parent)) {
+        (Some(dir), Some(parent)) => dir.starts_with(parent),
+        _ => false,
+    }
+}
+
+/// The absolute path with its symlinks and `..` components resolved, as far as it
+/// exists. `None` if that can't be told, such as for a `..` below a part of the path
+/// that doesn't exist yet
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(resolved) => {
+                return Some(missing.iter().rev().fold(resolved, |path, c| path.join(c)))
+            }
+            Err(_) => {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+        }
+    }
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("krustlet-sandbox-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn mount(name: &str, host_path: &Path) -> VolumeMount {
+        VolumeMount {
+            name: name.to_owned(),
+            host_path: host_path.to_owned(),
+            guest_path: PathBuf::from("/").join(name),
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let allowed = temp_dir("allowed");
+        std::env::set_var("KRUSTLET_SANDBOX_TEST", "passed");
+        let policy = SandboxPolicy {
+            env_passthrough: vec!["KRUSTLET_SANDBOX_TEST".to_owned()],
+            preopened_dirs: Some(vec![allowed.clone()]),
+            wall_clock: false,
+            random: true,
+        };
+        let request = SandboxRequest {
+            env_passthrough: vec!["KRUSTLET_SANDBOX_TEST".to_owned(), "HOME".to_owned()],
+            preopened_dirs: Some(vec![allowed.join("data"), PathBuf::from("/etc")]),
+            wall_clock: Some(true),
+            random: Some(false),
+        };
+        let (sandbox, denied) = policy.sandbox(request);
+        assert_eq!(3, denied.len(), "{:?}", denied);
+        assert!(denied.iter().any(|d| d.contains("HOME")));
+        assert!(denied.iter().any(|d| d.contains("/etc")));
+        assert!(denied.iter().any(|d| d.contains("wall clock")));
+        let container_env = vec![("PORT".to_owned(), "8080".to_owned())]
+            .into_iter()
+            .collect();
+        let env = sandbox.env(&container_env);
+        assert_eq!(
+            Some("passed"),
+            env.get("KRUSTLET_SANDBOX_TEST").map(|v| v.as_str())
+        );
+        assert_eq!(Some("8080"), env.get("PORT").map(|v| v.as_str()));
+        assert!(!env.contains_key("HOME"));
+        assert_eq!(Some(vec![allowed.join("data")]), sandbox.preopened_dirs);
+        assert!(!sandbox.wall_clock);
+        assert!(!sandbox.random);
+
+        // What a pod doesn't ask for is up to the policy
+        let (sandbox, denied) = policy.sandbox(SandboxRequest::default());
+        assert!(denied.is_empty());
+        assert!(sandbox.env(&HashMap::new()).is_empty());
+        assert_eq!(Some(vec![allowed]), sandbox.preopened_dirs);
+        assert!(!sandbox.wall_clock);
+        assert!(sandbox.random);
+    }
+
+    #[test]
+    fn test_preopens() {
+        let pod_dir = temp_dir("pod");
+        let allowed = temp_dir("preopened");
+        let outside = temp_dir("outside");
+        std::fs::create_dir_all(pod_dir.join("config")).unwrap();
+        let policy = SandboxPolicy {
+            preopened_dirs: Some(vec![allowed.clone()]),
+            ..Default::default()
+        };
+        let (sandbox, _) = policy.sandbox(SandboxRequest::default());
+        let mounts = vec![
+            mount("config", &pod_dir.join("config")),
+            mount("data", &allowed),
+            mount("etc", &outside),
+            mount("up", &allowed.join("..").join(outside.file_name().unwrap())),
+        ];
+        let (preopens, denied) = sandbox.preopens(&pod_dir, &mounts);
+        assert_eq!(2, preopens.len());
+        assert_eq!(
+            Some(&Some(PathBuf::from("/config"))),
+            preopens.get(&pod_dir.join("config"))
+        );
+        assert!(preopens.contains_key(&allowed));
+        assert_eq!(2, denied.len(), "{:?}", denied);
+        assert!(denied.iter().any(|d| d.contains("volume etc")));
+        assert!(denied.iter().any(|d| d.contains("volume up")));
+
+        // Without a list of directories any directory may be preopened
+        let (preopens, denied) = Sandbox::default().preopens(&pod_dir, &mounts);
+        assert_eq!(4, preopens.len());
+        assert!(denied.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_do_not_escape() {
+        let allowed = temp_dir("linked");
+        let outside = temp_dir("target");
+        let link = allowed.join("link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&outside, &link).unwrap();
+        let dirs = Some(vec![allowed.clone()]);
+        assert!(allows(&dirs, &allowed.join("data")));
+        assert!(!allows(&dirs, &link));
+        assert!(!allows(&dirs, &link.join("missing")));
+        assert!(!allows(
+            &dirs,
+            &allowed.join("missing").join("..").join("..")
+        ));
+        assert!(allows(&None, &link));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = temp_dir("resolve");
+        let canonical = dir.canonicalize().unwrap();
+        assert_eq!(Some(canonical.clone()), resolve(&dir));
+        assert_eq!(
+            Some(canonical.join("a").join("b")),
+            resolve(&dir.join("a/b"))
+        );
+        assert_eq!(
+            Some(canonical.clone()),
+            resolve(&dir.join("..").join(dir.file_name().unwrap()))
+        );
+        assert_eq!(None, resolve(&dir.join("missing/..")));
+        assert_eq!(None, resolve(Path::new("relative/missing")));
+    }
+}
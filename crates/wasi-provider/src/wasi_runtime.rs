@@ -1,31 +1,144 @@
 use anyhow::bail;
-use log::{error, info};
+use log::{error, info, warn};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
-use tokio::sync::{
-    oneshot,
-    watch::{self, Sender},
-};
+use tokio::sync::watch::{self, Sender};
 use tokio::task::JoinHandle;
 use wasi_common::preopen_dir;
-use wasmtime::InterruptHandle;
+use wasmtime::{Caller, Extern, Func, InterruptHandle, Trap};
 use wasmtime_wasi::old::snapshot_0::Wasi as WasiUnstable;
 use wasmtime_wasi::{Wasi, WasiCtxBuilder};
 
+use kubelet::annotations::{AnnotationConfig, Annotations};
+use kubelet::concurrency::{Limits, Operation};
 use kubelet::handle::{RuntimeHandle, Stop};
+use kubelet::stats::ThreadCpu;
 use kubelet::status::ContainerStatus;
+use kubelet::RestartPolicy;
+
+use crate::host::{HostCapability, HostContext};
+use crate::sandbox::Sandbox;
+
+/// How long to wait before the first restart of a module. The wait doubles with each
+/// restart, up to [`MAX_RESTART_BACKOFF`]
+const RESTART_BACKOFF: Duration = Duration::from_secs(10);
+/// The longest time to wait before restarting a module
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(300);
+
+/// The WASI errors returned by the functions the sandbox replaces, which are the same
+/// in both WASI snapshots
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_FAULT: i32 = 21;
+const ERRNO_INVAL: i32 = 28;
+const ERRNO_NOTCAPABLE: i32 = 76;
+/// The WASI clock that tells the time of day
+const CLOCK_REALTIME: i32 = 0;
+/// The last of the WASI clocks, the thread's CPU time
+const CLOCK_THREAD_CPUTIME_ID: i32 = 3;
+
+/// Wasmtime settings for a pod, read from its `alpha.krustlet.dev/wasmtime-*` annotations
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WasmtimeConfig {
+    /// The maximum stack size of the module in bytes
+    max_wasm_stack: Option<usize>,
+    /// Whether to enable WebAssembly SIMD support
+    simd: bool,
+    /// Whether to enable the WebAssembly bulk memory operations proposal
+    bulk_memory: bool,
+    /// Whether to enable the WebAssembly multi-value proposal
+    multi_value: bool,
+    /// Whether to generate debug info for the module
+    debug_info: bool,
+    /// The settings of wasmtime's cache of compiled modules, which the provider sets
+    /// rather than the pod
+    cache_config: Option<PathBuf>,
+}
+
+impl AnnotationConfig for WasmtimeConfig {
+    fn from_annotations(annotations: &mut Annotations) -> Self {
+        WasmtimeConfig {
+            max_wasm_stack: annotations.get("wasmtime-max-wasm-stack"),
+            simd: annotations.get("wasmtime-simd").unwrap_or_default(),
+            bulk_memory: annotations.get("wasmtime-bulk-memory").unwrap_or_default(),
+            multi_value: annotations.get("wasmtime-multi-value").unwrap_or_default(),
+            debug_info: annotations.get("wasmtime-debug-info").unwrap_or_default(),
+            cache_config: None,
+        }
+    }
+}
+
+impl WasmtimeConfig {
+    /// Cache the modules compiled with these settings as the given wasmtime cache
+    /// configuration file says
+    pub(crate) fn with_cache(mut self, cache_config: Option<PathBuf>) -> Self {
+        self.cache_config = cache_config;
+        self
+    }
+
+    /// Cut the stack the pod asks for down to the given size, returning a message for
+    /// the pod if it asked for more
+    pub(crate) fn limit_stack(mut self, max: usize) -> (Self, Option<String>) {
+        let denied = match self.max_wasm_stack {
+            Some(size) if size > max => {
+                self.max_wasm_stack = Some(max);
+                Some(format!(
+                    "a wasm stack of {} bytes is not allowed, so it is limited to {} bytes",
+                    size, max
+                ))
+            }
+            _ => None,
+        };
+        (self, denied)
+    }
+
+    /// The engine settings modules are compiled and run with. Prefetched modules are
+    /// compiled with the same settings, so the cached compilation matches
+    fn engine_config(&self) -> wasmtime::Config {
+        let mut config = wasmtime::Config::new();
+        self.apply(&mut config);
+        config.interruptable(true);
+        if let Some(path) = &self.cache_config {
+            if let Err(e) = config.cache_config_load(path) {
+                warn!("Unable to enable the cache of compiled modules: {}", e);
+            }
+        }
+        config
+    }
+
+    fn apply(&self, config: &mut wasmtime::Config) {
+        if let Some(size) = self.max_wasm_stack {
+            config.max_wasm_stack(size);
+        }
+        config
+            .wasm_simd(self.simd)
+            .wasm_bulk_memory(self.bulk_memory)
+            .wasm_multi_value(self.multi_value)
+            .debug_info(self.debug_info);
+    }
+}
 
 pub struct HandleStopper {
     handle: JoinHandle<anyhow::Result<()>>,
-    interrupt_handle: InterruptHandle,
+    /// Interrupts the current run of the module. Each run has a store of its own, so
+    /// this is replaced when the module is restarted
+    interrupt_handle: Arc<Mutex<Option<InterruptHandle>>>,
+    /// Set when the runtime is stopped so the module is not restarted
+    stopped: Arc<AtomicBool>,
 }
 
 #[async_trait::async_trait]
 impl Stop for HandleStopper {
     async fn stop(&mut self) -> anyhow::Result<()> {
-        self.interrupt_handle.interrupt();
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(interrupt_handle) = self.interrupt_handle.lock().unwrap().as_ref() {
+            interrupt_handle.interrupt();
+        }
         Ok(())
     }
 
@@ -42,6 +155,34 @@ pub struct WasiRuntime {
     data: Arc<Data>,
     /// The tempfile that output from the wasmtime process writes to
     output: Arc<NamedTempFile>,
+    /// The host capabilities linked into the module
+    host: Option<Arc<Host>>,
+    /// What the module can access
+    sandbox: Sandbox,
+    /// Measures the CPU of the thread the module runs on
+    cpu: ThreadCpu,
+    /// The limits compiling the module runs under
+    concurrency: Limits,
+}
+
+/// The host capabilities of a container
+struct Host {
+    context: HostContext,
+    capabilities: Vec<Arc<dyn HostCapability>>,
+}
+
+impl Host {
+    /// Resolve an import from the capabilities that provide its import module
+    fn resolve(&self, store: &wasmtime::Store, module: &str, name: &str) -> Option<Extern> {
+        self.capabilities
+            .iter()
+            .filter(|c| c.module() == module)
+            .find_map(|c| c.resolve(store, &self.context, name))
+    }
+
+    fn provides(&self, module: &str) -> bool {
+        self.capabilities.iter().any(|c| c.module() == module)
+    }
 }
 
 struct Data {
@@ -55,6 +196,10 @@ struct Data {
     /// (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     /// the same path will be allowed in the runtime
     dirs: HashMap<PathBuf, Option<PathBuf>>,
+    /// whether the module is run again after it exits
+    restart_policy: RestartPolicy,
+    /// the settings wasmtime runs the module with
+    wasmtime: WasmtimeConfig,
 }
 
 /// Holds our tempfile handle.
@@ -80,12 +225,16 @@ impl WasiRuntime {
     /// * `dirs` - a map of local file system paths to optional path names in the runtime
     ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
     ///     the same path will be allowed in the runtime
+    /// * `restart_policy` - whether the module is run again after it exits
+    /// * `wasmtime` - the settings wasmtime runs the module with
     /// * `log_dir` - location for storing logs
     pub async fn new<L: AsRef<Path> + Send + Sync + 'static>(
         module_data: Vec<u8>,
         env: HashMap<String, String>,
         args: Vec<String>,
         dirs: HashMap<PathBuf, Option<PathBuf>>,
+        restart_policy: RestartPolicy,
+        wasmtime: WasmtimeConfig,
         log_dir: L,
     ) -> anyhow::Result<Self> {
         let temp = tokio::task::spawn_blocking(move || -> anyhow::Result<NamedTempFile> {
@@ -105,11 +254,49 @@ impl WasiRuntime {
                 env,
                 args,
                 dirs,
+                restart_policy,
+                wasmtime,
             }),
             output: Arc::new(temp),
+            host: None,
+            sandbox: Sandbox::default(),
+            cpu: ThreadCpu::default(),
+            concurrency: Limits::default(),
         })
     }
 
+    /// Link the functions of the given host capabilities into the module when it is
+    /// instantiated for the given container
+    pub fn with_capabilities(
+        mut self,
+        context: HostContext,
+        capabilities: Vec<Arc<dyn HostCapability>>,
+    ) -> Self {
+        self.host = Some(Arc::new(Host {
+            context,
+            capabilities,
+        }));
+        self
+    }
+
+    /// Limit what the module can access to the given sandbox. The environment and
+    /// directories of the sandbox are those given to [`WasiRuntime::new`]
+    pub(crate) fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Compile the module under the given limits, such as those of the pod it runs for
+    pub fn with_concurrency(mut self, limits: Limits) -> Self {
+        self.concurrency = limits;
+        self
+    }
+
+    /// Measures the CPU the module uses while it runs
+    pub(crate) fn cpu(&self) -> ThreadCpu {
+        self.cpu.clone()
+    }
+
     pub async fn start(&self) -> anyhow::Result<RuntimeHandle<HandleStopper, LogHandleFactory>> {
         let temp = self.output.clone();
         // Because a reopen is blocking, run in a blocking task to get new
@@ -119,11 +306,19 @@ impl WasiRuntime {
         })
         .await??;
 
-        let (status_sender, status_recv) = watch::channel(ContainerStatus::Waiting {
-            timestamp: chrono::Utc::now(),
-            message: "No status has been received from the process".into(),
-        });
-        let (interrupt_handle, handle) = self.spawn_wasmtime(status_sender, output_write).await?;
+        let (status_sender, status_recv) = watch::channel(ContainerStatus::waiting(
+            "No status has been received from the process",
+        ));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let interrupt_handle = Arc::new(Mutex::new(None));
+        let handle = self
+            .spawn_wasmtime(
+                status_sender,
+                output_write,
+                stopped.clone(),
+                interrupt_handle.clone(),
+            )
+            .await;
 
         let log_handle_factory = LogHandleFactory {
             temp: self.output.clone(),
@@ -133,6 +328,7 @@ impl WasiRuntime {
             HandleStopper {
                 handle,
                 interrupt_handle,
+                stopped,
             },
             log_handle_factory,
             status_recv,
@@ -141,174 +337,368 @@ impl WasiRuntime {
 
     // Spawns a running wasmtime instance with the given context and status
     // channel. Due to the Instance type not being Send safe, all of the logic
-    // needs to be done within the spawned task
+    // needs to be done within the spawned task. The module is run again when it
+    // exits if the restart policy allows it and the runtime has not been stopped.
+    // Each run gets a fresh store, as a store keeps the instances and memory of every
+    // run in it until it is dropped
     async fn spawn_wasmtime(
         &self,
         status_sender: Sender<ContainerStatus>,
         output_write: std::fs::File,
-    ) -> anyhow::Result<(InterruptHandle, JoinHandle<anyhow::Result<()>>)> {
+        stopped: Arc<AtomicBool>,
+        interrupt_handle: Arc<Mutex<Option<InterruptHandle>>>,
+    ) -> JoinHandle<anyhow::Result<()>> {
         // Clone the module data Arc so it can be moved
         let data = self.data.clone();
+        let host = self.host.clone();
+        let sandbox = self.sandbox.clone();
+        let cpu = self.cpu.clone();
+        let concurrency = self.concurrency.clone();
 
-        let (tx, rx) = oneshot::channel();
-
-        let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-            // Build the WASI instance and then generate a list of WASI modules
-            let mut ctx_builder_snapshot = WasiCtxBuilder::new();
-            let mut ctx_builder_snapshot = ctx_builder_snapshot
-                .args(&data.args)
-                .envs(&data.env)
-                .stdout(output_write.try_clone()?)
-                .stderr(output_write.try_clone()?);
-            let mut ctx_builder_unstable = wasi_common::old::snapshot_0::WasiCtxBuilder::new();
-            let mut ctx_builder_unstable = ctx_builder_unstable
-                .args(&data.args)
-                .envs(&data.env)
-                .stdout(output_write.try_clone()?)
-                .stderr(output_write);
-
-            for (key, value) in data.dirs.iter() {
-                let guest_dir = value.as_ref().unwrap_or(key);
-                ctx_builder_snapshot =
-                    ctx_builder_snapshot.preopened_dir(preopen_dir(key)?, guest_dir);
-                ctx_builder_unstable =
-                    ctx_builder_unstable.preopened_dir(preopen_dir(key)?, guest_dir);
-            }
-            let wasi_ctx_snapshot = ctx_builder_snapshot.build()?;
-            let wasi_ctx_unstable = ctx_builder_unstable.build()?;
-            let mut config = wasmtime::Config::new();
-            config.interruptable(true);
-            let engine = wasmtime::Engine::new(&config);
-            let store = wasmtime::Store::new(&engine);
-            let interrupt = store.interrupt_handle()?;
-            tx.send(interrupt)
-                .map_err(|_| anyhow::anyhow!("Unable to send interrupt back to main thread"))?;
-
-            let wasi_snapshot = Wasi::new(&store, wasi_ctx_snapshot);
-            let wasi_unstable = WasiUnstable::new(&store, wasi_ctx_unstable);
-            let module = match wasmtime::Module::new(&store, &data.module_data) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "unable to create module";
-                    error!("{}: {:?}", message, e);
-                    status_sender
-                        .broadcast(ContainerStatus::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        })
-                        .expect("status should be able to send");
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
-                }
-            };
-            // Iterate through the module includes and resolve imports
-            let imports = module
-                .imports()
-                .map(|i| {
-                    // This is super funky logic, but it matches what is in 0.12.0
-                    let export = match i.module() {
-                        "wasi_snapshot_preview1" => wasi_snapshot.get_export(i.name()),
-                        "wasi_unstable" => wasi_unstable.get_export(i.name()),
-                        other => bail!("import module `{}` was not found", other),
-                    };
-                    match export {
-                        Some(export) => Ok(export.clone().into()),
-                        None => bail!(
-                            "import `{}` was not found in module `{}`",
-                            i.name(),
-                            i.module()
-                        ),
+        // Held until the module is compiled for its first run
+        let mut compiling = Some(concurrency.acquire(Operation::ModuleCompilation).await);
+
+        tokio::task::spawn_blocking(move || {
+            cpu.measure(|| -> anyhow::Result<_> {
+                let engine = wasmtime::Engine::new(&data.wasmtime.engine_config());
+                let mut backoff = RESTART_BACKOFF;
+                loop {
+                    let store = wasmtime::Store::new(&engine);
+                    *interrupt_handle.lock().unwrap() = Some(store.interrupt_handle()?);
+                    // A stop before the handle was replaced could not interrupt this run
+                    if stopped.load(Ordering::SeqCst) {
+                        return Ok(());
                     }
-                })
-                .collect::<Result<Vec<_>, _>>();
-            let imports = match imports {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "unable to load module";
-                    error!("{}: {:?}", message, e);
-                    status_sender
-                        .broadcast(ContainerStatus::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        })
-                        .expect("status should be able to send");
-                    return Err(e);
-                }
-            };
 
-            let instance = match wasmtime::Instance::new(&module, &imports) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "unable to instantiate module";
-                    error!("{}: {:?}", message, e);
-                    status_sender
-                        .broadcast(ContainerStatus::Terminated {
-                            failed: true,
-                            message: message.into(),
+                    // Restarts compile the module again for their store, which the cache
+                    // of compiled modules makes cheap when it is enabled
+                    let permit = compiling.take().unwrap_or_else(|| {
+                        futures::executor::block_on(
+                            concurrency.acquire(Operation::ModuleCompilation),
+                        )
+                    });
+                    let module = wasmtime::Module::new(&store, &data.module_data);
+                    drop(permit);
+                    let module = match module {
+                        // We can't map errors here or it moves the send channel, so we
+                        // do it in a match
+                        Ok(m) => m,
+                        Err(e) => {
+                            let message = "unable to create module";
+                            error!("{}: {:?}", message, e);
+                            report(
+                                &status_sender,
+                                ContainerStatus::Terminated {
+                                    exit_code: 1,
+                                    message: message.into(),
+                                    timestamp: chrono::Utc::now(),
+                                },
+                            );
+                            return Err(anyhow::anyhow!("{}: {}", message, e));
+                        }
+                    };
+
+                    let result = run_module(
+                        &store,
+                        &module,
+                        &data,
+                        host.as_deref(),
+                        &sandbox,
+                        &output_write,
+                        &status_sender,
+                    );
+                    let (exit_code, message) = match &result {
+                        Ok(0) => {
+                            info!("module run complete");
+                            (0, "Module run completed".to_owned())
+                        }
+                        Ok(code) => {
+                            info!("module exited with code {}", code);
+                            (*code, format!("Module exited with code {}", code))
+                        }
+                        Err(e) => {
+                            error!("{:?}", e);
+                            (1, e.to_string())
+                        }
+                    };
+                    let reported = report(
+                        &status_sender,
+                        ContainerStatus::Terminated {
+                            exit_code,
+                            message,
                             timestamp: chrono::Utc::now(),
-                        })
-                        .expect("status should be able to send");
-                    // Converting from anyhow
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
+                        },
+                    );
+
+                    // Nobody watches the container anymore once its handle is dropped, so it
+                    // isn't restarted either
+                    if !reported
+                        || stopped.load(Ordering::SeqCst)
+                        || !data.restart_policy.restarts(exit_code)
+                    {
+                        return result.map(|_| ());
+                    }
+                    info!("restarting module in {:?}", backoff);
+                    // Sleep in small steps so a stop during the backoff is not held up
+                    let restart_at = Instant::now() + backoff;
+                    while Instant::now() < restart_at {
+                        if stopped.load(Ordering::SeqCst) {
+                            return result.map(|_| ());
+                        }
+                        std::thread::sleep(Duration::from_millis(100));
+                    }
+                    backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
                 }
-            };
+            })
+        })
+    }
+}
 
-            // NOTE(taylor): In the future, if we want to pass args directly, we'll
-            // need to do a bit more to pass them in here.
-            info!("starting run of module");
-            status_sender
-                .broadcast(ContainerStatus::Running {
-                    timestamp: chrono::Utc::now(),
-                })
-                .expect("status should be able to send");
-            let export = instance
-                .get_export("_start")
-                .ok_or_else(|| anyhow::anyhow!("_start import doesn't exist in wasm module"))?;
-            let func = match export {
-                wasmtime::Extern::Func(f) => f,
-                _ => {
-                    return Err(anyhow::anyhow!(
-                    "_start import was not a function. This is likely a problem with the module"
-                ))
+/// Report a status of the container, returning false if nobody receives them anymore
+fn report(status_sender: &Sender<ContainerStatus>, status: ContainerStatus) -> bool {
+    status_sender.broadcast(status).is_ok()
+}
+
+/// Compile a module without running it, which stores the compiled module in wasmtime's
+/// cache if the settings enable it. Images are prefetched one at a time, so this isn't
+/// limited like the compilations for pods
+pub(crate) async fn compile(module_data: Vec<u8>, wasmtime: WasmtimeConfig) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let engine = wasmtime::Engine::new(&wasmtime.engine_config());
+        let store = wasmtime::Store::new(&engine);
+        wasmtime::Module::new(&store, &module_data)?;
+        Ok(())
+    })
+    .await?
+}
+
+/// Runs the module once to completion, returning its exit code. Errors are returned
+/// with a short description of what failed as their outermost context.
+fn run_module(
+    store: &wasmtime::Store,
+    module: &wasmtime::Module,
+    data: &Data,
+    host: Option<&Host>,
+    sandbox: &Sandbox,
+    output_write: &std::fs::File,
+    status_sender: &Sender<ContainerStatus>,
+) -> anyhow::Result<i32> {
+    // Build the WASI instance and then generate a list of WASI modules
+    let mut ctx_builder_snapshot = WasiCtxBuilder::new();
+    let mut ctx_builder_snapshot = ctx_builder_snapshot
+        .args(&data.args)
+        .envs(&data.env)
+        .stdout(output_write.try_clone()?)
+        .stderr(output_write.try_clone()?);
+    let mut ctx_builder_unstable = wasi_common::old::snapshot_0::WasiCtxBuilder::new();
+    let mut ctx_builder_unstable = ctx_builder_unstable
+        .args(&data.args)
+        .envs(&data.env)
+        .stdout(output_write.try_clone()?)
+        .stderr(output_write.try_clone()?);
+
+    for (key, value) in data.dirs.iter() {
+        let guest_dir = value.as_ref().unwrap_or(key);
+        ctx_builder_snapshot = ctx_builder_snapshot.preopened_dir(preopen_dir(key)?, guest_dir);
+        ctx_builder_unstable = ctx_builder_unstable.preopened_dir(preopen_dir(key)?, guest_dir);
+    }
+    let wasi_ctx_snapshot = ctx_builder_snapshot.build()?;
+    let wasi_ctx_unstable = ctx_builder_unstable.build()?;
+
+    let wasi_snapshot = Wasi::new(store, wasi_ctx_snapshot);
+    let wasi_unstable = WasiUnstable::new(store, wasi_ctx_unstable);
+    // The WASI implementation of proc_exit exits the whole process, so it is replaced
+    // with a function that records the exit code and unwinds the module with a trap
+    let exit_code = Rc::new(Cell::new(None));
+    let proc_exit = {
+        let exit_code = exit_code.clone();
+        Func::wrap(store, move |code: i32| -> Result<(), Trap> {
+            exit_code.set(Some(code));
+            Err(Trap::new(format!("module exited with code {}", code)))
+        })
+    };
+    // Iterate through the module includes and resolve imports
+    let imports = module
+        .imports()
+        .map(|i| {
+            // This is super funky logic, but it matches what is in 0.12.0
+            let export = match i.module() {
+                "wasi_snapshot_preview1" | "wasi_unstable" if i.name() == "proc_exit" => {
+                    return Ok(proc_exit.clone().into())
                 }
-            };
-            match func.call(&[]) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(_) => {}
-                Err(e) => {
-                    let message = "unable to run module";
-                    error!("{}: {:?}", message, e);
-                    status_sender
-                        .broadcast(ContainerStatus::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        })
-                        .expect("status should be able to send");
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
+                "wasi_snapshot_preview1" | "wasi_unstable" => {
+                    match restricted_import(store, sandbox, i.name()) {
+                        Some(func) => return Ok(func.into()),
+                        None if i.module() == "wasi_snapshot_preview1" => {
+                            wasi_snapshot.get_export(i.name())
+                        }
+                        None => wasi_unstable.get_export(i.name()),
+                    }
                 }
+                other => match host.filter(|h| h.provides(other)) {
+                    Some(host) => {
+                        return host.resolve(store, other, i.name()).ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "import `{}` was not found in module `{}`",
+                                i.name(),
+                                other
+                            )
+                        })
+                    }
+                    None => bail!("import module `{}` was not found", other),
+                },
             };
+            match export {
+                Some(export) => Ok(export.clone().into()),
+                None => bail!(
+                    "import `{}` was not found in module `{}`",
+                    i.name(),
+                    i.module()
+                ),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.context("unable to load module"))?;
+
+    let instance = wasmtime::Instance::new(module, &imports)
+        .map_err(|e| e.context("unable to instantiate module"))?;
+
+    // NOTE(taylor): In the future, if we want to pass args directly, we'll
+    // need to do a bit more to pass them in here.
+    info!("starting run of module");
+    report(
+        status_sender,
+        ContainerStatus::Running {
+            timestamp: chrono::Utc::now(),
+        },
+    );
+    let export = instance
+        .get_export("_start")
+        .ok_or_else(|| anyhow::anyhow!("_start import doesn't exist in wasm module"))?;
+    let func = match export {
+        wasmtime::Extern::Func(f) => f,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "_start import was not a function. This is likely a problem with the module"
+            ))
+        }
+    };
+    match func.call(&[]) {
+        Ok(_) => Ok(0),
+        Err(trap) => match exit_code.get() {
+            Some(code) => Ok(code),
+            None => Err(anyhow::anyhow!("{}", trap).context("unable to run module")),
+        },
+    }
+}
+
+/// A replacement for a WASI function that the sandbox doesn't allow, if it replaces
+/// the function with the given name
+fn restricted_import(store: &wasmtime::Store, sandbox: &Sandbox, name: &str) -> Option<Func> {
+    match name {
+        // Only the time of day is hidden, the other clocks count from when the module
+        // was instantiated
+        "clock_time_get" if !sandbox.wall_clock => {
+            let started = Instant::now();
+            Some(Func::wrap(
+                store,
+                move |caller: Caller<'_>, id: i32, _precision: i64, time: i32| {
+                    if !(CLOCK_REALTIME..=CLOCK_THREAD_CPUTIME_ID).contains(&id) {
+                        return ERRNO_INVAL;
+                    }
+                    if id == CLOCK_REALTIME {
+                        return ERRNO_NOTCAPABLE;
+                    }
+                    let elapsed = started.elapsed().as_nanos() as u64;
+                    write_memory(&caller, time, &elapsed.to_le_bytes())
+                },
+            ))
+        }
+        "random_get" if !sandbox.random => {
+            Some(Func::wrap(store, |_buf: i32, _len: i32| ERRNO_NOTCAPABLE))
+        }
+        _ => None,
+    }
+}
+
+/// Write to the memory of the module calling a WASI function, returning the WASI error
+fn write_memory(caller: &Caller<'_>, offset: i32, bytes: &[u8]) -> i32 {
+    let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+        Some(memory) => memory,
+        None => return ERRNO_FAULT,
+    };
+    let start = offset as u32 as usize;
+    // This is safe because no other reference to the memory is held while the
+    // function runs
+    let data = unsafe { memory.data_unchecked_mut() };
+    match data.get_mut(start..start + bytes.len()) {
+        Some(dest) => {
+            dest.copy_from_slice(bytes);
+            ERRNO_SUCCESS
+        }
+        None => ERRNO_FAULT,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sandbox::{SandboxPolicy, SandboxRequest};
+    use wasmtime::{Instance, Module, Store, Val};
+
+    /// A module that passes its arguments on to the imported clock and random functions,
+    /// with its memory for them to write to
+    const MODULE: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "clock_time_get"
+            (func $clock_time_get (param i32 i64 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "random_get"
+            (func $random_get (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "clock") (param i32 i32) (result i32)
+            (call $clock_time_get (local.get 0) (i64.const 0) (local.get 1)))
+          (func (export "random") (param i32 i32) (result i32)
+            (call $random_get (local.get 0) (local.get 1))))
+    "#;
+
+    fn denying() -> Sandbox {
+        let policy = SandboxPolicy {
+            wall_clock: false,
+            random: false,
+            ..Default::default()
+        };
+        policy.sandbox(SandboxRequest::default()).0
+    }
+
+    #[test]
+    fn test_restricted_import() {
+        let store = Store::default();
+        assert!(restricted_import(&store, &Sandbox::default(), "clock_time_get").is_none());
+        assert!(restricted_import(&store, &Sandbox::default(), "random_get").is_none());
+        assert!(restricted_import(&store, &denying(), "fd_write").is_none());
+
+        let module = Module::new(&store, MODULE).unwrap();
+        let imports: Vec<Extern> = ["clock_time_get", "random_get"]
+            .iter()
+            .map(|name| restricted_import(&store, &denying(), name).unwrap().into())
+            .collect();
+        let instance = Instance::new(&module, &imports).unwrap();
+        let call = |name, args: &[i32]| {
+            let args: Vec<Val> = args.iter().map(|a| Val::I32(*a)).collect();
+            instance.get_func(name).unwrap().call(&args).unwrap()[0].unwrap_i32()
+        };
+
+        assert_eq!(ERRNO_NOTCAPABLE, call("clock", &[CLOCK_REALTIME, 0]));
+        assert_eq!(ERRNO_INVAL, call("clock", &[4, 0]));
+        assert_eq!(ERRNO_INVAL, call("clock", &[-1, 0]));
+        assert_eq!(ERRNO_FAULT, call("clock", &[1, 65536]));
+        // The monotonic clock counts from when the module was instantiated
+        assert_eq!(ERRNO_SUCCESS, call("clock", &[1, 8]));
+        let memory = instance.get_memory("memory").unwrap();
+        let mut elapsed = [0; 8];
+        elapsed.copy_from_slice(unsafe { &memory.data_unchecked()[8..16] });
+        assert!(u64::from_le_bytes(elapsed) < Duration::from_secs(60).as_nanos() as u64);
 
-            info!("module run complete");
-            status_sender
-                .broadcast(ContainerStatus::Terminated {
-                    failed: false,
-                    message: "Module run completed".into(),
-                    timestamp: chrono::Utc::now(),
-                })
-                .expect("status should be able to send");
-            Ok(())
-        });
-        // Wait for the interrupt to be sent back to us
-        let interrupt = rx.await?;
-        Ok((interrupt, handle))
+        assert_eq!(ERRNO_NOTCAPABLE, call("random", &[0, 16]));
     }
 }
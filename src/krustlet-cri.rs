@@ -0,0 +1,18 @@
+use cri_provider::{CriProvider, DEFAULT_RUNTIME_ENDPOINT};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // The provider is responsible for all the "back end" logic. If you are creating
+    // a new Kubelet, all you need to implement is a provider.
+    kubelet::cli::run_provider(
+        "krustlet-cri",
+        env!("CARGO_PKG_VERSION"),
+        |config, client| async move {
+            // The runtime socket can be overridden for runtimes other than containerd
+            let endpoint = std::env::var("CRI_RUNTIME_ENDPOINT")
+                .unwrap_or_else(|_| DEFAULT_RUNTIME_ENDPOINT.to_owned());
+            CriProvider::new(endpoint, &config, client).await
+        },
+    )
+    .await
+}
@@ -1,25 +1,46 @@
+use std::sync::Arc;
+
+use kubelet::capabilities::Capabilities;
 use kubelet::config::Config;
+use kubelet::module_store::bindle::BindleModuleStore;
 use kubelet::module_store::FileModuleStore;
-use kubelet::Kubelet;
+use wasi_provider::dns::ClusterDns;
+use wasi_provider::host::HostCapability;
 use wasi_provider::WasiProvider;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // The provider is responsible for all the "back end" logic. If you are creating
     // a new Kubelet, all you need to implement is a provider.
-    let config = Config::new_from_flags(env!("CARGO_PKG_VERSION"));
-
-    let kubeconfig = kube::Config::infer().await?;
-
-    // Initialize the logger
-    env_logger::init();
+    kubelet::cli::run_provider(
+        "krustlet-wasi",
+        env!("CARGO_PKG_VERSION"),
+        |config, client| async move {
+            let store = module_store(&config)?;
+            // Pods opt in to resolving cluster names with the dns capability
+            let mut capabilities: Capabilities<dyn HostCapability> = Capabilities::new();
+            capabilities.register("dns", Arc::new(ClusterDns::new(client.clone())));
+            let provider = WasiProvider::new(store, &config, client).await?;
+            Ok(provider.with_capabilities(capabilities))
+        },
+    )
+    .await
+}
 
-    let client = oci_distribution::Client::default();
+fn module_store(
+    config: &Config,
+) -> anyhow::Result<BindleModuleStore<FileModuleStore<oci_distribution::Client>>> {
+    // Registries signed by private CAs or that require client certificates are
+    // configured in the certs directory
+    let client_config = config.registry_client.client_config(&config.data_dir)?;
+    let client = oci_distribution::Client::new(client_config).with_cloud_credential_helpers();
     let mut module_store_path = config.data_dir.join(".oci");
     module_store_path.push("modules");
     let store = FileModuleStore::new(client, &module_store_path);
-
-    let provider = WasiProvider::new(store, &config, kubeconfig.clone()).await?;
-    let kubelet = Kubelet::new(provider, kubeconfig, config);
-    kubelet.start().await
+    let store =
+        BindleModuleStore::new(store).with_cache_dir(config.data_dir.join(".bindle/parcels"));
+    Ok(match config.bindle_server.clone() {
+        Some(server) => store.with_server(server),
+        None => store,
+    })
 }